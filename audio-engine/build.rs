@@ -0,0 +1,10 @@
+// Enables `shared_memory` for builds whose memory other threads can write: native targets, and
+// WASM built with `+atomics` so that its memory can back a `SharedArrayBuffer`
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(shared_memory)");
+    let arch = std::env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let features = std::env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    if arch != "wasm32" || features.split(',').any(|feature| feature == "atomics") {
+        println!("cargo::rustc-cfg=shared_memory");
+    }
+}
@@ -0,0 +1,72 @@
+//! Times rendering at increasing grain densities.  Run with
+//! `cargo run --release --example grain_density`; prints the best time per frame out of a few
+//! runs for each spacing between grains.
+//!
+//! Each spacing is timed twice: once with the envelope parameters holding still, so grains read
+//! their voice's window table, and once with the slope linearity moving on every frame, so grains
+//! work their envelopes out directly.  The difference between the two columns is what the window
+//! table saves.
+//!
+//! Changes to the render loop itself, like the structure-of-arrays grain store, are compared
+//! across commits.  The example only uses the `Granular` API, so it runs unchanged on any commit
+//! that has it: check the earlier commit out with `git worktree add`, copy this file into its
+//! `examples`, and alternate runs of the two builds so that both see the same machine load.
+
+use std::time::Instant;
+
+use audio_engine::{GlobalParam, Granular, GranularConfig, ParamId, VoiceParam};
+
+const WAVEFORM_LEN: usize = 480000;
+const GRAIN_SIZE: f32 = 4800.;
+const SAMPLE_SPEED_RATIO: f32 = 1.3;
+const FRAMES: usize = 2000;
+const RUNS: usize = 5;
+
+fn granular(samples_between_grains: f32) -> Granular {
+    let mut granular = GranularConfig::builder().seed(7).build().unwrap();
+    granular.load_waveform((0..WAVEFORM_LEN).map(|i| (i as f32 * 0.01).sin()).collect());
+    granular.set_param(
+        ParamId::Global(GlobalParam::SelectionEndSampleIx),
+        (WAVEFORM_LEN - 1) as f32,
+    );
+    granular.set_param(ParamId::Global(GlobalParam::GrainSize), GRAIN_SIZE);
+    for voice_ix in 0..2 {
+        let mut set = |param, value| granular.set_param(ParamId::Voice(voice_ix, param), value);
+        set(VoiceParam::SampleSpeedRatio, SAMPLE_SPEED_RATIO);
+        set(VoiceParam::SamplesBetweenGrains, samples_between_grains);
+        set(VoiceParam::GrainStartRandomnessSamples, 20000.);
+        set(VoiceParam::Gain, 1.);
+    }
+    // Until the grains overlap as much as they're going to
+    granular.render_frames(400);
+    granular
+}
+
+/// Best time per frame in microseconds.  With `moving_envelope` the slope linearity gets a new
+/// target every frame, so the smoothed value never settles and no window table is built.
+fn time_frames(granular: &mut Granular, moving_envelope: bool) -> f64 {
+    let slope_linearity = ParamId::Global(GlobalParam::SlopeLinearity);
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            for frame in 0..FRAMES {
+                if moving_envelope {
+                    granular.set_param(slope_linearity, if frame % 2 == 0 { 0.3 } else { 0.7 });
+                }
+                std::hint::black_box(granular.render());
+            }
+            start.elapsed().as_secs_f64() * 1e6 / FRAMES as f64
+        })
+        .fold(f64::MAX, f64::min)
+}
+
+fn main() {
+    println!("spacing  grains per voice  us per frame  moving envelope");
+    for samples_between_grains in [60., 20., 10., 4.] {
+        let mut granular = granular(samples_between_grains);
+        let table = time_frames(&mut granular, false);
+        let direct = time_frames(&mut granular, true);
+        let overlap = GRAIN_SIZE / SAMPLE_SPEED_RATIO / samples_between_grains;
+        println!("{samples_between_grains:>7}  {overlap:>16.0}  {table:>12.1}  {direct:>15.1}");
+    }
+}
@@ -0,0 +1,913 @@
+//! Drives the WASM bindings with arbitrary parameter values, buffer lengths and call orders,
+//! including calls on freed and never-issued handles, the way a buggy host could.  Run with
+//! `cargo fuzz run ffi` from `audio-engine/`, preferably with the default address sanitizer so that
+//! out-of-bounds accesses through the returned pointers are caught as well as panics.
+//!
+//! Every byte string decodes to a sequence of calls: one byte picks the call and the bytes after
+//! it its arguments, reading zeros once the input runs out.
+
+#![no_main]
+
+use audio_engine::*;
+use libfuzzer_sys::fuzz_target;
+
+const FRAME_SIZE: usize = 128;
+const STEREO_FRAME_SIZE: usize = 2 * FRAME_SIZE;
+/// Header and value words in the packed parameter block
+const PARAM_BLOCK_WORDS: usize = 5 + 1024;
+/// Header and command words in the command ring
+const COMMAND_RING_WORDS: usize = 4 + 64 * 4;
+/// Header, parameter block, events, inputs, output and meters in the I/O block
+const IO_BLOCK_WORDS: usize =
+    12 + PARAM_BLOCK_WORDS + 64 * 4 + 2 * FRAME_SIZE + 4 * FRAME_SIZE + 10;
+/// Longest buffer passed in, to keep every run short
+const MAX_LEN: usize = 1 << 14;
+/// Most frames rendered by a single call
+const MAX_FRAMES: usize = 8;
+/// Number of different calls an input can make
+const CALL_COUNT: u8 = 86;
+/// Status bit of an instance that panicked
+const INSTANCE_POISONED: u32 = 1 << 9;
+
+struct Input<'a> {
+    bytes: &'a [u8],
+}
+
+impl Input<'_> {
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn u8(&mut self) -> u8 {
+        let Some((&byte, rest)) = self.bytes.split_first() else {
+            return 0;
+        };
+        self.bytes = rest;
+        byte
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes([self.u8(), self.u8()])
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes([self.u8(), self.u8(), self.u8(), self.u8()])
+    }
+
+    fn bool(&mut self) -> bool {
+        self.u8() & 1 == 1
+    }
+
+    /// Value for a parameter: any bit pattern, NaN and infinities included, a quarter of the time,
+    /// and otherwise one in a range that's plausible for sample indices, times and frequencies so
+    /// that grains actually get played
+    fn f32(&mut self) -> f32 {
+        if self.u8() % 4 == 0 {
+            f32::from_bits(self.u32())
+        } else {
+            (self.u16() as i16) as f32 * 0.5
+        }
+    }
+
+    /// Small index, sometimes just past the voice, slot or mapping counts
+    fn index(&mut self) -> usize {
+        (self.u8() % 8) as usize
+    }
+
+    fn len(&mut self) -> usize {
+        self.u16() as usize % (MAX_LEN + 1)
+    }
+
+    fn frames(&mut self) -> usize {
+        self.u8() as usize % (MAX_FRAMES + 1)
+    }
+
+    fn samples(&mut self) -> Vec<f32> {
+        let len = self.u8() as usize % 32;
+        let fill = self.f32();
+        (0..len).map(|ix| fill * (ix as f32 * 0.37).sin()).collect()
+    }
+
+    fn text(&mut self) -> String {
+        let len = self.u8() as usize;
+        let end = len.min(self.bytes.len());
+        let text = String::from_utf8_lossy(&self.bytes[..end]).into_owned();
+        self.bytes = &self.bytes[end..];
+        text
+    }
+}
+
+/// Reads what a returned pointer points to, so that the address sanitizer flags it if the
+/// documented length runs past the allocation
+fn touch<T: Copy>(ptr: *const T, len: usize) {
+    if !ptr.is_null() {
+        let values = unsafe { std::slice::from_raw_parts(ptr, len) };
+        std::hint::black_box(values);
+    }
+}
+
+/// Writes through a returned pointer, which must be valid for `len` values
+fn fill<T: Copy>(ptr: *mut T, len: usize, value: T) {
+    if !ptr.is_null() {
+        unsafe { std::slice::from_raw_parts_mut(ptr, len) }.fill(value);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Errors, e.g. where a panic happened, are all that's worth printing
+    set_log_level(1);
+    let mut input = Input { bytes: data };
+    // Freed handles stay in the list so that later calls use them
+    let mut handles = vec![create_granular_instance()];
+    // Owned here so that waveforms bound to it stay valid until the instances are freed below
+    let external: Vec<f32> = (0..MAX_LEN).map(|ix| (ix as f32 * 0.01).sin()).collect();
+    let mut render_target = vec![0.; MAX_FRAMES * FRAME_SIZE];
+
+    while !input.is_empty() {
+        let call = input.u8() % CALL_COUNT;
+        // Mostly live handles, sometimes one that was never handed out
+        let handle = match input.u8() {
+            0 => input.u32(),
+            pick => handles[pick as usize % handles.len()],
+        };
+        match call {
+            0 => {
+                if handles.len() < 8 {
+                    handles.push(create_granular_instance());
+                }
+            }
+            1 => free_granular_instance(handle),
+            2 => {
+                let len = input.len();
+                let value = input.f32();
+                fill(get_granular_waveform_ptr(handle, len), len, value);
+                commit_waveform(handle, input.len());
+            }
+            3 => {
+                let len = input.len();
+                let value = input.f32();
+                fill(get_staging_waveform_ptr(handle, len), len, value);
+                if input.bool() {
+                    fill(get_staging_waveform_right_ptr(handle, len), len, -value);
+                }
+                if input.bool() {
+                    swap_staging_waveform(handle, input.f32());
+                } else {
+                    swap_staging_waveform_gapless(handle);
+                }
+            }
+            4 => {
+                process_loaded_waveform(handle);
+            }
+            5 => {
+                let samples = input.samples();
+                import_interleaved_waveform(handle, &samples, input.index(), input.bool());
+            }
+            6 => {
+                set_waveform_valid_len(handle, input.len());
+            }
+            7 => {
+                let len = input.len();
+                let generation = bind_external_waveform(handle, external.as_ptr(), len);
+                if input.bool() {
+                    unbind_external_waveform(handle, generation);
+                }
+            }
+            8 => {
+                crop_waveform(handle, input.len(), input.len());
+            }
+            9 => {
+                begin_waveform_upload(handle, input.len());
+                for _ in 0..input.u8() % 4 {
+                    append_waveform_chunk(handle, &input.samples());
+                }
+                if input.bool() {
+                    finish_waveform_upload(handle);
+                }
+            }
+            10 => set_waveform_source_rate(handle, input.f32(), input.u32()),
+            11 => set_waveform_normalization(handle, input.bool(), input.u32(), input.f32()),
+            12 => {
+                compute_rms_envelope(handle, input.f32());
+                analyze_features(handle, input.f32());
+            }
+            13 => {
+                set_position_weighting(handle, input.f32());
+                set_feature_weighting(handle, input.u32(), input.f32());
+            }
+            14 => {
+                let voice_params = get_voice_render_params_ptr(handle);
+                if !voice_params.is_null() {
+                    let voice_params = unsafe { std::slice::from_raw_parts_mut(voice_params, 12) };
+                    for value in voice_params.iter_mut().take(input.len() % 13) {
+                        *value = input.f32();
+                    }
+                }
+                let output = render_granular(
+                    handle,
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                );
+                // Renders return the buffer of the output layout
+                touch(output, FRAME_SIZE * get_output_channels(handle) as usize);
+                touch(get_output_ptr(handle), FRAME_SIZE);
+                touch(get_stereo_output_ptr(handle), STEREO_FRAME_SIZE);
+                touch(get_voice_output_ptr(handle, input.index()), FRAME_SIZE);
+                touch(get_meters(handle), 10);
+            }
+            15 => {
+                let block = get_param_block_ptr(handle);
+                if !block.is_null() {
+                    let word_ix = input.u16() as usize % PARAM_BLOCK_WORDS;
+                    unsafe { *block.add(word_ix) = input.u32() };
+                }
+                touch(render_granular_block(handle), FRAME_SIZE);
+            }
+            16 => {
+                let frames = input.frames();
+                render_granular_into(handle, render_target.as_mut_ptr(), frames);
+            }
+            17 => {
+                if input.bool() {
+                    start_chunked_render(handle);
+                }
+                let frames = input.frames();
+                touch(
+                    render_next_chunk(handle, frames),
+                    frames * STEREO_FRAME_SIZE,
+                );
+            }
+            18 => {
+                let slot_id = bounce_selection(handle, input.len());
+                if input.bool() {
+                    stage_sample_slot(handle, slot_id);
+                } else {
+                    load_sample_slot(handle, slot_id, input.f32());
+                }
+                if input.bool() {
+                    free_sample_slot(handle, slot_id);
+                }
+                let added = if input.bool() {
+                    add_test_signal_slot(handle, input.u8() as u32 % 4, input.f32())
+                } else {
+                    add_sample_slot(handle, &input.samples())
+                };
+                if input.u8() == 0 {
+                    load_test_signal(handle, input.u8() as u32 % 4, input.f32());
+                }
+                let voice_ix = input.index();
+                if input.bool() {
+                    let policy = input.u8() as u32 % 4;
+                    let weights = input.samples();
+                    set_voice_slot_selection(handle, voice_ix, policy, &[slot_id, added], &weights);
+                } else {
+                    clear_voice_slot_selection(handle, voice_ix);
+                }
+            }
+            19 => {
+                let voice_ix = input.index();
+                if input.bool() {
+                    freeze_voice(handle, voice_ix, input.f32());
+                } else {
+                    unfreeze_voice(handle, voice_ix);
+                }
+                is_voice_frozen(handle, voice_ix);
+            }
+            20 => {
+                set_overdub(handle, input.u32(), input.len(), input.len(), input.f32());
+                if input.bool() {
+                    clear_overdub(handle);
+                }
+            }
+            21 => {
+                if input.bool() {
+                    start_recording(handle, input.f32());
+                } else {
+                    stop_recording(handle);
+                }
+                is_recording(handle);
+                get_recording_len(handle);
+            }
+            22 => {
+                export_recording_wav(handle, input.u32(), input.bool());
+                export_recording_wav_at(handle, input.u32() % 200_000, input.u32(), 1, false);
+            }
+            23 => {
+                let samples = input.samples();
+                resample_interleaved(
+                    &samples,
+                    input.index(),
+                    input.f32(),
+                    input.f32(),
+                    input.u32(),
+                );
+            }
+            24 => set_random_seed(handle, input.u32()),
+            25 => {
+                let voice_ix = input.index();
+                set_voice_mute(handle, voice_ix, input.bool());
+                set_voice_solo(handle, voice_ix, input.bool());
+                set_voice_pan(handle, voice_ix, input.f32());
+                set_voice_delay_send(handle, voice_ix, input.f32());
+                set_voice_overlap_compensation(handle, voice_ix, input.bool());
+                set_voice_grain_skip_probability(handle, voice_ix, input.f32());
+            }
+            26 => {
+                set_master_gain(handle, input.f32());
+                set_dry_gain(handle, input.f32());
+                set_detune_spread(handle, input.f32());
+                set_limiter(handle, input.bool(), input.f32(), input.f32());
+            }
+            27 => {
+                let config_a = input.samples();
+                let config_b = input.samples();
+                morph(handle, &config_a, &config_b, input.f32());
+                if input.bool() {
+                    clear_morph(handle);
+                }
+                get_param_values(handle);
+                let scene = input.index();
+                if input.bool() {
+                    store_scene(handle, scene);
+                } else {
+                    set_scene_values(handle, scene, &input.samples());
+                }
+                get_scene_values(handle, input.index());
+                set_scene_morph(handle, input.f32());
+                if input.bool() {
+                    clear_scenes(handle);
+                }
+            }
+            28 => {
+                if input.bool() {
+                    let json = get_params_json(handle);
+                    set_params_json(handle, &json);
+                } else {
+                    set_params_json(handle, &input.text());
+                }
+                commit_param_state(handle);
+                match input.u8() % 3 {
+                    0 => {
+                        undo(handle);
+                    }
+                    1 => {
+                        redo(handle);
+                    }
+                    _ => clear_param_history(handle),
+                }
+                can_undo(handle);
+                can_redo(handle);
+            }
+            29 => set_sample_rate(handle, input.f32()),
+            30 => {
+                set_param_smoothing_time(handle, input.index(), input.f32());
+                set_param_locked(handle, input.u8() as usize, input.bool());
+                is_param_locked(handle, input.index());
+                randomize(handle, input.f32(), input.u8() as u32);
+            }
+            31 => {
+                set_param(handle, input.u8() as usize, input.f32());
+                set_send_delay(handle, input.f32(), input.f32());
+                set_click_guard(handle, input.bool());
+                set_overlap_add_mode(handle, input.bool());
+                set_density_compensation(handle, input.bool());
+            }
+            32 => {
+                let source_ix = input.index();
+                match input.u8() % 5 {
+                    0 => set_mod_lfo(handle, source_ix, input.u32(), input.f32()),
+                    1 => set_mod_random_walk(
+                        handle,
+                        source_ix,
+                        input.f32(),
+                        input.f32(),
+                        input.f32(),
+                    ),
+                    2 => set_mod_grain_envelope(handle, source_ix, input.f32(), input.f32()),
+                    3 => set_mod_grain_sample_hold(handle, source_ix),
+                    _ => set_mod_sidechain(handle, source_ix),
+                }
+                if input.bool() {
+                    clear_mod_source(handle, source_ix);
+                }
+            }
+            33 => {
+                fill(get_sidechain_input_ptr(handle), FRAME_SIZE, input.f32());
+                fill(get_audio_input_ptr(handle), FRAME_SIZE, input.f32());
+                set_sidechain_follower(handle, input.f32(), input.f32());
+            }
+            34 => {
+                let connection_ix = input.index();
+                set_mod_connection(
+                    handle,
+                    connection_ix,
+                    input.index(),
+                    input.index(),
+                    input.u32() % 10,
+                    input.f32(),
+                );
+                if input.bool() {
+                    clear_mod_connection(handle, connection_ix);
+                }
+            }
+            35 => {
+                let mapping_ix = input.index();
+                set_macro_value(handle, input.index(), input.f32());
+                set_macro_mapping(
+                    handle,
+                    mapping_ix,
+                    input.index(),
+                    input.index(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                );
+                if input.bool() {
+                    clear_macro_mapping(handle, mapping_ix);
+                }
+            }
+            36 => {
+                // Half system messages, which are otherwise a small part of the status bytes
+                let status = match input.bool() {
+                    true => 0xf0 | input.u8(),
+                    false => input.u8(),
+                };
+                handle_midi_event(handle, status, input.u8(), input.u8());
+                if input.bool() {
+                    let sync = ParamId::Global(GlobalParam::MidiClockSync);
+                    set_param(handle, sync.index(), input.bool() as u8 as f32);
+                }
+                get_midi_clock_bpm(handle);
+            }
+            37 => {
+                set_note_mode(handle, input.bool(), input.bool(), input.f32());
+                let mapping_ix = input.index();
+                set_cc_mapping(
+                    handle,
+                    mapping_ix,
+                    input.u8(),
+                    input.u8(),
+                    input.index(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                );
+                if input.bool() {
+                    clear_cc_mapping(handle, mapping_ix);
+                }
+                get_cc_mappings(handle);
+            }
+            38 => {
+                begin_cc_learn(handle, input.index(), input.f32(), input.f32(), input.f32());
+                if input.bool() {
+                    cancel_cc_learn(handle);
+                }
+                is_cc_learning(handle);
+            }
+            39 => {
+                set_transport(handle, input.bool(), input.f32(), input.f32() as f64);
+                set_automation_lane(handle, input.index(), &input.samples());
+                set_automation_position(handle, input.f32() as f64);
+                if input.bool() {
+                    clear_automation(handle);
+                }
+                set_automation_recording(handle, input.bool());
+                is_recording_automation(handle);
+                get_recorded_automation(handle);
+                if input.bool() {
+                    replay_recorded_automation(handle);
+                }
+            }
+            40 => {
+                let voice_ix = input.index();
+                set_grain_envelope_table(handle, voice_ix, &input.samples());
+                set_voice_envelope_skew(handle, voice_ix, input.f32());
+                set_voice_envelope_morph(handle, voice_ix, input.f32());
+                let wraps = ParamId::Voice(voice_ix, VoiceParam::WrapsSelection);
+                set_param(handle, wraps.index(), input.bool() as u8 as f32);
+                set_voice_reversed_source(handle, voice_ix, input.bool());
+            }
+            41 => {
+                let voice_ix = input.index();
+                set_voice_auto_pan(handle, voice_ix, input.f32(), input.f32(), input.f32());
+                set_voice_auto_pan_sync(handle, voice_ix, input.f32());
+                set_voice_grain_sync(handle, voice_ix, input.f32());
+            }
+            42 => {
+                let voice_ix = input.index();
+                set_voice_filter_envelope(
+                    handle,
+                    voice_ix,
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                );
+                set_voice_filter_envelope_amount(handle, voice_ix, input.f32(), input.f32());
+            }
+            43 => {
+                set_voice_link(handle, input.bool(), input.f32(), input.f32());
+                set_voice_ducking(handle, input.f32(), input.f32(), input.f32());
+            }
+            44 => {
+                capture_grains(handle, input.index());
+                let grain_ix = input.index();
+                get_captured_grain_info(handle, grain_ix);
+                get_captured_grain_samples(handle, grain_ix);
+                get_captured_grain_count(handle);
+            }
+            45 => {
+                set_grain_trace(handle, input.bool());
+                get_grain_trace(handle);
+                set_data_track(handle, input.bool(), input.u32());
+                export_data_track_csv(handle);
+            }
+            46 => {
+                set_profiling(handle, input.bool());
+                touch(get_profile(handle), 6);
+                touch(get_grain_stats(handle), 4 * 2);
+                get_loudness(handle, input.u32());
+                get_clip_count(handle);
+                get_status(handle);
+            }
+            47 => {
+                let voice_ix = input.index();
+                set_voice_pulsar(handle, voice_ix, input.f32(), input.f32(), input.f32());
+                if input.bool() {
+                    clear_voice_pulsar(handle, voice_ix);
+                }
+            }
+            48 => {
+                let voice_ix = input.index();
+                set_voice_wavetable(handle, voice_ix, input.len(), input.len(), input.len());
+                set_voice_wavetable_position(handle, voice_ix, input.f32(), input.f32());
+                if input.bool() {
+                    clear_voice_wavetable(handle, voice_ix);
+                }
+            }
+            49 => {
+                analyze_corpus(handle, input.f32());
+                set_concatenative_mode(handle, input.bool(), input.u32());
+                set_concatenative_weights(handle, input.f32(), input.f32(), input.f32());
+                if input.bool() {
+                    set_concatenative_target(handle, input.f32(), input.f32(), input.f32());
+                } else {
+                    set_concatenative_target_buffer(handle, &input.samples());
+                }
+            }
+            50 => {
+                let voice_ix = input.index();
+                let frame_len = 1 << (input.u8() % 16);
+                set_voice_spectral(handle, voice_ix, frame_len, input.f32(), input.f32());
+                if input.bool() {
+                    clear_voice_spectral(handle, voice_ix);
+                }
+            }
+            51 => {
+                let style = match input.u8() % 5 {
+                    0 => input.text(),
+                    ix => ["cloud", "stutter", "drone", "shimmer"][ix as usize - 1].to_string(),
+                };
+                let json = generate_texture_preset(handle, &style);
+                if input.bool() {
+                    set_params_json(handle, &json);
+                }
+            }
+            52 => {
+                let voice_ix = input.index();
+                set_voice_resonator(
+                    handle,
+                    voice_ix,
+                    input.f32(),
+                    input.bool(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                );
+                if input.bool() {
+                    clear_voice_resonator(handle, voice_ix);
+                }
+            }
+            53 => {
+                analyze_pitch(handle, input.f32());
+                let voice_ix = input.index();
+                set_voice_psola(handle, voice_ix, input.f32(), input.bool());
+                if input.bool() {
+                    clear_voice_psola(handle, voice_ix);
+                }
+            }
+            54 => {
+                set_drone_mode(handle, input.bool(), input.f32());
+                set_drone_blur(handle, input.f32());
+            }
+            55 => {
+                set_stutter_mode(
+                    handle,
+                    input.u32(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                    input.f32(),
+                );
+                is_stutter_repeating(handle);
+                if input.bool() {
+                    clear_stutter_mode(handle);
+                }
+            }
+            56 => {
+                if input.bool() {
+                    set_live_input(handle, input.f32());
+                }
+                set_live_scrub(handle, input.f32());
+                if input.bool() {
+                    clear_live_input(handle);
+                }
+            }
+            57 => {
+                let voice_ix = input.index();
+                let settings = (input.u32(), input.f32(), input.f32(), input.f32());
+                if input.bool() {
+                    set_voice_phaser(
+                        handle, voice_ix, settings.0, settings.1, settings.2, settings.3,
+                    );
+                    set_master_phaser(handle, settings.0, settings.1, settings.2, settings.3);
+                } else {
+                    clear_voice_phaser(handle, voice_ix);
+                    clear_master_phaser(handle);
+                }
+            }
+            58 => {
+                let voice_ix = input.index();
+                set_voice_comb(
+                    handle,
+                    voice_ix,
+                    input.u32(),
+                    input.f32(),
+                    input.bool(),
+                    input.f32(),
+                );
+                if input.bool() {
+                    clear_voice_comb(handle, voice_ix);
+                }
+            }
+            59 => set_master_tilt(handle, input.f32(), input.f32()),
+            60 => {
+                if input.bool() {
+                    set_master_eq(
+                        handle,
+                        input.f32(),
+                        input.f32(),
+                        input.f32(),
+                        input.f32(),
+                        input.f32(),
+                        input.f32(),
+                    );
+                } else {
+                    clear_master_eq(handle);
+                }
+            }
+            61 => {
+                set_master_transient_shaper(handle, input.f32(), input.f32());
+                if input.bool() {
+                    let [threshold_db, ratio, attack_ms, release_ms, makeup_db] =
+                        std::array::from_fn(|_| input.f32());
+                    let keyed = input.bool();
+                    set_master_compressor(
+                        handle,
+                        threshold_db,
+                        ratio,
+                        attack_ms,
+                        release_ms,
+                        makeup_db,
+                        keyed,
+                    );
+                }
+                get_master_compressor_reduction_db(handle);
+            }
+            62 => {
+                if input.bool() {
+                    set_master_haas(handle, input.u32() % 3, input.f32(), input.f32());
+                } else {
+                    clear_master_haas(handle);
+                }
+            }
+            63 => set_master_vibrato(handle, input.f32(), input.f32()),
+            64 => set_master_tape(handle, input.f32(), input.f32(), input.f32(), input.f32()),
+            65 => {
+                if input.bool() {
+                    set_master_saturation(handle, input.u32() % 5, input.f32(), input.u32() % 6);
+                } else {
+                    clear_master_saturation(handle);
+                }
+                get_latency_frames(handle);
+                get_tail_frames(handle);
+            }
+            66 => {
+                let len = input.len();
+                let value = input.f32();
+                if input.bool() {
+                    let spectra = prepare_impulse_response(&vec![value; len], input.f32());
+                    let ptr = get_impulse_response_ptr(handle, spectra.len());
+                    if !ptr.is_null() {
+                        unsafe { std::slice::from_raw_parts_mut(ptr, spectra.len()) }
+                            .copy_from_slice(&spectra);
+                    }
+                } else {
+                    fill(get_impulse_response_ptr(handle, len), len, value);
+                }
+                if input.bool() {
+                    load_impulse_response(handle, input.f32());
+                }
+                set_convolution_mix(handle, input.f32());
+                if input.bool() {
+                    clear_master_convolution(handle);
+                }
+            }
+            67 => {
+                // Just past the number of parameters
+                let param_ix = input.u8() as usize % 40;
+                fill(
+                    get_audio_rate_param_ptr(handle, param_ix),
+                    FRAME_SIZE,
+                    input.f32(),
+                );
+                if input.bool() {
+                    disable_audio_rate_param(handle, param_ix);
+                }
+                if input.bool() {
+                    clear_audio_rate_params(handle);
+                }
+            }
+            68 => {
+                let block = get_shared_param_block_ptr(handle);
+                if !block.is_null() {
+                    // The counter goes first so the whole block is one word longer
+                    let word_ix = input.u16() as usize % (PARAM_BLOCK_WORDS + 1);
+                    unsafe { *block.add(word_ix) = input.u32() };
+                }
+                touch(render_granular_shared(handle), FRAME_SIZE);
+            }
+            69 => {
+                let ring = get_command_ring_ptr(handle);
+                if !ring.is_null() {
+                    // Any word, including the counts in the header
+                    let word_ix = input.u16() as usize % COMMAND_RING_WORDS;
+                    let word = match input.index() {
+                        0 => get_command_clock(handle).wrapping_add(input.u16() as u32),
+                        1 => input.f32().to_bits(),
+                        _ => input.u32(),
+                    };
+                    unsafe { *ring.add(word_ix) = word };
+                }
+            }
+            70 => {
+                match input.index() {
+                    0 => playback_start(handle, input.f32()),
+                    1 => playback_stop(handle),
+                    2 => playback_seek(handle, input.f32()),
+                    3 => release_playback(handle),
+                    _ => {}
+                }
+                get_playback_state(handle);
+                get_playhead(handle, input.index());
+            }
+            71 => {
+                configure_output(handle, input.u8() as u32 % 6);
+                set_voice_quad_position(handle, input.index(), input.f32(), input.f32());
+                touch(get_quad_output_ptr(handle), FRAME_SIZE * 4);
+            }
+            72 => {
+                let text = if input.bool() {
+                    input.text()
+                } else {
+                    let count = input.u8() % 4;
+                    let degrees: Vec<String> = (0..count)
+                        .map(|_| match input.u8() % 3 {
+                            0 => format!("{:.3}", input.f32()),
+                            1 => format!("{}/{}", input.u8(), input.u8()),
+                            _ => input.u8().to_string(),
+                        })
+                        .collect();
+                    format!("! fuzz.scl\nfuzz\n{}\n{}\n", count, degrees.join("\n"))
+                };
+                if input.bool() {
+                    load_scala_tuning(handle, &text);
+                } else {
+                    clear_tuning(handle);
+                }
+            }
+            73 => {
+                let voice_ix = input.index();
+                if input.bool() {
+                    set_voice_pitch_intervals(handle, voice_ix, &input.samples());
+                } else {
+                    clear_voice_pitch_intervals(handle, voice_ix);
+                }
+            }
+            74 => {
+                let voice_ix = input.index();
+                if input.bool() {
+                    let semitones = input.samples();
+                    let gains = input.samples();
+                    set_voice_chord(handle, voice_ix, &semitones, &gains);
+                } else {
+                    clear_voice_chord(handle, voice_ix);
+                }
+            }
+            75 => {
+                let voice_ix = input.index();
+                if input.bool() {
+                    let semitones = input.samples();
+                    set_voice_arpeggio(handle, voice_ix, &semitones, input.f32());
+                } else {
+                    clear_voice_arpeggio(handle, voice_ix);
+                }
+            }
+            76 => {
+                if input.bool() {
+                    estimate_key(handle, input.bool());
+                } else {
+                    let snap = ParamId::Global(GlobalParam::GrainPitchSnap);
+                    set_param(handle, snap.index(), input.bool() as u8 as f32);
+                }
+            }
+            77 => {
+                let voice_ix = input.index();
+                if input.bool() {
+                    let semitones = input.samples();
+                    let gains = input.samples();
+                    set_voice_harmonies(handle, voice_ix, &semitones, &gains);
+                } else {
+                    clear_voice_harmonies(handle, voice_ix);
+                }
+            }
+            78 => set_voice_grain_glide(handle, input.index(), input.f32()),
+            79 => {
+                let voice_ix = input.index();
+                if input.bool() {
+                    let (spread, low, high) = (input.f32(), input.f32(), input.f32());
+                    set_voice_pitch_spray(handle, voice_ix, spread, low, high, &input.samples());
+                } else {
+                    clear_voice_pitch_spray(handle, voice_ix);
+                }
+                if input.bool() {
+                    let (low, high, pivot) = (input.f32(), input.f32(), input.f32());
+                    set_voice_grain_tilt(handle, voice_ix, low, high, pivot);
+                } else {
+                    clear_voice_grain_tilt(handle, voice_ix);
+                }
+            }
+            80 => set_voice_grain_note_values(handle, input.index(), input.f32(), input.f32()),
+            81 => {
+                if input.bool() {
+                    let timings = input.samples();
+                    set_groove(handle, &timings, &input.samples());
+                } else {
+                    clear_groove(handle);
+                }
+            }
+            82 => {
+                set_waveform_storage(handle, input.u8() as u32 % 3);
+                get_waveform_storage_metadata();
+            }
+            83 => {
+                set_processing_chunk_size(handle, input.u16() as usize % 2100);
+            }
+            84 => {
+                let block = get_io_block_ptr(handle);
+                if !block.is_null() {
+                    // Any word, including the header and the event count
+                    let word_ix = input.u16() as usize % IO_BLOCK_WORDS;
+                    let word = match input.index() {
+                        0 => input.u8() as u32,
+                        1 => input.f32().to_bits(),
+                        _ => input.u32(),
+                    };
+                    unsafe { *block.add(word_ix) = word };
+                }
+                process_io_block(handle);
+            }
+            _ => {
+                if input.bool() {
+                    reset(handle, input.f32());
+                } else {
+                    begin_shutdown(handle, input.f32());
+                }
+                is_drained(handle);
+            }
+        }
+        // With unwinding the bindings catch panics and poison the instance instead of unwinding
+        // into the host, so the fuzzer only notices them through its status; with abort it
+        // crashes on the panic itself
+        let poisoned = get_status(handle) & INSTANCE_POISONED != 0;
+        assert!(!poisoned, "call {} panicked", call);
+    }
+
+    for handle in handles {
+        free_granular_instance(handle);
+    }
+    drop(external);
+});
@@ -0,0 +1,165 @@
+// Size-class allocator for size-optimized WASM builds
+// Every block is rounded up to a power of two of at least 8 bytes, and freed blocks go on a free
+// list for their size to be handed out again, without the splitting and coalescing of the default
+// allocator.  That costs some memory for much less code.  Memory comes from growing the WASM
+// memory and is never given back, which WASM doesn't allow anyway.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::UnsafeCell;
+use std::ptr;
+
+const PAGE_SIZE: usize = 65536;
+/// Blocks are at least `1 << MIN_CLASS_BITS` bytes, enough to hold the free list's link
+const MIN_CLASS_BITS: usize = 3;
+const CLASS_COUNT: usize = usize::BITS as usize - MIN_CLASS_BITS;
+
+pub struct SizeClassAllocator {
+    state: UnsafeCell<State>,
+}
+
+struct State {
+    /// The last freed block of each size, which holds a pointer to the one freed before it
+    free: [*mut u8; CLASS_COUNT],
+    /// Start and end of the unused part of the memory grown so far
+    next: usize,
+    end: usize,
+}
+
+// Only installed in WASM builds without threads, where there's never more than one caller
+unsafe impl Sync for SizeClassAllocator {}
+
+impl SizeClassAllocator {
+    pub const fn new() -> Self {
+        SizeClassAllocator {
+            state: UnsafeCell::new(State {
+                free: [ptr::null_mut(); CLASS_COUNT],
+                next: 0,
+                end: 0,
+            }),
+        }
+    }
+}
+
+/// Size class of blocks that hold `layout`, or None if there's no block that big
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_CLASS_BITS);
+    let class = size.checked_next_power_of_two()?.trailing_zeros() as usize - MIN_CLASS_BITS;
+    (class < CLASS_COUNT).then_some(class)
+}
+
+impl State {
+    /// A new block of `size` bytes from the unused memory, growing it if there's too little
+    fn take(&mut self, size: usize, align: usize) -> *mut u8 {
+        let fits = |next: usize, end: usize| {
+            let start = next.checked_add(align - 1)? & !(align - 1);
+            (start.checked_add(size)? <= end).then_some(start)
+        };
+        let start = match fits(self.next, self.end) {
+            Some(start) => start,
+            None => {
+                let Some(pages) = size.checked_add(align).map(|len| len.div_ceil(PAGE_SIZE)) else {
+                    return ptr::null_mut();
+                };
+                let Some(grown) = grow(pages) else {
+                    return ptr::null_mut();
+                };
+                // Memory grown by someone else in between leaves a gap, and the rest of the old
+                // memory goes unused
+                if grown != self.end {
+                    self.next = grown;
+                }
+                self.end = grown + pages * PAGE_SIZE;
+                match fits(self.next, self.end) {
+                    Some(start) => start,
+                    None => return ptr::null_mut(),
+                }
+            }
+        };
+        self.next = start + size;
+        start as *mut u8
+    }
+}
+
+unsafe impl GlobalAlloc for SizeClassAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(class) = class_of(layout) else {
+            return ptr::null_mut();
+        };
+        let state = &mut *self.state.get();
+        let block = state.free[class];
+        // Freed blocks are only ever as aligned as what they were allocated for
+        if !block.is_null() && (block as usize).is_multiple_of(layout.align()) {
+            state.free[class] = *(block as *mut *mut u8);
+            return block;
+        }
+        state.take(1 << (class + MIN_CLASS_BITS), layout.align())
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let Some(class) = class_of(layout) else {
+            return;
+        };
+        let state = &mut *self.state.get();
+        *(block as *mut *mut u8) = state.free[class];
+        state.free[class] = block;
+    }
+
+    unsafe fn realloc(&self, block: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if class_of(new_layout) == class_of(layout) {
+            return block;
+        }
+        let new_block = self.alloc(new_layout);
+        if !new_block.is_null() {
+            ptr::copy_nonoverlapping(block, new_block, layout.size().min(new_size));
+            self.dealloc(block, layout);
+        }
+        new_block
+    }
+}
+
+/// Grows the memory by `pages` and returns the address of the first new byte
+#[cfg(target_arch = "wasm32")]
+fn grow(pages: usize) -> Option<usize> {
+    let previous_pages = core::arch::wasm32::memory_grow::<0>(pages);
+    (previous_pages != usize::MAX).then(|| previous_pages * PAGE_SIZE)
+}
+
+/// Takes pages from the system allocator instead, so that the allocator can be tried natively
+#[cfg(not(target_arch = "wasm32"))]
+fn grow(pages: usize) -> Option<usize> {
+    let layout = Layout::from_size_align(pages.checked_mul(PAGE_SIZE)?, PAGE_SIZE).ok()?;
+    let memory = unsafe { std::alloc::System.alloc(layout) };
+    (!memory.is_null()).then_some(memory as usize)
+}
+
+#[test]
+fn freed_blocks_are_reused_for_the_same_size() {
+    let allocator = SizeClassAllocator::new();
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let first = allocator.alloc(layout);
+        let second = allocator.alloc(layout);
+        assert_eq!(second as usize - first as usize, 32);
+        allocator.dealloc(first, layout);
+        assert_eq!(
+            allocator.alloc(Layout::from_size_align(17, 4).unwrap()),
+            first
+        );
+
+        let aligned = Layout::from_size_align(8, 64).unwrap();
+        let block = allocator.alloc(aligned);
+        assert_eq!(block as usize % 64, 0);
+        let grown = allocator.realloc(block, aligned, 40);
+        assert_eq!(grown, block);
+
+        let large = Layout::from_size_align(PAGE_SIZE * 3, 8).unwrap();
+        let block = allocator.alloc(large);
+        assert!(!block.is_null());
+        block.write_bytes(1, large.size());
+        let moved = allocator.realloc(block, large, PAGE_SIZE * 5);
+        assert_eq!(*moved.add(PAGE_SIZE * 3 - 1), 1);
+        let too_large = Layout::from_size_align(isize::MAX as usize - 7, 8).unwrap();
+        assert!(allocator.alloc(too_large).is_null());
+    }
+}
@@ -0,0 +1,110 @@
+// Logging facade
+// Messages at or above the global level go to the host's `log_msg(level, ptr, len)` import in the
+// browser and to stderr natively.  Formatting is skipped entirely for disabled levels, so logging
+// calls can stay in the render path.  Size-optimized builds drop info and debug messages at
+// compile time, along with the code that formats them.
+
+#[cfg(test)]
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl LogLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+/// Most verbose level that's logged, where 0 turns logging off
+static MAX_LEVEL: AtomicU32 = AtomicU32::new(LogLevel::Warn as u32);
+
+pub fn set_max_level(level: u32) {
+    MAX_LEVEL.store(level.min(LogLevel::Debug as u32), Ordering::Relaxed);
+}
+
+#[inline]
+pub fn enabled(level: LogLevel) -> bool {
+    if cfg!(feature = "small-binary") && level > LogLevel::Warn {
+        return false;
+    }
+    level as u32 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+extern "C" {
+    #[cfg(target_arch = "wasm32")]
+    fn log_msg(level: u32, ptr: *const u8, len: usize);
+}
+
+/// Log a message, e.g. `log(LogLevel::Info, format_args!("loaded {} samples", len))`
+#[inline]
+pub fn log(level: LogLevel, args: fmt::Arguments) {
+    if enabled(level) {
+        write(level, args);
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Messages written on this thread, for tests to check what got through
+    static WRITTEN: RefCell<Vec<(LogLevel, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn write(level: LogLevel, args: fmt::Arguments) {
+    let msg = args.to_string();
+    #[cfg(test)]
+    WRITTEN.with_borrow_mut(|written| written.push((level, msg.clone())));
+    #[cfg(target_arch = "wasm32")]
+    unsafe {
+        log_msg(level as u32, msg.as_ptr(), msg.len());
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("[{}] {}", level.name(), msg);
+}
+
+#[test]
+fn messages_below_the_level_are_dropped() {
+    let levels = [
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+    ];
+    let logged_at = |max_level: u32| {
+        set_max_level(max_level);
+        for level in levels {
+            log(level, format_args!("{}", level.name()));
+        }
+        WRITTEN.take()
+    };
+
+    assert_eq!(logged_at(0), []);
+    assert_eq!(
+        logged_at(LogLevel::Warn as u32),
+        [
+            (LogLevel::Error, "error".to_string()),
+            (LogLevel::Warn, "warn".to_string())
+        ]
+    );
+    let all = logged_at(LogLevel::Debug as u32);
+    let expected = if cfg!(feature = "small-binary") { 2 } else { 4 };
+    assert_eq!(all.len(), expected);
+    for (level, msg) in &all {
+        assert_eq!(msg, level.name());
+    }
+    // Levels past the most verbose one log everything
+    assert_eq!(logged_at(100), all);
+    set_max_level(LogLevel::Warn as u32);
+}
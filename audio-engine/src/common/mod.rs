@@ -1,30 +1,51 @@
 // Common utilities module
 // Provides shared utilities for initialization, logging, and random number generation
 
+#[cfg(any(feature = "small-binary", test))]
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+mod alloc;
+pub mod log;
+
 use rand::{rngs::StdRng, SeedableRng};
-use std::cell::RefCell;
-
-/// Macro for safely getting a mutable reference to a static mutable variable
-#[macro_export]
-macro_rules! ref_static_mut {
-    ($name:ident) => {
-        unsafe { &mut $name }
-    };
+
+// Size-optimized builds swap in the much smaller size-class allocator, which assumes a single
+// thread
+#[cfg(all(
+    feature = "small-binary",
+    target_arch = "wasm32",
+    not(target_feature = "atomics")
+))]
+#[global_allocator]
+static ALLOCATOR: alloc::SizeClassAllocator = alloc::SizeClassAllocator::new();
+
+/// Get a new random number generator seeded from the system's entropy source
+/// Generators are owned by whatever uses them rather than shared through thread-locals, so an
+/// instance draws the same numbers whichever thread it renders on
+pub fn rng() -> StdRng {
+    StdRng::from_entropy()
 }
 
-thread_local! {
-    static RNG: RefCell<Option<StdRng>> = RefCell::new(None);
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(catch, js_namespace = performance, js_name = now)]
+    fn performance_now() -> Result<f64, wasm_bindgen::JsValue>;
 }
 
-/// Get or initialize the thread-local random number generator
-pub fn rng() -> StdRng {
-    RNG.with(|rng_cell| {
-        let mut rng_opt = rng_cell.borrow_mut();
-        if rng_opt.is_none() {
-            *rng_opt = Some(StdRng::from_entropy());
-        }
-        rng_opt.as_ref().unwrap().clone()
-    })
+/// Milliseconds from an arbitrary starting point, for measuring durations
+/// Uses `performance.now()` in the browser; returns 0 in scopes that don't provide it
+#[cfg(target_arch = "wasm32")]
+pub fn now_ms() -> f64 {
+    performance_now().unwrap_or(0.)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now_ms() -> f64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.
 }
 
 /// Initialize WASM bindgen and other common systems
@@ -33,27 +54,15 @@ pub fn maybe_init(sample_rate: Option<f32>) {
     let _ = sample_rate;
 }
 
-// Set a custom panic hook that logs to JavaScript console
-// Uses an external JavaScript function to log errors
-extern "C" {
-    #[cfg(target_arch = "wasm32")]
-    fn log_err(ptr: *const u8, len: usize);
-}
-
+// Set a custom panic hook that logs panics as errors
+// Size-optimized builds log that there was a panic but not its message and location, which keeps
+// the code that formats them out of the binary
 pub fn set_raw_panic_hook() {
     std::panic::set_hook(Box::new(|panic_info| {
-        #[cfg(target_arch = "wasm32")]
-        {
-            let msg = format!("Panic: {:?}", panic_info);
-            let msg_bytes = msg.as_bytes();
-            unsafe {
-                log_err(msg_bytes.as_ptr(), msg_bytes.len());
-            }
-        }
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            eprintln!("{}", panic_info);
+        if cfg!(feature = "small-binary") {
+            log::log(log::LogLevel::Error, format_args!("Panic"));
+        } else {
+            log::log(log::LogLevel::Error, format_args!("Panic: {}", panic_info));
         }
     }));
 }
-
@@ -0,0 +1,103 @@
+// Attack/decay/sustain/release envelope generator
+// Unipolar, with linear segments.  Retriggering starts the attack from the current level so that
+// it never jumps.
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Adsr {
+    pub attack_ms: f32,
+    pub decay_ms: f32,
+    /// Level held while the gate is open once the decay is over, from 0 to 1
+    pub sustain: f32,
+    pub release_ms: f32,
+    stage: Stage,
+    value: f32,
+    /// Level the release started from, so that it takes `release_ms` from any level
+    release_start: f32,
+}
+
+impl Adsr {
+    pub fn new(attack_ms: f32, decay_ms: f32, sustain: f32, release_ms: f32) -> Self {
+        Adsr {
+            attack_ms,
+            decay_ms,
+            sustain,
+            release_ms,
+            stage: Stage::Idle,
+            value: 0.,
+            release_start: 0.,
+        }
+    }
+
+    /// Start the attack
+    pub fn trigger(&mut self) {
+        self.stage = Stage::Attack;
+    }
+
+    /// Start the release from wherever the envelope is
+    pub fn release(&mut self) {
+        if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+            self.release_start = self.value;
+        }
+    }
+
+    /// Whether the gate is open, i.e. the envelope was triggered and not released since
+    pub fn is_gated(&self) -> bool {
+        matches!(self.stage, Stage::Attack | Stage::Decay | Stage::Sustain)
+    }
+
+    /// Advance the envelope by one sample
+    /// Returns the new level
+    pub fn tick(&mut self, sample_rate: f32) -> f32 {
+        let step = |time_ms: f32| 1000. / (time_ms.max(0.01) * sample_rate);
+        match self.stage {
+            Stage::Idle => {}
+            Stage::Attack => {
+                self.value += step(self.attack_ms);
+                if self.value >= 1. {
+                    self.value = 1.;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.value -= step(self.decay_ms) * (1. - self.sustain);
+                if self.value <= self.sustain {
+                    self.value = self.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => self.value = self.sustain,
+            Stage::Release => {
+                self.value -= step(self.release_ms) * self.release_start;
+                if self.value <= 0. {
+                    self.value = 0.;
+                    self.stage = Stage::Idle;
+                }
+            }
+        }
+        self.value
+    }
+}
+
+#[test]
+fn adsr_goes_through_its_stages() {
+    // At 1 kHz every millisecond is one sample
+    let mut adsr = Adsr::new(2., 2., 0.5, 4.);
+    assert_eq!(adsr.tick(1000.), 0.);
+    adsr.trigger();
+    let levels: Vec<f32> = (0..6).map(|_| adsr.tick(1000.)).collect();
+    assert_eq!(levels, vec![0.5, 1., 0.75, 0.5, 0.5, 0.5]);
+    adsr.release();
+    assert!(!adsr.is_gated());
+    let levels: Vec<f32> = (0..5).map(|_| adsr.tick(1000.)).collect();
+    assert_eq!(levels, vec![0.375, 0.25, 0.125, 0., 0.]);
+}
@@ -0,0 +1,220 @@
+// Partitioned convolution
+// Uniformly partitioned overlap-save: the impulse response is cut into blocks, each transformed
+// once when it's loaded, and every block of input is transformed and kept in a frequency-domain
+// delay line.  The spectrum of each output block is the sum of the last input spectra times the
+// partitions they line up with, so long responses cost a multiply-add per partition instead of one
+// per sample of the response.  Output lags the input by one block.
+//
+// Input and response are real, so only the bins up to the middle of each spectrum are kept and
+// multiplied, and the rest are mirrored from them before the inverse transform.
+
+use super::fft::Fft;
+
+/// Spectra of the partitions of an impulse response, shared by every channel convolved with it
+#[derive(Clone)]
+pub struct ImpulseResponse {
+    block_len: usize,
+    /// Spectrum of each partition in turn, the real parts of its `block_len + 1` bins followed by
+    /// the imaginary ones
+    spectra: Vec<f32>,
+}
+
+impl ImpulseResponse {
+    /// Values in the spectrum of one partition of `block_len` samples
+    pub const fn partition_len(block_len: usize) -> usize {
+        2 * (block_len + 1)
+    }
+
+    /// Partitions `samples` into blocks of `block_len`, a power of two, and transforms each
+    pub fn new(samples: &[f32], block_len: usize) -> Self {
+        let fft = Fft::new(2 * block_len);
+        let bins = block_len + 1;
+        let mut re = vec![0.; 2 * block_len];
+        let mut im = vec![0.; 2 * block_len];
+        let mut spectra =
+            Vec::with_capacity(samples.len().div_ceil(block_len) * Self::partition_len(block_len));
+        for chunk in samples.chunks(block_len) {
+            re.fill(0.);
+            im.fill(0.);
+            re[..chunk.len()].copy_from_slice(chunk);
+            fft.forward(&mut re, &mut im);
+            spectra.extend_from_slice(&re[..bins]);
+            spectra.extend_from_slice(&im[..bins]);
+        }
+        ImpulseResponse { block_len, spectra }
+    }
+
+    /// Takes the spectra of a response made by `new` with the same `block_len`, without
+    /// transforming anything.  Returns None unless they're a whole number of partitions, all
+    /// finite.
+    pub fn from_spectra(spectra: Vec<f32>, block_len: usize) -> Option<Self> {
+        let whole =
+            !spectra.is_empty() && spectra.len().is_multiple_of(Self::partition_len(block_len));
+        (whole && spectra.iter().all(|value| value.is_finite()))
+            .then_some(ImpulseResponse { block_len, spectra })
+    }
+
+    pub fn into_spectra(self) -> Vec<f32> {
+        self.spectra
+    }
+
+    pub fn partition_count(&self) -> usize {
+        self.spectra.len() / Self::partition_len(self.block_len)
+    }
+
+    /// Real and imaginary parts of the spectrum of each partition
+    fn partitions(&self) -> impl Iterator<Item = (&[f32], &[f32])> {
+        self.spectra
+            .chunks_exact(Self::partition_len(self.block_len))
+            .map(|partition| partition.split_at(self.block_len + 1))
+    }
+}
+
+/// State of one channel convolved with an `ImpulseResponse`
+#[derive(Clone)]
+pub struct Convolver {
+    fft: Fft,
+    /// The last two blocks of input, the older first
+    input: Vec<f32>,
+    /// Output of the block being played
+    output: Vec<f32>,
+    /// Position in the current block
+    pos: usize,
+    /// Lower halves of the spectra of the latest input windows, as many as there are partitions,
+    /// in a loop
+    history: Vec<(Vec<f32>, Vec<f32>)>,
+    /// Index in `history` of the latest spectrum
+    latest: usize,
+    /// Sum of the products of the lower halves of the spectra
+    sum_re: Vec<f32>,
+    sum_im: Vec<f32>,
+    /// Scratch for the whole spectra being transformed
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl Convolver {
+    pub fn new(response: &ImpulseResponse) -> Self {
+        let block_len = response.block_len;
+        let fft_len = 2 * block_len;
+        let bins = block_len + 1;
+        Convolver {
+            fft: Fft::new(fft_len),
+            input: vec![0.; fft_len],
+            output: vec![0.; block_len],
+            pos: 0,
+            history: vec![(vec![0.; bins], vec![0.; bins]); response.partition_count()],
+            latest: 0,
+            sum_re: vec![0.; bins],
+            sum_im: vec![0.; bins],
+            re: vec![0.; fft_len],
+            im: vec![0.; fft_len],
+        }
+    }
+
+    /// Takes one input sample and returns one output sample, a block later.  `response` must be
+    /// the one the convolver was made for.
+    pub fn process(&mut self, sample: f32, response: &ImpulseResponse) -> f32 {
+        let block_len = response.block_len;
+        if self.history.is_empty() {
+            return 0.;
+        }
+        let output = self.output[self.pos];
+        self.input[block_len + self.pos] = if sample.is_finite() { sample } else { 0. };
+        self.pos += 1;
+        if self.pos == block_len {
+            self.pos = 0;
+            self.convolve_block(response);
+        }
+        output
+    }
+
+    /// Silences the input, the output and the spectra the next blocks would be made of
+    pub fn reset(&mut self) {
+        self.input.fill(0.);
+        self.output.fill(0.);
+        self.pos = 0;
+        for (re, im) in &mut self.history {
+            re.fill(0.);
+            im.fill(0.);
+        }
+    }
+
+    fn convolve_block(&mut self, response: &ImpulseResponse) {
+        let block_len = response.block_len;
+        let bins = block_len + 1;
+        let count = self.history.len();
+        self.latest = (self.latest + 1) % count;
+        self.re.copy_from_slice(&self.input);
+        self.im.fill(0.);
+        self.fft.forward(&mut self.re, &mut self.im);
+        let (re, im) = &mut self.history[self.latest];
+        re.copy_from_slice(&self.re[..bins]);
+        im.copy_from_slice(&self.im[..bins]);
+        self.input.copy_within(block_len.., 0);
+
+        self.sum_re.fill(0.);
+        self.sum_im.fill(0.);
+        for (age, (h_re, h_im)) in response.partitions().enumerate() {
+            let (x_re, x_im) = &self.history[(self.latest + count - age) % count];
+            for bin in 0..bins {
+                self.sum_re[bin] += x_re[bin] * h_re[bin] - x_im[bin] * h_im[bin];
+                self.sum_im[bin] += x_re[bin] * h_im[bin] + x_im[bin] * h_re[bin];
+            }
+        }
+        self.re[..bins].copy_from_slice(&self.sum_re);
+        self.im[..bins].copy_from_slice(&self.sum_im);
+        // The spectrum of a real signal is conjugate symmetric
+        for bin in 1..block_len {
+            self.re[2 * block_len - bin] = self.sum_re[bin];
+            self.im[2 * block_len - bin] = -self.sum_im[bin];
+        }
+        self.fft.inverse(&mut self.re, &mut self.im);
+        // The first half wrapped around from the end of the window and is discarded
+        self.output.copy_from_slice(&self.re[block_len..]);
+        if !self.output.iter().all(|sample| sample.is_finite()) {
+            self.output.fill(0.);
+        }
+    }
+}
+
+#[test]
+fn convolution_matches_direct_convolution() {
+    let response: Vec<f32> = (0..37).map(|ix| ((ix * 7) % 11) as f32 - 5.).collect();
+    let input: Vec<f32> = (0..100).map(|ix| ((ix * 3) % 7) as f32 - 3.).collect();
+    let impulse_response = ImpulseResponse::new(&response, 8);
+    let mut convolver = Convolver::new(&impulse_response);
+    let output: Vec<f32> = input
+        .iter()
+        .chain(&[0.; 8])
+        .map(|&sample| convolver.process(sample, &impulse_response))
+        .collect();
+    for ix in 0..input.len() {
+        let direct: f32 = (0..response.len().min(ix + 1))
+            .map(|k| response[k] * input[ix - k])
+            .sum();
+        // One block late
+        assert!((output[ix + 8] - direct).abs() < 1e-3, "{}", ix);
+    }
+}
+
+#[test]
+fn spectra_are_taken_back_without_transforming() {
+    let response = ImpulseResponse::new(&[1., 0.5, 0.25], 4);
+    assert_eq!(response.partition_count(), 1);
+    let spectra = response.into_spectra();
+    assert_eq!(spectra.len(), ImpulseResponse::partition_len(4));
+    assert!(ImpulseResponse::from_spectra(spectra[1..].to_vec(), 4).is_none());
+    assert!(ImpulseResponse::from_spectra(vec![f32::NAN; 10], 4).is_none());
+    assert!(ImpulseResponse::from_spectra(Vec::new(), 4).is_none());
+
+    let response = ImpulseResponse::from_spectra(spectra, 4).unwrap();
+    let mut convolver = Convolver::new(&response);
+    let output: Vec<f32> = [1., 0., 0., 0., 0., 0., 0., 0.]
+        .iter()
+        .map(|&sample| convolver.process(sample, &response))
+        .collect();
+    for (sample, expected) in output.iter().zip([0., 0., 0., 0., 1., 0.5, 0.25, 0.]) {
+        assert!((sample - expected).abs() < 1e-5);
+    }
+}
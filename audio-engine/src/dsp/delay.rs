@@ -0,0 +1,125 @@
+// Delay lines
+// A stereo feedback delay, and a mono one read at fractional delays for modulating its length.
+// Delay times are in samples so that callers convert from milliseconds at whatever sample rate
+// they run at.
+
+use super::{clamp, read_interpolated_with, EndPolicy, Float};
+
+#[derive(Clone, Default)]
+pub struct StereoDelay<T = f32> {
+    left: Vec<T>,
+    right: Vec<T>,
+    write_ix: usize,
+}
+
+impl<T: Float> StereoDelay<T> {
+    pub fn new(max_delay_samples: usize) -> Self {
+        let len = max_delay_samples + 1;
+        StereoDelay {
+            left: vec![T::ZERO; len],
+            right: vec![T::ZERO; len],
+            write_ix: 0,
+        }
+    }
+
+    /// Longest delay the buffers can hold
+    pub fn max_delay_samples(&self) -> usize {
+        self.left.len().saturating_sub(1)
+    }
+
+    /// Silence everything in the delay line
+    pub fn clear(&mut self) {
+        self.left.fill(T::ZERO);
+        self.right.fill(T::ZERO);
+    }
+
+    /// Process one stereo sample, feeding `feedback` of the delayed signal back in
+    /// Returns the delayed sample
+    pub fn process(&mut self, (left, right): (T, T), delay_samples: usize, feedback: T) -> (T, T) {
+        let len = self.left.len();
+        if len == 0 {
+            return (T::ZERO, T::ZERO);
+        }
+        let delay_samples = delay_samples.clamp(1, self.max_delay_samples().max(1));
+        let read_ix = (self.write_ix + len - delay_samples) % len;
+        let delayed = (self.left[read_ix], self.right[read_ix]);
+        self.left[self.write_ix] = left + delayed.0 * feedback;
+        self.right[self.write_ix] = right + delayed.1 * feedback;
+        self.write_ix = (self.write_ix + 1) % len;
+        delayed
+    }
+}
+
+/// Mono delay line read between samples, whose delay can change every sample.  Sweeping the delay
+/// bends the pitch of what comes out, as in vibrato and tape wow.
+#[derive(Clone, Default)]
+pub struct ModulatedDelay<T = f32> {
+    buffer: Vec<T>,
+    write_ix: usize,
+}
+
+impl<T: Float> ModulatedDelay<T> {
+    pub fn new(max_delay_samples: usize) -> Self {
+        ModulatedDelay {
+            buffer: vec![T::ZERO; max_delay_samples + 2],
+            write_ix: 0,
+        }
+    }
+
+    /// Longest delay the buffer can hold
+    pub fn max_delay_samples(&self) -> T {
+        T::from_usize(self.buffer.len().saturating_sub(2))
+    }
+
+    /// Silence everything in the delay line
+    pub fn clear(&mut self) {
+        self.buffer.fill(T::ZERO);
+    }
+
+    /// Writes one sample and returns the one from `delay_samples` ago, where a delay of 0 returns
+    /// the sample just written
+    pub fn process(&mut self, input: T, delay_samples: T) -> T {
+        self.write(input);
+        self.read(delay_samples)
+    }
+
+    /// Writes one sample without reading, for delays read at more than one tap
+    pub fn write(&mut self, input: T) {
+        let len = self.buffer.len();
+        if len == 0 {
+            return;
+        }
+        self.buffer[self.write_ix] = if input.is_finite() { input } else { T::ZERO };
+        self.write_ix = (self.write_ix + 1) % len;
+    }
+
+    /// The sample from `delay_samples` before the last one written
+    pub fn read(&self, delay_samples: T) -> T {
+        if self.buffer.is_empty() {
+            return T::ZERO;
+        }
+        let delay = clamp(T::ZERO, self.max_delay_samples(), delay_samples);
+        let read_ix = T::from_usize(self.write_ix) - T::ONE - delay;
+        read_interpolated_with(&self.buffer, read_ix, EndPolicy::Wrap)
+    }
+}
+
+#[test]
+fn delay_repeats_with_feedback() {
+    let mut delay = StereoDelay::new(4);
+    let mut output = Vec::new();
+    for ix in 0..10 {
+        let input = if ix == 0 { (1., -1.) } else { (0., 0.) };
+        output.push(delay.process(input, 3, 0.5).0);
+    }
+    assert_eq!(output, vec![0., 0., 0., 1., 0., 0., 0.5, 0., 0., 0.25]);
+}
+
+#[test]
+fn modulated_delay_reads_between_samples() {
+    let mut delay = ModulatedDelay::new(4);
+    let output: Vec<f32> = (0..6).map(|ix| delay.process(ix as f32, 1.5)).collect();
+    assert_eq!(output, vec![0., 0., 0.5, 1.5, 2.5, 3.5]);
+    // Delays past the end of the buffer are held at its length
+    assert_eq!(delay.process(6., 10.), 2.);
+}
@@ -0,0 +1,341 @@
+// Dynamics processors
+// Gain computers working on a single channel of audio, one sample at a time
+
+use super::{clamp, Float};
+
+/// Per-sample coefficient of a one-pole approach with the time constant `time_ms`
+fn time_coefficient<T: Float>(time_ms: T, sample_rate: T) -> T {
+    (-T::from_f64(1000.) / (time_ms.max(T::from_f64(0.01)) * sample_rate)).exp()
+}
+
+/// Peak limiter with instant attack and exponential release.  There's no lookahead, so the
+/// output never exceeds the threshold but fast transients are squashed rather than shaped.
+#[derive(Clone)]
+pub struct Limiter<T = f32> {
+    pub threshold: T,
+    release_ms: T,
+    release_coefficient: T,
+    gain: T,
+}
+
+impl<T: Float> Limiter<T> {
+    pub fn new(threshold: T, release_ms: T, sample_rate: T) -> Self {
+        let mut limiter = Limiter {
+            threshold,
+            release_ms,
+            release_coefficient: T::ZERO,
+            gain: T::ONE,
+        };
+        limiter.set_release(release_ms, sample_rate);
+        limiter
+    }
+
+    pub fn set_release(&mut self, release_ms: T, sample_rate: T) {
+        self.release_ms = release_ms;
+        self.release_coefficient = time_coefficient(release_ms, sample_rate);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: T) {
+        self.set_release(self.release_ms, sample_rate);
+    }
+
+    /// Process a sample through the limiter
+    /// Returns the limited sample
+    pub fn process(&mut self, sample: T) -> T {
+        let peak = sample.abs();
+        let target_gain = if peak > self.threshold {
+            self.threshold / peak
+        } else {
+            T::ONE
+        };
+
+        if target_gain < self.gain {
+            self.gain = target_gain;
+        } else {
+            self.gain = target_gain + (self.gain - target_gain) * self.release_coefficient;
+        }
+
+        sample * self.gain
+    }
+
+    /// Current gain reduction as a linear multiplier (1 = no reduction)
+    pub fn gain(&self) -> T {
+        self.gain
+    }
+
+    /// Current gain reduction in dB, positive while the limiter is reducing the level
+    pub fn gain_reduction_db(&self) -> T {
+        -T::from_f64(20.) * self.gain.max(T::from_f64(1e-6)).log10()
+    }
+
+    pub fn reset(&mut self) {
+        self.gain = T::ONE;
+    }
+}
+
+/// Peak envelope follower with separate attack and release times
+#[derive(Clone)]
+pub struct EnvelopeFollower<T = f32> {
+    attack_ms: T,
+    release_ms: T,
+    attack_coefficient: T,
+    release_coefficient: T,
+    envelope: T,
+}
+
+impl<T: Float> EnvelopeFollower<T> {
+    pub fn new(attack_ms: T, release_ms: T, sample_rate: T) -> Self {
+        let mut follower = EnvelopeFollower {
+            attack_ms,
+            release_ms,
+            attack_coefficient: T::ZERO,
+            release_coefficient: T::ZERO,
+            envelope: T::ZERO,
+        };
+        follower.set_times(attack_ms, release_ms, sample_rate);
+        follower
+    }
+
+    pub fn set_times(&mut self, attack_ms: T, release_ms: T, sample_rate: T) {
+        self.attack_ms = attack_ms;
+        self.release_ms = release_ms;
+        self.attack_coefficient = time_coefficient(attack_ms, sample_rate);
+        self.release_coefficient = time_coefficient(release_ms, sample_rate);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: T) {
+        self.set_times(self.attack_ms, self.release_ms, sample_rate);
+    }
+
+    /// Feeds one sample through the follower and returns the updated envelope
+    pub fn process(&mut self, sample: T) -> T {
+        let peak = if sample.is_finite() {
+            sample.abs()
+        } else {
+            T::ZERO
+        };
+        let coefficient = if peak > self.envelope {
+            self.attack_coefficient
+        } else {
+            self.release_coefficient
+        };
+        self.envelope = peak + (self.envelope - peak) * coefficient;
+        self.envelope
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = T::ZERO;
+    }
+}
+
+/// Width of the compressor's soft knee in dB, centered on the threshold
+const COMPRESSOR_KNEE_DB: f32 = 6.;
+
+/// Feed-forward compressor with a soft knee.  The level is followed on a detector signal passed
+/// in separately from the audio, which may be the audio itself or a key from elsewhere, so that
+/// one sound can duck another.
+#[derive(Clone)]
+pub struct Compressor<T = f32> {
+    pub threshold_db: T,
+    /// dB the level goes above the threshold for every dB the output does, 1 or more
+    pub ratio: T,
+    pub makeup_db: T,
+    follower: EnvelopeFollower<T>,
+    gain_reduction_db: T,
+}
+
+impl<T: Float> Compressor<T> {
+    pub fn new(
+        threshold_db: T,
+        ratio: T,
+        makeup_db: T,
+        attack_ms: T,
+        release_ms: T,
+        sample_rate: T,
+    ) -> Self {
+        Compressor {
+            threshold_db,
+            ratio,
+            makeup_db,
+            follower: EnvelopeFollower::new(attack_ms, release_ms, sample_rate),
+            gain_reduction_db: T::ZERO,
+        }
+    }
+
+    pub fn set_times(&mut self, attack_ms: T, release_ms: T, sample_rate: T) {
+        self.follower.set_times(attack_ms, release_ms, sample_rate);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: T) {
+        self.follower.set_sample_rate(sample_rate);
+    }
+
+    /// Feeds one sample of the detector signal through the follower
+    /// Returns the gain to apply to the audio as a linear multiplier
+    pub fn process(&mut self, sample: T) -> T {
+        let envelope = self.follower.process(sample);
+        let level_db = T::from_f64(20.) * envelope.max(T::from_f64(1e-6)).log10();
+        let over_db = level_db - self.threshold_db;
+        let knee_db = T::from_f64(COMPRESSOR_KNEE_DB as f64);
+        let half_knee_db = knee_db * T::from_f64(0.5);
+        let slope = T::ONE - T::ONE / self.ratio.max(T::ONE);
+        self.gain_reduction_db = if over_db <= -half_knee_db {
+            T::ZERO
+        } else if over_db >= half_knee_db {
+            slope * over_db
+        } else {
+            let into_knee = over_db + half_knee_db;
+            slope * into_knee * into_knee / (T::from_f64(2.) * knee_db)
+        };
+        let gain_db = self.makeup_db - self.gain_reduction_db;
+        (gain_db * T::from_f64(std::f64::consts::LN_10 / 20.)).exp()
+    }
+
+    /// Gain reduction of the last sample in dB, not counting the makeup gain
+    pub fn gain_reduction_db(&self) -> T {
+        self.gain_reduction_db
+    }
+
+    pub fn reset(&mut self) {
+        self.follower.reset();
+        self.gain_reduction_db = T::ZERO;
+    }
+}
+
+/// Largest boost or cut a transient shaper applies, in dB
+pub const MAX_SHAPER_GAIN_DB: f32 = 12.;
+
+const SHAPER_FAST_MS: f32 = 0.5;
+const SHAPER_SLOW_ATTACK_MS: f32 = 20.;
+const SHAPER_ATTACK_RELEASE_MS: f32 = 20.;
+const SHAPER_FAST_RELEASE_MS: f32 = 30.;
+const SHAPER_SLOW_RELEASE_MS: f32 = 300.;
+
+/// Attack and sustain shaper built from differential envelope followers.  A fast and a slow
+/// attack follower sharing a release part ways at every onset, and a fast and a slow release
+/// follower sharing an attack part ways as every sound decays.  The differences of their levels
+/// in dB pick out the attacks and the tails regardless of how loud they are, and scale the gain.
+#[derive(Clone)]
+pub struct TransientShaper<T = f32> {
+    /// From -1, softening attacks, to 1, emphasizing them
+    pub attack: T,
+    /// From -1, shortening tails, to 1, drawing them out
+    pub sustain: T,
+    fast_attack: EnvelopeFollower<T>,
+    slow_attack: EnvelopeFollower<T>,
+    fast_release: EnvelopeFollower<T>,
+    slow_release: EnvelopeFollower<T>,
+}
+
+impl<T: Float> TransientShaper<T> {
+    pub fn new(attack: T, sustain: T, sample_rate: T) -> Self {
+        let follower = |attack_ms: f32, release_ms: f32| {
+            EnvelopeFollower::new(
+                T::from_f64(attack_ms as f64),
+                T::from_f64(release_ms as f64),
+                sample_rate,
+            )
+        };
+        TransientShaper {
+            attack,
+            sustain,
+            fast_attack: follower(SHAPER_FAST_MS, SHAPER_ATTACK_RELEASE_MS),
+            slow_attack: follower(SHAPER_SLOW_ATTACK_MS, SHAPER_ATTACK_RELEASE_MS),
+            fast_release: follower(SHAPER_FAST_MS, SHAPER_FAST_RELEASE_MS),
+            slow_release: follower(SHAPER_FAST_MS, SHAPER_SLOW_RELEASE_MS),
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: T) {
+        self.fast_attack.set_sample_rate(sample_rate);
+        self.slow_attack.set_sample_rate(sample_rate);
+        self.fast_release.set_sample_rate(sample_rate);
+        self.slow_release.set_sample_rate(sample_rate);
+    }
+
+    /// Feeds one sample of the detector signal through the followers
+    /// Returns the gain to apply to the audio as a linear multiplier
+    pub fn process(&mut self, sample: T) -> T {
+        let max_db = T::from_f64(MAX_SHAPER_GAIN_DB as f64);
+        let db = |envelope: T| T::from_f64(20.) * envelope.max(T::from_f64(1e-6)).log10();
+        let attack_db = db(self.fast_attack.process(sample)) - db(self.slow_attack.process(sample));
+        let sustain_db =
+            db(self.slow_release.process(sample)) - db(self.fast_release.process(sample));
+        let gain_db = self.attack * clamp(T::ZERO, max_db, attack_db)
+            + self.sustain * clamp(T::ZERO, max_db, sustain_db);
+        let gain_db = clamp(-max_db, max_db, gain_db);
+        (gain_db * T::from_f64(std::f64::consts::LN_10 / 20.)).exp()
+    }
+
+    pub fn reset(&mut self) {
+        self.fast_attack.reset();
+        self.slow_attack.reset();
+        self.fast_release.reset();
+        self.slow_release.reset();
+    }
+}
+
+#[test]
+fn limiting_holds_peaks_at_the_threshold_and_releases() {
+    let sample_rate = 1000.;
+    let mut limiter = Limiter::new(0.5, 100., sample_rate);
+    assert_eq!(limiter.process(2.), 0.5);
+    assert_eq!(limiter.gain(), 0.25);
+    assert!((limiter.gain_reduction_db() - 12.04).abs() < 0.01);
+
+    // One release time later the gain has come back by 1 - 1/e of the way
+    for _ in 0..100 {
+        limiter.process(0.);
+    }
+    let expected = 1. - 0.75 * (-1f32).exp();
+    assert!(
+        (limiter.gain() - expected).abs() < 1e-3,
+        "{}",
+        limiter.gain()
+    );
+    assert_eq!(limiter.process(0.1), 0.1 * limiter.gain());
+
+    limiter.reset();
+    assert_eq!(limiter.process(0.4), 0.4);
+}
+
+#[test]
+fn compression_follows_the_detector() {
+    let mut compressor = Compressor::new(-20., 4., 0., 0.01, 50., 48000.);
+    // 0 dB is 20 dB over the threshold, which the ratio brings down to 5 dB over
+    for _ in 0..100 {
+        compressor.process(1.);
+    }
+    let gain = compressor.process(1.);
+    assert!((compressor.gain_reduction_db() - 15.).abs() < 0.01);
+    assert!((20. * gain.log10() + 15.).abs() < 0.01);
+    // Well under the knee there's no reduction, and the release lets it recover
+    for _ in 0..48000 {
+        compressor.process(0.01);
+    }
+    assert_eq!(compressor.gain_reduction_db(), 0.);
+    compressor.makeup_db = 6.;
+    assert!((compressor.process(0.) - 1.995).abs() < 0.01);
+}
+
+#[test]
+fn shaping_boosts_attacks_and_cuts_tails() {
+    let sample_rate = 48000.;
+    // Plucked tones every 250 ms that decay with a 50 ms time constant
+    let pluck = |ix: usize| {
+        let t = (ix % 12000) as f32 / sample_rate;
+        (-t / 0.05).exp() * (ix as f32 * 0.5).sin()
+    };
+    let gains = |attack: f32, sustain: f32| {
+        let mut shaper = TransientShaper::new(attack, sustain, sample_rate);
+        let gains: Vec<f32> = (0..48000).map(|ix| shaper.process(pluck(ix))).collect();
+        // Just after the last onset, and in the middle of its tail
+        (gains[36000 + 48], gains[36000 + 6000])
+    };
+    let (onset, tail) = gains(0., 0.);
+    assert!((onset - 1.).abs() < 1e-4 && (tail - 1.).abs() < 1e-4);
+    let (onset, tail) = gains(1., 0.);
+    assert!(onset > 2. && (tail - 1.).abs() < 0.05, "{} {}", onset, tail);
+    let (onset, tail) = gains(0., -1.);
+    assert!(tail < 0.5 && (onset - 1.).abs() < 0.2, "{} {}", onset, tail);
+}
@@ -0,0 +1,158 @@
+// Fast Fourier transform
+// In-place iterative radix-2 FFT.  `fft` doesn't allocate but recomputes its twiddle factors, which
+// is fine for analysis off the audio thread.  Transforms on the audio thread use an `Fft` instead,
+// which computes them once for its length.
+
+use std::f32::consts::PI;
+
+/// Transforms `re` and `im` in place.  Both must have the same power-of-two length.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let angle = -2. * PI / re.len() as f32;
+    transform(re, im, |k| (angle * k as f32).sin_cos());
+}
+
+/// Twiddle factors for transforms of one length
+#[derive(Clone, Debug)]
+pub struct Fft {
+    /// `(sin, cos)` of `-2 pi k / len` for the first half of the bins
+    twiddles: Vec<(f32, f32)>,
+}
+
+impl Fft {
+    /// `len` must be a power of two
+    pub fn new(len: usize) -> Self {
+        let angle = -2. * PI / len as f32;
+        Fft {
+            twiddles: (0..len / 2).map(|k| (angle * k as f32).sin_cos()).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.twiddles.len() * 2
+    }
+
+    /// Like `fft`, for `re` and `im` of this transform's length
+    pub fn forward(&self, re: &mut [f32], im: &mut [f32]) {
+        assert_eq!(re.len(), self.len());
+        transform(re, im, |k| self.twiddles[k]);
+    }
+
+    /// Inverse of `forward`, including the division by the length.  The inverse transform is the
+    /// conjugate of the transform of the conjugate.
+    pub fn inverse(&self, re: &mut [f32], im: &mut [f32]) {
+        im.iter_mut().for_each(|im| *im = -*im);
+        self.forward(re, im);
+        scale_inverse(re, im);
+    }
+}
+
+fn scale_inverse(re: &mut [f32], im: &mut [f32]) {
+    let len = re.len() as f32;
+    re.iter_mut().for_each(|re| *re /= len);
+    im.iter_mut().for_each(|im| *im /= -len);
+}
+
+/// Radix-2 transform with `twiddle(k)` giving `(sin, cos)` of `-2 pi k / len`
+#[inline]
+fn transform(re: &mut [f32], im: &mut [f32], twiddle: impl Fn(usize) -> (f32, f32)) {
+    let len = re.len();
+    assert!(len.is_power_of_two() && im.len() == len);
+
+    // Reorder into bit-reversed index order
+    let mut j = 0;
+    for i in 1..len {
+        let mut bit = len >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut span = 2;
+    while span <= len {
+        // Twiddle k of a span is twiddle k * len / span of the whole length
+        let stride = len / span;
+        for start in (0..len).step_by(span) {
+            for k in 0..span / 2 {
+                let (twiddle_im, twiddle_re) = twiddle(k * stride);
+                let (a, b) = (start + k, start + k + span / 2);
+                let b_re = re[b] * twiddle_re - im[b] * twiddle_im;
+                let b_im = re[b] * twiddle_im + im[b] * twiddle_re;
+                re[b] = re[a] - b_re;
+                im[b] = im[a] - b_im;
+                re[a] += b_re;
+                im[a] += b_im;
+            }
+        }
+        span *= 2;
+    }
+}
+
+/// Magnitude-weighted mean frequency of `samples`, Hann windowed.  `samples.len()` must be a
+/// power of two.  Returns 0 for silence.
+pub fn spectral_centroid(samples: &[f32], sample_rate: f32) -> f32 {
+    let len = samples.len();
+    let mut re: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            let window = 0.5 - 0.5 * (2. * PI * i as f32 / len as f32).cos();
+            if sample.is_finite() {
+                sample * window
+            } else {
+                0.
+            }
+        })
+        .collect();
+    let mut im = vec![0.; len];
+    fft(&mut re, &mut im);
+
+    let bin_hz = sample_rate / len as f32;
+    let (weighted_sum, magnitude_sum) =
+        (1..len / 2).fold((0., 0.), |(weighted_sum, magnitude_sum), bin| {
+            let magnitude = re[bin].hypot(im[bin]);
+            (
+                weighted_sum + magnitude * bin as f32 * bin_hz,
+                magnitude_sum + magnitude,
+            )
+        });
+    if magnitude_sum <= 1e-9 {
+        0.
+    } else {
+        weighted_sum / magnitude_sum
+    }
+}
+
+#[test]
+fn sine_centroid_matches_its_frequency() {
+    let sample_rate = 48000.;
+    let samples: Vec<f32> = (0..2048)
+        .map(|i| (2. * PI * 3000. * i as f32 / sample_rate).sin())
+        .collect();
+    let centroid = spectral_centroid(&samples, sample_rate);
+    assert!((centroid - 3000.).abs() < 100., "{}", centroid);
+    assert_eq!(spectral_centroid(&[0.; 256], sample_rate), 0.);
+}
+
+#[test]
+fn cached_twiddles_match_the_plain_transform() {
+    let signal: Vec<f32> = (0..512).map(|i| (i as f32 * 0.3).sin() + 0.1).collect();
+    let (mut re, mut im) = (signal.clone(), vec![0.; 512]);
+    fft(&mut re, &mut im);
+    let plan = Fft::new(512);
+    let (mut cached_re, mut cached_im) = (signal.clone(), vec![0.; 512]);
+    plan.forward(&mut cached_re, &mut cached_im);
+    for bin in 0..512 {
+        assert!((re[bin] - cached_re[bin]).abs() < 1e-3);
+        assert!((im[bin] - cached_im[bin]).abs() < 1e-3);
+    }
+    plan.inverse(&mut cached_re, &mut cached_im);
+    for (sample, original) in cached_re.iter().zip(&signal) {
+        assert!((sample - original).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,120 @@
+// Allpass filters
+// First and second-order allpass sections, discretized with the bilinear transform.  They pass
+// every frequency at unity gain and only shift its phase: the first-order section by 90 degrees
+// at its corner frequency and the second-order one by 180 degrees at its centre frequency, with a
+// Q setting how quickly the shift changes around it.
+
+use crate::dsp::clamp;
+
+/// Lowest corner in Hz; lower ones are raised to it
+const MIN_FREQUENCY_HZ: f32 = 20.0;
+/// Highest corner as a fraction of the sample rate, safely below Nyquist where the pre-warped
+/// frequency would go to infinity
+const MAX_FREQUENCY_RATIO: f32 = 0.49;
+
+fn angular_frequency(frequency_hz: f32, sample_rate: f32) -> f32 {
+    let frequency_hz = clamp(
+        MIN_FREQUENCY_HZ,
+        (sample_rate * MAX_FREQUENCY_RATIO).max(MIN_FREQUENCY_HZ),
+        frequency_hz,
+    );
+    std::f32::consts::PI * frequency_hz / sample_rate
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct FirstOrderAllpass {
+    x1: f32,
+    y1: f32,
+}
+
+impl FirstOrderAllpass {
+    /// Clears the filter's memory of previous samples
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Coefficient for a corner at `frequency_hz`.  It's computed apart from `process` so that a
+    /// chain of sections at the same frequency only computes it once.
+    pub fn coefficient(frequency_hz: f32, sample_rate: f32) -> f32 {
+        let t = angular_frequency(frequency_hz, sample_rate).tan();
+        (t - 1.0) / (t + 1.0)
+    }
+
+    pub fn process(&mut self, input: f32, coefficient: f32) -> f32 {
+        let output = coefficient * input + self.x1 - coefficient * self.y1;
+        if !output.is_finite() {
+            self.reset();
+            return 0.0;
+        }
+        self.x1 = input;
+        self.y1 = output;
+        output
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SecondOrderAllpass {
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl SecondOrderAllpass {
+    /// Clears the filter's memory of previous samples
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Coefficients for a centre at `frequency_hz`, computed apart from `process` like the
+    /// first-order section's
+    pub fn coefficients(frequency_hz: f32, q: f32, sample_rate: f32) -> (f32, f32) {
+        let omega = 2.0 * angular_frequency(frequency_hz, sample_rate);
+        let alpha = omega.sin() / (2.0 * q.max(0.01));
+        let a0 = 1.0 + alpha;
+        ((1.0 - alpha) / a0, -2.0 * omega.cos() / a0)
+    }
+
+    pub fn process(&mut self, input: f32, (b0, b1): (f32, f32)) -> f32 {
+        // An allpass's numerator is its denominator reversed, so b2 = 1 and a1 = b1, a2 = b0
+        let output = b0 * input + b1 * self.x1 + self.x2 - b1 * self.y1 - b0 * self.y2;
+        if !output.is_finite() {
+            self.reset();
+            return 0.0;
+        }
+        self.x2 = self.x1;
+        self.x1 = input;
+        self.y2 = self.y1;
+        self.y1 = output;
+        output
+    }
+}
+
+#[test]
+fn allpass_sections_keep_the_level_and_shift_the_phase() {
+    let sample_rate = 48000.;
+    let impulse = |ix: usize| if ix == 0 { 1. } else { 0. };
+    let mut first = FirstOrderAllpass::default();
+    let coefficient = FirstOrderAllpass::coefficient(1000., sample_rate);
+    let mut second = SecondOrderAllpass::default();
+    let coefficients = SecondOrderAllpass::coefficients(1000., 0.7, sample_rate);
+    let (mut first_energy, mut second_energy) = (0., 0.);
+    for ix in 0..48000 {
+        first_energy += first.process(impulse(ix), coefficient).powi(2);
+        second_energy += second.process(impulse(ix), coefficients).powi(2);
+    }
+    assert!((first_energy - 1.).abs() < 1e-3, "{}", first_energy);
+    assert!((second_energy - 1.).abs() < 1e-3, "{}", second_energy);
+
+    // At the second-order section's centre a sine comes out inverted
+    second.reset();
+    let sine = |ix: usize| (std::f32::consts::TAU * 1000. * ix as f32 / sample_rate).sin();
+    let mut correlation = 0.;
+    for ix in 0..9600 {
+        let output = second.process(sine(ix), coefficients);
+        if ix >= 4800 {
+            correlation += output * sine(ix) / 2400.;
+        }
+    }
+    assert!((correlation + 1.).abs() < 0.01, "{}", correlation);
+}
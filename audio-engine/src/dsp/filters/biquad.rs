@@ -0,0 +1,172 @@
+// Generic biquad section
+// Transposed direct form II with coefficients normalized so that a0 = 1
+// Lowpass, shelf and peaking coefficients follow the RBJ audio EQ cookbook, with shelf slopes of 1
+
+use crate::dsp::Float;
+
+/// Highest centre or corner frequency as a fraction of the sample rate, safely below Nyquist
+const MAX_FREQUENCY_RATIO: f64 = 0.49;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BiquadCoefficients<T = f32> {
+    pub b0: T,
+    pub b1: T,
+    pub b2: T,
+    pub a1: T,
+    pub a2: T,
+}
+
+impl<T: Float> BiquadCoefficients<T> {
+    /// Passes everything below `frequency_hz`, with a resonance that grows with `q`
+    pub fn lowpass(frequency_hz: f32, q: f32, sample_rate: f32) -> Self {
+        let (cos, alpha) = Self::angle(frequency_hz, q as f64, sample_rate);
+        Self::normalized(
+            [(1. - cos) / 2., 1. - cos, (1. - cos) / 2.],
+            [1. + alpha, -2. * cos, 1. - alpha],
+        )
+    }
+
+    /// Boosts or cuts everything below `frequency_hz` by `gain_db`
+    pub fn low_shelf(frequency_hz: f32, gain_db: f32, sample_rate: f32) -> Self {
+        Self::shelf(frequency_hz, gain_db, sample_rate, -1.)
+    }
+
+    /// Boosts or cuts everything above `frequency_hz` by `gain_db`
+    pub fn high_shelf(frequency_hz: f32, gain_db: f32, sample_rate: f32) -> Self {
+        Self::shelf(frequency_hz, gain_db, sample_rate, 1.)
+    }
+
+    /// Boosts or cuts around `frequency_hz` by `gain_db`, over a band that narrows as `q` grows
+    pub fn peaking(frequency_hz: f32, gain_db: f32, q: f32, sample_rate: f32) -> Self {
+        let (cos, alpha) = Self::angle(frequency_hz, q as f64, sample_rate);
+        let a = 10f64.powf(gain_db as f64 / 40.);
+        Self::normalized(
+            [1. + alpha * a, -2. * cos, 1. - alpha * a],
+            [1. + alpha / a, -2. * cos, 1. - alpha / a],
+        )
+    }
+
+    /// `sign` is -1 for a low shelf and 1 for a high one
+    fn shelf(frequency_hz: f32, gain_db: f32, sample_rate: f32, sign: f64) -> Self {
+        let (cos, alpha) = Self::angle(frequency_hz, std::f64::consts::FRAC_1_SQRT_2, sample_rate);
+        let a = 10f64.powf(gain_db as f64 / 40.);
+        let root = 2. * a.sqrt() * alpha;
+        Self::normalized(
+            [
+                a * ((a + 1.) + sign * (a - 1.) * cos + root),
+                -2. * sign * a * ((a - 1.) + sign * (a + 1.) * cos),
+                a * ((a + 1.) + sign * (a - 1.) * cos - root),
+            ],
+            [
+                (a + 1.) - sign * (a - 1.) * cos + root,
+                2. * sign * ((a - 1.) - sign * (a + 1.) * cos),
+                (a + 1.) - sign * (a - 1.) * cos - root,
+            ],
+        )
+    }
+
+    /// Group delay at DC in samples: how far behind its input the output of slow changes is
+    pub fn dc_group_delay(&self) -> T {
+        let two = T::from_f64(2.);
+        (self.b1 + two * self.b2) / (self.b0 + self.b1 + self.b2)
+            - (self.a1 + two * self.a2) / (T::ONE + self.a1 + self.a2)
+    }
+
+    /// Cosine of the angular frequency and the bandwidth term for a filter at `frequency_hz`
+    fn angle(frequency_hz: f32, q: f64, sample_rate: f32) -> (f64, f64) {
+        let sample_rate = sample_rate as f64;
+        let frequency_hz = (frequency_hz as f64).clamp(1., sample_rate * MAX_FREQUENCY_RATIO);
+        let omega = 2. * std::f64::consts::PI * frequency_hz / sample_rate;
+        (omega.cos(), omega.sin() / (2. * q.max(0.01)))
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        BiquadCoefficients {
+            b0: T::from_f64(b[0] / a[0]),
+            b1: T::from_f64(b[1] / a[0]),
+            b2: T::from_f64(b[2] / a[0]),
+            a1: T::from_f64(a[1] / a[0]),
+            a2: T::from_f64(a[2] / a[0]),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Biquad<T = f32> {
+    pub coefficients: BiquadCoefficients<T>,
+    z1: T,
+    z2: T,
+}
+
+impl<T: Float> Biquad<T> {
+    pub fn new(coefficients: BiquadCoefficients<T>) -> Self {
+        Biquad {
+            coefficients,
+            z1: T::ZERO,
+            z2: T::ZERO,
+        }
+    }
+
+    pub fn process(&mut self, input: T) -> T {
+        let c = &self.coefficients;
+        let output = c.b0 * input + self.z1;
+        self.z1 = c.b1 * input - c.a1 * output + self.z2;
+        self.z2 = c.b2 * input - c.a2 * output;
+        if !output.is_finite() {
+            self.reset();
+            return T::ZERO;
+        }
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = T::ZERO;
+        self.z2 = T::ZERO;
+    }
+}
+
+#[test]
+fn shelves_and_peaks_boost_their_bands() {
+    let sample_rate = 48000.;
+    let level = |coefficients: BiquadCoefficients, frequency_hz: f32| {
+        let mut biquad = Biquad::new(coefficients);
+        (0..9600).fold(0f32, |peak, ix| {
+            let phase = std::f32::consts::TAU * frequency_hz * ix as f32 / sample_rate;
+            let output = biquad.process(phase.sin());
+            if ix >= 4800 {
+                peak.max(output.abs())
+            } else {
+                peak
+            }
+        })
+    };
+    let db = |gain: f32| 20. * gain.log10();
+    let low = BiquadCoefficients::low_shelf(200., 12., sample_rate);
+    assert!((db(level(low, 30.)) - 12.).abs() < 0.5);
+    assert!(db(level(low, 5000.)).abs() < 0.5);
+    let high = BiquadCoefficients::high_shelf(5000., -12., sample_rate);
+    assert!((db(level(high, 20000.)) + 12.).abs() < 0.5);
+    assert!(db(level(high, 100.)).abs() < 0.5);
+    let peak = BiquadCoefficients::peaking(1000., 6., 0.7, sample_rate);
+    assert!((db(level(peak, 1000.)) - 6.).abs() < 0.1);
+    assert!(db(level(peak, 50.)).abs() < 0.5);
+    let lowpass = BiquadCoefficients::lowpass(1000., std::f32::consts::FRAC_1_SQRT_2, sample_rate);
+    assert!(db(level(lowpass, 50.)).abs() < 0.1);
+    assert!((db(level(lowpass, 1000.)) + 3.).abs() < 0.1);
+    assert!(db(level(lowpass, 10000.)) < -35.);
+}
+
+#[test]
+fn group_delay_is_how_far_a_ramp_falls_behind() {
+    let coefficients = BiquadCoefficients::<f64>::lowpass(1000., 0.7, 48000.);
+    let mut biquad = Biquad::new(coefficients);
+    let output = (0..2000).fold(0., |_, ix| biquad.process(ix as f64));
+    let lag = 1999. - output;
+    let delay = coefficients.dc_group_delay();
+    assert!(
+        delay > 5. && (lag - delay).abs() < 0.01,
+        "{} {}",
+        lag,
+        delay
+    );
+}
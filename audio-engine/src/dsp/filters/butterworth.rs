@@ -1,5 +1,19 @@
 // Butterworth filter implementation
-// Based on standard second-order IIR filter design
+// Based on standard second-order IIR filter design, discretized with the bilinear transform
+
+/// Butterworth Q factor
+const Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+/// Lowest cutoff in Hz; lower ones are raised to it
+const MIN_CUTOFF_HZ: f64 = 20.0;
+/// Highest cutoff as a fraction of the sample rate, safely below Nyquist where the pre-warped
+/// cutoff would go to infinity
+const MAX_CUTOFF_RATIO: f64 = 0.49;
+
+#[derive(Clone, Copy)]
+enum FilterKind {
+    Lowpass,
+    Highpass,
+}
 
 #[derive(Clone)]
 pub struct ButterworthFilter {
@@ -8,6 +22,7 @@ pub struct ButterworthFilter {
     x2: f32,
     y1: f32,
     y2: f32,
+    sample_rate: f32,
 }
 
 impl Default for ButterworthFilter {
@@ -17,100 +32,109 @@ impl Default for ButterworthFilter {
             x2: 0.0,
             y1: 0.0,
             y2: 0.0,
+            sample_rate: 44100.0,
         }
     }
 }
 
 impl ButterworthFilter {
+    /// Clears the filter's memory of previous samples
+    pub fn reset(&mut self) {
+        *self = ButterworthFilter {
+            sample_rate: self.sample_rate,
+            ..Default::default()
+        };
+    }
+
+    /// Clears the filter state if it has become NaN or infinite so that a single bad input
+    /// sample can't poison all output from then on
+    fn recover_if_unstable(&mut self, output: f32) -> f32 {
+        if output.is_finite() {
+            return output;
+        }
+        self.reset();
+        0.0
+    }
+
+    /// Sets the sample rate that cutoffs are relative to
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Normalized biquad coefficients `[b0, b1, b2, a1, a2]` for a cutoff in Hz, or `None` for a
+    /// NaN cutoff.  The cutoff is clamped to a range where the filter stays stable and pre-warped
+    /// so that the bilinear transform puts it at the right frequency even close to Nyquist.
+    /// They're computed in double precision since low cutoffs need more than `f32` can hold.
+    fn coefficients(&self, kind: FilterKind, cutoff: f32) -> Option<[f32; 5]> {
+        if cutoff.is_nan() {
+            return None;
+        }
+        let sample_rate = self.sample_rate as f64;
+        let max_cutoff = sample_rate * MAX_CUTOFF_RATIO;
+        let cutoff = (cutoff as f64).max(MIN_CUTOFF_HZ).min(max_cutoff);
+        let k = (std::f64::consts::PI * cutoff / sample_rate).tan();
+        let k2 = k * k;
+        let norm = 1.0 / (1.0 + k / Q + k2);
+        let a1 = 2.0 * (k2 - 1.0) * norm;
+        let a2 = (1.0 - k / Q + k2) * norm;
+        let coefficients = match kind {
+            FilterKind::Lowpass => [k2 * norm, 2.0 * k2 * norm, k2 * norm, a1, a2],
+            FilterKind::Highpass => [norm, -2.0 * norm, norm, a1, a2],
+        };
+        Some(coefficients.map(|coefficient| coefficient as f32))
+    }
+
+    fn process(&mut self, kind: FilterKind, cutoff: f32, sample: f32) -> f32 {
+        let Some([b0, b1, b2, a1, a2]) = self.coefficients(kind, cutoff) else {
+            return sample;
+        };
+        let output = b0 * sample + b1 * self.x1 + b2 * self.x2 - a1 * self.y1 - a2 * self.y2;
+
+        self.x2 = self.x1;
+        self.x1 = sample;
+        self.y2 = self.y1;
+        self.y1 = output;
+
+        self.recover_if_unstable(output)
+    }
+
     /// Process input through lowpass filter
     /// cutoff: cutoff frequency in Hz
     /// sample: input sample
     /// Returns filtered sample
     pub fn lowpass(&mut self, cutoff: f32, sample: f32) -> f32 {
-        // Simplified butterworth lowpass filter coefficients
-        // Using a fixed sample rate assumption (44100 Hz typical)
-        const SAMPLE_RATE: f32 = 44100.0;
-        
-        let normalized_freq = cutoff / SAMPLE_RATE;
-        let omega = 2.0 * std::f32::consts::PI * normalized_freq;
-        
-        // Butterworth filter coefficients (simplified)
-        let q = 0.707; // Butterworth Q factor
-        let alpha = omega.sin() / (2.0 * q);
-        let cos_omega = omega.cos();
-        
-        let a0 = 1.0 + alpha;
-        let a1 = -2.0 * cos_omega;
-        let a2 = 1.0 - alpha;
-        let b0 = (1.0 - cos_omega) / 2.0;
-        let b1 = 1.0 - cos_omega;
-        let b2 = (1.0 - cos_omega) / 2.0;
-        
-        // Normalize coefficients
-        let inv_a0 = 1.0 / a0;
-        let b0_norm = b0 * inv_a0;
-        let b1_norm = b1 * inv_a0;
-        let b2_norm = b2 * inv_a0;
-        let a1_norm = a1 * inv_a0;
-        let a2_norm = a2 * inv_a0;
-        
-        // Apply biquad filter
-        let output = b0_norm * sample
-            + b1_norm * self.x1
-            + b2_norm * self.x2
-            - a1_norm * self.y1
-            - a2_norm * self.y2;
-        
-        // Update state
-        self.x2 = self.x1;
-        self.x1 = sample;
-        self.y2 = self.y1;
-        self.y1 = output;
-        
-        output
+        self.process(FilterKind::Lowpass, cutoff, sample)
     }
-    
+
     /// Process input through highpass filter
     /// cutoff: cutoff frequency in Hz
     /// sample: input sample
     /// Returns filtered sample
     pub fn highpass(&mut self, cutoff: f32, sample: f32) -> f32 {
-        // Simplified butterworth highpass filter coefficients
-        const SAMPLE_RATE: f32 = 44100.0;
-        
-        let normalized_freq = cutoff / SAMPLE_RATE;
-        let omega = 2.0 * std::f32::consts::PI * normalized_freq;
-        
-        let q = 0.707;
-        let alpha = omega.sin() / (2.0 * q);
-        let cos_omega = omega.cos();
-        
-        let a0 = 1.0 + alpha;
-        let a1 = -2.0 * cos_omega;
-        let a2 = 1.0 - alpha;
-        let b0 = (1.0 + cos_omega) / 2.0;
-        let b1 = -(1.0 + cos_omega);
-        let b2 = (1.0 + cos_omega) / 2.0;
-        
-        let inv_a0 = 1.0 / a0;
-        let b0_norm = b0 * inv_a0;
-        let b1_norm = b1 * inv_a0;
-        let b2_norm = b2 * inv_a0;
-        let a1_norm = a1 * inv_a0;
-        let a2_norm = a2 * inv_a0;
-        
-        let output = b0_norm * sample
-            + b1_norm * self.x1
-            + b2_norm * self.x2
-            - a1_norm * self.y1
-            - a2_norm * self.y2;
-        
-        self.x2 = self.x1;
-        self.x1 = sample;
-        self.y2 = self.y1;
-        self.y1 = output;
-        
-        output
+        self.process(FilterKind::Highpass, cutoff, sample)
     }
 }
 
+#[test]
+fn cutoffs_outside_the_audio_band_stay_stable() {
+    for cutoff in [-100., 0., 22050., 30000., f32::INFINITY] {
+        let mut filter = ButterworthFilter::default();
+        let output: Vec<f32> = (0..20000).map(|_| filter.lowpass(cutoff, 1.0)).collect();
+        assert!(output.iter().all(|sample| sample.is_finite()));
+        // Lowpass filters pass DC, up to the rounding of the coefficients at low cutoffs
+        assert!((output[19999] - 1.0).abs() < 0.02);
+    }
+    let mut filter = ButterworthFilter::default();
+    assert_eq!(filter.highpass(f32::NAN, 0.5), 0.5);
+
+    // Pre-warping puts the cutoff at -3 dB even close to Nyquist
+    let mut filter = ButterworthFilter::default();
+    filter.set_sample_rate(48000.0);
+    let frequency = 20000.0;
+    let omega = 2.0 * std::f32::consts::PI * frequency / 48000.0;
+    let peak = (0..4800)
+        .map(|ix| filter.lowpass(frequency, (omega * ix as f32).sin()))
+        .skip(2400)
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    assert!((peak - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+}
@@ -0,0 +1,91 @@
+// Comb filters
+// A delay line read at a fractional delay and either added to its input (feedforward), which
+// notches every odd multiple of half the frequency whose period is the delay, or fed back into it
+// (feedback), which rings at every multiple of that frequency.
+
+use crate::dsp::mix;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CombKind {
+    Feedforward,
+    Feedback,
+}
+
+impl CombKind {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(CombKind::Feedforward),
+            1 => Some(CombKind::Feedback),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct CombFilter {
+    buffer: Vec<f32>,
+    write_ix: usize,
+}
+
+impl CombFilter {
+    pub fn new(max_delay_samples: usize) -> Self {
+        CombFilter {
+            buffer: vec![0.; max_delay_samples + 2],
+            write_ix: 0,
+        }
+    }
+
+    /// Longest delay the buffer can hold
+    pub fn max_delay_samples(&self) -> f32 {
+        self.buffer.len().saturating_sub(2) as f32
+    }
+
+    /// Silences everything in the delay line
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.);
+    }
+
+    /// Processes one sample, delaying by `delay_samples` from 1 to the longest delay and scaling
+    /// the delayed signal by `gain`, which has to stay below 1 either way for feedback combs
+    pub fn process(&mut self, input: f32, kind: CombKind, delay_samples: f32, gain: f32) -> f32 {
+        let len = self.buffer.len();
+        if len < 3 {
+            return input;
+        }
+        let delay = delay_samples.clamp(1., self.max_delay_samples());
+        let read_pos = self.write_ix as f32 + len as f32 - delay;
+        let read_ix = read_pos as usize;
+        let delayed = mix(
+            read_pos.fract(),
+            self.buffer[read_ix % len],
+            self.buffer[(read_ix + 1) % len],
+        );
+        let (written, output) = match kind {
+            CombKind::Feedforward => (input, input + gain * delayed),
+            CombKind::Feedback => {
+                let output = input + gain * delayed;
+                (output, output)
+            }
+        };
+        // Guard the delay line against NaN and runaway input
+        self.buffer[self.write_ix] = if written.is_finite() { written } else { 0. };
+        self.write_ix = (self.write_ix + 1) % len;
+        output
+    }
+}
+
+#[test]
+fn combs_repeat_or_cancel_the_delayed_input() {
+    let impulse = |ix: usize| if ix == 0 { 1. } else { 0. };
+    let mut comb = CombFilter::new(8);
+    let feedback: Vec<f32> = (0..10)
+        .map(|ix| comb.process(impulse(ix), CombKind::Feedback, 3., 0.5))
+        .collect();
+    assert_eq!(feedback, vec![1., 0., 0., 0.5, 0., 0., 0.25, 0., 0., 0.125]);
+
+    comb.clear();
+    let feedforward: Vec<f32> = (0..8)
+        .map(|ix| comb.process(impulse(ix), CombKind::Feedforward, 2.5, -1.))
+        .collect();
+    assert_eq!(feedforward, vec![1., 0., -0.5, -0.5, 0., 0., 0., 0.]);
+}
@@ -1,4 +1,7 @@
 // Audio filters module
 
+pub mod allpass;
+pub mod biquad;
 pub mod butterworth;
-
+pub mod comb;
+pub mod tilt;
@@ -0,0 +1,77 @@
+// Tilt EQ
+// A one-pole lowpass splits the signal at the pivot frequency, and the part below it is turned
+// down as much as the part above it is turned up, or the other way round.  The result is a gentle
+// shelf in both directions around the pivot that brightens or darkens the whole signal while
+// keeping the pivot's level.  The lowpass is discretized with the bilinear transform so that it
+// fully rejects Nyquist, which keeps the shelves symmetric up there.
+
+use crate::dsp::clamp;
+
+/// Gain of the shelves at full tilt
+pub const MAX_TILT_DB: f32 = 6.;
+
+/// Coefficients for a tilt, shared by every channel it's applied to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tilt {
+    lowpass: f32,
+    high_gain: f32,
+}
+
+impl Tilt {
+    /// A tilt of `tilt` from -1, darkest, to 1, brightest, around `pivot_hz`
+    pub fn new(tilt: f32, pivot_hz: f32, sample_rate: f32) -> Self {
+        let pivot_hz = clamp(20., sample_rate * 0.49, pivot_hz);
+        let g = (std::f32::consts::PI * pivot_hz / sample_rate).tan();
+        Tilt {
+            lowpass: g / (1. + g),
+            high_gain: 10f32.powf(clamp(-1., 1., tilt) * MAX_TILT_DB / 20.),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct TiltFilter {
+    /// State of the splitting lowpass
+    state: f32,
+}
+
+impl TiltFilter {
+    pub fn reset(&mut self) {
+        self.state = 0.;
+    }
+
+    pub fn process(&mut self, input: f32, tilt: &Tilt) -> f32 {
+        let v = (input - self.state) * tilt.lowpass;
+        let low = v + self.state;
+        self.state = low + v;
+        if !self.state.is_finite() {
+            self.reset();
+            return 0.;
+        }
+        low / tilt.high_gain + (input - low) * tilt.high_gain
+    }
+}
+
+#[test]
+fn tilting_trades_lows_for_highs() {
+    let sample_rate = 48000.;
+    let level = |frequency_hz: f32, tilt: f32| {
+        let tilt = Tilt::new(tilt, 1000., sample_rate);
+        let mut filter = TiltFilter::default();
+        (0..9600).fold(0f32, |peak, ix| {
+            let phase = std::f32::consts::TAU * frequency_hz * ix as f32 / sample_rate;
+            let output = filter.process(phase.sin(), &tilt);
+            if ix >= 4800 {
+                peak.max(output.abs())
+            } else {
+                peak
+            }
+        })
+    };
+    let db = |gain: f32| 20. * gain.log10();
+    assert!((level(100., 0.) - 1.).abs() < 0.01);
+    assert!((db(level(50., 1.)) + MAX_TILT_DB).abs() < 0.5);
+    assert!((db(level(20000., 1.)) - MAX_TILT_DB).abs() < 0.5);
+    assert!((db(level(50., -1.)) - MAX_TILT_DB).abs() < 0.5);
+    assert!((db(level(20000., -1.)) + MAX_TILT_DB).abs() < 0.5);
+}
@@ -0,0 +1,124 @@
+// Sample type abstraction
+// The realtime engine runs in f32, but the DSP building blocks are generic over `Float` so that
+// offline analysis can run them in f64 without losing precision.
+
+use std::fmt::Debug;
+use std::ops::{Add, AddAssign, Div, Mul, MulAssign, Neg, Sub, SubAssign};
+
+pub trait Float:
+    Copy
+    + Default
+    + Debug
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + MulAssign
+{
+    const ZERO: Self;
+    const ONE: Self;
+
+    /// Converts a constant, which is exact for the constants the DSP code uses
+    fn from_f64(value: f64) -> Self;
+    fn from_usize(value: usize) -> Self;
+    /// Converts to an index, saturating like an `as` cast
+    fn to_usize(self) -> usize;
+    fn abs(self) -> Self;
+    fn floor(self) -> Self;
+    fn exp(self) -> Self;
+    fn log10(self) -> Self;
+    fn max(self, other: Self) -> Self;
+    fn is_finite(self) -> bool;
+}
+
+macro_rules! impl_float {
+    ($float:ty) => {
+        impl Float for $float {
+            const ZERO: Self = 0.;
+            const ONE: Self = 1.;
+
+            #[inline]
+            fn from_f64(value: f64) -> Self {
+                value as $float
+            }
+
+            #[inline]
+            fn from_usize(value: usize) -> Self {
+                value as $float
+            }
+
+            #[inline]
+            fn to_usize(self) -> usize {
+                self as usize
+            }
+
+            #[inline]
+            fn abs(self) -> Self {
+                <$float>::abs(self)
+            }
+
+            #[inline]
+            fn floor(self) -> Self {
+                <$float>::floor(self)
+            }
+
+            #[inline]
+            fn exp(self) -> Self {
+                <$float>::exp(self)
+            }
+
+            #[inline]
+            fn log10(self) -> Self {
+                <$float>::log10(self)
+            }
+
+            #[inline]
+            fn max(self, other: Self) -> Self {
+                <$float>::max(self, other)
+            }
+
+            #[inline]
+            fn is_finite(self) -> bool {
+                <$float>::is_finite(self)
+            }
+        }
+    };
+}
+
+impl_float!(f32);
+impl_float!(f64);
+
+/// Format a buffer of samples can be stored in and read back from as `T`
+pub trait Sample<T: Float>: Copy {
+    fn to_float(self) -> T;
+}
+
+impl<T: Float> Sample<T> for T {
+    #[inline]
+    fn to_float(self) -> T {
+        self
+    }
+}
+
+/// 16-bit PCM, from -1 up to just under 1
+impl Sample<f32> for i16 {
+    #[inline]
+    fn to_float(self) -> f32 {
+        self as f32 / 32768.
+    }
+}
+
+#[test]
+fn dsp_runs_in_f64() {
+    // Out of f32's precision near 1
+    let step = 1e-12;
+    let buf = [1., 1. + step];
+    assert_eq!(super::read_interpolated(&buf, 0.5), 1. + step / 2.);
+
+    let mut limiter = super::dynamics::Limiter::<f64>::new(0.5, 100., 48000.);
+    assert_eq!(limiter.process(2.), 0.5);
+}
@@ -0,0 +1,235 @@
+// Loudness measurement following ITU-R BS.1770
+// K-weighted momentary (400 ms), short-term (3 s) and gated integrated loudness of a stereo signal
+
+use super::filters::biquad::{Biquad, BiquadCoefficients};
+
+const MOMENTARY_SECONDS: f32 = 0.4;
+const SHORT_TERM_SECONDS: f32 = 3.;
+/// Gating blocks are 400 ms long and overlap by 75%
+const GATING_STEP_SECONDS: f32 = 0.1;
+const ABSOLUTE_GATE_LUFS: f64 = -70.;
+const RELATIVE_GATE_LU: f64 = -10.;
+/// The integrated measurement keeps a histogram of gating block loudness instead of every block
+/// so that it can run for arbitrarily long
+const HISTOGRAM_MAX_LUFS: f64 = 5.;
+const HISTOGRAM_BIN_LU: f64 = 0.1;
+const HISTOGRAM_BIN_COUNT: usize =
+    ((HISTOGRAM_MAX_LUFS - ABSOLUTE_GATE_LUFS) / HISTOGRAM_BIN_LU) as usize;
+
+/// First stage of the K-weighting filter: a high shelf modelling the acoustic effect of the head
+fn shelf_coefficients(sample_rate: f32) -> BiquadCoefficients {
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / sample_rate as f64).tan();
+    let vh = 10f64.powf(gain_db / 20.);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1. + k / q + k * k;
+    BiquadCoefficients {
+        b0: ((vh + vb * k / q + k * k) / a0) as f32,
+        b1: (2. * (k * k - vh) / a0) as f32,
+        b2: ((vh - vb * k / q + k * k) / a0) as f32,
+        a1: (2. * (k * k - 1.) / a0) as f32,
+        a2: ((1. - k / q + k * k) / a0) as f32,
+    }
+}
+
+/// Second stage of the K-weighting filter: the "RLB" highpass
+fn highpass_coefficients(sample_rate: f32) -> BiquadCoefficients {
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / sample_rate as f64).tan();
+    let a0 = 1. + k / q + k * k;
+    BiquadCoefficients {
+        b0: 1.,
+        b1: -2.,
+        b2: 1.,
+        a1: (2. * (k * k - 1.) / a0) as f32,
+        a2: ((1. - k / q + k * k) / a0) as f32,
+    }
+}
+
+fn energy_to_lufs(mean_square: f64) -> f64 {
+    if mean_square <= 0. {
+        f64::NEG_INFINITY
+    } else {
+        -0.691 + 10. * mean_square.log10()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct KWeighting {
+    shelf: Biquad,
+    highpass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: f32) -> Self {
+        KWeighting {
+            shelf: Biquad::new(shelf_coefficients(sample_rate)),
+            highpass: Biquad::new(highpass_coefficients(sample_rate)),
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.highpass.process(self.shelf.process(sample))
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct HistogramBin {
+    count: u64,
+    energy: f64,
+}
+
+#[derive(Clone)]
+pub struct LoudnessMeter {
+    sample_rate: f32,
+    filters: [KWeighting; 2],
+    /// Summed channel energy of every `block_len` samples, oldest first once full
+    block_energies: Vec<f64>,
+    block_len: usize,
+    next_block_ix: usize,
+    samples_in_block: usize,
+    block_energy: f64,
+    blocks_since_gating_step: usize,
+    /// Blocks measured since the last reset, saturating at the ring's capacity
+    filled_blocks: usize,
+    histogram: Vec<HistogramBin>,
+}
+
+impl LoudnessMeter {
+    /// `block_len` is the granularity of the sliding windows in samples
+    pub fn new(sample_rate: f32, block_len: usize) -> Self {
+        let block_count = (SHORT_TERM_SECONDS * sample_rate / block_len as f32).ceil() as usize;
+        LoudnessMeter {
+            sample_rate,
+            filters: [KWeighting::new(sample_rate); 2],
+            block_energies: vec![0.; block_count.max(1)],
+            block_len,
+            next_block_ix: 0,
+            samples_in_block: 0,
+            block_energy: 0.,
+            blocks_since_gating_step: 0,
+            filled_blocks: 0,
+            histogram: vec![HistogramBin::default(); HISTOGRAM_BIN_COUNT],
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        *self = LoudnessMeter::new(sample_rate, self.block_len);
+    }
+
+    pub fn reset(&mut self) {
+        self.set_sample_rate(self.sample_rate);
+    }
+
+    fn blocks_for(&self, seconds: f32) -> usize {
+        ((seconds * self.sample_rate / self.block_len as f32).round() as usize)
+            .clamp(1, self.block_energies.len())
+    }
+
+    pub fn process(&mut self, left: f32, right: f32) {
+        let left = self.filters[0].process(left) as f64;
+        let right = self.filters[1].process(right) as f64;
+        self.block_energy += left * left + right * right;
+        self.samples_in_block += 1;
+        if self.samples_in_block < self.block_len {
+            return;
+        }
+
+        self.block_energies[self.next_block_ix] = self.block_energy;
+        self.next_block_ix = (self.next_block_ix + 1) % self.block_energies.len();
+        self.filled_blocks = (self.filled_blocks + 1).min(self.block_energies.len());
+        self.block_energy = 0.;
+        self.samples_in_block = 0;
+
+        self.blocks_since_gating_step += 1;
+        if self.blocks_since_gating_step >= self.blocks_for(GATING_STEP_SECONDS) {
+            self.blocks_since_gating_step = 0;
+            self.add_gating_block();
+        }
+    }
+
+    /// Mean square of the most recent `block_count` blocks, or `None` until that many have been
+    /// measured
+    fn window_mean_square(&self, block_count: usize) -> Option<f64> {
+        if self.filled_blocks < block_count {
+            return None;
+        }
+        let len = self.block_energies.len();
+        let sum: f64 = (1..=block_count)
+            .map(|offset| self.block_energies[(self.next_block_ix + len - offset) % len])
+            .sum();
+        Some(sum / (block_count * self.block_len) as f64)
+    }
+
+    fn add_gating_block(&mut self) {
+        let Some(mean_square) = self.window_mean_square(self.blocks_for(MOMENTARY_SECONDS)) else {
+            return;
+        };
+        let lufs = energy_to_lufs(mean_square);
+        if lufs < ABSOLUTE_GATE_LUFS {
+            return;
+        }
+        let bin_ix = (((lufs - ABSOLUTE_GATE_LUFS) / HISTOGRAM_BIN_LU) as usize)
+            .min(HISTOGRAM_BIN_COUNT - 1);
+        let bin = &mut self.histogram[bin_ix];
+        bin.count += 1;
+        bin.energy += mean_square;
+    }
+
+    /// Loudness over the last 400 ms in LUFS
+    pub fn momentary(&self) -> f32 {
+        self.window_mean_square(self.blocks_for(MOMENTARY_SECONDS))
+            .map_or(f64::NEG_INFINITY, energy_to_lufs) as f32
+    }
+
+    /// Loudness over the last 3 s in LUFS
+    pub fn short_term(&self) -> f32 {
+        self.window_mean_square(self.blocks_for(SHORT_TERM_SECONDS))
+            .map_or(f64::NEG_INFINITY, energy_to_lufs) as f32
+    }
+
+    /// Gated loudness since the last reset in LUFS
+    pub fn integrated(&self) -> f32 {
+        let gated_mean = |min_bin_ix: usize| {
+            let (count, energy) = self.histogram[min_bin_ix..]
+                .iter()
+                .fold((0, 0.), |(count, energy), bin| {
+                    (count + bin.count, energy + bin.energy)
+                });
+            if count == 0 {
+                None
+            } else {
+                Some(energy / count as f64)
+            }
+        };
+
+        let Some(ungated) = gated_mean(0) else {
+            return f32::NEG_INFINITY;
+        };
+        let relative_gate = energy_to_lufs(ungated) + RELATIVE_GATE_LU;
+        let min_bin_ix = ((relative_gate - ABSOLUTE_GATE_LUFS) / HISTOGRAM_BIN_LU).max(0.) as usize;
+        gated_mean(min_bin_ix.min(HISTOGRAM_BIN_COUNT - 1))
+            .map_or(f64::NEG_INFINITY, energy_to_lufs) as f32
+    }
+}
+
+#[test]
+fn full_scale_sine_reads_about_minus_three_lufs() {
+    // A 997 Hz sine at 0 dBFS in one channel should measure -3.01 LUFS
+    let sample_rate = 48000.;
+    let mut meter = LoudnessMeter::new(sample_rate, 128);
+    for i in 0..(sample_rate as usize * 4) {
+        let phase = i as f64 * 997. / sample_rate as f64;
+        meter.process((phase * 2. * std::f64::consts::PI).sin() as f32, 0.);
+    }
+    assert!(
+        (meter.momentary() + 3.01).abs() < 0.1,
+        "{}",
+        meter.momentary()
+    );
+    assert!((meter.short_term() + 3.01).abs() < 0.1);
+    assert!((meter.integrated() + 3.01).abs() < 0.1);
+}
@@ -1,10 +1,30 @@
 // DSP utilities module
 // Provides common audio DSP functions like interpolation, mixing, filtering, etc.
 
+pub mod adsr;
+pub mod convolution;
+pub mod delay;
+pub mod dynamics;
+pub mod fft;
 pub mod filters;
+pub mod float;
+#[cfg(feature = "loudness")]
+pub mod loudness;
+pub mod phaser;
+pub mod pitch;
+pub mod processor;
+pub mod resample;
+pub mod resonator;
+pub mod saturation;
+pub mod test_signal;
+
+pub use float::{Float, Sample};
+
+use std::ops::Range;
 
 /// Clamp a value between min and max
-pub fn clamp(min: f32, max: f32, value: f32) -> f32 {
+#[inline]
+pub fn clamp<T: Float>(min: T, max: T, value: T) -> T {
     if value < min {
         min
     } else if value > max {
@@ -16,31 +36,119 @@ pub fn clamp(min: f32, max: f32, value: f32) -> f32 {
 
 /// Linear interpolation between two values
 /// mix: 0.0 = a, 1.0 = b
-pub fn mix(mix: f32, a: f32, b: f32) -> f32 {
-    a + (b - a) * clamp(0.0, 1.0, mix)
+#[inline]
+pub fn mix<T: Float>(mix: T, a: T, b: T) -> T {
+    a + (b - a) * clamp(T::ZERO, T::ONE, mix)
+}
+
+/// What an interpolated read does at and past the last sample of a buffer
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum EndPolicy {
+    /// Hold the last sample
+    #[default]
+    Clamp,
+    /// Treat the buffer as a loop: indices wrap around and the last sample interpolates towards
+    /// the first
+    Wrap,
 }
 
 /// Read interpolated sample from buffer
-/// Uses linear interpolation for fractional indices
-pub fn read_interpolated(buf: &[f32], index: f32) -> f32 {
-    let idx = index.floor() as usize;
-    let frac = index - idx as f32;
-    
-    if idx >= buf.len() - 1 {
-        return *buf.last().unwrap_or(&0.0);
+/// Uses linear interpolation for fractional indices and holds the last sample past the end
+#[inline]
+pub fn read_interpolated<T: Float, S: Sample<T>>(buf: &[S], index: T) -> T {
+    read_interpolated_with(buf, index, EndPolicy::Clamp)
+}
+
+/// Read interpolated sample from buffer with the given policy for the end of the buffer
+/// Empty buffers read as silence, and negative indices read the first sample unless wrapping
+#[inline]
+pub fn read_interpolated_with<T: Float, S: Sample<T>>(buf: &[S], index: T, end: EndPolicy) -> T {
+    let Some(last) = buf.last().map(|last| last.to_float()) else {
+        return T::ZERO;
+    };
+    let index = match end {
+        EndPolicy::Clamp => index,
+        EndPolicy::Wrap => {
+            let len = T::from_usize(buf.len());
+            index - (index / len).floor() * len
+        }
+    };
+    let idx = index.floor().to_usize();
+    let frac = index - T::from_usize(idx);
+
+    // Indices too large for a usize saturate, so this mustn't add to `idx`
+    if idx < buf.len() - 1 {
+        #[cfg(feature = "unchecked-reads")]
+        // SAFETY: `idx + 1` is below the length, checked just above
+        let (a, b) = unsafe { (buf.get_unchecked(idx), buf.get_unchecked(idx + 1)) };
+        #[cfg(not(feature = "unchecked-reads"))]
+        let (a, b) = (&buf[idx], &buf[idx + 1]);
+        return mix(frac, a.to_float(), b.to_float());
+    }
+    match end {
+        EndPolicy::Clamp => last,
+        EndPolicy::Wrap => mix(frac, last, buf[0].to_float()),
     }
-    
-    let sample_a = buf[idx];
-    let sample_b = buf[idx + 1];
-    
-    mix(frac, sample_a, sample_b)
+}
+
+/// Read interpolated sample from the region `range` of buffer as if it were circular
+/// `index` is absolute in `buf`, and reads past the end of the region continue from its start, so
+/// a read crossing the end interpolates back to the start seamlessly
+#[inline]
+pub fn read_interpolated_wrapped<T: Float, S: Sample<T>>(
+    buf: &[S],
+    range: Range<usize>,
+    index: T,
+) -> T {
+    let end = range.end.min(buf.len());
+    let start = range.start.min(end);
+    read_interpolated_with(
+        &buf[start..end],
+        index - T::from_usize(start),
+        EndPolicy::Wrap,
+    )
 }
 
 /// Smooth a value towards a target using exponential smoothing
 /// current: mutable reference to current value
 /// target: target value to smooth towards
 /// smoothing: smoothing factor (0.0 = no smoothing, 1.0 = full smoothing)
-pub fn smooth(current: &mut f32, target: f32, smoothing: f32) {
+#[inline]
+pub fn smooth<T: Float>(current: &mut T, target: T, smoothing: T) {
     *current = mix(smoothing, target, *current);
 }
 
+/// Samples a feedback loop takes to die away by 60 dB
+/// period: samples around the loop
+/// gain: gain around the loop, below 1 in magnitude for the loop to die away at all
+pub fn decay_samples(period: f32, gain: f32) -> f32 {
+    let gain = gain.abs();
+    if gain >= 1. {
+        return f32::INFINITY;
+    }
+    let round_trips = if gain > 0.001 {
+        0.001f32.ln() / gain.ln()
+    } else {
+        0.
+    };
+    period * (1. + round_trips)
+}
+
+#[test]
+fn interpolated_reads_handle_buffer_ends() {
+    assert_eq!(read_interpolated::<f32, f32>(&[], 0.), 0.);
+    assert_eq!(read_interpolated(&[0.5f32], 0.75), 0.5);
+
+    let buf = [0f32, 1., 2., 3.];
+    assert_eq!(read_interpolated(&buf, -1.), 0.);
+    assert_eq!(read_interpolated(&buf, 3.5), 3.);
+    assert_eq!(read_interpolated(&buf, 10.), 3.);
+    assert_eq!(read_interpolated_with(&buf, 3.5, EndPolicy::Wrap), 1.5);
+    assert_eq!(read_interpolated_with(&buf, 5.5, EndPolicy::Wrap), 1.5);
+    assert_eq!(read_interpolated_with(&buf, -0.5, EndPolicy::Wrap), 1.5);
+
+    assert_eq!(read_interpolated_wrapped(&buf, 1..3, 2.5), 1.5);
+    assert_eq!(read_interpolated_wrapped(&buf, 1..3, 3.), 1.);
+    assert_eq!(read_interpolated_wrapped(&buf, 2..9, 4.5), 2.5);
+    assert_eq!(read_interpolated_wrapped(&buf, 3..3, 3.), 0.);
+}
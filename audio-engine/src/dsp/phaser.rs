@@ -0,0 +1,107 @@
+// Phaser
+// A chain of first-order allpass stages whose corner frequency a sine LFO sweeps, mixed equally
+// with the dry signal so that the frequencies the chain shifts by 180 degrees cancel out.  Every
+// two stages add a notch, and feedback around the chain deepens the notches and makes the peaks
+// between them ring.
+// Pairs of stages run as second-order sections with a Q of 0.5, which is the same as two
+// first-order sections at the same corner, so only an odd last stage is first-order.
+
+use super::filters::allpass::{FirstOrderAllpass, SecondOrderAllpass};
+
+pub const MAX_STAGES: usize = 12;
+/// Corner frequency at the middle of the sweep
+const CENTER_HZ: f32 = 800.;
+/// How far the sweep goes either side of the centre at full depth
+const SWEEP_OCTAVES: f32 = 2.5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PhaserSettings {
+    /// Number of allpass stages, up to `MAX_STAGES`
+    pub stages: usize,
+    pub rate_hz: f32,
+    /// From 0, where the notches stay still, to 1
+    pub depth: f32,
+    /// From just above -1 to just below 1
+    pub feedback: f32,
+}
+
+/// Q of a second-order section equivalent to two first-order ones
+const STAGE_PAIR_Q: f32 = 0.5;
+
+#[derive(Clone, Copy, Default)]
+pub struct Phaser {
+    stage_pairs: [SecondOrderAllpass; MAX_STAGES / 2],
+    last_stage: FirstOrderAllpass,
+    /// Position of the LFO in its cycle, from 0 to 1
+    phase: f32,
+    /// Last output of the chain, fed back into its input
+    feedback_sample: f32,
+}
+
+impl Phaser {
+    /// A phaser whose LFO starts `phase` of the way into its cycle
+    pub fn with_phase(phase: f32) -> Self {
+        Phaser {
+            phase,
+            ..Default::default()
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for stage_pair in &mut self.stage_pairs {
+            stage_pair.reset();
+        }
+        self.last_stage.reset();
+        self.feedback_sample = 0.;
+    }
+
+    pub fn process(&mut self, input: f32, settings: &PhaserSettings, sample_rate: f32) -> f32 {
+        self.phase = (self.phase + settings.rate_hz / sample_rate).fract();
+        let sweep = (self.phase * std::f32::consts::TAU).sin() * settings.depth * SWEEP_OCTAVES;
+        let frequency_hz = CENTER_HZ * sweep.exp2();
+        let stages = settings.stages.min(MAX_STAGES);
+        let mut sample = input + settings.feedback * self.feedback_sample;
+        let coefficients =
+            SecondOrderAllpass::coefficients(frequency_hz, STAGE_PAIR_Q, sample_rate);
+        for stage_pair in &mut self.stage_pairs[..stages / 2] {
+            sample = stage_pair.process(sample, coefficients);
+        }
+        if stages % 2 == 1 {
+            let coefficient = FirstOrderAllpass::coefficient(frequency_hz, sample_rate);
+            sample = self.last_stage.process(sample, coefficient);
+        }
+        self.feedback_sample = if sample.is_finite() { sample } else { 0. };
+        0.5 * (input + sample)
+    }
+}
+
+#[test]
+fn phaser_notches_the_frequency_its_stages_shift_by_half_a_cycle() {
+    let sample_rate = 48000.;
+    let mut settings = PhaserSettings {
+        stages: 2,
+        rate_hz: 0.,
+        depth: 0.,
+        feedback: 0.,
+    };
+    // Two stages each shift the centre by 90 degrees
+    let level = |settings: PhaserSettings, frequency_hz: f32| {
+        let mut phaser = Phaser::default();
+        (0..9600).fold(0f32, |peak, ix| {
+            let input = (std::f32::consts::TAU * frequency_hz * ix as f32 / sample_rate).sin();
+            let output = phaser.process(input, &settings, sample_rate);
+            if ix >= 4800 {
+                peak.max(output.abs())
+            } else {
+                peak
+            }
+        })
+    };
+    assert!(level(settings, CENTER_HZ) < 0.01);
+    assert!(level(settings, 50.) > 0.95);
+    assert!(level(settings, 10000.) > 0.9);
+    // Three stages shift it by 270 degrees, and the notch moves down to where they shift it by 180
+    settings.stages = 3;
+    assert!(level(settings, CENTER_HZ) > 0.5);
+    assert!(level(settings, CENTER_HZ / 3f32.sqrt()) < 0.05);
+}
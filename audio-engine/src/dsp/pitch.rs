@@ -0,0 +1,76 @@
+// Pitch estimation
+// YIN: the lag with the lowest cumulative-mean-normalized difference below a threshold is the
+// period.  Meant for offline analysis of short segments, not the audio thread.
+
+/// Lowest and highest detected pitch
+const MIN_PITCH_HZ: f32 = 50.;
+const MAX_PITCH_HZ: f32 = 2000.;
+/// Normalized difference below which a lag counts as periodic; higher values accept noisier
+/// signals as pitched
+const THRESHOLD: f32 = 0.15;
+
+/// Fundamental frequency of `samples` in Hz, or `None` if they're too short to hold two periods of
+/// the lowest pitch, silent or not periodic enough
+pub fn estimate_pitch(samples: &[f32], sample_rate: f32) -> Option<f32> {
+    let min_lag = (sample_rate / MAX_PITCH_HZ).floor().max(2.) as usize;
+    let max_lag = (sample_rate / MIN_PITCH_HZ).ceil() as usize;
+    // The differences are needed up to one past the longest lag for the interpolation
+    let window = samples.len().checked_sub(max_lag + 1)?;
+    if window < max_lag || samples.iter().any(|sample| !sample.is_finite()) {
+        return None;
+    }
+
+    let difference = |lag: usize| -> f32 {
+        (0..window)
+            .map(|i| {
+                let delta = samples[i] - samples[i + lag];
+                delta * delta
+            })
+            .sum()
+    };
+    let differences: Vec<f32> = (0..=max_lag + 1).map(difference).collect();
+    let mut normalized = vec![1.; differences.len()];
+    let mut running_sum = 0.;
+    for lag in 1..differences.len() {
+        running_sum += differences[lag];
+        if running_sum > 0. {
+            normalized[lag] = differences[lag] * lag as f32 / running_sum;
+        }
+    }
+
+    // The first dip below the threshold, followed down to its minimum
+    let mut lag = (min_lag..=max_lag).find(|&lag| normalized[lag] < THRESHOLD)?;
+    while lag < max_lag && normalized[lag + 1] < normalized[lag] {
+        lag += 1;
+    }
+    // Parabolic interpolation between neighbouring lags
+    let (before, at, after) = (normalized[lag - 1], normalized[lag], normalized[lag + 1]);
+    let curvature = before - 2. * at + after;
+    let offset = if curvature > 0. {
+        0.5 * (before - after) / curvature
+    } else {
+        0.
+    };
+    Some(sample_rate / (lag as f32 + offset))
+}
+
+#[test]
+fn pitch_of_a_tone_is_found_and_noise_has_none() {
+    let sample_rate = 48000.;
+    let tone: Vec<f32> = (0..4096)
+        .map(|i| {
+            let phase = 2. * std::f32::consts::PI * 220. * i as f32 / sample_rate;
+            phase.sin() + 0.5 * (2. * phase).sin()
+        })
+        .collect();
+    let pitch = estimate_pitch(&tone, sample_rate).unwrap();
+    assert!((pitch - 220.).abs() < 1., "{}", pitch);
+
+    assert_eq!(estimate_pitch(&[0.; 4096], sample_rate), None);
+    assert_eq!(estimate_pitch(&tone[..1000], sample_rate), None);
+    let mut rng = crate::common::rng();
+    let noise: Vec<f32> = (0..4096)
+        .map(|_| rand::Rng::gen_range(&mut rng, -1.0..1.0))
+        .collect();
+    assert_eq!(estimate_pitch(&noise, sample_rate), None);
+}
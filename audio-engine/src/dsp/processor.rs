@@ -0,0 +1,268 @@
+// Block processing interface shared by the filters, effects and the granular engine
+// Processors work in place on a block of mono samples, so they can be chained generically: a
+// tuple of processors runs them in order, as does a `Vec` of boxed ones built at runtime.  Filters
+// and effects that take their settings with every sample are processors once `Configured` with
+// the settings to run at.
+
+use super::convolution::{Convolver, ImpulseResponse};
+use super::delay::ModulatedDelay;
+use super::dynamics::{Compressor, Limiter, TransientShaper};
+use super::filters::allpass::{FirstOrderAllpass, SecondOrderAllpass};
+use super::filters::biquad::Biquad;
+use super::filters::butterworth::ButterworthFilter;
+use super::filters::comb::{CombFilter, CombKind};
+use super::filters::tilt::{Tilt, TiltFilter};
+use super::phaser::{Phaser, PhaserSettings};
+use super::resonator::CombResonator;
+use super::saturation::Saturator;
+
+pub trait AudioProcessor {
+    /// Process `io` in place
+    fn process_block(&mut self, io: &mut [f32]);
+
+    /// Clear any internal state such as filter memory
+    fn reset(&mut self) {}
+}
+
+/// Implements `AudioProcessor` for types with a `process(sample) -> sample` and a `reset()`
+macro_rules! sample_processor {
+    ($($processor:ty),*) => {$(
+        impl AudioProcessor for $processor {
+            fn process_block(&mut self, io: &mut [f32]) {
+                for sample in io {
+                    *sample = self.process(*sample);
+                }
+            }
+
+            fn reset(&mut self) {
+                <$processor>::reset(self);
+            }
+        }
+    )*};
+}
+
+sample_processor!(Biquad<f32>, Limiter<f32>, Saturator);
+
+/// Compresses the block keyed by itself
+impl AudioProcessor for Compressor<f32> {
+    fn process_block(&mut self, io: &mut [f32]) {
+        for sample in io {
+            *sample *= self.process(*sample);
+        }
+    }
+
+    fn reset(&mut self) {
+        Compressor::reset(self);
+    }
+}
+
+impl AudioProcessor for TransientShaper<f32> {
+    fn process_block(&mut self, io: &mut [f32]) {
+        for sample in io {
+            *sample *= self.process(*sample);
+        }
+    }
+
+    fn reset(&mut self) {
+        TransientShaper::reset(self);
+    }
+}
+
+/// A filter or effect together with the settings it's processed with, which can still be changed
+/// between blocks
+#[derive(Clone)]
+pub struct Configured<P, S> {
+    pub processor: P,
+    pub settings: S,
+}
+
+impl<P, S> Configured<P, S> {
+    pub fn new(processor: P, settings: S) -> Self {
+        Configured {
+            processor,
+            settings,
+        }
+    }
+}
+
+/// Implements `AudioProcessor` for a `Configured` processor from how one sample is processed and
+/// how its state is cleared
+macro_rules! configured_processor {
+    ($processor:ty, $settings:ty, |$this:ident, $sample:ident| $process:expr, $reset:ident) => {
+        impl AudioProcessor for Configured<$processor, $settings> {
+            fn process_block(&mut self, io: &mut [f32]) {
+                let $this = self;
+                for $sample in io {
+                    *$sample = $process;
+                }
+            }
+
+            fn reset(&mut self) {
+                self.processor.$reset();
+            }
+        }
+    };
+}
+
+// A cutoff above 0 is a lowpass and one below it a highpass at its magnitude, as for the voices
+configured_processor!(
+    ButterworthFilter,
+    f32,
+    |this, sample| if this.settings > 0. {
+        this.processor.lowpass(this.settings, *sample)
+    } else {
+        this.processor.highpass(-this.settings, *sample)
+    },
+    reset
+);
+configured_processor!(
+    TiltFilter,
+    Tilt,
+    |this, sample| this.processor.process(*sample, &this.settings),
+    reset
+);
+// The coefficient from `FirstOrderAllpass::coefficient`
+configured_processor!(
+    FirstOrderAllpass,
+    f32,
+    |this, sample| this.processor.process(*sample, this.settings),
+    reset
+);
+// The coefficients from `SecondOrderAllpass::coefficients`
+configured_processor!(
+    SecondOrderAllpass,
+    (f32, f32),
+    |this, sample| this.processor.process(*sample, this.settings),
+    reset
+);
+// The kind, delay in samples and gain of the comb
+configured_processor!(
+    CombFilter,
+    (CombKind, f32, f32),
+    |this, sample| {
+        let (kind, delay_samples, gain) = this.settings;
+        this.processor.process(*sample, kind, delay_samples, gain)
+    },
+    clear
+);
+// The period in samples, feedback and damping.  The block is replaced with the ringing alone.
+configured_processor!(
+    CombResonator,
+    (f32, f32, f32),
+    |this, sample| {
+        let (period_samples, feedback, damping) = this.settings;
+        this.processor
+            .process(*sample, period_samples, feedback, damping)
+    },
+    clear
+);
+// The delay in samples
+configured_processor!(
+    ModulatedDelay<f32>,
+    f32,
+    |this, sample| this.processor.process(*sample, this.settings),
+    clear
+);
+// The settings and the sample rate the LFO runs at
+configured_processor!(
+    Phaser,
+    (PhaserSettings, f32),
+    |this, sample| {
+        let (settings, sample_rate) = &this.settings;
+        this.processor.process(*sample, settings, *sample_rate)
+    },
+    reset
+);
+// The response the convolver was made for
+configured_processor!(
+    Convolver,
+    ImpulseResponse,
+    |this, sample| this.processor.process(*sample, &this.settings),
+    reset
+);
+
+impl<A: AudioProcessor, B: AudioProcessor> AudioProcessor for (A, B) {
+    fn process_block(&mut self, io: &mut [f32]) {
+        self.0.process_block(io);
+        self.1.process_block(io);
+    }
+
+    fn reset(&mut self) {
+        self.0.reset();
+        self.1.reset();
+    }
+}
+
+impl<P: AudioProcessor + ?Sized> AudioProcessor for Box<P> {
+    fn process_block(&mut self, io: &mut [f32]) {
+        (**self).process_block(io);
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+}
+
+impl<P: AudioProcessor> AudioProcessor for Vec<P> {
+    fn process_block(&mut self, io: &mut [f32]) {
+        for processor in self {
+            processor.process_block(io);
+        }
+    }
+
+    fn reset(&mut self) {
+        for processor in self {
+            processor.reset();
+        }
+    }
+}
+
+#[test]
+fn chains_run_in_order() {
+    struct Offset(f32);
+    impl AudioProcessor for Offset {
+        fn process_block(&mut self, io: &mut [f32]) {
+            io.iter_mut().for_each(|sample| *sample += self.0);
+        }
+    }
+
+    let limiter = Limiter::<f32>::new(1., 100., 1000.);
+    let mut chain: Vec<Box<dyn AudioProcessor>> = vec![
+        Box::new((Offset(1.), Offset(0.5))),
+        Box::new(limiter),
+        Box::new(Offset(-1.)),
+    ];
+    let mut io = [0.; 4];
+    chain.process_block(&mut io);
+    assert_eq!(io, [0.; 4]);
+}
+
+#[test]
+fn configured_processors_match_their_sample_calls() {
+    let input: Vec<f32> = (0..256).map(|ix| (ix as f32 * 0.3).sin()).collect();
+    let mut filter = ButterworthFilter::default();
+    let expected: Vec<f32> = input.iter().map(|x| filter.highpass(500., *x)).collect();
+
+    let tilt = Tilt::new(0., 1000., 44100.);
+    let mut chain = (
+        Configured::new(ButterworthFilter::default(), -500.),
+        Configured::new(TiltFilter::default(), tilt),
+    );
+    let mut io = input.clone();
+    chain.process_block(&mut io);
+    for (output, expected) in io.iter().zip(&expected) {
+        assert!((output - expected).abs() < 1e-5, "{} {}", output, expected);
+    }
+
+    // Resetting forgets the previous block
+    chain.reset();
+    let mut io = input.clone();
+    chain.process_block(&mut io);
+    assert!((io[0] - expected[0]).abs() < 1e-5);
+
+    let mut delay = Configured::new(ModulatedDelay::new(8), 3.);
+    let mut io = input.clone();
+    delay.process_block(&mut io);
+    assert_eq!(io[..3], [0.; 3]);
+    assert!((io[3] - input[0]).abs() < 1e-6);
+}
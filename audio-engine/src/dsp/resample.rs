@@ -0,0 +1,125 @@
+// Sample rate conversion
+// Offline windowed-sinc resampling of whole buffers, used when loading waveforms and exporting
+// renders
+
+use std::f64::consts::PI;
+
+/// Trade-off between speed and passband flatness/aliasing rejection
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ResampleQuality {
+    Fast,
+    #[default]
+    Standard,
+    Best,
+}
+
+impl ResampleQuality {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(ResampleQuality::Fast),
+            1 => Some(ResampleQuality::Standard),
+            2 => Some(ResampleQuality::Best),
+            _ => None,
+        }
+    }
+
+    /// Zero crossings of the sinc kernel on either side of its center
+    fn zero_crossings(self) -> usize {
+        match self {
+            ResampleQuality::Fast => 8,
+            ResampleQuality::Standard => 16,
+            ResampleQuality::Best => 32,
+        }
+    }
+}
+
+/// Blackman window over -1..1
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1. {
+        return 0.;
+    }
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2. * PI * x).cos()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0. {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Converts `input` from `from_rate` to `to_rate`.  When downsampling, the kernel's cutoff is
+/// lowered to the new Nyquist frequency so that content above it doesn't alias.
+pub fn resample(input: &[f32], from_rate: f32, to_rate: f32, quality: ResampleQuality) -> Vec<f32> {
+    if input.is_empty() || from_rate == to_rate {
+        return input.to_vec();
+    }
+
+    let step = from_rate as f64 / to_rate as f64;
+    // Slightly below the lower of the two Nyquist frequencies to leave room for the transition band
+    let cutoff = 0.97 * (1. / step).min(1.);
+    let half_width = quality.zero_crossings() as f64 / cutoff;
+    let output_len = (input.len() as f64 / step).ceil() as usize;
+
+    (0..output_len)
+        .map(|output_ix| {
+            let center = output_ix as f64 * step;
+            let first_ix = (center - half_width).ceil().max(0.) as usize;
+            let last_ix = ((center + half_width).floor() as usize).min(input.len() - 1);
+            let sum: f64 = (first_ix..=last_ix)
+                .map(|input_ix| {
+                    let distance = input_ix as f64 - center;
+                    let sample = input[input_ix];
+                    let sample = if sample.is_finite() {
+                        sample as f64
+                    } else {
+                        0.
+                    };
+                    sample * cutoff * sinc(cutoff * distance) * blackman(distance / half_width)
+                })
+                .sum();
+            sum as f32
+        })
+        .collect()
+}
+
+/// Converts interleaved audio with `channels` channels, resampling each channel on its own
+pub fn resample_interleaved(
+    input: &[f32],
+    channels: usize,
+    from_rate: f32,
+    to_rate: f32,
+    quality: ResampleQuality,
+) -> Vec<f32> {
+    if channels <= 1 {
+        return resample(input, from_rate, to_rate, quality);
+    }
+    let resampled: Vec<Vec<f32>> = (0..channels)
+        .map(|channel| {
+            let samples: Vec<f32> = input
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect();
+            resample(&samples, from_rate, to_rate, quality)
+        })
+        .collect();
+    let len = resampled.iter().map(Vec::len).min().unwrap_or(0);
+    (0..len)
+        .flat_map(|ix| resampled.iter().map(move |channel| channel[ix]))
+        .collect()
+}
+
+#[test]
+fn resampled_sine_keeps_its_frequency() {
+    let sine = |rate: f32, i: usize| (2. * std::f32::consts::PI * 1000. * i as f32 / rate).sin();
+    let input: Vec<f32> = (0..4800).map(|i| sine(48000., i)).collect();
+    let output = resample(&input, 48000., 44100., ResampleQuality::Standard);
+    assert_eq!(output.len(), 4410);
+    // Away from the edges, where the kernel runs out of input
+    for (i, sample) in output.iter().enumerate().take(4300).skip(100) {
+        assert!((sample - sine(44100., i)).abs() < 0.01, "{}", i);
+    }
+}
@@ -0,0 +1,80 @@
+// Karplus-Strong resonator
+// A feedback comb with a fractional delay of one period and a one-pole lowpass in its loop.  Fed
+// with noise bursts or grains it rings at its tuned pitch like a plucked string, and the lowpass
+// makes high partials die away faster than low ones.
+
+use super::{clamp, mix};
+
+#[derive(Clone, Default)]
+pub struct CombResonator {
+    buffer: Vec<f32>,
+    write_ix: usize,
+    /// State of the loop lowpass
+    damped: f32,
+}
+
+impl CombResonator {
+    pub fn new(max_delay_samples: usize) -> Self {
+        CombResonator {
+            buffer: vec![0.; max_delay_samples + 2],
+            write_ix: 0,
+            damped: 0.,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.);
+        self.damped = 0.;
+    }
+
+    /// Processes one sample.  `period_samples` is the period of the pitch to ring at, `feedback`
+    /// from 0 to just below 1 sets how long it rings and `damping` from 0 to just below 1 how much
+    /// darker every repeat gets.  Returns the resonator's output, without the input.
+    pub fn process(&mut self, input: f32, period_samples: f32, feedback: f32, damping: f32) -> f32 {
+        let len = self.buffer.len();
+        if len < 3 {
+            return 0.;
+        }
+        // The lowpass delays low frequencies by `damping / (1 - damping)` samples, which would
+        // flatten the pitch
+        let delay = clamp(
+            1.,
+            (len - 2) as f32,
+            period_samples - damping / (1. - damping),
+        );
+        let read_pos = self.write_ix as f32 + len as f32 - delay;
+        let read_ix = read_pos as usize;
+        let delayed = mix(
+            read_pos.fract(),
+            self.buffer[read_ix % len],
+            self.buffer[(read_ix + 1) % len],
+        );
+        self.damped = mix(damping, delayed, self.damped);
+        let written = input + feedback * self.damped;
+        // Guard the loop against NaN and runaway input
+        self.buffer[self.write_ix] = if written.is_finite() { written } else { 0. };
+        self.write_ix = (self.write_ix + 1) % len;
+        self.damped
+    }
+}
+
+#[test]
+fn resonator_rings_at_its_period_and_decays() {
+    let mut resonator = CombResonator::new(1000);
+    let output: Vec<f32> = (0..4000)
+        .map(|ix| {
+            let input = if ix == 0 { 1. } else { 0. };
+            resonator.process(input, 100.5, 0.99, 0.3)
+        })
+        .collect();
+    // The second repeat of the impulse is two periods in
+    let peak_ix = (150..250)
+        .max_by(|&a, &b| output[a].total_cmp(&output[b]))
+        .unwrap();
+    assert!((peak_ix as f32 - 201.).abs() <= 2., "{}", peak_ix);
+    let level = |range: std::ops::Range<usize>| output[range].iter().fold(0f32, |m, s| m.max(*s));
+    assert!(level(3000..4000) < level(0..1000) * 0.5);
+
+    resonator.clear();
+    assert_eq!(resonator.process(0., 100., 0.99, 0.3), 0.);
+}
@@ -0,0 +1,223 @@
+// Saturation
+// Memoryless waveshapers that round off peaks the harder they're driven, adding the harmonics of
+// analog overload, and a saturator that runs them oversampled.  Shaping creates harmonics above
+// Nyquist that would otherwise fold back down as inharmonic aliases, so the saturator shapes at
+// two or four times the sample rate between lowpasses that take them out again.
+
+use super::filters::biquad::{Biquad, BiquadCoefficients};
+
+/// Edge of the band kept around the nonlinearity, as a fraction of the original sample rate
+const PASSBAND_RATIO: f32 = 0.4;
+/// Qs of the four sections of an eighth-order Butterworth lowpass
+const LOWPASS_QS: [f32; 4] = [0.509_795_6, 0.601_344_9, 0.899_976_2, 2.562_915_4];
+/// Corner of the DC blocker after asymmetric curves, which shift the average of what they shape
+const DC_BLOCKER_HZ: f32 = 10.;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SaturationModel {
+    /// Smooth and symmetric, adding odd harmonics
+    #[default]
+    Tanh,
+    /// Cubic curve that stays clean below its knee and clips flat above it
+    SoftKnee,
+    /// Exponential curve of a pair of clipping diodes, with a sharper knee than tanh
+    Diode,
+    /// Asymmetric curve that clips negative peaks harder than positive ones, adding the even
+    /// harmonics of a tube stage
+    Tube,
+}
+
+impl SaturationModel {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(SaturationModel::Tanh),
+            1 => Some(SaturationModel::SoftKnee),
+            2 => Some(SaturationModel::Diode),
+            3 => Some(SaturationModel::Tube),
+            _ => None,
+        }
+    }
+
+    /// The curve itself, with a slope of 1 at 0
+    fn curve(self, x: f32) -> f32 {
+        match self {
+            SaturationModel::Tanh => x.tanh(),
+            SaturationModel::SoftKnee => {
+                // The knee is at 1.5, where the cubic reaches 1 with a slope of 0
+                let x = x.clamp(-1.5, 1.5);
+                x - 4. / 27. * x * x * x
+            }
+            SaturationModel::Diode => x.signum() * (1. - (-x.abs()).exp()),
+            SaturationModel::Tube if x >= 0. => x.tanh(),
+            SaturationModel::Tube => (1.5 * x).tanh() / 1.5,
+        }
+    }
+
+    /// The curve driven by `drive` of at least 1, scaled so that positive full scale still maps
+    /// to full scale
+    pub fn shape(self, sample: f32, drive: f32) -> f32 {
+        let drive = drive.max(1.);
+        self.curve(sample * drive) / self.curve(drive)
+    }
+}
+
+/// A saturation curve run at `oversampling` times the sample rate
+#[derive(Clone, Copy)]
+pub struct Saturator {
+    pub model: SaturationModel,
+    pub drive: f32,
+    /// 1, 2 or 4
+    oversampling: usize,
+    upsampling_lowpass: [Biquad; 4],
+    downsampling_lowpass: [Biquad; 4],
+    dc_coefficient: f32,
+    /// Last input and output of the DC blocker
+    dc_state: (f32, f32),
+}
+
+impl Saturator {
+    pub fn new(model: SaturationModel, drive: f32, oversampling: usize, sample_rate: f32) -> Self {
+        let oversampling = match oversampling {
+            0 | 1 => 1,
+            2 | 3 => 2,
+            _ => 4,
+        };
+        let oversampled_rate = sample_rate * oversampling as f32;
+        let lowpass = LOWPASS_QS.map(|q| {
+            Biquad::new(BiquadCoefficients::lowpass(
+                sample_rate * PASSBAND_RATIO,
+                q,
+                oversampled_rate,
+            ))
+        });
+        Saturator {
+            model,
+            drive,
+            oversampling,
+            upsampling_lowpass: lowpass,
+            downsampling_lowpass: lowpass,
+            dc_coefficient: 1. - std::f32::consts::TAU * DC_BLOCKER_HZ / sample_rate,
+            dc_state: (0., 0.),
+        }
+    }
+
+    pub fn oversampling(&self) -> usize {
+        self.oversampling
+    }
+
+    /// How far the lowpasses around an oversampled curve delay the signal, in samples at the
+    /// original rate
+    pub fn latency_samples(&self) -> f32 {
+        if self.oversampling == 1 {
+            return 0.;
+        }
+        let delay: f32 = self
+            .upsampling_lowpass
+            .iter()
+            .chain(&self.downsampling_lowpass)
+            .map(|section| section.coefficients.dc_group_delay())
+            .sum();
+        delay / self.oversampling as f32
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let output = if self.oversampling == 1 {
+            self.model.shape(sample, self.drive)
+        } else {
+            // Stuffing zeros between the samples keeps the spectrum but mirrors it into every
+            // multiple of the old sample rate, which the first lowpass takes out, and scaling the
+            // input makes up for the level the zeros lose
+            let mut output = 0.;
+            for ix in 0..self.oversampling {
+                let stuffed = if ix == 0 {
+                    sample * self.oversampling as f32
+                } else {
+                    0.
+                };
+                let upsampled = cascade(&mut self.upsampling_lowpass, stuffed);
+                let shaped = self.model.shape(upsampled, self.drive);
+                let filtered = cascade(&mut self.downsampling_lowpass, shaped);
+                if ix == 0 {
+                    output = filtered;
+                }
+            }
+            output
+        };
+        if self.model != SaturationModel::Tube {
+            return output;
+        }
+        let (last_input, last_output) = self.dc_state;
+        let blocked = output - last_input + self.dc_coefficient * last_output;
+        self.dc_state = (output, blocked);
+        blocked
+    }
+
+    /// Clears the lowpasses and the DC blocker
+    pub fn reset(&mut self) {
+        for section in self
+            .upsampling_lowpass
+            .iter_mut()
+            .chain(&mut self.downsampling_lowpass)
+        {
+            section.reset();
+        }
+        self.dc_state = (0., 0.);
+    }
+}
+
+fn cascade(sections: &mut [Biquad], sample: f32) -> f32 {
+    sections
+        .iter_mut()
+        .fold(sample, |sample, section| section.process(sample))
+}
+
+#[test]
+fn tanh_saturation_keeps_full_scale_and_rounds_off_peaks() {
+    let tanh = |sample, drive| SaturationModel::Tanh.shape(sample, drive);
+    assert!((tanh(1., 4.) - 1.).abs() < 1e-6);
+    assert!((tanh(-1., 4.) + 1.).abs() < 1e-6);
+    assert!(tanh(0.5, 4.) > 0.9);
+    assert!(tanh(10., 4.) < 1.001);
+    assert!((tanh(0.5, 1.) - 0.5f32.tanh() / 1f32.tanh()).abs() < 1e-6);
+}
+
+#[test]
+fn models_clip_differently_and_oversampling_cuts_aliases() {
+    for ix in 0..4 {
+        let model = SaturationModel::from_index(ix).unwrap();
+        assert!((model.shape(1., 4.) - 1.).abs() < 1e-6);
+        assert!((model.shape(0.01, 1.) * model.curve(1.) - 0.01).abs() < 1e-4);
+    }
+    assert_eq!(SaturationModel::from_index(4), None);
+    // The soft knee is flat above its knee
+    let soft_knee = SaturationModel::SoftKnee;
+    assert_eq!(soft_knee.shape(2., 1.), soft_knee.shape(5., 1.));
+    // The tube clips negative peaks harder
+    let tube = SaturationModel::Tube;
+    assert!(tube.shape(-1., 4.) > -1.);
+
+    // A loud 11 kHz tone at 48 kHz has a third harmonic at 33 kHz, which aliases down to 15 kHz
+    let sample_rate = 48000.;
+    let alias_level = |oversampling: usize| {
+        let mut saturator = Saturator::new(SaturationModel::Tanh, 4., oversampling, sample_rate);
+        let output: Vec<f32> = (0..4800)
+            .map(|ix| {
+                let phase = std::f32::consts::TAU * 11000. * ix as f32 / sample_rate;
+                saturator.process(phase.sin())
+            })
+            .collect();
+        // Correlate the second half with 15 kHz
+        let (sin, cos) = output[2400..]
+            .iter()
+            .enumerate()
+            .fold((0., 0.), |(s, c), (ix, x)| {
+                let phase = std::f32::consts::TAU * 15000. * (ix + 2400) as f32 / sample_rate;
+                (s + x * phase.sin(), c + x * phase.cos())
+            });
+        (sin * sin + cos * cos).sqrt() / 1200.
+    };
+    let aliased = alias_level(1);
+    let oversampled = alias_level(4);
+    assert!(aliased > 0.1, "{}", aliased);
+    assert!(oversampled < aliased * 0.1, "{} {}", oversampled, aliased);
+}
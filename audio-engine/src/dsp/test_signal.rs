@@ -0,0 +1,107 @@
+// Test signals
+// Known sources for checking filters, envelopes and latency without loading audio: an exponential
+// sine sweep covers the audible range with equal time per octave, an impulse marks a single sample
+// and white noise has a flat spectrum.  Noise is generated from a fixed seed so that every burst
+// is the same.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::f64::consts::PI;
+
+/// Amplitude of the sweep and the noise, leaving headroom for filter resonance
+pub const TEST_SIGNAL_AMPLITUDE: f32 = 0.5;
+/// Frequency the sweep starts at
+pub const SWEEP_START_HZ: f64 = 20.;
+/// Frequency the sweep ends at, or less if that's too close to Nyquist
+pub const SWEEP_END_HZ: f64 = 20000.;
+/// Longest signal that's generated
+pub const MAX_TEST_SIGNAL_SECONDS: f32 = 60.;
+const NOISE_SEED: u64 = 0x5eed;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TestSignal {
+    Sweep,
+    /// A full-scale sample at the start followed by silence
+    Impulse,
+    Noise,
+}
+
+impl TestSignal {
+    pub fn from_u32(value: u32) -> Option<TestSignal> {
+        match value {
+            0 => Some(TestSignal::Sweep),
+            1 => Some(TestSignal::Impulse),
+            2 => Some(TestSignal::Noise),
+            _ => None,
+        }
+    }
+
+    /// `len` samples of the signal at `sample_rate`
+    pub fn generate(self, len: usize, sample_rate: f32) -> Vec<f32> {
+        match self {
+            TestSignal::Sweep => sweep(len, sample_rate as f64),
+            TestSignal::Impulse => {
+                let mut samples = vec![0.; len];
+                if let Some(first) = samples.first_mut() {
+                    *first = 1.;
+                }
+                samples
+            }
+            TestSignal::Noise => {
+                let mut rng = StdRng::seed_from_u64(NOISE_SEED);
+                (0..len)
+                    .map(|_| rng.gen_range(-TEST_SIGNAL_AMPLITUDE..=TEST_SIGNAL_AMPLITUDE))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Exponential sweep whose frequency rises from `SWEEP_START_HZ` to `SWEEP_END_HZ` over `len`
+/// samples.  The phase is the integral of the frequency, so the sweep is continuous.
+fn sweep(len: usize, sample_rate: f64) -> Vec<f32> {
+    let end_hz = SWEEP_END_HZ.min(sample_rate * 0.45).max(SWEEP_START_HZ);
+    let duration = len as f64 / sample_rate;
+    let log_ratio = (end_hz / SWEEP_START_HZ).ln();
+    (0..len)
+        .map(|ix| {
+            let t = ix as f64 / sample_rate;
+            let phase = if log_ratio > 0. {
+                let rate = log_ratio / duration;
+                2. * PI * SWEEP_START_HZ * ((rate * t).exp() - 1.) / rate
+            } else {
+                2. * PI * SWEEP_START_HZ * t
+            };
+            phase.sin() as f32 * TEST_SIGNAL_AMPLITUDE
+        })
+        .collect()
+}
+
+#[test]
+fn test_signals_have_their_shapes() {
+    let sample_rate = 44100.;
+    let bounded = |samples: &[f32]| samples.iter().all(|x| x.abs() <= TEST_SIGNAL_AMPLITUDE);
+    let impulse = TestSignal::Impulse.generate(100, sample_rate);
+    assert_eq!(impulse[0], 1.);
+    assert!(impulse[1..].iter().all(|sample| *sample == 0.));
+    assert!(TestSignal::Impulse.generate(0, sample_rate).is_empty());
+
+    let noise = TestSignal::Noise.generate(4410, sample_rate);
+    assert_eq!(noise, TestSignal::Noise.generate(4410, sample_rate));
+    let mean = noise.iter().sum::<f32>() / noise.len() as f32;
+    assert!(mean.abs() < 0.02, "{}", mean);
+    assert!(bounded(&noise));
+
+    // Rising zero crossings count the cycles, about 0.2 in the first 10 ms and 200 in the last
+    let sweep = TestSignal::Sweep.generate(44100, sample_rate);
+    let crossings = |range: std::ops::Range<usize>| {
+        sweep[range]
+            .windows(2)
+            .filter(|pair| pair[0] < 0. && pair[1] >= 0.)
+            .count()
+    };
+    let (start, end) = (crossings(0..441), crossings(43659..44100));
+    assert!(start <= 1, "{}", start);
+    assert!((190..=210).contains(&end), "{}", end);
+    assert!(bounded(&sweep));
+}
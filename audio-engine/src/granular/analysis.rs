@@ -0,0 +1,386 @@
+//! Analysis of the loaded waveform.  The RMS envelope is computed on request over fixed windows
+//! of the buffer; hosts can draw it directly and the engine can use it to favour loud regions when
+//! picking randomized grain start positions.
+//!
+//! The feature map splits the buffer into longer regions and measures the energy and spectral
+//! centroid of each, so that grain spawning can prefer loud, quiet, bright or dark material.
+
+use rand::Rng;
+
+use super::psola::PitchTrack;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::fft::spectral_centroid;
+
+/// Randomized start positions are redrawn at most this many times looking for an accepted one
+const MAX_POSITION_CANDIDATES: usize = 16;
+/// Longest transform used to measure the centroid of a region; longer regions are measured over
+/// their central part
+const MAX_CENTROID_FFT_LEN: usize = 4096;
+const MIN_CENTROID_FFT_LEN: usize = 64;
+
+/// RMS level of every `window_len` samples of the waveform
+#[derive(Clone, Default)]
+pub struct RmsEnvelope {
+    pub window_len: usize,
+    pub values: Vec<f32>,
+    max: f32,
+}
+
+impl RmsEnvelope {
+    pub fn compute(waveform: &[f32], window_len: usize) -> Self {
+        let window_len = window_len.max(1);
+        let values: Vec<f32> = waveform
+            .chunks(window_len)
+            .map(|window| {
+                let sum_of_squares: f32 = window
+                    .iter()
+                    .filter(|sample| sample.is_finite())
+                    .map(|sample| sample * sample)
+                    .sum();
+                (sum_of_squares / window.len() as f32).sqrt()
+            })
+            .collect();
+        let max = values.iter().copied().fold(0., f32::max);
+        RmsEnvelope {
+            window_len,
+            values,
+            max,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Level of the window containing `sample_ix` relative to the loudest window
+    pub fn normalized_at(&self, sample_ix: f32) -> f32 {
+        if self.max <= 0. {
+            return 0.;
+        }
+        let window_ix = (sample_ix.max(0.) as usize / self.window_len).min(self.values.len() - 1);
+        self.values[window_ix] / self.max
+    }
+}
+
+/// Weights randomized grain start positions by the RMS envelope
+#[derive(Clone, Default)]
+pub struct PositionWeighting {
+    pub envelope: RmsEnvelope,
+    /// 0 picks positions uniformly, 1 picks them in proportion to their level
+    pub amount: f32,
+}
+
+impl PositionWeighting {
+    pub fn is_active(&self) -> bool {
+        self.amount > 0. && !self.envelope.is_empty()
+    }
+
+    /// Draws start positions from `draw` until one is accepted with a probability given by its
+    /// weight, falling back to the last candidate so that silent buffers still spawn grains.
+    /// `draw` gets the same generator, so the voice's random choices come from one stream.
+    pub fn pick<R: Rng>(&self, rng: &mut R, mut draw: impl FnMut(&mut R) -> f32) -> f32 {
+        let mut candidate = draw(rng);
+        if !self.is_active() {
+            return candidate;
+        }
+
+        for _ in 1..MAX_POSITION_CANDIDATES {
+            let weight = 1. - self.amount + self.amount * self.envelope.normalized_at(candidate);
+            if rng.gen::<f32>() < weight {
+                break;
+            }
+            candidate = draw(rng);
+        }
+        candidate
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct RegionFeatures {
+    /// RMS level of the region
+    pub energy: f32,
+    pub centroid_hz: f32,
+}
+
+/// RMS level of a region
+pub fn region_energy(region: &[f32]) -> f32 {
+    RmsEnvelope::compute(region, region.len()).values[0]
+}
+
+/// Spectral centroid of a region, measured over its central part if it's long.  Regions too short
+/// to measure have a centroid of 0.
+pub fn region_centroid(region: &[f32], sample_rate: f32) -> f32 {
+    let fft_len = if region.len().is_power_of_two() {
+        region.len()
+    } else {
+        region.len().next_power_of_two() / 2
+    }
+    .min(MAX_CENTROID_FFT_LEN);
+    if fft_len < MIN_CENTROID_FFT_LEN {
+        return 0.;
+    }
+    let offset = (region.len() - fft_len) / 2;
+    spectral_centroid(&region[offset..offset + fft_len], sample_rate)
+}
+
+/// Features of every `region_len` samples of the waveform
+#[derive(Clone, Default)]
+pub struct FeatureMap {
+    pub region_len: usize,
+    pub regions: Vec<RegionFeatures>,
+    max_energy: f32,
+    min_centroid_hz: f32,
+    max_centroid_hz: f32,
+}
+
+impl FeatureMap {
+    pub fn compute(waveform: &[f32], region_len: usize, sample_rate: f32) -> Self {
+        let region_len = region_len.max(1);
+        let regions: Vec<RegionFeatures> = waveform
+            .chunks(region_len)
+            .map(|region| RegionFeatures {
+                energy: region_energy(region),
+                centroid_hz: region_centroid(region, sample_rate),
+            })
+            .collect();
+
+        // Silent regions have no meaningful centroid, so they don't count towards its range
+        let audible_centroids = || {
+            regions
+                .iter()
+                .filter(|region| region.energy > 0.)
+                .map(|region| region.centroid_hz)
+        };
+        FeatureMap {
+            region_len,
+            max_energy: regions
+                .iter()
+                .map(|region| region.energy)
+                .fold(0., f32::max),
+            min_centroid_hz: audible_centroids().fold(f32::INFINITY, f32::min),
+            max_centroid_hz: audible_centroids().fold(0., f32::max),
+            regions,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    fn region_at(&self, sample_ix: f32) -> &RegionFeatures {
+        let region_ix = (sample_ix.max(0.) as usize / self.region_len).min(self.regions.len() - 1);
+        &self.regions[region_ix]
+    }
+
+    /// Energy of the region containing `sample_ix` relative to the loudest region
+    pub fn normalized_energy_at(&self, sample_ix: f32) -> f32 {
+        if self.max_energy <= 0. {
+            return 0.;
+        }
+        self.region_at(sample_ix).energy / self.max_energy
+    }
+
+    /// Centroid of the region containing `sample_ix` within the range of centroids of audible
+    /// regions, from 0 for the darkest to 1 for the brightest.  Silent regions count as dark.
+    pub fn normalized_brightness_at(&self, sample_ix: f32) -> f32 {
+        let region = self.region_at(sample_ix);
+        let range = self.max_centroid_hz - self.min_centroid_hz;
+        if region.energy <= 0. || range <= 0. {
+            return 0.;
+        }
+        (region.centroid_hz - self.min_centroid_hz) / range
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FeaturePreference {
+    #[default]
+    Loud,
+    Quiet,
+    Bright,
+    Dark,
+}
+
+impl FeaturePreference {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(FeaturePreference::Loud),
+            1 => Some(FeaturePreference::Quiet),
+            2 => Some(FeaturePreference::Bright),
+            3 => Some(FeaturePreference::Dark),
+            _ => None,
+        }
+    }
+}
+
+/// Weights the probability of spawning grains by the features of the region under the read head
+#[derive(Clone, Default)]
+pub struct FeatureWeighting {
+    pub map: FeatureMap,
+    pub preference: FeaturePreference,
+    /// 0 spawns every grain, 1 spawns grains with a probability equal to the region's score
+    pub amount: f32,
+}
+
+impl FeatureWeighting {
+    pub fn is_active(&self) -> bool {
+        self.amount > 0. && !self.map.is_empty()
+    }
+
+    /// How well the region containing `sample_ix` matches the preference, from 0 to 1
+    pub fn score_at(&self, sample_ix: f32) -> f32 {
+        match self.preference {
+            FeaturePreference::Loud => self.map.normalized_energy_at(sample_ix),
+            FeaturePreference::Quiet => 1. - self.map.normalized_energy_at(sample_ix),
+            FeaturePreference::Bright => self.map.normalized_brightness_at(sample_ix),
+            FeaturePreference::Dark => 1. - self.map.normalized_brightness_at(sample_ix),
+        }
+    }
+
+    /// Decides whether a grain that's due to spawn at `sample_ix` actually does
+    pub fn should_spawn(&self, rng: &mut impl Rng, sample_ix: f32) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        let probability = 1. - self.amount + self.amount * self.score_at(sample_ix);
+        rng.gen::<f32>() < probability
+    }
+}
+
+/// Computes the RMS level of every `window_ms` of the loaded waveform and returns the levels in
+/// order.  The envelope is also kept for energy-weighted grain positions, so call this again after
+/// loading a new waveform.
+pub fn compute_rms_envelope(ctx: *mut GranularCtx, window_ms: f32) -> Vec<f32> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    if !window_ms.is_finite() || window_ms <= 0. {
+        return Vec::new();
+    }
+    let window_len = (window_ms * 0.001 * ctx.sample_rate).round() as usize;
+    ctx.position_weighting.envelope = RmsEnvelope::compute(&ctx.samples().decode(), window_len);
+    ctx.position_weighting.envelope.values.clone()
+}
+
+/// Sets how strongly randomized grain start positions favour loud regions of the waveform, from 0
+/// (uniform) to 1 (in proportion to the RMS envelope).  Has no effect until
+/// `compute_rms_envelope` has been called.
+pub fn set_position_weighting(ctx: *mut GranularCtx, amount: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !amount.is_finite() {
+        return;
+    }
+    ctx.position_weighting.amount = clamp(0., 1., amount);
+}
+
+/// Measures the energy and spectral centroid of every `region_ms` of the loaded waveform and
+/// returns them as interleaved `[rms, centroid_hz]` pairs.  The features are also kept for
+/// `set_feature_weighting`, so call this again after loading a new waveform.
+pub fn analyze_features(ctx: *mut GranularCtx, region_ms: f32) -> Vec<f32> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    if !region_ms.is_finite() || region_ms <= 0. {
+        return Vec::new();
+    }
+    let region_len = (region_ms * 0.001 * ctx.sample_rate).round() as usize;
+    ctx.feature_weighting.map =
+        FeatureMap::compute(&ctx.samples().decode(), region_len, ctx.sample_rate);
+    ctx.feature_weighting
+        .map
+        .regions
+        .iter()
+        .flat_map(|region| [region.energy, region.centroid_hz])
+        .collect()
+}
+
+/// Makes grain spawning favour regions of the waveform matching `preference` (0 = loud,
+/// 1 = quiet, 2 = bright, 3 = dark).  At an `amount` of 1 a grain due at the read head spawns with
+/// a probability equal to how well its region matches; at 0 every grain spawns.
+pub fn set_feature_weighting(ctx: *mut GranularCtx, preference: u32, amount: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(preference) = FeaturePreference::from_index(preference) else {
+        return;
+    };
+    if !amount.is_finite() {
+        return;
+    }
+    ctx.feature_weighting.preference = preference;
+    ctx.feature_weighting.amount = clamp(0., 1., amount);
+}
+
+/// Tracks the pitch of every `window_ms` of the loaded waveform and returns it in Hz, with 0 for
+/// unpitched windows.  The track is kept for PSOLA voices, so call this again after loading a new
+/// waveform.
+pub fn analyze_pitch(ctx: *mut GranularCtx, window_ms: f32) -> Vec<f32> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    if !window_ms.is_finite() || window_ms <= 0. {
+        return Vec::new();
+    }
+    let window_len = (window_ms * 0.001 * ctx.sample_rate).round() as usize;
+    ctx.pitch_track = PitchTrack::analyze(&ctx.samples().decode(), window_len, ctx.sample_rate);
+    ctx.pitch_track
+        .periods
+        .iter()
+        .map(|period| period.map_or(0., |period| ctx.sample_rate / period))
+        .collect()
+}
+
+#[test]
+fn weighted_positions_favour_loud_regions() {
+    // The first half of the buffer is silent and the second half is loud
+    let waveform: Vec<f32> = (0..1000).map(|i| if i < 500 { 0. } else { 0.5 }).collect();
+    let envelope = RmsEnvelope::compute(&waveform, 100);
+    assert_eq!(envelope.values.len(), 10);
+    assert_eq!(envelope.values[0], 0.);
+    assert_eq!(envelope.values[9], 0.5);
+    assert_eq!(envelope.normalized_at(999.), 1.);
+
+    let weighting = PositionWeighting {
+        envelope,
+        amount: 1.,
+    };
+    let mut rng = crate::common::rng();
+    let mut candidates = (0..1000).cycle().step_by(37).map(|ix| ix as f32);
+    let loud_picks = (0..100)
+        .filter(|_| weighting.pick(&mut rng, |_| candidates.next().unwrap()) >= 500.)
+        .count();
+    assert_eq!(loud_picks, 100);
+}
+
+#[test]
+fn features_rank_regions_by_brightness() {
+    use std::f32::consts::PI;
+
+    // A low sine, silence, then a high sine
+    let sample_rate = 48000.;
+    let waveform: Vec<f32> = (0..3 * 4096)
+        .map(|i| match i / 4096 {
+            0 => (2. * PI * 200. * i as f32 / sample_rate).sin(),
+            1 => 0.,
+            _ => 0.5 * (2. * PI * 5000. * i as f32 / sample_rate).sin(),
+        })
+        .collect();
+    let map = FeatureMap::compute(&waveform, 4096, sample_rate);
+    assert_eq!(map.regions.len(), 3);
+    assert_eq!(map.normalized_brightness_at(0.), 0.);
+    assert_eq!(map.normalized_brightness_at(5000.), 0.);
+    assert_eq!(map.normalized_brightness_at(9000.), 1.);
+    assert_eq!(map.normalized_energy_at(0.), 1.);
+
+    let weighting = FeatureWeighting {
+        map,
+        preference: FeaturePreference::Dark,
+        amount: 1.,
+    };
+    let mut rng = crate::common::rng();
+    assert!(weighting.should_spawn(&mut rng, 100.));
+    assert!(!weighting.should_spawn(&mut rng, 9000.));
+}
@@ -0,0 +1,162 @@
+//! Grain arpeggiation.  A voice with a pitch sequence transposes each grain it spawns by the
+//! current step of the sequence, so successive grains walk through a melodic pattern.  The
+//! sequence moves on a step with every grain, or every so many beats of the host's tempo, locked
+//! to its song position while the transport plays and running freely at its tempo otherwise.
+
+use super::params::VOICE_COUNT;
+use super::transport::Transport;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Most steps in a sequence
+pub const MAX_ARPEGGIO_STEPS: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Arpeggio {
+    /// Transposition of each step from the voice's pitch
+    steps: [f32; MAX_ARPEGGIO_STEPS],
+    len: usize,
+    /// Beats per step when stepped with the tempo, or None to step on every grain
+    pub step_beats: Option<f32>,
+    step_ix: usize,
+    /// Steps into the sequence while the transport is stopped
+    phase: f64,
+}
+
+impl Arpeggio {
+    /// Returns None for no steps or more than `MAX_ARPEGGIO_STEPS`
+    pub fn new(semitones: &[f32], step_beats: Option<f32>) -> Option<Self> {
+        if semitones.is_empty() || semitones.len() > MAX_ARPEGGIO_STEPS {
+            return None;
+        }
+        let mut steps = [0.; MAX_ARPEGGIO_STEPS];
+        steps[..semitones.len()].copy_from_slice(semitones);
+        Some(Arpeggio {
+            steps,
+            len: semitones.len(),
+            step_beats,
+            step_ix: 0,
+            phase: 0.,
+        })
+    }
+
+    /// Advances a tempo-stepped sequence by one sample
+    pub fn tick(&mut self, transport: &Transport, sample_rate: f32) {
+        let Some(beats) = self.step_beats else {
+            return;
+        };
+        let step_frames = transport.beats_to_frames(beats, sample_rate);
+        self.phase = if transport.playing {
+            transport.position_frames / step_frames
+        } else {
+            self.phase + 1. / step_frames
+        }
+        .rem_euclid(self.len as f64);
+        self.step_ix = (self.phase as usize).min(self.len - 1);
+    }
+
+    /// Transposition in semitones of the grain about to spawn
+    pub fn next_grain(&mut self) -> f32 {
+        let semitones = self.steps[self.step_ix];
+        if self.step_beats.is_none() {
+            self.step_ix = (self.step_ix + 1) % self.len;
+        }
+        semitones
+    }
+}
+
+/// Transposes each grain a voice spawns by the next step of a sequence of `semitones` (-48 to 48
+/// each), moving on a step every `step_beats` beats of the host's tempo, or with every grain if
+/// it's 0.  Returns false and changes nothing for an empty sequence or one longer than
+/// `MAX_ARPEGGIO_STEPS`.
+pub fn set_voice_arpeggio(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    semitones: &[f32],
+    step_beats: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT
+        || !step_beats.is_finite()
+        || !semitones.iter().all(|step| step.is_finite())
+    {
+        return false;
+    }
+    let steps: Vec<f32> = semitones
+        .iter()
+        .map(|&step| clamp(-48., 48., step))
+        .collect();
+    let step_beats = (step_beats > 0.).then(|| clamp(1. / 64., 64., step_beats));
+    let Some(arpeggio) = Arpeggio::new(&steps, step_beats) else {
+        return false;
+    };
+    ctx.voices[voice_ix].arpeggio = Some(arpeggio);
+    true
+}
+
+/// Stops a voice's grains walking through its pitch sequence
+pub fn clear_voice_arpeggio(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.arpeggio = None;
+    }
+}
+
+#[test]
+fn sequences_step_per_grain_or_per_beat() {
+    assert_eq!(Arpeggio::new(&[], None), None);
+
+    let mut arpeggio = Arpeggio::new(&[0., 4., 7.], None).unwrap();
+    let grains: Vec<f32> = (0..4).map(|_| arpeggio.next_grain()).collect();
+    assert_eq!(grains, [0., 4., 7., 0.]);
+
+    // One beat at 120 BPM and 100 Hz is 50 frames, so each step lasts 25
+    let mut arpeggio = Arpeggio::new(&[0., 4., 7.], Some(0.5)).unwrap();
+    let mut transport = Transport::default();
+    transport.set(true, 120., 30.);
+    arpeggio.tick(&transport, 100.);
+    assert_eq!(arpeggio.next_grain(), 4.);
+    assert_eq!(arpeggio.next_grain(), 4.);
+    transport.set(true, 120., 80.);
+    arpeggio.tick(&transport, 100.);
+    assert_eq!(arpeggio.next_grain(), 0.);
+
+    // Stopped, it carries on at the tempo
+    transport.set(false, 120., 0.);
+    for _ in 0..25 {
+        arpeggio.tick(&transport, 100.);
+    }
+    assert_eq!(arpeggio.next_grain(), 4.);
+}
+
+#[test]
+fn arpeggiated_grains_walk_through_the_sequence() {
+    use super::params::{GlobalParam, ParamId};
+    use super::{sine_ctx, spawn_events};
+
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    assert!(!set_voice_arpeggio(&mut ctx, 0, &[], 0.));
+    assert!(set_voice_arpeggio(&mut ctx, 0, &[0., 12., 7.], 0.));
+    ctx.grain_trace.set_enabled(true);
+    let targets = *ctx.params.target();
+    for _ in 0..256 {
+        ctx.render(&targets);
+    }
+    let ratios: Vec<f32> = spawn_events(&mut ctx, 0)
+        .iter()
+        .map(|event| event.sample_playback_ratio)
+        .collect();
+    assert!(ratios.len() >= 4);
+    assert_eq!(ratios[..2], [1., 2.]);
+    assert!((ratios[2] - 2f32.powf(7. / 12.)).abs() < 1e-4);
+    assert_eq!(ratios[3], 1.);
+
+    clear_voice_arpeggio(&mut ctx, 0);
+    assert!(ctx.voices[0].arpeggio.is_none());
+}
@@ -0,0 +1,169 @@
+//! Audio-rate parameters.  A host can hand selected parameters to the engine as a buffer of one
+//! value per sample of a frame instead of a single target, so that Web Audio `AudioParam`
+//! automation computed in the worklet can drive e.g. a cutoff or a position at audio rate.  While
+//! a parameter is audio-rate its buffer overrides its smoothed value, and any automation lane, on
+//! every sample, and the buffer is read again every frame until the host writes new values.
+//! Control-rate parameters only change once per block, so they can't be audio-rate.
+
+use super::params::{ParamId, ParamRate, ParamValues};
+use super::FRAME_SIZE;
+use super::{ctx_mut, GranularCtx};
+
+/// Most parameters that can be audio-rate at once
+pub const MAX_AUDIO_RATE_PARAMS: usize = 8;
+
+#[derive(Clone, Default)]
+pub struct AudioRateParams {
+    /// The buffers are boxed so that the pointers handed to the host stay put as params come and
+    /// go
+    slots: Vec<(ParamId, Box<[f32; FRAME_SIZE]>)>,
+}
+
+impl AudioRateParams {
+    pub fn is_active(&self) -> bool {
+        !self.slots.is_empty()
+    }
+
+    /// Makes `param` audio-rate, starting its buffer at `value`, and returns its buffer.  A param
+    /// that's already audio-rate keeps its buffer.  Returns None for a control-rate param or when
+    /// all the slots are taken.
+    pub fn enable(&mut self, param: ParamId, value: f32) -> Option<&mut [f32; FRAME_SIZE]> {
+        if param.rate() == ParamRate::Control {
+            return None;
+        }
+        let existing = self.slots.iter().position(|(id, _)| *id == param);
+        let ix = match existing {
+            Some(ix) => ix,
+            None if self.slots.len() < MAX_AUDIO_RATE_PARAMS => {
+                self.slots.push((param, Box::new([value; FRAME_SIZE])));
+                self.slots.len() - 1
+            }
+            None => return None,
+        };
+        Some(&mut self.slots[ix].1)
+    }
+
+    /// Returns `param` to following its target
+    pub fn disable(&mut self, param: ParamId) {
+        self.slots.retain(|(id, _)| *id != param);
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    /// Overwrites the audio-rate params in `values` with sample `sample_ix` of their buffers.
+    /// Values outside the params' ranges are clamped and ones that aren't finite are skipped.
+    pub fn apply(&self, sample_ix: usize, values: &mut ParamValues) {
+        for (id, buffer) in &self.slots {
+            let value = buffer[sample_ix % FRAME_SIZE];
+            if value.is_finite() {
+                let info = id.info();
+                values.set(*id, value.clamp(info.min, info.max));
+            }
+        }
+    }
+}
+
+/// Makes the parameter at `param_ix` audio-rate and returns a pointer to its buffer of
+/// `FRAME_SIZE` values, one for each sample of the next frame, starting out at its current value.
+/// The host writes the buffer before each render, and it's read again by every frame until it's
+/// rewritten.  Returns null for an unknown or control-rate parameter, or when
+/// `MAX_AUDIO_RATE_PARAMS` are already audio-rate.
+pub fn get_audio_rate_param_ptr(ctx: *mut GranularCtx, param_ix: usize) -> *mut f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    let Some(param) = ParamId::from_index(param_ix) else {
+        return std::ptr::null_mut();
+    };
+    let value = ctx.params.current.get(param);
+    match ctx.audio_rate.enable(param, value) {
+        Some(buffer) => buffer.as_mut_ptr(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Returns the parameter at `param_ix` to following its target
+pub fn disable_audio_rate_param(ctx: *mut GranularCtx, param_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(param) = ParamId::from_index(param_ix) {
+        ctx.audio_rate.disable(param);
+    }
+}
+
+/// Returns every audio-rate parameter to following its target
+pub fn clear_audio_rate_params(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.audio_rate.clear();
+    }
+}
+
+#[test]
+fn buffers_override_values_sample_by_sample() {
+    use super::params::{GlobalParam, VoiceParam, PARAM_COUNT};
+
+    let gain = ParamId::Voice(1, VoiceParam::Gain);
+    let mut audio_rate = AudioRateParams::default();
+    let buffer = audio_rate.enable(gain, 0.5).unwrap();
+    buffer[1] = 100.;
+    buffer[2] = f32::NAN;
+    let mut values = ParamValues::default();
+    values.set(gain, 2.);
+    audio_rate.apply(0, &mut values);
+    assert_eq!(values.get(gain), 0.5);
+    audio_rate.apply(1, &mut values);
+    assert_eq!(values.get(gain), 4.);
+    audio_rate.apply(2, &mut values);
+    assert_eq!(values.get(gain), 4.);
+
+    // Enabling again keeps the buffer, and there's a limited number of slots
+    assert_eq!(audio_rate.enable(gain, 0.).unwrap()[1], 100.);
+    let size = ParamId::Global(GlobalParam::GrainSize);
+    assert!(audio_rate.enable(size, 0.).is_none());
+    let others = (0..PARAM_COUNT)
+        .filter_map(ParamId::from_index)
+        .filter(|id| id.rate() == ParamRate::Audio && *id != gain);
+    for id in others.take(MAX_AUDIO_RATE_PARAMS - 1) {
+        assert!(audio_rate.enable(id, 0.).is_some());
+    }
+    let send = ParamId::Voice(1, VoiceParam::DelaySend);
+    assert!(audio_rate.enable(send, 0.).is_none());
+    audio_rate.disable(gain);
+    assert!(audio_rate.enable(send, 0.).is_some());
+}
+
+#[test]
+fn audio_rate_params_change_every_sample() {
+    use super::params::{GlobalParam, PARAM_COUNT};
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 4410],
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4409.);
+    let targets = *ctx.params.target();
+    assert!(get_audio_rate_param_ptr(&mut ctx, PARAM_COUNT).is_null());
+    let gain_ix = ParamId::Global(GlobalParam::MasterGain).index();
+    let ptr = get_audio_rate_param_ptr(&mut ctx, gain_ix);
+    let buffer = unsafe { std::slice::from_raw_parts_mut(ptr, FRAME_SIZE) };
+    for (ix, gain) in buffer.iter_mut().enumerate() {
+        *gain = (ix % 2) as f32;
+    }
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    assert!(ctx.rendered_output.iter().step_by(2).all(|&s| s == 0.));
+    assert!(ctx
+        .rendered_output
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .any(|&s| s != 0.));
+
+    disable_audio_rate_param(&mut ctx, gain_ix);
+    assert!(!ctx.audio_rate.is_active());
+}
@@ -0,0 +1,409 @@
+//! Breakpoint automation.  Each lane is a list of breakpoints for one parameter that's evaluated
+//! every sample, so long renders can evolve without the host pushing new values every frame.
+//! Automated parameters bypass smoothing while their lane is active since the lanes are already
+//! continuous; removing a lane lets the parameter glide back to the value set by the host.
+//! Control-rate parameters follow their lanes once per processing chunk instead.
+//!
+//! Lanes can also be recorded from a performance: while recording, every change to a parameter
+//! target is timestamped into a lane of its own, which can be exported or installed as the
+//! automation to render the performance again.
+
+use super::macros::curved_range;
+use super::params::{ParamId, ParamRate, ParamValues, PARAM_COUNT};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Most breakpoints a recording holds across its lanes.  Recording stops once it's full.
+pub const MAX_RECORDED_BREAKPOINTS: usize = 1 << 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Breakpoint {
+    pub time_seconds: f64,
+    pub value: f32,
+    /// Shape of the segment leading to the next breakpoint; see `macros::curved_range`
+    pub curve: f32,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct AutomationLane {
+    pub param: ParamId,
+    /// Sorted by time
+    pub points: Vec<Breakpoint>,
+}
+
+impl AutomationLane {
+    /// Value of the lane at `time_seconds`.  Before the first and after the last breakpoint the
+    /// lane holds their values.
+    pub fn value_at(&self, time_seconds: f64) -> f32 {
+        let next_ix = self
+            .points
+            .partition_point(|point| point.time_seconds <= time_seconds);
+        if next_ix == 0 {
+            return self.points[0].value;
+        }
+        let prev = &self.points[next_ix - 1];
+        let Some(next) = self.points.get(next_ix) else {
+            return prev.value;
+        };
+        let progress = (time_seconds - prev.time_seconds) / (next.time_seconds - prev.time_seconds);
+        curved_range(progress as f32, prev.value, next.value, prev.curve)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Automation {
+    pub lanes: Vec<AutomationLane>,
+    /// Time used while the transport isn't playing, advanced as frames are rendered
+    pub position_seconds: f64,
+}
+
+impl Automation {
+    pub fn is_active(&self) -> bool {
+        !self.lanes.is_empty()
+    }
+
+    /// Replaces the lane of `param`.  Breakpoints are sorted by time; an empty list removes the
+    /// lane.
+    pub fn set_lane(&mut self, param: ParamId, mut points: Vec<Breakpoint>) {
+        self.lanes.retain(|lane| lane.param != param);
+        if points.is_empty() {
+            return;
+        }
+        points.sort_by(|a, b| a.time_seconds.total_cmp(&b.time_seconds));
+        self.lanes.push(AutomationLane { param, points });
+    }
+
+    pub fn clear(&mut self) {
+        self.lanes.clear();
+    }
+
+    /// Overwrites the automated parameters with their lanes' values at `time_seconds`, leaving
+    /// the control-rate ones alone unless it's the start of a chunk
+    pub fn apply(&self, time_seconds: f64, chunk_start: bool, values: &mut ParamValues) {
+        for lane in &self.lanes {
+            if chunk_start || lane.param.rate() == ParamRate::Audio {
+                values.set(lane.param, lane.value_at(time_seconds));
+            }
+        }
+    }
+}
+
+/// A breakpoint recorded into the lane of the parameter numbered `param_ix`
+#[derive(Clone, Copy)]
+struct RecordedPoint {
+    param_ix: u32,
+    value: f32,
+    time_seconds: f64,
+}
+
+/// Records the parameter targets of each rendered frame, adding breakpoints to the lanes of the
+/// ones that changed.  Times are in seconds since recording started.  Recording runs on the audio
+/// thread, so the breakpoints go into a buffer reserved when recording starts and are only
+/// sorted into lanes when they're read.
+#[derive(Clone)]
+pub struct AutomationRecorder {
+    pub recording: bool,
+    /// Breakpoints of all lanes in the order they were recorded
+    points: Vec<RecordedPoint>,
+    /// Time of the last breakpoint in each parameter's lane, or None if it hasn't changed
+    lane_ends: [Option<f64>; PARAM_COUNT],
+    /// Targets and time of the last recorded frame
+    last: Option<(ParamValues, f64)>,
+    position_seconds: f64,
+}
+
+impl Default for AutomationRecorder {
+    fn default() -> Self {
+        AutomationRecorder {
+            recording: false,
+            points: Vec::new(),
+            lane_ends: [None; PARAM_COUNT],
+            last: None,
+            position_seconds: 0.,
+        }
+    }
+}
+
+impl AutomationRecorder {
+    /// Starts a new recording, dropping the last one
+    pub fn start(&mut self) {
+        let mut points = std::mem::take(&mut self.points);
+        points.clear();
+        points.reserve_exact(MAX_RECORDED_BREAKPOINTS);
+        *self = AutomationRecorder {
+            recording: true,
+            points,
+            ..Default::default()
+        };
+    }
+
+    /// Records the targets of a frame lasting `frame_seconds`.  A changed parameter holds its
+    /// last value until the frame before the change and then moves to the new one over a frame,
+    /// as it would have been smoothed.
+    pub fn record(&mut self, targets: &ParamValues, frame_seconds: f64) {
+        if !self.recording {
+            return;
+        }
+        let time_seconds = self.position_seconds;
+        self.position_seconds += frame_seconds;
+        let Some((last, last_seconds)) = self.last.replace((*targets, time_seconds)) else {
+            return;
+        };
+        for ix in 0..PARAM_COUNT {
+            let (value, last_value) = (targets.0[ix], last.0[ix]);
+            if value == last_value {
+                continue;
+            }
+            if self.points.len() + 3 > MAX_RECORDED_BREAKPOINTS {
+                self.recording = false;
+                return;
+            }
+            let mut push = |time_seconds, value| {
+                self.points.push(RecordedPoint {
+                    param_ix: ix as u32,
+                    value,
+                    time_seconds,
+                });
+            };
+            let lane_end = self.lane_ends[ix];
+            if lane_end.is_none() {
+                push(0., last_value);
+            }
+            if lane_end.unwrap_or(0.) < last_seconds {
+                push(last_seconds, last_value);
+            }
+            push(time_seconds, value);
+            self.lane_ends[ix] = Some(time_seconds);
+        }
+    }
+
+    /// Lanes of the parameters that changed, in the order they first changed
+    pub fn lanes(&self) -> Vec<AutomationLane> {
+        let mut lane_ixs = [None; PARAM_COUNT];
+        let mut lanes: Vec<AutomationLane> = Vec::new();
+        for point in &self.points {
+            let param_ix = point.param_ix as usize;
+            let lane_ix = *lane_ixs[param_ix].get_or_insert_with(|| {
+                lanes.push(AutomationLane {
+                    param: ParamId::from_index(param_ix).unwrap(),
+                    points: Vec::new(),
+                });
+                lanes.len() - 1
+            });
+            lanes[lane_ix].points.push(Breakpoint {
+                time_seconds: point.time_seconds,
+                value: point.value,
+                curve: 1.,
+            });
+        }
+        lanes
+    }
+}
+
+/// Replaces the automation lane of a parameter addressed by its flat index.  `points` holds
+/// `(time_seconds, value, curve)` triples; values are clamped to the parameter's range and an
+/// empty list removes the lane.
+pub fn set_automation_lane(ctx: *mut GranularCtx, param_ix: usize, points: &[f32]) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(param) = ParamId::from_index(param_ix) else {
+        return;
+    };
+    if !points.len().is_multiple_of(3) || points.iter().any(|value| !value.is_finite()) {
+        return;
+    }
+    let info = param.info();
+    let points = points
+        .chunks_exact(3)
+        .map(|point| Breakpoint {
+            time_seconds: point[0].max(0.) as f64,
+            value: clamp(info.min, info.max, point[1]),
+            curve: if point[2] > 0. { point[2] } else { 1. },
+        })
+        .collect();
+    ctx.automation.set_lane(param, points);
+}
+
+pub fn clear_automation(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.automation.clear();
+    }
+}
+
+/// Starts recording every change to the parameter targets into automation lanes, dropping the
+/// last recording, or stops recording and keeps the lanes.  Times are in seconds since recording
+/// started, counted in rendered frames.
+pub fn set_automation_recording(ctx: *mut GranularCtx, recording: bool) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if recording {
+        ctx.automation_recorder.start();
+    } else {
+        ctx.automation_recorder.recording = false;
+    }
+}
+
+/// Whether automation is being recorded, which stops by itself once
+/// `MAX_RECORDED_BREAKPOINTS` breakpoints are recorded
+pub fn is_recording_automation(ctx: *mut GranularCtx) -> bool {
+    ctx_mut(ctx).is_some_and(|ctx| ctx.automation_recorder.recording)
+}
+
+/// Recorded lanes as `(param_ix, time_seconds, value, curve)` quadruples, lane by lane and in
+/// time order within each lane
+pub fn get_recorded_automation(ctx: *mut GranularCtx) -> Vec<f64> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    let mut values = Vec::new();
+    for lane in ctx.automation_recorder.lanes() {
+        for point in &lane.points {
+            values.extend([
+                lane.param.index() as f64,
+                point.time_seconds,
+                point.value as f64,
+                point.curve as f64,
+            ]);
+        }
+    }
+    values
+}
+
+/// Stops recording and replaces the automation lanes with the recorded ones, from the start of
+/// the automation clock.  Returns false, leaving the lanes alone, if nothing was recorded.
+pub fn replay_recorded_automation(ctx: *mut GranularCtx) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let recorder = &mut ctx.automation_recorder;
+    recorder.recording = false;
+    let lanes = recorder.lanes();
+    if lanes.is_empty() {
+        return false;
+    }
+    ctx.automation.clear();
+    for lane in lanes {
+        ctx.automation.set_lane(lane.param, lane.points);
+    }
+    ctx.automation.position_seconds = 0.;
+    true
+}
+
+/// Moves the clock that automation lanes follow while the transport isn't playing
+pub fn set_automation_position(ctx: *mut GranularCtx, position_seconds: f64) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if position_seconds.is_finite() {
+        ctx.automation.position_seconds = position_seconds.max(0.);
+    }
+}
+
+#[test]
+fn lanes_interpolate_between_breakpoints() {
+    use super::params::GlobalParam;
+
+    let point = |time_seconds, value, curve| Breakpoint {
+        time_seconds,
+        value,
+        curve,
+    };
+    let lane = AutomationLane {
+        param: ParamId::Global(GlobalParam::GrainSize),
+        points: vec![point(1., 100., 1.), point(2., 200., 2.), point(4., 0., 1.)],
+    };
+    assert_eq!(lane.value_at(0.), 100.);
+    assert_eq!(lane.value_at(1.5), 150.);
+    assert_eq!(lane.value_at(3.), 150.);
+    assert_eq!(lane.value_at(2.), 200.);
+    assert_eq!(lane.value_at(10.), 0.);
+}
+
+#[test]
+fn recorded_changes_replay_as_lanes() {
+    use super::params::{GlobalParam, VoiceParam};
+
+    let grain_size = ParamId::Global(GlobalParam::GrainSize);
+    let pan = ParamId::Voice(1, VoiceParam::Pan);
+    let mut targets = ParamValues([0.; PARAM_COUNT]);
+    targets.set(grain_size, 800.);
+    let mut recorder = AutomationRecorder::default();
+    recorder.record(&targets, 1.);
+    assert!(recorder.lanes().is_empty());
+
+    recorder.start();
+    let capacity = recorder.points.capacity();
+    for frame_ix in 0..8 {
+        if frame_ix == 4 {
+            targets.set(grain_size, 1600.);
+        }
+        if frame_ix >= 6 {
+            targets.set(pan, frame_ix as f32 / 8.);
+        }
+        recorder.record(&targets, 1.);
+    }
+    assert_eq!(recorder.points.capacity(), capacity);
+    let lanes = recorder.lanes();
+    let params: Vec<ParamId> = lanes.iter().map(|lane| lane.param).collect();
+    assert_eq!(params, [grain_size, pan]);
+    let mut automation = Automation::default();
+    for lane in lanes {
+        automation.set_lane(lane.param, lane.points);
+    }
+    let mut values = ParamValues([0.; PARAM_COUNT]);
+    for (time_seconds, size, pan_value) in [
+        (0., 800., 0.),
+        (3., 800., 0.),
+        (3.5, 1200., 0.),
+        (6., 1600., 0.75),
+        (9., 1600., 0.875),
+    ] {
+        automation.apply(time_seconds, true, &mut values);
+        assert_eq!(values.get(grain_size), size);
+        assert_eq!(values.get(pan), pan_value);
+    }
+
+    recorder.recording = false;
+    targets.set(grain_size, 400.);
+    recorder.record(&targets, 1.);
+    assert_eq!(recorder.lanes()[0].points.len(), 3);
+}
+
+#[test]
+fn recorded_automation_replays_the_performance() {
+    use super::params::VoiceParam;
+    use super::test_targets;
+
+    let mut ctx = GranularCtx::default();
+    ctx.load_waveform((0..44100).map(|i| (i as f32 * 0.01).sin()).collect(), None);
+    let targets = test_targets(44099.);
+    let pan = ParamId::Voice(0, VoiceParam::Pan);
+    assert!(!replay_recorded_automation(&mut ctx));
+    set_automation_recording(&mut ctx, true);
+    let mut performed = targets;
+    for frame_ix in 0..8 {
+        if frame_ix == 4 {
+            performed.set(pan, -0.5);
+        }
+        ctx.render(&performed);
+    }
+    set_automation_recording(&mut ctx, false);
+    assert!(!is_recording_automation(&mut ctx));
+    let recorded = get_recorded_automation(&mut ctx);
+    assert_eq!(recorded.len(), 3 * 4);
+    let pan_ix = pan.index() as f64;
+    assert!(recorded.chunks(4).all(|point| point[0] == pan_ix));
+
+    // Rendering again with the targets from before the performance follows the recording
+    assert!(replay_recorded_automation(&mut ctx));
+    let pan_after = |ctx: &mut GranularCtx, frames: usize| {
+        for _ in 0..frames {
+            ctx.render(&targets);
+        }
+        ctx.params.current.get(pan)
+    };
+    assert_eq!(pan_after(&mut ctx, 3), 0.);
+    assert_eq!(pan_after(&mut ctx, 2), -0.5);
+}
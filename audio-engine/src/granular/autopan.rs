@@ -0,0 +1,96 @@
+//! Per-voice auto-pan.  Each voice has its own sine LFO that moves it around its static pan
+//! position, either at a free rate or synced to the host's tempo.  While the transport is playing,
+//! synced LFOs follow the song position so that they stay on the beat across seeks.
+
+use super::params::VOICE_COUNT;
+use super::transport::Transport;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct AutoPan {
+    pub rate_hz: f32,
+    /// Largest offset from the voice's pan position, where 1 sweeps a centered voice from hard
+    /// left to hard right
+    pub depth: f32,
+    /// Starting point of the cycle as a fraction of it, for moving voices in different directions
+    pub phase_offset: f32,
+    /// Beats per cycle when synced to the host's tempo, used instead of `rate_hz`
+    pub sync_beats: Option<f32>,
+    phase: f32,
+}
+
+impl AutoPan {
+    /// Advances the LFO by one sample and returns the offset to add to the voice's pan
+    #[inline]
+    pub fn tick(&mut self, transport: &Transport, sample_rate: f32) -> f32 {
+        if self.depth == 0. {
+            return 0.;
+        }
+        match self.sync_beats {
+            Some(beats) => {
+                let period_frames = transport.beats_to_frames(beats, sample_rate);
+                if transport.playing {
+                    self.phase = (transport.position_frames / period_frames).fract() as f32;
+                } else {
+                    self.phase = (self.phase + (1. / period_frames) as f32).fract();
+                }
+            }
+            None => self.phase = (self.phase + self.rate_hz / sample_rate).fract(),
+        }
+        self.depth * ((self.phase + self.phase_offset) * 2. * std::f32::consts::PI).sin()
+    }
+}
+
+/// Sets up a voice's auto-pan LFO, which moves the voice up to `depth` either side of its pan
+/// position `rate_hz` times a second, starting `phase` of the way into its cycle.  A depth of 0
+/// turns it off.
+pub fn set_voice_auto_pan(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    rate_hz: f32,
+    depth: f32,
+    phase: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !rate_hz.is_finite() || !depth.is_finite() || !phase.is_finite() {
+        return;
+    }
+    let auto_pan = &mut ctx.voices[voice_ix].auto_pan;
+    auto_pan.rate_hz = clamp(0., 100., rate_hz);
+    auto_pan.depth = clamp(0., 2., depth);
+    auto_pan.phase_offset = phase.rem_euclid(1.);
+}
+
+/// Syncs a voice's auto-pan LFO to the host's tempo with one cycle every `beats` beats.  A value
+/// of 0 goes back to its free rate.
+pub fn set_voice_auto_pan_sync(ctx: *mut GranularCtx, voice_ix: usize, beats: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !beats.is_finite() {
+        return;
+    }
+    ctx.voices[voice_ix].auto_pan.sync_beats = if beats > 0. { Some(beats) } else { None };
+}
+
+#[test]
+fn synced_auto_pan_follows_the_song_position() {
+    let mut auto_pan = AutoPan {
+        depth: 0.5,
+        phase_offset: 0.25,
+        sync_beats: Some(1.),
+        ..Default::default()
+    };
+    let mut transport = Transport::default();
+    // One beat at 120 BPM and 100 Hz is 50 frames
+    transport.set(true, 120., 100.);
+    assert!((auto_pan.tick(&transport, 100.) - 0.5).abs() < 1e-6);
+    transport.set(true, 120., 125.);
+    assert!((auto_pan.tick(&transport, 100.) + 0.5).abs() < 1e-6);
+
+    auto_pan.depth = 0.;
+    assert_eq!(auto_pan.tick(&transport, 100.), 0.);
+}
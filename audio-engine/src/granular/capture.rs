@@ -0,0 +1,230 @@
+//! One-shot grain capture.  Once armed with a count, the next grains the voices spawn are each
+//! rendered into their own buffer, along with where they came from, for grain-to-sampler
+//! workflows and for checking envelopes.  Captured grains are rendered a sample at a time as the
+//! voice plays them, so capturing one costs no more than playing it, with the envelope settings
+//! the voice plays it with, before the voice's filter, gain and pan.  The buffers are allocated
+//! when capture is armed, so at most `MAX_CAPTURED_GRAINS` are captured and grains longer than
+//! `MAX_CAPTURED_GRAIN_LEN` are cut short.
+
+use super::envelope::{EnvelopeParams, VoiceEnvelope};
+use super::waveform::{MidSideChannel, WaveformChannels};
+use super::window::GrainWindow;
+use super::Grain;
+use super::{ctx_mut, GranularCtx};
+
+/// Values per grain in `CapturedGrain::info`
+pub const CAPTURED_GRAIN_INFO_COUNT: usize = 4;
+/// Most grains captured at once
+pub const MAX_CAPTURED_GRAINS: usize = 16;
+/// Longest captured grain in samples, over five seconds at 48 kHz
+pub const MAX_CAPTURED_GRAIN_LEN: usize = 1 << 18;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct CapturedGrain {
+    pub voice_ix: usize,
+    /// Index in the waveform of the sample the grain starts at
+    pub start_sample_ix: f32,
+    /// Playback speed relative to the source, where 2 is an octave up
+    pub sample_playback_ratio: f32,
+    /// Mono samples of the grain
+    pub samples: Vec<f32>,
+}
+
+impl CapturedGrain {
+    /// Voice index, start position in samples, playback ratio and length in samples
+    pub fn info(&self) -> [f64; CAPTURED_GRAIN_INFO_COUNT] {
+        [
+            self.voice_ix as f64,
+            self.start_sample_ix as f64,
+            self.sample_playback_ratio as f64,
+            self.samples.len() as f64,
+        ]
+    }
+}
+
+/// How a voice was playing a grain when it spawned
+pub struct GrainPlayback<'a> {
+    pub channels: WaveformChannels<'a>,
+    pub is_reversed: bool,
+    pub envelope: &'a VoiceEnvelope,
+    pub envelope_params: EnvelopeParams,
+    /// The voice's window table, if it's up to date for `envelope_params`
+    pub window: Option<&'a GrainWindow>,
+    pub mid_side: Option<MidSideChannel>,
+}
+
+#[derive(Clone, Default)]
+pub struct GrainCapture {
+    remaining: usize,
+    /// Grains still playing and what's been captured of them
+    rendering: Vec<(Grain, CapturedGrain)>,
+    /// Buffers allocated by `arm` for the grains still to spawn
+    buffers: Vec<Vec<f32>>,
+    /// Captured grains in the order they finished
+    pub grains: Vec<CapturedGrain>,
+}
+
+impl GrainCapture {
+    /// Starts capturing the next `count` grains, up to `MAX_CAPTURED_GRAINS`, dropping the ones
+    /// captured before
+    pub fn arm(&mut self, count: usize) {
+        let count = count.min(MAX_CAPTURED_GRAINS);
+        self.remaining = count;
+        self.rendering = Vec::with_capacity(count);
+        self.buffers = (0..count)
+            .map(|_| Vec::with_capacity(MAX_CAPTURED_GRAIN_LEN))
+            .collect();
+        self.grains = Vec::with_capacity(count);
+    }
+
+    #[inline]
+    pub fn is_armed(&self) -> bool {
+        self.remaining > 0
+    }
+
+    #[inline]
+    pub fn is_rendering(&self) -> bool {
+        !self.rendering.is_empty()
+    }
+
+    /// Starts capturing a grain that was just spawned
+    pub fn capture(&mut self, voice_ix: usize, grain: &Grain) {
+        if self.remaining == 0 {
+            return;
+        }
+        let Some(samples) = self.buffers.pop() else {
+            return;
+        };
+        self.remaining -= 1;
+        let captured = CapturedGrain {
+            voice_ix,
+            start_sample_ix: grain.start_sample_ix,
+            sample_playback_ratio: grain.sample_playback_ratio,
+            samples,
+        };
+        let mut grain = grain.clone();
+        grain.samples_read_so_far = 0.;
+        self.rendering.push((grain, captured));
+    }
+
+    /// Renders the next sample of each grain of the voice being captured, played as `playback`
+    /// describes
+    pub fn render<'a>(&mut self, voice_ix: usize, playback: impl Fn(&Grain) -> GrainPlayback<'a>) {
+        let mut ix = 0;
+        while ix < self.rendering.len() {
+            let (grain, captured) = &mut self.rendering[ix];
+            if captured.voice_ix != voice_ix {
+                ix += 1;
+                continue;
+            }
+            let sample = render_sample(grain, &playback(grain));
+            captured.samples.push(sample);
+            if grain.tick() && captured.samples.len() < MAX_CAPTURED_GRAIN_LEN {
+                ix += 1;
+                continue;
+            }
+            let (_, captured) = self.rendering.swap_remove(ix);
+            self.grains.push(captured);
+        }
+    }
+}
+
+/// The grain's sample at its current position, without moving on
+fn render_sample(grain: &mut Grain, playback: &GrainPlayback) -> f32 {
+    let (gain, left) = grain.sample(
+        playback.channels.left,
+        playback.is_reversed,
+        playback.envelope,
+        playback.envelope_params,
+        playback.window,
+    );
+    let sample = match playback.channels.right {
+        Some(right) => {
+            let right = grain.read(right, playback.is_reversed);
+            match playback.mid_side {
+                Some(channel) => channel.encode(left, right),
+                None => (left + right) * 0.5,
+            }
+        }
+        None => left,
+    };
+    let sample = match &mut grain.tilt {
+        Some(tilt) => tilt.process_mono(sample),
+        None => sample,
+    };
+    let gain = if playback.envelope.click_guard && !playback.envelope.overlap_add {
+        gain * grain.click_guard_gain()
+    } else {
+        gain
+    };
+    gain * grain.gain * sample
+}
+
+/// Renders each of the next `count` grains spawned, up to `MAX_CAPTURED_GRAINS`, into its
+/// own buffer as it plays, dropping the grains captured before.  The buffers are allocated here;
+/// see `GrainCapture`.
+pub fn capture_grains(ctx: *mut GranularCtx, count: usize) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.grain_capture.arm(count);
+    }
+}
+
+pub fn get_captured_grain_count(ctx: *mut GranularCtx) -> usize {
+    ctx_mut(ctx)
+        .map(|ctx| ctx.grain_capture.grains.len())
+        .unwrap_or(0)
+}
+
+/// Returns the `CAPTURED_GRAIN_INFO_COUNT` values describing a captured grain, or
+/// nothing for an invalid index
+pub fn get_captured_grain_info(ctx: *mut GranularCtx, grain_ix: usize) -> Vec<f64> {
+    ctx_mut(ctx)
+        .and_then(|ctx| ctx.grain_capture.grains.get(grain_ix))
+        .map(|grain| grain.info().to_vec())
+        .unwrap_or_default()
+}
+
+pub fn get_captured_grain_samples(ctx: *mut GranularCtx, grain_ix: usize) -> Vec<f32> {
+    ctx_mut(ctx)
+        .and_then(|ctx| ctx.grain_capture.grains.get(grain_ix))
+        .map(|grain| grain.samples.clone())
+        .unwrap_or_default()
+}
+
+#[test]
+fn captured_grains_are_rendered_whole() {
+    use super::render_granular;
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 44100],
+        ..Default::default()
+    };
+    capture_grains(&mut ctx, 3);
+    ctx.voice_render_params[2] = [1., 2.];
+    ctx.voice_render_params[3] = [200., 200.];
+    for _ in 0..16 {
+        render_granular(&mut ctx, 0., 44099., 400., 0.5, 0.5);
+    }
+    assert_eq!(get_captured_grain_count(&mut ctx), 3);
+    for grain_ix in 0..3 {
+        let info = get_captured_grain_info(&mut ctx, grain_ix);
+        let samples = get_captured_grain_samples(&mut ctx, grain_ix);
+        assert_eq!(info[3], samples.len() as f64);
+        assert_eq!(samples.len() as f64, (400. / info[2]).ceil());
+        assert!(samples.iter().all(|sample| (0. ..=0.5).contains(sample)));
+    }
+    assert!(get_captured_grain_info(&mut ctx, 3).is_empty());
+
+    // Captures are capped, with their buffers allocated up front
+    capture_grains(&mut ctx, 100);
+    assert_eq!(get_captured_grain_count(&mut ctx), 0);
+    for _ in 0..64 {
+        render_granular(&mut ctx, 0., 44099., 400., 0.5, 0.5);
+    }
+    assert_eq!(get_captured_grain_count(&mut ctx), MAX_CAPTURED_GRAINS);
+    assert!(ctx
+        .grain_capture
+        .grains
+        .iter()
+        .all(|grain| grain.samples.capacity() == MAX_CAPTURED_GRAIN_LEN));
+}
@@ -0,0 +1,171 @@
+//! Chord mode.  A voice playing a chord spawns a cluster of grains on every trigger instead of
+//! one, all from the same start and of the same size but each transposed by an interval of the
+//! chord and scaled by its own gain, so a monophonic source turns into a harmonic cloud.  The
+//! chord lists every interval played, so a root at 0 semitones has to be included to be heard.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Most grains spawned on each trigger
+pub const MAX_CHORD_NOTES: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChordNote {
+    /// Transposition from the voice's pitch
+    pub semitones: f32,
+    pub gain: f32,
+}
+
+impl ChordNote {
+    const ROOT: ChordNote = ChordNote {
+        semitones: 0.,
+        gain: 1.,
+    };
+
+    /// Ratio the grain's speed is multiplied by
+    pub fn ratio(&self) -> f32 {
+        (self.semitones / 12.).exp2()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Chord {
+    notes: [ChordNote; MAX_CHORD_NOTES],
+    len: usize,
+}
+
+/// A single grain at the voice's pitch, as spawned without chord mode
+impl Default for Chord {
+    fn default() -> Self {
+        Chord {
+            notes: [ChordNote::ROOT; MAX_CHORD_NOTES],
+            len: 1,
+        }
+    }
+}
+
+impl Chord {
+    /// Returns None for no notes or more than `MAX_CHORD_NOTES`
+    pub fn new(notes: &[ChordNote]) -> Option<Self> {
+        if notes.is_empty() || notes.len() > MAX_CHORD_NOTES {
+            return None;
+        }
+        let mut chord = Chord {
+            len: notes.len(),
+            ..Default::default()
+        };
+        chord.notes[..notes.len()].copy_from_slice(notes);
+        Some(chord)
+    }
+
+    pub fn notes(&self) -> &[ChordNote] {
+        &self.notes[..self.len]
+    }
+}
+
+/// Switches a voice to chord mode, where every trigger spawns a grain for each interval in
+/// `semitones` (-48 to 48) scaled by the gain at the same index of `gains` (0 to 2).  Intervals
+/// are from the voice's pitch, so the root is only heard if 0 is listed.  Returns false and
+/// changes nothing if the two lists differ in length, are empty or are longer than
+/// `MAX_CHORD_NOTES`.
+pub fn set_voice_chord(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    semitones: &[f32],
+    gains: &[f32],
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT
+        || semitones.len() != gains.len()
+        || !semitones.iter().chain(gains).all(|value| value.is_finite())
+    {
+        return false;
+    }
+    let notes: Vec<ChordNote> = semitones
+        .iter()
+        .zip(gains)
+        .map(|(&semitones, &gain)| ChordNote {
+            semitones: clamp(-48., 48., semitones),
+            gain: clamp(0., 2., gain),
+        })
+        .collect();
+    let Some(chord) = Chord::new(&notes) else {
+        return false;
+    };
+    ctx.voices[voice_ix].chord = Some(chord);
+    true
+}
+
+/// Goes back to spawning one grain per trigger
+pub fn clear_voice_chord(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.chord = None;
+    }
+}
+
+#[test]
+fn chords_hold_their_notes() {
+    assert_eq!(Chord::new(&[]), None);
+    assert_eq!(Chord::new(&[ChordNote::ROOT; MAX_CHORD_NOTES + 1]), None);
+    assert_eq!(Chord::default().notes(), &[ChordNote::ROOT]);
+
+    let fifth = ChordNote {
+        semitones: 7.,
+        gain: 0.5,
+    };
+    let chord = Chord::new(&[ChordNote::ROOT, fifth]).unwrap();
+    assert_eq!(chord.notes(), &[ChordNote::ROOT, fifth]);
+    assert!((fifth.ratio() - 1.498).abs() < 0.001);
+}
+
+#[test]
+fn chord_mode_spawns_a_grain_per_interval() {
+    use super::params::{GlobalParam, ParamId};
+    use super::{sine_ctx, Grain};
+
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    assert!(!set_voice_chord(&mut ctx, 0, &[0., 7.], &[1.]));
+    assert!(set_voice_chord(
+        &mut ctx,
+        0,
+        &[0., 7., 12.],
+        &[1., 0.5, 0.25]
+    ));
+    let targets = *ctx.params.target();
+    for _ in 0..64 {
+        ctx.render(&targets);
+        if !ctx.voices[0].grains.is_empty() {
+            break;
+        }
+    }
+    // The first cluster, which shares its start and size
+    let cluster: Vec<Grain> = ctx.voices[0].grains.iter().collect();
+    assert_eq!(cluster.len(), 3);
+    assert!(cluster
+        .iter()
+        .all(|grain| grain.start_sample_ix == cluster[0].start_sample_ix
+            && grain.len_samples == cluster[0].len_samples));
+    let fifth = 2f32.powf(7. / 12.);
+    assert!(
+        (cluster[1].sample_playback_ratio / cluster[0].sample_playback_ratio - fifth).abs() < 1e-4
+    );
+    assert_eq!(
+        cluster[2].sample_playback_ratio,
+        2. * cluster[0].sample_playback_ratio
+    );
+    assert_eq!(
+        cluster.iter().map(|grain| grain.gain).collect::<Vec<_>>(),
+        [1., 0.5, 0.25]
+    );
+
+    clear_voice_chord(&mut ctx, 0);
+    assert!(ctx.voices[0].chord.is_none());
+}
@@ -0,0 +1,162 @@
+//! Voice comb filters.  A voice with a comb filter feeds its output through a feedforward or
+//! feedback comb after its resonator.  A feedback comb tuned in Hz rings at that pitch and its
+//! harmonics, which turns noisy grains into pitched resonant textures; tuned in samples it acts
+//! as a short, metallic echo.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::filters::comb::{CombFilter, CombKind};
+use crate::dsp::{clamp, decay_samples};
+
+/// Lowest frequency the delay lines are long enough for
+pub const MIN_FREQUENCY_HZ: f32 = 20.;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CombDelay {
+    Samples(f32),
+    /// Frequency whose period is the delay, which keeps the tuning across sample rates
+    Hz(f32),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CombSettings {
+    pub kind: CombKind,
+    pub delay: CombDelay,
+    /// Gain of the delayed signal, from -1 to 1 for feedforward combs and just inside that for
+    /// feedback ones
+    pub gain: f32,
+}
+
+#[derive(Clone)]
+pub struct VoiceComb {
+    pub settings: CombSettings,
+    left: CombFilter,
+    right: CombFilter,
+}
+
+impl VoiceComb {
+    pub fn new(settings: CombSettings, sample_rate: f32) -> Self {
+        let max_delay = (sample_rate / MIN_FREQUENCY_HZ).ceil() as usize;
+        VoiceComb {
+            settings,
+            left: CombFilter::new(max_delay),
+            right: CombFilter::new(max_delay),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.left.clear();
+        self.right.clear();
+    }
+
+    fn delay_samples(&self, sample_rate: f32) -> f32 {
+        match self.settings.delay {
+            CombDelay::Samples(samples) => samples,
+            CombDelay::Hz(hz) => sample_rate / hz.max(MIN_FREQUENCY_HZ),
+        }
+    }
+
+    /// How long the comb goes on after its input stops
+    pub fn tail_samples(&self, sample_rate: f32) -> f32 {
+        let delay_samples = self.delay_samples(sample_rate);
+        match self.settings.kind {
+            CombKind::Feedforward => delay_samples,
+            CombKind::Feedback => decay_samples(delay_samples, self.settings.gain),
+        }
+    }
+
+    pub fn process(&mut self, (left, right): (f32, f32), sample_rate: f32) -> (f32, f32) {
+        let CombSettings { kind, gain, .. } = self.settings;
+        let delay_samples = self.delay_samples(sample_rate);
+        (
+            self.left.process(left, kind, delay_samples, gain),
+            self.right.process(right, kind, delay_samples, gain),
+        )
+    }
+}
+
+/// Feeds a voice's output through a feedforward (`kind` 0) or feedback (1) comb filter after its
+/// resonator.  The delay is `delay` samples, or the period of `delay` Hz if `delay_in_hz` is set,
+/// and at most 50 ms either way.  `gain` scales the delayed signal from -1 to 1, or just inside
+/// that for feedback combs.  A voice that already has a comb filter keeps its delay line.
+pub fn set_voice_comb(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    kind: u32,
+    delay: f32,
+    delay_in_hz: bool,
+    gain: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(kind) = CombKind::from_index(kind) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !delay.is_finite() || !gain.is_finite() {
+        return;
+    }
+    let max_gain = match kind {
+        CombKind::Feedforward => 1.,
+        CombKind::Feedback => 0.999,
+    };
+    let sample_rate = ctx.sample_rate;
+    let settings = CombSettings {
+        kind,
+        delay: if delay_in_hz {
+            CombDelay::Hz(clamp(MIN_FREQUENCY_HZ, 20000., delay))
+        } else {
+            CombDelay::Samples(clamp(1., sample_rate / MIN_FREQUENCY_HZ, delay))
+        },
+        gain: clamp(-max_gain, max_gain, gain),
+    };
+    ctx.voices[voice_ix]
+        .comb
+        .get_or_insert_with(|| VoiceComb::new(settings, sample_rate))
+        .settings = settings;
+}
+
+/// Removes a voice's comb filter
+pub fn clear_voice_comb(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.comb = None;
+    }
+}
+
+#[test]
+fn feedback_combs_pitch_noisy_grains() {
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+    use crate::common;
+    use rand::Rng;
+
+    let mut rng = common::rng();
+    let mut ctx = GranularCtx {
+        waveform: (0..8192).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 8191.);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    set_voice_comb(&mut ctx, 0, 2, 100., false, 0.9);
+    assert!(ctx.voices[0].comb.is_none());
+    set_voice_comb(&mut ctx, 0, 1, 441., true, 2.);
+    assert_eq!(ctx.voices[0].comb.as_ref().unwrap().settings.gain, 0.999);
+    let targets = *ctx.params.target();
+    let mut output = Vec::new();
+    for _ in 0..64 {
+        ctx.render(&targets);
+        output.extend_from_slice(&ctx.rendered_output);
+    }
+    // The output repeats every 100 samples
+    let correlation =
+        |lag: usize| -> f32 { output[lag..].iter().zip(&output).map(|(a, b)| a * b).sum() };
+    let best_lag = (50..150).max_by(|&a, &b| correlation(a).total_cmp(&correlation(b)));
+    assert_eq!(best_lag, Some(100));
+
+    clear_voice_comb(&mut ctx, 0);
+    assert!(ctx.voices[0].comb.is_none());
+}
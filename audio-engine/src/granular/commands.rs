@@ -0,0 +1,352 @@
+//! Command ring.  Control changes that happen at a moment rather than continuously, like loading
+//! a slot, triggering a note or holding the drone, go from the host to the engine through a single-producer,
+//! single-consumer ring in the engine's memory, timed to the sample.  The ring's counts are atomic
+//! so that the host can queue commands from another thread without locks, in builds where that
+//! thread can see the engine's memory: native builds, and WASM builds with shared memory like
+//! `pnpm run build:wasm:shared`.  With the default WASM build the ring is written by the worklet
+//! that renders, e.g. from messages the UI thread posts to it.  The ring is a header of `u32`
+//! words followed by the commands:
+//!
+//! | word | contents                                                            |
+//! |------|---------------------------------------------------------------------|
+//! | 0    | commands written, advanced by the host after writing each command   |
+//! | 1    | commands read, advanced by the engine after running each command    |
+//! | 2    | clock: the time in samples of the next sample the engine renders    |
+//! | 3    | capacity in commands, `COMMAND_RING_CAPACITY`                       |
+//! | 4..  | commands of `COMMAND_WORDS` words: kind, time and two arguments     |
+//!
+//! Both counts run freely and wrap, command `n` goes in slot `n % capacity` and the ring is full
+//! when the host is `capacity` commands ahead of the engine.  The host writes a command's words
+//! and then advances word 0, with a release store when it writes from another thread.
+//!
+//! Each command runs on the sample at its time, which is on the same clock as word 2 and wraps
+//! like it, so a host schedules a command by reading the clock and adding the delay it wants.
+//! Commands run in the order they were written, so they should be written in time order, and ones
+//! whose time has already passed run at the start of the next frame.
+
+use super::{ctx_mut, GranularCtx};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Commands the ring holds, a power of two
+pub const COMMAND_RING_CAPACITY: usize = 64;
+pub const COMMAND_RING_HEADER_LEN: usize = 4;
+pub const COMMAND_WORDS: usize = 4;
+
+const WRITE_COUNT: usize = 0;
+const READ_COUNT: usize = 1;
+const CLOCK: usize = 2;
+
+/// What each command does, by the kind in its first word.  Arguments are `u32`s or the bits of
+/// `f32`s.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    /// Kind 0: swaps in a sample slot like `load_sample_slot`, once it's been staged with
+    /// `stage_sample_slot` so that the render doesn't copy it; the arguments are the slot id and
+    /// the crossfade in milliseconds
+    LoadSlot { slot_id: u32, crossfade_ms: f32 },
+    /// Kind 1: overrides the selection in the targets with a start and end sample index, until
+    /// it's released
+    SetSelection { start: f32, end: f32 },
+    /// Kind 2: goes back to the selection in the targets
+    ReleaseSelection,
+    /// Kind 3: starts a note in note mode on channel 1; the arguments are the note and velocity
+    NoteOn { note: u8, velocity: u8 },
+    /// Kind 4: releases a note started with `NoteOn`
+    NoteOff { note: u8 },
+    /// Kind 5: resets like `reset`, with the fade in milliseconds
+    Reset { fade_ms: f32 },
+    /// Kind 6: holds the drone when the argument is nonzero and releases it otherwise.  Holding
+    /// freezes every voice into a loop a few seconds long, crossfaded into itself for longer the
+    /// more blur there is.  Does nothing while drone mode is off.
+    DroneHold { hold: bool },
+    /// Kind 7: engages the stutter when the argument is nonzero and releases it otherwise.  While
+    /// the transport plays it starts repeating on the next line of its repeat grid.  Does nothing
+    /// while the stutter is off.
+    Stutter { engaged: bool },
+}
+
+impl Command {
+    /// Returns None for an unknown kind or arguments that aren't valid for it
+    pub fn parse(kind: u32, arg_1: u32, arg_2: u32) -> Option<Command> {
+        let (float_1, float_2) = (f32::from_bits(arg_1), f32::from_bits(arg_2));
+        let command = match kind {
+            0 if float_2.is_finite() => Command::LoadSlot {
+                slot_id: arg_1,
+                crossfade_ms: float_2.max(0.),
+            },
+            1 if float_1.is_finite() && float_2.is_finite() && float_1 < float_2 => {
+                Command::SetSelection {
+                    start: float_1,
+                    end: float_2,
+                }
+            }
+            2 => Command::ReleaseSelection,
+            3 => Command::NoteOn {
+                note: arg_1.min(127) as u8,
+                velocity: arg_2.min(127) as u8,
+            },
+            4 => Command::NoteOff {
+                note: arg_1.min(127) as u8,
+            },
+            5 => Command::Reset {
+                fade_ms: if float_1.is_finite() { float_1 } else { 0. },
+            },
+            6 => Command::DroneHold { hold: arg_1 != 0 },
+            7 => Command::Stutter {
+                engaged: arg_1 != 0,
+            },
+            _ => return None,
+        };
+        Some(command)
+    }
+}
+
+/// A command the engine couldn't parse, which is skipped
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InvalidCommand {
+    pub kind: u32,
+}
+
+pub struct CommandRing {
+    words: Box<[AtomicU32]>,
+    /// Time of the first sample of the frame being rendered
+    clock: u32,
+}
+
+impl Default for CommandRing {
+    fn default() -> Self {
+        let len = COMMAND_RING_HEADER_LEN + COMMAND_RING_CAPACITY * COMMAND_WORDS;
+        let words: Box<[AtomicU32]> = (0..len).map(|_| AtomicU32::new(0)).collect();
+        words[3].store(COMMAND_RING_CAPACITY as u32, Ordering::Relaxed);
+        CommandRing { words, clock: 0 }
+    }
+}
+
+/// A ring at its own address holding the same words, which the host doesn't write to
+impl Clone for CommandRing {
+    fn clone(&self) -> Self {
+        CommandRing {
+            words: self
+                .words
+                .iter()
+                .map(|word| AtomicU32::new(word.load(Ordering::Relaxed)))
+                .collect(),
+            clock: self.clock,
+        }
+    }
+}
+
+impl CommandRing {
+    pub fn as_mut_ptr(&mut self) -> *mut u32 {
+        self.words.as_ptr() as *mut u32
+    }
+
+    pub fn clock(&self) -> u32 {
+        self.clock
+    }
+
+    /// Takes the next command if it's due by sample `sample_ix` of the frame being rendered.
+    /// Commands the engine can't parse are taken as errors.
+    pub fn pop_due(&mut self, sample_ix: usize) -> Option<Result<Command, InvalidCommand>> {
+        let written = self.words[WRITE_COUNT].load(Ordering::Acquire);
+        let read = self.words[READ_COUNT].load(Ordering::Relaxed);
+        let pending = written.wrapping_sub(read);
+        if pending == 0 {
+            return None;
+        }
+        if pending as usize > COMMAND_RING_CAPACITY {
+            // The host wrote past the end of the ring, so the commands left in it can't be trusted
+            self.words[READ_COUNT].store(written, Ordering::Release);
+            return None;
+        }
+        let start =
+            COMMAND_RING_HEADER_LEN + (read as usize % COMMAND_RING_CAPACITY) * COMMAND_WORDS;
+        let [kind, time, arg_1, arg_2] =
+            std::array::from_fn(|ix| self.words[start + ix].load(Ordering::Relaxed));
+        let now = self.clock.wrapping_add(sample_ix as u32);
+        if (time.wrapping_sub(now) as i32) > 0 {
+            return None;
+        }
+        // After the command's words are read, so the host doesn't overwrite them first
+        self.words[READ_COUNT].store(read.wrapping_add(1), Ordering::Release);
+        Some(Command::parse(kind, arg_1, arg_2).ok_or(InvalidCommand { kind }))
+    }
+
+    /// Moves the clock on to the next frame
+    pub fn advance(&mut self, frames: usize) {
+        self.clock = self.clock.wrapping_add(frames as u32);
+        self.words[CLOCK].store(self.clock, Ordering::Release);
+    }
+}
+
+/// Returns a pointer to the command ring described in `commands`, creating it empty on the first
+/// call.  The ring never moves.
+pub fn get_command_ring_ptr(ctx: *mut GranularCtx) -> *mut u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    ctx.commands
+        .get_or_insert_with(CommandRing::default)
+        .as_mut_ptr()
+}
+
+/// Returns the time in samples of the next sample to be rendered, which commands are timed
+/// against, or 0 before the command ring has been created
+pub fn get_command_clock(ctx: *mut GranularCtx) -> u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    ctx.commands.as_ref().map_or(0, CommandRing::clock)
+}
+
+#[test]
+fn commands_run_in_order_when_theyre_due() {
+    let mut ring = CommandRing::default();
+    let write = |ring: &CommandRing, words: [u32; COMMAND_WORDS]| {
+        let written = ring.words[WRITE_COUNT].load(Ordering::Relaxed) as usize;
+        let start = COMMAND_RING_HEADER_LEN + (written % COMMAND_RING_CAPACITY) * COMMAND_WORDS;
+        for (ix, word) in words.into_iter().enumerate() {
+            ring.words[start + ix].store(word, Ordering::Relaxed);
+        }
+        ring.words[WRITE_COUNT].fetch_add(1, Ordering::Release);
+    };
+    ring.advance(1000);
+    write(&ring, [3, 1010, 64, 100]);
+    write(&ring, [9, 1010, 0, 0]);
+    write(&ring, [5, 1000, 2f32.to_bits(), 0]);
+    assert_eq!(ring.pop_due(9), None);
+    assert_eq!(
+        ring.pop_due(10),
+        Some(Ok(Command::NoteOn {
+            note: 64,
+            velocity: 100
+        }))
+    );
+    assert_eq!(ring.pop_due(10), Some(Err(InvalidCommand { kind: 9 })));
+    // Late commands run straight away
+    assert_eq!(ring.pop_due(10), Some(Ok(Command::Reset { fade_ms: 2. })));
+    assert_eq!(ring.pop_due(10), None);
+
+    // Times wrap with the clock
+    ring.advance(u32::MAX as usize - 1000);
+    assert_eq!(ring.clock(), u32::MAX);
+    write(&ring, [2, 1, 0, 0]);
+    assert_eq!(ring.pop_due(1), None);
+    assert_eq!(ring.pop_due(2), Some(Ok(Command::ReleaseSelection)));
+    assert_eq!(ring.words[CLOCK].load(Ordering::Relaxed), u32::MAX);
+
+    assert_eq!(Command::parse(1, 5f32.to_bits(), 5f32.to_bits()), None);
+    assert_eq!(Command::parse(0, 1, f32::NAN.to_bits()), None);
+    assert_eq!(
+        Command::parse(6, 2, 0),
+        Some(Command::DroneHold { hold: true })
+    );
+    assert_eq!(
+        Command::parse(7, 0, 1),
+        Some(Command::Stutter { engaged: false })
+    );
+}
+
+#[test]
+fn commands_written_from_another_thread_arrive_in_order() {
+    const COMMANDS: u32 = 10_000;
+    let mut ring = CommandRing::default();
+    // The producer only has the ring's address, like a host does
+    let address = ring.as_mut_ptr() as usize;
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let word = |ix: usize| unsafe { AtomicU32::from_ptr((address as *mut u32).add(ix)) };
+            for n in 0..COMMANDS {
+                while n.wrapping_sub(word(READ_COUNT).load(Ordering::Acquire))
+                    >= COMMAND_RING_CAPACITY as u32
+                {
+                    std::thread::yield_now();
+                }
+                let start =
+                    COMMAND_RING_HEADER_LEN + (n as usize % COMMAND_RING_CAPACITY) * COMMAND_WORDS;
+                for (ix, value) in [3, 0, n % 128, n / 128 % 128].into_iter().enumerate() {
+                    word(start + ix).store(value, Ordering::Relaxed);
+                }
+                word(WRITE_COUNT).store(n + 1, Ordering::Release);
+            }
+        });
+
+        let mut received = 0;
+        while received < COMMANDS {
+            match ring.pop_due(0) {
+                Some(command) => {
+                    assert_eq!(
+                        command,
+                        Ok(Command::NoteOn {
+                            note: (received % 128) as u8,
+                            velocity: (received / 128 % 128) as u8,
+                        })
+                    );
+                    received += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+    });
+    assert_eq!(ring.pop_due(0), None);
+}
+
+#[test]
+fn commands_run_on_the_sample_theyre_timed_for() {
+    use super::params::{GlobalParam, ParamId};
+    use super::slots::stage_sample_slot;
+    use super::FRAME_SIZE;
+
+    let mut ctx = GranularCtx::default();
+    let slot_id = ctx.sample_slots.add(vec![0.5; 4410]);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4409.);
+    let targets = *ctx.params.target();
+    let ring = get_command_ring_ptr(&mut ctx);
+    let words = unsafe {
+        std::slice::from_raw_parts_mut(
+            ring,
+            COMMAND_RING_HEADER_LEN + COMMAND_RING_CAPACITY * COMMAND_WORDS,
+        )
+    };
+    let write = |words: &mut [u32], command: [u32; COMMAND_WORDS]| {
+        let start = COMMAND_RING_HEADER_LEN + words[0] as usize * COMMAND_WORDS;
+        words[start..start + COMMAND_WORDS].copy_from_slice(&command);
+        words[0] += 1;
+    };
+    // Slots that weren't staged aren't loaded
+    write(words, [0, 0, slot_id, 0]);
+    ctx.render(&targets);
+    assert!(ctx.samples().is_empty());
+    assert!(!stage_sample_slot(&mut ctx, slot_id + 1));
+
+    // Loading a slot works without a waveform, and the selection changes partway into a frame
+    assert!(stage_sample_slot(&mut ctx, slot_id));
+    write(words, [0, 0, slot_id, 0]);
+    write(
+        words,
+        [
+            1,
+            4 * FRAME_SIZE as u32 + 64,
+            100f32.to_bits(),
+            200f32.to_bits(),
+        ],
+    );
+    let selection_end = ParamId::Global(GlobalParam::SelectionEndSampleIx);
+    ctx.render(&targets);
+    assert_eq!(ctx.samples().len(), 4410);
+    ctx.render(&targets);
+    ctx.render(&targets);
+    assert_eq!(get_command_clock(&mut ctx), 4 * FRAME_SIZE as u32);
+    assert_eq!(ctx.params.target().get(selection_end), 4409.);
+    ctx.render(&targets);
+    assert_eq!(ctx.params.target().get(selection_end), 200.);
+    assert_eq!(words[1], 3);
+
+    // The override outlasts the frame until it's released
+    ctx.render(&targets);
+    assert_eq!(ctx.params.target().get(selection_end), 200.);
+    write(words, [2, 0, 0, 0]);
+    ctx.render(&targets);
+    assert_eq!(ctx.params.target().get(selection_end), 4409.);
+}
@@ -0,0 +1,141 @@
+//! Master bus compressor.  Its level detector follows either the master output itself or the
+//! sidechain input the host writes, so a texture can be ducked under e.g. a drum loop that's
+//! played elsewhere without the drums passing through the engine.
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::dynamics::Compressor;
+
+#[derive(Clone)]
+pub struct MasterCompressor {
+    pub compressor: Compressor,
+    /// Whether the detector follows the sidechain input instead of the output
+    pub keyed: bool,
+}
+
+impl MasterCompressor {
+    /// Compresses the left and right channels and the mono mix together, by the level of the
+    /// loudest of them or of `sidechain` when the compressor is keyed
+    pub fn process(&mut self, samples: [f32; 3], sidechain: f32) -> [f32; 3] {
+        let detector = if self.keyed {
+            sidechain.abs()
+        } else {
+            // Stereo-linked, so that the image doesn't shift as the gain moves
+            samples
+                .iter()
+                .fold(0f32, |max, sample| max.max(sample.abs()))
+        };
+        let gain = self.compressor.process(detector);
+        samples.map(|sample| sample * gain)
+    }
+}
+
+/// Compresses the master output above `threshold_db` (-60 to 0 dB) by `ratio` (1 to 20) with
+/// `makeup_db` (0 to 24 dB) of gain afterwards, following the level with `attack_ms` (0.1 to 200)
+/// and `release_ms` (5 to 2000).  When `keyed` the level is the sidechain input's rather than the
+/// output's, which ducks the output under the sidechain.  A ratio of 1 turns the compressor off.
+#[allow(clippy::too_many_arguments)]
+pub fn set_master_compressor(
+    ctx: *mut GranularCtx,
+    threshold_db: f32,
+    ratio: f32,
+    attack_ms: f32,
+    release_ms: f32,
+    makeup_db: f32,
+    keyed: bool,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let values = [threshold_db, ratio, attack_ms, release_ms, makeup_db];
+    if !values.iter().all(|value| value.is_finite()) {
+        return;
+    }
+    let threshold_db = clamp(-60., 0., threshold_db);
+    let ratio = clamp(1., 20., ratio);
+    let attack_ms = clamp(0.1, 200., attack_ms);
+    let release_ms = clamp(5., 2000., release_ms);
+    let makeup_db = clamp(0., 24., makeup_db);
+    let sample_rate = ctx.sample_rate;
+    match &mut ctx.master_compressor {
+        _ if ratio == 1. => ctx.master_compressor = None,
+        Some(master) => {
+            master.compressor.threshold_db = threshold_db;
+            master.compressor.ratio = ratio;
+            master.compressor.makeup_db = makeup_db;
+            master
+                .compressor
+                .set_times(attack_ms, release_ms, sample_rate);
+            master.keyed = keyed;
+        }
+        master => {
+            let compressor = Compressor::new(
+                threshold_db,
+                ratio,
+                makeup_db,
+                attack_ms,
+                release_ms,
+                sample_rate,
+            );
+            *master = Some(MasterCompressor { compressor, keyed });
+        }
+    }
+}
+
+/// Returns how far the master compressor is turning the output down in dB, or 0 while it's off
+pub fn get_master_compressor_reduction_db(ctx: *mut GranularCtx) -> f32 {
+    ctx_mut(ctx)
+        .and_then(|ctx| ctx.master_compressor.as_ref())
+        .map_or(0., |master| master.compressor.gain_reduction_db())
+}
+
+#[test]
+fn keyed_compression_ducks_under_the_sidechain() {
+    let mut compressor = MasterCompressor {
+        compressor: Compressor::new(-20., 10., 0., 1., 100., 48000.),
+        keyed: true,
+    };
+    for _ in 0..4800 {
+        assert_eq!(compressor.process([0.5; 3], 0.), [0.5; 3]);
+    }
+    let ducked = (0..4800).fold([0.; 3], |_, _| compressor.process([0.5; 3], 1.));
+    assert!(ducked[0] < 0.1);
+    // Unkeyed, a signal under the threshold passes through
+    compressor.keyed = false;
+    let passed = (0..48000).fold([0.; 3], |_, _| compressor.process([0.05; 3], 1.));
+    assert!((passed[0] - 0.05).abs() < 1e-4);
+}
+
+#[test]
+fn keyed_master_compressor_ducks_under_the_sidechain() {
+    use super::params::{GlobalParam, ParamId};
+    use super::FRAME_SIZE;
+
+    let mut ctx = GranularCtx {
+        waveform: (0..4410).map(|i| (i as f32 * 0.05).sin()).collect(),
+        ..Default::default()
+    };
+    ctx.seed(1);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4409.);
+    let targets = *ctx.params.target();
+    let energy = |ctx: &mut GranularCtx, sidechain: f32| {
+        let mut energy = 0.;
+        for _ in 0..32 {
+            ctx.sidechain_input = [sidechain; FRAME_SIZE];
+            ctx.render(&targets);
+            energy += ctx.rendered_output.iter().map(|s| s * s).sum::<f32>();
+        }
+        energy
+    };
+    energy(&mut ctx, 0.);
+    set_master_compressor(&mut ctx, -30., 20., 1., 50., 0., true);
+    let open = energy(&mut ctx, 0.);
+    assert_eq!(get_master_compressor_reduction_db(&mut ctx), 0.);
+    let ducked = energy(&mut ctx, 1.);
+    assert!(ducked < open * 0.01, "{} {}", ducked, open);
+    assert!(get_master_compressor_reduction_db(&mut ctx) > 20.);
+
+    set_master_compressor(&mut ctx, -30., 1., 1., 50., 0., true);
+    assert!(ctx.master_compressor.is_none());
+}
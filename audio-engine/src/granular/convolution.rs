@@ -0,0 +1,251 @@
+//! Convolution of the master output with an uploaded impulse response, for the reverbs of real
+//! rooms and the colour of speakers and other gear.  The response is normalized to unit energy,
+//! so that swapping one for another doesn't jump in level, and the convolved signal lags the dry
+//! one by a block, like a short pre-delay.
+//!
+//! The response is partitioned into frames, so every render does the same share of the work
+//! instead of a long block's worth landing on a single frame.  Its spectra are prepared by
+//! `prepare`, which needs no instance and can run on another thread, so loading them only moves
+//! them in.  The left and right channels are convolved and the mono mix takes their average,
+//! which is exact while the mono mix is the average of the two.
+
+use super::FRAME_SIZE;
+use super::{ctx_mut, is_supported_sample_rate, GranularCtx};
+use crate::dsp::convolution::{Convolver, ImpulseResponse};
+use crate::dsp::{clamp, mix};
+
+/// Longest impulse response accepted, at the sample rate it's loaded at
+pub const MAX_RESPONSE_SECONDS: f32 = 2.;
+/// Length of the partitions, and the latency of the convolved signal
+pub const BLOCK_LEN: usize = FRAME_SIZE;
+
+/// Values in the prepared spectra of a response of up to `MAX_RESPONSE_SECONDS` at `sample_rate`
+pub fn max_spectra_len(sample_rate: f32) -> usize {
+    let max_len = (MAX_RESPONSE_SECONDS * sample_rate) as usize;
+    max_len.div_ceil(BLOCK_LEN) * ImpulseResponse::partition_len(BLOCK_LEN)
+}
+
+/// Normalized spectra of `samples`, cut to `MAX_RESPONSE_SECONDS` at `sample_rate`, which
+/// `MasterConvolution::new` takes.  Returns None for a response that's silent or not finite.
+pub fn prepare(samples: &[f32], sample_rate: f32) -> Option<Vec<f32>> {
+    let len = samples
+        .len()
+        .min((MAX_RESPONSE_SECONDS * sample_rate) as usize);
+    let samples = &samples[..len];
+    let energy: f32 = samples.iter().map(|sample| sample * sample).sum();
+    if !(energy.is_finite() && energy > 0.) {
+        return None;
+    }
+    let scale = energy.sqrt().recip();
+    let normalized: Vec<f32> = samples.iter().map(|sample| sample * scale).collect();
+    Some(ImpulseResponse::new(&normalized, BLOCK_LEN).into_spectra())
+}
+
+#[derive(Clone)]
+pub struct MasterConvolution {
+    /// From 0, dry, to 1, only the convolved signal
+    pub mix: f32,
+    response: ImpulseResponse,
+    /// Left and right
+    convolvers: [Convolver; 2],
+}
+
+impl MasterConvolution {
+    /// Takes spectra made by `prepare`.  Returns None unless they're whole partitions, all finite
+    /// and no longer than `max_spectra_len` at `sample_rate`.
+    pub fn new(spectra: Vec<f32>, mix: f32, sample_rate: f32) -> Option<Self> {
+        if spectra.len() > max_spectra_len(sample_rate) {
+            return None;
+        }
+        let response = ImpulseResponse::from_spectra(spectra, BLOCK_LEN)?;
+        let convolver = Convolver::new(&response);
+        Some(MasterConvolution {
+            mix,
+            convolvers: [convolver.clone(), convolver],
+            response,
+        })
+    }
+
+    /// A block when the output is only the convolved signal.  Mixed with the dry signal it's
+    /// heard as a pre-delay rather than latency, so it's 0.
+    pub fn latency_samples(&self) -> usize {
+        if self.mix >= 1. {
+            BLOCK_LEN
+        } else {
+            0
+        }
+    }
+
+    /// How long the convolved signal goes on after the input stops, including its block of
+    /// latency, with the response rounded up to whole partitions
+    pub fn tail_samples(&self) -> usize {
+        if self.mix > 0. {
+            (self.response.partition_count() + 1) * BLOCK_LEN
+        } else {
+            0
+        }
+    }
+
+    /// Convolves the left and right channels and mixes the average of the two into the mono mix
+    pub fn process(&mut self, samples: [f32; 3]) -> [f32; 3] {
+        let [left, right, mono] = samples;
+        let wet_left = self.convolvers[0].process(left, &self.response);
+        let wet_right = self.convolvers[1].process(right, &self.response);
+        [
+            mix(self.mix, left, wet_left),
+            mix(self.mix, right, wet_right),
+            mix(self.mix, mono, 0.5 * (wet_left + wet_right)),
+        ]
+    }
+}
+
+/// Normalizes the impulse response in `samples`, cuts it to `MAX_RESPONSE_SECONDS` at
+/// `sample_rate` and transforms it into the spectra `load_impulse_response` takes.  It needs no
+/// instance, so hosts can prepare responses away from the thread that renders.  Returns an empty
+/// buffer for a response that's silent or not finite, or a rate outside the supported sample
+/// rates.
+pub fn prepare_impulse_response(samples: &[f32], sample_rate: f32) -> Vec<f32> {
+    if !is_supported_sample_rate(sample_rate) {
+        return Vec::new();
+    }
+    prepare(samples, sample_rate).unwrap_or_default()
+}
+
+/// Allocates a buffer of `len` values for the host to write the spectra of an impulse response
+/// from `prepare_impulse_response` into, replacing any that were being written.  Returns null
+/// for responses longer than `MAX_RESPONSE_SECONDS` at the current sample rate.
+pub fn get_impulse_response_ptr(ctx: *mut GranularCtx, len: usize) -> *mut f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    if len > max_spectra_len(ctx.sample_rate) {
+        ctx.impulse_response_upload = None;
+        return std::ptr::null_mut();
+    }
+    ctx.impulse_response_upload
+        .insert(vec![0.; len])
+        .as_mut_ptr()
+}
+
+/// Starts convolving the master output with the spectra written through
+/// `get_impulse_response_ptr`, replacing any response it was convolved with before.  The spectra
+/// are moved in as they are, so this doesn't transform anything.  `mix` is from 0, dry, to 1, only
+/// the convolved signal.  Returns false if no spectra were written or they aren't spectra that
+/// `prepare_impulse_response` makes.
+pub fn load_impulse_response(ctx: *mut GranularCtx, mix: f32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(spectra) = ctx.impulse_response_upload.take() else {
+        return false;
+    };
+    let mix = if mix.is_finite() {
+        clamp(0., 1., mix)
+    } else {
+        1.
+    };
+    match MasterConvolution::new(spectra, mix, ctx.sample_rate) {
+        Some(convolution) => {
+            ctx.master_convolution = Some(convolution);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sets how much of the convolved signal the master output is made of, from 0 to 1
+pub fn set_convolution_mix(ctx: *mut GranularCtx, mix: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let (Some(convolution), true) = (&mut ctx.master_convolution, mix.is_finite()) {
+        convolution.mix = clamp(0., 1., mix);
+    }
+}
+
+/// Stops convolving the master output
+pub fn clear_master_convolution(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.master_convolution = None;
+    }
+}
+
+#[test]
+fn responses_are_normalized_and_mixed() {
+    assert!(prepare(&[], 44100.).is_none());
+    assert!(prepare(&[0., f32::NAN], 44100.).is_none());
+    let too_long = prepare(&vec![1.; 44100 * 3], 44100.).unwrap();
+    assert_eq!(too_long.len(), max_spectra_len(44100.));
+    assert!(MasterConvolution::new(too_long, 1., 22050.).is_none());
+
+    // An echo of equal level after two samples, both at 1/sqrt(2) after normalizing
+    let spectra = prepare(&[2., 0., 2.], 44100.).unwrap();
+    let mut convolution = MasterConvolution::new(spectra, 0.5, 44100.).unwrap();
+    let output: Vec<[f32; 3]> = (0..BLOCK_LEN + 4)
+        .map(|ix| {
+            let input = if ix == 0 { 1. } else { 0. };
+            convolution.process([input, 0., input])
+        })
+        .collect();
+    let half_wet = 0.5 * std::f32::consts::FRAC_1_SQRT_2;
+    assert_eq!(output[0], [0.5, 0., 0.5]);
+    assert!((output[BLOCK_LEN][0] - half_wet).abs() < 1e-5);
+    assert!(output[BLOCK_LEN + 1][0].abs() < 1e-5);
+    assert!((output[BLOCK_LEN + 2][0] - half_wet).abs() < 1e-5);
+    assert!(output[BLOCK_LEN + 2][1].abs() < 1e-5);
+    // The mono mix is wet with the average of the channels
+    assert!((output[BLOCK_LEN + 2][2] - half_wet / 2.).abs() < 1e-5);
+}
+
+#[test]
+fn impulse_responses_are_uploaded_and_convolved() {
+    use super::params::{GlobalParam, ParamId};
+
+    let new_ctx = || {
+        let mut ctx = GranularCtx {
+            waveform: (0..4410).map(|i| (i as f32 * 0.05).sin()).collect(),
+            ..Default::default()
+        };
+        ctx.params
+            .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4409.);
+        ctx.seed(1);
+        ctx
+    };
+    let mut ctx = new_ctx();
+    let mut reference = new_ctx();
+    let targets = *ctx.params.target();
+    assert!(!load_impulse_response(&mut ctx, 1.));
+    let max_len = max_spectra_len(ctx.sample_rate);
+    assert!(get_impulse_response_ptr(&mut ctx, max_len + 1).is_null());
+    assert!(prepare_impulse_response(&[1.], 100.).is_empty());
+    assert!(prepare_impulse_response(&[0.; 10], 44100.).is_empty());
+
+    // Samples instead of spectra are refused
+    get_impulse_response_ptr(&mut ctx, 101);
+    assert!(!load_impulse_response(&mut ctx, 1.));
+
+    // A single echo 100 samples late, normalized to full level, after the block of latency
+    let mut response = [0.; 101];
+    response[100] = 0.5;
+    let spectra = prepare_impulse_response(&response, ctx.sample_rate);
+    let ptr = get_impulse_response_ptr(&mut ctx, spectra.len());
+    unsafe { std::slice::from_raw_parts_mut(ptr, spectra.len()) }.copy_from_slice(&spectra);
+    assert!(load_impulse_response(&mut ctx, 1.));
+    assert!(ctx.impulse_response_upload.is_none());
+    let (mut wet, mut dry) = (Vec::new(), Vec::new());
+    for _ in 0..16 {
+        ctx.render(&targets);
+        reference.render(&targets);
+        wet.extend_from_slice(&ctx.rendered_output);
+        dry.extend_from_slice(&reference.rendered_output);
+    }
+    let delay = BLOCK_LEN + 100;
+    for ix in 0..dry.len() - delay {
+        assert!((wet[ix + delay] - dry[ix]).abs() < 1e-3);
+    }
+
+    set_convolution_mix(&mut ctx, 0.);
+    assert_eq!(ctx.master_convolution.as_ref().unwrap().mix, 0.);
+    clear_master_convolution(&mut ctx);
+    assert!(ctx.master_convolution.is_none());
+}
@@ -0,0 +1,526 @@
+//! Concatenative synthesis.  The corpus is a database of fixed-length segments of the loaded
+//! waveform with the energy, spectral centroid and pitch of each.  In concatenative mode every
+//! grain starts at a segment matching a target instead of at the read head, so the texture
+//! follows the target's features with material from the waveform.
+//!
+//! The target is either set directly by the host as a control input, or is the trajectory of
+//! another buffer analyzed into segments the same way, which is followed in real time and loops.
+//!
+//! Matching runs on the audio thread at every grain spawn, so the corpus keeps each segment's
+//! features on the logarithmic scales distances are measured on, and the segments sorted by level
+//! and by brightness.  A match searches outwards from the target along whichever of the two counts
+//! more and stops once that feature alone puts the remaining segments further away than the
+//! matches found so far.
+
+use std::ops::Range;
+
+use rand::Rng;
+
+use super::analysis::{region_centroid, region_energy};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::pitch::estimate_pitch;
+
+/// Most matches a grain can be picked from at random
+pub const MAX_CANDIDATES: usize = 16;
+/// Level differences in decibels that count as much as an octave of brightness or pitch
+const DB_PER_OCTAVE: f32 = 20.;
+/// Distance of a pitched target from an unpitched segment, in octaves
+const UNPITCHED_DISTANCE: f32 = 1.;
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Features {
+    /// RMS level
+    pub energy: f32,
+    pub centroid_hz: f32,
+    /// `None` for unpitched material, and for targets that don't care about pitch
+    pub pitch_hz: Option<f32>,
+}
+
+impl Features {
+    pub fn measure(samples: &[f32], sample_rate: f32) -> Self {
+        Features {
+            energy: region_energy(samples),
+            centroid_hz: region_centroid(samples, sample_rate),
+            pitch_hz: estimate_pitch(samples, sample_rate),
+        }
+    }
+}
+
+/// Features on the scales they're compared on
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+struct Point {
+    /// Level in units of `DB_PER_OCTAVE`
+    level: f32,
+    /// Centroid in octaves
+    brightness: f32,
+    /// Pitch in octaves
+    pitch: Option<f32>,
+}
+
+impl Point {
+    fn new(features: &Features) -> Self {
+        let octaves = |hz: f32| hz.max(1.).log2();
+        Point {
+            level: 20. * features.energy.max(1e-5).log10() / DB_PER_OCTAVE,
+            brightness: octaves(features.centroid_hz),
+            pitch: features.pitch_hz.map(octaves),
+        }
+    }
+
+    /// Weighted squared distance of a segment at this point from `target`
+    #[inline]
+    fn distance(&self, target: &Point, weights: &MatchWeights) -> f32 {
+        let level = self.level - target.level;
+        let brightness = self.brightness - target.brightness;
+        let pitch = match (self.pitch, target.pitch) {
+            (_, None) => 0.,
+            (Some(pitch), Some(target)) => pitch - target,
+            (None, Some(_)) => UNPITCHED_DISTANCE,
+        };
+        weights.energy * level * level
+            + weights.brightness * brightness * brightness
+            + weights.pitch * pitch * pitch
+    }
+}
+
+/// How much each feature counts when matching segments to the target
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MatchWeights {
+    pub energy: f32,
+    pub brightness: f32,
+    pub pitch: f32,
+}
+
+impl Default for MatchWeights {
+    fn default() -> Self {
+        MatchWeights {
+            energy: 1.,
+            brightness: 1.,
+            pitch: 1.,
+        }
+    }
+}
+
+/// Features of every `segment_len` samples of a buffer, leaving out a shorter segment at the end
+#[derive(Clone, Default)]
+pub struct Corpus {
+    pub segment_len: usize,
+    pub segments: Vec<Features>,
+    points: Vec<Point>,
+    /// Segment indices sorted by level
+    by_level: Vec<usize>,
+    /// Segment indices sorted by brightness
+    by_brightness: Vec<usize>,
+}
+
+impl Corpus {
+    pub fn analyze(samples: &[f32], segment_len: usize, sample_rate: f32) -> Self {
+        let segment_len = segment_len.max(1);
+        let segments = samples
+            .chunks_exact(segment_len)
+            .map(|segment| Features::measure(segment, sample_rate))
+            .collect();
+        Self::from_segments(segment_len, segments)
+    }
+
+    fn from_segments(segment_len: usize, segments: Vec<Features>) -> Self {
+        let points: Vec<Point> = segments.iter().map(Point::new).collect();
+        let sorted_by = |key: fn(&Point) -> f32| {
+            let mut order: Vec<usize> = (0..points.len()).collect();
+            order.sort_by(|a, b| key(&points[*a]).total_cmp(&key(&points[*b])));
+            order
+        };
+        Corpus {
+            segment_len,
+            by_level: sorted_by(|point| point.level),
+            by_brightness: sorted_by(|point| point.brightness),
+            segments,
+            points,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Start of one of the `candidates` segments matching `target` best, picked at random, among
+    /// the segments lying within `range` of the buffer.  Returns `None` if there are none.
+    pub fn best_match(
+        &self,
+        target: &Features,
+        weights: &MatchWeights,
+        range: Range<f32>,
+        candidates: usize,
+        rng: &mut impl Rng,
+    ) -> Option<f32> {
+        let candidates = candidates.clamp(1, MAX_CANDIDATES);
+        let target = Point::new(target);
+        let (order, key, weight): (_, fn(&Point) -> f32, _) =
+            if weights.energy >= weights.brightness {
+                (&self.by_level, |point| point.level, weights.energy)
+            } else {
+                (
+                    &self.by_brightness,
+                    |point| point.brightness,
+                    weights.brightness,
+                )
+            };
+        let gap = |order_ix: usize| (key(&self.points[order[order_ix]]) - key(&target)).abs();
+        // Next segments to visit on either side of the target, in `order`
+        let mut above = order.partition_point(|ix| key(&self.points[*ix]) < key(&target));
+        let mut below = above;
+        // The closest segments so far, nearest first, with ties going to the earlier segment
+        let mut best = [(f32::INFINITY, 0); MAX_CANDIDATES];
+        let mut found = 0;
+        loop {
+            let order_ix = match (below.checked_sub(1), above < order.len()) {
+                (Some(lower), true) if gap(lower) <= gap(above) => lower,
+                (_, true) => above,
+                (Some(lower), false) => lower,
+                (None, false) => break,
+            };
+            if found == candidates && weight * gap(order_ix).powi(2) > best[candidates - 1].0 {
+                break;
+            }
+            if order_ix < below {
+                below = order_ix;
+            } else {
+                above += 1;
+            }
+
+            let segment_ix = order[order_ix];
+            let start = (segment_ix * self.segment_len) as f32;
+            if start < range.start || start + self.segment_len as f32 > range.end + 1. {
+                continue;
+            }
+            let distance = self.points[segment_ix].distance(&target, weights);
+            let Some(rank) = best[..candidates]
+                .iter()
+                .position(|best| (distance, segment_ix) < *best)
+            else {
+                continue;
+            };
+            best.copy_within(rank..candidates - 1, rank + 1);
+            best[rank] = (distance, segment_ix);
+            found = (found + 1).min(candidates);
+        }
+        if found == 0 {
+            return None;
+        }
+        let (_, segment_ix) = best[rng.gen_range(0..found)];
+        Some((segment_ix * self.segment_len) as f32)
+    }
+}
+
+/// What the grains are matched to
+#[derive(Clone)]
+pub enum Target {
+    /// Features set by the host
+    Control(Features),
+    /// Features of the segments of another buffer, played through one segment at a time
+    Trajectory {
+        segments: Vec<Features>,
+        segment_len: usize,
+        /// Samples since the start of the trajectory
+        elapsed: usize,
+    },
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Target::Control(Features::default())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Concatenative {
+    pub enabled: bool,
+    pub corpus: Corpus,
+    pub target: Target,
+    pub weights: MatchWeights,
+    /// Number of best matches each grain is picked from at random, for variety
+    pub candidates: usize,
+}
+
+impl Concatenative {
+    pub fn is_active(&self) -> bool {
+        self.enabled && !self.corpus.is_empty()
+    }
+
+    /// Advances a trajectory target by one sample
+    #[inline]
+    pub fn tick(&mut self) {
+        if let Target::Trajectory {
+            segments,
+            segment_len,
+            elapsed,
+        } = &mut self.target
+        {
+            *elapsed = (*elapsed + 1) % (segments.len() * *segment_len).max(1);
+        }
+    }
+
+    pub fn current_target(&self) -> Features {
+        match &self.target {
+            Target::Control(features) => *features,
+            Target::Trajectory {
+                segments,
+                segment_len,
+                elapsed,
+            } => segments
+                .get(elapsed / segment_len)
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Start of the segment the next grain plays, or `None` if no segment lies within `selection`
+    pub fn pick(&self, rng: &mut impl Rng, selection: Range<f32>) -> Option<f32> {
+        self.corpus.best_match(
+            &self.current_target(),
+            &self.weights,
+            selection,
+            self.candidates,
+            rng,
+        )
+    }
+}
+
+/// Splits the loaded waveform into segments of `segment_ms`, measures the energy, spectral
+/// centroid and pitch of each and returns them as interleaved `[rms, centroid_hz, pitch_hz]`
+/// triplets, with a pitch of 0 for unpitched segments.  The segments are kept as the corpus for
+/// concatenative mode, so call this again after loading a new waveform.  Pitch detection makes
+/// this slow on long waveforms.
+pub fn analyze_corpus(ctx: *mut GranularCtx, segment_ms: f32) -> Vec<f32> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    if !segment_ms.is_finite() || segment_ms <= 0. {
+        return Vec::new();
+    }
+    let segment_len = (segment_ms * 0.001 * ctx.sample_rate).round() as usize;
+    ctx.concatenative.corpus =
+        Corpus::analyze(&ctx.samples().decode(), segment_len, ctx.sample_rate);
+    ctx.concatenative
+        .corpus
+        .segments
+        .iter()
+        .flat_map(|segment| {
+            [
+                segment.energy,
+                segment.centroid_hz,
+                segment.pitch_hz.unwrap_or(0.),
+            ]
+        })
+        .collect()
+}
+
+/// Enables or disables concatenative mode.  While it's enabled and `analyze_corpus` has been
+/// called, every grain starts at the start of a corpus segment within the selection that best
+/// matches the target, picked at random among the `candidates` best matches (1 to 16).
+pub fn set_concatenative_mode(ctx: *mut GranularCtx, enabled: bool, candidates: u32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.concatenative.enabled = enabled;
+    ctx.concatenative.candidates = (candidates as usize).clamp(1, MAX_CANDIDATES);
+}
+
+/// Sets how much differences in energy, brightness and pitch count when matching segments to the
+/// target, each from 0 to 10.  Level differences of 20 dB count as much as an octave of
+/// brightness or pitch at equal weights.
+pub fn set_concatenative_weights(ctx: *mut GranularCtx, energy: f32, brightness: f32, pitch: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !energy.is_finite() || !brightness.is_finite() || !pitch.is_finite() {
+        return;
+    }
+    ctx.concatenative.weights = MatchWeights {
+        energy: clamp(0., 10., energy),
+        brightness: clamp(0., 10., brightness),
+        pitch: clamp(0., 10., pitch),
+    };
+}
+
+/// Makes grains match fixed features, e.g. driven by a control input.  A `pitch_hz` of 0 or less
+/// matches segments regardless of pitch.
+pub fn set_concatenative_target(
+    ctx: *mut GranularCtx,
+    energy: f32,
+    centroid_hz: f32,
+    pitch_hz: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !energy.is_finite() || !centroid_hz.is_finite() || !pitch_hz.is_finite() {
+        return;
+    }
+    ctx.concatenative.target = Target::Control(Features {
+        energy: energy.max(0.),
+        centroid_hz: centroid_hz.max(0.),
+        pitch_hz: (pitch_hz > 0.).then_some(pitch_hz),
+    });
+}
+
+/// Makes grains follow the feature trajectory of `samples`, analyzed into segments of the corpus'
+/// length and followed in real time, looping at the end.  Returns the number of target segments,
+/// or 0 if `analyze_corpus` hasn't been called or `samples` is shorter than a segment, in which
+/// case the target is left as it was.
+pub fn set_concatenative_target_buffer(ctx: *mut GranularCtx, samples: &[f32]) -> usize {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    let segment_len = ctx.concatenative.corpus.segment_len;
+    if ctx.concatenative.corpus.is_empty() || samples.iter().any(|sample| !sample.is_finite()) {
+        return 0;
+    }
+    let segments = Corpus::analyze(samples, segment_len, ctx.sample_rate).segments;
+    if segments.is_empty() {
+        return 0;
+    }
+    let segment_count = segments.len();
+    ctx.concatenative.target = Target::Trajectory {
+        segments,
+        segment_len,
+        elapsed: 0,
+    };
+    segment_count
+}
+
+#[test]
+fn grains_are_matched_to_the_target_trajectory() {
+    use std::f32::consts::PI;
+
+    // A quiet low tone, a loud low tone and a loud high tone
+    let sample_rate = 48000.;
+    let tone = |amplitude: f32, hz: f32| {
+        (0..4096).map(move |i| amplitude * (2. * PI * hz * i as f32 / sample_rate).sin())
+    };
+    let waveform: Vec<f32> = tone(0.05, 220.)
+        .chain(tone(0.8, 220.))
+        .chain(tone(0.8, 880.))
+        .collect();
+    let corpus = Corpus::analyze(&waveform, 4096, sample_rate);
+    assert_eq!(corpus.segments.len(), 3);
+    assert!((corpus.segments[2].pitch_hz.unwrap() - 880.).abs() < 5.);
+
+    let mut concatenative = Concatenative {
+        enabled: true,
+        target: Target::Trajectory {
+            segments: vec![corpus.segments[2], corpus.segments[0]],
+            segment_len: 2,
+            elapsed: 0,
+        },
+        corpus,
+        ..Default::default()
+    };
+    let mut rng = crate::common::rng();
+    let whole = 0.0..waveform.len() as f32;
+    let picks: Vec<Option<f32>> = (0..4)
+        .map(|_| {
+            let pick = concatenative.pick(&mut rng, whole.clone());
+            concatenative.tick();
+            pick
+        })
+        .collect();
+    assert_eq!(picks, vec![Some(8192.), Some(8192.), Some(0.), Some(0.)]);
+
+    // A loud target that doesn't care about pitch matches either loud segment
+    concatenative.target = Target::Control(Features {
+        energy: 0.5,
+        centroid_hz: 500.,
+        pitch_hz: None,
+    });
+    concatenative.candidates = 2;
+    for _ in 0..8 {
+        let pick = concatenative.pick(&mut rng, whole.clone()).unwrap();
+        assert!(pick == 4096. || pick == 8192.);
+    }
+    // Only the first segment lies within this selection
+    assert_eq!(concatenative.pick(&mut rng, 0.0..4095.), Some(0.));
+    assert_eq!(concatenative.pick(&mut rng, 100.0..4095.), None);
+}
+
+#[test]
+fn indexed_matches_equal_a_full_scan() {
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+    let random_features = |rng: &mut rand::rngs::StdRng| Features {
+        energy: rng.gen_range(0.0..1.),
+        centroid_hz: rng.gen_range(50.0..10000.),
+        pitch_hz: rng.gen_bool(0.5).then(|| rng.gen_range(50.0..1000.)),
+    };
+    let segments: Vec<Features> = (0..200).map(|_| random_features(&mut rng)).collect();
+    let corpus = Corpus::from_segments(10, segments);
+    for _ in 0..100 {
+        let target = random_features(&mut rng);
+        let weights = MatchWeights {
+            energy: rng.gen_range(0.0..10.),
+            brightness: rng.gen_range(0.0..10.),
+            pitch: rng.gen_range(0.0..10.),
+        };
+        let range = rng.gen_range(0.0..1000.)..rng.gen_range(1000.0..2000.);
+        let candidates = rng.gen_range(1..=MAX_CANDIDATES);
+
+        let target_point = Point::new(&target);
+        let mut scan: Vec<(f32, usize)> = (0..corpus.segments.len())
+            .filter(|ix| {
+                let start = (ix * 10) as f32;
+                start >= range.start && start + 10. <= range.end + 1.
+            })
+            .map(|ix| (corpus.points[ix].distance(&target_point, &weights), ix))
+            .collect();
+        scan.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        scan.truncate(candidates);
+        let mut scan_rng = rng.clone();
+        let pick = corpus.best_match(&target, &weights, range, candidates, &mut rng);
+        let scan_pick = (!scan.is_empty()).then(|| scan[scan_rng.gen_range(0..scan.len())].1 * 10);
+        assert_eq!(pick, scan_pick.map(|start| start as f32));
+    }
+}
+
+#[test]
+fn concatenative_grains_start_at_the_best_matching_segment() {
+    use super::crop_waveform;
+    use super::params::{GlobalParam, ParamId};
+
+    // Three segments of 10ms: quiet, loud and quiet again
+    let segment_len = 441;
+    let mut ctx = GranularCtx {
+        waveform: (0..3 * segment_len)
+            .map(|i| {
+                let amplitude = if i / segment_len == 1 { 0.8 } else { 0.01 };
+                amplitude * (i as f32 * 0.1).sin()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    assert_eq!(analyze_corpus(&mut ctx, 10.).len(), 9);
+    set_concatenative_mode(&mut ctx, true, 1);
+    set_concatenative_target(&mut ctx, 0.5, 1000., 0.);
+    ctx.params.set_target(
+        ParamId::Global(GlobalParam::SelectionEndSampleIx),
+        (3 * segment_len - 1) as f32,
+    );
+    let targets = *ctx.params.target();
+    let mut grain_count = 0;
+    for _ in 0..64 {
+        ctx.render(&targets);
+        for grain in ctx.voices.iter().flat_map(|voice| &voice.grains) {
+            assert_eq!(grain.start_sample_ix, segment_len as f32);
+            grain_count += 1;
+        }
+    }
+    assert!(grain_count > 0);
+
+    // A target buffer shorter than a segment is rejected
+    assert_eq!(set_concatenative_target_buffer(&mut ctx, &[0.; 100]), 0);
+    assert_eq!(set_concatenative_target_buffer(&mut ctx, &[0.; 1000]), 2);
+    // Cropping invalidates the segment positions
+    assert_eq!(crop_waveform(&mut ctx, 0, 1000), 1000);
+    assert!(!ctx.concatenative.is_active());
+}
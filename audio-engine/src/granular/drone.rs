@@ -0,0 +1,154 @@
+//! Drone mode.  A preset way of playing the engine for pads and drones, driven by a single blur
+//! amount: while it's on, every frame's targets get long, heavily overlapping grains with
+//! full-length sine slopes from positions scattered over much of the selection, and each grain is
+//! detuned by a few random cents.  More blur means longer grains, more overlap, wider scatter and
+//! more detune, smearing the source into a wash.
+//!
+//! Holding the drone freezes every voice into a long loop with a long crossfade at its ends, so it
+//! sustains indefinitely without reading the waveform.
+
+use super::params::{GlobalParam, ParamId, ParamValues, VoiceParam, VOICE_COUNT};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::{clamp, mix};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DroneMode {
+    /// From 0 to 1
+    pub blur: f32,
+}
+
+impl DroneMode {
+    fn grain_ms(&self) -> f32 {
+        mix(self.blur, 150., 1500.)
+    }
+
+    /// Largest random detune of each grain, in cents either way
+    pub fn grain_detune_cents(&self) -> f32 {
+        mix(self.blur, 3., 20.)
+    }
+
+    pub fn hold_loop_ms(&self) -> f32 {
+        mix(self.blur, 2000., 8000.)
+    }
+
+    pub fn hold_crossfade_ms(&self) -> f32 {
+        mix(self.blur, 250., 2000.)
+    }
+
+    /// Overrides the grain parameters in `targets`
+    pub fn apply(&self, targets: &mut ParamValues, sample_rate: f32) {
+        let max_samples = GlobalParam::GrainSize.info().max;
+        let grain_size = (self.grain_ms() * 0.001 * sample_rate).min(max_samples);
+        let overlap = mix(self.blur, 4., 12.);
+        let selection_len = targets.global(GlobalParam::SelectionEndSampleIx)
+            - targets.global(GlobalParam::SelectionStartSampleIx);
+        let scatter = (selection_len.max(0.) * mix(self.blur, 0.3, 1.)).min(max_samples);
+        let slope_linearity = mix(self.blur, 0.5, 1.);
+
+        targets.set(ParamId::Global(GlobalParam::GrainSize), grain_size);
+        targets.set(ParamId::Global(GlobalParam::LinearSlopeLength), 1.);
+        targets.set(
+            ParamId::Global(GlobalParam::SlopeLinearity),
+            slope_linearity,
+        );
+        for voice_ix in 0..VOICE_COUNT {
+            let mut set = |param: VoiceParam, value: f32| {
+                targets.set(ParamId::Voice(voice_ix, param), value);
+            };
+            set(VoiceParam::SamplesBetweenGrains, grain_size / overlap);
+            set(VoiceParam::GrainStartRandomnessSamples, scatter);
+            set(VoiceParam::SlopeLength, 1.);
+            set(VoiceParam::SlopeLinearity, slope_linearity);
+        }
+    }
+}
+
+/// Turns drone mode on or off.  While it's on, the grain size, spacing, slopes and start
+/// randomness are set from `blur` from 0 to 1 instead of their parameters, and every grain is
+/// slightly detuned.  Turning it off also releases a held drone.
+pub fn set_drone_mode(ctx: *mut GranularCtx, enabled: bool, blur: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !blur.is_finite() {
+        return;
+    }
+    if enabled {
+        ctx.drone = Some(DroneMode {
+            blur: clamp(0., 1., blur),
+        });
+    } else if ctx.drone.take().is_some() {
+        for voice in &mut ctx.voices {
+            voice.freeze = None;
+        }
+    }
+}
+
+/// Sets how much drone mode smears the source, from 0 to 1.  Has no effect while it's off.
+pub fn set_drone_blur(ctx: *mut GranularCtx, blur: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(drone) = ctx.drone.as_mut().filter(|_| blur.is_finite()) {
+        drone.blur = clamp(0., 1., blur);
+    }
+}
+
+#[test]
+fn more_blur_means_longer_denser_and_wider_grains() {
+    let mut targets = ParamValues::default();
+    targets.set(ParamId::Global(GlobalParam::SelectionEndSampleIx), 48000.);
+    let mut check = |blur: f32, size: f32, overlap: f32, scatter: f32| {
+        DroneMode { blur }.apply(&mut targets, 48000.);
+        let grain_size = targets.global(GlobalParam::GrainSize);
+        assert!((grain_size - size).abs() < 0.1, "{}", grain_size);
+        let spacing = targets.voice(1, VoiceParam::SamplesBetweenGrains);
+        assert!((grain_size / spacing - overlap).abs() < 0.001);
+        let randomness = targets.voice(0, VoiceParam::GrainStartRandomnessSamples);
+        assert!((randomness - scatter).abs() < 0.1, "{}", randomness);
+    };
+    check(0., 7200., 4., 14400.);
+    check(1., 72000., 12., 48000.);
+}
+
+#[test]
+fn drone_mode_sets_long_detuned_grains_and_holds() {
+    use super::{send_command, sine_ctx, DEFAULT_SAMPLE_RATE};
+
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    set_drone_blur(&mut ctx, 1.);
+    assert!(ctx.drone.is_none());
+    set_drone_mode(&mut ctx, true, 0.5);
+    let targets = *ctx.params.target();
+    for _ in 0..512 {
+        ctx.render(&targets);
+    }
+    // 825 ms grains once the size has been smoothed, each detuned by up to 11.5 cents
+    let max_ratio = (11.5f32 / 1200.).exp2();
+    let grains = &ctx.voices[0].grains;
+    assert!(grains.len() > 1);
+    let newest_len = grains.last().unwrap().len_samples;
+    assert!(
+        (newest_len - 0.825 * DEFAULT_SAMPLE_RATE).abs() < 2.,
+        "{}",
+        newest_len
+    );
+    assert!(grains.iter().all(|grain| {
+        grain.sample_playback_ratio <= max_ratio * 1.0001
+            && grain.sample_playback_ratio >= max_ratio.recip() / 1.0001
+    }));
+    assert!(grains
+        .iter()
+        .any(|grain| grain.sample_playback_ratio != grains.get(0).unwrap().sample_playback_ratio));
+
+    send_command(&mut ctx, [6, 0, 1, 0]);
+    ctx.render(&targets);
+    assert!(ctx.voices.iter().all(|voice| voice.freeze.is_some()));
+    set_drone_mode(&mut ctx, false, 0.);
+    assert!(ctx.voices.iter().all(|voice| voice.freeze.is_none()));
+    send_command(&mut ctx, [6, 0, 1, 0]);
+    ctx.render(&targets);
+    assert!(ctx.voices[0].freeze.is_none());
+}
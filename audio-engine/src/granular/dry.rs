@@ -0,0 +1,84 @@
+//! Dry playback.  Alongside the grains, the selection can be played straight through at its
+//! original speed and mixed under the voices at its own gain, for blending the intelligible
+//! source with the granulated texture.  Playback loops over the selection, with short fades at
+//! its ends so that the loop point doesn't click.
+
+use super::envelope::click_guard_gain;
+use super::params::ParamId;
+use super::params::{GlobalParam, ParamValues};
+use super::waveform::WaveformChannels;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+#[derive(Clone, Copy, Default)]
+pub struct DryPlayback {
+    /// Absolute index of the next sample to play
+    position: f32,
+}
+
+impl DryPlayback {
+    pub fn reset(&mut self) {
+        self.position = 0.;
+    }
+
+    /// Plays the next sample of the selection, returning it already scaled by the dry gain
+    #[inline]
+    pub fn tick(&mut self, channels: WaveformChannels, params: &ParamValues) -> (f32, f32) {
+        let gain = params.global(GlobalParam::DryGain);
+        let selection_start = params.global(GlobalParam::SelectionStartSampleIx);
+        let selection_end = params.global(GlobalParam::SelectionEndSampleIx);
+        if self.position < selection_start || self.position >= selection_end {
+            self.position = selection_start;
+        }
+        let position = self.position;
+        self.position += 1.;
+        if gain <= 0. || channels.left.is_empty() {
+            return (0., 0.);
+        }
+
+        let gain = gain * click_guard_gain(position - selection_start, selection_end - position);
+        let left = channels.left.read(position);
+        let right = match channels.right {
+            Some(right) if !right.is_empty() => right.read(position),
+            _ => left,
+        };
+        (left * gain, right * gain)
+    }
+}
+
+/// Sets the gain of the dry playback, which plays the selection straight through at its original
+/// speed under the voices.  It's off at 0 and ramped across the next frame like the other gains.
+pub fn set_dry_gain(ctx: *mut GranularCtx, gain: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !gain.is_finite() {
+        return;
+    }
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::DryGain), clamp(0., 4., gain));
+}
+
+#[test]
+fn dry_playback_loops_over_the_selection() {
+    let mut params = ParamValues::default();
+    let set = |params: &mut ParamValues, param, value| {
+        params.set(super::params::ParamId::Global(param), value)
+    };
+    set(&mut params, GlobalParam::DryGain, 1.);
+    set(&mut params, GlobalParam::SelectionStartSampleIx, 100.);
+    set(&mut params, GlobalParam::SelectionEndSampleIx, 300.);
+    let samples: Vec<f32> = (0..400).map(|ix| ix as f32).collect();
+    let channels = WaveformChannels {
+        left: samples[..].into(),
+        right: None,
+    };
+
+    let mut dry = DryPlayback::default();
+    let played: Vec<f32> = (0..400).map(|_| dry.tick(channels, &params).0).collect();
+    // Loop ends are faded in and out
+    assert_eq!(played[0], 0.);
+    assert_eq!(played[100], 200.);
+    assert_eq!(played[200], 0.);
+    assert_eq!(played[300], 200.);
+}
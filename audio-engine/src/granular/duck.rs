@@ -0,0 +1,118 @@
+//! Ducking of the second voice by the first.  The first voice's output level is followed with
+//! its own attack and release, and the second voice is turned down by as much of that level as
+//! the amount asks for, so a rhythmic first voice can carve room out of a sustained second one
+//! without the host routing a sidechain.
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::dynamics::EnvelopeFollower;
+
+/// Voice whose level ducks the other one
+pub const KEY_IX: usize = 0;
+/// Voice that's ducked
+pub const DUCKED_IX: usize = 1;
+
+#[derive(Clone)]
+pub struct VoiceDucking {
+    /// From 0, no ducking, to 1, where a full-scale first voice silences the second
+    pub amount: f32,
+    follower: EnvelopeFollower,
+}
+
+impl VoiceDucking {
+    pub fn new(amount: f32, attack_ms: f32, release_ms: f32, sample_rate: f32) -> Self {
+        VoiceDucking {
+            amount,
+            follower: EnvelopeFollower::new(attack_ms, release_ms, sample_rate),
+        }
+    }
+
+    /// Changes the settings without resetting the level that's being followed
+    pub fn set(&mut self, amount: f32, attack_ms: f32, release_ms: f32, sample_rate: f32) {
+        self.amount = amount;
+        self.follower.set_times(attack_ms, release_ms, sample_rate);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.follower.set_sample_rate(sample_rate);
+    }
+
+    /// Follows a sample of the first voice's output and returns the gain of the second voice
+    pub fn process(&mut self, key: f32) -> f32 {
+        let level = self.follower.process(key).min(1.);
+        1. - self.amount * level
+    }
+}
+
+/// Ducks the second voice by the level of the first, followed with `attack_ms` (0.1 to 1000) and
+/// `release_ms` (1 to 5000).  An `amount` of 1 lets a full-scale first voice silence the second,
+/// and one of 0 turns ducking off.
+pub fn set_voice_ducking(ctx: *mut GranularCtx, amount: f32, attack_ms: f32, release_ms: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !amount.is_finite() || !attack_ms.is_finite() || !release_ms.is_finite() {
+        return;
+    }
+    let amount = clamp(0., 1., amount);
+    let attack_ms = clamp(0.1, 1000., attack_ms);
+    let release_ms = clamp(1., 5000., release_ms);
+    let sample_rate = ctx.sample_rate;
+    match &mut ctx.voice_ducking {
+        _ if amount == 0. => ctx.voice_ducking = None,
+        Some(ducking) => ducking.set(amount, attack_ms, release_ms, sample_rate),
+        ducking => {
+            *ducking = Some(VoiceDucking::new(
+                amount,
+                attack_ms,
+                release_ms,
+                sample_rate,
+            ))
+        }
+    }
+}
+
+#[test]
+fn ducking_follows_the_key_and_recovers() {
+    let mut ducking = VoiceDucking::new(0.5, 1., 50., 44100.);
+    assert_eq!(ducking.process(0.), 1.);
+    let mut gain = 1.;
+    for _ in 0..441 {
+        gain = ducking.process(1.);
+    }
+    assert!((gain - 0.5).abs() < 0.01, "{}", gain);
+    // Still ducked a little after the release time, and back after several of them
+    for _ in 0..2205 {
+        gain = ducking.process(0.);
+    }
+    assert!(gain > 0.75 && gain < 0.95, "{}", gain);
+    for _ in 0..22050 {
+        gain = ducking.process(0.);
+    }
+    assert!(gain > 0.999);
+}
+
+#[test]
+fn the_first_voice_ducks_the_second() {
+    use super::test_targets;
+
+    let render = |amount: f32| {
+        let mut ctx = GranularCtx {
+            waveform: (0..44100).map(|i| (i as f32 * 0.01).sin()).collect(),
+            ..Default::default()
+        };
+        set_voice_ducking(&mut ctx, amount, 1., 100.);
+        assert_eq!(ctx.voice_ducking.is_some(), amount > 0.);
+        let targets = test_targets(44099.);
+        for _ in 0..32 {
+            ctx.render(&targets);
+        }
+        let level = |voice: &[f32]| voice.iter().map(|sample| sample.abs()).sum::<f32>();
+        let [first, second] = &ctx.rendered_voice_outputs;
+        (level(first), level(second))
+    };
+    let (first, second) = render(0.);
+    let (ducked_first, ducked_second) = render(1.);
+    assert_eq!(first, ducked_first);
+    assert!(ducked_second < second * 0.9, "{} {}", ducked_second, second);
+}
@@ -0,0 +1,856 @@
+//! Grain amplitude envelopes.  By default grains use the symmetric slope shape set by the global
+//! `linear_slope_length` and `slope_linearity` parameters.  Each voice can have its own values
+//! for them instead, so that one voice plays smooth clouds while another plays clicks from the
+//! same source.  Voices can also give their grains separate attack and release slopes, follow an
+//! envelope table drawn by the host which grains read with linear interpolation from start to
+//! end, or crossfade between two fixed window shapes, e.g. from Gaussian clouds to trapezoid
+//! slices, under control of a modulatable morph parameter.
+//!
+//! The slopes of a voice can follow one of the usual curve families instead of the sine/linear
+//! blend, so that the character of the fades matches what users expect from other granular
+//! tools.
+//!
+//! Any of these shapes can have ripples added, a number of amplitude bumps within every grain that
+//! give it a buzzy tremolo texture.
+//!
+//! Whatever the shape, it can be skewed so that its peak moves towards the start of the grain for
+//! percussive grains or towards its end for reversed-sounding ones.
+//!
+//! In overlap-add mode every grain uses a Hann window instead of any of the above, which keeps the
+//! summed output steady as grains overlap; see `set_overlap_add_mode`.
+//!
+//! Voices can also compensate their level for the density of their grains; see
+//! `DensityCompensation` and `OverlapCompensation`.
+//!
+//! Unless it's turned off, a short fade is applied to the edges of every grain on top of its
+//! envelope so that shapes which start or end at full level, like a slope length of 0, don't
+//! click.
+
+use super::params::{ParamId, VoiceParam, VOICE_COUNT};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::{clamp, mix, read_interpolated, smooth};
+use std::f32::consts::{FRAC_PI_2, PI};
+
+/// Longest envelope table accepted from the host; longer tables add no audible detail
+pub const MAX_ENVELOPE_TABLE_LEN: usize = 4096;
+
+/// Steepness of the exponential and logarithmic slope curves
+const CURVE_STEEPNESS: f32 = 5.;
+/// Closest the peak of a fully skewed envelope gets to the edge of the grain
+const MIN_PEAK_DISTANCE: f32 = 0.01;
+/// Length of the click guard fades in output samples
+pub const CLICK_GUARD_SAMPLES: f32 = 32.;
+/// Points the envelope is sampled at to measure its energy for density compensation
+const ENERGY_SAMPLE_COUNT: usize = 64;
+/// Per-sample smoothing of the density compensation gain, so that it doesn't zipper as grains
+/// spawn and settings change
+const COMPENSATION_SMOOTHING: f32 = 0.999;
+
+/// Standard deviation of the Gaussian window relative to the grain length
+const GAUSSIAN_WIDTH: f32 = 0.15;
+/// Fraction of the grain taken up by each ramp of the trapezoid window
+const TRAPEZOID_RAMP: f32 = 0.1;
+
+/// Fixed window shapes that voices can morph between
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowShape {
+    Sine,
+    Hann,
+    /// Gaussian bell, shifted and scaled to reach silence at the grain's edges
+    Gaussian,
+    Triangle,
+    /// Flat top with short linear ramps
+    Trapezoid,
+    /// Full level throughout; relies on the click guard for its edges
+    Rectangle,
+}
+
+impl WindowShape {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(WindowShape::Sine),
+            1 => Some(WindowShape::Hann),
+            2 => Some(WindowShape::Gaussian),
+            3 => Some(WindowShape::Triangle),
+            4 => Some(WindowShape::Trapezoid),
+            5 => Some(WindowShape::Rectangle),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn gain_at(self, pos_in_grain: f32) -> f32 {
+        let pos_in_grain = clamp(0., 1., pos_in_grain);
+        match self {
+            WindowShape::Sine => (PI * pos_in_grain).sin(),
+            WindowShape::Hann => 0.5 - 0.5 * (2. * PI * pos_in_grain).cos(),
+            WindowShape::Gaussian => {
+                let gaussian = |pos: f32| (-0.5 * ((pos - 0.5) / GAUSSIAN_WIDTH).powi(2)).exp();
+                let edge = gaussian(0.);
+                (gaussian(pos_in_grain) - edge) / (1. - edge)
+            }
+            WindowShape::Triangle => 1. - (2. * pos_in_grain - 1.).abs(),
+            WindowShape::Trapezoid => {
+                (pos_in_grain.min(1. - pos_in_grain) / TRAPEZOID_RAMP).min(1.)
+            }
+            WindowShape::Rectangle => 1.,
+        }
+    }
+}
+
+/// Most ripples a grain envelope can have
+pub const MAX_RIPPLE_COUNT: u32 = 64;
+
+/// Amplitude bumps within a grain's envelope
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ripple {
+    /// Bumps per grain
+    pub count: u32,
+    /// 0 leaves the envelope unchanged and 1 splits it into separate bumps
+    pub depth: f32,
+}
+
+impl Ripple {
+    #[inline]
+    pub fn gain_at(self, pos_in_grain: f32) -> f32 {
+        let bump = 0.5 - 0.5 * (2. * PI * self.count as f32 * pos_in_grain).cos();
+        1. - self.depth + self.depth * bump
+    }
+}
+
+/// Envelope parameters of a voice for the current sample, after modulation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnvelopeParams {
+    pub linear_slope_length: f32,
+    pub slope_linearity: f32,
+    pub skew: f32,
+    /// Position of the crossfade between the voice's morph shapes, from 0 to 1
+    pub morph: f32,
+}
+
+/// Mean squared gain of an envelope over the length of a grain
+pub fn envelope_energy(gain_at: impl Fn(f32) -> f32) -> f32 {
+    (0..ENERGY_SAMPLE_COUNT)
+        .map(|ix| {
+            let gain = gain_at((ix as f32 + 0.5) / ENERGY_SAMPLE_COUNT as f32);
+            gain * gain
+        })
+        .sum::<f32>()
+        / ENERGY_SAMPLE_COUNT as f32
+}
+
+/// Mean gain of an envelope over the length of a grain
+pub fn envelope_mean_gain(gain_at: impl Fn(f32) -> f32) -> f32 {
+    (0..ENERGY_SAMPLE_COUNT)
+        .map(|ix| gain_at((ix as f32 + 0.5) / ENERGY_SAMPLE_COUNT as f32))
+        .sum::<f32>()
+        / ENERGY_SAMPLE_COUNT as f32
+}
+
+/// Automatic gain that keeps the level of a voice steady as its grains overlap more or less.
+/// Grains reading different parts of the waveform add up in power rather than amplitude, so the
+/// compensation is based on the summed energy of the envelopes playing at any time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DensityCompensation {
+    pub enabled: bool,
+    target: f32,
+    gain: f32,
+}
+
+impl Default for DensityCompensation {
+    fn default() -> Self {
+        DensityCompensation {
+            enabled: false,
+            target: 1.,
+            gain: 1.,
+        }
+    }
+}
+
+impl DensityCompensation {
+    /// Updates the target gain from the number of grains overlapping on average and the energy
+    /// of their envelope.  Sparse grains that leave the equivalent of less than one full-level
+    /// grain playing aren't boosted.
+    pub fn set_overlap(&mut self, overlap: f32, energy: f32) {
+        let summed_energy = overlap * energy;
+        self.target = if summed_energy.is_finite() && summed_energy > 1. {
+            1. / summed_energy.sqrt()
+        } else {
+            1.
+        };
+    }
+
+    #[inline]
+    pub fn tick(&mut self) -> f32 {
+        smooth(&mut self.gain, self.target, COMPENSATION_SMOOTHING);
+        self.gain
+    }
+}
+
+/// Gain of the click guard for a grain that has played `elapsed_samples` output samples and has
+/// `remaining_samples` left to play
+#[inline]
+pub fn click_guard_gain(elapsed_samples: f32, remaining_samples: f32) -> f32 {
+    (elapsed_samples.min(remaining_samples) / CLICK_GUARD_SAMPLES).clamp(0., 1.)
+}
+
+/// Warps `pos_in_grain` so that the center of the envelope is reached at a peak moved by `skew`
+/// from the center towards the start (-1) or end (1) of the grain
+#[inline]
+pub fn skew_position(pos_in_grain: f32, skew: f32) -> f32 {
+    if skew == 0. {
+        return pos_in_grain;
+    }
+    let peak = clamp(MIN_PEAK_DISTANCE, 1. - MIN_PEAK_DISTANCE, 0.5 + 0.5 * skew);
+    if pos_in_grain < peak {
+        0.5 * pos_in_grain / peak
+    } else {
+        0.5 + 0.5 * (pos_in_grain - peak) / (1. - peak)
+    }
+}
+
+/// Envelope drawn by the host, sampled evenly over the length of the grain
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvelopeTable {
+    values: Vec<f32>,
+}
+
+impl EnvelopeTable {
+    /// Returns `None` if `values` is empty, too long or contains non-finite values.  Values are
+    /// clamped to 0..1 so that grains never get louder than with the built-in shapes.
+    pub fn new(values: &[f32]) -> Option<Self> {
+        if values.is_empty()
+            || values.len() > MAX_ENVELOPE_TABLE_LEN
+            || values.iter().any(|value| !value.is_finite())
+        {
+            return None;
+        }
+        Some(EnvelopeTable {
+            values: values.iter().map(|value| clamp(0., 1., *value)).collect(),
+        })
+    }
+
+    /// Gain at `pos_in_grain`, from 0 at the start of the grain to 1 at its end
+    #[inline]
+    pub fn gain_at(&self, pos_in_grain: f32) -> f32 {
+        let index = clamp(0., 1., pos_in_grain) * (self.values.len() - 1) as f32;
+        read_interpolated(&self.values, index)
+    }
+}
+
+/// Shape of a slope as it rises from silence to full level
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SlopeCurve {
+    /// Mix between a quarter sine and a straight line set by the slope's linearity
+    #[default]
+    Blend,
+    Linear,
+    /// Half a cosine period, flat at both ends
+    RaisedCosine,
+    /// Starts slowly and rises steeply towards full level
+    Exponential,
+    /// Rises steeply at first and levels off towards full level
+    Logarithmic,
+}
+
+impl SlopeCurve {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(SlopeCurve::Blend),
+            1 => Some(SlopeCurve::Linear),
+            2 => Some(SlopeCurve::RaisedCosine),
+            3 => Some(SlopeCurve::Exponential),
+            4 => Some(SlopeCurve::Logarithmic),
+            _ => None,
+        }
+    }
+}
+
+fn exponential_curve(pos_in_slope: f32) -> f32 {
+    (CURVE_STEEPNESS * pos_in_slope).exp_m1() / CURVE_STEEPNESS.exp_m1()
+}
+
+/// Curves of the attack and release slopes of a voice's grains
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct SlopeCurves {
+    pub attack: SlopeCurve,
+    pub release: SlopeCurve,
+}
+
+/// One side of a grain's envelope
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Slope {
+    /// Fraction of the grain the slope takes up, from 0 for none to 1 for the whole grain
+    pub length: f32,
+    /// Mix between a straight line (0) and a quarter sine (1) for the `Blend` curve
+    pub linearity: f32,
+}
+
+impl Slope {
+    pub fn new(length: f32, linearity: f32) -> Self {
+        Slope {
+            length: clamp(0., 1., length),
+            linearity: clamp(0., 1., linearity),
+        }
+    }
+
+    /// Gain at `pos_in_slope`, from 0 where the slope starts at silence to 1 where it reaches
+    /// full level
+    #[inline]
+    fn gain_at(self, pos_in_slope: f32, curve: SlopeCurve) -> f32 {
+        match curve {
+            SlopeCurve::Blend => mix(
+                self.linearity,
+                pos_in_slope,
+                (pos_in_slope * FRAC_PI_2).sin(),
+            ),
+            SlopeCurve::Linear => pos_in_slope,
+            SlopeCurve::RaisedCosine => 0.5 - 0.5 * (pos_in_slope * PI).cos(),
+            SlopeCurve::Exponential => exponential_curve(pos_in_slope),
+            SlopeCurve::Logarithmic => 1. - exponential_curve(1. - pos_in_slope),
+        }
+    }
+}
+
+/// Separate attack and release slopes, for percussive grains with a fast attack and a long tail
+/// or swelling ones with a slow attack
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnvelopeSlopes {
+    pub attack: Slope,
+    pub release: Slope,
+}
+
+impl EnvelopeSlopes {
+    /// Slopes equivalent to the global `linear_slope_length` and `slope_linearity` parameters
+    pub fn symmetric(linear_slope_length: f32, slope_linearity: f32) -> Self {
+        let slope = Slope::new(linear_slope_length / 2., slope_linearity);
+        EnvelopeSlopes {
+            attack: slope,
+            release: slope,
+        }
+    }
+
+    /// Gain at `pos_in_grain`.  Slopes that add up to more than the whole grain are shortened in
+    /// proportion so that they meet without a plateau.
+    #[inline]
+    pub fn gain_at(&self, pos_in_grain: f32, curves: SlopeCurves) -> f32 {
+        let total_length = self.attack.length + self.release.length;
+        let scale = if total_length > 1. {
+            1. / total_length
+        } else {
+            1.
+        };
+        let attack_length = self.attack.length * scale;
+        let release_length = self.release.length * scale;
+        let attack_gain = if pos_in_grain < attack_length {
+            self.attack
+                .gain_at(pos_in_grain / attack_length, curves.attack)
+        } else {
+            1.
+        };
+        let release_gain = if 1. - pos_in_grain < release_length {
+            self.release
+                .gain_at((1. - pos_in_grain).max(0.) / release_length, curves.release)
+        } else {
+            1.
+        };
+        attack_gain.min(release_gain)
+    }
+}
+
+/// Envelope settings of one voice that replace the built-in slope shape when set
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoiceEnvelope {
+    /// Set when the voice's `SlopeLength` and `SlopeLinearity` parameters are used instead of
+    /// the global ones
+    pub own_slope_shape: bool,
+    pub table: Option<EnvelopeTable>,
+    pub slopes: Option<EnvelopeSlopes>,
+    pub curves: SlopeCurves,
+    /// Fades the edges of grains over `CLICK_GUARD_SAMPLES` whatever their envelope
+    pub click_guard: bool,
+    /// Replaces every other setting with a Hann window
+    pub overlap_add: bool,
+    /// Applied on top of the shape
+    pub ripple: Option<Ripple>,
+    /// Window shapes that the `EnvelopeMorph` parameter crossfades between, replacing the other
+    /// shape settings
+    pub morph: Option<(WindowShape, WindowShape)>,
+}
+
+impl Default for VoiceEnvelope {
+    fn default() -> Self {
+        VoiceEnvelope {
+            own_slope_shape: false,
+            table: None,
+            slopes: None,
+            curves: SlopeCurves::default(),
+            click_guard: true,
+            overlap_add: false,
+            ripple: None,
+            morph: None,
+        }
+    }
+}
+
+impl VoiceEnvelope {
+    /// Gain at `pos_in_grain`, or `None` if grains of this voice use the built-in shape.  The
+    /// overlap-add window takes precedence over morphing, then tables and then slopes.  Voices
+    /// with their own curves but not their own slopes apply the curves to the symmetric slopes
+    /// set by the global parameters.
+    #[inline]
+    pub fn gain_at(&self, pos_in_grain: f32, params: EnvelopeParams) -> Option<f32> {
+        if self.overlap_add {
+            return Some(WindowShape::Hann.gain_at(pos_in_grain));
+        }
+        if let Some((from, to)) = self.morph {
+            return Some(mix(
+                params.morph,
+                from.gain_at(pos_in_grain),
+                to.gain_at(pos_in_grain),
+            ));
+        }
+        if let Some(table) = &self.table {
+            return Some(table.gain_at(pos_in_grain));
+        }
+        let slopes = match self.slopes {
+            Some(slopes) => slopes,
+            None if self.curves == SlopeCurves::default() => return None,
+            None => EnvelopeSlopes::symmetric(params.linear_slope_length, params.slope_linearity),
+        };
+        Some(slopes.gain_at(pos_in_grain, self.curves))
+    }
+}
+
+/// Moves the peak of a voice's grain envelopes from the center towards the start (-1) or end (1)
+/// of the grain.  The `EnvelopeSkew` modulation destination is added on top.
+pub fn set_voice_envelope_skew(ctx: *mut GranularCtx, voice_ix: usize, skew: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !skew.is_finite() {
+        return;
+    }
+    ctx.params.set_target(
+        ParamId::Voice(voice_ix, VoiceParam::EnvelopeSkew),
+        clamp(-1., 1., skew),
+    );
+}
+
+/// Turns the short fade applied to the edges of every grain on or off for all voices.  It's on by
+/// default so that envelopes starting or ending at full level don't click.
+pub fn set_click_guard(ctx: *mut GranularCtx, enabled: bool) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    for voice in &mut ctx.voices {
+        voice.envelope_mut().click_guard = enabled;
+    }
+}
+
+/// Adds `count` amplitude bumps to every grain of a voice on top of its envelope shape, for
+/// tremolo textures within grains.  `depth` goes from 0, which leaves the envelope unchanged, to
+/// 1, which splits grains into separate bumps.  A count or depth of 0 removes the ripples.
+/// Returns false if `count` is above `MAX_RIPPLE_COUNT` or `depth` isn't finite.
+pub fn set_voice_envelope_ripple(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    count: u32,
+    depth: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(voice) = ctx.voices.get_mut(voice_ix) else {
+        return false;
+    };
+    if count > MAX_RIPPLE_COUNT || !depth.is_finite() {
+        return false;
+    }
+    let depth = clamp(0., 1., depth);
+    voice.envelope_mut().ripple = (count > 0 && depth > 0.).then_some(Ripple { count, depth });
+    true
+}
+
+/// Makes a voice's grain envelopes a crossfade between two window shapes, set by the voice's
+/// `EnvelopeMorph` parameter and modulation: 0 = sine, 1 = Hann, 2 = Gaussian, 3 = triangle,
+/// 4 = trapezoid, 5 = rectangle.  This replaces the voice's other shape settings, though ripples
+/// and skew still apply.  Returns false for unknown shapes.
+pub fn set_voice_envelope_morph_shapes(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    from_shape: u32,
+    to_shape: u32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(voice) = ctx.voices.get_mut(voice_ix) else {
+        return false;
+    };
+    let (Some(from), Some(to)) = (
+        WindowShape::from_index(from_shape),
+        WindowShape::from_index(to_shape),
+    ) else {
+        return false;
+    };
+    voice.envelope_mut().morph = Some((from, to));
+    true
+}
+
+/// Goes back to the voice's other envelope shape settings after `set_voice_envelope_morph_shapes`
+pub fn clear_voice_envelope_morph_shapes(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.envelope_mut().morph = None;
+    }
+}
+
+/// Sets the position of the crossfade between a voice's morph shapes, from 0 to 1.  The
+/// `EnvelopeMorph` modulation destination is added on top.
+pub fn set_voice_envelope_morph(ctx: *mut GranularCtx, voice_ix: usize, morph: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !morph.is_finite() {
+        return;
+    }
+    ctx.params.set_target(
+        ParamId::Voice(voice_ix, VoiceParam::EnvelopeMorph),
+        clamp(0., 1., morph),
+    );
+}
+
+/// Makes a voice's grains follow the envelope in `table`, read from the start of the grain to its
+/// end, instead of the built-in slope shape.  An empty table goes back to the built-in shape.
+/// Returns false, leaving the envelope unchanged, if the table is longer than
+/// `MAX_ENVELOPE_TABLE_LEN` or contains non-finite values.
+pub fn set_grain_envelope_table(ctx: *mut GranularCtx, voice_ix: usize, table: &[f32]) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(voice) = ctx.voices.get_mut(voice_ix) else {
+        return false;
+    };
+    if table.is_empty() {
+        voice.envelope_mut().table = None;
+        return true;
+    }
+    let Some(table) = EnvelopeTable::new(table) else {
+        return false;
+    };
+    voice.envelope_mut().table = Some(table);
+    true
+}
+
+/// Gives a voice's grains separate attack and release slopes instead of the symmetric ones set
+/// by `linear_slope_length` and `slope_linearity`.  Lengths are fractions of the grain and
+/// linearities mix between a quarter sine (0) and a straight line (1); all are clamped to 0..1.
+/// Returns false if any of them isn't finite.
+pub fn set_voice_grain_slopes(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    attack_length: f32,
+    attack_linearity: f32,
+    release_length: f32,
+    release_linearity: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(voice) = ctx.voices.get_mut(voice_ix) else {
+        return false;
+    };
+    if [
+        attack_length,
+        attack_linearity,
+        release_length,
+        release_linearity,
+    ]
+    .iter()
+    .any(|value| !value.is_finite())
+    {
+        return false;
+    }
+    voice.envelope_mut().slopes = Some(EnvelopeSlopes {
+        attack: Slope::new(attack_length, attack_linearity),
+        release: Slope::new(release_length, release_linearity),
+    });
+    true
+}
+
+/// Sets the curves of a voice's attack and release slopes: 0 = the sine/linear blend set by the
+/// slope linearity, 1 = linear, 2 = raised cosine, 3 = exponential, 4 = logarithmic.  Applies to
+/// the voice's own slopes if it has any and to the global symmetric ones otherwise.  Returns
+/// false for unknown curves.
+pub fn set_voice_slope_curves(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    attack_curve: u32,
+    release_curve: u32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(voice) = ctx.voices.get_mut(voice_ix) else {
+        return false;
+    };
+    let (Some(attack), Some(release)) = (
+        SlopeCurve::from_index(attack_curve),
+        SlopeCurve::from_index(release_curve),
+    ) else {
+        return false;
+    };
+    voice.envelope_mut().curves = SlopeCurves { attack, release };
+    true
+}
+
+/// Gives a voice its own slope length and linearity, used instead of the global
+/// `linear_slope_length` and `slope_linearity` for the voice's built-in envelope shape and the
+/// curves set by `set_voice_slope_curves`.  Values are clamped to 0..1 and smoothed like the
+/// global ones.  Returns false if either isn't finite.
+pub fn set_voice_slope_shape(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    slope_length: f32,
+    slope_linearity: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT || !slope_length.is_finite() || !slope_linearity.is_finite() {
+        return false;
+    }
+    for (param, value) in [
+        (VoiceParam::SlopeLength, slope_length),
+        (VoiceParam::SlopeLinearity, slope_linearity),
+    ] {
+        ctx.params
+            .set_target(ParamId::Voice(voice_ix, param), clamp(0., 1., value));
+    }
+    ctx.voices[voice_ix].envelope_mut().own_slope_shape = true;
+    true
+}
+
+/// Makes a voice follow the global `linear_slope_length` and `slope_linearity` again
+pub fn clear_voice_slope_shape(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.envelope_mut().own_slope_shape = false;
+    }
+}
+
+/// Makes a voice's grains use the symmetric slopes set by the global parameters again
+pub fn clear_voice_grain_slopes(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.envelope_mut().slopes = None;
+    }
+}
+
+#[test]
+fn envelope_tables_are_interpolated() {
+    let table = EnvelopeTable::new(&[0., 1., 0.5]).unwrap();
+    assert_eq!(table.gain_at(0.), 0.);
+    assert_eq!(table.gain_at(0.25), 0.5);
+    assert_eq!(table.gain_at(0.5), 1.);
+    assert_eq!(table.gain_at(1.), 0.5);
+    assert_eq!(EnvelopeTable::new(&[2.]).unwrap().gain_at(0.3), 1.);
+
+    assert!(EnvelopeTable::new(&[]).is_none());
+    assert!(EnvelopeTable::new(&[0., f32::NAN]).is_none());
+}
+
+#[test]
+fn attack_and_release_slopes_are_independent() {
+    let slopes = EnvelopeSlopes {
+        attack: Slope::new(0.1, 0.),
+        release: Slope::new(0.6, 0.),
+    };
+    let curves = SlopeCurves::default();
+    assert_eq!(slopes.gain_at(0., curves), 0.);
+    assert!((slopes.gain_at(0.05, curves) - 0.5).abs() < 1e-6);
+    assert_eq!(slopes.gain_at(0.2, curves), 1.);
+    assert!((slopes.gain_at(0.7, curves) - 0.5).abs() < 1e-6);
+    assert_eq!(slopes.gain_at(1., curves), 0.);
+
+    // Overlapping slopes are scaled down to meet at the peak
+    let slopes = EnvelopeSlopes {
+        attack: Slope::new(1., 0.),
+        release: Slope::new(1., 0.),
+    };
+    assert!((slopes.gain_at(0.5, curves) - 1.).abs() < 1e-6);
+    assert!((slopes.gain_at(0.25, curves) - 0.5).abs() < 1e-6);
+}
+
+#[test]
+fn slope_curves_rise_from_silence_to_full_level() {
+    let slope = Slope::new(1., 0.5);
+    for ix in 0..5 {
+        let curve = SlopeCurve::from_index(ix).unwrap();
+        assert!(slope.gain_at(0., curve).abs() < 1e-6, "{:?}", curve);
+        assert!((slope.gain_at(1., curve) - 1.).abs() < 1e-6, "{:?}", curve);
+    }
+    assert!((slope.gain_at(0.5, SlopeCurve::RaisedCosine) - 0.5).abs() < 1e-6);
+    assert!(slope.gain_at(0.5, SlopeCurve::Exponential) < 0.5);
+    assert!(slope.gain_at(0.5, SlopeCurve::Logarithmic) > 0.5);
+    assert!(SlopeCurve::from_index(5).is_none());
+}
+
+#[test]
+fn skew_moves_the_envelope_peak() {
+    assert_eq!(skew_position(0.3, 0.), 0.3);
+    assert_eq!(skew_position(0.25, 0.), 0.25);
+    assert!((skew_position(0.25, -0.5) - 0.5).abs() < 1e-6);
+    assert!((skew_position(0.75, 0.5) - 0.5).abs() < 1e-6);
+    for skew in [-1., -0.3, 0.7, 1.] {
+        assert_eq!(skew_position(0., skew), 0.);
+        assert!((skew_position(1., skew) - 1.).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn click_guard_fades_grain_edges() {
+    assert_eq!(click_guard_gain(0., 1000.), 0.);
+    assert_eq!(click_guard_gain(CLICK_GUARD_SAMPLES / 2., 1000.), 0.5);
+    assert_eq!(click_guard_gain(500., 500.), 1.);
+    assert_eq!(click_guard_gain(1000., 1.), 1. / CLICK_GUARD_SAMPLES);
+}
+
+/// Slewed gain of the inverse of the amplitude a voice's grains are expected to add up to, for
+/// the voice's `OverlapCompensation` parameter.  It follows only the envelope shape, grain
+/// duration and spacing, so unlike dividing by the sum of the gains of the grains playing it
+/// doesn't move from sample to sample as grains start and end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OverlapCompensation {
+    target: f32,
+    gain: f32,
+}
+
+impl Default for OverlapCompensation {
+    fn default() -> Self {
+        OverlapCompensation {
+            target: 1.,
+            gain: 1.,
+        }
+    }
+}
+
+impl OverlapCompensation {
+    /// Updates the target gain from the number of grains overlapping on average and the mean
+    /// gain of their envelope.  Like `DensityCompensation`, sparse grains aren't boosted.
+    pub fn set_overlap(&mut self, overlap: f32, mean_gain: f32) {
+        let summed_gain = overlap * mean_gain;
+        self.target = if summed_gain.is_finite() && summed_gain > 1. {
+            1. / summed_gain
+        } else {
+            1.
+        };
+    }
+
+    #[inline]
+    pub fn tick(&mut self) -> f32 {
+        smooth(&mut self.gain, self.target, COMPENSATION_SMOOTHING);
+        self.gain
+    }
+}
+
+#[test]
+fn density_compensation_follows_summed_energy() {
+    let energy = envelope_energy(|_| 1.);
+    assert!((energy - 1.).abs() < 1e-6);
+    let sine_energy = envelope_energy(|pos| (pos * PI).sin());
+    assert!((sine_energy - 0.5).abs() < 1e-3);
+
+    let mut compensation = DensityCompensation::default();
+    compensation.set_overlap(8., 0.5);
+    for _ in 0..20000 {
+        compensation.tick();
+    }
+    assert!((compensation.tick() - 0.5).abs() < 1e-3);
+    compensation.set_overlap(0.5, 1.);
+    for _ in 0..20000 {
+        compensation.tick();
+    }
+    assert!((compensation.tick() - 1.).abs() < 1e-3);
+}
+
+#[test]
+fn overlap_compensation_follows_the_summed_amplitude() {
+    let sine_mean = envelope_mean_gain(|pos| (pos * PI).sin());
+    assert!((sine_mean - 2. / PI).abs() < 1e-3);
+
+    let mut compensation = OverlapCompensation::default();
+    compensation.set_overlap(8., 0.5);
+    for _ in 0..20000 {
+        compensation.tick();
+    }
+    assert!((compensation.tick() - 0.25).abs() < 1e-3);
+    compensation.set_overlap(f32::INFINITY, 0.5);
+    for _ in 0..20000 {
+        compensation.tick();
+    }
+    assert!((compensation.tick() - 1.).abs() < 1e-3);
+}
+
+#[test]
+fn ripples_split_grains_into_bumps() {
+    let ripple = Ripple {
+        count: 3,
+        depth: 1.,
+    };
+    assert!(ripple.gain_at(0.).abs() < 1e-6);
+    assert!((ripple.gain_at(1. / 6.) - 1.).abs() < 1e-6);
+    assert!(ripple.gain_at(1. / 3.).abs() < 1e-6);
+    assert!((ripple.gain_at(0.5) - 1.).abs() < 1e-6);
+
+    let shallow = Ripple {
+        count: 3,
+        depth: 0.25,
+    };
+    assert!((shallow.gain_at(1. / 3.) - 0.75).abs() < 1e-6);
+}
+
+#[test]
+fn window_shapes_peak_in_the_center() {
+    for ix in 0..5 {
+        let shape = WindowShape::from_index(ix).unwrap();
+        assert!(shape.gain_at(0.).abs() < 1e-6, "{:?}", shape);
+        assert!(shape.gain_at(1.).abs() < 1e-6, "{:?}", shape);
+        assert!((shape.gain_at(0.5) - 1.).abs() < 1e-6, "{:?}", shape);
+    }
+    assert_eq!(WindowShape::Rectangle.gain_at(0.), 1.);
+    assert!(WindowShape::from_index(6).is_none());
+
+    let envelope = VoiceEnvelope {
+        morph: Some((WindowShape::Triangle, WindowShape::Rectangle)),
+        ..Default::default()
+    };
+    let params = EnvelopeParams {
+        linear_slope_length: 0.5,
+        slope_linearity: 0.5,
+        skew: 0.,
+        morph: 0.5,
+    };
+    assert_eq!(envelope.gain_at(0.25, params), Some(0.75));
+}
+
+#[test]
+fn voices_can_have_their_own_slope_shape() {
+    let mut ctx = GranularCtx::default();
+    assert!(!set_voice_slope_shape(&mut ctx, 0, f32::NAN, 1.));
+    assert!(!set_voice_slope_shape(&mut ctx, VOICE_COUNT, 0., 1.));
+    assert!(set_voice_slope_shape(&mut ctx, 1, 2., 1.));
+    assert!(ctx.voices[1].envelope.own_slope_shape);
+    assert!(!ctx.voices[0].envelope.own_slope_shape);
+    let length = ParamId::Voice(1, VoiceParam::SlopeLength);
+    assert_eq!(ctx.params.target().get(length), 1.);
+    clear_voice_slope_shape(&mut ctx, 1);
+    assert!(!ctx.voices[1].envelope.own_slope_shape);
+}
@@ -0,0 +1,144 @@
+//! Three-band EQ on the master output: a low shelf, a peak in the mids and a high shelf, each
+//! with its own frequency and gain, for shaping the tone of a patch inside the engine
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::filters::biquad::{Biquad, BiquadCoefficients};
+
+/// Width of the mid peak, about two octaves
+const MID_Q: f32 = 0.7;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EqSettings {
+    pub low_hz: f32,
+    pub low_db: f32,
+    pub mid_hz: f32,
+    pub mid_db: f32,
+    pub high_hz: f32,
+    pub high_db: f32,
+}
+
+#[derive(Clone, Copy)]
+pub struct MasterEq {
+    pub settings: EqSettings,
+    /// Low, mid and high bands of the left, right and mono channels
+    bands: [[Biquad; 3]; 3],
+}
+
+impl MasterEq {
+    pub fn new(settings: EqSettings, sample_rate: f32) -> Self {
+        MasterEq {
+            settings,
+            bands: [Self::coefficients(&settings, sample_rate).map(Biquad::new); 3],
+        }
+    }
+
+    /// Changes the settings without clearing the filters
+    pub fn set(&mut self, settings: EqSettings, sample_rate: f32) {
+        self.settings = settings;
+        let coefficients = Self::coefficients(&settings, sample_rate);
+        for channel in &mut self.bands {
+            for (band, coefficients) in channel.iter_mut().zip(coefficients) {
+                band.coefficients = coefficients;
+            }
+        }
+    }
+
+    fn coefficients(settings: &EqSettings, sample_rate: f32) -> [BiquadCoefficients; 3] {
+        [
+            BiquadCoefficients::low_shelf(settings.low_hz, settings.low_db, sample_rate),
+            BiquadCoefficients::peaking(settings.mid_hz, settings.mid_db, MID_Q, sample_rate),
+            BiquadCoefficients::high_shelf(settings.high_hz, settings.high_db, sample_rate),
+        ]
+    }
+
+    /// Equalizes the left and right channels and the mono mix
+    pub fn process(&mut self, samples: [f32; 3]) -> [f32; 3] {
+        let mut output = samples;
+        for (sample, channel) in output.iter_mut().zip(&mut self.bands) {
+            for band in channel {
+                *sample = band.process(*sample);
+            }
+        }
+        output
+    }
+}
+
+/// Sets up the three-band EQ on the master output: a low shelf at `low_hz` (20 Hz to 1 kHz), a
+/// two-octave peak at `mid_hz` (100 Hz to 10 kHz) and a high shelf at `high_hz` (1 to 20 kHz),
+/// with gains from -18 to 18 dB.  Changing the settings of an EQ that's on doesn't clear its
+/// filters.
+#[allow(clippy::too_many_arguments)]
+pub fn set_master_eq(
+    ctx: *mut GranularCtx,
+    low_hz: f32,
+    low_db: f32,
+    mid_hz: f32,
+    mid_db: f32,
+    high_hz: f32,
+    high_db: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let values = [low_hz, low_db, mid_hz, mid_db, high_hz, high_db];
+    if !values.iter().all(|value| value.is_finite()) {
+        return;
+    }
+    let settings = EqSettings {
+        low_hz: clamp(20., 1000., low_hz),
+        low_db: clamp(-18., 18., low_db),
+        mid_hz: clamp(100., 10000., mid_hz),
+        mid_db: clamp(-18., 18., mid_db),
+        high_hz: clamp(1000., 20000., high_hz),
+        high_db: clamp(-18., 18., high_db),
+    };
+    let sample_rate = ctx.sample_rate;
+    match &mut ctx.master_eq {
+        Some(eq) => eq.set(settings, sample_rate),
+        eq => *eq = Some(MasterEq::new(settings, sample_rate)),
+    }
+}
+
+/// Removes the master output's EQ
+pub fn clear_master_eq(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.master_eq = None;
+    }
+}
+
+#[test]
+fn master_eq_shapes_the_output() {
+    use super::params::{GlobalParam, ParamId};
+    use super::DEFAULT_SAMPLE_RATE;
+
+    let period = DEFAULT_SAMPLE_RATE / 100.;
+    let mut ctx = GranularCtx {
+        waveform: (0..44100)
+            .map(|i| (std::f32::consts::TAU * i as f32 / period).sin())
+            .collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    let targets = *ctx.params.target();
+    let level = |ctx: &mut GranularCtx| {
+        let mut sum = 0.;
+        for frame_ix in 0..128 {
+            ctx.render(&targets);
+            if frame_ix >= 64 {
+                sum += ctx.rendered_output.iter().map(|s| s * s).sum::<f32>();
+            }
+        }
+        sum
+    };
+    let flat = level(&mut ctx);
+    // Cutting the lows by 12 dB leaves a sixteenth of the energy of a 100 Hz tone
+    set_master_eq(&mut ctx, 500., -12., 1000., 0., 5000., 0.);
+    let cut = level(&mut ctx);
+    assert!(cut < flat * 0.1, "{} {}", cut, flat);
+    set_master_eq(&mut ctx, 500., 0., 1000., 0., 5000., f32::NAN);
+    assert_eq!(ctx.master_eq.unwrap().settings.low_db, -12.);
+    clear_master_eq(&mut ctx);
+    assert!(ctx.master_eq.is_none());
+}
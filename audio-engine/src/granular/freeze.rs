@@ -0,0 +1,142 @@
+//! Voice freeze.  Freezing a voice captures the next stretch of its granulated output, before its
+//! gain and pan, and then loops that instead of granulating live, which frees the CPU the voice's
+//! grains took.  The end of the capture is crossfaded into its start so that the loop is seamless.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Crossfade at the loop point of `VoiceFreeze::new`; shorter loops use a quarter of their length
+const CROSSFADE_MS: f32 = 10.;
+
+#[derive(Clone, Debug)]
+enum State {
+    /// Collecting the loop plus the crossfade after it
+    Capturing {
+        samples: Vec<(f32, f32)>,
+        loop_len: usize,
+        fade_len: usize,
+    },
+    Playing {
+        samples: Vec<(f32, f32)>,
+        pos: usize,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct VoiceFreeze {
+    state: State,
+}
+
+impl VoiceFreeze {
+    pub fn new(loop_ms: f32, sample_rate: f32) -> Self {
+        Self::with_crossfade(loop_ms, CROSSFADE_MS, sample_rate)
+    }
+
+    /// Freeze with a crossfade of up to `crossfade_ms` at the loop point, for loops that should
+    /// blur into themselves rather than just not click.  The crossfade is at most a quarter of the
+    /// loop.
+    pub fn with_crossfade(loop_ms: f32, crossfade_ms: f32, sample_rate: f32) -> Self {
+        let loop_len = ((loop_ms * sample_rate / 1000.) as usize).max(1);
+        let fade_len = ((crossfade_ms * sample_rate / 1000.) as usize).min(loop_len / 4);
+        VoiceFreeze {
+            state: State::Capturing {
+                samples: Vec::with_capacity(loop_len + fade_len),
+                loop_len,
+                fade_len,
+            },
+        }
+    }
+
+    /// Whether the capture is done and the loop is playing
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, State::Playing { .. })
+    }
+
+    /// Adds a sample of the voice's live output to the capture.  Returns true once the capture is
+    /// complete, after which the loop plays.
+    pub fn capture(&mut self, sample: (f32, f32)) -> bool {
+        let State::Capturing {
+            samples,
+            loop_len,
+            fade_len,
+        } = &mut self.state
+        else {
+            return false;
+        };
+        let (loop_len, fade_len) = (*loop_len, *fade_len);
+        samples.push(sample);
+        if samples.len() < loop_len + fade_len {
+            return false;
+        }
+
+        let mut samples = std::mem::take(samples);
+        for ix in 0..fade_len {
+            let fade_in = ix as f32 / fade_len as f32;
+            let (tail_left, tail_right) = samples[loop_len + ix];
+            let (left, right) = &mut samples[ix];
+            *left = *left * fade_in + tail_left * (1. - fade_in);
+            *right = *right * fade_in + tail_right * (1. - fade_in);
+        }
+        samples.truncate(loop_len);
+        self.state = State::Playing { samples, pos: 0 };
+        true
+    }
+
+    /// Next sample of the loop, or `None` while it's still being captured
+    #[inline]
+    pub fn next_sample(&mut self) -> Option<(f32, f32)> {
+        let State::Playing { samples, pos } = &mut self.state else {
+            return None;
+        };
+        let sample = samples[*pos];
+        *pos = (*pos + 1) % samples.len();
+        Some(sample)
+    }
+}
+
+/// Freezes a voice: its next `loop_ms` of output are captured and then looped in place of live
+/// granulation until `unfreeze_voice`.  The voice's gain and pan keep applying to the loop.
+pub fn freeze_voice(ctx: *mut GranularCtx, voice_ix: usize, loop_ms: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !loop_ms.is_finite() {
+        return;
+    }
+    ctx.voices[voice_ix].freeze = Some(VoiceFreeze::new(
+        clamp(1., 60000., loop_ms),
+        ctx.sample_rate,
+    ));
+}
+
+/// Goes back to granulating the voice live, picking up from where its read head was
+pub fn unfreeze_voice(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix < VOICE_COUNT {
+        ctx.voices[voice_ix].freeze = None;
+    }
+}
+
+/// Whether a voice is frozen and its capture is complete, so it's looping
+pub fn is_voice_frozen(ctx: *mut GranularCtx, voice_ix: usize) -> bool {
+    ctx_mut(ctx)
+        .and_then(|ctx| ctx.voices.get(voice_ix))
+        .and_then(|voice| voice.freeze.as_ref())
+        .is_some_and(VoiceFreeze::is_playing)
+}
+
+#[test]
+fn frozen_loops_crossfade_their_ends() {
+    // 8 samples at 1 kHz, with a 2 sample crossfade
+    let mut freeze = VoiceFreeze::new(8., 1000.);
+    for ix in 0..9 {
+        assert!(!freeze.capture((ix as f32, 0.)));
+        assert_eq!(freeze.next_sample(), None);
+    }
+    assert!(freeze.capture((9., 0.)));
+    let looped: Vec<f32> = (0..10).map(|_| freeze.next_sample().unwrap().0).collect();
+    assert_eq!(looped, vec![8., 5., 2., 3., 4., 5., 6., 7., 8., 5.]);
+}
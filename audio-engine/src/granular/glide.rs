@@ -0,0 +1,151 @@
+//! Grain portamento.  A voice with a glide time starts each grain it spawns at the pitch of the
+//! grain before it and slides the grain's playback ratio to its own pitch over that time, so
+//! random or sequenced jumps in pitch turn into swoops like a tape speeding up or slowing down.
+//! The slide is exponential, which moves through each semitone at the same rate.  Grains shorter
+//! than the glide time end before they reach their pitch.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Longest glide time accepted
+pub const MAX_GLIDE_MS: f32 = 10000.;
+
+/// A voice's glide between the pitches of successive grains
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GrainGlide {
+    time_ms: f32,
+    samples: f32,
+    /// Ratio of the last grain spawned, before chord intervals and pitch snapping
+    last_ratio: Option<f32>,
+}
+
+impl GrainGlide {
+    pub fn new(time_ms: f32, sample_rate: f32) -> Self {
+        GrainGlide {
+            time_ms,
+            samples: time_ms * sample_rate / 1000.,
+            last_ratio: None,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.samples = self.time_ms * sample_rate / 1000.;
+    }
+
+    /// Output samples a grain takes to reach its pitch
+    pub fn samples(&self) -> f32 {
+        self.samples
+    }
+
+    /// Ratio a grain spawned at `ratio` starts from, or None for the first grain and grains at
+    /// the same pitch as the last
+    pub fn next_grain(&mut self, ratio: f32) -> Option<f32> {
+        self.last_ratio
+            .replace(ratio)
+            .filter(|&last_ratio| last_ratio != ratio)
+    }
+}
+
+/// Slide of a grain's playback ratio towards its target
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Glide {
+    target: f32,
+    /// Factor the ratio is multiplied by on each sample
+    step: f32,
+    samples_left: u32,
+}
+
+impl Glide {
+    /// Returns None if the ratios are the same or the slide is shorter than a sample
+    pub fn new(from: f32, to: f32, samples: f32) -> Option<Self> {
+        if from == to || samples < 1. {
+            return None;
+        }
+        Some(Glide {
+            target: to,
+            step: (to / from).powf(samples.recip()),
+            samples_left: samples as u32,
+        })
+    }
+
+    /// Moves `ratio` on by a sample, returning false once it's reached the target
+    pub fn tick(&mut self, ratio: &mut f32) -> bool {
+        self.samples_left = self.samples_left.saturating_sub(1);
+        if self.samples_left == 0 {
+            *ratio = self.target;
+            return false;
+        }
+        *ratio *= self.step;
+        true
+    }
+}
+
+/// Slides each grain a voice spawns from the pitch of the grain before it to its own over
+/// `glide_ms` milliseconds, clamped to `MAX_GLIDE_MS`.  A value of 0 turns the glide off.
+pub fn set_voice_grain_glide(ctx: *mut GranularCtx, voice_ix: usize, glide_ms: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !glide_ms.is_finite() {
+        return;
+    }
+    ctx.voices[voice_ix].grain_glide = (glide_ms > 0.)
+        .then(|| GrainGlide::new(clamp(0., MAX_GLIDE_MS, glide_ms), ctx.sample_rate));
+}
+
+#[test]
+fn grains_slide_from_the_last_pitch() {
+    let mut grain_glide = GrainGlide::new(10., 1000.);
+    assert_eq!(grain_glide.samples(), 10.);
+    assert_eq!(grain_glide.next_grain(1.), None);
+    assert_eq!(grain_glide.next_grain(1.), None);
+    assert_eq!(grain_glide.next_grain(2.), Some(1.));
+    grain_glide.set_sample_rate(2000.);
+    assert_eq!(grain_glide.samples(), 20.);
+
+    assert_eq!(Glide::new(1., 1., 10.), None);
+    let mut glide = Glide::new(1., 2., 10.).unwrap();
+    let mut ratio = 1.;
+    assert!(glide.tick(&mut ratio));
+    assert!((ratio - 2f32.powf(0.1)).abs() < 1e-5);
+    let mut samples = 1;
+    while glide.tick(&mut ratio) {
+        samples += 1;
+    }
+    assert_eq!(samples, 9);
+    assert_eq!(ratio, 2.);
+}
+
+#[test]
+fn gliding_grains_start_at_the_last_grains_pitch() {
+    use super::arpeggio::set_voice_arpeggio;
+    use super::params::{GlobalParam, ParamId};
+    use super::{sine_ctx, spawn_events};
+
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    assert!(set_voice_arpeggio(&mut ctx, 0, &[0., 12.], 0.));
+    set_voice_grain_glide(&mut ctx, 0, 1000.);
+    ctx.grain_trace.set_enabled(true);
+    let targets = *ctx.params.target();
+    for _ in 0..256 {
+        ctx.render(&targets);
+    }
+    let ratios: Vec<f32> = spawn_events(&mut ctx, 0)
+        .iter()
+        .map(|event| event.sample_playback_ratio)
+        .collect();
+    assert!(ratios.len() >= 3);
+    assert_eq!(ratios[..3], [1., 1., 2.]);
+    assert!(ctx.voices[0]
+        .grains
+        .iter()
+        .any(|grain| grain.glide.is_some()
+            && grain.sample_playback_ratio > 1.
+            && grain.sample_playback_ratio < 2.));
+
+    set_voice_grain_glide(&mut ctx, 0, 0.);
+    assert!(ctx.voices[0].grain_glide.is_none());
+}
@@ -0,0 +1,370 @@
+//! Storage for a voice's playing grains.  The grains are kept as a structure of arrays, one
+//! column per field of `Grain`, rather than as a list of `Grain`s.  The loops that run for every
+//! grain on every sample then walk contiguous arrays of only the fields they need.  Work that's
+//! the same arithmetic for every grain, like moving read positions on, runs over whole columns,
+//! where the compiler can vectorize it across grains.
+//!
+//! Grains go in and come out as `Grain` values, in the same order a list of them would keep, so
+//! that anything outside the hot loops can go on working with whole grains.
+
+use std::ops::Range;
+
+use super::envelope::{click_guard_gain, EnvelopeParams, VoiceEnvelope};
+use super::glide::Glide;
+use super::slots::SampleSlots;
+use super::tilt::GrainTilt;
+use super::waveform::{MidSideChannel, WaveformChannels};
+use super::window::GrainWindow;
+use super::Grain;
+
+/// What's the same for every grain of a voice on a sample
+pub struct GrainMix<'a> {
+    /// Buffers and gain of grains reading the current waveform
+    pub current: (WaveformChannels<'a>, f32),
+    /// Buffers and gain of grains spawned before the last swap
+    pub retired: (WaveformChannels<'a>, f32),
+    /// Slots that grains with a slot read instead
+    pub slots: &'a SampleSlots,
+    pub envelope: &'a VoiceEnvelope,
+    pub envelope_params: EnvelopeParams,
+    /// The voice's window table, if it's up to date for `envelope_params`
+    pub window: Option<&'a GrainWindow>,
+    pub click_guard: bool,
+    pub voice_reversed: bool,
+    pub mid_side: Option<MidSideChannel>,
+}
+
+/// A voice's grains summed over one sample
+#[derive(Clone, Copy, Default)]
+pub struct GrainSums {
+    pub left: f32,
+    pub right: f32,
+    /// Sum of the grains' gains
+    pub gain: f32,
+    /// Sum of the grains' depths weighted by their gains
+    pub depth: f32,
+}
+
+macro_rules! grain_columns {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        #[derive(Clone, Default)]
+        pub struct Grains {
+            $($field: Vec<$ty>,)*
+            /// Working space for `mix`, holding each grain's gain and read position
+            gains: Vec<f32>,
+            positions: Vec<f32>,
+        }
+
+        impl Grains {
+            pub fn with_capacity(capacity: usize) -> Self {
+                Grains {
+                    $($field: Vec::with_capacity(capacity),)*
+                    gains: Vec::with_capacity(capacity),
+                    positions: Vec::with_capacity(capacity),
+                }
+            }
+
+            pub fn push(&mut self, grain: Grain) {
+                $(self.$field.push(grain.$field);)*
+            }
+
+            pub fn get(&self, ix: usize) -> Option<Grain> {
+                (ix < self.len()).then(|| Grain {
+                    $($field: self.$field[ix].clone(),)*
+                })
+            }
+
+            fn set(&mut self, ix: usize, grain: Grain) {
+                $(self.$field[ix] = grain.$field;)*
+            }
+
+            /// Removes the grain at `ix`, moving the last grain into its place
+            pub fn swap_remove(&mut self, ix: usize) -> Grain {
+                Grain {
+                    $($field: self.$field.swap_remove(ix),)*
+                }
+            }
+
+            fn truncate(&mut self, len: usize) {
+                $(self.$field.truncate(len);)*
+            }
+        }
+    };
+}
+
+grain_columns! {
+    len_samples: f32,
+    start_sample_ix: f32,
+    samples_read_so_far: f32,
+    sample_playback_ratio: f32,
+    gain: f32,
+    retired: bool,
+    reversed: bool,
+    wrap: Option<Range<usize>>,
+    depth: f32,
+    glide: Option<Glide>,
+    tilt: Option<GrainTilt>,
+    slot: Option<u32>,
+}
+
+impl Grains {
+    pub fn len(&self) -> usize {
+        self.len_samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn last(&self) -> Option<Grain> {
+        self.get(self.len().checked_sub(1)?)
+    }
+
+    pub fn iter(&self) -> GrainIter<'_> {
+        GrainIter {
+            grains: self,
+            ix: 0,
+        }
+    }
+
+    /// Keeps the grains `keep` returns true for, in order, along with its changes to them
+    pub fn retain_mut(&mut self, mut keep: impl FnMut(&mut Grain) -> bool) {
+        let mut kept = 0;
+        for ix in 0..self.len() {
+            let Some(mut grain) = self.get(ix) else {
+                break;
+            };
+            if keep(&mut grain) {
+                self.set(kept, grain);
+                kept += 1;
+            }
+        }
+        self.truncate(kept);
+    }
+
+    /// Marks every grain as reading the retired waveform
+    pub fn retire_all(&mut self) {
+        self.retired.fill(true);
+    }
+
+    /// Advances every grain by a sample like `Grain::tick` and removes the ones that have played
+    /// out, handing each to `on_end`
+    pub fn tick(&mut self, mut on_end: impl FnMut(Grain)) {
+        for (read, ratio) in self
+            .samples_read_so_far
+            .iter_mut()
+            .zip(&self.sample_playback_ratio)
+        {
+            *read += ratio;
+        }
+        for (glide, ratio) in self.glide.iter_mut().zip(&mut self.sample_playback_ratio) {
+            if let Some(active) = glide {
+                if !active.tick(ratio) {
+                    *glide = None;
+                }
+            }
+        }
+        // The same order of removals as ticking and removing the grains one at a time
+        let mut ix = 0;
+        while ix < self.len() {
+            if self.samples_read_so_far[ix] < self.len_samples[ix] {
+                ix += 1;
+            } else {
+                on_end(self.swap_remove(ix));
+            }
+        }
+    }
+
+    /// Plays the next sample of every grain and sums them.  The envelope gains and read
+    /// positions of all the grains are worked out first, in passes over the columns that don't
+    /// depend on the buffers, and only then are the grains read.
+    pub fn mix(&mut self, mix: &GrainMix) -> GrainSums {
+        let len = self.len();
+        self.gains.resize(len, 0.);
+        self.positions.resize(len, 0.);
+        // Slicing every column to the same length lets the compiler drop the bounds checks below
+        let gains = &mut self.gains[..len];
+        let positions = &mut self.positions[..len];
+        let len_samples = &self.len_samples[..len];
+        let samples_read_so_far = &self.samples_read_so_far[..len];
+        let start_sample_ix = &self.start_sample_ix[..len];
+        let reversed = &self.reversed[..len];
+
+        for ix in 0..len {
+            let (len_samples, read) = (len_samples[ix], samples_read_so_far[ix]);
+            gains[ix] = read / len_samples;
+            positions[ix] = start_sample_ix[ix]
+                + if reversed[ix] != mix.voice_reversed {
+                    len_samples - read
+                } else {
+                    read
+                };
+        }
+        match mix.window {
+            Some(window) => {
+                for gain in gains.iter_mut() {
+                    *gain = window.gain_at(*gain);
+                }
+            }
+            None => {
+                for gain in gains.iter_mut() {
+                    *gain = Grain::envelope_gain(*gain, mix.envelope, mix.envelope_params);
+                }
+            }
+        }
+        if mix.click_guard {
+            let sample_playback_ratio = &self.sample_playback_ratio[..len];
+            for ix in 0..len {
+                let (read, ratio) = (samples_read_so_far[ix], sample_playback_ratio[ix]);
+                gains[ix] *= click_guard_gain(read / ratio, (len_samples[ix] - read) / ratio);
+            }
+        }
+        let grain_gain = &self.gain[..len];
+        let retired = &self.retired[..len];
+        let fades = [mix.current.1, mix.retired.1];
+        for ix in 0..len {
+            gains[ix] *= fades[retired[ix] as usize] * grain_gain[ix];
+        }
+
+        let wrap = &self.wrap[..len];
+        let depth = &self.depth[..len];
+        let tilt = &mut self.tilt[..len];
+        let slot = &self.slot[..len];
+        let mut sums = GrainSums::default();
+        for ix in 0..len {
+            let channels = match slot[ix] {
+                Some(slot_id) => mix.slots.channels(slot_id),
+                None if retired[ix] => mix.retired.0,
+                None => mix.current.0,
+            };
+            let (gain, position, wrap) = (gains[ix], positions[ix], wrap[ix].as_ref());
+            let left = Grain::read_at(channels.left, wrap, position);
+            let (left, right) = match channels.right {
+                Some(right) => {
+                    let right = Grain::read_at(right, wrap, position);
+                    match mix.mid_side {
+                        Some(channel) => {
+                            let sample = channel.encode(left, right);
+                            (sample, sample)
+                        }
+                        None => (left, right),
+                    }
+                }
+                None => (left, left),
+            };
+            let (left, right) = match &mut tilt[ix] {
+                Some(tilt) if channels.right.is_some() => tilt.process(left, right),
+                Some(tilt) => {
+                    let sample = tilt.process_mono(left);
+                    (sample, sample)
+                }
+                None => (left, right),
+            };
+            sums.gain += gain;
+            sums.depth += gain * depth[ix];
+            sums.left += left * gain;
+            sums.right += right * gain;
+        }
+        sums
+    }
+}
+
+/// The grains in order, as `Grain` values
+pub struct GrainIter<'a> {
+    grains: &'a Grains,
+    ix: usize,
+}
+
+impl Iterator for GrainIter<'_> {
+    type Item = Grain;
+
+    fn next(&mut self) -> Option<Grain> {
+        let grain = self.grains.get(self.ix)?;
+        self.ix += 1;
+        Some(grain)
+    }
+
+    /// Skips ahead without building the grains skipped over
+    fn nth(&mut self, n: usize) -> Option<Grain> {
+        self.ix = self.ix.saturating_add(n);
+        self.next()
+    }
+}
+
+impl<'a> IntoIterator for &'a Grains {
+    type Item = Grain;
+    type IntoIter = GrainIter<'a>;
+
+    fn into_iter(self) -> GrainIter<'a> {
+        self.iter()
+    }
+}
+
+#[test]
+fn grains_tick_and_come_out_like_a_list_of_grains() {
+    let grain = |len_samples: f32, glide: Option<Glide>| Grain {
+        len_samples,
+        start_sample_ix: len_samples * 10.,
+        samples_read_so_far: 0.,
+        sample_playback_ratio: 1.,
+        gain: 1.,
+        retired: false,
+        reversed: false,
+        wrap: None,
+        depth: 0.,
+        glide,
+        tilt: None,
+        slot: None,
+    };
+    let grains = [
+        grain(2., None),
+        grain(5., Glide::new(2., 1., 3.)),
+        grain(1., None),
+        grain(3., None),
+    ];
+    let mut list = grains.to_vec();
+    let mut columns = Grains::with_capacity(4);
+    for grain in grains {
+        columns.push(grain);
+    }
+
+    let starts = |grains: &[Grain]| -> Vec<f32> {
+        grains.iter().map(|grain| grain.start_sample_ix).collect()
+    };
+    for _ in 0..4 {
+        let mut ended = vec![];
+        columns.tick(|grain| ended.push(grain));
+        let mut list_ended = vec![];
+        let mut ix = 0;
+        while ix < list.len() {
+            if list[ix].tick() {
+                ix += 1;
+            } else {
+                list_ended.push(list.swap_remove(ix));
+            }
+        }
+        assert_eq!(starts(&ended), starts(&list_ended));
+        let remaining: Vec<Grain> = columns.iter().collect();
+        assert_eq!(starts(&remaining), starts(&list));
+        for (grain, listed) in remaining.iter().zip(&list) {
+            assert_eq!(grain.samples_read_so_far, listed.samples_read_so_far);
+            assert_eq!(grain.sample_playback_ratio, listed.sample_playback_ratio);
+            assert_eq!(grain.glide, listed.glide);
+        }
+    }
+    assert_eq!(columns.len(), 1);
+
+    columns.retain_mut(|grain| {
+        grain.depth = 0.5;
+        true
+    });
+    assert_eq!(columns.last().unwrap().depth, 0.5);
+    columns.retire_all();
+    assert!(columns.iter().all(|grain| grain.retired));
+    columns.clear();
+    assert!(columns.is_empty() && columns.get(0).is_none());
+}
@@ -0,0 +1,249 @@
+//! Groove templates.  A groove splits each bar into equal steps and moves the onsets of
+//! tempo-synced grains on each step earlier or later by a fraction of a step and scales their
+//! gain, so the grain rhythm can take on the swing and accents of the host project.  The template
+//! warps the song position rather than each onset, with a step's start moved by its offset and the
+//! time between stretched to fit, so onsets finer than the steps are pushed along with the step
+//! they fall in.  Grooves only apply while the transport plays, since the grid only runs then.
+
+use super::transport::Transport;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Most steps in a groove
+pub const MAX_GROOVE_STEPS: usize = 64;
+/// Furthest a step's onsets can move either way, in steps, which keeps them in order
+pub const MAX_TIMING: f32 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GrooveStep {
+    /// Fraction of a step that onsets on the step are moved later by, or earlier if negative
+    pub timing: f32,
+    pub gain: f32,
+}
+
+/// The step of one bar that a song position falls in, which the next samples' positions usually
+/// fall in too
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct StepSpan {
+    bar_frames: f64,
+    /// Song positions the step starts and ends at, moved by the groove
+    start: f64,
+    end: f64,
+    /// Where the step starts on the straight grid
+    grid_start: f64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Groove {
+    steps: Vec<GrooveStep>,
+    /// Step of the last position unwarped, so that finding the step only takes a scan over the
+    /// steps when the position moves into another one
+    span: Option<StepSpan>,
+}
+
+impl Groove {
+    /// Returns None for no steps or more than `MAX_GROOVE_STEPS`
+    pub fn new(steps: &[GrooveStep]) -> Option<Self> {
+        if steps.is_empty() || steps.len() > MAX_GROOVE_STEPS {
+            return None;
+        }
+        let steps = steps
+            .iter()
+            .map(|step| GrooveStep {
+                timing: step.timing.clamp(-MAX_TIMING, MAX_TIMING),
+                ..*step
+            })
+            .collect();
+        Some(Groove { steps, span: None })
+    }
+
+    /// Position on the straight grid that the groove moves to `frames` into the song
+    fn unwarp(&mut self, frames: f64, bar_frames: f64) -> f64 {
+        let span = match self.span {
+            Some(span)
+                if span.bar_frames == bar_frames && (span.start..span.end).contains(&frames) =>
+            {
+                span
+            }
+            _ => *self.span.insert(self.find_span(frames, bar_frames)),
+        };
+        let through_step = if span.end > span.start {
+            (frames - span.start) / (span.end - span.start)
+        } else {
+            0.
+        };
+        span.grid_start + through_step * bar_frames / self.steps.len() as f64
+    }
+
+    fn find_span(&self, frames: f64, bar_frames: f64) -> StepSpan {
+        let len = self.steps.len();
+        let step_frames = bar_frames / len as f64;
+        let start =
+            |step_ix: usize| (step_ix as f64 + self.steps[step_ix].timing as f64) * step_frames;
+        // Bars are counted from where their first step starts, which the groove can move
+        let first = start(0);
+        let bars = ((frames - first) / bar_frames).floor();
+        let frames_into_bar = frames - bars * bar_frames;
+        let step_ix = (1..len)
+            .rev()
+            .find(|&step_ix| start(step_ix) <= frames_into_bar)
+            .unwrap_or(0);
+        let step_start = start(step_ix);
+        let step_end = if step_ix + 1 < len {
+            start(step_ix + 1)
+        } else {
+            first + bar_frames
+        };
+        let bar_start = bars * bar_frames;
+        StepSpan {
+            bar_frames,
+            start: bar_start + step_start,
+            end: bar_start + step_end,
+            grid_start: (bars * len as f64 + step_ix as f64) * step_frames,
+        }
+    }
+
+    /// Gain of the grid onset falling on the sample being rendered, or None if none does.  Grid
+    /// lines are `interval_frames` apart on the straight grid.
+    pub fn onset(
+        &mut self,
+        transport: &Transport,
+        interval_frames: f64,
+        bar_frames: f64,
+    ) -> Option<f32> {
+        if !transport.playing || interval_frames <= 0. || bar_frames <= 0. {
+            return None;
+        }
+        let position = self.unwarp(transport.position_frames, bar_frames);
+        let previous = self.unwarp(transport.position_frames - 1., bar_frames);
+        if (position / interval_frames).floor() <= (previous / interval_frames).floor() {
+            return None;
+        }
+        let onset = (position / interval_frames).floor() * interval_frames;
+        let step_frames = bar_frames / self.steps.len() as f64;
+        let step_ix = ((onset / step_frames).floor() as i64).rem_euclid(self.steps.len() as i64);
+        Some(self.steps[step_ix as usize].gain)
+    }
+}
+
+/// Applies a groove template to the onsets of tempo-synced grains while the transport plays.  The
+/// bar is split into a step for each value of `timings`, which moves the onsets on the step later
+/// by that fraction of a step, or earlier if negative, clamped to ±`MAX_TIMING`.  The
+/// gain at the same index of `gains` (0 to 2) scales the grains spawned on the step.  Returns
+/// false and changes nothing if the two lists differ in length, are empty or are longer than
+/// `MAX_GROOVE_STEPS`.
+pub fn set_groove(ctx: *mut GranularCtx, timings: &[f32], gains: &[f32]) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if timings.len() != gains.len() || !timings.iter().chain(gains).all(|value| value.is_finite()) {
+        return false;
+    }
+    let steps: Vec<GrooveStep> = timings
+        .iter()
+        .zip(gains)
+        .map(|(&timing, &gain)| GrooveStep {
+            timing,
+            gain: clamp(0., 2., gain),
+        })
+        .collect();
+    let Some(groove) = Groove::new(&steps) else {
+        return false;
+    };
+    ctx.groove = Some(groove);
+    true
+}
+
+/// Goes back to onsets on the straight grid
+pub fn clear_groove(ctx: *mut GranularCtx) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.groove = None;
+}
+
+#[test]
+fn grooves_move_onsets_within_their_step() {
+    assert_eq!(Groove::new(&[]), None);
+
+    // Eighth-note swing, with the offbeats a third of a step late and quieter
+    let straight = GrooveStep {
+        timing: 0.,
+        gain: 1.,
+    };
+    let swung = GrooveStep {
+        timing: 1. / 3.,
+        gain: 0.5,
+    };
+    let steps = [straight, swung].repeat(4);
+    let mut groove = Groove::new(&steps).unwrap();
+    let mut transport = Transport::default();
+    // A bar at 120 BPM and 100 Hz is 200 frames, so eighths are 25 apart
+    let mut onsets = Vec::new();
+    for frame in 0..200 {
+        transport.set(true, 120., frame as f64);
+        if let Some(gain) = groove.onset(&transport, 25., 200.) {
+            onsets.push((frame, gain));
+        }
+    }
+    assert_eq!(
+        onsets,
+        [
+            (0, 1.),
+            (34, 0.5),
+            (50, 1.),
+            (84, 0.5),
+            (100, 1.),
+            (134, 0.5),
+            (150, 1.),
+            (184, 0.5)
+        ]
+    );
+
+    // The cached step gives the same onsets as finding the step afresh, also after a seek back
+    for frame in (0..400).chain(100..150) {
+        transport.set(true, 120., frame as f64);
+        let fresh = Groove::new(&steps).unwrap().onset(&transport, 25., 200.);
+        assert_eq!(groove.onset(&transport, 25., 200.), fresh, "{}", frame);
+    }
+}
+
+#[test]
+fn grooves_delay_and_scale_synced_onsets() {
+    use super::params::{GlobalParam, ParamId};
+    use super::transport::set_transport;
+    use super::{set_voice_grain_note_values, sine_ctx, spawn_events};
+
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    // Eighth notes with every other one half a step late and at half the gain
+    set_voice_grain_note_values(&mut ctx, 0, 0., 1. / 8.);
+    assert!(!set_groove(&mut ctx, &[0.], &[]));
+    assert!(set_groove(
+        &mut ctx,
+        &[0., 0.5].repeat(4),
+        &[1., 0.5].repeat(4)
+    ));
+    set_transport(&mut ctx, true, 120., 0.);
+    ctx.grain_trace.set_enabled(true);
+    let targets = *ctx.params.target();
+    let eighth = 0.25 * ctx.sample_rate;
+    let mut onsets = Vec::new();
+    while onsets.len() < 2 {
+        ctx.render(&targets);
+        onsets.extend(
+            spawn_events(&mut ctx, 0)
+                .iter()
+                .map(|event| event.timestamp_samples),
+        );
+        assert!(ctx.transport.position_frames < 8. * eighth as f64);
+    }
+    assert_eq!(onsets[0], 0);
+    let offbeat = (onsets[1] - onsets[0]) as f32;
+    assert!((offbeat - 1.5 * eighth).abs() <= 1., "{}", offbeat);
+    assert!(ctx.voices[0].grains.iter().any(|grain| grain.gain == 0.5));
+
+    clear_groove(&mut ctx);
+    assert!(ctx.groove.is_none());
+}
@@ -0,0 +1,174 @@
+//! Haas widener on the master output, a cheap alternative to spreading grains across the stereo
+//! field.  One channel is delayed by a few milliseconds, which the ear hears as width rather than
+//! as an echo.  Only the highs of that channel are delayed and its lows stay where they were, so
+//! that summing the output to mono doesn't comb-filter the bass.
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::filters::butterworth::ButterworthFilter;
+
+pub const MAX_DELAY_MS: f32 = 30.;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HaasChannel {
+    Left,
+    Right,
+}
+
+impl HaasChannel {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(HaasChannel::Left),
+            1 => Some(HaasChannel::Right),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct HaasSettings {
+    pub delayed: HaasChannel,
+    pub delay_ms: f32,
+    /// Frequency below which the delayed channel isn't delayed
+    pub highpass_hz: f32,
+}
+
+#[derive(Clone)]
+pub struct HaasWidener {
+    pub settings: HaasSettings,
+    buffer: Vec<f32>,
+    write_ix: usize,
+    /// Highpasses of the delayed and the undelayed signal.  What the undelayed highpass removes
+    /// is the lows that are kept in place.
+    delayed_highpass: ButterworthFilter,
+    dry_highpass: ButterworthFilter,
+}
+
+impl HaasWidener {
+    pub fn new(settings: HaasSettings, sample_rate: f32) -> Self {
+        let mut delayed_highpass = ButterworthFilter::default();
+        delayed_highpass.set_sample_rate(sample_rate);
+        HaasWidener {
+            settings,
+            buffer: vec![0.; (MAX_DELAY_MS * 0.001 * sample_rate) as usize + 1],
+            write_ix: 0,
+            dry_highpass: delayed_highpass.clone(),
+            delayed_highpass,
+        }
+    }
+
+    /// Delays the highs of one of the channels
+    pub fn process(&mut self, left: f32, right: f32, sample_rate: f32) -> (f32, f32) {
+        let input = match self.settings.delayed {
+            HaasChannel::Left => left,
+            HaasChannel::Right => right,
+        };
+        let len = self.buffer.len();
+        let delay = ((self.settings.delay_ms * 0.001 * sample_rate).round() as usize).min(len - 1);
+        self.buffer[self.write_ix] = if input.is_finite() { input } else { 0. };
+        let delayed = self.buffer[(self.write_ix + len - delay) % len];
+        self.write_ix = (self.write_ix + 1) % len;
+
+        let cutoff = self.settings.highpass_hz;
+        let lows = input - self.dry_highpass.highpass(cutoff, input);
+        let output = lows + self.delayed_highpass.highpass(cutoff, delayed);
+        match self.settings.delayed {
+            HaasChannel::Left => (output, right),
+            HaasChannel::Right => (left, output),
+        }
+    }
+}
+
+/// Widens the master output by delaying `delayed_channel` (0 for left, 1 for right) by
+/// `delay_ms` (0 to 30 ms).  Below `highpass_hz` (20 Hz to 1 kHz) the channel isn't delayed, so
+/// that the bass doesn't comb-filter when the output is summed to mono; the mono mix itself isn't
+/// widened.  Returns false, changing nothing, for an unknown channel.
+pub fn set_master_haas(
+    ctx: *mut GranularCtx,
+    delayed_channel: u32,
+    delay_ms: f32,
+    highpass_hz: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(delayed) = HaasChannel::from_index(delayed_channel) else {
+        return false;
+    };
+    if !delay_ms.is_finite() || !highpass_hz.is_finite() {
+        return false;
+    }
+    let settings = HaasSettings {
+        delayed,
+        delay_ms: clamp(0., MAX_DELAY_MS, delay_ms),
+        highpass_hz: clamp(20., 1000., highpass_hz),
+    };
+    match &mut ctx.master_haas {
+        Some(haas) => haas.settings = settings,
+        None => ctx.master_haas = Some(HaasWidener::new(settings, ctx.sample_rate)),
+    }
+    true
+}
+
+/// Removes the master output's Haas widener
+pub fn clear_master_haas(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.master_haas = None;
+    }
+}
+
+#[test]
+fn delays_the_highs_of_one_channel() {
+    let settings = HaasSettings {
+        delayed: HaasChannel::Right,
+        delay_ms: 10.,
+        highpass_hz: 20.,
+    };
+    let mut widener = HaasWidener::new(settings, 1000.);
+    let output: Vec<(f32, f32)> = (0..20)
+        .map(|ix| {
+            let input = if ix == 0 { 1. } else { 0. };
+            widener.process(input, input, 1000.)
+        })
+        .collect();
+    assert_eq!(output[0].0, 1.);
+    assert!(output[1..].iter().all(|&(left, _)| left == 0.));
+    // The click arrives 10 samples later on the right, apart from what the highpass lets through
+    // of it in place
+    let (peak_ix, _) = output
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1 .1.abs().total_cmp(&b.1 .1.abs()))
+        .unwrap();
+    assert_eq!(peak_ix, 10);
+}
+
+#[test]
+fn haas_widener_delays_one_channel() {
+    use super::params::{GlobalParam, ParamId};
+    use super::FRAME_SIZE;
+
+    let mut ctx = GranularCtx {
+        waveform: (0..4410).map(|i| (i as f32 * 0.3).sin()).collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4409.);
+    let targets = *ctx.params.target();
+    assert!(!set_master_haas(&mut ctx, 2, 10., 100.));
+    assert!(set_master_haas(&mut ctx, 1, 10., 20.));
+    let (mut left, mut right) = (Vec::new(), Vec::new());
+    for _ in 0..32 {
+        ctx.render(&targets);
+        let (frame_left, frame_right) = ctx.rendered_output_stereo.split_at(FRAME_SIZE);
+        left.extend_from_slice(frame_left);
+        right.extend_from_slice(frame_right);
+    }
+    // 10 ms at 44.1 kHz
+    assert!(left.iter().any(|&sample| sample != 0.));
+    for ix in 0..left.len() - 441 {
+        assert!((right[ix + 441] - left[ix]).abs() < 0.05);
+    }
+    clear_master_haas(&mut ctx);
+    assert!(ctx.master_haas.is_none());
+}
@@ -0,0 +1,459 @@
+//! Registry of the instances the WASM bindings hand out.  Hosts get a handle instead of a
+//! pointer: the low 16 bits pick a slot and the high 16 bits hold the slot's generation, which is
+//! bumped whenever its instance is freed.  A handle that outlived its instance therefore resolves
+//! to nothing, even once the slot holds a new instance, instead of aliasing the new one.  A slot
+//! whose generation would wrap around is retired instead of reused, so that no handle ever becomes
+//! valid again.
+//!
+//! Calls go through `with_instance` or `guard`, which keep the instance alive until they return:
+//! freeing it from another thread meanwhile invalidates its handle straight away but leaves
+//! dropping it to the call.  Calls on one instance still mustn't overlap, since each of them has
+//! the instance mutably.
+//!
+//! The registry also counts the live instances so hosts can spot leaks, and logs every create and
+//! free at the debug level so that unmatched ones can be tracked down.
+//!
+//! The bindings are meant not to panic at all: they check every argument and do nothing, or
+//! clamp it, when it's out of range, and the fuzz target checks that no input gets through.  That
+//! is the only protection WASM builds have, since they abort on panic and take the whole module
+//! down.  Builds with `panic = "unwind"`, such as native hosts linking the rlib, also have calls
+//! made through `guard` catch panics as a last resort.  An instance that panicked is poisoned: its
+//! output is silenced, its status reports `status::INSTANCE_POISONED` and every further guarded
+//! call on it does nothing, while other instances keep running.
+
+#[cfg(panic = "unwind")]
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+use super::GranularCtx;
+use crate::common::log::{self, LogLevel};
+
+pub type InstanceHandle = u32;
+
+const SLOT_BITS: u32 = 16;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+
+struct Slot {
+    generation: u16,
+    /// Address of the boxed instance, or 0 while the slot is free
+    ctx: usize,
+    /// Calls running on the instance, which keep it alive until they return
+    in_use: u32,
+    /// Set once the instance was freed while calls were running on it.  Its handle no longer
+    /// resolves, and the last of the calls drops it.
+    freeing: bool,
+    /// Set once a call on the instance panicked
+    #[cfg(panic = "unwind")]
+    poisoned: bool,
+}
+
+/// How `Registry::remove` disposed of an instance
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Removal {
+    /// The instance at this address can be dropped now
+    Now(usize),
+    /// Calls are still running on it, so the last of them drops it
+    Deferred,
+}
+
+struct Registry {
+    slots: Vec<Slot>,
+    free_slots: Vec<u32>,
+    /// Slots that ran out of generations and are never handed out again
+    retired_count: usize,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Registry {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            retired_count: 0,
+        }
+    }
+
+    fn live_count(&self) -> usize {
+        self.slots.len() - self.free_slots.len() - self.retired_count
+    }
+
+    /// Stores the instance at address `ctx` and returns its handle, or None if every slot is
+    /// taken
+    fn insert(&mut self, ctx: usize) -> Option<InstanceHandle> {
+        let slot_ix = match self.free_slots.pop() {
+            Some(slot_ix) => slot_ix,
+            None if self.slots.len() <= SLOT_MASK as usize => {
+                self.slots.push(Slot {
+                    generation: 1,
+                    ctx: 0,
+                    in_use: 0,
+                    freeing: false,
+                    #[cfg(panic = "unwind")]
+                    poisoned: false,
+                });
+                self.slots.len() as u32 - 1
+            }
+            None => return None,
+        };
+        let slot = &mut self.slots[slot_ix as usize];
+        slot.ctx = ctx;
+        slot.freeing = false;
+        #[cfg(panic = "unwind")]
+        {
+            slot.poisoned = false;
+        }
+        Some(handle(slot_ix, slot.generation))
+    }
+
+    fn slot_of(&mut self, handle: InstanceHandle) -> Option<&mut Slot> {
+        let slot = self.slots.get_mut((handle & SLOT_MASK) as usize)?;
+        (slot.ctx != 0 && !slot.freeing && slot.generation as u32 == handle >> SLOT_BITS)
+            .then_some(slot)
+    }
+
+    /// Address of the instance behind `handle`, marked as in use until `unpin` is called for it
+    fn pin(&mut self, handle: InstanceHandle) -> Option<usize> {
+        let slot = self.slot_of(handle)?;
+        slot.in_use += 1;
+        Some(slot.ctx)
+    }
+
+    /// Ends a call started with `pin`, returning the address of the instance to drop if it was
+    /// freed during the call and this was the last one running
+    fn unpin(&mut self, handle: InstanceHandle) -> Option<usize> {
+        let slot_ix = handle & SLOT_MASK;
+        let slot = &mut self.slots[slot_ix as usize];
+        slot.in_use -= 1;
+        (slot.freeing && slot.in_use == 0).then(|| self.vacate(slot_ix))
+    }
+
+    /// Invalidates every copy of `handle`, or returns None if it was already stale
+    fn remove(&mut self, handle: InstanceHandle) -> Option<Removal> {
+        let slot = self.slot_of(handle)?;
+        if slot.in_use > 0 {
+            slot.freeing = true;
+            return Some(Removal::Deferred);
+        }
+        Some(Removal::Now(self.vacate(handle & SLOT_MASK)))
+    }
+
+    /// Empties a slot, moving it on to its next generation or retiring it, and returns the
+    /// address of the instance it held
+    fn vacate(&mut self, slot_ix: u32) -> usize {
+        let slot = &mut self.slots[slot_ix as usize];
+        let ctx = std::mem::replace(&mut slot.ctx, 0);
+        slot.freeing = false;
+        match slot.generation.checked_add(1) {
+            Some(generation) => {
+                slot.generation = generation;
+                self.free_slots.push(slot_ix);
+            }
+            None => self.retired_count += 1,
+        }
+        ctx
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn handle(slot_ix: u32, generation: u16) -> InstanceHandle {
+    (generation as u32) << SLOT_BITS | slot_ix
+}
+
+fn drop_instance(ctx: usize) {
+    drop(unsafe { Box::from_raw(ctx as *mut GranularCtx) });
+}
+
+/// Takes ownership of `ctx` and returns its handle, or 0 if every slot is taken.  0 is never a
+/// valid handle because generations start at 1.
+pub fn register(ctx: Box<GranularCtx>) -> InstanceHandle {
+    let ctx = Box::into_raw(ctx) as usize;
+    let mut registry = registry();
+    let Some(handle) = registry.insert(ctx) else {
+        drop(registry);
+        drop_instance(ctx);
+        return 0;
+    };
+    log::log(
+        LogLevel::Debug,
+        format_args!(
+            "created instance {:#x}, {} alive",
+            handle,
+            registry.live_count()
+        ),
+    );
+    handle
+}
+
+/// Number of instances that have been created and not freed yet.  An instance freed during a
+/// call on it counts until the call returns.
+pub fn instance_count() -> usize {
+    registry().live_count()
+}
+
+/// An instance kept alive for the length of a call on it
+struct Pinned {
+    handle: InstanceHandle,
+    ctx: *mut GranularCtx,
+}
+
+impl Pinned {
+    /// Pins the instance behind `handle`, or returns None with a warning if it was freed or never
+    /// handed out
+    fn new(handle: InstanceHandle) -> Option<Self> {
+        match registry().pin(handle) {
+            Some(ctx) => Some(Pinned {
+                handle,
+                ctx: ctx as *mut GranularCtx,
+            }),
+            None => {
+                log::log(
+                    LogLevel::Warn,
+                    format_args!("call with stale or invalid instance handle {:#x}", handle),
+                );
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        let freed = registry().unpin(self.handle);
+        if let Some(ctx) = freed {
+            drop_instance(ctx);
+        }
+    }
+}
+
+/// Calls `f` with the instance behind `handle`, or with null if it was freed or never handed out.
+/// The instance stays alive until `f` returns even if another thread frees it meanwhile, though
+/// calls on the same instance still mustn't run on two threads at once.
+pub fn with_instance<R>(handle: InstanceHandle, f: impl FnOnce(*mut GranularCtx) -> R) -> R {
+    let pinned = Pinned::new(handle);
+    f(pinned
+        .as_ref()
+        .map_or(std::ptr::null_mut(), |pinned| pinned.ctx))
+}
+
+/// Value a guarded call returns when it can't run, matching what the bindings return for a null
+/// instance
+#[cfg(panic = "unwind")]
+pub trait Fallback {
+    fn fallback() -> Self;
+}
+
+#[cfg(panic = "unwind")]
+macro_rules! impl_fallback_default {
+    ($($ty:ty),*) => {
+        $(impl Fallback for $ty {
+            fn fallback() -> Self {
+                Default::default()
+            }
+        })*
+    };
+}
+
+#[cfg(panic = "unwind")]
+impl_fallback_default!((), bool, u32, usize, f32, f64, String);
+
+#[cfg(panic = "unwind")]
+impl<T> Fallback for Vec<T> {
+    fn fallback() -> Self {
+        Vec::new()
+    }
+}
+
+#[cfg(panic = "unwind")]
+impl<T> Fallback for *const T {
+    fn fallback() -> Self {
+        std::ptr::null()
+    }
+}
+
+#[cfg(panic = "unwind")]
+impl<T> Fallback for *mut T {
+    fn fallback() -> Self {
+        std::ptr::null_mut()
+    }
+}
+
+/// Calls `f` like `with_instance`, catching any panic.  A panic poisons the instance, after which
+/// this returns `R::fallback()` without calling `f`.
+#[cfg(panic = "unwind")]
+pub fn guard<R: Fallback>(handle: InstanceHandle, f: impl FnOnce(*mut GranularCtx) -> R) -> R {
+    let Some(pinned) = Pinned::new(handle) else {
+        return f(std::ptr::null_mut());
+    };
+    if registry().slot_of(handle).is_some_and(|slot| slot.poisoned) {
+        return R::fallback();
+    }
+    // The instance is never touched by guarded calls again if `f` panics, so whatever state the
+    // panic left it in can't be observed
+    match panic::catch_unwind(AssertUnwindSafe(|| f(pinned.ctx))) {
+        Ok(value) => value,
+        Err(_) => {
+            if let Some(slot) = registry().slot_of(handle) {
+                slot.poisoned = true;
+            }
+            log::log(
+                LogLevel::Error,
+                format_args!("instance {:#x} panicked and was poisoned", handle),
+            );
+            if let Some(ctx) = unsafe { pinned.ctx.as_mut() } {
+                ctx.poison();
+            }
+            R::fallback()
+        }
+    }
+}
+
+/// Calls `f` like `with_instance`.  A panic aborts before it could be caught, so no instance is
+/// ever poisoned.
+#[cfg(not(panic = "unwind"))]
+pub fn guard<R>(handle: InstanceHandle, f: impl FnOnce(*mut GranularCtx) -> R) -> R {
+    with_instance(handle, f)
+}
+
+/// Frees the instance behind `handle`, invalidating every copy of the handle.  If calls are still
+/// running on it, the last of them drops it once it returns.  Returns false if the handle was
+/// already stale.
+pub fn release(handle: InstanceHandle) -> bool {
+    let mut registry = registry();
+    let Some(removal) = registry.remove(handle) else {
+        return false;
+    };
+    log::log(
+        LogLevel::Debug,
+        format_args!(
+            "freed instance {:#x}, {} alive",
+            handle,
+            registry.live_count()
+        ),
+    );
+    drop(registry);
+    if let Removal::Now(ctx) = removal {
+        drop_instance(ctx);
+    }
+    true
+}
+
+#[test]
+fn stale_handles_resolve_to_nothing() {
+    let mut registry = Registry::new();
+    let first = registry.insert(8).unwrap();
+    assert_ne!(first, 0);
+    assert_eq!(registry.pin(first), Some(8));
+    registry.unpin(first);
+    assert_eq!(registry.remove(first), Some(Removal::Now(8)));
+    assert_eq!(registry.pin(first), None);
+    assert_eq!(registry.remove(first), None);
+
+    // The slot is reused with a new generation
+    let second = registry.insert(16).unwrap();
+    assert_eq!(second & SLOT_MASK, first & SLOT_MASK);
+    assert_ne!(second, first);
+    assert_eq!(registry.pin(first), None);
+    assert_eq!(registry.pin(second), Some(16));
+    registry.unpin(second);
+
+    // A slot on its last generation is retired when freed
+    let slot_ix = second & SLOT_MASK;
+    registry.slots[slot_ix as usize].generation = u16::MAX;
+    let last = handle(slot_ix, u16::MAX);
+    assert_eq!(registry.remove(last), Some(Removal::Now(16)));
+    assert!(!registry.free_slots.contains(&slot_ix));
+    assert_eq!(registry.pin(last), None);
+    let third = registry.insert(24).unwrap();
+    assert_ne!(third & SLOT_MASK, slot_ix);
+}
+
+#[test]
+fn instances_freed_mid_call_outlive_the_call() {
+    let mut registry = Registry::new();
+    let handle = registry.insert(8).unwrap();
+    registry.pin(handle);
+    registry.pin(handle);
+    assert_eq!(registry.remove(handle), Some(Removal::Deferred));
+    // The handle is stale straight away, but the slot isn't reused until the calls return
+    assert_eq!(registry.pin(handle), None);
+    assert_eq!(registry.remove(handle), None);
+    assert!(registry.free_slots.is_empty());
+    assert_eq!(registry.unpin(handle), None);
+    assert_eq!(registry.unpin(handle), Some(8));
+    assert_eq!(registry.free_slots, [handle & SLOT_MASK]);
+}
+
+#[test]
+fn creating_and_freeing_moves_the_live_count() {
+    let mut registry = Registry::new();
+    assert_eq!(registry.live_count(), 0);
+    let first = registry.insert(8).unwrap();
+    let second = registry.insert(16).unwrap();
+    assert_eq!(registry.live_count(), 2);
+    registry.remove(first);
+    assert_eq!(registry.live_count(), 1);
+    // Stale frees don't move it
+    registry.remove(first);
+    assert_eq!(registry.live_count(), 1);
+
+    // An instance freed mid-call counts until the call returns
+    registry.pin(second);
+    registry.remove(second);
+    assert_eq!(registry.live_count(), 1);
+    registry.unpin(second);
+    assert_eq!(registry.live_count(), 0);
+
+    // Retired slots don't count
+    let third = registry.insert(24).unwrap();
+    registry.slots[(third & SLOT_MASK) as usize].generation = u16::MAX;
+    registry.remove(handle(third & SLOT_MASK, u16::MAX));
+    assert_eq!(registry.live_count(), 0);
+}
+
+#[test]
+fn bindings_reject_bad_arguments_without_panicking() {
+    let handle = crate::create_granular_instance();
+    let waveform = crate::get_granular_waveform_ptr(handle, 1000);
+    unsafe { std::slice::from_raw_parts_mut(waveform, 1000).fill(0.5) };
+    assert!(crate::commit_waveform(handle, 1000));
+    assert!(crate::get_granular_waveform_ptr(handle, usize::MAX).is_null());
+    assert!(!crate::commit_waveform(handle, 2000));
+    crate::freeze_voice(handle, usize::MAX, f32::NAN);
+    assert_eq!(crate::crop_waveform(handle, 900, 100), 0);
+    crate::render_granular(handle, f32::NAN, -1., f32::INFINITY, 2., -1.);
+    crate::render_granular(handle, 900., 100., 0., f32::NAN, f32::NAN);
+    assert!(!crate::get_output_ptr(handle).is_null());
+    #[cfg(panic = "unwind")]
+    assert_eq!(
+        crate::get_status(handle) & super::status::INSTANCE_POISONED,
+        0
+    );
+    crate::free_granular_instance(handle);
+    // Calls with the stale handle do nothing
+    crate::render_granular(handle, 0., 1000., 800., 0.5, 0.5);
+    assert!(crate::get_output_ptr(handle).is_null());
+}
+
+#[cfg(panic = "unwind")]
+#[test]
+fn panicking_calls_poison_only_their_instance() {
+    let poisoned = register(Box::default());
+    let healthy = register(Box::default());
+    let result: bool = guard(poisoned, |_| panic!("render failed"));
+    assert!(!result);
+    assert!(!guard(poisoned, |_| true));
+    assert!(guard(healthy, |_| true));
+    let status = with_instance(poisoned, |ctx| unsafe { (*ctx).status });
+    assert_eq!(
+        status & super::status::INSTANCE_POISONED,
+        super::status::INSTANCE_POISONED
+    );
+    release(poisoned);
+    release(healthy);
+}
@@ -0,0 +1,180 @@
+//! Harmonizer.  A voice with harmonies adds copies of its own output shifted by fixed intervals,
+//! each at its own gain, on top of the unshifted output.  Each copy reads the voice's recent
+//! output through two taps of a delay line whose delays sweep at the rate that shifts the pitch
+//! by the interval.  Each tap jumps back once it has swept a whole window, and the taps sit half
+//! a window apart and crossfade with Hann windows, so that one is always faded out when it jumps.
+//! That's the same overlap-add of short grains the voice itself plays, over its own output.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::delay::ModulatedDelay;
+
+/// Most harmonies on a voice
+pub const MAX_HARMONIES: usize = 4;
+/// Length of each tap's sweep.  Longer windows sound smoother on sustained sounds and smear
+/// transients more.
+const WINDOW_MS: f32 = 40.;
+/// Transpositions allowed for harmonies, in semitones either way
+pub const MAX_SEMITONES: f32 = 24.;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Harmony {
+    pub semitones: f32,
+    pub gain: f32,
+}
+
+/// Sweep of the two taps of a harmony
+#[derive(Clone, Copy, Default)]
+struct Sweep {
+    ratio: f32,
+    gain: f32,
+    /// Position of the first tap in its window, from 0 to 1, the second being half a window on
+    phase: f32,
+}
+
+#[derive(Clone)]
+pub struct VoiceHarmonizer {
+    sweeps: [Sweep; MAX_HARMONIES],
+    len: usize,
+    window: f32,
+    /// Left and right
+    delays: [ModulatedDelay; 2],
+}
+
+impl VoiceHarmonizer {
+    /// Returns None for no harmonies or more than `MAX_HARMONIES`
+    pub fn new(harmonies: &[Harmony], sample_rate: f32) -> Option<Self> {
+        if harmonies.is_empty() || harmonies.len() > MAX_HARMONIES {
+            return None;
+        }
+        let mut sweeps = [Sweep::default(); MAX_HARMONIES];
+        for (sweep, harmony) in sweeps.iter_mut().zip(harmonies) {
+            *sweep = Sweep {
+                ratio: (harmony.semitones / 12.).exp2(),
+                gain: harmony.gain,
+                phase: 0.,
+            };
+        }
+        let mut harmonizer = VoiceHarmonizer {
+            sweeps,
+            len: harmonies.len(),
+            window: 0.,
+            delays: Default::default(),
+        };
+        harmonizer.set_sample_rate(sample_rate);
+        Some(harmonizer)
+    }
+
+    /// Resizes the delay lines for the sample rate, which clears them
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.window = WINDOW_MS * 0.001 * sample_rate;
+        let delay = ModulatedDelay::new(self.window.ceil() as usize + 1);
+        self.delays = [delay.clone(), delay];
+    }
+
+    pub fn clear(&mut self) {
+        for delay in &mut self.delays {
+            delay.clear();
+        }
+    }
+
+    /// Longest delay the harmonies are read at
+    pub fn tail_samples(&self) -> f32 {
+        self.window
+    }
+
+    /// Adds the harmonies of a stereo sample to it
+    pub fn process(&mut self, (left, right): (f32, f32)) -> (f32, f32) {
+        let [left_delay, right_delay] = &mut self.delays;
+        left_delay.write(left);
+        right_delay.write(right);
+        let mut output = (left, right);
+        for sweep in &mut self.sweeps[..self.len] {
+            // A rising pitch reads ever closer to the write head, so the delay shrinks
+            sweep.phase = (sweep.phase + (1. - sweep.ratio) / self.window).rem_euclid(1.);
+            for phase in [sweep.phase, (sweep.phase + 0.5).fract()] {
+                let delay = phase * self.window;
+                let gain = sweep.gain * (phase * std::f32::consts::PI).sin().powi(2);
+                output.0 += gain * left_delay.read(delay);
+                output.1 += gain * right_delay.read(delay);
+            }
+        }
+        output
+    }
+}
+
+/// Adds pitch-shifted copies of a voice's output on top of it, one for each interval in
+/// `semitones` (-24 to 24) at the gain at the same index of `gains` (0 to 2), like a harmonizer
+/// after the voice's phaser.  Returns false and changes nothing if the two lists differ in length,
+/// are empty or are longer than `MAX_HARMONIES`.
+pub fn set_voice_harmonies(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    semitones: &[f32],
+    gains: &[f32],
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT
+        || semitones.len() != gains.len()
+        || !semitones.iter().chain(gains).all(|value| value.is_finite())
+    {
+        return false;
+    }
+    let harmonies: Vec<Harmony> = semitones
+        .iter()
+        .zip(gains)
+        .map(|(&semitones, &gain)| Harmony {
+            semitones: clamp(-MAX_SEMITONES, MAX_SEMITONES, semitones),
+            gain: clamp(0., 2., gain),
+        })
+        .collect();
+    let Some(harmonizer) = VoiceHarmonizer::new(&harmonies, ctx.sample_rate) else {
+        return false;
+    };
+    ctx.voices[voice_ix].harmonizer = Some(harmonizer);
+    true
+}
+
+/// Removes a voice's harmonies
+pub fn clear_voice_harmonies(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.harmonizer = None;
+    }
+}
+
+#[test]
+fn harmonies_shift_the_pitch_by_their_interval() {
+    assert!(VoiceHarmonizer::new(&[], 48000.).is_none());
+
+    let sample_rate = 48000.;
+    let fifth = Harmony {
+        semitones: 7.,
+        gain: 1.,
+    };
+    let mut harmonizer = VoiceHarmonizer::new(&[fifth], sample_rate).unwrap();
+    // Only the harmony of a 200 Hz sine, by taking the dry signal back out
+    let hz = 200.;
+    let output: Vec<f32> = (0..9600)
+        .map(|i| {
+            let sample = (std::f32::consts::TAU * hz * i as f32 / sample_rate).sin();
+            harmonizer.process((sample, sample)).0 - sample
+        })
+        .collect();
+    let rising_zero_crossings = output[4800..]
+        .windows(2)
+        .filter(|pair| pair[0] <= 0. && pair[1] > 0.)
+        .count();
+    // 4800 samples of 299.7 Hz, give or take the windows' seams
+    let expected = hz * 2f32.powf(7. / 12.) * 0.1;
+    assert!(
+        (rising_zero_crossings as f32 - expected).abs() <= 2.,
+        "{}",
+        rising_zero_crossings
+    );
+}
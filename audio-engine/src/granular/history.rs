@@ -0,0 +1,185 @@
+//! Undo history of parameter states.  The host commits the parameter targets after each sound
+//! design move it wants to be able to undo, and undo and redo step back and forth through the
+//! committed states.  The history is bounded and forgets the oldest states first.
+
+use std::collections::VecDeque;
+
+use super::params::ParamValues;
+use super::{ctx_mut, GranularCtx};
+
+/// Most states the history keeps
+pub const MAX_HISTORY_STATES: usize = 64;
+
+#[derive(Clone, Default)]
+pub struct ParamHistory {
+    states: VecDeque<ParamValues>,
+    /// State the parameters were last committed or stepped to
+    position: usize,
+}
+
+impl ParamHistory {
+    /// Records `state` after the current one, forgetting the states that were undone.  A state
+    /// that's the same as the current one isn't recorded again.
+    pub fn commit(&mut self, state: ParamValues) {
+        if self.states.get(self.position) == Some(&state) {
+            return;
+        }
+        self.states.truncate(self.position + 1);
+        if self.states.len() == MAX_HISTORY_STATES {
+            self.states.pop_front();
+        }
+        self.states.push_back(state);
+        self.position = self.states.len() - 1;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.position > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.position + 1 < self.states.len()
+    }
+
+    /// Steps back to the state committed before the current one
+    pub fn undo(&mut self) -> Option<ParamValues> {
+        if !self.can_undo() {
+            return None;
+        }
+        self.position -= 1;
+        Some(self.states[self.position])
+    }
+
+    /// Steps forward to the state that was last undone
+    pub fn redo(&mut self) -> Option<ParamValues> {
+        if !self.can_redo() {
+            return None;
+        }
+        self.position += 1;
+        Some(self.states[self.position])
+    }
+
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.position = 0;
+    }
+}
+
+/// Records the current parameter targets in the undo history, after any states that were undone,
+/// which are forgotten.  Commit the starting state too, so that the first move can be undone.
+/// The history keeps the last `MAX_HISTORY_STATES` states.
+pub fn commit_param_state(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        let state = *ctx.params.target();
+        ctx.param_history.commit(state);
+    }
+}
+
+/// Sets every parameter target back to the state committed before the current one.  Returns
+/// false and changes nothing if there's nothing to undo.
+pub fn undo(ctx: *mut GranularCtx) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    match ctx.param_history.undo() {
+        Some(state) => {
+            ctx.params.set_targets(&state);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Sets every parameter target to the state that was last undone.  Returns false and changes
+/// nothing if there's nothing to redo.
+pub fn redo(ctx: *mut GranularCtx) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    match ctx.param_history.redo() {
+        Some(state) => {
+            ctx.params.set_targets(&state);
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn can_undo(ctx: *mut GranularCtx) -> bool {
+    ctx_mut(ctx).is_some_and(|ctx| ctx.param_history.can_undo())
+}
+
+pub fn can_redo(ctx: *mut GranularCtx) -> bool {
+    ctx_mut(ctx).is_some_and(|ctx| ctx.param_history.can_redo())
+}
+
+pub fn clear_param_history(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.param_history.clear();
+    }
+}
+
+#[test]
+fn undo_and_redo_step_through_committed_states() {
+    use super::params::PARAM_COUNT;
+
+    let state = |value: f32| ParamValues([value; PARAM_COUNT]);
+    let mut history = ParamHistory::default();
+    assert_eq!(history.undo(), None);
+    history.commit(state(0.));
+    history.commit(state(1.));
+    history.commit(state(1.));
+    history.commit(state(2.));
+    assert_eq!(history.undo(), Some(state(1.)));
+    assert_eq!(history.undo(), Some(state(0.)));
+    assert_eq!(history.undo(), None);
+    assert_eq!(history.redo(), Some(state(1.)));
+
+    // Committing after an undo forgets the states that were undone
+    history.commit(state(3.));
+    assert!(!history.can_redo());
+    assert_eq!(history.undo(), Some(state(1.)));
+
+    for value in 0..MAX_HISTORY_STATES + 10 {
+        history.commit(state(value as f32 + 10.));
+    }
+    let mut undone = 0;
+    while history.undo().is_some() {
+        undone += 1;
+    }
+    assert_eq!(undone, MAX_HISTORY_STATES - 1);
+    assert_eq!(history.redo(), Some(state(21.)));
+}
+
+#[test]
+fn undo_restores_committed_parameter_states() {
+    use super::dry::set_dry_gain;
+    use super::params::{GlobalParam, VoiceParam};
+    use super::set_voice_pan;
+
+    let mut ctx = GranularCtx::default();
+    assert!(!undo(&mut ctx));
+    commit_param_state(&mut ctx);
+    set_voice_pan(&mut ctx, 0, -0.5);
+    commit_param_state(&mut ctx);
+    set_dry_gain(&mut ctx, 0.5);
+    commit_param_state(&mut ctx);
+    assert!(can_undo(&mut ctx) && !can_redo(&mut ctx));
+
+    let pan_and_dry = |ctx: &mut GranularCtx| {
+        let targets = ctx.params.target();
+        (
+            targets.voice(0, VoiceParam::Pan),
+            targets.global(GlobalParam::DryGain),
+        )
+    };
+    assert!(undo(&mut ctx));
+    assert_eq!(pan_and_dry(&mut ctx), (-0.5, 0.));
+    assert!(undo(&mut ctx));
+    assert_eq!(pan_and_dry(&mut ctx), (0., 0.));
+    assert!(!undo(&mut ctx));
+    assert!(redo(&mut ctx));
+    assert_eq!(pan_and_dry(&mut ctx), (-0.5, 0.));
+
+    clear_param_history(&mut ctx);
+    assert!(!can_undo(&mut ctx) && !redo(&mut ctx));
+}
@@ -0,0 +1,107 @@
+//! Interval tables.  A voice with one transposes every grain it spawns by a ratio drawn from the
+//! table, in place of the continuous random detune, so a cloud of grains can stay on just
+//! intervals like 1/1, 5/4 and 3/2.  Listing a ratio more than once makes it more likely.
+
+use super::{ctx_mut, GranularCtx};
+use rand::Rng;
+
+/// Most ratios a table holds
+pub const MAX_INTERVALS: usize = 64;
+/// Lowest and highest ratios accepted, four octaves either way
+const MIN_RATIO: f32 = 1. / 16.;
+const MAX_RATIO: f32 = 16.;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct IntervalTable {
+    ratios: Vec<f32>,
+}
+
+impl IntervalTable {
+    /// Returns None if `ratios` is empty or too long, or any ratio is out of range
+    pub fn new(ratios: &[f32]) -> Option<Self> {
+        let valid = !ratios.is_empty()
+            && ratios.len() <= MAX_INTERVALS
+            && ratios
+                .iter()
+                .all(|ratio| (MIN_RATIO..=MAX_RATIO).contains(ratio));
+        valid.then(|| IntervalTable {
+            ratios: ratios.to_vec(),
+        })
+    }
+
+    /// Pitch ratio of a new grain
+    pub fn pick(&self, rng: &mut impl Rng) -> f32 {
+        self.ratios[rng.gen_range(0..self.ratios.len())]
+    }
+}
+
+/// Transposes every grain a voice spawns by a ratio drawn at random from `ratios`, like the just
+/// intervals 1, 1.25 and 1.5, instead of detuning it by a random amount.  Returns false and
+/// changes nothing if `ratios` is empty, holds more than `MAX_INTERVALS` or has any
+/// outside 1/16 to 16.
+pub fn set_voice_pitch_intervals(ctx: *mut GranularCtx, voice_ix: usize, ratios: &[f32]) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let (Some(voice), Some(intervals)) = (ctx.voices.get_mut(voice_ix), IntervalTable::new(ratios))
+    else {
+        return false;
+    };
+    voice.pitch_intervals = Some(intervals);
+    true
+}
+
+/// Goes back to transposing a voice's grains by only its pitch and the random detune
+pub fn clear_voice_pitch_intervals(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.pitch_intervals = None;
+    }
+}
+
+#[test]
+fn grains_draw_from_the_table() {
+    assert_eq!(IntervalTable::new(&[]), None);
+    assert_eq!(IntervalTable::new(&[1., 0.]), None);
+    assert_eq!(IntervalTable::new(&[f32::NAN]), None);
+
+    let table = IntervalTable::new(&[1., 1.25, 1.5]).unwrap();
+    let mut rng = crate::common::rng();
+    let mut counts = [0; 3];
+    for _ in 0..3000 {
+        let ratio = table.pick(&mut rng);
+        counts[table.ratios.iter().position(|&r| r == ratio).unwrap()] += 1;
+    }
+    assert!(counts.iter().all(|&count| count > 900), "{:?}", counts);
+}
+
+#[test]
+fn grains_are_transposed_by_ratios_from_the_interval_table() {
+    use super::params::{GlobalParam, ParamId};
+    use super::sine_ctx;
+
+    let mut ctx = sine_ctx();
+    ctx.seed(1);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    assert!(!set_voice_pitch_intervals(&mut ctx, 0, &[1., 20.]));
+    assert!(set_voice_pitch_intervals(&mut ctx, 0, &[1., 1.5]));
+    let targets = *ctx.params.target();
+    let mut ratios = Vec::new();
+    for _ in 0..256 {
+        ctx.render(&targets);
+        ratios.extend(
+            ctx.voices[0]
+                .grains
+                .iter()
+                .map(|grain| grain.sample_playback_ratio),
+        );
+    }
+    assert!(ratios.iter().all(|&ratio| ratio == 1. || ratio == 1.5));
+    assert!(ratios.contains(&1.) && ratios.contains(&1.5));
+
+    clear_voice_pitch_intervals(&mut ctx, 0);
+    assert!(ctx.voices[0].pitch_intervals.is_none());
+}
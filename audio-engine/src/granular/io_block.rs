@@ -0,0 +1,270 @@
+//! Packed I/O block.  Hosts that want to cross into WASM once per frame write everything the
+//! engine needs into a single block of `u32` words and call `process_io_block`, which reads the
+//! parameters, runs the queued events, renders and writes the output, meters and status back into
+//! the same block.  The block starts with a header, the same for the life of the instance apart
+//! from words 2 to 4:
+//!
+//! | word | contents                                                                    |
+//! |------|-----------------------------------------------------------------------------|
+//! | 0    | `IO_BLOCK_MAGIC`, the bytes `GIOB`                                          |
+//! | 1    | layout version, currently `IO_BLOCK_VERSION`                                |
+//! | 2    | `status` flags of the last frame, written by the engine                     |
+//! | 3    | frames rendered, advanced by the engine after each one and wrapping         |
+//! | 4    | events queued for the next frame, set by the host and cleared by the engine |
+//! | 5    | capacity in events, `IO_EVENT_CAPACITY`                                     |
+//! | 6    | offset in words of the packed parameter block described in `param_block`    |
+//! | 7    | offset of the events                                                        |
+//! | 8    | offset of the audio input, `FRAME_SIZE` `f32`s                              |
+//! | 9    | offset of the sidechain input, `FRAME_SIZE` `f32`s                          |
+//! | 10   | offset of the output, `FRAME_SIZE` `f32`s per channel of the output layout  |
+//! | 11   | offset of the meters, `METER_VALUE_COUNT` `f32`s                            |
+//!
+//! Events are commands as described in `commands`, of kind, time and two arguments, except that
+//! the time is the sample of the frame the event runs on rather than a time on the ring's clock.
+//! Events should be written in time order, and ones past the end of the frame run after its last
+//! sample.  The inputs are cleared once they've been read, like `get_audio_input_ptr`'s.
+//!
+//! The block is meant for a host that writes it from the thread that renders, e.g. an audio
+//! worklet's `process`.  Native hosts writing from another thread keep using the shared parameter
+//! block and the command ring, and commands in the ring still run alongside the block's events.
+
+use super::commands::{Command, InvalidCommand, COMMAND_WORDS};
+use super::meters::METER_VALUE_COUNT;
+use super::param_block::{self, ParamBlockError, PARAM_BLOCK_LEN};
+use super::params::ParamValues;
+use super::FRAME_SIZE;
+use super::{ctx_mut, render_from_block, GranularCtx};
+
+pub const IO_BLOCK_MAGIC: u32 = u32::from_le_bytes(*b"GIOB");
+pub const IO_BLOCK_VERSION: u32 = 1;
+pub const IO_BLOCK_HEADER_LEN: usize = 12;
+/// Events the block holds per frame
+pub const IO_EVENT_CAPACITY: usize = 64;
+
+const STATUS: usize = 2;
+const FRAMES_RENDERED: usize = 3;
+const EVENT_COUNT: usize = 4;
+
+const PARAMS: usize = IO_BLOCK_HEADER_LEN;
+const EVENTS: usize = PARAMS + PARAM_BLOCK_LEN;
+const AUDIO_INPUT: usize = EVENTS + IO_EVENT_CAPACITY * COMMAND_WORDS;
+const SIDECHAIN_INPUT: usize = AUDIO_INPUT + FRAME_SIZE;
+/// Room for the widest output layout, quad
+const OUTPUT: usize = SIDECHAIN_INPUT + FRAME_SIZE;
+const METERS: usize = OUTPUT + FRAME_SIZE * 4;
+const IO_BLOCK_LEN: usize = METERS + METER_VALUE_COUNT;
+
+#[derive(Clone)]
+pub struct IoBlock {
+    words: Box<[u32]>,
+    /// Events taken from the block for the frame being rendered
+    events: Vec<[u32; COMMAND_WORDS]>,
+    /// Number of `events` that have run
+    events_run: usize,
+}
+
+impl Default for IoBlock {
+    fn default() -> Self {
+        let mut words = vec![0; IO_BLOCK_LEN].into_boxed_slice();
+        words[..IO_BLOCK_HEADER_LEN].copy_from_slice(&[
+            IO_BLOCK_MAGIC,
+            IO_BLOCK_VERSION,
+            0,
+            0,
+            0,
+            IO_EVENT_CAPACITY as u32,
+            PARAMS as u32,
+            EVENTS as u32,
+            AUDIO_INPUT as u32,
+            SIDECHAIN_INPUT as u32,
+            OUTPUT as u32,
+            METERS as u32,
+        ]);
+        words[PARAMS..EVENTS].copy_from_slice(&param_block::default_words());
+        IoBlock {
+            words,
+            events: Vec::with_capacity(IO_EVENT_CAPACITY),
+            events_run: 0,
+        }
+    }
+}
+
+impl IoBlock {
+    pub fn as_mut_ptr(&mut self) -> *mut u32 {
+        self.words.as_mut_ptr()
+    }
+
+    /// Writes the values in the parameter block to `targets`
+    pub fn read_params(&self, targets: &mut ParamValues) -> Result<(), ParamBlockError> {
+        param_block::read_words(&self.words[PARAMS..EVENTS], targets)
+    }
+
+    /// Takes the queued events and inputs for the next frame, clearing them from the block
+    pub fn begin_frame(
+        &mut self,
+        audio_input: &mut [f32; FRAME_SIZE],
+        sidechain_input: &mut [f32; FRAME_SIZE],
+    ) {
+        let count = (self.words[EVENT_COUNT] as usize).min(IO_EVENT_CAPACITY);
+        self.words[EVENT_COUNT] = 0;
+        self.events.clear();
+        self.events.extend(
+            self.words[EVENTS..EVENTS + count * COMMAND_WORDS]
+                .chunks_exact(COMMAND_WORDS)
+                .map(|event| std::array::from_fn(|ix| event[ix])),
+        );
+        self.events_run = 0;
+        for (input, start) in [
+            (audio_input, AUDIO_INPUT),
+            (sidechain_input, SIDECHAIN_INPUT),
+        ] {
+            let words = &mut self.words[start..start + FRAME_SIZE];
+            for (sample, word) in input.iter_mut().zip(words.iter_mut()) {
+                *sample = f32::from_bits(std::mem::take(word));
+            }
+        }
+    }
+
+    /// Takes the next event if it's due by sample `sample_ix` of the frame, like
+    /// `CommandRing::pop_due`
+    pub fn pop_due(&mut self, sample_ix: usize) -> Option<Result<Command, InvalidCommand>> {
+        let [kind, time, arg_1, arg_2] = *self.events.get(self.events_run)?;
+        if time as usize > sample_ix {
+            return None;
+        }
+        self.events_run += 1;
+        Some(Command::parse(kind, arg_1, arg_2).ok_or(InvalidCommand { kind }))
+    }
+
+    /// Writes back the frame's output, meters and status and counts it as rendered
+    pub fn finish_frame(&mut self, output: &[f32], meters: &[f32], status: u32) {
+        let sections = [(OUTPUT, output), (METERS, meters)];
+        for (start, values) in sections {
+            for (word, value) in self.words[start..].iter_mut().zip(values) {
+                *word = value.to_bits();
+            }
+        }
+        self.words[STATUS] = status;
+        self.words[FRAMES_RENDERED] = self.words[FRAMES_RENDERED].wrapping_add(1);
+    }
+}
+
+/// Returns a pointer to the I/O block described in `io_block`, creating it with the engine's own
+/// header and zeroed values on the first call.  The block never moves.
+pub fn get_io_block_ptr(ctx: *mut GranularCtx) -> *mut u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    ctx.io_block
+        .get_or_insert_with(IoBlock::default)
+        .as_mut_ptr()
+}
+
+/// Renders a frame from the I/O block: its parameters, events and inputs go in and the output,
+/// meters and status come back out into it.  An unreadable parameter block is handled like it is
+/// for `render_granular_block`.  Returns false if there's no instance.
+pub fn process_io_block(ctx: *mut GranularCtx) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let mut targets = *ctx.params.target();
+    let io_block = ctx.io_block.get_or_insert_with(IoBlock::default);
+    let result = io_block.read_params(&mut targets);
+    io_block.begin_frame(&mut ctx.audio_input, &mut ctx.sidechain_input);
+    render_from_block(ctx, &targets, result);
+    // Taken out of the context while the rest of it is borrowed for the output
+    let mut io_block = ctx.io_block.take().unwrap_or_default();
+    io_block.finish_frame(ctx.output(), &ctx.meters.values, ctx.status);
+    ctx.io_block = Some(io_block);
+    true
+}
+
+#[test]
+fn io_blocks_hand_over_one_frame_of_events_and_input() {
+    let mut block = IoBlock::default();
+    let event = |block: &mut IoBlock, ix: usize, words: [u32; COMMAND_WORDS]| {
+        let start = EVENTS + ix * COMMAND_WORDS;
+        block.words[start..start + COMMAND_WORDS].copy_from_slice(&words);
+    };
+    event(&mut block, 0, [3, 0, 64, 100]);
+    event(&mut block, 1, [9, 10, 0, 0]);
+    event(&mut block, 2, [4, 20, 64, 0]);
+    block.words[EVENT_COUNT] = 3;
+    block.words[AUDIO_INPUT + 1] = 0.5f32.to_bits();
+
+    let (mut audio_input, mut sidechain_input) = ([0.; FRAME_SIZE], [1.; FRAME_SIZE]);
+    block.begin_frame(&mut audio_input, &mut sidechain_input);
+    assert_eq!(block.words[EVENT_COUNT], 0);
+    assert_eq!(&audio_input[..3], [0., 0.5, 0.]);
+    assert_eq!(sidechain_input, [0.; FRAME_SIZE]);
+    assert_eq!(block.words[AUDIO_INPUT + 1], 0);
+
+    assert_eq!(
+        block.pop_due(0),
+        Some(Ok(Command::NoteOn {
+            note: 64,
+            velocity: 100
+        }))
+    );
+    assert_eq!(block.pop_due(9), None);
+    assert_eq!(block.pop_due(10), Some(Err(InvalidCommand { kind: 9 })));
+    assert_eq!(
+        block.pop_due(FRAME_SIZE),
+        Some(Ok(Command::NoteOff { note: 64 }))
+    );
+    assert_eq!(block.pop_due(FRAME_SIZE), None);
+
+    block.finish_frame(&[0.25; FRAME_SIZE], &[0.75; METER_VALUE_COUNT], 1);
+    assert_eq!(f32::from_bits(block.words[OUTPUT + 5]), 0.25);
+    assert_eq!(f32::from_bits(block.words[METERS]), 0.75);
+    assert_eq!(block.words[STATUS..=FRAMES_RENDERED], [1, 1]);
+}
+
+#[test]
+fn io_blocks_render_like_separate_calls() {
+    use super::params::{ParamId, PARAM_COUNT};
+    use super::{configure_output, meters, sine_ctx, test_targets};
+
+    let targets = test_targets(47999.);
+    let mut separate = sine_ctx();
+    let mut packed = sine_ctx();
+    separate.seed(3);
+    packed.seed(3);
+    assert!(configure_output(&mut separate, 2));
+    assert!(configure_output(&mut packed, 2));
+
+    let words = get_io_block_ptr(&mut packed);
+    let header = unsafe { std::slice::from_raw_parts(words, IO_BLOCK_HEADER_LEN) };
+    let len = header[11] as usize + meters::METER_VALUE_COUNT;
+    let words = unsafe { std::slice::from_raw_parts_mut(words, len) };
+    let values = words[6] as usize + param_block::PARAM_BLOCK_HEADER_LEN;
+    for ix in 0..PARAM_COUNT {
+        words[values + ix] = targets.get(ParamId::from_index(ix).unwrap()).to_bits();
+    }
+    let input = words[8] as usize;
+    words[input..input + FRAME_SIZE].fill(0.5f32.to_bits());
+    separate.audio_input = [0.5; FRAME_SIZE];
+
+    assert!(process_io_block(&mut packed));
+    separate.render(&targets);
+    let output = words[10] as usize;
+    let output: Vec<f32> = words[output..output + FRAME_SIZE * 2]
+        .iter()
+        .map(|word| f32::from_bits(*word))
+        .collect();
+    assert_eq!(output, separate.rendered_output_stereo);
+    let meters = words[11] as usize;
+    assert_eq!(f32::from_bits(words[meters]), separate.meters.values[0]);
+    assert_eq!(words[2..4], [separate.status, 1]);
+    // Inputs are cleared once they're read
+    assert_eq!(words[input], 0);
+
+    // A selection set on the middle sample of the next frame
+    let events = words[7] as usize;
+    words[events..events + 4].copy_from_slice(&[1, 64, 100f32.to_bits(), 2000f32.to_bits()]);
+    words[4] = 1;
+    assert!(process_io_block(&mut packed));
+    assert_eq!(packed.command_selection, Some((100., 2000.)));
+    assert_eq!(words[3..5], [2, 0]);
+    assert!(!process_io_block(std::ptr::null_mut()));
+}
@@ -0,0 +1,268 @@
+//! Key estimation.  The loaded waveform is split into frames whose spectra are folded into a
+//! chromagram, the energy of each of the twelve pitch classes, and the key is the one whose
+//! Krumhansl-Kessler profile correlates best with it.  The profiles are how well each pitch
+//! class fits a major or minor key in listening tests, so the estimate holds up for chords and
+//! melodies alike.  A key and its relative minor or major share their notes, so telling them
+//! apart relies on the tonic being emphasized.
+
+use std::f32::consts::PI;
+use std::fmt;
+
+use super::params::{GlobalParam, ParamId};
+use super::tuning::Tuning;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::fft::fft;
+
+const FRAME_LEN: usize = 8192;
+/// Most frames analyzed, spread over the waveform, which keeps long buffers quick to analyze
+const MAX_FRAMES: usize = 64;
+/// Frequencies folded into the chromagram.  Below it the bins are wider than a semitone.
+const MIN_HZ: f32 = 100.;
+const MAX_HZ: f32 = 5000.;
+const A4_HZ: f32 = 440.;
+/// Pitch class of A, counting from C
+const A_PITCH_CLASS: i32 = 9;
+
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Major,
+    /// Natural minor
+    Minor,
+}
+
+impl Mode {
+    pub fn index(self) -> u32 {
+        match self {
+            Mode::Major => 0,
+            Mode::Minor => 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Key {
+    /// Pitch class of the tonic, from 0 for C to 11 for B
+    pub tonic: u8,
+    pub mode: Mode,
+}
+
+impl Key {
+    /// Semitones of each degree of the scale above the tonic
+    pub fn scale(self) -> [u8; 7] {
+        match self.mode {
+            Mode::Major => [0, 2, 4, 5, 7, 9, 11],
+            Mode::Minor => [0, 2, 3, 5, 7, 8, 10],
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            Mode::Major => "major",
+            Mode::Minor => "minor",
+        };
+        write!(f, "{} {}", NOTE_NAMES[self.tonic as usize % 12], mode)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KeyEstimate {
+    pub key: Key,
+    /// Correlation of the chromagram with the key's profile, from -1 to 1
+    pub confidence: f32,
+}
+
+/// Energy of each pitch class in `samples`, counting from C
+pub fn chromagram(samples: &[f32], sample_rate: f32) -> [f32; 12] {
+    let mut chroma = [0.; 12];
+    if samples.len() < FRAME_LEN {
+        return chroma;
+    }
+    let frame_count = ((samples.len() - FRAME_LEN) / FRAME_LEN + 1).min(MAX_FRAMES);
+    let hop = if frame_count > 1 {
+        (samples.len() - FRAME_LEN) / (frame_count - 1)
+    } else {
+        0
+    };
+    let bin_hz = sample_rate / FRAME_LEN as f32;
+    let bins = (MIN_HZ / bin_hz).ceil() as usize..((MAX_HZ / bin_hz) as usize).min(FRAME_LEN / 2);
+    let pitch_classes: Vec<usize> = bins
+        .clone()
+        .map(|bin| {
+            let semitones_from_a = (12. * (bin as f32 * bin_hz / A4_HZ).log2()).round() as i32;
+            (semitones_from_a + A_PITCH_CLASS).rem_euclid(12) as usize
+        })
+        .collect();
+    let mut re = vec![0.; FRAME_LEN];
+    let mut im = vec![0.; FRAME_LEN];
+    for frame_ix in 0..frame_count {
+        let frame = &samples[frame_ix * hop..frame_ix * hop + FRAME_LEN];
+        for (i, (re, sample)) in re.iter_mut().zip(frame).enumerate() {
+            let window = 0.5 - 0.5 * (2. * PI * i as f32 / FRAME_LEN as f32).cos();
+            *re = if sample.is_finite() {
+                sample * window
+            } else {
+                0.
+            };
+        }
+        im.fill(0.);
+        fft(&mut re, &mut im);
+        for (bin, &pitch_class) in bins.clone().zip(&pitch_classes) {
+            chroma[pitch_class] += re[bin] * re[bin] + im[bin] * im[bin];
+        }
+    }
+    chroma
+}
+
+/// Pearson correlation of `chroma` with `profile` moved up to `tonic`
+fn correlation(chroma: &[f32; 12], profile: &[f32; 12], tonic: usize) -> f32 {
+    let chroma_mean = chroma.iter().sum::<f32>() / 12.;
+    let profile_mean = profile.iter().sum::<f32>() / 12.;
+    let (mut covariance, mut chroma_variance, mut profile_variance) = (0., 0., 0.);
+    for (pitch_class, value) in chroma.iter().enumerate() {
+        let a = value - chroma_mean;
+        let b = profile[(pitch_class + 12 - tonic) % 12] - profile_mean;
+        covariance += a * b;
+        chroma_variance += a * a;
+        profile_variance += b * b;
+    }
+    covariance / (chroma_variance * profile_variance).sqrt()
+}
+
+/// The most likely key of `samples`, or None if they're too short, silent or not finite
+pub fn key_of(samples: &[f32], sample_rate: f32) -> Option<KeyEstimate> {
+    let chroma = chromagram(samples, sample_rate);
+    let total: f32 = chroma.iter().sum();
+    if !(total.is_finite() && total > 1e-9) {
+        return None;
+    }
+    let candidates = [(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)]
+        .into_iter()
+        .flat_map(|(mode, profile)| {
+            (0..12).map(move |tonic| KeyEstimate {
+                key: Key {
+                    tonic: tonic as u8,
+                    mode,
+                },
+                confidence: correlation(&chroma, profile, tonic),
+            })
+        });
+    candidates
+        .filter(|estimate| estimate.confidence.is_finite())
+        .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+}
+
+/// Estimates the key of the loaded waveform from its chromagram.  Returns the tonic's pitch class
+/// from 0 for C to 11 for B, 0 for major or 1 for minor, and the confidence from -1 to 1, or
+/// nothing for a waveform that's too short or silent.  With `apply` set, the key's scale becomes
+/// the tuning and the `GrainPitchSnap` parameter is turned on, so grains stay in the source's key.
+pub fn estimate_key(ctx: *mut GranularCtx, apply: bool) -> Vec<f32> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    let Some(estimate) = key_of(&ctx.samples().decode(), ctx.sample_rate) else {
+        return Vec::new();
+    };
+    if apply {
+        ctx.tuning = Some(Tuning::from_key(estimate.key));
+        ctx.params
+            .set_target(ParamId::Global(GlobalParam::GrainPitchSnap), 1.);
+    }
+    vec![
+        estimate.key.tonic as f32,
+        estimate.key.mode.index() as f32,
+        estimate.confidence,
+    ]
+}
+
+#[test]
+fn triads_give_their_key() {
+    let sample_rate = 48000.;
+    let chord = |midi_notes: &[f32]| -> Vec<f32> {
+        (0..FRAME_LEN * 4)
+            .map(|i| {
+                midi_notes
+                    .iter()
+                    .map(|note| {
+                        let hz = A4_HZ * ((note - 69.) / 12.).exp2();
+                        (2. * PI * hz * i as f32 / sample_rate).sin()
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    // C major and D minor triads with the tonic doubled an octave down
+    let estimate = key_of(&chord(&[48., 60., 64., 67.]), sample_rate).unwrap();
+    assert_eq!(
+        estimate.key,
+        Key {
+            tonic: 0,
+            mode: Mode::Major
+        }
+    );
+    assert!(estimate.confidence > 0.5, "{}", estimate.confidence);
+    let estimate = key_of(&chord(&[50., 62., 65., 69.]), sample_rate).unwrap();
+    assert_eq!(estimate.key.to_string(), "D minor");
+
+    assert_eq!(key_of(&[0.; FRAME_LEN * 2], sample_rate), None);
+    assert_eq!(key_of(&[1.; 100], sample_rate), None);
+}
+
+#[test]
+fn grains_snap_to_the_estimated_key() {
+    use super::params::VoiceParam;
+    use super::{spawn_events, DEFAULT_SAMPLE_RATE};
+
+    let sample_rate = DEFAULT_SAMPLE_RATE;
+    // A G major triad
+    let mut ctx = GranularCtx {
+        waveform: (0..48000)
+            .map(|i| {
+                [55., 67., 71., 74.]
+                    .iter()
+                    .map(|note: &f32| {
+                        let hz = 440. * ((note - 69.) / 12.).exp2();
+                        (std::f32::consts::TAU * hz * i as f32 / sample_rate).sin() * 0.25
+                    })
+                    .sum()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    assert_eq!(estimate_key(&mut ctx, false)[..2], [7., 0.]);
+    let snap = ParamId::Global(GlobalParam::GrainPitchSnap);
+    assert!(ctx.tuning.is_none() && ctx.params.target().get(snap) == 0.);
+    estimate_key(&mut ctx, true);
+    assert_eq!(ctx.params.target().get(snap), 1.);
+
+    ctx.grain_trace.set_enabled(true);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    // Between a major third and a fourth up, nearer the fourth
+    ctx.params
+        .set_target(ParamId::Voice(0, VoiceParam::SampleSpeedRatio), 1.32);
+    let targets = *ctx.params.target();
+    for _ in 0..64 {
+        ctx.render(&targets);
+    }
+    let ratios: Vec<f32> = spawn_events(&mut ctx, 0)
+        .iter()
+        .map(|event| event.sample_playback_ratio)
+        .collect();
+    let fourth = 2f32.powf(5. / 12.);
+    assert!(!ratios.is_empty());
+    assert!(ratios.iter().all(|ratio| (ratio - fourth).abs() < 1e-4));
+
+    assert!(estimate_key(&mut GranularCtx::default(), true).is_empty());
+}
@@ -0,0 +1,157 @@
+//! Linked stereo voice pairs.  While the link is on, the second voice follows every parameter and
+//! envelope setting of the first, but runs its grain clock and read head a fixed fraction ahead
+//! of it.  The pair is panned hard left and right, so a single parameter set produces wide stereo
+//! granulation.  Modulation routings stay per voice.
+
+use super::params::{GlobalParam, ParamId, ParamValues, VoiceParam};
+use super::spectral::SpectralGranulator;
+use super::GranularVoice;
+use super::{ctx_mut, GranularCtx};
+
+const LEADER_IX: usize = 0;
+const FOLLOWER_IX: usize = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct VoiceLink {
+    /// How far the follower's grain clock runs ahead, as a fraction of the interval between grains
+    pub phase_offset: f32,
+    /// How far the follower's read head runs ahead, as a fraction of the room it has to move in
+    pub position_offset: f32,
+}
+
+impl VoiceLink {
+    /// Copies the leader's parameter targets to the follower and pans the two apart
+    pub fn apply(&self, targets: &mut ParamValues) {
+        for param in VoiceParam::ALL {
+            let value = targets.voice(LEADER_IX, param);
+            targets.set(ParamId::Voice(FOLLOWER_IX, param), value);
+        }
+        targets.set(ParamId::Voice(LEADER_IX, VoiceParam::Pan), -1.);
+        targets.set(ParamId::Voice(FOLLOWER_IX, VoiceParam::Pan), 1.);
+    }
+
+    /// Copies the settings that aren't backed by parameters from the leader to the follower
+    pub fn follow_settings(&self, voices: &mut [GranularVoice; 2]) {
+        let [leader, follower] = voices;
+        if follower.envelope != leader.envelope {
+            *follower.envelope_mut() = leader.envelope.clone();
+        }
+        follower.sync_beats = leader.sync_beats;
+        follower.size_sync_beats = leader.size_sync_beats;
+        follower.pulsar = leader.pulsar;
+        follower.psola = leader.psola;
+        follower.wavetable = match (leader.wavetable, follower.wavetable) {
+            (Some(leader_table), Some(table)) => Some(table.with_settings_of(&leader_table)),
+            (leader_table, _) => leader_table,
+        };
+        match (&leader.spectral, &mut follower.spectral) {
+            (Some(leader_spectral), Some(spectral))
+                if spectral.settings.frame_len == leader_spectral.settings.frame_len =>
+            {
+                spectral.settings = leader_spectral.settings;
+            }
+            (leader_spectral, spectral) => {
+                *spectral = leader_spectral
+                    .as_ref()
+                    .map(|leader_spectral| SpectralGranulator::new(leader_spectral.settings));
+            }
+        }
+        match (&leader.resonator, &mut follower.resonator) {
+            (Some(leader_resonator), Some(resonator)) => {
+                resonator.settings = leader_resonator.settings;
+            }
+            (leader_resonator, resonator) => *resonator = leader_resonator.clone(),
+        }
+        match (&leader.comb, &mut follower.comb) {
+            (Some(leader_comb), Some(comb)) => comb.settings = leader_comb.settings,
+            (leader_comb, comb) => *comb = leader_comb.clone(),
+        }
+        match (&leader.phaser, &mut follower.phaser) {
+            (Some(leader_phaser), Some(phaser)) => phaser.settings = leader_phaser.settings,
+            (leader_phaser, phaser) => *phaser = *leader_phaser,
+        }
+        follower.reversed_source = leader.reversed_source;
+    }
+
+    /// Places the follower's grain clock and read head at their offsets from the leader's.  The
+    /// offsets can't be kept on the host's beat grid, where both voices spawn on the same lines.
+    pub fn sync(&self, voices: &mut [GranularVoice; 2], params: &ParamValues) {
+        let [leader, follower] = voices;
+        let interval = leader.grain_interval;
+        follower.samples_since_last_grain = if interval > 0. {
+            (leader.samples_since_last_grain + self.phase_offset * interval) % interval
+        } else {
+            leader.samples_since_last_grain
+        };
+
+        let selection_start_sample_ix = params.global(GlobalParam::SelectionStartSampleIx);
+        let range = params.global(GlobalParam::SelectionEndSampleIx)
+            - selection_start_sample_ix
+            - params.global(GlobalParam::GrainSize);
+        follower.cur_grain_start = if range > 0. {
+            let offset_from_selection_start = leader.cur_grain_start - selection_start_sample_ix;
+            selection_start_sample_ix
+                + (offset_from_selection_start + self.position_offset * range).rem_euclid(range)
+        } else {
+            leader.cur_grain_start
+        };
+    }
+}
+
+/// Links the two voices into a stereo pair, or unlinks them.  While linked, the second voice
+/// follows the first one's parameters and envelope settings with its grain clock running
+/// `phase_offset` of a grain interval ahead and its read head `position_offset` of the selection
+/// ahead, and the voices are panned hard left and right.  Offsets are wrapped into 0..1.
+/// Unlinking leaves the second voice with the parameters it was last given directly.
+pub fn set_voice_link(
+    ctx: *mut GranularCtx,
+    enabled: bool,
+    phase_offset: f32,
+    position_offset: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !enabled {
+        ctx.voice_link = None;
+        return;
+    }
+    if !phase_offset.is_finite() || !position_offset.is_finite() {
+        return;
+    }
+    ctx.voice_link = Some(VoiceLink {
+        phase_offset: phase_offset.rem_euclid(1.),
+        position_offset: position_offset.rem_euclid(1.),
+    });
+}
+
+#[test]
+fn linked_voices_are_panned_apart() {
+    use super::{test_targets, FRAME_SIZE};
+
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| (i as f32 * 0.01).sin() * 0.5).collect(),
+        ..Default::default()
+    };
+    set_voice_link(&mut ctx, true, 0.5, 0.25);
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    let target = ctx.params.target();
+    assert_eq!(target.voice(1, VoiceParam::Gain), 1.);
+    assert_eq!(target.voice(0, VoiceParam::Pan), -1.);
+    assert_eq!(target.voice(1, VoiceParam::Pan), 1.);
+    let [leader, follower] = &ctx.voices;
+    assert!(
+        (follower.samples_since_last_grain - leader.samples_since_last_grain).rem_euclid(100.) > 1.
+    );
+    assert_ne!(follower.cur_grain_start, leader.cur_grain_start);
+
+    let [left_voice, right_voice] = &ctx.rendered_voice_outputs;
+    assert!(left_voice[..FRAME_SIZE].iter().any(|sample| *sample != 0.));
+    assert!(left_voice[FRAME_SIZE..].iter().all(|sample| *sample == 0.));
+    assert!(right_voice[FRAME_SIZE..].iter().any(|sample| *sample != 0.));
+    assert!(right_voice[..FRAME_SIZE].iter().all(|sample| *sample == 0.));
+}
@@ -0,0 +1,135 @@
+//! Live input granulation.  The waveform becomes a loop of the last few seconds of the audio
+//! input, overwritten at a write head as the host writes input, and every voice granulates it
+//! from a scrub position some time behind the write head instead of from its own read head.
+//! Moving the scrub position moves through recent time like a tape head.
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+pub const MIN_HISTORY_SECONDS: f32 = 1.;
+pub const MAX_HISTORY_SECONDS: f32 = 60.;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct LiveInput {
+    /// Where the next input sample is written
+    pub write_ix: usize,
+    /// How far behind the write head grains end, before their start randomness
+    pub scrub_seconds: f32,
+}
+
+impl LiveInput {
+    /// Overwrites the oldest history with `input`
+    pub fn write(&mut self, history: &mut [f32], input: &[f32]) {
+        if history.is_empty() {
+            return;
+        }
+        for &sample in input {
+            self.write_ix %= history.len();
+            history[self.write_ix] = if sample.is_finite() { sample } else { 0. };
+            self.write_ix += 1;
+        }
+        self.write_ix %= history.len();
+    }
+
+    /// Start of a grain of `grain_size` samples ending at the scrub position, in a history of
+    /// `history_len` samples
+    pub fn read_head(&self, history_len: usize, grain_size: f32, sample_rate: f32) -> f32 {
+        let behind = self.scrub_seconds * sample_rate + grain_size;
+        (self.write_ix as f32 - behind).rem_euclid(history_len.max(1) as f32)
+    }
+}
+
+/// Replaces the waveform with a silent history of `history_seconds` seconds that the audio input
+/// is written into as it arrives, looping over the oldest input.  Voices then granulate it
+/// from the scrub position set with `set_live_scrub` instead of their read heads, and the
+/// selection covers all of it.  Loading or cropping a waveform turns live input off.  Returns
+/// false for a non-finite length.
+pub fn set_live_input(ctx: *mut GranularCtx, history_seconds: f32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if !history_seconds.is_finite() {
+        return false;
+    }
+    let history_seconds = clamp(MIN_HISTORY_SECONDS, MAX_HISTORY_SECONDS, history_seconds);
+    let scrub_seconds = ctx.live_input.map_or(0., |live_input| {
+        live_input.scrub_seconds.min(history_seconds)
+    });
+    ctx.load_waveform(vec![0.; (history_seconds * ctx.sample_rate) as usize], None);
+    ctx.live_input = Some(LiveInput {
+        write_ix: 0,
+        scrub_seconds,
+    });
+    true
+}
+
+/// Sets how many seconds behind the most recent input live grains end, up to the history length
+pub fn set_live_scrub(ctx: *mut GranularCtx, seconds_ago: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let history_seconds = ctx.waveform.len() as f32 / ctx.sample_rate;
+    if let Some(live_input) = ctx.live_input.as_mut().filter(|_| seconds_ago.is_finite()) {
+        live_input.scrub_seconds = clamp(0., history_seconds, seconds_ago);
+    }
+}
+
+/// Stops writing the input into the waveform, which keeps the history as it is
+pub fn clear_live_input(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.live_input = None;
+    }
+}
+
+#[test]
+fn history_loops_and_scrubbing_reads_behind_the_write_head() {
+    let mut history = vec![0.; 8];
+    let mut live = LiveInput::default();
+    live.write(&mut history, &[1., 2., 3., 4., 5., 6.]);
+    live.write(&mut history, &[7., 8., 9., f32::NAN]);
+    assert_eq!(history, vec![9., 0., 3., 4., 5., 6., 7., 8.]);
+    assert_eq!(live.write_ix, 2);
+
+    // Two samples ago at 1 Hz, for a grain of a single sample
+    live.scrub_seconds = 2.;
+    assert_eq!(live.read_head(history.len(), 1., 1.), 7.);
+    assert_eq!(history[7], 8.);
+}
+
+#[test]
+fn live_input_is_granulated_from_behind_the_write_head() {
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+    use super::{DEFAULT_SAMPLE_RATE, FRAME_SIZE};
+
+    let mut ctx = GranularCtx::default();
+    assert!(set_live_input(&mut ctx, 1.));
+    assert_eq!(ctx.waveform.len(), DEFAULT_SAMPLE_RATE as usize);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::GrainSize), 1024.);
+    let targets = *ctx.params.target();
+    let render = |ctx: &mut GranularCtx, input: f32, frames: usize| {
+        let mut output = Vec::new();
+        for _ in 0..frames {
+            ctx.audio_input = [input; FRAME_SIZE];
+            ctx.render(&targets);
+            output.extend_from_slice(&ctx.rendered_output);
+        }
+        output
+    };
+    let level = |output: &[f32]| output.iter().fold(0f32, |max, s| max.max(s.abs()));
+    // Half a second of input followed by a quarter of a second of silence
+    render(&mut ctx, 0.5, 172);
+    let output = render(&mut ctx, 0., 86);
+    assert!(level(&output[output.len() / 2..]) < 0.001);
+
+    set_live_scrub(&mut ctx, 0.4);
+    let output = render(&mut ctx, 0., 16);
+    assert!(level(&output[FRAME_SIZE * 8..]) > 0.1);
+    assert_eq!(ctx.live_input.unwrap().scrub_seconds, 0.4);
+
+    clear_live_input(&mut ctx);
+    render(&mut ctx, 1., 1);
+    assert!(ctx.waveform.iter().all(|&sample| sample < 1.));
+}
@@ -0,0 +1,161 @@
+//! Macro controls.  Each macro is a single 0-1 knob that drives any number of engine parameters
+//! through a table of mappings, each with its own output range and curve.  Mapped parameters are
+//! overridden by their macros when each frame's targets are set, so the regular parameter
+//! smoothing still applies to them.
+
+use super::params::{ParamId, ParamValues};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::{clamp, mix};
+
+pub const MACRO_COUNT: usize = 4;
+pub const MACRO_MAPPING_COUNT: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MacroMapping {
+    pub macro_ix: usize,
+    pub param: ParamId,
+    /// Parameter value when the macro is at 0
+    pub min: f32,
+    /// Parameter value when the macro is at 1
+    pub max: f32,
+    /// Exponent applied to the macro value before it's mapped onto the range.  1 is linear,
+    /// larger values spend more of the knob's travel near `min`.
+    pub curve: f32,
+}
+
+/// Maps a normalized 0-1 control value onto `[min, max]` after raising it to the power of `curve`
+pub fn curved_range(value: f32, min: f32, max: f32, curve: f32) -> f32 {
+    mix(value.powf(curve), min, max)
+}
+
+impl MacroMapping {
+    fn value(&self, macro_value: f32) -> f32 {
+        curved_range(macro_value, self.min, self.max, self.curve)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct MacroBank {
+    pub values: [f32; MACRO_COUNT],
+    pub mappings: [Option<MacroMapping>; MACRO_MAPPING_COUNT],
+}
+
+impl Default for MacroBank {
+    fn default() -> Self {
+        MacroBank {
+            values: [0.; MACRO_COUNT],
+            mappings: [None; MACRO_MAPPING_COUNT],
+        }
+    }
+}
+
+impl MacroBank {
+    pub fn set_value(&mut self, macro_ix: usize, value: f32) {
+        if let Some(slot) = self.values.get_mut(macro_ix) {
+            *slot = clamp(0., 1., value);
+        }
+    }
+
+    pub fn set_mapping(&mut self, mapping_ix: usize, mapping: Option<MacroMapping>) {
+        if let Some(slot) = self.mappings.get_mut(mapping_ix) {
+            *slot = mapping;
+        }
+    }
+
+    /// Overwrites every mapped parameter in `targets`.  When several mappings share a parameter
+    /// the one in the highest slot wins.
+    pub fn apply(&self, targets: &mut ParamValues) {
+        for mapping in self.mappings.iter().flatten() {
+            targets.set(mapping.param, mapping.value(self.values[mapping.macro_ix]));
+        }
+    }
+}
+
+/// Sets the position of a macro knob, clamped to the range 0-1
+pub fn set_macro_value(ctx: *mut GranularCtx, macro_ix: usize, value: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !value.is_finite() {
+        return;
+    }
+    ctx.macros.set_value(macro_ix, value);
+}
+
+/// Maps a macro onto a parameter addressed by its flat index.  The parameter moves from `min` to
+/// `max` as the macro goes from 0 to 1, shaped by the exponent `curve`.
+pub fn set_macro_mapping(
+    ctx: *mut GranularCtx,
+    mapping_ix: usize,
+    macro_ix: usize,
+    param_ix: usize,
+    min: f32,
+    max: f32,
+    curve: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(param) = ParamId::from_index(param_ix) else {
+        return;
+    };
+    if macro_ix >= MACRO_COUNT
+        || !min.is_finite()
+        || !max.is_finite()
+        || !curve.is_finite()
+        || curve <= 0.
+    {
+        return;
+    }
+    ctx.macros.set_mapping(
+        mapping_ix,
+        Some(MacroMapping {
+            macro_ix,
+            param,
+            min,
+            max,
+            curve,
+        }),
+    );
+}
+
+pub fn clear_macro_mapping(ctx: *mut GranularCtx, mapping_ix: usize) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.macros.set_mapping(mapping_ix, None);
+    }
+}
+
+#[test]
+fn macros_drive_mapped_params() {
+    use super::params::{GlobalParam, VoiceParam};
+
+    let mut bank = MacroBank::default();
+    let grain_size = ParamId::Global(GlobalParam::GrainSize);
+    let pan = ParamId::Voice(1, VoiceParam::Pan);
+    bank.set_mapping(
+        0,
+        Some(MacroMapping {
+            macro_ix: 2,
+            param: grain_size,
+            min: 100.,
+            max: 1100.,
+            curve: 2.,
+        }),
+    );
+    bank.set_mapping(
+        1,
+        Some(MacroMapping {
+            macro_ix: 2,
+            param: pan,
+            min: 1.,
+            max: -1.,
+            curve: 1.,
+        }),
+    );
+    bank.set_value(2, 0.5);
+
+    let mut targets = ParamValues::default();
+    bank.apply(&mut targets);
+    assert_eq!(targets.get(grain_size), 350.);
+    assert_eq!(targets.get(pan), 0.);
+}
@@ -0,0 +1,193 @@
+//! Level metering.  Peak and RMS levels of every voice and of both master channels are measured
+//! over each rendered frame and published as a flat array that the host can read through a
+//! pointer, so UIs don't have to tap the audio stream in JS.  Ballistics (decay, peak hold) are
+//! left to the UI.
+//!
+//! The array ends with the phase correlation of the master channels, averaged over roughly
+//! `CORRELATION_AVERAGING_SECONDS`: 1 for mono content, 0 for unrelated channels and -1 for
+//! channels that cancel out when summed to mono.  It's followed by the largest gain reduction
+//! applied by the output limiter during the frame, in dB.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+
+/// Voices followed by the left and right master channels
+pub const METER_CHANNEL_COUNT: usize = VOICE_COUNT + 2;
+/// Each channel publishes its RMS followed by its peak, then come the master correlation and the
+/// limiter's gain reduction
+pub const METER_VALUE_COUNT: usize = METER_CHANNEL_COUNT * 2 + 2;
+const CORRELATION_IX: usize = METER_CHANNEL_COUNT * 2;
+const GAIN_REDUCTION_IX: usize = CORRELATION_IX + 1;
+const CORRELATION_AVERAGING_SECONDS: f32 = 0.3;
+
+#[derive(Clone, Copy, Default)]
+struct ChannelAccumulator {
+    sum_of_squares: f32,
+    peak: f32,
+}
+
+impl ChannelAccumulator {
+    #[inline]
+    fn add(&mut self, sample: f32) {
+        self.sum_of_squares += sample * sample;
+        self.peak = self.peak.max(sample.abs());
+    }
+}
+
+/// Sums of the products of the master channels, either over one frame or as running averages
+#[derive(Clone, Copy, Default)]
+struct CorrelationSums {
+    left_right: f32,
+    left_left: f32,
+    right_right: f32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Meters {
+    accumulators: [ChannelAccumulator; METER_CHANNEL_COUNT],
+    frame_correlation: CorrelationSums,
+    average_correlation: CorrelationSums,
+    max_gain_reduction_db: f32,
+    /// Linear RMS and peak of each channel over the last frame, followed by the correlation and
+    /// gain reduction
+    pub values: [f32; METER_VALUE_COUNT],
+}
+
+impl Default for Meters {
+    fn default() -> Self {
+        Meters {
+            accumulators: [ChannelAccumulator::default(); METER_CHANNEL_COUNT],
+            frame_correlation: CorrelationSums::default(),
+            average_correlation: CorrelationSums::default(),
+            max_gain_reduction_db: 0.,
+            values: [0.; METER_VALUE_COUNT],
+        }
+    }
+}
+
+impl Meters {
+    #[inline]
+    pub fn add_voice_sample(&mut self, voice_ix: usize, sample: f32) {
+        self.accumulators[voice_ix].add(sample);
+    }
+
+    #[inline]
+    pub fn add_master_sample(&mut self, left: f32, right: f32) {
+        self.accumulators[VOICE_COUNT].add(left);
+        self.accumulators[VOICE_COUNT + 1].add(right);
+        let sums = &mut self.frame_correlation;
+        sums.left_right += left * right;
+        sums.left_left += left * left;
+        sums.right_right += right * right;
+    }
+
+    #[inline]
+    pub fn add_gain_reduction(&mut self, gain_reduction_db: f32) {
+        self.max_gain_reduction_db = self.max_gain_reduction_db.max(gain_reduction_db);
+    }
+
+    /// Publishes the levels measured over the last `frame_len` samples and starts a new frame
+    pub fn finish_frame(&mut self, frame_len: usize, sample_rate: f32) {
+        for (channel_ix, accumulator) in self.accumulators.iter_mut().enumerate() {
+            self.values[channel_ix * 2] = (accumulator.sum_of_squares / frame_len as f32).sqrt();
+            self.values[channel_ix * 2 + 1] = accumulator.peak;
+            *accumulator = ChannelAccumulator::default();
+        }
+
+        let weight = 1. - (-(frame_len as f32 / sample_rate) / CORRELATION_AVERAGING_SECONDS).exp();
+        let (frame, average) = (&self.frame_correlation, &mut self.average_correlation);
+        average.left_right += (frame.left_right - average.left_right) * weight;
+        average.left_left += (frame.left_left - average.left_left) * weight;
+        average.right_right += (frame.right_right - average.right_right) * weight;
+        self.frame_correlation = CorrelationSums::default();
+
+        let energy = (average.left_left * average.right_right).sqrt();
+        // Silence has no meaningful correlation; report it as unrelated
+        self.values[CORRELATION_IX] = if energy > 1e-12 {
+            (average.left_right / energy).clamp(-1., 1.)
+        } else {
+            0.
+        };
+
+        self.values[GAIN_REDUCTION_IX] = self.max_gain_reduction_db;
+        self.max_gain_reduction_db = 0.;
+    }
+
+    /// Reports silence for frames that weren't rendered
+    pub fn clear(&mut self) {
+        *self = Meters::default();
+    }
+}
+
+/// Returns a pointer to the `METER_VALUE_COUNT` levels measured over the last frame
+pub fn get_meters(ctx: *mut GranularCtx) -> *const f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.meters.values.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Returns the loudness of the master output in LUFS: 0 = momentary, 1 = short-term and
+/// 2 = integrated since the last `reset_loudness`.  Windows that haven't filled up yet read as
+/// negative infinity.
+#[cfg(feature = "loudness")]
+pub fn get_loudness(ctx: *mut GranularCtx, measurement: u32) -> f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return f32::NEG_INFINITY;
+    };
+    match measurement {
+        0 => ctx.loudness.momentary(),
+        1 => ctx.loudness.short_term(),
+        2 => ctx.loudness.integrated(),
+        _ => f32::NEG_INFINITY,
+    }
+}
+
+#[cfg(feature = "loudness")]
+pub fn reset_loudness(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.loudness.reset();
+    }
+}
+
+/// Returns the number of output samples that had to be clamped since the last `reset_clip_count`
+pub fn get_clip_count(ctx: *mut GranularCtx) -> u32 {
+    ctx_mut(ctx).map(|ctx| ctx.clip_count).unwrap_or(0)
+}
+
+pub fn reset_clip_count(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.clip_count = 0;
+    }
+}
+
+#[test]
+fn meters_report_rms_and_peak() {
+    let mut meters = Meters::default();
+    for i in 0..4 {
+        let sample = if i % 2 == 0 { 0.5 } else { -0.5 };
+        meters.add_voice_sample(1, sample);
+        meters.add_master_sample(sample * 2., 0.);
+    }
+    meters.finish_frame(4, 44100.);
+    assert_eq!(&meters.values[2..4], &[0.5, 0.5]);
+    assert_eq!(&meters.values[4..6], &[1., 1.]);
+    assert_eq!(meters.values[0], 0.);
+}
+
+#[test]
+fn correlation_tracks_channel_relationship() {
+    let mut meters = Meters::default();
+    let render = |meters: &mut Meters, right_sign: f32| {
+        for frame in 0..1000 {
+            for i in 0..128 {
+                let sample = ((frame * 128 + i) as f32 * 0.1).sin();
+                meters.add_master_sample(sample, sample * right_sign);
+            }
+            meters.finish_frame(128, 44100.);
+        }
+        meters.values[CORRELATION_IX]
+    };
+    assert!(render(&mut meters, 1.) > 0.99);
+    assert!(render(&mut meters, -1.) < -0.99);
+}
@@ -0,0 +1,370 @@
+//! MIDI input.  The host forwards raw channel messages and the engine handles them itself.  Notes
+//! and per-note expression go to `notes::NoteMode`; controllers go through a CC mapping table
+//! where each mapping routes one controller to one parameter with its own range and curve.  Like
+//! macros, mapped parameters are overridden when each frame's targets are set.  Clock and song
+//! position messages go to `midi_clock::MidiClock`.
+
+use super::macros::curved_range;
+use super::notes::TIMBRE_CC;
+use super::params::{json_number, ParamId, ParamValues};
+use super::{ctx_mut, GranularCtx};
+
+pub const CC_MAPPING_COUNT: usize = 32;
+
+/// The channel and system messages the engine understands.  Anything else is ignored.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MidiEvent {
+    NoteOff {
+        channel: u8,
+        note: u8,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    ControlChange {
+        channel: u8,
+        cc: u8,
+        value: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        value: u8,
+    },
+    /// 14-bit value, centred on 8192
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    /// 24 per beat
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    SongPosition {
+        sixteenths: u16,
+    },
+}
+
+/// Controller that releases every held note
+pub const ALL_NOTES_OFF_CC: u8 = 123;
+
+impl MidiEvent {
+    pub fn parse(status: u8, data_1: u8, data_2: u8) -> Option<MidiEvent> {
+        let channel = status & 0x0f;
+        let (data_1, data_2) = (data_1 & 0x7f, data_2 & 0x7f);
+        match status & 0xf0 {
+            0x80 => Some(MidiEvent::NoteOff {
+                channel,
+                note: data_1,
+            }),
+            0x90 => Some(MidiEvent::NoteOn {
+                channel,
+                note: data_1,
+                velocity: data_2,
+            }),
+            0xb0 => Some(MidiEvent::ControlChange {
+                channel,
+                cc: data_1,
+                value: data_2,
+            }),
+            0xd0 => Some(MidiEvent::ChannelPressure {
+                channel,
+                value: data_1,
+            }),
+            0xe0 => Some(MidiEvent::PitchBend {
+                channel,
+                value: ((data_2 as u16) << 7) | data_1 as u16,
+            }),
+            0xf0 => match status {
+                0xf2 => Some(MidiEvent::SongPosition {
+                    sixteenths: ((data_2 as u16) << 7) | data_1 as u16,
+                }),
+                0xf8 => Some(MidiEvent::Clock),
+                0xfa => Some(MidiEvent::Start),
+                0xfb => Some(MidiEvent::Continue),
+                0xfc => Some(MidiEvent::Stop),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CcMapping {
+    /// `None` responds to the controller on every channel
+    pub channel: Option<u8>,
+    pub cc: u8,
+    pub param: ParamId,
+    pub min: f32,
+    pub max: f32,
+    /// Exponent applied to the normalized CC value; see `macros::curved_range`
+    pub curve: f32,
+}
+
+impl CcMapping {
+    fn matches(&self, channel: u8, cc: u8) -> bool {
+        self.cc == cc && self.channel.is_none_or(|mapped| mapped == channel)
+    }
+}
+
+/// A mapping waiting for the next incoming CC to fill in its channel and controller number
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct PendingLearn {
+    param: ParamId,
+    min: f32,
+    max: f32,
+    curve: f32,
+}
+
+#[derive(Clone, Copy)]
+pub struct CcMap {
+    pub mappings: [Option<CcMapping>; CC_MAPPING_COUNT],
+    /// Last received value of each mapping's controller, normalized to 0-1.  Mappings don't
+    /// override anything until their controller has been moved.
+    values: [Option<f32>; CC_MAPPING_COUNT],
+    learn: Option<PendingLearn>,
+}
+
+impl Default for CcMap {
+    fn default() -> Self {
+        CcMap {
+            mappings: [None; CC_MAPPING_COUNT],
+            values: [None; CC_MAPPING_COUNT],
+            learn: None,
+        }
+    }
+}
+
+impl CcMap {
+    pub fn set_mapping(&mut self, mapping_ix: usize, mapping: Option<CcMapping>) {
+        if mapping_ix < CC_MAPPING_COUNT {
+            self.mappings[mapping_ix] = mapping;
+            self.values[mapping_ix] = None;
+        }
+    }
+
+    /// Maps the next CC that arrives onto `param`
+    pub fn begin_learn(&mut self, param: ParamId, min: f32, max: f32, curve: f32) {
+        self.learn = Some(PendingLearn {
+            param,
+            min,
+            max,
+            curve,
+        });
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learn = None;
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learn.is_some()
+    }
+
+    fn finish_learn(&mut self, learn: PendingLearn, channel: u8, cc: u8) {
+        // Learning the same controller for the same parameter again replaces the old mapping
+        let existing_ix = self.mappings.iter().position(|mapping| {
+            mapping
+                .is_some_and(|mapping| mapping.param == learn.param && mapping.matches(channel, cc))
+        });
+        let Some(mapping_ix) =
+            existing_ix.or_else(|| self.mappings.iter().position(|mapping| mapping.is_none()))
+        else {
+            return;
+        };
+        self.set_mapping(
+            mapping_ix,
+            Some(CcMapping {
+                channel: Some(channel),
+                cc,
+                param: learn.param,
+                min: learn.min,
+                max: learn.max,
+                curve: learn.curve,
+            }),
+        );
+    }
+
+    pub fn handle_cc(&mut self, channel: u8, cc: u8, value: u8) {
+        if let Some(learn) = self.learn.take() {
+            self.finish_learn(learn, channel, cc);
+        }
+
+        let normalized = value as f32 / 127.;
+        for (mapping, mapped_value) in self.mappings.iter().zip(self.values.iter_mut()) {
+            if mapping.is_some_and(|mapping| mapping.matches(channel, cc)) {
+                *mapped_value = Some(normalized);
+            }
+        }
+    }
+
+    /// Overwrites every parameter whose controller has been moved.  When several mappings share
+    /// a parameter the one in the highest slot wins.
+    pub fn apply(&self, targets: &mut ParamValues) {
+        for (mapping, value) in self.mappings.iter().zip(self.values.iter()) {
+            if let (Some(mapping), Some(value)) = (mapping, value) {
+                targets.set(
+                    mapping.param,
+                    curved_range(*value, mapping.min, mapping.max, mapping.curve),
+                );
+            }
+        }
+    }
+
+    /// Describes the mapping table as a JSON array so that hosts can store it in presets and
+    /// restore it later through `set_cc_mapping`.  A `channel` of `null` means every channel.
+    pub fn mappings_json(&self) -> String {
+        let entries: Vec<String> = self
+            .mappings
+            .iter()
+            .enumerate()
+            .filter_map(|(mapping_ix, mapping)| {
+                let mapping = mapping.as_ref()?;
+                Some(format!(
+                    "{{\"slot\":{},\"channel\":{},\"cc\":{},\"param\":{},\"min\":{},\"max\":{},\"curve\":{}}}",
+                    mapping_ix,
+                    mapping
+                        .channel
+                        .map_or("null".to_string(), |channel| channel.to_string()),
+                    mapping.cc,
+                    mapping.param.index(),
+                    json_number(mapping.min),
+                    json_number(mapping.max),
+                    json_number(mapping.curve),
+                ))
+            })
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+}
+
+/// Handles one raw MIDI channel message.  Messages the engine doesn't understand are ignored.
+pub fn handle_midi_event(ctx: *mut GranularCtx, status: u8, data_1: u8, data_2: u8) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(event) = MidiEvent::parse(status, data_1, data_2) else {
+        return;
+    };
+    match event {
+        MidiEvent::NoteOn {
+            channel,
+            note,
+            velocity,
+        } => ctx.notes.note_on(channel, note, velocity),
+        MidiEvent::NoteOff { channel, note } => ctx.notes.note_off(channel, note),
+        MidiEvent::ControlChange { channel, cc, value } => {
+            match cc {
+                ALL_NOTES_OFF_CC => ctx.notes.all_notes_off(),
+                TIMBRE_CC => ctx.notes.timbre(channel, value),
+                _ => {}
+            }
+            ctx.cc_map.handle_cc(channel, cc, value);
+        }
+        MidiEvent::ChannelPressure { channel, value } => ctx.notes.pressure(channel, value),
+        MidiEvent::PitchBend { channel, value } => ctx.notes.pitch_bend(channel, value),
+        MidiEvent::Clock => ctx.midi_clock.tick(),
+        MidiEvent::Start => ctx.midi_clock.start(),
+        MidiEvent::Continue => ctx.midi_clock.resume(),
+        MidiEvent::Stop => ctx.midi_clock.stop(),
+        MidiEvent::SongPosition { sixteenths } => ctx.midi_clock.set_position(sixteenths),
+    }
+}
+
+/// Maps a MIDI CC onto a parameter addressed by its flat index.  A `channel` above 15 responds to
+/// the controller on every channel.
+#[allow(clippy::too_many_arguments)]
+pub fn set_cc_mapping(
+    ctx: *mut GranularCtx,
+    mapping_ix: usize,
+    channel: u8,
+    cc: u8,
+    param_ix: usize,
+    min: f32,
+    max: f32,
+    curve: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(param) = ParamId::from_index(param_ix) else {
+        return;
+    };
+    if cc > 127 || !min.is_finite() || !max.is_finite() || !curve.is_finite() || curve <= 0. {
+        return;
+    }
+    ctx.cc_map.set_mapping(
+        mapping_ix,
+        Some(CcMapping {
+            channel: if channel < 16 { Some(channel) } else { None },
+            cc,
+            param,
+            min,
+            max,
+            curve,
+        }),
+    );
+}
+
+pub fn clear_cc_mapping(ctx: *mut GranularCtx, mapping_ix: usize) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.cc_map.set_mapping(mapping_ix, None);
+    }
+}
+
+/// Maps the next CC that arrives onto a parameter, using the first free mapping slot
+pub fn begin_cc_learn(ctx: *mut GranularCtx, param_ix: usize, min: f32, max: f32, curve: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(param) = ParamId::from_index(param_ix) else {
+        return;
+    };
+    if !min.is_finite() || !max.is_finite() || !curve.is_finite() || curve <= 0. {
+        return;
+    }
+    ctx.cc_map.begin_learn(param, min, max, curve);
+}
+
+pub fn cancel_cc_learn(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.cc_map.cancel_learn();
+    }
+}
+
+pub fn is_cc_learning(ctx: *mut GranularCtx) -> bool {
+    ctx_mut(ctx)
+        .map(|ctx| ctx.cc_map.is_learning())
+        .unwrap_or(false)
+}
+
+pub fn get_cc_mappings(ctx: *mut GranularCtx) -> String {
+    ctx_mut(ctx)
+        .map(|ctx| ctx.cc_map.mappings_json())
+        .unwrap_or_else(|| "[]".to_string())
+}
+
+#[test]
+fn learned_cc_drives_param() {
+    use super::params::GlobalParam;
+
+    let grain_size = ParamId::Global(GlobalParam::GrainSize);
+    let mut map = CcMap::default();
+    map.begin_learn(grain_size, 0., 1270., 1.);
+    map.handle_cc(3, 21, 0);
+    assert!(!map.is_learning());
+
+    let mut targets = ParamValues::default();
+    targets.set(grain_size, 5.);
+    map.handle_cc(4, 21, 127);
+    map.apply(&mut targets);
+    assert_eq!(targets.get(grain_size), 0.);
+
+    map.handle_cc(3, 21, 64);
+    map.apply(&mut targets);
+    assert_eq!(targets.get(grain_size), 640.);
+    assert!(map.mappings_json().contains("\"channel\":3,\"cc\":21"));
+}
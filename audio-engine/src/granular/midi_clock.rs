@@ -0,0 +1,232 @@
+//! MIDI clock sync.  External sequencers send 24 clock ticks per beat along with start, stop and
+//! continue messages, and the engine can follow them instead of the transport the host reports.
+//! Ticks reach the engine between renders, so each is timed at the start of the next frame and
+//! arrives up to a frame late.  A delay-locked loop smooths that jitter out: it predicts when the
+//! next tick is due, and corrects both the tick period, which is the tempo, and the phase of the
+//! beat by a fraction of every prediction's error, so the clock stays locked to a sequencer whose
+//! tempo drifts or changes.
+
+use super::{ctx_mut, GranularCtx};
+use std::f64::consts::{PI, SQRT_2};
+
+pub const TICKS_PER_BEAT: u32 = 24;
+/// Bandwidth of the loop as a fraction of the tick rate.  Narrower filters more jitter but
+/// follows tempo changes more slowly; this settles in about two beats.
+const LOOP_BANDWIDTH: f64 = 1. / 48.;
+/// Ticks that can go missing before the lock is dropped and the next tick starts a new one
+const MAX_MISSED_TICKS: f64 = 4.;
+
+/// Delay-locked loop state
+#[derive(Clone, Copy, Debug)]
+struct Lock {
+    /// Smoothed time of the last tick, in samples
+    tick_time: f64,
+    /// Smoothed samples between ticks
+    period: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MidiClock {
+    /// Samples rendered, which ticks are timed by
+    now: f64,
+    lock: Option<Lock>,
+    /// Time of the first tick of a new lock, until the second gives it a period
+    first_tick: Option<f64>,
+    /// Between a start or continue and a stop
+    pub running: bool,
+    /// Ticks counted since the start, the song position in ticks
+    ticks: u64,
+    /// Set by a start or continue, since the tick after one is at the song position rather than a
+    /// tick past it
+    awaiting_tick: bool,
+    /// Set when the song position jumps rather than moving on with the ticks
+    jumped: bool,
+}
+
+impl MidiClock {
+    /// Handles a clock tick (0xF8)
+    pub fn tick(&mut self) {
+        if self.running && !std::mem::take(&mut self.awaiting_tick) {
+            self.ticks += 1;
+        }
+        let now = self.now;
+        let lock = match (self.lock, self.first_tick) {
+            (Some(lock), _) if now - lock.tick_time <= MAX_MISSED_TICKS * lock.period => lock,
+            (_, Some(first_tick)) if now > first_tick => {
+                self.first_tick = None;
+                self.lock = Some(Lock {
+                    tick_time: now,
+                    period: now - first_tick,
+                });
+                return;
+            }
+            _ => {
+                // Two ticks timed at the same frame can't give a period yet
+                self.lock = None;
+                self.first_tick = Some(now);
+                return;
+            }
+        };
+        let omega = 2. * PI * LOOP_BANDWIDTH;
+        let error = now - (lock.tick_time + lock.period);
+        self.lock = Some(Lock {
+            tick_time: lock.tick_time + lock.period + SQRT_2 * omega * error,
+            period: (lock.period + omega * omega * error).max(1.),
+        });
+    }
+
+    /// Handles a start (0xFA), which plays from the top
+    pub fn start(&mut self) {
+        self.running = true;
+        self.awaiting_tick = true;
+        self.ticks = 0;
+        self.jumped = true;
+    }
+
+    /// Handles a continue (0xFB), which plays on from the song position
+    pub fn resume(&mut self) {
+        self.running = true;
+        self.awaiting_tick = true;
+        self.jumped = true;
+    }
+
+    /// Handles a stop (0xFC).  Sequencers usually keep sending ticks while stopped, so the lock is
+    /// kept.
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    /// Handles a song position pointer (0xF2) in sixteenth notes
+    pub fn set_position(&mut self, sixteenths: u16) {
+        self.ticks = sixteenths as u64 * (TICKS_PER_BEAT / 4) as u64;
+        self.jumped = true;
+    }
+
+    /// Moves the clock on past a rendered frame
+    pub fn advance(&mut self, frames: usize) {
+        self.now += frames as f64;
+    }
+
+    /// Tempo of the locked clock, or None until two ticks have arrived
+    pub fn bpm(&self, sample_rate: f32) -> Option<f32> {
+        let lock = self.lock?;
+        Some((60. * sample_rate as f64 / (lock.period * TICKS_PER_BEAT as f64)) as f32)
+    }
+
+    /// Song position of the next sample rendered in frames, at the current tempo
+    pub fn position_frames(&self) -> Option<f64> {
+        let lock = self.lock?;
+        let ticks = if self.running && !self.awaiting_tick {
+            self.ticks as f64 + (self.now - lock.tick_time) / lock.period
+        } else {
+            self.ticks as f64
+        };
+        Some(ticks.max(0.) * lock.period)
+    }
+
+    /// Whether the song position jumped since the last call
+    pub fn take_jump(&mut self) -> bool {
+        std::mem::take(&mut self.jumped)
+    }
+}
+
+/// Returns the tempo of the MIDI clock, or 0 until two clock ticks have arrived
+pub fn get_midi_clock_bpm(ctx: *mut GranularCtx) -> f32 {
+    ctx_mut(ctx)
+        .and_then(|ctx| ctx.midi_clock.bpm(ctx.sample_rate))
+        .unwrap_or(0.)
+}
+
+#[test]
+fn lock_follows_jittery_ticks() {
+    use rand::Rng;
+
+    let sample_rate = 48000.;
+    let mut clock = MidiClock::default();
+    let mut rng = crate::common::rng();
+    // Ticks at 125 BPM, 960 samples apart, each arriving at the start of the frame after it
+    let mut run = |clock: &mut MidiClock, period: f64, ticks: usize| {
+        let mut next_tick = clock.now + rng.gen_range(0.0..period);
+        for _ in 0..ticks {
+            while clock.now < next_tick {
+                clock.advance(128);
+            }
+            clock.tick();
+            next_tick += period;
+        }
+    };
+    assert_eq!(clock.bpm(sample_rate), None);
+    run(&mut clock, 960., 24 * 8);
+    let bpm = clock.bpm(sample_rate).unwrap();
+    assert!((bpm - 125.).abs() < 0.5, "{}", bpm);
+
+    // It drifts along with a tempo change, without losing the lock
+    run(&mut clock, 1000., 24 * 8);
+    let bpm = clock.bpm(sample_rate).unwrap();
+    assert!((bpm - 120.).abs() < 0.5, "{}", bpm);
+
+    // Ticks count from a start
+    clock.start();
+    assert!(clock.take_jump() && !clock.take_jump());
+    run(&mut clock, 1000., 24);
+    let beats = clock.position_frames().unwrap() / (1000. * TICKS_PER_BEAT as f64);
+    assert!((beats - 1.).abs() < 0.2, "{}", beats);
+    clock.stop();
+    clock.set_position(4);
+    assert_eq!(
+        clock.position_frames(),
+        clock.lock.map(|lock| 24. * lock.period)
+    );
+
+    // A long gap starts a new lock
+    clock.advance(48000);
+    clock.tick();
+    assert_eq!(clock.bpm(sample_rate), None);
+}
+
+#[test]
+fn midi_clock_drives_the_transport() {
+    use super::midi::handle_midi_event;
+    use super::params::{GlobalParam, ParamId};
+    use super::transport::set_transport;
+    use super::{set_param, FRAME_SIZE};
+
+    let mut ctx = GranularCtx::default();
+    let midi_clock_sync = ParamId::Global(GlobalParam::MidiClockSync);
+    assert!(set_param(&mut ctx, midi_clock_sync.index(), 1.));
+    set_transport(&mut ctx, false, 90., 0.);
+    let targets = *ctx.params.target();
+    // Ticks at 100 BPM, forwarded ahead of the frame they fall in
+    let period = 60. / 100. / TICKS_PER_BEAT as f64 * ctx.sample_rate as f64;
+    let mut next_tick = 0.;
+    let mut render = |ctx: &mut GranularCtx, frames: usize| {
+        for frame_ix in 0..frames {
+            while next_tick < ((frame_ix + 1) * FRAME_SIZE) as f64 {
+                handle_midi_event(ctx, 0xf8, 0, 0);
+                next_tick += period;
+            }
+            ctx.render(&targets);
+        }
+        next_tick -= (frames * FRAME_SIZE) as f64;
+    };
+    assert_eq!(get_midi_clock_bpm(&mut ctx), 0.);
+    render(&mut ctx, 400);
+    assert!((get_midi_clock_bpm(&mut ctx) - 100.).abs() < 0.5);
+    assert!((ctx.transport.bpm - 100.).abs() < 0.5);
+    assert!(!ctx.transport.playing);
+
+    handle_midi_event(&mut ctx, 0xfa, 0, 0);
+    render(&mut ctx, 400);
+    assert!(ctx.transport.playing);
+    let beats = ctx.transport.position_frames / ctx.transport.beats_to_frames(1., ctx.sample_rate);
+    let expected_beats = (400 * FRAME_SIZE) as f64 / (period * 24.);
+    assert!(
+        (beats - expected_beats).abs() < 0.25,
+        "{} {}",
+        beats,
+        expected_beats
+    );
+    handle_midi_event(&mut ctx, 0xfc, 0, 0);
+    ctx.render(&targets);
+    assert!(!ctx.transport.playing);
+}
@@ -5,30 +5,184 @@
 //! Several aspects of this design draw significant inspiration from the Clouds eurorack module made
 //! by Mutable Instruments and associated code which is available on Github: https://github.com/pichenettes/eurorack
 
+pub mod analysis;
+pub mod arpeggio;
+pub mod audio_rate;
+pub mod automation;
+pub mod autopan;
+pub mod capture;
+pub mod chord;
+pub mod comb;
+pub mod commands;
+pub mod compressor;
+pub mod convolution;
+pub mod corpus;
+pub mod drone;
+pub mod dry;
+pub mod duck;
+pub mod envelope;
+pub mod eq;
+pub mod freeze;
+pub mod glide;
+pub mod grains;
+pub mod groove;
+pub mod haas;
+pub mod handles;
+pub mod harmonizer;
+pub mod history;
+pub mod intervals;
+pub mod io_block;
+pub mod key;
+pub mod link;
+pub mod live;
+pub mod macros;
+pub mod meters;
+pub mod midi;
+pub mod midi_clock;
+pub mod modulation;
+pub mod native;
+pub mod notes;
+pub mod overdub;
+pub mod param_block;
+pub mod params;
+pub mod phaser;
+pub mod playback;
+pub mod profile;
+pub mod psola;
+pub mod pulsar;
+pub mod quad;
+pub mod random;
+pub mod randomize;
+pub mod recorder;
+pub mod resonator;
+pub mod sends;
+pub mod slots;
+pub mod spectral;
+pub mod spray;
+pub mod stats;
+pub mod status;
+pub mod stutter;
+pub mod tape;
+#[cfg(feature = "serde")]
+pub mod texture;
+pub mod tilt;
+pub mod trace;
+pub mod transport;
+pub mod tuning;
+pub mod vibrato;
+pub mod waveform;
+pub mod wavetable;
+pub mod window;
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::ops::Range;
+
 use crate::common;
-use crate::dsp::{clamp, filters::butterworth::ButterworthFilter, mix, read_interpolated, smooth};
-use crate::ref_static_mut;
+use crate::common::log::{self, LogLevel};
+#[cfg(feature = "loudness")]
+use crate::dsp::loudness::LoudnessMeter;
+use crate::dsp::resample::{self, ResampleQuality};
+use crate::dsp::saturation::{SaturationModel, Saturator};
+use crate::dsp::test_signal::{TestSignal, MAX_TEST_SIGNAL_SECONDS};
+use crate::dsp::{
+    clamp, decay_samples,
+    dynamics::{EnvelopeFollower, Limiter, TransientShaper},
+    filters::butterworth::ButterworthFilter,
+    mix,
+};
+use analysis::{FeatureMap, FeatureWeighting, PositionWeighting, RmsEnvelope};
+use arpeggio::Arpeggio;
+use audio_rate::AudioRateParams;
+use automation::{Automation, AutomationRecorder};
+use autopan::AutoPan;
+use capture::{GrainCapture, GrainPlayback};
+use chord::Chord;
+use comb::VoiceComb;
+use commands::{Command, CommandRing, InvalidCommand};
+use compressor::MasterCompressor;
+use convolution::MasterConvolution;
+use corpus::{Concatenative, Corpus};
+use drone::DroneMode;
+use dry::DryPlayback;
+use duck::VoiceDucking;
+use envelope::{
+    click_guard_gain, envelope_energy, envelope_mean_gain, skew_position, DensityCompensation,
+    EnvelopeParams, OverlapCompensation, VoiceEnvelope,
+};
+use eq::MasterEq;
+use freeze::VoiceFreeze;
+use glide::{Glide, GrainGlide};
+use grains::{GrainMix, Grains};
+use groove::Groove;
+use haas::HaasWidener;
+use harmonizer::VoiceHarmonizer;
+use history::ParamHistory;
+use intervals::IntervalTable;
+use io_block::IoBlock;
+use link::VoiceLink;
+use live::LiveInput;
+use macros::MacroBank;
+use meters::Meters;
+use midi::CcMap;
+use midi_clock::MidiClock;
+use modulation::{ModDestination, ModMatrix, VoiceModulation};
+use notes::{FilterEnvelope, NoteMode};
+use overdub::{Overdub, OverdubSource};
+#[cfg(shared_memory)]
+use param_block::SharedParamBlock;
+use param_block::{ParamBlock, ParamBlockError};
+#[cfg(feature = "serde")]
+use params::GranularParams;
+use params::{
+    ConfigMorph, GlobalParam, ParamId, ParamSmoother, ParamValues, VoiceParam, PARAM_COUNT,
+};
+use phaser::PhaserEffect;
+use playback::{Playback, PlaybackState};
+use profile::Profiler;
+use psola::{PitchTrack, Psola, PsolaGrain};
+use pulsar::Pulsar;
+use quad::{OutputLayout, QuadPosition, QuadSplit};
+use random::RandomPool;
+use randomize::Randomizer;
+use recorder::{Recorder, MAX_RECORDING_FRAMES};
+use resonator::VoiceResonator;
+use sends::SendBuses;
+use slots::{SampleSlots, SlotSelection};
+use spectral::SpectralGranulator;
+use spray::PitchSpray;
+use stats::GrainStats;
+use stutter::Stutter;
+use tape::MasterTape;
+use tilt::{GrainTilt, GrainTiltRange, MasterTilt};
+use trace::{GrainEventKind, GrainTrace};
+use transport::Transport;
+use tuning::{PitchSnap, Tuning};
+use vibrato::MasterVibrato;
+use waveform::{
+    ChannelSamples, CompactWaveform, ExternalWaveform, LoadOptions, MidSideChannel, Normalization,
+    RetiredWaveform, SwapTail, WaveformChannels, WaveformSources, WaveformStorage, WaveformSwap,
+    WaveformUpload, MAX_WAVEFORM_LEN,
+};
+use wavetable::{Wavetable, ROOT_FREQUENCY_HZ};
+use window::GrainWindow;
 
 const FRAME_SIZE: usize = 128;
+/// Range of `set_processing_chunk_size`.  Chunks can span several frames, e.g. for offline
+/// renders where control-rate work matters more than its timing.
+const CHUNK_SIZES: std::ops::RangeInclusive<usize> = 16..=FRAME_SIZE * 16;
+const DEFAULT_SAMPLE_RATE: f32 = 44100.;
+/// Sample rates the engine accepts; higher ones would need absurdly large analysis buffers
+const SAMPLE_RATES: std::ops::RangeInclusive<f32> = 1000.0..=768_000.0;
+/// Absolute limit on output sample values no matter how the gains are staged
+const OUTPUT_CEILING: f32 = 1.;
+/// Gain reduction below which the limiter isn't considered to be doing anything
+const LIMITING_ACTIVE_GAIN: f32 = 0.99;
+/// Longest offline render in frames, as long as the longest recording
+const MAX_OFFLINE_FRAMES: usize = MAX_RECORDING_FRAMES / FRAME_SIZE;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct ReverseState {
     pub grain_is_reversed: bool,
-    pub grain_movement_is_reversed: bool,
-}
-
-impl Default for ReverseState {
-    fn default() -> Self {
-        ReverseState {
-            grain_is_reversed: false,
-            grain_movement_is_reversed: false,
-        }
-    }
-}
-
-static mut SCRATCH: [(f32, f32); 8192] = [(0.0, 0.0); 8192];
-fn scratch() -> &'static mut [(f32, f32); 8192] {
-    ref_static_mut!(SCRATCH)
 }
 
 #[derive(Clone)]
@@ -37,9 +191,104 @@ pub struct GranularVoice {
     pub cur_grain_start: f32,
     pub reversed: ReverseState,
     filter: ButterworthFilter,
-    filter_cutoff: f32,
-    pub grains: Vec<Grain>,
+    /// Filters the right channel of stereo waveforms
+    filter_right: ButterworthFilter,
+    pub grains: Grains,
     pub samples_since_last_grain: f32,
+    /// Cleared while the instance is shutting down so that existing grains can play out
+    pub spawning_enabled: bool,
+    /// Beats between grains when spawning is synced to the host's tempo
+    pub sync_beats: Option<f32>,
+    /// Beats of the host's tempo each grain lasts, replacing the grain size parameter
+    pub size_sync_beats: Option<f32>,
+    /// Length of `size_sync_beats` at the current tempo, in samples
+    synced_grain_size: Option<f32>,
+    /// Gain of the grain about to spawn on the groove's step, and otherwise 1
+    onset_gain: f32,
+    /// Reads the selection as if the waveform were reversed, for layering backwards textures over
+    /// forwards ones
+    pub reversed_source: bool,
+    /// Makes grains that run past the end of the selection continue from its start instead of
+    /// reading the audio after it, for loop-style granulation.  Follows the voice's
+    /// `WrapsSelection` parameter.
+    pub wraps_selection: bool,
+    /// Overrides of the built-in grain envelope shape.  Changes go through `envelope_mut` so
+    /// that the window table is rebuilt.
+    pub envelope: VoiceEnvelope,
+    /// Table of the envelope that grains read
+    window: GrainWindow,
+    /// Output samples between the voice's grains as of the last sample, for scaling overlap-add
+    /// output by density
+    pub grain_interval: f32,
+    pub density_compensation: DensityCompensation,
+    /// Followed while the voice's `OverlapCompensation` parameter is on
+    pub overlap_compensation: OverlapCompensation,
+    pub auto_pan: AutoPan,
+    pub filter_envelope: FilterEnvelope,
+    /// Signal the voice granulates in mid/side mode, set by the `MidSide` parameter.  It only
+    /// applies to stereo waveforms, and replaces the voice's pan while it does.
+    pub mid_side: Option<MidSideChannel>,
+    /// Set while the voice is frozen, capturing its output or looping the capture
+    pub freeze: Option<VoiceFreeze>,
+    /// Set while the voice plays a pulsar train, which replaces its grain size and spacing
+    pub pulsar: Option<Pulsar>,
+    /// Set while the voice plays a region of the waveform as a wavetable instead of grains
+    pub wavetable: Option<Wavetable>,
+    /// Set while the voice granulates FFT frames instead of grains
+    pub spectral: Option<SpectralGranulator>,
+    /// Set while the voice's output excites a tuned resonator before its filter
+    pub resonator: Option<VoiceResonator>,
+    /// Set while the voice's output goes through a comb filter, after its resonator
+    pub comb: Option<VoiceComb>,
+    /// Set while the voice's output goes through a phaser, after its comb filter
+    pub phaser: Option<PhaserEffect>,
+    /// Set while the voice adds pitch-shifted copies of its output, after its phaser
+    pub harmonizer: Option<VoiceHarmonizer>,
+    /// Start of the corpus segment matched for the grain about to spawn in concatenative mode,
+    /// replacing the read head and start randomness
+    pub matched_start: Option<f32>,
+    /// Set while the voice granulates pitch-synchronously
+    pub psola: Option<Psola>,
+    /// Grain cut at the source's pitch marks for the grain about to spawn in PSOLA mode
+    pub psola_grain: Option<PsolaGrain>,
+    /// Read head behind the write head of the live input history, replacing the voice's own
+    /// while live input is on
+    pub live_read_head: Option<f32>,
+    /// Largest random detune of each new grain, in cents either way
+    pub grain_detune_cents: f32,
+    /// Ratios that new grains are transposed by instead of the random detune
+    pub pitch_intervals: Option<IntervalTable>,
+    /// Set while new grains are transposed by a random offset folded into an octave range
+    pub pitch_spray: Option<PitchSpray>,
+    /// Set while the voice spawns a cluster of grains on every trigger
+    pub chord: Option<Chord>,
+    /// Set while the voice's grains walk through a sequence of transpositions
+    pub arpeggio: Option<Arpeggio>,
+    /// Set while each of the voice's grains slides to its pitch from the last grain's
+    pub grain_glide: Option<GrainGlide>,
+    /// Set while each of the voice's grains is tilted darker or brighter by a random amount
+    pub grain_tilt: Option<GrainTiltRange>,
+    /// Set while the voice's grains play from sample slots instead of the waveform
+    pub slot_selection: Option<SlotSelection>,
+    /// Depth of the voice's grains between the front and rear pairs of quad output
+    pub quad: QuadPosition,
+    /// Depth of the voice's output for the current sample, the average of its grains' weighted
+    /// by their envelopes
+    grain_depth: f32,
+    /// Source of the voice's random start offsets and spawn decisions
+    pub rng: RandomPool,
+}
+
+/// What decides when a voice spawns its next grain
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GrainClock {
+    /// Every `SamplesBetweenGrains` samples
+    Free,
+    /// Every `interval` samples, used for pulsar trains and for tempo-synced voices while the
+    /// transport is stopped
+    FixedInterval(f32),
+    /// On the beat grid of the host's transport, with grid lines `interval` samples apart
+    Grid { trigger: bool, interval: f32 },
 }
 
 impl Default for GranularVoice {
@@ -48,9 +297,47 @@ impl Default for GranularVoice {
             cur_grain_start: 0.0,
             reversed: ReverseState::default(),
             filter: ButterworthFilter::default(),
-            filter_cutoff: 0.,
-            grains: Vec::with_capacity(128),
+            filter_right: ButterworthFilter::default(),
+            grains: Grains::with_capacity(128),
             samples_since_last_grain: 0.,
+            spawning_enabled: true,
+            sync_beats: None,
+            size_sync_beats: None,
+            synced_grain_size: None,
+            onset_gain: 1.,
+            reversed_source: false,
+            wraps_selection: false,
+            envelope: VoiceEnvelope::default(),
+            window: GrainWindow::default(),
+            grain_interval: 0.,
+            density_compensation: DensityCompensation::default(),
+            overlap_compensation: OverlapCompensation::default(),
+            auto_pan: AutoPan::default(),
+            filter_envelope: FilterEnvelope::default(),
+            mid_side: None,
+            freeze: None,
+            pulsar: None,
+            wavetable: None,
+            spectral: None,
+            resonator: None,
+            comb: None,
+            phaser: None,
+            harmonizer: None,
+            matched_start: None,
+            psola: None,
+            psola_grain: None,
+            live_read_head: None,
+            grain_detune_cents: 0.,
+            pitch_intervals: None,
+            pitch_spray: None,
+            chord: None,
+            arpeggio: None,
+            grain_glide: None,
+            grain_tilt: None,
+            slot_selection: None,
+            quad: QuadPosition::default(),
+            grain_depth: 0.,
+            rng: RandomPool::new(common::rng()),
         }
     }
 }
@@ -62,8 +349,23 @@ pub struct Grain {
     pub start_sample_ix: f32,
     pub samples_read_so_far: f32,
     pub sample_playback_ratio: f32,
-    pub linear_slope_length: f32,
-    pub slope_linearity: f32,
+    /// Gain of the grain's interval in chord mode, and otherwise 1
+    pub gain: f32,
+    /// Set for grains spawned before a waveform swap, which read the retired waveform
+    pub retired: bool,
+    /// Plays the grain from its end, on top of the voice's `ReverseState::grain_is_reversed`
+    pub reversed: bool,
+    /// Part of the waveform the grain loops within, set for grains of voices that wrap at the
+    /// selection
+    pub wrap: Option<Range<usize>>,
+    /// From -1 at the front of quad output to 1 at the back
+    pub depth: f32,
+    /// Set while the grain's playback ratio slides to its pitch
+    pub glide: Option<Glide>,
+    /// Set for grains of voices that tilt each grain darker or brighter
+    pub tilt: Option<GrainTilt>,
+    /// Sample slot the grain reads instead of the waveform
+    pub slot: Option<u32>,
 }
 
 impl Grain {
@@ -83,7 +385,7 @@ impl Grain {
     ///
     /// `slope_linearity` determines the envelope mix between the linear slope determined by
     /// `linear_slope_length` and the sine.  It's a simple 0 to 1 mix between the linear slope and
-    /// the sine.
+    /// the sine, where 0 is fully linear and 1 is fully sine.
     fn get_volume(pos_in_grain: f32, linear_slope_length: f32, slope_linearity: f32) -> f32 {
         let linear_slope = Self::compute_linear_envelope_volume(pos_in_grain, linear_slope_length);
         let sine_slope = (pos_in_grain * std::f32::consts::PI).sin();
@@ -94,67 +396,633 @@ impl Grain {
     /// consumed
     pub fn tick(&mut self) -> bool {
         self.samples_read_so_far += self.sample_playback_ratio;
+        if let Some(glide) = &mut self.glide {
+            if !glide.tick(&mut self.sample_playback_ratio) {
+                self.glide = None;
+            }
+        }
         self.samples_read_so_far < self.len_samples
     }
 
+    /// Gain of the click guard at the grain's current position; see `envelope::click_guard_gain`
+    fn click_guard_gain(&self) -> f32 {
+        click_guard_gain(
+            self.samples_read_so_far / self.sample_playback_ratio,
+            (self.len_samples - self.samples_read_so_far) / self.sample_playback_ratio,
+        )
+    }
+
+    /// Absolute index in the buffer of the next sample this grain reads
+    #[inline]
+    fn read_position(&self, is_reversed: bool) -> f32 {
+        let sample_ix = if is_reversed {
+            self.len_samples - self.samples_read_so_far
+        } else {
+            self.samples_read_so_far
+        };
+        self.start_sample_ix + sample_ix
+    }
+
+    /// Next sample this grain reads from `buf`, wrapping within its loop region if it has one
+    #[inline]
+    fn read(&self, buf: ChannelSamples, is_reversed: bool) -> f32 {
+        Self::read_at(buf, self.wrap.as_ref(), self.read_position(is_reversed))
+    }
+
+    /// Sample at `position` in `buf`, wrapping within `wrap` if the grain loops
+    #[inline]
+    fn read_at(buf: ChannelSamples, wrap: Option<&Range<usize>>, position: f32) -> f32 {
+        match wrap {
+            Some(wrap) => buf.read_wrapped(wrap.clone(), position),
+            None => buf.read(position),
+        }
+    }
+
+    /// Envelope gain at `pos_in_grain` of a grain of a voice with the given envelope settings
+    fn envelope_gain(pos_in_grain: f32, envelope: &VoiceEnvelope, params: EnvelopeParams) -> f32 {
+        let pos_in_grain = skew_position(pos_in_grain, params.skew);
+        let ripple_gain = match envelope.ripple {
+            Some(ripple) if !envelope.overlap_add => ripple.gain_at(pos_in_grain),
+            _ => 1.,
+        };
+        ripple_gain
+            * envelope.gain_at(pos_in_grain, params).unwrap_or_else(|| {
+                Self::get_volume(
+                    pos_in_grain,
+                    params.linear_slope_length,
+                    params.slope_linearity,
+                )
+            })
+    }
+
+    /// The envelope gain and next sample of this grain.  The gain comes from `window` if there's
+    /// a table up to date for `envelope_params`.
+    #[inline]
     pub fn sample(
         &self,
-        buf: &[f32],
+        buf: ChannelSamples,
         is_reversed: bool,
-        linear_slope_length: f32,
-        slope_linearity: f32,
+        envelope: &VoiceEnvelope,
+        envelope_params: EnvelopeParams,
+        window: Option<&GrainWindow>,
     ) -> (f32, f32) {
         let pos_in_grain = self.samples_read_so_far / self.len_samples;
-        let sample_ix = if is_reversed {
-            self.len_samples - self.samples_read_so_far
-        } else {
-            self.samples_read_so_far
+        let gain = match window {
+            Some(window) => window.gain_at(pos_in_grain),
+            None => Self::envelope_gain(pos_in_grain, envelope, envelope_params),
         };
+        (gain, self.read(buf, is_reversed))
+    }
+}
+
+/// Linear fade of the whole output to silence, used by `begin_shutdown` and faded `reset`s
+#[derive(Clone, Copy)]
+pub struct OutputFade {
+    pub gain: f32,
+    /// Amount the gain decreases by every sample
+    pub step: f32,
+}
+
+impl OutputFade {
+    pub fn new(fade_ms: f32, sample_rate: f32) -> Self {
+        let fade_samples = (fade_ms.max(0.) * sample_rate / 1000.).max(1.);
+        OutputFade {
+            gain: 1.,
+            step: 1. / fade_samples,
+        }
+    }
+
+    /// Advances the fade by one sample and returns the gain to apply
+    #[inline]
+    pub fn tick(&mut self) -> f32 {
+        self.gain = (self.gain - self.step).max(0.);
+        self.gain
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.gain <= 0.
+    }
+}
 
-        let gain = Self::get_volume(pos_in_grain, linear_slope_length, slope_linearity);
-        let sample = read_interpolated(buf, self.start_sample_ix + sample_ix);
-        (gain, sample)
+/// One sample of output: the mono mix plus the panned stereo mix
+#[derive(Clone, Copy, Default)]
+pub struct OutputSample {
+    pub mono: f32,
+    pub left: f32,
+    pub right: f32,
+    /// Left and right contribution of each voice to `left` and `right`
+    pub voices: [(f32, f32); params::VOICE_COUNT],
+    /// Front left, front right, rear left and rear right parts of `left` and `right` before the
+    /// master effects, which quad output is split by
+    pub quad_parts: [f32; 4],
+}
+
+impl OutputSample {
+    fn scale(self, gain: f32) -> Self {
+        OutputSample {
+            mono: self.mono * gain,
+            left: self.left * gain,
+            right: self.right * gain,
+            voices: self.voices.map(|(left, right)| (left * gain, right * gain)),
+            quad_parts: self.quad_parts.map(|part| part * gain),
+        }
     }
 }
 
+/// Left and right gains for a pan position from -1 (hard left) to 1 (hard right).  A centered
+/// voice is at unity gain on both sides so that the stereo mix matches the mono one.
+fn pan_gains(pan: f32) -> (f32, f32) {
+    let pan = clamp(-1., 1., pan);
+    ((1. - pan).min(1.), (1. + pan).min(1.))
+}
+
+/// State of one engine instance.  It's `Send`, so an instance created on one thread can be
+/// rendered on another, e.g. by a worker pool, but it isn't shared: every call takes it mutably,
+/// so only one thread uses it at a time.  Instances keep no state outside of themselves apart from
+/// the log level, so any number of them can render on different threads at once.
+#[derive(Clone)]
 pub struct GranularCtx {
+    /// The loaded waveform, or its left channel if it's stereo
     pub waveform: Vec<f32>,
-    /// The offset from `cur_grain_start` at which the latest sample will be read
-    pub cur_sample_offset: f32,
+    /// Right channel of a stereo waveform, the same length as `waveform`
+    pub waveform_right: Option<Vec<f32>>,
+    /// The loaded waveform in 16-bit storage, in which case `waveform` is empty and
+    /// `waveform_right` is `None`
+    pub compact_waveform: Option<CompactWaveform>,
     pub rendered_output: [f32; FRAME_SIZE],
+    /// Planar stereo output: `FRAME_SIZE` left samples followed by `FRAME_SIZE` right samples
+    pub rendered_output_stereo: [f32; FRAME_SIZE * 2],
+    /// Planar stereo output of each voice, laid out like `rendered_output_stereo`.  These are
+    /// taken after master gain, fades and limiting but before the final clamp, so they add up to
+    /// the stereo output as long as it doesn't clip and no voice sends to the effect buses.
+    pub rendered_voice_outputs: [[f32; FRAME_SIZE * 2]; params::VOICE_COUNT],
+    /// Planar quad output, rendered while the output layout is quad: `FRAME_SIZE` samples each of
+    /// front left, front right, rear left and rear right
+    pub rendered_output_quad: [f32; FRAME_SIZE * 4],
+    /// Which output renders return
+    pub output_layout: OutputLayout,
+    quad_split: QuadSplit,
     pub voices: [GranularVoice; 2],
-    pub last_start_sample_ix: f32,
-    pub last_end_sample_ix: f32,
-    pub last_grain_size: f32,
+    pub sample_rate: f32,
+    /// Smoothed values of all render parameters; `params.current` is what the voices read
+    pub params: ParamSmoother,
+    /// Samples between runs of the control-rate work, i.e. control-rate parameter steps and
+    /// automation lanes and the voices' window tables and density compensation.  Only set
+    /// through `set_processing_chunk_size`, which keeps it in `CHUNK_SIZES`.
+    chunk_size: usize,
+    /// Samples rendered since the current chunk began
+    chunk_pos: usize,
+    /// Bitfield of `status` flags describing the last rendered frame
+    pub status: u32,
+    /// Status of the frame before, so that the log only gets a message when it changes
+    logged_status: u32,
+    pub shutdown: Option<OutputFade>,
+    /// Samples left until the output goes silent on its own during a shutdown, counted down from
+    /// `tail_frames`, or None if it doesn't before the fade ends
+    shutdown_tail: Option<usize>,
+    /// Fade in progress before a `reset` is carried out
+    pub pending_reset: Option<OutputFade>,
+    /// Optional gain reduction stage ahead of the hard output clamp
+    pub limiter: Limiter,
+    pub limiter_enabled: bool,
+    /// Samples that exceeded the output ceiling and were clamped since the count was last reset
+    pub clip_count: u32,
+    pub modulation: ModMatrix,
+    pub macros: MacroBank,
+    pub cc_map: CcMap,
+    pub notes: NoteMode,
+    /// Scala tuning that notes and snapping PSOLA voices follow instead of equal temperament
+    pub tuning: Option<Tuning>,
+    /// Snaps every grain's transposition to the tuning, or to semitones without one.  Follows the
+    /// `GrainPitchSnap` parameter.
+    pub snap_grain_pitch: bool,
+    pub transport: Transport,
+    /// Timing and gain offsets of tempo-synced grain onsets on each step of the bar
+    pub groove: Option<Groove>,
+    pub midi_clock: MidiClock,
+    /// Clip-style starting, stopping and seeking of the read heads
+    pub playback: Playback,
+    pub automation: Automation,
+    /// Lanes recorded from the parameter targets of rendered frames while recording is on
+    pub automation_recorder: AutomationRecorder,
+    /// Parameters the host supplies a value per sample for, which override automation
+    pub audio_rate: AudioRateParams,
+    /// Timed commands queued by the host, allocated the first time it asks for the ring
+    pub commands: Option<CommandRing>,
+    /// Packed block of the host's parameters, events, inputs and outputs, allocated the first
+    /// time it asks for it
+    pub io_block: Option<IoBlock>,
+    /// Selection set by a `SetSelection` command, which overrides the one in the targets
+    pub command_selection: Option<(f32, f32)>,
+    /// Targets passed to the frame being rendered, so commands can work out new ones mid-frame
+    host_targets: ParamValues,
+    pub meters: Meters,
+    pub grain_stats: GrainStats,
+    pub profiler: Profiler,
+    #[cfg(feature = "loudness")]
+    pub loudness: LoudnessMeter,
+    /// Audio written by the host ahead of each render to be granulated live, overdubbed or
+    /// stuttered.  Cleared once it's been consumed so that stale audio isn't looped.
+    pub audio_input: [f32; FRAME_SIZE],
+    /// Audio written by the host ahead of each render that's only listened to, by
+    /// `ModSourceKind::Sidechain` and a keyed master compressor, and never heard.  Cleared like
+    /// `audio_input`.
+    pub sidechain_input: [f32; FRAME_SIZE],
+    pub sidechain_follower: EnvelopeFollower,
+    /// RMS envelope of the waveform and how strongly it biases randomized grain positions.  The
+    /// envelope is dropped whenever a new waveform is loaded.
+    pub position_weighting: PositionWeighting,
+    /// Spectral features of the waveform and how strongly they gate grain spawning.  Also dropped
+    /// whenever a new waveform is loaded.
+    pub feature_weighting: FeatureWeighting,
+    /// Segment database of the waveform and the target its grains are matched to in
+    /// concatenative mode.  The database is dropped whenever a new waveform is loaded.
+    pub concatenative: Concatenative,
+    /// Pitch of the waveform over time for PSOLA voices, also dropped whenever a new waveform is
+    /// loaded
+    pub pitch_track: PitchTrack,
+    pub grain_trace: GrainTrace,
+    /// Waveform being streamed in to replace `waveform` once it's complete
+    pub waveform_upload: Option<WaveformUpload>,
+    pub waveform_swap: WaveformSwap,
+    pub load_options: LoadOptions,
+    /// Host memory that grains read instead of `waveform` while it's bound
+    pub external_waveform: Option<ExternalWaveform>,
+    /// Generation handed out by the most recent `bind_external_waveform`
+    external_generation: u32,
+    /// Number of samples at the start of the waveform the host has declared written.  While set,
+    /// renders whose selection reaches past it are rejected instead of repaired.
+    pub waveform_valid_len: Option<usize>,
+    /// Length of the buffer handed out by `get_granular_waveform_ptr` until the host commits it
+    pub uncommitted_len: Option<usize>,
+    /// Set while the two voices play as a linked stereo pair
+    pub voice_link: Option<VoiceLink>,
+    /// Set while the first voice ducks the second
+    pub voice_ducking: Option<VoiceDucking>,
+    /// Set while drone mode overrides the grain parameters
+    pub drone: Option<DroneMode>,
+    /// Parameter snapshots A and B, which the `SceneMorph` parameter crossfades between once both
+    /// are stored
+    pub scenes: [Option<ParamValues>; 2],
+    /// Parameter states the host committed, for undo and redo
+    pub param_history: ParamHistory,
+    /// Parameter locks and generator of `randomize`
+    pub randomizer: Randomizer,
+    /// Set while the host is crossfading between two configurations, which then replace the
+    /// targets it passes to each render
+    pub config_morph: Option<ConfigMorph>,
+    pub sends: SendBuses,
+    pub dry: DryPlayback,
+    /// Per-voice values that `render_granular` reads, written in place by the host through
+    /// `get_voice_render_params_ptr`.  Entries that aren't finite keep the current target.
+    pub voice_render_params: [[f32; params::VOICE_COUNT]; VOICE_RENDER_PARAMS.len()],
+    /// Packed parameters written by the host, allocated the first time it asks for the block
+    pub param_block: Option<ParamBlock>,
+    /// Packed parameters written by the host from another thread, allocated like `param_block`
+    #[cfg(shared_memory)]
+    pub shared_param_block: Option<SharedParamBlock>,
+    pub recorder: Recorder,
+    /// Interleaved stereo output of the last `render_next_chunk`, reused across chunks
+    pub offline_chunk: Vec<f32>,
+    /// Copy of the instance a chunked offline render runs on, set by `start_chunked_render`
+    pub offline_render: Option<Box<GranularCtx>>,
+    /// Set while output or input is being overdubbed into the waveform
+    pub overdub: Option<Overdub>,
+    /// Set while the stutter effect is on, keeping the history it repeats
+    pub stutter: Option<Stutter>,
+    /// Set while the pitch of the master output wavers, after the stutter
+    pub master_vibrato: Option<MasterVibrato>,
+    /// Set while the master output goes through tape wow, flutter, saturation and hiss, after its
+    /// vibrato
+    pub master_tape: Option<MasterTape>,
+    /// Set while the master output goes through a phaser, after its tape
+    pub master_phaser: Option<PhaserEffect>,
+    /// Set while the master output is tilted darker or brighter, after its phaser
+    pub master_tilt: Option<MasterTilt>,
+    /// Set while the master output goes through the three-band EQ, after its tilt
+    pub master_eq: Option<MasterEq>,
+    /// Set while the master output's attacks and tails are shaped, after its EQ
+    pub master_transients: Option<TransientShaper>,
+    /// Set while the master output is compressed, after its shaper
+    pub master_compressor: Option<MasterCompressor>,
+    /// Left, right and mono saturators, set while the master output is saturated after its
+    /// compressor
+    pub master_saturation: Option<[Saturator; 3]>,
+    /// Set while one channel of the master output is delayed to widen it, after its saturation
+    pub master_haas: Option<HaasWidener>,
+    /// Set while the master output is convolved with an impulse response, after its widener
+    pub master_convolution: Option<MasterConvolution>,
+    /// Impulse response the host is writing, until it's loaded
+    pub impulse_response_upload: Option<Vec<f32>>,
+    /// Set while the waveform is a loop of the live input
+    pub live_input: Option<LiveInput>,
+    pub grain_capture: GrainCapture,
+    pub sample_slots: SampleSlots,
 }
 
+const _: () = {
+    const fn assert_send<T: Send>() {}
+    assert_send::<GranularCtx>();
+};
+
 impl Default for GranularCtx {
     fn default() -> Self {
         GranularCtx {
             waveform: Vec::new(),
-            cur_sample_offset: 0.0,
+            waveform_right: None,
+            compact_waveform: None,
             rendered_output: [0.0; FRAME_SIZE],
+            rendered_output_stereo: [0.0; FRAME_SIZE * 2],
+            rendered_voice_outputs: [[0.0; FRAME_SIZE * 2]; params::VOICE_COUNT],
+            rendered_output_quad: [0.0; FRAME_SIZE * 4],
+            output_layout: OutputLayout::default(),
+            quad_split: QuadSplit::new(DEFAULT_SAMPLE_RATE),
             voices: [GranularVoice::default(), GranularVoice::default()],
-            last_start_sample_ix: -10.0,
-            last_end_sample_ix: -10.0,
-            last_grain_size: 800.,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            params: ParamSmoother::new(DEFAULT_SAMPLE_RATE),
+            chunk_size: FRAME_SIZE,
+            chunk_pos: 0,
+            status: 0,
+            logged_status: 0,
+            shutdown: None,
+            shutdown_tail: None,
+            pending_reset: None,
+            limiter: Limiter::new(OUTPUT_CEILING, 100., DEFAULT_SAMPLE_RATE),
+            limiter_enabled: false,
+            clip_count: 0,
+            modulation: ModMatrix::default(),
+            macros: MacroBank::default(),
+            cc_map: CcMap::default(),
+            notes: NoteMode::default(),
+            tuning: None,
+            snap_grain_pitch: false,
+            transport: Transport::default(),
+            groove: None,
+            midi_clock: MidiClock::default(),
+            playback: Playback::default(),
+            automation: Automation::default(),
+            automation_recorder: AutomationRecorder::default(),
+            audio_rate: AudioRateParams::default(),
+            commands: None,
+            io_block: None,
+            command_selection: None,
+            host_targets: ParamValues::default(),
+            meters: Meters::default(),
+            grain_stats: GrainStats::default(),
+            profiler: Profiler::default(),
+            #[cfg(feature = "loudness")]
+            loudness: LoudnessMeter::new(DEFAULT_SAMPLE_RATE, FRAME_SIZE),
+            audio_input: [0.; FRAME_SIZE],
+            sidechain_input: [0.; FRAME_SIZE],
+            sidechain_follower: EnvelopeFollower::new(5., 150., DEFAULT_SAMPLE_RATE),
+            position_weighting: PositionWeighting::default(),
+            feature_weighting: FeatureWeighting::default(),
+            concatenative: Concatenative::default(),
+            drone: None,
+            pitch_track: PitchTrack::default(),
+            grain_trace: GrainTrace::default(),
+            waveform_upload: None,
+            waveform_swap: WaveformSwap::default(),
+            load_options: LoadOptions::default(),
+            external_waveform: None,
+            external_generation: 0,
+            waveform_valid_len: None,
+            uncommitted_len: None,
+            voice_link: None,
+            voice_ducking: None,
+            config_morph: None,
+            scenes: [None; 2],
+            param_history: ParamHistory::default(),
+            randomizer: Randomizer::new(common::rng()),
+            sends: SendBuses::default(),
+            dry: DryPlayback::default(),
+            voice_render_params: [[f32::NAN; params::VOICE_COUNT]; VOICE_RENDER_PARAMS.len()],
+            param_block: None,
+            #[cfg(shared_memory)]
+            shared_param_block: None,
+            recorder: Recorder::default(),
+            offline_chunk: Vec::new(),
+            offline_render: None,
+            overdub: None,
+            stutter: None,
+            master_vibrato: None,
+            master_tape: None,
+            master_phaser: None,
+            master_tilt: None,
+            master_eq: None,
+            master_transients: None,
+            master_compressor: None,
+            master_saturation: None,
+            master_haas: None,
+            master_convolution: None,
+            impulse_response_upload: None,
+            live_input: None,
+            grain_capture: GrainCapture::default(),
+            sample_slots: SampleSlots::default(),
         }
     }
 }
 
-fn normalize_gain(samples_and_gains: &[(f32, f32)], total_gain: f32) -> f32 {
-    let gain_multiplier = if total_gain < 1. {
+/// Gain that grains summed at `total_gain` are mixed down at, so that overlapping grains don't
+/// get louder than a single one
+#[inline]
+fn normalization_gain(total_gain: f32) -> f32 {
+    if total_gain < 1. {
         1.
     } else {
         1. / (total_gain + 0.001) // Maybe this should be scaled differently
-    };
-
-    samples_and_gains.iter().fold(0.0, |acc, (sample, gain)| {
-        acc + sample * gain * gain_multiplier
-    })
+    }
 }
 
 impl GranularVoice {
+    /// Scale that keeps the sum of overlapping Hann windows at unity: they add up to half the
+    /// number of grains overlapping at any time.  Sparse grains that don't overlap aren't boosted.
+    fn overlap_add_scale(&self) -> f32 {
+        (2. / self.overlap()).min(1.)
+    }
+
+    /// Average number of grains playing at once going by the newest grain's duration and the
+    /// current spacing, or 1 if there are no grains
+    fn overlap(&self) -> f32 {
+        match self.grains.last() {
+            Some(grain) if self.grain_interval > 0. => {
+                grain.len_samples / grain.sample_playback_ratio / self.grain_interval
+            }
+            _ => 1.,
+        }
+    }
+
+    /// Slope shape and skew of the voice's grain envelopes for the current sample
+    fn envelope_params(
+        &self,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        voice_ix: usize,
+    ) -> EnvelopeParams {
+        let (linear_slope_length, slope_linearity) = if self.envelope.own_slope_shape {
+            (
+                params.voice(voice_ix, VoiceParam::SlopeLength),
+                params.voice(voice_ix, VoiceParam::SlopeLinearity),
+            )
+        } else {
+            (
+                params.global(GlobalParam::LinearSlopeLength),
+                params.global(GlobalParam::SlopeLinearity),
+            )
+        };
+        // Skewing the window would break its constant overlap-add property
+        let skew = if self.envelope.overlap_add {
+            0.
+        } else {
+            clamp(
+                -1.,
+                1.,
+                params.voice(voice_ix, VoiceParam::EnvelopeSkew)
+                    + modulation.get(ModDestination::EnvelopeSkew),
+            )
+        };
+        let morph = clamp(
+            0.,
+            1.,
+            params.voice(voice_ix, VoiceParam::EnvelopeMorph)
+                + modulation.get(ModDestination::EnvelopeMorph),
+        );
+        EnvelopeParams {
+            linear_slope_length,
+            slope_linearity,
+            skew,
+            morph,
+        }
+    }
+
+    /// The voice's envelope settings, for changing them
+    pub fn envelope_mut(&mut self) -> &mut VoiceEnvelope {
+        self.window.invalidate();
+        &mut self.envelope
+    }
+
+    /// Rebuilds the window table if the envelope has changed.  Runs once per frame.
+    fn update_window(
+        &mut self,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        voice_ix: usize,
+    ) {
+        let envelope_params = self.envelope_params(params, modulation, voice_ix);
+        let envelope = &self.envelope;
+        self.window.update(envelope_params, |pos_in_grain| {
+            Grain::envelope_gain(pos_in_grain, envelope, envelope_params)
+        });
+    }
+
+    /// Measures the energy of the voice's current envelope and updates the density compensation
+    /// target.  Runs once per frame since the measurement samples the whole envelope.
+    fn update_density_compensation(
+        &mut self,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        voice_ix: usize,
+    ) {
+        if !self.density_compensation.enabled {
+            return;
+        }
+        let envelope_params = self.envelope_params(params, modulation, voice_ix);
+        let energy = envelope_energy(|pos_in_grain| {
+            Grain::envelope_gain(pos_in_grain, &self.envelope, envelope_params)
+        });
+        let overlap = self.overlap();
+        self.density_compensation.set_overlap(overlap, energy);
+    }
+
+    /// Measures the mean gain of the voice's current envelope and updates the overlap
+    /// compensation target, once per processing chunk while the compensation is on
+    fn update_overlap_compensation(
+        &mut self,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        voice_ix: usize,
+    ) {
+        if params.voice(voice_ix, VoiceParam::OverlapCompensation) <= 0. {
+            return;
+        }
+        let envelope_params = self.envelope_params(params, modulation, voice_ix);
+        let mean_gain = envelope_mean_gain(|pos_in_grain| {
+            Grain::envelope_gain(pos_in_grain, &self.envelope, envelope_params)
+        });
+        let overlap = self.overlap();
+        self.overlap_compensation.set_overlap(overlap, mean_gain);
+    }
+
+    /// How long the voice's output goes on if it stops spawning grains now: until its longest
+    /// grain or spectral frame ends, and then its resonator, comb and harmonies ring out
+    fn tail_samples(&self, sample_rate: f32) -> f32 {
+        let grains = self.grains.iter().fold(0f32, |tail, grain| {
+            let remaining = (grain.len_samples - grain.samples_read_so_far).max(0.);
+            tail.max(remaining / grain.sample_playback_ratio.max(f32::EPSILON))
+        });
+        let spectral = self
+            .spectral
+            .as_ref()
+            .map_or(0., |spectral| spectral.settings.frame_len as f32);
+        let resonator = self
+            .resonator
+            .as_ref()
+            .map_or(0., |resonator| resonator.tail_samples(sample_rate));
+        let comb = self
+            .comb
+            .as_ref()
+            .map_or(0., |comb| comb.tail_samples(sample_rate));
+        let harmonies = self
+            .harmonizer
+            .as_ref()
+            .map_or(0., VoiceHarmonizer::tail_samples);
+        grains.max(spectral) + resonator + comb + harmonies
+    }
+
+    pub fn reset(&mut self) {
+        self.grains.clear();
+        self.filter.reset();
+        self.filter_right.reset();
+        if let Some(resonator) = &mut self.resonator {
+            resonator.clear();
+        }
+        if let Some(comb) = &mut self.comb {
+            comb.clear();
+        }
+        if let Some(phaser) = &mut self.phaser {
+            phaser.reset();
+        }
+        if let Some(harmonizer) = &mut self.harmonizer {
+            harmonizer.clear();
+        }
+        self.cur_grain_start = 0.;
+        self.samples_since_last_grain = 0.;
+    }
+
+    /// Moves the read head to where it would be if it had been moving since the start of the
+    /// song, so that position movement follows the host's timeline across seeks
+    fn relock_read_head(&mut self, params: &ParamValues, voice_ix: usize, position_frames: f64) {
+        let selection_start_sample_ix = params.global(GlobalParam::SelectionStartSampleIx);
+        let selection_end_sample_ix = params.global(GlobalParam::SelectionEndSampleIx);
+        let grain_size = params.global(GlobalParam::GrainSize);
+        let movement = params.voice(voice_ix, VoiceParam::MovementSamplesPerSample);
+        // Wrapped here rather than by `move_read_head`, which would clamp a seek beyond the
+        // selection to its end
+        let room = (selection_end_sample_ix - selection_start_sample_ix - grain_size) as f64;
+        let offset = if room > 0. {
+            (position_frames * movement as f64).rem_euclid(room)
+        } else {
+            0.
+        };
+        self.cur_grain_start = selection_start_sample_ix + offset as f32;
+        self.move_read_head(
+            selection_start_sample_ix,
+            selection_end_sample_ix,
+            grain_size,
+            0.,
+        );
+    }
+
     fn move_read_head(
         &mut self,
         selection_start_sample_ix: f32,
@@ -170,10 +1038,16 @@ impl GranularVoice {
         // TODO: Handle reverse
         self.cur_grain_start += movement_samples_per_sample;
 
+        let selection_len = selection_end_sample_ix - selection_start_sample_ix;
+        if selection_len <= grain_size {
+            // There's no room for the read head to move; the wrapping below would divide by zero
+            self.cur_grain_start = selection_start_sample_ix;
+            return;
+        }
+
         if self.cur_grain_start + grain_size > selection_end_sample_ix {
             // Grain would overflow the selection; we need to wrap it back around to the start of
             // the selection
-            let selection_len = selection_end_sample_ix - selection_start_sample_ix;
             let offset_from_selection_start = self.cur_grain_start - selection_start_sample_ix;
             let new_offset_from_selection_start =
                 offset_from_selection_start % (selection_len - grain_size);
@@ -181,302 +1055,4492 @@ impl GranularVoice {
         }
     }
 
-    fn seed_grain(
-        &mut self,
+    /// Random offset from the read head for a new grain's start position
+    fn random_start_offset(rng: &mut impl Rng, grain_start_randomness_samples: f32) -> f32 {
+        if grain_start_randomness_samples == 0. {
+            return 0.;
+        }
+
+        rng.gen_range(
+            -(grain_start_randomness_samples.abs()) / 2.
+                ..=(grain_start_randomness_samples.abs()) / 2.,
+        )
+    }
+
+    fn new_grain(
+        &self,
         grain_size: f32,
-        linear_slope_length: f32,
-        slope_linearity: f32,
         sample_playback_ratio: f32,
-        grain_start_randomness_samples: f32,
+        start_sample_ix: f32,
         sample_buffer_len: usize,
-    ) {
-        let start_offset = if grain_start_randomness_samples == 0. {
-            0.
-        } else {
-            use rand::Rng;
-            let mut rng = common::rng();
-            rng.gen_range(
-                -(grain_start_randomness_samples.abs()) / 2.
-                    ..=(grain_start_randomness_samples.abs()) / 2.,
-            )
-        };
-
-        self.grains.push(Grain {
+    ) -> Grain {
+        Grain {
             len_samples: grain_size,
-            start_sample_ix: clamp(
-                0.,
-                (sample_buffer_len - 1) as f32,
-                self.cur_grain_start + start_offset,
-            ),
+            start_sample_ix: clamp(0., (sample_buffer_len - 1) as f32, start_sample_ix),
             samples_read_so_far: 0.,
             sample_playback_ratio: clamp(0.001, 1000., sample_playback_ratio),
-            linear_slope_length,
-            slope_linearity,
-        });
+            gain: 1.,
+            retired: false,
+            reversed: false,
+            wrap: None,
+            depth: self.quad.depth,
+            glide: None,
+            tilt: None,
+            slot: None,
+        }
     }
 
-    fn maybe_seed_new_grain(
+    /// Advances the voice's grain clock by one sample and returns whether a new grain should be
+    /// spawned on it.  This runs ahead of the rest of the voice so that modulation sources
+    /// triggered by grain onsets can update before the grain is seeded.
+    pub fn should_spawn_grain(
         &mut self,
-        samples_between_grains: f32,
-        grain_size: f32,
-        linear_slope_length: f32,
-        slope_linearity: f32,
-        sample_playback_ratio: f32,
-        grain_start_randomness_samples: f32,
-        sample_buffer_len: usize,
-    ) {
-        if sample_playback_ratio <= 0.05 {
-            return;
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        clock: GrainClock,
+        voice_ix: usize,
+    ) -> bool {
+        let sample_playback_ratio = params.voice_speed_ratio(voice_ix);
+        if !self.spawning_enabled || sample_playback_ratio <= 0.05 {
+            return false;
         }
 
+        let samples_between_grains = match clock {
+            GrainClock::Grid { trigger, interval } => {
+                self.grain_interval = interval;
+                self.samples_since_last_grain = 0.;
+                return trigger;
+            }
+            GrainClock::FixedInterval(interval) => interval,
+            GrainClock::Free => {
+                params.voice(voice_ix, VoiceParam::SamplesBetweenGrains)
+                    / modulation.get(ModDestination::Density).exp2()
+            }
+        };
+        self.grain_interval = samples_between_grains;
         self.samples_since_last_grain += 1.;
         if self.samples_since_last_grain >= samples_between_grains {
             self.samples_since_last_grain -= samples_between_grains;
-            self.seed_grain(
-                grain_size,
-                linear_slope_length,
-                slope_linearity,
-                sample_playback_ratio,
-                grain_start_randomness_samples,
-                sample_buffer_len,
-            );
+            true
+        } else {
+            false
         }
     }
 
-    fn tick_grains(&mut self) {
-        // Tick grains and remove grains that are done playing
-        let mut i = 0;
-        while i < self.grains.len() {
-            let is_still_running = {
-                let grain = &mut self.grains[i];
-                grain.tick()
-            };
-            if !is_still_running {
-                self.grains.swap_remove(i);
+    /// Whether to drop a grain the voice's clock has scheduled, by its `GrainSkipProbability`
+    /// and the `GrainSkip` modulation on top.  The clock keeps its timing, so the grains that are
+    /// left still fall on the pattern.
+    fn skips_grain(
+        &mut self,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        voice_ix: usize,
+    ) -> bool {
+        let probability = params.voice(voice_ix, VoiceParam::GrainSkipProbability)
+            + modulation.get(ModDestination::GrainSkip);
+        probability > 0. && self.rng.gen::<f32>() < probability
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn seed_new_grain(
+        &mut self,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        position_weighting: &PositionWeighting,
+        pitch_snap: PitchSnap,
+        trace: &mut GrainTrace,
+        voice_ix: usize,
+        sample_buffer_len: usize,
+        slots: &SampleSlots,
+    ) {
+        let sample_playback_ratio = params.voice_speed_ratio(voice_ix);
+        let selection_start_sample_ix = params.global(GlobalParam::SelectionStartSampleIx);
+        let selection_end_sample_ix = params.global(GlobalParam::SelectionEndSampleIx);
+        let selection_len = selection_end_sample_ix - selection_start_sample_ix;
+        // PSOLA grains play at the source's speed, since their spacing sets the pitch
+        if let Some(psola_grain) = self.psola_grain.take() {
+            let grain_size = psola_grain.len.clamp(1., GlobalParam::GrainSize.info().max);
+            let grain = self.new_grain(grain_size, 1., psola_grain.start, sample_buffer_len);
+            trace.record(GrainEventKind::Spawn, voice_ix, &grain);
+            self.grains.push(grain);
+            return;
+        }
+        let mut pitch_ratio = (modulation.get(ModDestination::Pitch) / 12.).exp2();
+        if let Some(arpeggio) = &mut self.arpeggio {
+            pitch_ratio *= (arpeggio.next_grain() / 12.).exp2();
+        }
+        if let Some(intervals) = &self.pitch_intervals {
+            pitch_ratio *= intervals.pick(&mut self.rng);
+        } else if self.grain_detune_cents > 0. {
+            let cents = self.grain_detune_cents;
+            pitch_ratio *= (self.rng.gen_range(-cents..=cents) / 1200.).exp2();
+        }
+        if let Some(spray) = &self.pitch_spray {
+            pitch_ratio *= (spray.pick(&mut self.rng) / 12.).exp2();
+        }
+        let grain_size = match self.pulsar {
+            Some(pulsar) => {
+                pitch_ratio *= pulsar.pitch_ratio();
+                pulsar.pulsaret_len(self.grain_interval, sample_playback_ratio * pitch_ratio)
+            }
+            None => modulation.apply_octaves(
+                ModDestination::GrainSize,
+                self.synced_grain_size
+                    .unwrap_or(params.global(GlobalParam::GrainSize)),
+            ),
+        }
+        // Modulation can take the size out of the parameter's range
+        .clamp(1., GlobalParam::GrainSize.info().max);
+        // Reversed sources read the selection mirrored, so the read head moves backwards through
+        // it and every grain is played from its end
+        let (read_head, direction) = match self.live_read_head {
+            Some(live_read_head) => (live_read_head, 1.),
+            None if self.reversed_source => (
+                selection_start_sample_ix + selection_end_sample_ix
+                    - self.cur_grain_start
+                    - grain_size,
+                -1.,
+            ),
+            None => (self.cur_grain_start, 1.),
+        };
+        let randomness_samples = params.voice(voice_ix, VoiceParam::GrainStartRandomnessSamples);
+        let start_sample_ix =
+            match self.matched_start.take() {
+                Some(matched_start) => matched_start,
+                None if randomness_samples == 0. => read_head,
+                None => position_weighting.pick(&mut self.rng, |rng| {
+                    read_head + Self::random_start_offset(rng, randomness_samples)
+                }),
+            } + direction * modulation.get(ModDestination::Position) * selection_len;
+        // The live history is a loop, so grains can start anywhere in it and read across its end
+        let start_sample_ix = match self.live_read_head {
+            Some(_) => start_sample_ix.rem_euclid(sample_buffer_len.max(1) as f32),
+            None => start_sample_ix,
+        };
+
+        let ratio = sample_playback_ratio * pitch_ratio;
+        let glide = self.grain_glide.as_mut().and_then(|grain_glide| {
+            let glide_samples = grain_glide.samples();
+            grain_glide
+                .next_grain(ratio)
+                .map(|from| (from, glide_samples))
+        });
+        // Slot grains start as far into their slot as the waveform grains would into the selection
+        let slot = self.slot_selection.as_mut().map(|selection| {
+            let slot_id = selection.pick(&mut self.rng);
+            let slot_len = slots.get(slot_id).map_or(0, <[f32]>::len);
+            let fraction = if selection_len > 0. {
+                (start_sample_ix - selection_start_sample_ix) / selection_len
             } else {
-                i += 1;
+                0.
+            };
+            let start = clamp(
+                0.,
+                slot_len.saturating_sub(1) as f32,
+                fraction * slot_len as f32,
+            );
+            (slot_id, slot_len, start)
+        });
+        let chord = self.chord.unwrap_or_default();
+        for note in chord.notes() {
+            let mut grain = self.new_grain(
+                grain_size,
+                pitch_snap.ratio(ratio * note.ratio()),
+                start_sample_ix,
+                sample_buffer_len,
+            );
+            if let Some((from, glide_samples)) = glide {
+                let target = grain.sample_playback_ratio;
+                let from = clamp(0.001, 1000., pitch_snap.ratio(from * note.ratio()));
+                grain.glide = Glide::new(from, target, glide_samples);
+                if grain.glide.is_some() {
+                    grain.sample_playback_ratio = from;
+                }
+            }
+            grain.gain = note.gain * self.onset_gain;
+            grain.reversed = self.reversed_source;
+            grain.depth = self.quad.pick(&mut self.rng);
+            grain.tilt = self.grain_tilt.map(|range| range.pick(&mut self.rng));
+            if self.live_read_head.is_some() {
+                grain.wrap = Some(0..sample_buffer_len);
+            } else if self.wraps_selection {
+                grain.wrap = Some(
+                    selection_start_sample_ix.max(0.) as usize
+                        ..selection_end_sample_ix.max(0.) as usize,
+                );
             }
+            if let Some((slot_id, slot_len, start)) = slot {
+                grain.slot = Some(slot_id);
+                grain.start_sample_ix = start;
+                if grain.wrap.is_some() {
+                    grain.wrap = Some(0..slot_len);
+                }
+            }
+            trace.record(GrainEventKind::Spawn, voice_ix, &grain);
+            self.grains.push(grain);
         }
     }
 
-    pub fn update_and_get_sample(
+    /// Spawns, plays and mixes the voice's grains for one sample
+    #[allow(clippy::too_many_arguments)]
+    fn play_grains(
         &mut self,
-        waveform: &[f32],
-        selection_start_sample_ix: f32,
-        selection_end_sample_ix: f32,
-        grain_size: f32,
-        // Positive values are highpass, negative values are lowpass
-        filter_cutoff: f32,
-        linear_slope_length: f32,
-        slope_linearity: f32,
-        samples_between_grains: f32,
-        movement_samples_per_sample: f32,
-        sample_speed_ratio: f32,
-        grain_start_randomness_samples: f32,
-    ) -> f32 {
+        sources: &WaveformSources,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        position_weighting: &PositionWeighting,
+        pitch_snap: PitchSnap,
+        trace: &mut GrainTrace,
+        capture: &mut GrainCapture,
+        spawn_grain: bool,
+        voice_ix: usize,
+    ) -> (f32, f32) {
+        let selection_start_sample_ix = params.global(GlobalParam::SelectionStartSampleIx);
+        let selection_end_sample_ix = params.global(GlobalParam::SelectionEndSampleIx);
+        let grain_size = params.global(GlobalParam::GrainSize);
+        let envelope_params = self.envelope_params(params, modulation, voice_ix);
+
         self.move_read_head(
             selection_start_sample_ix,
             selection_end_sample_ix,
             grain_size,
-            movement_samples_per_sample,
-        );
-
-        self.maybe_seed_new_grain(
-            samples_between_grains,
-            grain_size,
-            linear_slope_length,
-            slope_linearity,
-            sample_speed_ratio,
-            grain_start_randomness_samples,
-            waveform.len(),
+            params.voice(voice_ix, VoiceParam::MovementSamplesPerSample),
         );
 
-        self.tick_grains();
-
-        let samples_and_gains = scratch();
-        let mut total_gain = 0.;
-        let mut active_grain_count = 0;
-        self.grains.iter().for_each(|grain| {
-            let (gain, sample) = grain.sample(
-                waveform,
-                self.reversed.grain_is_reversed,
-                linear_slope_length,
-                slope_linearity,
+        if spawn_grain {
+            let grain_count = self.grains.len();
+            self.seed_new_grain(
+                params,
+                modulation,
+                position_weighting,
+                pitch_snap,
+                trace,
+                voice_ix,
+                sources.current.left.len(),
+                sources.slots,
             );
-            total_gain += gain;
-            samples_and_gains[active_grain_count] = (gain, sample);
-            active_grain_count += 1;
-        });
+            let spawned = if capture.is_armed() {
+                grain_count
+            } else {
+                self.grains.len()
+            };
+            // Grains are only ever pushed, so the ones just spawned are at the end
+            for grain in self.grains.iter().skip(spawned) {
+                capture.capture(voice_ix, &grain);
+            }
+        }
+        if capture.is_rendering() {
+            capture.render(voice_ix, |grain| GrainPlayback {
+                channels: match grain.slot {
+                    Some(slot_id) => sources.slots.channels(slot_id),
+                    None => sources.for_grain(grain.retired).0,
+                },
+                is_reversed: grain.reversed != self.reversed.grain_is_reversed,
+                envelope: &self.envelope,
+                envelope_params,
+                window: self.window.built_for(envelope_params),
+                mid_side: self.mid_side,
+            });
+        }
 
-        let sample = normalize_gain(&samples_and_gains[0..active_grain_count], total_gain);
+        self.grains
+            .tick(|grain| trace.record(GrainEventKind::End, voice_ix, &grain));
 
-        smooth(&mut self.filter_cutoff, filter_cutoff, 0.9);
-        if self.filter_cutoff.abs() < 15. {
-            return sample;
+        // Whatever is the same for every grain is worked out once per sample, leaving the loop
+        // over the grains to do only what differs between them
+        let sums = self.grains.mix(&GrainMix {
+            current: sources.for_grain(false),
+            retired: sources.for_grain(true),
+            slots: sources.slots,
+            envelope: &self.envelope,
+            envelope_params,
+            window: self.window.built_for(envelope_params),
+            click_guard: self.envelope.click_guard && !self.envelope.overlap_add,
+            voice_reversed: self.reversed.grain_is_reversed,
+            mid_side: self.mid_side,
+        });
+        let total_gain = sums.gain;
+        if total_gain > 0. {
+            self.grain_depth = sums.depth / total_gain;
         }
 
-        // Apply filter
-        if filter_cutoff > 0. {
-            self.filter.lowpass(self.filter_cutoff, sample)
+        let gain_multiplier = if self.envelope.overlap_add {
+            self.overlap_add_scale()
+        } else if self.density_compensation.enabled {
+            self.density_compensation.tick()
         } else {
-            self.filter.highpass(-self.filter_cutoff, sample)
-        }
+            // Crossfaded while the parameter ramps, so that toggling it doesn't click
+            let compensation = params.voice(voice_ix, VoiceParam::OverlapCompensation);
+            let normalization = normalization_gain(total_gain);
+            if compensation > 0. {
+                mix(
+                    compensation,
+                    normalization,
+                    self.overlap_compensation.tick(),
+                )
+            } else {
+                normalization
+            }
+        };
+        (sums.left * gain_multiplier, sums.right * gain_multiplier)
     }
-}
 
-impl GranularCtx {
-    pub fn get_sample(
+    /// Plays one sample of spectral granulation, analyzing a new frame around the read head when
+    /// the last one is a hop old
+    fn play_spectral(
         &mut self,
-        selection_start_sample_ix: f32,
-        selection_end_sample_ix: f32,
-        grain_size: f32,
-        linear_slope_length: f32,
-        slope_linearity: f32,
-        voice_1_filter_cutoff: f32,
-        voice_2_filter_cutoff: f32,
-        voice_1_samples_between_grains: f32,
-        voice_2_samples_between_grains: f32,
-        voice_1_gain: f32,
-        voice_2_gain: f32,
-        voice_1_movement_samples_per_sample: f32,
-        voice_2_movement_samples_per_sample: f32,
-        voice_1_sample_speed_ratio: f32,
-        voice_2_sample_speed_ratio: f32,
-        voice_1_grain_start_randomness_samples: f32,
-        voice_2_grain_start_randomness_samples: f32,
-    ) -> f32 {
-        let v1_sample = self.voices[0].update_and_get_sample(
-            &self.waveform,
-            selection_start_sample_ix,
-            selection_end_sample_ix,
-            grain_size,
-            voice_1_filter_cutoff,
-            linear_slope_length,
-            slope_linearity,
-            voice_1_samples_between_grains,
-            voice_1_movement_samples_per_sample,
-            voice_1_sample_speed_ratio,
-            voice_1_grain_start_randomness_samples,
-        );
-        let v2_sample = self.voices[1].update_and_get_sample(
-            &self.waveform,
+        sources: &WaveformSources,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        voice_ix: usize,
+    ) -> (f32, f32) {
+        let selection_start_sample_ix = params.global(GlobalParam::SelectionStartSampleIx);
+        let selection_end_sample_ix = params.global(GlobalParam::SelectionEndSampleIx);
+        self.move_read_head(
             selection_start_sample_ix,
             selection_end_sample_ix,
-            grain_size,
-            voice_2_filter_cutoff,
-            linear_slope_length,
-            slope_linearity,
-            voice_2_samples_between_grains,
-            voice_2_movement_samples_per_sample,
-            voice_2_sample_speed_ratio,
-            voice_2_grain_start_randomness_samples,
+            params.global(GlobalParam::GrainSize),
+            params.voice(voice_ix, VoiceParam::MovementSamplesPerSample),
         );
-        (v1_sample * 0.5 * voice_1_gain) + (v2_sample * 0.5 * voice_2_gain)
+        let Some(spectral) = &mut self.spectral else {
+            return (0., 0.);
+        };
+        if spectral.needs_frame() {
+            let randomness_samples =
+                params.voice(voice_ix, VoiceParam::GrainStartRandomnessSamples);
+            let start = self.cur_grain_start
+                + Self::random_start_offset(&mut self.rng, randomness_samples)
+                + modulation.get(ModDestination::Position)
+                    * (selection_end_sample_ix - selection_start_sample_ix);
+            let playback_ratio = params.voice_speed_ratio(voice_ix)
+                * (modulation.get(ModDestination::Pitch) / 12.).exp2();
+            spectral.add_frame(sources.current, start, playback_ratio, &mut self.rng);
+        }
+        spectral.next_sample(sources.current.right.is_some())
     }
-}
 
-pub fn create_granular_instance() -> *mut GranularCtx {
-    common::maybe_init(None);
-    common::set_raw_panic_hook();
-    let ctx = Box::new(GranularCtx::default());
-    Box::into_raw(ctx)
-}
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_and_get_sample(
+        &mut self,
+        sources: &WaveformSources,
+        params: &ParamValues,
+        modulation: &VoiceModulation,
+        position_weighting: &PositionWeighting,
+        pitch_snap: PitchSnap,
+        trace: &mut GrainTrace,
+        capture: &mut GrainCapture,
+        spawn_grain: bool,
+        voice_ix: usize,
+        sample_rate: f32,
+    ) -> (f32, f32) {
+        // Positive values are lowpass, negative values are highpass
+        let filter_cutoff = params.voice(voice_ix, VoiceParam::FilterCutoff);
 
-pub fn get_granular_waveform_ptr(ctx: *mut GranularCtx, new_waveform_len: usize) -> *mut f32 {
-    unsafe {
-        (*ctx).waveform = Vec::with_capacity(new_waveform_len);
-        (*ctx).waveform.set_len(new_waveform_len);
-        (*ctx).waveform.as_mut_ptr()
+        let (left, right) = match &mut self.wavetable {
+            Some(wavetable) => {
+                self.grain_depth = self.quad.depth;
+                let frequency_hz = ROOT_FREQUENCY_HZ
+                    * params.voice_speed_ratio(voice_ix)
+                    * (modulation.get(ModDestination::Pitch) / 12.).exp2();
+                wavetable.next_sample(
+                    sources.current,
+                    frequency_hz,
+                    modulation.get(ModDestination::Position),
+                    sample_rate,
+                )
+            }
+            None if self.spectral.is_some() => {
+                self.grain_depth = self.quad.depth;
+                self.play_spectral(sources, params, modulation, voice_ix)
+            }
+            None => self.play_grains(
+                sources,
+                params,
+                modulation,
+                position_weighting,
+                pitch_snap,
+                trace,
+                capture,
+                spawn_grain,
+                voice_ix,
+            ),
+        };
+        let (left, right) = match &mut self.resonator {
+            Some(resonator) => {
+                let speed_ratio = params.voice_speed_ratio(voice_ix)
+                    * (modulation.get(ModDestination::Pitch) / 12.).exp2();
+                resonator.process((left, right), speed_ratio, sample_rate)
+            }
+            None => (left, right),
+        };
+        let (left, right) = match &mut self.comb {
+            Some(comb) => comb.process((left, right), sample_rate),
+            None => (left, right),
+        };
+        let (left, right) = match &mut self.phaser {
+            Some(phaser) => phaser.process_stereo((left, right), sample_rate),
+            None => (left, right),
+        };
+        let (left, right) = match &mut self.harmonizer {
+            Some(harmonizer) => harmonizer.process((left, right)),
+            None => (left, right),
+        };
+
+        if filter_cutoff.abs() < 15. {
+            return (left, right);
+        }
+        let envelope_octaves = self.filter_envelope.octaves();
+        let filter_cutoff = if envelope_octaves == 0. {
+            filter_cutoff
+        } else {
+            filter_cutoff * envelope_octaves.exp2()
+        };
+        let filter_cutoff = clamp(
+            -20000.,
+            20000.,
+            modulation.apply_octaves(ModDestination::FilterCutoff, filter_cutoff),
+        );
+
+        // Apply filter
+        let filter = |filter: &mut ButterworthFilter, sample: f32| {
+            if filter_cutoff > 0. {
+                filter.lowpass(filter_cutoff, sample)
+            } else {
+                filter.highpass(-filter_cutoff, sample)
+            }
+        };
+        let left = filter(&mut self.filter, left);
+        let right = if sources.current.right.is_some() {
+            filter(&mut self.filter_right, right)
+        } else {
+            left
+        };
+        (left, right)
     }
 }
 
-pub fn render_granular(
-    ctx: *mut GranularCtx,
+impl GranularCtx {
+    pub fn get_sample(&mut self) -> OutputSample {
+        self.modulation.tick(self.sample_rate);
+        self.concatenative.tick();
+
+        let sources = WaveformSources {
+            current: WaveformChannels::stored(
+                self.external_waveform.as_ref(),
+                self.compact_waveform.as_ref(),
+                &self.waveform,
+                self.waveform_right.as_deref(),
+            )
+            .valid_part(self.waveform_valid_len),
+            retired: self.waveform_swap.retired.as_mut().map(|retired| {
+                let gain = retired.tick();
+                (retired.channels(), gain)
+            }),
+            slots: &self.sample_slots,
+        };
+        let params = &self.params.current;
+        let pitch_snap = match (self.snap_grain_pitch, &self.tuning) {
+            (false, _) => PitchSnap::Off,
+            (true, None) => PitchSnap::EqualTempered,
+            (true, Some(tuning)) => PitchSnap::Tuning(tuning),
+        };
+        let mut output = OutputSample::default();
+        // The first voice is played before the second, so its level this sample ducks the second
+        let mut ducking_gain = 1.;
+        for (voice_ix, voice) in self.voices.iter_mut().enumerate() {
+            let frozen_sample = voice.freeze.as_mut().and_then(VoiceFreeze::next_sample);
+            // PSOLA voices spawn a grain every period of the output pitch where the source is
+            // pitched, and granulate as usual elsewhere
+            let source_period = voice
+                .psola
+                .and_then(|_| self.pitch_track.period_at(voice.cur_grain_start));
+            voice.onset_gain = 1.;
+            let clock = match (
+                voice.psola.zip(source_period),
+                voice.pulsar,
+                voice.sync_beats,
+            ) {
+                (Some((psola, period)), _, _) => {
+                    let speed_ratio = params.voice_speed_ratio(voice_ix)
+                        * (self.modulation.voices[voice_ix].get(ModDestination::Pitch) / 12.)
+                            .exp2();
+                    let output_hz = psola.output_hz(
+                        self.sample_rate / period,
+                        speed_ratio,
+                        self.tuning.as_ref(),
+                    );
+                    GrainClock::FixedInterval(self.sample_rate / output_hz)
+                }
+                // Density modulation moves the fundamental
+                (None, Some(pulsar), _) => GrainClock::FixedInterval(
+                    pulsar.period_samples(self.sample_rate)
+                        / self.modulation.voices[voice_ix]
+                            .get(ModDestination::Density)
+                            .exp2(),
+                ),
+                (None, None, Some(beats)) => {
+                    let interval = self.transport.beats_to_frames(beats, self.sample_rate);
+                    if self.transport.playing {
+                        let trigger = match &mut self.groove {
+                            Some(groove) => {
+                                let bar = self
+                                    .transport
+                                    .beats_to_frames(transport::BEATS_PER_BAR, self.sample_rate);
+                                match groove.onset(&self.transport, interval, bar) {
+                                    Some(gain) => {
+                                        voice.onset_gain = gain;
+                                        true
+                                    }
+                                    None => false,
+                                }
+                            }
+                            None => self.transport.crosses_grid(interval),
+                        };
+                        GrainClock::Grid {
+                            trigger,
+                            interval: interval as f32,
+                        }
+                    } else {
+                        GrainClock::FixedInterval(interval as f32)
+                    }
+                }
+                (None, None, None) => GrainClock::Free,
+            };
+            voice.synced_grain_size = voice
+                .size_sync_beats
+                .map(|beats| self.transport.beats_to_frames(beats, self.sample_rate) as f32);
+            let spawn_grain = frozen_sample.is_none()
+                && voice.wavetable.is_none()
+                && voice.spectral.is_none()
+                && voice.should_spawn_grain(
+                    params,
+                    &self.modulation.voices[voice_ix],
+                    clock,
+                    voice_ix,
+                )
+                && self
+                    .feature_weighting
+                    .should_spawn(&mut voice.rng, voice.cur_grain_start)
+                && !voice.skips_grain(params, &self.modulation.voices[voice_ix], voice_ix);
+            if spawn_grain {
+                self.modulation.trigger_grain(voice_ix, self.sample_rate);
+                self.grain_stats.grain_spawned(voice_ix);
+                if let Some(period) = source_period {
+                    voice.psola_grain = Some(PsolaGrain {
+                        start: psola::grain_start(
+                            sources.current.left,
+                            voice.cur_grain_start,
+                            period,
+                        ),
+                        len: 2. * period,
+                    });
+                } else if self.concatenative.is_active() {
+                    let selection = params.global(GlobalParam::SelectionStartSampleIx)
+                        ..params.global(GlobalParam::SelectionEndSampleIx);
+                    voice.matched_start = self.concatenative.pick(&mut voice.rng, selection);
+                }
+            }
+
+            if let Some(arpeggio) = &mut voice.arpeggio {
+                arpeggio.tick(&self.transport, self.sample_rate);
+            }
+            voice
+                .filter_envelope
+                .tick(self.notes.velocity(voice_ix), self.sample_rate);
+            let modulation = &self.modulation.voices[voice_ix];
+            let (left, right) = match frozen_sample {
+                Some(sample) => sample,
+                None => {
+                    let sample = voice.update_and_get_sample(
+                        &sources,
+                        params,
+                        modulation,
+                        &self.position_weighting,
+                        pitch_snap,
+                        &mut self.grain_trace,
+                        &mut self.grain_capture,
+                        spawn_grain,
+                        voice_ix,
+                        self.sample_rate,
+                    );
+                    let captured = voice
+                        .freeze
+                        .as_mut()
+                        .is_some_and(|freeze| freeze.capture(sample));
+                    // The loop replaces the grains from here on
+                    if captured {
+                        for grain in &voice.grains {
+                            self.grain_trace
+                                .record(GrainEventKind::End, voice_ix, &grain);
+                        }
+                        voice.grains.clear();
+                    }
+                    sample
+                }
+            };
+            let gain = params.voice(voice_ix, VoiceParam::Gain)
+                * params.voice_mute_solo_gain(voice_ix)
+                * (1. + modulation.get(ModDestination::Gain)).max(0.)
+                * if voice_ix == duck::DUCKED_IX {
+                    ducking_gain
+                } else {
+                    1.
+                };
+            let (left, right) = (left * 0.5 * gain, right * 0.5 * gain);
+            let sample = (left + right) * 0.5;
+            if voice_ix == duck::KEY_IX {
+                if let Some(ducking) = &mut self.voice_ducking {
+                    ducking_gain = ducking.process(sample);
+                }
+            }
+            self.meters.add_voice_sample(voice_ix, sample);
+            let (left_gain, right_gain, mono_gain) = match voice.mid_side {
+                Some(channel) if sources.current.right.is_some() => {
+                    let (left_gain, right_gain) = channel.decode_gains();
+                    (left_gain, right_gain, (left_gain + right_gain) * 0.5)
+                }
+                // Mono waveforms produce the same sample on both sides, so for them this is a
+                // pan; for stereo waveforms it acts as a balance control
+                _ => {
+                    let (left_gain, right_gain) = pan_gains(
+                        params.voice(voice_ix, VoiceParam::Pan)
+                            + modulation.get(ModDestination::Pan)
+                            + voice.auto_pan.tick(&self.transport, self.sample_rate),
+                    );
+                    (left_gain, right_gain, 1.)
+                }
+            };
+            output.mono += sample * mono_gain;
+            output.left += left * left_gain;
+            output.right += right * right_gain;
+            output.voices[voice_ix] = (left * left_gain, right * right_gain);
+            let (front_gain, rear_gain) = pan_gains(voice.grain_depth);
+            output.quad_parts[0] += left * left_gain * front_gain;
+            output.quad_parts[1] += right * right_gain * front_gain;
+            output.quad_parts[2] += left * left_gain * rear_gain;
+            output.quad_parts[3] += right * right_gain * rear_gain;
+            self.sends.send(params, voice_ix, output.voices[voice_ix]);
+        }
+        // The effect buses and the dry signal are at the center, in both pairs
+        let (send_left, send_right) = self.sends.process(self.sample_rate);
+        output.mono += (send_left + send_right) * 0.5;
+        output.left += send_left;
+        output.right += send_right;
+        let (dry_left, dry_right) = self.dry.tick(sources.current, params);
+        output.mono += (dry_left + dry_right) * 0.5;
+        output.left += dry_left;
+        output.right += dry_right;
+        let (center_left, center_right) = (send_left + dry_left, send_right + dry_right);
+        output.quad_parts[0] += center_left;
+        output.quad_parts[1] += center_right;
+        output.quad_parts[2] += center_left;
+        output.quad_parts[3] += center_right;
+        if let Some(link) = &self.voice_link {
+            link.sync(&mut self.voices, params);
+        }
+        let output = output.scale(params.global(GlobalParam::MasterGain));
+
+        if self
+            .waveform_swap
+            .retired
+            .as_ref()
+            .is_some_and(RetiredWaveform::is_faded_out)
+        {
+            self.drop_retired_waveform();
+        }
+
+        let output = match &mut self.shutdown {
+            Some(fade) => {
+                if let Some(tail) = &mut self.shutdown_tail {
+                    *tail = tail.saturating_sub(1);
+                }
+                output.scale(fade.tick())
+            }
+            None => output,
+        };
+        let output = match &mut self.pending_reset {
+            Some(fade) => {
+                let output = output.scale(fade.tick());
+                if fade.is_finished() {
+                    self.pending_reset = None;
+                    self.reset_voices();
+                }
+                output
+            }
+            None => output,
+        };
+        // NaNs in the loaded waveform can still make it through when filters are bypassed
+        if output.mono.is_finite() && output.left.is_finite() && output.right.is_finite() {
+            output
+        } else {
+            OutputSample::default()
+        }
+    }
+
+    /// Stops spawning new grains and fades the output to silence over `fade_ms`
+    pub fn begin_shutdown(&mut self, fade_ms: f32) {
+        self.shutdown = Some(OutputFade::new(fade_ms, self.sample_rate));
+        for voice in &mut self.voices {
+            voice.spawning_enabled = false;
+        }
+        // The dry playback keeps going without grains, so only the fade silences it
+        let dry_playing = self.params.current.global(GlobalParam::DryGain) > 0.;
+        self.shutdown_tail = if dry_playing {
+            None
+        } else {
+            self.tail_frames()
+        };
+    }
+
+    /// Returns `true` once a shutdown has been started and the output has become silent, either
+    /// because the fade finished or because the tail the output had when it started, including
+    /// the effects that keep sounding without grains, played out before it did
+    pub fn is_drained(&self) -> bool {
+        match self.shutdown {
+            Some(fade) => fade.is_finished() || self.shutdown_tail == Some(0),
+            None => false,
+        }
+    }
+
+    /// Samples that the master effects delay the whole output by, for hosts to compensate.  The
+    /// Haas widener only delays one channel, so it isn't counted.
+    pub fn latency_frames(&self) -> usize {
+        let vibrato = self
+            .master_vibrato
+            .as_ref()
+            .map_or(0., |vibrato| vibrato.latency_samples(self.sample_rate));
+        let tape = self
+            .master_tape
+            .as_ref()
+            .map_or(0., |tape| tape.latency_samples(self.sample_rate));
+        let saturation = self
+            .master_saturation
+            .as_ref()
+            .map_or(0., |saturators| saturators[0].latency_samples());
+        let convolution = self
+            .master_convolution
+            .as_ref()
+            .map_or(0, MasterConvolution::latency_samples);
+        (vibrato + tape + saturation).round() as usize + convolution
+    }
+
+    /// Samples the output stays non-silent for once the voices stop starting grains, e.g. after
+    /// the last note, or None when it never goes silent: a voice is frozen, the stutter is
+    /// repeating or the tape is hissing
+    pub fn tail_frames(&self) -> Option<usize> {
+        let hissing = self
+            .master_tape
+            .as_ref()
+            .is_some_and(|tape| tape.settings.hiss > 0.);
+        let repeating = self.stutter.as_ref().is_some_and(Stutter::is_repeating);
+        if hissing || repeating || self.voices.iter().any(|voice| voice.freeze.is_some()) {
+            return None;
+        }
+        let voices = self.voices.iter().fold(0f32, |tail, voice| {
+            tail.max(voice.tail_samples(self.sample_rate))
+        });
+        let sending = (0..params::VOICE_COUNT)
+            .any(|voice_ix| self.params.current.voice(voice_ix, VoiceParam::DelaySend) > 0.);
+        let delay = if sending {
+            let period = self.sends.delay_ms * 0.001 * self.sample_rate;
+            decay_samples(period, self.sends.delay_feedback)
+        } else {
+            0.
+        };
+        let convolution = self
+            .master_convolution
+            .as_ref()
+            .map_or(0, MasterConvolution::tail_samples);
+        let tail = voices + delay + (convolution + self.latency_frames()) as f32;
+        tail.is_finite().then_some(tail.ceil() as usize)
+    }
+
+    /// Replaces the output with the stutter's repeats while it's repeating.  The per-voice outputs
+    /// keep playing live.
+    fn apply_stutter(&mut self, output: OutputSample, input: f32) -> OutputSample {
+        let Some(stutter) = &mut self.stutter else {
+            return output;
+        };
+        let source = match stutter.settings.source {
+            OverdubSource::Output => (output.mono, output.left, output.right),
+            OverdubSource::Input => (input, input, input),
+        };
+        match stutter.process(source, &self.transport, self.sample_rate) {
+            Some((mono, left, right)) => OutputSample {
+                mono,
+                left,
+                right,
+                ..output
+            },
+            None => output,
+        }
+    }
+
+    fn apply_master_vibrato(&mut self, output: OutputSample) -> OutputSample {
+        let Some(vibrato) = &mut self.master_vibrato else {
+            return output;
+        };
+        let samples = [output.left, output.right, output.mono];
+        let [left, right, mono] = vibrato.process(samples, self.sample_rate);
+        OutputSample {
+            mono,
+            left,
+            right,
+            ..output
+        }
+    }
+
+    fn apply_master_tape(&mut self, output: OutputSample) -> OutputSample {
+        let Some(tape) = &mut self.master_tape else {
+            return output;
+        };
+        let samples = [output.left, output.right, output.mono];
+        let [left, right, mono] = tape.process(samples, self.sample_rate);
+        OutputSample {
+            mono,
+            left,
+            right,
+            ..output
+        }
+    }
+
+    fn apply_master_phaser(&mut self, output: OutputSample) -> OutputSample {
+        let Some(phaser) = &mut self.master_phaser else {
+            return output;
+        };
+        let (left, right) = phaser.process_stereo((output.left, output.right), self.sample_rate);
+        OutputSample {
+            mono: phaser.process_mono(output.mono, self.sample_rate),
+            left,
+            right,
+            ..output
+        }
+    }
+
+    fn apply_master_tilt(&mut self, output: OutputSample) -> OutputSample {
+        let Some(tilt) = &mut self.master_tilt else {
+            return output;
+        };
+        let [left, right, mono] = tilt.process([output.left, output.right, output.mono]);
+        OutputSample {
+            mono,
+            left,
+            right,
+            ..output
+        }
+    }
+
+    fn apply_master_eq(&mut self, output: OutputSample) -> OutputSample {
+        let Some(eq) = &mut self.master_eq else {
+            return output;
+        };
+        let [left, right, mono] = eq.process([output.left, output.right, output.mono]);
+        OutputSample {
+            mono,
+            left,
+            right,
+            ..output
+        }
+    }
+
+    fn apply_master_transients(&mut self, output: OutputSample) -> OutputSample {
+        let Some(shaper) = &mut self.master_transients else {
+            return output;
+        };
+        // Stereo-linked, so that the image doesn't shift on every attack
+        let peak = output
+            .mono
+            .abs()
+            .max(output.left.abs())
+            .max(output.right.abs());
+        let gain = shaper.process(peak);
+        OutputSample {
+            mono: output.mono * gain,
+            left: output.left * gain,
+            right: output.right * gain,
+            ..output
+        }
+    }
+
+    fn apply_master_compressor(&mut self, output: OutputSample, sidechain: f32) -> OutputSample {
+        let Some(compressor) = &mut self.master_compressor else {
+            return output;
+        };
+        let [left, right, mono] =
+            compressor.process([output.left, output.right, output.mono], sidechain);
+        OutputSample {
+            mono,
+            left,
+            right,
+            ..output
+        }
+    }
+
+    fn apply_master_saturation(&mut self, output: OutputSample) -> OutputSample {
+        let Some([left, right, mono]) = &mut self.master_saturation else {
+            return output;
+        };
+        OutputSample {
+            mono: mono.process(output.mono),
+            left: left.process(output.left),
+            right: right.process(output.right),
+            ..output
+        }
+    }
+
+    fn apply_master_haas(&mut self, output: OutputSample) -> OutputSample {
+        let Some(haas) = &mut self.master_haas else {
+            return output;
+        };
+        let (left, right) = haas.process(output.left, output.right, self.sample_rate);
+        OutputSample {
+            left,
+            right,
+            ..output
+        }
+    }
+
+    fn apply_master_convolution(&mut self, output: OutputSample) -> OutputSample {
+        let Some(convolution) = &mut self.master_convolution else {
+            return output;
+        };
+        let [left, right, mono] = convolution.process([output.left, output.right, output.mono]);
+        OutputSample {
+            mono,
+            left,
+            right,
+            ..output
+        }
+    }
+
+    /// Last stage of the output: optional limiting followed by a hard clamp.  Sets
+    /// `status::LIMITING_ACTIVE` if either of them changed the sample.  The limiter is driven by
+    /// the loudest of the channels and its gain is applied to all of them.
+    fn apply_output_safety(&mut self, output: OutputSample) -> OutputSample {
+        let output = if self.limiter_enabled {
+            let peak = output
+                .mono
+                .abs()
+                .max(output.left.abs())
+                .max(output.right.abs());
+            self.limiter.process(peak);
+            self.meters
+                .add_gain_reduction(self.limiter.gain_reduction_db());
+            if self.limiter.gain() < LIMITING_ACTIVE_GAIN {
+                self.status |= status::LIMITING_ACTIVE;
+            }
+            output.scale(self.limiter.gain())
+        } else {
+            output
+        };
+
+        let mut clipped = false;
+        let mut clamp_sample = |sample: f32| {
+            clipped |= sample.abs() > OUTPUT_CEILING;
+            clamp(-OUTPUT_CEILING, OUTPUT_CEILING, sample)
+        };
+        let output = OutputSample {
+            mono: clamp_sample(output.mono),
+            left: clamp_sample(output.left),
+            right: clamp_sample(output.right),
+            ..output
+        };
+        if clipped {
+            self.status |= status::LIMITING_ACTIVE | status::OUTPUT_CLIPPED;
+            self.clip_count = self.clip_count.saturating_add(1);
+        }
+        output
+    }
+
+    /// Replaces the waveform.  Analysis of the previous waveform no longer applies, so it's
+    /// dropped until the host requests it again.
+    fn load_waveform(&mut self, samples: Vec<f32>, right: Option<Vec<f32>>) {
+        if !samples.is_empty() {
+            log::log(
+                LogLevel::Info,
+                format_args!(
+                    "loaded a {} waveform of {} samples",
+                    if right.is_some() { "stereo" } else { "mono" },
+                    samples.len()
+                ),
+            );
+        }
+        self.external_waveform = None;
+        self.waveform_valid_len = None;
+        self.uncommitted_len = None;
+        self.waveform = samples;
+        self.waveform_right = right;
+        self.compact_waveform = None;
+        self.position_weighting.envelope = RmsEnvelope::default();
+        self.feature_weighting.map = FeatureMap::default();
+        self.concatenative.corpus = Corpus::default();
+        self.pitch_track = PitchTrack::default();
+        self.live_input = None;
+    }
+
+    /// Loads a waveform the load options have been applied to, in the storage they ask for
+    fn load_processed_waveform(&mut self, samples: Vec<f32>, right: Option<Vec<f32>>) {
+        if self.load_options.storage == WaveformStorage::Int16 {
+            self.load_compact_waveform(CompactWaveform::encode(&samples, right.as_deref()));
+        } else {
+            self.load_waveform(samples, right);
+        }
+    }
+
+    fn load_compact_waveform(&mut self, compact: CompactWaveform) {
+        log::log(
+            LogLevel::Info,
+            format_args!(
+                "loaded a {} waveform of {} samples in 16-bit storage",
+                if compact.right.is_some() {
+                    "stereo"
+                } else {
+                    "mono"
+                },
+                compact.left.len()
+            ),
+        );
+        self.load_waveform(Vec::new(), None);
+        self.compact_waveform = Some(compact);
+    }
+
+    /// Stages a waveform the load options have been applied to.  Waveforms in 16-bit storage are
+    /// encoded here, on the host's thread, rather than when the audio thread swaps them in.
+    fn stage_processed_waveform(&mut self, samples: Vec<f32>, right: Option<Vec<f32>>) {
+        let swap = &mut self.waveform_swap;
+        if self.load_options.storage == WaveformStorage::Int16 {
+            swap.staged_compact = Some(CompactWaveform::encode(&samples, right.as_deref()));
+            swap.staged = None;
+            swap.staged_right = None;
+        } else {
+            swap.staged_compact = None;
+            swap.staged = Some(samples);
+            swap.staged_right = right;
+        }
+    }
+
+    /// Trims the waveform to `start..end`, moving playing grains, read heads and the smoothed
+    /// selection along so that they keep pointing at the same audio.  Grains that started before
+    /// the region are dropped.  Returns false if the region is empty.
+    fn crop_waveform(&mut self, start: usize, end: usize) -> bool {
+        let end = end.min(self.allocated_samples().len());
+        // External memory belongs to the host, which can bind a smaller region instead
+        if start >= end || self.external_waveform.is_some() {
+            return false;
+        }
+        fn crop<T>(channel: &mut Vec<T>, start: usize, end: usize) {
+            channel.truncate(end);
+            channel.drain(..start);
+            channel.shrink_to_fit();
+        }
+        match &mut self.compact_waveform {
+            Some(compact) => {
+                for channel in std::iter::once(&mut compact.left).chain(compact.right.as_mut()) {
+                    crop(channel, start, end);
+                }
+            }
+            None => {
+                for channel in
+                    std::iter::once(&mut self.waveform).chain(self.waveform_right.as_mut())
+                {
+                    crop(channel, start, end);
+                }
+            }
+        }
+        self.waveform_valid_len = self
+            .waveform_valid_len
+            .map(|valid_len| valid_len.saturating_sub(start));
+        self.position_weighting.envelope = RmsEnvelope::default();
+        self.feature_weighting.map = FeatureMap::default();
+        self.concatenative.corpus = Corpus::default();
+        self.pitch_track = PitchTrack::default();
+        self.live_input = None;
+
+        let offset = start as f32;
+        let new_len = (end - start) as f32;
+        for (voice_ix, voice) in self.voices.iter_mut().enumerate() {
+            voice.cur_grain_start = (voice.cur_grain_start - offset).max(0.);
+            let trace = &mut self.grain_trace;
+            voice.grains.retain_mut(|grain| {
+                // Grains fading out after a swap read the retired waveform, which isn't cropped
+                if grain.retired {
+                    return true;
+                }
+                grain.start_sample_ix -= offset;
+                let keep = (0. ..new_len).contains(&grain.start_sample_ix);
+                if !keep {
+                    trace.record(GrainEventKind::End, voice_ix, grain);
+                }
+                keep
+            });
+        }
+        for param in [
+            GlobalParam::SelectionStartSampleIx,
+            GlobalParam::SelectionEndSampleIx,
+        ] {
+            let id = ParamId::Global(param);
+            let value = self.params.current.get(id);
+            self.params.current.set(id, (value - offset).max(0.));
+        }
+        true
+    }
+
+    /// Carries out a swap to the staged waveform requested by `swap_staging_waveform`.  Grains
+    /// that are playing keep reading from the old waveform while they fade out or finish.
+    fn apply_pending_swap(&mut self) {
+        let Some(tail) = self.waveform_swap.pending.take() else {
+            return;
+        };
+        let staged = self.waveform_swap.staged.take();
+        let staged_right = self.waveform_swap.staged_right.take();
+        let staged_compact = self.waveform_swap.staged_compact.take();
+        if staged.is_none() && staged_compact.is_none() {
+            return;
+        }
+        self.waveform_swap.staged_slot = None;
+        // Only one retired waveform is kept around, so grains still fading out from an earlier
+        // swap are cut off
+        self.drop_retired_waveform();
+        if self.external_waveform.is_some() {
+            // The host may free external memory as soon as it's unbound, so grains reading it
+            // can't be left to fade out
+            self.external_waveform = None;
+            self.reset_voices();
+        }
+        for voice in &mut self.voices {
+            voice.grains.retire_all();
+        }
+        let mut samples = std::mem::take(&mut self.waveform);
+        let mut right = self.waveform_right.take();
+        let mut compact = self.compact_waveform.take();
+        if let Some(valid_len) = self.waveform_valid_len {
+            for channel in std::iter::once(&mut samples).chain(right.as_mut()) {
+                channel.truncate(valid_len);
+            }
+            if let Some(compact) = &mut compact {
+                compact.left.truncate(valid_len);
+                if let Some(right) = &mut compact.right {
+                    right.truncate(valid_len);
+                }
+            }
+        }
+        self.waveform_swap.retired = Some(RetiredWaveform {
+            samples,
+            right,
+            compact,
+            fade: match tail {
+                SwapTail::Crossfade(fade_ms) => Some(OutputFade::new(fade_ms, self.sample_rate)),
+                SwapTail::Finish => None,
+            },
+        });
+        match staged_compact {
+            Some(compact) => self.load_compact_waveform(compact),
+            None => self.load_waveform(staged.unwrap_or_default(), staged_right),
+        }
+    }
+
+    fn drop_retired_waveform(&mut self) {
+        if self.waveform_swap.retired.take().is_none() {
+            return;
+        }
+        for (voice_ix, voice) in self.voices.iter_mut().enumerate() {
+            let trace = &mut self.grain_trace;
+            voice.grains.retain_mut(|grain| {
+                if grain.retired {
+                    trace.record(GrainEventKind::End, voice_ix, grain);
+                }
+                !grain.retired
+            });
+        }
+    }
+
+    fn reset_voices(&mut self) {
+        for (voice_ix, voice) in self.voices.iter_mut().enumerate() {
+            for grain in &voice.grains {
+                self.grain_trace
+                    .record(GrainEventKind::End, voice_ix, &grain);
+            }
+            voice.reset();
+        }
+        self.sends.clear();
+        self.dry.reset();
+        self.chunk_pos = 0;
+    }
+
+    /// The output buffer of the configured layout, which renders return
+    fn output(&self) -> &[f32] {
+        match self.output_layout {
+            OutputLayout::Mono => &self.rendered_output,
+            OutputLayout::Stereo => &self.rendered_output_stereo,
+            OutputLayout::Quad => &self.rendered_output_quad,
+        }
+    }
+
+    fn output_ptr(&self) -> *const f32 {
+        self.output().as_ptr()
+    }
+
+    /// Silences the output after a panic and flags it in the status; see `handles::guard`
+    #[cfg(panic = "unwind")]
+    pub fn poison(&mut self) {
+        self.rendered_output = [0.; FRAME_SIZE];
+        self.rendered_output_stereo = [0.; FRAME_SIZE * 2];
+        self.rendered_voice_outputs = [[0.; FRAME_SIZE * 2]; params::VOICE_COUNT];
+        self.rendered_output_quad = [0.; FRAME_SIZE * 4];
+        self.status |= status::INSTANCE_POISONED;
+    }
+
+    /// Kills all active grains, clears filter state and moves the playheads back to the start of
+    /// the selection.  With a non-zero `fade_ms` the output is faded out first and the reset
+    /// happens once it's silent.
+    pub fn reset(&mut self, fade_ms: f32) {
+        if fade_ms > 0. {
+            self.pending_reset = Some(OutputFade::new(fade_ms, self.sample_rate));
+        } else {
+            self.pending_reset = None;
+            self.reset_voices();
+        }
+    }
+
+    /// Seeds every random choice the engine makes from `seed` and resets the voices, so that
+    /// rendering the same frames with the same parameters from here produces the same output.
+    /// Every instance has its own generators, so seeding one doesn't affect any other.
+    pub fn seed(&mut self, seed: u64) {
+        let mut seed_rng = StdRng::seed_from_u64(seed);
+        self.modulation
+            .reseed(StdRng::seed_from_u64(seed_rng.gen()));
+        for voice in &mut self.voices {
+            voice.rng.reseed(StdRng::seed_from_u64(seed_rng.gen()));
+        }
+        if let Some(tape) = &mut self.master_tape {
+            tape.reseed(StdRng::seed_from_u64(seed_rng.gen()));
+        }
+        self.randomizer
+            .reseed(StdRng::seed_from_u64(seed_rng.gen()));
+        self.reset(0.);
+    }
+
+    /// Overwrites automated parameters for the current sample.  Lanes follow the transport while
+    /// it's playing and otherwise the automation's own clock.
+    /// Control-rate parameters only follow their lanes at the start of a chunk
+    fn apply_automation(&mut self, chunk_start: bool) {
+        let time_seconds = if self.transport.playing {
+            self.transport.position_frames / self.sample_rate as f64
+        } else {
+            self.automation.position_seconds
+        };
+        self.automation.position_seconds = time_seconds + 1. / self.sample_rate as f64;
+
+        let mut current = self.params.current;
+        self.automation
+            .apply(time_seconds, chunk_start, &mut current);
+        self.clamp_selection(&mut current);
+        self.params.current = current;
+    }
+
+    /// Every sample of the waveform buffer, including any past the valid length
+    fn allocated_samples(&self) -> ChannelSamples<'_> {
+        WaveformChannels::stored(
+            self.external_waveform.as_ref(),
+            self.compact_waveform.as_ref(),
+            &self.waveform,
+            None,
+        )
+        .left
+    }
+
+    /// The samples grains read: the valid part of the external waveform if one is bound, or of
+    /// the loaded one otherwise.  Analysis that needs them as floats decodes them.
+    pub fn samples(&self) -> ChannelSamples<'_> {
+        self.allocated_samples().valid_part(self.waveform_valid_len)
+    }
+
+    /// Whether the host has declared a valid length and the requested selection reaches past it
+    fn selection_exceeds_valid_len(&self, values: &ParamValues) -> bool {
+        let Some(valid_len) = self.waveform_valid_len else {
+            return false;
+        };
+        let max_ix = valid_len as f32 - 1.;
+        [
+            GlobalParam::SelectionStartSampleIx,
+            GlobalParam::SelectionEndSampleIx,
+        ]
+        .into_iter()
+        .any(|param| !(0. ..=max_ix).contains(&values.global(param)))
+    }
+
+    /// Keeps the selection inside the waveform, with the end never before the start
+    fn clamp_selection(&self, values: &mut ParamValues) {
+        let max_ix = self.samples().len() as f32 - 1.;
+        let start_id = ParamId::Global(GlobalParam::SelectionStartSampleIx);
+        let end_id = ParamId::Global(GlobalParam::SelectionEndSampleIx);
+        let start = values.get(start_id).min(max_ix);
+        let end = values.get(end_id).min(max_ix).max(start);
+        values.set(start_id, start);
+        values.set(end_id, end);
+    }
+
+    /// Repairs a selection requested by the host so that it lies within the waveform with its
+    /// end after its start, returning the `status` flags describing what had to be changed
+    fn validate_selection(&self, values: &mut ParamValues) -> u32 {
+        let start_id = ParamId::Global(GlobalParam::SelectionStartSampleIx);
+        let end_id = ParamId::Global(GlobalParam::SelectionEndSampleIx);
+        let max_ix = self.samples().len() as f32 - 1.;
+        let mut start = values.get(start_id);
+        let mut end = values.get(end_id);
+        let mut flags = 0;
+
+        if end < start {
+            std::mem::swap(&mut start, &mut end);
+            flags |= status::SELECTION_SWAPPED;
+        }
+
+        if end < 0. || start > max_ix {
+            start = 0.;
+            end = max_ix;
+            flags |= status::SELECTION_FALLBACK;
+        } else if start < 0. || end > max_ix {
+            start = start.max(0.);
+            end = end.min(max_ix);
+            flags |= status::SELECTION_CLAMPED;
+        }
+
+        values.set(start_id, start);
+        values.set(end_id, end);
+        flags
+    }
+
+    fn render_silence(&mut self) {
+        self.rendered_output = [0.; FRAME_SIZE];
+        self.rendered_output_stereo = [0.; FRAME_SIZE * 2];
+        self.rendered_voice_outputs = [[0.; FRAME_SIZE * 2]; params::VOICE_COUNT];
+        self.rendered_output_quad = [0.; FRAME_SIZE * 4];
+        self.meters.clear();
+        self.grain_stats.clear();
+        self.profiler.end_control();
+        self.profiler.end_render();
+    }
+
+    /// Runs the control-rate work due at the start of each chunk
+    fn begin_chunk(&mut self) {
+        self.params.step_control(self.chunk_size);
+        self.apply_switches();
+        let live_read_head = self.live_input.map(|live_input| {
+            live_input.read_head(
+                self.waveform.len(),
+                self.params.current.global(GlobalParam::GrainSize),
+                self.sample_rate,
+            )
+        });
+        for (voice_ix, voice) in self.voices.iter_mut().enumerate() {
+            voice.rng.refill();
+            voice.live_read_head = live_read_head;
+            voice.update_window(
+                &self.params.current,
+                &self.modulation.voices[voice_ix],
+                voice_ix,
+            );
+            voice.update_density_compensation(
+                &self.params.current,
+                &self.modulation.voices[voice_ix],
+                voice_ix,
+            );
+            voice.update_overlap_compensation(
+                &self.params.current,
+                &self.modulation.voices[voice_ix],
+                voice_ix,
+            );
+        }
+    }
+
+    /// Follows the parameters that switch modes on and off, which are control rate and so only
+    /// change at the start of a chunk
+    fn apply_switches(&mut self) {
+        let current = &self.params.current;
+        let on = |param| current.global(param) >= 0.5;
+        self.snap_grain_pitch = on(GlobalParam::GrainPitchSnap);
+        let mid_side = on(GlobalParam::MidSide);
+        let channels = [MidSideChannel::Mid, MidSideChannel::Side];
+        for (voice_ix, voice) in self.voices.iter_mut().enumerate() {
+            voice.mid_side = mid_side.then_some(channels[voice_ix]);
+            voice.wraps_selection = current.voice(voice_ix, VoiceParam::WrapsSelection) >= 0.5;
+        }
+    }
+
+    /// Sets new parameter targets and renders one frame into `rendered_output`
+    pub fn render(&mut self, targets: &ParamValues) {
+        self.follow_midi_clock(targets);
+        self.host_targets = *targets;
+        self.run_commands(0, false);
+        self.render_frame(targets);
+        // Frames that bailed out early still run the commands due in them
+        self.run_commands(FRAME_SIZE - 1, false);
+        let frame_seconds = FRAME_SIZE as f64 / self.sample_rate as f64;
+        let targets = *self.params.target();
+        self.automation_recorder.record(&targets, frame_seconds);
+        if let Some(commands) = &mut self.commands {
+            commands.advance(FRAME_SIZE);
+        }
+        self.midi_clock.advance(FRAME_SIZE);
+        self.recorder.record(&self.rendered_output_stereo);
+        if self.status != self.logged_status {
+            self.log_status();
+            self.logged_status = self.status;
+        }
+    }
+
+    /// Takes the tempo, play state and song position from the MIDI clock while `MidiClockSync` is
+    /// on in the targets and the clock is locked.  The transport runs without a waveform, so this
+    /// doesn't wait for the parameters to be updated at the start of a chunk.
+    fn follow_midi_clock(&mut self, targets: &ParamValues) {
+        if targets.global(GlobalParam::MidiClockSync) < 0.5 {
+            return;
+        }
+        let clock = &mut self.midi_clock;
+        let (Some(bpm), Some(position_frames)) =
+            (clock.bpm(self.sample_rate), clock.position_frames())
+        else {
+            return;
+        };
+        // The loop nudges the position on every tick to correct drift, which isn't a seek
+        self.transport.seeked |= clock.take_jump() && clock.running;
+        self.transport.playing = clock.running;
+        self.transport.bpm = clamp(1., 999., bpm);
+        self.transport.position_frames = position_frames;
+    }
+
+    /// Renders `frames` frames, up to `MAX_OFFLINE_FRAMES`, with `targets` and returns them as
+    /// interleaved stereo, calling `progress` with the number of frames rendered so far and the
+    /// total every `progress_interval` frames and once at the end.  Automation lanes start from
+    /// the beginning unless the transport is playing.  The frames are rendered on an
+    /// `offline_copy`, so live playback carries on from where it was.
+    pub fn render_offline(
+        &mut self,
+        targets: &ParamValues,
+        frames: usize,
+        progress_interval: usize,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Vec<f32> {
+        let frames = frames.min(MAX_OFFLINE_FRAMES);
+        let mut copy = self.offline_copy();
+        copy.automation.position_seconds = 0.;
+        self.swap_lent_buffers(&mut copy);
+        let mut output = Vec::with_capacity(frames * FRAME_SIZE * 2);
+        for frame_ix in 1..=frames {
+            copy.render_interleaved(targets, &mut output);
+            if frame_ix == frames || (progress_interval > 0 && frame_ix % progress_interval == 0) {
+                progress(frame_ix, frames);
+            }
+        }
+        self.swap_lent_buffers(&mut copy);
+        output
+    }
+
+    /// A copy of the instance for offline renders, which leave the instance itself where it was.
+    /// What renders only write to, like the recording, isn't copied, and nor are the host's
+    /// queues and blocks or a pending waveform swap.  The waveform and the sample slots aren't
+    /// copied either but lent with `swap_lent_buffers` while the copy renders, unless the
+    /// waveform is a live loop that renders keep writing to.  The copy doesn't overdub.
+    fn offline_copy(&mut self) -> GranularCtx {
+        let recorder = std::mem::take(&mut self.recorder);
+        let automation_recorder = std::mem::take(&mut self.automation_recorder);
+        let param_history = std::mem::take(&mut self.param_history);
+        let grain_capture = std::mem::take(&mut self.grain_capture);
+        let offline_chunk = std::mem::take(&mut self.offline_chunk);
+        let offline_render = self.offline_render.take();
+        let sample_slots = std::mem::take(&mut self.sample_slots);
+        let uploads = (
+            self.waveform_upload.take(),
+            self.impulse_response_upload.take(),
+        );
+        let host_buffers = (
+            self.commands.take(),
+            self.io_block.take(),
+            self.param_block.take(),
+        );
+        #[cfg(shared_memory)]
+        let shared_param_block = self.shared_param_block.take();
+        let swap = (
+            self.waveform_swap.staged.take(),
+            self.waveform_swap.staged_right.take(),
+            self.waveform_swap.staged_compact.take(),
+            self.waveform_swap.staged_slot.take(),
+            self.waveform_swap.pending.take(),
+        );
+        let waveform = self.live_input.is_none().then(|| {
+            (
+                std::mem::take(&mut self.waveform),
+                self.waveform_right.take(),
+                self.compact_waveform.take(),
+            )
+        });
+
+        let mut copy = self.clone();
+        copy.overdub = None;
+
+        self.recorder = recorder;
+        self.automation_recorder = automation_recorder;
+        self.param_history = param_history;
+        self.grain_capture = grain_capture;
+        self.offline_chunk = offline_chunk;
+        self.offline_render = offline_render;
+        self.sample_slots = sample_slots;
+        (self.waveform_upload, self.impulse_response_upload) = uploads;
+        (self.commands, self.io_block, self.param_block) = host_buffers;
+        #[cfg(shared_memory)]
+        {
+            self.shared_param_block = shared_param_block;
+        }
+        (
+            self.waveform_swap.staged,
+            self.waveform_swap.staged_right,
+            self.waveform_swap.staged_compact,
+            self.waveform_swap.staged_slot,
+            self.waveform_swap.pending,
+        ) = swap;
+        if let Some((waveform, right, compact)) = waveform {
+            self.waveform = waveform;
+            self.waveform_right = right;
+            self.compact_waveform = compact;
+        }
+        copy
+    }
+
+    /// Lends the waveform and the sample slots to an `offline_copy`, or takes them back
+    fn swap_lent_buffers(&mut self, copy: &mut GranularCtx) {
+        std::mem::swap(&mut self.sample_slots, &mut copy.sample_slots);
+        if copy.live_input.is_none() {
+            std::mem::swap(&mut self.waveform, &mut copy.waveform);
+            std::mem::swap(&mut self.waveform_right, &mut copy.waveform_right);
+            std::mem::swap(&mut self.compact_waveform, &mut copy.compact_waveform);
+        }
+    }
+
+    /// Starts a chunked offline render on a fresh `offline_copy` with the automation lanes at the
+    /// beginning, dropping any chunked render in progress
+    pub fn start_chunked_render(&mut self) {
+        let mut copy = self.offline_copy();
+        copy.automation.position_seconds = 0.;
+        self.offline_render = Some(Box::new(copy));
+        self.offline_chunk = Vec::new();
+    }
+
+    /// Renders the next `frames` frames, up to `MAX_OFFLINE_FRAMES`, of the chunked render into
+    /// `offline_chunk`, so long renders can be streamed out without holding all of them in
+    /// memory.  Returns None if no chunked render was started.
+    pub fn render_chunk(&mut self, targets: &ParamValues, frames: usize) -> Option<&[f32]> {
+        let mut copy = self.offline_render.take()?;
+        self.swap_lent_buffers(&mut copy);
+        let mut chunk = std::mem::take(&mut self.offline_chunk);
+        chunk.clear();
+        chunk.reserve(frames.min(MAX_OFFLINE_FRAMES) * FRAME_SIZE * 2);
+        for _ in 0..frames.min(MAX_OFFLINE_FRAMES) {
+            copy.render_interleaved(targets, &mut chunk);
+        }
+        self.swap_lent_buffers(&mut copy);
+        self.offline_render = Some(copy);
+        self.offline_chunk = chunk;
+        Some(&self.offline_chunk)
+    }
+
+    /// Renders a frame and appends it to `output` as interleaved stereo
+    fn render_interleaved(&mut self, targets: &ParamValues, output: &mut Vec<f32>) {
+        self.render(targets);
+        let (left, right) = self.rendered_output_stereo.split_at(FRAME_SIZE);
+        for (left, right) in left.iter().zip(right) {
+            output.extend_from_slice(&[*left, *right]);
+        }
+    }
+
+    /// Mixes the frame's output or input into the waveform if overdubbing.  Host memory bound as
+    /// an external waveform is never written to, and nor are waveforms in 16-bit storage.
+    fn write_overdub(&mut self) {
+        if self.external_waveform.is_some() || self.compact_waveform.is_some() {
+            return;
+        }
+        let valid_len = self.samples().len();
+        let Some(overdub) = &mut self.overdub else {
+            return;
+        };
+        let left = &mut self.waveform[..valid_len];
+        let right = self
+            .waveform_right
+            .as_mut()
+            .map(|right| &mut right[..left.len()]);
+        let input = match overdub.source {
+            OverdubSource::Input => (&self.audio_input[..], &self.audio_input[..]),
+            OverdubSource::Output if right.is_some() => {
+                self.rendered_output_stereo.split_at(FRAME_SIZE)
+            }
+            OverdubSource::Output => (&self.rendered_output[..], &self.rendered_output[..]),
+        };
+        overdub.write(left, right, input);
+    }
+
+    /// Writes the frame's input into the live input history
+    fn write_live_input(&mut self) {
+        if self.external_waveform.is_some() {
+            return;
+        }
+        if let Some(live_input) = &mut self.live_input {
+            live_input.write(&mut self.waveform, &self.audio_input);
+        }
+    }
+
+    /// Warns about the input problems that started with the last frame
+    fn log_status(&mut self) {
+        let new_flags = self.status & !self.logged_status;
+        let warnings = [
+            (
+                status::SELECTION_CLAMPED,
+                "selection clamped to the waveform",
+            ),
+            (
+                status::SELECTION_SWAPPED,
+                "selection end was before its start",
+            ),
+            (
+                status::SELECTION_FALLBACK,
+                "selection outside the waveform, using all of it",
+            ),
+            (
+                status::SELECTION_OUT_OF_BOUNDS,
+                "selection past the valid length, rendering silence",
+            ),
+        ];
+        for (flag, message) in warnings {
+            if new_flags & flag != 0 {
+                log::log(LogLevel::Warn, format_args!("{}", message));
+            }
+        }
+    }
+
+    /// Applies everything that overrides the host's targets, in order, and sanitizes the result
+    fn resolve_targets(&mut self, targets: &ParamValues) -> ParamValues {
+        let mut targets = *targets;
+        match (&self.config_morph, self.scenes) {
+            (Some(morph), _) => morph.apply(&mut targets),
+            (None, [Some(a), Some(b)]) => {
+                let t = clamp(0., 1., targets.global(GlobalParam::SceneMorph));
+                ConfigMorph { a, b, t }.apply(&mut targets);
+            }
+            _ => {}
+        }
+        self.macros.apply(&mut targets);
+        self.cc_map.apply(&mut targets);
+        self.notes.apply(&mut targets, self.tuning.as_ref());
+        if let Some((start, end)) = self.command_selection {
+            targets.set(ParamId::Global(GlobalParam::SelectionStartSampleIx), start);
+            targets.set(ParamId::Global(GlobalParam::SelectionEndSampleIx), end);
+        }
+        let grain_detune_cents = match &self.drone {
+            Some(drone) => {
+                drone.apply(&mut targets, self.sample_rate);
+                drone.grain_detune_cents()
+            }
+            None => 0.,
+        };
+        for voice in &mut self.voices {
+            voice.grain_detune_cents = grain_detune_cents;
+        }
+        if self.live_input.is_some() {
+            let end = self.waveform.len().saturating_sub(1) as f32;
+            targets.set(ParamId::Global(GlobalParam::SelectionStartSampleIx), 0.);
+            targets.set(ParamId::Global(GlobalParam::SelectionEndSampleIx), end);
+        }
+        if let Some(link) = &self.voice_link {
+            link.apply(&mut targets);
+            link.follow_settings(&mut self.voices);
+        }
+        targets.sanitize(self.params.target());
+        targets
+    }
+
+    /// Runs the queued commands that are due by sample `sample_ix` of the frame.  Commands that
+    /// run `mid_frame` take effect from that sample on, rather than from the next frame.
+    fn run_commands(&mut self, sample_ix: usize, mid_frame: bool) {
+        while let Some(command) = self.pop_due_command(sample_ix) {
+            let command = match command {
+                Ok(command) => command,
+                Err(err) => {
+                    log::log(
+                        LogLevel::Warn,
+                        format_args!("skipping unknown or invalid command of kind {}", err.kind),
+                    );
+                    continue;
+                }
+            };
+            let retarget = match command {
+                Command::LoadSlot {
+                    slot_id,
+                    crossfade_ms,
+                } => {
+                    // Copying a slot mid-frame would allocate in the render, so only a slot staged
+                    // beforehand is swapped in
+                    let swap = &self.waveform_swap;
+                    if swap.staged_slot != Some(slot_id)
+                        || (swap.staged.is_none() && swap.staged_compact.is_none())
+                    {
+                        log::log(
+                            LogLevel::Warn,
+                            format_args!("slot {} wasn't staged, so it isn't loaded", slot_id),
+                        );
+                        continue;
+                    }
+                    self.waveform_swap.pending = Some(SwapTail::Crossfade(crossfade_ms));
+                    if mid_frame {
+                        self.apply_pending_swap();
+                    }
+                    true
+                }
+                Command::SetSelection { start, end } => {
+                    self.command_selection = Some((start, end));
+                    true
+                }
+                Command::ReleaseSelection => {
+                    self.command_selection = None;
+                    true
+                }
+                Command::NoteOn { note, velocity } => {
+                    self.notes.note_on(0, note, velocity);
+                    true
+                }
+                Command::NoteOff { note } => {
+                    self.notes.note_off(0, note);
+                    true
+                }
+                Command::Reset { fade_ms } => {
+                    self.reset(fade_ms);
+                    false
+                }
+                Command::DroneHold { hold } => {
+                    self.hold_drone(hold);
+                    false
+                }
+                Command::Stutter { engaged } => {
+                    if let Some(stutter) = &mut self.stutter {
+                        stutter.set_engaged(engaged);
+                    }
+                    false
+                }
+            };
+            if mid_frame && retarget {
+                self.retarget(sample_ix);
+            }
+        }
+    }
+
+    /// Holds the drone: every voice is frozen into a loop a few seconds long, crossfaded into
+    /// itself for longer the more blur there is, so it sustains indefinitely.  Releasing it goes
+    /// back to granulating live.  Has no effect while drone mode is off.
+    fn hold_drone(&mut self, hold: bool) {
+        let Some(drone) = self.drone else {
+            return;
+        };
+        for voice in &mut self.voices {
+            voice.freeze = hold.then(|| {
+                VoiceFreeze::with_crossfade(
+                    drone.hold_loop_ms(),
+                    drone.hold_crossfade_ms(),
+                    self.sample_rate,
+                )
+            });
+        }
+    }
+
+    /// Takes the next command due by `sample_ix` of the frame, events in the I/O block going
+    /// before the command ring's
+    fn pop_due_command(&mut self, sample_ix: usize) -> Option<Result<Command, InvalidCommand>> {
+        if let Some(event) = self
+            .io_block
+            .as_mut()
+            .and_then(|io_block| io_block.pop_due(sample_ix))
+        {
+            return Some(event);
+        }
+        self.commands
+            .as_mut()
+            .and_then(|commands| commands.pop_due(sample_ix))
+    }
+
+    /// Carries out the playback change queued for this frame.  A stopped engine spawns no new
+    /// grains, and voices that start playing or jump start a grain on the first sample.
+    fn apply_playback_change(&mut self) {
+        if !self.playback.has_pending_change() {
+            return;
+        }
+        let was_playing = self.playback.state == PlaybackState::Playing;
+        let jump = self.playback.begin_frame();
+        let starting = self.playback.state == PlaybackState::Playing && !was_playing;
+        let params = &self.params.current;
+        for voice in &mut self.voices {
+            voice.spawning_enabled = !self.playback.is_stopped() && self.shutdown.is_none();
+            if starting || jump.is_some() {
+                voice.samples_since_last_grain = voice.grain_interval;
+            }
+            if let Some(sample_ix) = jump {
+                voice.cur_grain_start = sample_ix;
+                voice.move_read_head(
+                    params.global(GlobalParam::SelectionStartSampleIx),
+                    params.global(GlobalParam::SelectionEndSampleIx),
+                    params.global(GlobalParam::GrainSize),
+                    0.,
+                );
+            }
+        }
+    }
+
+    /// Works out the targets again for the rest of the frame from sample `sample_ix`
+    fn retarget(&mut self, sample_ix: usize) {
+        let host_targets = self.host_targets;
+        let mut targets = self.resolve_targets(&host_targets);
+        self.status |= self.validate_selection(&mut targets);
+        for param in [GlobalParam::LinearSlopeLength, GlobalParam::SlopeLinearity] {
+            let id = ParamId::Global(param);
+            targets.set(id, clamp(0., 1., targets.get(id)));
+        }
+        self.params.set_targets(&targets);
+        self.params.ramp_over(FRAME_SIZE - sample_ix);
+        let mut current = self.params.current;
+        self.clamp_selection(&mut current);
+        self.params.current = current;
+    }
+
+    fn render_frame(&mut self, targets: &ParamValues) {
+        self.profiler.begin_render();
+        self.apply_pending_swap();
+        if self.uncommitted_len.is_some() {
+            self.status = status::WAVEFORM_UNCOMMITTED;
+            self.render_silence();
+            return;
+        }
+        // There's nothing to read from so bail out before any of the grain math runs
+        if self.samples().is_empty() {
+            self.status = status::WAVEFORM_EMPTY;
+            self.render_silence();
+            return;
+        }
+
+        let mut targets = self.resolve_targets(targets);
+        if self.selection_exceeds_valid_len(&targets) {
+            self.status = status::SELECTION_OUT_OF_BOUNDS;
+            self.render_silence();
+            return;
+        }
+        self.status = self.validate_selection(&mut targets);
+        for param in [GlobalParam::LinearSlopeLength, GlobalParam::SlopeLinearity] {
+            let id = ParamId::Global(param);
+            targets.set(id, clamp(0., 1., targets.get(id)));
+        }
+        self.params.set_targets(&targets);
+
+        let selection_len = targets.global(GlobalParam::SelectionEndSampleIx)
+            - targets.global(GlobalParam::SelectionStartSampleIx);
+        if selection_len <= 0. {
+            self.status |= status::SELECTION_EMPTY;
+            self.render_silence();
+            return;
+        }
+
+        // Start and end samples must be within the waveform even if it was swapped for a shorter
+        // one since the last frame
+        let mut current = self.params.current;
+        self.clamp_selection(&mut current);
+        self.params.current = current;
+
+        self.apply_playback_change();
+
+        if self.transport.seeked {
+            self.transport.seeked = false;
+            let position_frames = self.transport.position_frames;
+            for (voice_ix, voice) in self.voices.iter_mut().enumerate() {
+                if voice.sync_beats.is_some() {
+                    voice.relock_read_head(&self.params.current, voice_ix, position_frames);
+                }
+            }
+        }
+
+        self.params.ramp_over(FRAME_SIZE);
+        if self.chunk_pos == 0 {
+            self.begin_chunk();
+        }
+        self.profiler.end_control();
+        for i in 0..FRAME_SIZE {
+            let synthesis_start = self.profiler.begin_synthesis();
+            let chunk_start = self.chunk_pos == 0;
+            // The first chunk of the frame began with the rest of its control work
+            if chunk_start && i > 0 {
+                self.begin_chunk();
+            }
+            self.chunk_pos = (self.chunk_pos + 1) % self.chunk_size;
+            if self.commands.is_some() || self.io_block.is_some() {
+                self.run_commands(i, true);
+            }
+            self.params.tick();
+            if self.automation.is_active() {
+                self.apply_automation(chunk_start);
+            }
+            if self.audio_rate.is_active() {
+                let mut current = self.params.current;
+                self.audio_rate.apply(i, &mut current);
+                self.clamp_selection(&mut current);
+                self.params.current = current;
+            }
+            self.modulation.sidechain_level =
+                self.sidechain_follower.process(self.sidechain_input[i]);
+            let output = self.get_sample();
+            let output = self.apply_stutter(output, self.audio_input[i]);
+            let output = self.apply_master_vibrato(output);
+            let output = self.apply_master_tape(output);
+            let output = self.apply_master_phaser(output);
+            let output = self.apply_master_tilt(output);
+            let output = self.apply_master_eq(output);
+            let output = self.apply_master_transients(output);
+            let output = self.apply_master_compressor(output, self.sidechain_input[i]);
+            let output = self.apply_master_saturation(output);
+            let output = self.apply_master_haas(output);
+            let output = self.apply_master_convolution(output);
+            let output_start = self.profiler.end_synthesis(synthesis_start);
+            let output = self.apply_output_safety(output);
+            self.meters.add_master_sample(output.left, output.right);
+            #[cfg(feature = "loudness")]
+            self.loudness.process(output.left, output.right);
+            self.rendered_output[i] = output.mono;
+            self.rendered_output_stereo[i] = output.left;
+            self.rendered_output_stereo[FRAME_SIZE + i] = output.right;
+            if self.output_layout == OutputLayout::Quad {
+                let channels = self
+                    .quad_split
+                    .split(output.quad_parts, output.left, output.right);
+                for (channel_ix, sample) in channels.into_iter().enumerate() {
+                    self.rendered_output_quad[channel_ix * FRAME_SIZE + i] = sample;
+                }
+            }
+            for (voice_output, (left, right)) in
+                self.rendered_voice_outputs.iter_mut().zip(output.voices)
+            {
+                voice_output[i] = left;
+                voice_output[FRAME_SIZE + i] = right;
+            }
+            self.transport.tick();
+            self.grain_trace.tick();
+            if let Some(track) = &mut self.grain_trace.track {
+                track.tick(self.voices.each_ref().map(|voice| voice.cur_grain_start));
+            }
+            self.profiler.end_output(output_start);
+        }
+        // Once every grain reading the old waveform has ended there's no reason to keep it around
+        if self.waveform_swap.retired.is_some()
+            && self
+                .voices
+                .iter()
+                .all(|voice| voice.grains.iter().all(|grain| !grain.retired))
+        {
+            self.drop_retired_waveform();
+        }
+        self.meters.finish_frame(FRAME_SIZE, self.sample_rate);
+        self.grain_stats
+            .finish_frame(&self.voices, FRAME_SIZE, self.sample_rate);
+        self.write_overdub();
+        self.write_live_input();
+        self.audio_input = [0.; FRAME_SIZE];
+        self.sidechain_input = [0.; FRAME_SIZE];
+        self.profiler.end_render();
+    }
+}
+
+/// Returns a mutable reference to the context behind a pointer from `handles::guard`, or `None`
+/// for a null pointer, which is what stale handles resolve to
+fn ctx_mut<'a>(ctx: *mut GranularCtx) -> Option<&'a mut GranularCtx> {
+    unsafe { ctx.as_mut() }
+}
+
+pub fn create_granular_instance() -> handles::InstanceHandle {
+    common::maybe_init(None);
+    common::set_raw_panic_hook();
+    handles::register(Box::default())
+}
+
+/// Allocates a waveform of `new_waveform_len` samples for the host to write.  Returns null,
+/// keeping the current waveform, if the length is over `MAX_WAVEFORM_LEN`.
+pub fn get_granular_waveform_ptr(ctx: *mut GranularCtx, new_waveform_len: usize) -> *mut f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    if new_waveform_len > MAX_WAVEFORM_LEN {
+        return std::ptr::null_mut();
+    }
+    ctx.load_waveform(vec![0.; new_waveform_len], None);
+    ctx.uncommitted_len = Some(new_waveform_len);
+    ctx.waveform.as_mut_ptr()
+}
+
+/// Tells the engine the host has finished writing the buffer from `get_granular_waveform_ptr`,
+/// which renders stay silent until.  Returns false, leaving the waveform uncommitted, if
+/// `written_len` isn't the length the buffer was allocated with.
+pub fn commit_waveform(ctx: *mut GranularCtx, written_len: usize) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if ctx.uncommitted_len != Some(written_len) {
+        return false;
+    }
+    ctx.uncommitted_len = None;
+    true
+}
+
+/// Starts streaming in a new waveform of `total_len` samples, abandoning any upload in progress.
+/// The current waveform keeps playing until `finish_waveform_upload`.  Returns false, without
+/// starting an upload, if `total_len` is over `MAX_WAVEFORM_LEN`.
+pub fn begin_waveform_upload(ctx: *mut GranularCtx, total_len: usize) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    ctx.waveform_upload = WaveformUpload::new(total_len);
+    ctx.waveform_upload.is_some()
+}
+
+/// Appends the next chunk of the waveform being uploaded.  Returns false if there's no upload in
+/// progress or the chunk runs past its announced length, in which case the excess is dropped.
+pub fn append_waveform_chunk(ctx: *mut GranularCtx, chunk: &[f32]) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    match &mut ctx.waveform_upload {
+        Some(upload) => upload.append(chunk),
+        None => false,
+    }
+}
+
+/// Replaces the waveform with the uploaded one.  Returns false, keeping the current waveform, if
+/// there's no upload in progress or fewer samples than announced were appended.
+pub fn finish_waveform_upload(ctx: *mut GranularCtx) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    match ctx.waveform_upload.take().and_then(WaveformUpload::finish) {
+        Some(samples) => {
+            let (samples, _) = ctx.load_options.prepare(samples, None, ctx.sample_rate);
+            ctx.load_processed_waveform(samples, None);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Allocates a staging buffer of `len` samples for the host to fill with the next waveform while
+/// the current one keeps playing, replacing any buffer that was staged before.  The waveform is
+/// mono unless `get_staging_waveform_right_ptr` is called as well.  Returns null if `len` is over
+/// `MAX_WAVEFORM_LEN`.
+pub fn get_staging_waveform_ptr(ctx: *mut GranularCtx, len: usize) -> *mut f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    if len > MAX_WAVEFORM_LEN {
+        return std::ptr::null_mut();
+    }
+    ctx.waveform_swap.pending = None;
+    ctx.waveform_swap.staged_right = None;
+    ctx.waveform_swap.staged_compact = None;
+    ctx.waveform_swap.staged_slot = None;
+    ctx.waveform_swap.staged.insert(vec![0.; len]).as_mut_ptr()
+}
+
+/// Allocates the right channel of the staged waveform, making it a stereo waveform.  Returns
+/// null if nothing has been staged or `len` isn't the length of the staged left channel.
+pub fn get_staging_waveform_right_ptr(ctx: *mut GranularCtx, len: usize) -> *mut f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    if ctx.waveform_swap.staged.as_ref().map(Vec::len) != Some(len) {
+        return std::ptr::null_mut();
+    }
+    ctx.waveform_swap.pending = None;
+    ctx.waveform_swap.staged_slot = None;
+    ctx.waveform_swap
+        .staged_right
+        .insert(vec![0.; len])
+        .as_mut_ptr()
+}
+
+/// Swaps the staged waveform in at the start of the next frame.  Grains playing from the current
+/// waveform keep doing so while they fade out over `crossfade_ms`.  Returns false if nothing has
+/// been staged.
+pub fn swap_staging_waveform(ctx: *mut GranularCtx, crossfade_ms: f32) -> bool {
+    if !crossfade_ms.is_finite() {
+        return false;
+    }
+    request_swap(ctx, SwapTail::Crossfade(crossfade_ms.max(0.)))
+}
+
+/// Like `swap_staging_waveform`, but grains playing from the current waveform aren't faded and
+/// play out in full, so the replacement is gapless.  New grains read the staged waveform.
+pub fn swap_staging_waveform_gapless(ctx: *mut GranularCtx) -> bool {
+    request_swap(ctx, SwapTail::Finish)
+}
+
+fn request_swap(ctx: *mut GranularCtx, tail: SwapTail) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(staged) = ctx.waveform_swap.staged.take() else {
+        return false;
+    };
+    let staged_right = ctx.waveform_swap.staged_right.take();
+    ctx.waveform_swap.staged_slot = None;
+    let (staged, staged_right) = ctx
+        .load_options
+        .prepare(staged, staged_right, ctx.sample_rate);
+    ctx.stage_processed_waveform(staged, staged_right);
+    ctx.waveform_swap.pending = Some(tail);
+    true
+}
+
+/// Sets the sample rate of waveforms the host loads from now on, so that they're resampled to the
+/// engine's rate with the given `quality` (0 = fast, 1 = standard, 2 = best).  A rate of 0 means
+/// waveforms are already at the engine's rate, and other rates outside of `SAMPLE_RATES` are
+/// ignored.  Applies to uploads, staged swaps and `process_loaded_waveform`, which resample
+/// within the call.
+pub fn set_waveform_source_rate(ctx: *mut GranularCtx, source_sample_rate: f32, quality: u32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(quality) = ResampleQuality::from_index(quality) else {
+        return;
+    };
+    if source_sample_rate != 0. && !is_supported_sample_rate(source_sample_rate) {
+        return;
+    }
+    ctx.load_options.source_sample_rate = (source_sample_rate > 0.).then_some(source_sample_rate);
+    ctx.load_options.resample_quality = quality;
+}
+
+/// Sets the processing of waveforms the host loads from now on: DC offset removal and
+/// normalization of either the peak level to `target` dBFS (`mode` 1) or the integrated loudness
+/// to `target` LUFS (`mode` 2, only with the `loudness` feature).  A `mode` of 0 disables
+/// normalization.
+pub fn set_waveform_normalization(ctx: *mut GranularCtx, remove_dc: bool, mode: u32, target: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !target.is_finite() {
+        return;
+    }
+    let normalization = match mode {
+        0 => Normalization::Off,
+        1 => Normalization::Peak(target),
+        #[cfg(feature = "loudness")]
+        2 => Normalization::Loudness(target),
+        _ => return,
+    };
+    ctx.load_options.remove_dc = remove_dc;
+    ctx.load_options.normalization = normalization;
+}
+
+/// Sets the format waveforms the host loads from now on are kept in (0 = 32-bit float, 1 =
+/// 16-bit), as described by `get_waveform_storage_metadata`.  Applies to uploads, imports, swaps
+/// and `process_loaded_waveform`.  Waveforms in 16-bit storage take half the memory but can't be
+/// overdubbed.
+pub fn set_waveform_storage(ctx: *mut GranularCtx, storage: u32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(storage) = WaveformStorage::from_index(storage) else {
+        return;
+    };
+    ctx.load_options.storage = storage;
+}
+
+pub fn get_waveform_storage_metadata() -> String {
+    waveform::storage_metadata_json()
+}
+
+/// Sets how many samples apart the control-rate work runs: control-rate parameters step and
+/// follow their automation lanes, and voices update their window tables and density
+/// compensation, once per chunk.  The default is a frame.  Smaller chunks track parameter changes
+/// more closely and larger ones save work, e.g. offline.  The new size applies from the next
+/// chunk.  Returns false, keeping the current size, if it's outside 16 to 2048 samples.
+pub fn set_processing_chunk_size(ctx: *mut GranularCtx, chunk_size: usize) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if !CHUNK_SIZES.contains(&chunk_size) {
+        return false;
+    }
+    ctx.chunk_size = chunk_size;
+    ctx.chunk_pos = 0;
+    true
+}
+
+/// Applies the load options to a waveform written through `get_granular_waveform_ptr` once it's
+/// been committed.  Returns the new length, or 0 if it hasn't been committed yet.
+pub fn process_loaded_waveform(ctx: *mut GranularCtx) -> usize {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    if ctx.uncommitted_len.is_some() {
+        return 0;
+    }
+    // Bound waveforms aren't processed, and ones in 16-bit storage already have been
+    if ctx.external_waveform.is_some() || ctx.compact_waveform.is_some() {
+        return ctx.allocated_samples().len();
+    }
+    let samples = std::mem::take(&mut ctx.waveform);
+    let (samples, right) =
+        ctx.load_options
+            .prepare(samples, ctx.waveform_right.take(), ctx.sample_rate);
+    ctx.load_processed_waveform(samples, right);
+    ctx.samples().len()
+}
+
+/// Number of samples allocated for the waveform, which is what the host may write to, no matter
+/// how many of them have been declared valid
+pub fn get_waveform_capacity(ctx: *mut GranularCtx) -> usize {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.allocated_samples().len(),
+        None => 0,
+    }
+}
+
+/// Declares how many samples at the start of the waveform hold audio, for hosts that reuse one
+/// allocation for shorter samples.  From then on renders whose selection reaches past that length
+/// are rejected with `status::SELECTION_OUT_OF_BOUNDS` rather than clamped, and grains never read
+/// past it.  Loading a new waveform clears it.  Returns false if `valid_len` exceeds the capacity.
+pub fn set_waveform_valid_len(ctx: *mut GranularCtx, valid_len: usize) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if valid_len > ctx.allocated_samples().len() {
+        return false;
+    }
+    ctx.waveform_valid_len = Some(valid_len);
+    true
+}
+
+/// Makes grains read `len` samples at `ptr` in the host's memory instead of a copy, freeing the
+/// loaded waveform.  Returns the generation of the binding to pass to `unbind_external_waveform`,
+/// or 0, keeping the current waveform, for a null or misaligned pointer or a `len` no slice can
+/// have.
+///
+/// The memory must stay valid and unmodified until it's unbound or another waveform is loaded.
+/// Load options don't apply to it and it can't be cropped.
+pub fn bind_external_waveform(ctx: *mut GranularCtx, ptr: *const f32, len: usize) -> u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    let generation = ctx.external_generation.wrapping_add(1).max(1);
+    // SAFETY: the host keeps the memory valid until it unbinds it, as documented above
+    let Some(external) = (unsafe { ExternalWaveform::new(ptr, len, generation) }) else {
+        return 0;
+    };
+    ctx.load_waveform(Vec::new(), None);
+    ctx.reset_voices();
+    ctx.external_generation = generation;
+    ctx.external_waveform = Some(external);
+    generation
+}
+
+/// Stops reading the external waveform bound with `generation`, after which the host may free or
+/// reuse its memory.  Returns false if that binding is no longer current, e.g. because another
+/// waveform was bound or loaded since.
+pub fn unbind_external_waveform(ctx: *mut GranularCtx, generation: u32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if ctx
+        .external_waveform
+        .as_ref()
+        .is_none_or(|external| external.generation != generation)
+    {
+        return false;
+    }
+    ctx.external_waveform = None;
+    ctx.waveform_valid_len = None;
+    ctx.reset_voices();
+    true
+}
+
+/// Generation of the bound external waveform, or 0 if none is bound
+pub fn get_external_waveform_generation(ctx: *mut GranularCtx) -> u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    ctx.external_waveform
+        .as_ref()
+        .map_or(0, |external| external.generation)
+}
+
+/// Trims the waveform to the samples from `start` up to but not including `end`, freeing the rest.
+/// Selections sent afterwards are relative to the new start.  Returns the new length, or 0 if
+/// the region is empty, in which case the waveform is left alone.
+pub fn crop_waveform(ctx: *mut GranularCtx, start: usize, end: usize) -> usize {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    if !ctx.crop_waveform(start, end) {
+        return 0;
+    }
+    ctx.allocated_samples().len()
+}
+
+/// Loads interleaved frames of `channel_count` samples, either summed to mono or, with
+/// `keep_stereo`, as a stereo waveform made of the first two channels.  The load options are
+/// applied to every channel.  Returns the length of the loaded waveform.
+pub fn import_interleaved_waveform(
+    ctx: *mut GranularCtx,
+    interleaved: &[f32],
+    channel_count: usize,
+    keep_stereo: bool,
+) -> usize {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    if channel_count == 0 {
+        return 0;
+    }
+    let (samples, right) = waveform::deinterleave(interleaved, channel_count, keep_stereo);
+    let (samples, right) = ctx.load_options.prepare(samples, right, ctx.sample_rate);
+    ctx.load_processed_waveform(samples, right);
+    ctx.samples().len()
+}
+
+/// Per-voice parameters `render_granular` takes, in the order of the rows at
+/// `get_voice_render_params_ptr`
+const VOICE_RENDER_PARAMS: [VoiceParam; 6] = [
+    VoiceParam::FilterCutoff,
+    VoiceParam::MovementSamplesPerSample,
+    VoiceParam::SampleSpeedRatio,
+    VoiceParam::SamplesBetweenGrains,
+    VoiceParam::Gain,
+    VoiceParam::GrainStartRandomnessSamples,
+];
+
+/// Returns a pointer to the per-voice parameters `render_granular` reads, so that the host can
+/// write them in place rather than passing arrays with every render.  There's a row of one value
+/// per voice, indexed by voice, for each of filter cutoff, movement, speed ratio, spacing, gain
+/// and start randomness in that order.  Values that aren't finite, which they all are until the
+/// host writes them, keep the voice's current target.  The buffer never moves.
+pub fn get_voice_render_params_ptr(ctx: *mut GranularCtx) -> *mut f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.voice_render_params.as_mut_ptr().cast(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Renders a frame with the given global parameters and the per-voice ones at
+/// `get_voice_render_params_ptr`
+pub fn render_granular(
+    ctx: *mut GranularCtx,
     selection_start_sample_ix: f32,
     selection_end_sample_ix: f32,
     grain_size: f32,
-    voice_1_filter_cutoff: f32,
-    voice_2_filter_cutoff: f32,
     linear_slope_length: f32,
     slope_linearity: f32,
-    voice_1_movement_samples_per_sample: f32,
-    voice_2_movement_samples_per_sample: f32,
-    voice_1_sample_speed_ratio: f32,
-    voice_2_sample_speed_ratio: f32,
-    voice_1_samples_between_grains: f32,
-    voice_2_samples_between_grains: f32,
-    voice_1_gain: f32,
-    voice_2_gain: f32,
-    voice_1_grain_start_randomness_samples: f32,
-    voice_2_grain_start_randomness_samples: f32,
 ) -> *const f32 {
-    let ctx = unsafe { &mut *ctx };
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null();
+    };
+
+    // Parameters that aren't passed on every call keep whatever their setters last set them to
+    let mut targets = *ctx.params.target();
+    let globals = [
+        (
+            GlobalParam::SelectionStartSampleIx,
+            selection_start_sample_ix,
+        ),
+        (GlobalParam::SelectionEndSampleIx, selection_end_sample_ix),
+        (GlobalParam::GrainSize, grain_size),
+        (GlobalParam::LinearSlopeLength, linear_slope_length),
+        (GlobalParam::SlopeLinearity, slope_linearity),
+    ];
+    for (param, value) in globals {
+        targets.set(ParamId::Global(param), value);
+    }
+    for (param, values) in VOICE_RENDER_PARAMS
+        .into_iter()
+        .zip(&ctx.voice_render_params)
+    {
+        for (voice_ix, value) in values.iter().enumerate() {
+            if value.is_finite() {
+                targets.set(ParamId::Voice(voice_ix, param), *value);
+            }
+        }
+    }
+
+    ctx.render(&targets);
+    ctx.output_ptr()
+}
+
+/// Returns a pointer to the packed parameter block described in `param_block`, creating it with
+/// the engine's own header and zeroed values on the first call.  The block never moves, so hosts
+/// can keep a view of it across renders.
+pub fn get_param_block_ptr(ctx: *mut GranularCtx) -> *mut u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    ctx.param_block
+        .get_or_insert_with(ParamBlock::default)
+        .as_mut_ptr()
+}
+
+/// Renders a frame with the parameters in the packed parameter block.  If the block's header
+/// isn't one the engine can read, the previous targets are kept and the frame's status has
+/// `status::PARAM_BLOCK_INVALID` set.
+pub fn render_granular_block(ctx: *mut GranularCtx) -> *const f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null();
+    };
+    let mut targets = *ctx.params.target();
+    let result = ctx
+        .param_block
+        .get_or_insert_with(ParamBlock::default)
+        .read(&mut targets);
+    render_from_block(ctx, &targets, result)
+}
+
+/// Returns a pointer to the shared parameter block described in `param_block`: a sequence
+/// counter followed by a packed parameter block, created like the packed block on the first call
+#[cfg(shared_memory)]
+pub fn get_shared_param_block_ptr(ctx: *mut GranularCtx) -> *mut u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null_mut();
+    };
+    ctx.shared_param_block
+        .get_or_insert_with(SharedParamBlock::default)
+        .as_mut_ptr()
+}
+
+/// Renders a frame with the parameters in the shared parameter block, if the host has finished
+/// writing them since the last frame, or else with the previous targets.  An unreadable header
+/// sets `status::PARAM_BLOCK_INVALID` like it does for the packed block.
+#[cfg(shared_memory)]
+pub fn render_granular_shared(ctx: *mut GranularCtx) -> *const f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null();
+    };
+    let mut targets = *ctx.params.target();
+    let result = ctx
+        .shared_param_block
+        .get_or_insert_with(SharedParamBlock::default)
+        .read(&mut targets)
+        .map(|_| ());
+    render_from_block(ctx, &targets, result)
+}
+
+fn render_from_block(
+    ctx: &mut GranularCtx,
+    targets: &ParamValues,
+    result: Result<(), ParamBlockError>,
+) -> *const f32 {
+    let was_invalid = ctx.status & status::PARAM_BLOCK_INVALID != 0;
+    ctx.render(targets);
+    if let Err(err) = result {
+        ctx.status |= status::PARAM_BLOCK_INVALID;
+        if !was_invalid {
+            log::log(
+                LogLevel::Warn,
+                format_args!(
+                    "unreadable parameter block, keeping previous values: {:?}",
+                    err
+                ),
+            );
+        }
+    }
+    ctx.output_ptr()
+}
+
+/// Makes the engine deterministic from here on by seeding its random number generators with
+/// `seed` and resetting the voices, e.g. for comparing renders against golden files
+pub fn set_random_seed(ctx: *mut GranularCtx, seed: u32) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.seed(seed as u64);
+    }
+}
+
+/// Renders `duration_frames` samples, up to `MAX_RECORDING_FRAMES`, of the stereo output with
+/// the current parameters into a new sample slot and returns its id, for resampling a texture and
+/// granulating the result.  The render runs on an offline copy of the instance, so it isn't
+/// recorded or overdubbed and live playback carries on from where it was.  Returns `u32::MAX` if
+/// there's nothing to render.
+pub fn bounce_selection(ctx: *mut GranularCtx, duration_frames: usize) -> u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return u32::MAX;
+    };
+    if duration_frames == 0 || ctx.samples().is_empty() {
+        return u32::MAX;
+    }
+    let duration_frames = duration_frames.min(MAX_RECORDING_FRAMES);
+    let targets = *ctx.params.target();
+    let mut copy = ctx.offline_copy();
+    ctx.swap_lent_buffers(&mut copy);
+    let capacity = duration_frames.next_multiple_of(FRAME_SIZE);
+    let (mut left, mut right) = (Vec::with_capacity(capacity), Vec::with_capacity(capacity));
+    while left.len() < duration_frames {
+        copy.render(&targets);
+        let (frame_left, frame_right) = copy.rendered_output_stereo.split_at(FRAME_SIZE);
+        left.extend_from_slice(frame_left);
+        right.extend_from_slice(frame_right);
+    }
+    ctx.swap_lent_buffers(&mut copy);
+    left.truncate(duration_frames);
+    right.truncate(duration_frames);
+    ctx.sample_slots.add_stereo(left, Some(right))
+}
+
+/// `duration_ms` of the test signal numbered `signal` at the engine's rate, or None for unknown
+/// signals and durations that aren't positive.  Durations are cut to `MAX_TEST_SIGNAL_SECONDS`.
+fn test_signal_samples(ctx: &GranularCtx, signal: u32, duration_ms: f32) -> Option<Vec<f32>> {
+    let signal = TestSignal::from_u32(signal)?;
+    if duration_ms.is_nan() || duration_ms <= 0. {
+        return None;
+    }
+    let seconds = (duration_ms / 1000.).min(MAX_TEST_SIGNAL_SECONDS);
+    let len = ((seconds * ctx.sample_rate) as usize).max(1);
+    Some(signal.generate(len, ctx.sample_rate))
+}
+
+/// Replaces the waveform with `duration_ms` of a test signal: 0 = exponential sine sweep,
+/// 1 = impulse, 2 = white noise.  Load options other than the storage don't apply, so the
+/// signals keep their levels.  Returns false, keeping the waveform, for an unknown signal or a
+/// duration that isn't positive.
+pub fn load_test_signal(ctx: *mut GranularCtx, signal: u32, duration_ms: f32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    match test_signal_samples(ctx, signal, duration_ms) {
+        Some(samples) => {
+            ctx.load_processed_waveform(samples, None);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stores a test signal, as `load_test_signal` generates it, in a new sample slot and returns
+/// its id, so voices can play it instead of the waveform without replacing it.  Returns
+/// `u32::MAX` for an unknown signal or a duration that isn't positive.
+pub fn add_test_signal_slot(ctx: *mut GranularCtx, signal: u32, duration_ms: f32) -> u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return u32::MAX;
+    };
+    match test_signal_samples(ctx, signal, duration_ms) {
+        Some(samples) => ctx.sample_slots.add(samples),
+        None => u32::MAX,
+    }
+}
+
+/// Renders `duration_seconds` of output with the current parameter targets and automation lanes
+/// as interleaved stereo, rounded up to whole frames and at most `MAX_OFFLINE_FRAMES`, without
+/// disturbing live playback.  See `GranularCtx::render_offline` for when `progress` is called.
+pub fn render_offline(
+    ctx: *mut GranularCtx,
+    duration_seconds: f32,
+    progress_interval: usize,
+    progress: impl FnMut(usize, usize),
+) -> Vec<f32> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    if !duration_seconds.is_finite() || duration_seconds <= 0. {
+        return Vec::new();
+    }
+    let frames = (duration_seconds * ctx.sample_rate / FRAME_SIZE as f32).ceil() as usize;
+    let targets = *ctx.params.target();
+    ctx.render_offline(&targets, frames, progress_interval, progress)
+}
+
+/// Starts a chunked offline render from the beginning of the automation lanes on a copy of the
+/// instance, so live playback carries on where it was; see `render_next_chunk`
+pub fn start_chunked_render(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.start_chunked_render();
+    }
+}
+
+/// Renders the next `frames` frames, up to `MAX_OFFLINE_FRAMES`, of a chunked offline render with
+/// the current parameter targets and returns a pointer to them as `frames * 256` interleaved
+/// stereo samples, or null if no chunked render was started.  The pointer is valid until the
+/// next chunk, so the host copies each chunk out before asking for the next one.
+pub fn render_next_chunk(ctx: *mut GranularCtx, frames: usize) -> *const f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return std::ptr::null();
+    };
+    let targets = *ctx.params.target();
+    ctx.render_chunk(&targets, frames)
+        .map_or(std::ptr::null(), <[f32]>::as_ptr)
+}
+
+/// Resamples interleaved audio such as an offline render from `from_rate` to `to_rate`, e.g. to
+/// export it at a standard rate.  Returns an empty buffer for invalid arguments, including rates
+/// outside the supported sample rates, whose ratios could ask for more output than fits in memory.
+pub fn resample_interleaved(
+    samples: &[f32],
+    channels: usize,
+    from_rate: f32,
+    to_rate: f32,
+    quality: u32,
+) -> Vec<f32> {
+    let Some(quality) = ResampleQuality::from_index(quality) else {
+        return Vec::new();
+    };
+    if channels == 0 || !is_supported_sample_rate(from_rate) || !is_supported_sample_rate(to_rate) {
+        return Vec::new();
+    }
+    resample::resample_interleaved(samples, channels, from_rate, to_rate, quality)
+}
+
+/// Renders `frames` frames one after another straight into `frames * FRAME_SIZE` samples at
+/// `out_ptr` in the host's memory, such as a ring buffer shared with the audio thread, using the
+/// current parameter targets.  Returns the number of samples written, which is 0 for a null or
+/// misaligned pointer, a region too long for a slice or one that overlaps the instance.
+///
+/// The memory must otherwise be valid for writes of that many samples and not be read or written
+/// by anything else during the call.
+pub fn render_granular_into(ctx: *mut GranularCtx, out_ptr: *mut f32, frames: usize) -> usize {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    if out_ptr.is_null() || !out_ptr.is_aligned() {
+        return 0;
+    }
+    let Some(bytes) = frames
+        .checked_mul(FRAME_SIZE * std::mem::size_of::<f32>())
+        .filter(|bytes| *bytes <= isize::MAX as usize)
+    else {
+        return 0;
+    };
+    // The instance is written to while rendering, so the output can't be in it
+    let instance_start = ctx as *const GranularCtx as usize;
+    let instance_end = instance_start + std::mem::size_of::<GranularCtx>();
+    let out_start = out_ptr as usize;
+    if out_start < instance_end && instance_start < out_start.saturating_add(bytes) {
+        return 0;
+    }
+    let len = frames * FRAME_SIZE;
+    // SAFETY: the pointer is aligned, the region fits in a slice and doesn't overlap the instance,
+    // and the host passes memory it owns of at least `len` samples, as documented above
+    let out = unsafe { std::slice::from_raw_parts_mut(out_ptr, len) };
+    let targets = *ctx.params.target();
+    for frame in out.chunks_exact_mut(FRAME_SIZE) {
+        ctx.render(&targets);
+        frame.copy_from_slice(&ctx.rendered_output);
+    }
+    len
+}
+
+/// Frees the instance behind `handle`.  Freeing it twice only logs a warning.
+pub fn free_granular_instance(handle: handles::InstanceHandle) {
+    if !handles::release(handle) {
+        log::log(
+            LogLevel::Warn,
+            format_args!("free of stale or invalid instance handle {:#x}", handle),
+        );
+    }
+}
+
+/// Fades the instance out over `fade_ms` without spawning any new grains so that it can be freed
+/// once `is_drained` reports `true` without an audible click
+pub fn begin_shutdown(ctx: *mut GranularCtx, fade_ms: f32) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.begin_shutdown(if fade_ms.is_finite() { fade_ms } else { 0. });
+    }
+}
+
+pub fn is_drained(ctx: *mut GranularCtx) -> bool {
+    ctx_mut(ctx).map(|ctx| ctx.is_drained()).unwrap_or(true)
+}
+
+/// See `GranularCtx::latency_frames`
+pub fn get_latency_frames(ctx: *mut GranularCtx) -> u32 {
+    ctx_mut(ctx).map_or(0, |ctx| ctx.latency_frames() as u32)
+}
+
+/// See `GranularCtx::tail_frames`.  Returns `u32::MAX` when the output never goes silent.
+pub fn get_tail_frames(ctx: *mut GranularCtx) -> u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0;
+    };
+    ctx.tail_frames()
+        .map_or(u32::MAX, |frames| frames.min(u32::MAX as usize - 1) as u32)
+}
+
+fn set_voice_flag(ctx: *mut GranularCtx, voice_ix: usize, param: VoiceParam, enabled: bool) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= params::VOICE_COUNT {
+        return;
+    }
+    ctx.params.set_target(
+        ParamId::Voice(voice_ix, param),
+        if enabled { 1. } else { 0. },
+    );
+}
+
+pub fn set_voice_mute(ctx: *mut GranularCtx, voice_ix: usize, muted: bool) {
+    set_voice_flag(ctx, voice_ix, VoiceParam::Mute, muted);
+}
+
+pub fn set_voice_solo(ctx: *mut GranularCtx, voice_ix: usize, soloed: bool) {
+    set_voice_flag(ctx, voice_ix, VoiceParam::Solo, soloed);
+}
+
+/// Turns a voice's overlap compensation on or off by setting its `OverlapCompensation`
+/// parameter.  While it's on, the sum of the voice's grains is scaled by the inverse of the
+/// amplitude they're expected to add up to, worked out once per processing chunk from the
+/// envelope shape, grain duration and spacing and slewed, instead of being divided by the sum of
+/// their gains on every sample.  This keeps the level steady while texture parameters change.
+/// Overlap-add mode and density compensation take precedence over it.
+pub fn set_voice_overlap_compensation(ctx: *mut GranularCtx, voice_ix: usize, enabled: bool) {
+    set_voice_flag(ctx, voice_ix, VoiceParam::OverlapCompensation, enabled);
+}
+
+pub fn set_voice_pan(ctx: *mut GranularCtx, voice_ix: usize, pan: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= params::VOICE_COUNT || !pan.is_finite() {
+        return;
+    }
+    ctx.params.set_target(
+        ParamId::Voice(voice_ix, VoiceParam::Pan),
+        clamp(-1., 1., pan),
+    );
+}
+
+/// Switches every voice between the built-in mixing, which divides the sum of overlapping grains
+/// by the sum of their envelope gains, and overlap-add mixing.  In overlap-add mode grains use a
+/// Hann window, which adds up to a constant when grains overlap evenly, and the sum is scaled by
+/// the grain spacing and duration so that the level stays put as grain size and density change
+/// instead of pumping.  The voices' own envelope settings, skew and click guard are ignored in this
+/// mode.
+pub fn set_overlap_add_mode(ctx: *mut GranularCtx, enabled: bool) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    for voice in &mut ctx.voices {
+        voice.envelope_mut().overlap_add = enabled;
+    }
+}
+
+/// Sets the chance, from 0 to 1, that each grain a voice schedules is dropped instead of spawned.
+/// Grains that are left keep to the voice's clock, grid or groove.  The `GrainSkip` modulation
+/// destination is added on top.
+pub fn set_voice_grain_skip_probability(ctx: *mut GranularCtx, voice_ix: usize, probability: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= params::VOICE_COUNT || !probability.is_finite() {
+        return;
+    }
+    ctx.params.set_target(
+        ParamId::Voice(voice_ix, VoiceParam::GrainSkipProbability),
+        clamp(0., 1., probability),
+    );
+}
+
+/// Turns density compensation on or off for every voice.  While it's on, the sum of a voice's
+/// grains is scaled by the inverse square root of the energy of the grains playing at once,
+/// worked out from the voice's grain spacing, duration and envelope shape, instead of being
+/// divided by the sum of their envelope gains.  This keeps the level roughly constant while
+/// density and grain size are tweaked.  Overlap-add mode takes precedence over it.
+pub fn set_density_compensation(ctx: *mut GranularCtx, enabled: bool) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    for voice in &mut ctx.voices {
+        voice.density_compensation.enabled = enabled;
+    }
+}
+
+/// Returns a pointer to the mono output buffer that every render writes to, and returns with the
+/// default mono layout.  It stays the same for the life of the instance, so hosts can create
+/// their view of it once.
+pub fn get_output_ptr(ctx: *mut GranularCtx) -> *const f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.rendered_output.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Returns a pointer to the planar stereo output of the last rendered frame: 128 left samples
+/// followed by 128 right samples
+pub fn get_stereo_output_ptr(ctx: *mut GranularCtx) -> *const f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.rendered_output_stereo.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Sets the output layout that renders return: 1 channel for mono, 2 for planar stereo and 4 for
+/// planar quad.  Returns false, changing nothing, for any other count.
+pub fn configure_output(ctx: *mut GranularCtx, channels: u32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(layout) = OutputLayout::from_channels(channels) else {
+        return false;
+    };
+    if layout == OutputLayout::Quad && ctx.output_layout != OutputLayout::Quad {
+        ctx.rendered_output_quad = [0.; FRAME_SIZE * 4];
+    }
+    ctx.output_layout = layout;
+    true
+}
+
+/// Returns the number of channels of the configured output layout
+pub fn get_output_channels(ctx: *mut GranularCtx) -> u32 {
+    ctx_mut(ctx).map_or(1, |ctx| ctx.output_layout.channels())
+}
+
+/// Returns a pointer to the planar stereo output of one voice for the last rendered frame, laid
+/// out like `get_stereo_output_ptr`, or null for an invalid voice.  The voice outputs add up to
+/// the stereo output unless it was clamped.
+pub fn get_voice_output_ptr(ctx: *mut GranularCtx, voice_ix: usize) -> *const f32 {
+    match ctx_mut(ctx).and_then(|ctx| ctx.rendered_voice_outputs.get(voice_ix)) {
+        Some(voice_output) => voice_output.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Returns a pointer to the `FRAME_SIZE` samples of audio input that the next render consumes
+pub fn get_audio_input_ptr(ctx: *mut GranularCtx) -> *mut f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.audio_input.as_mut_ptr(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Returns a pointer to the `FRAME_SIZE` samples of sidechain input that the next render consumes
+pub fn get_sidechain_input_ptr(ctx: *mut GranularCtx) -> *mut f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.sidechain_input.as_mut_ptr(),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Syncs a voice's grain spawning to the host's tempo with one grain every `beats` beats.  A
+/// value of 0 goes back to `samples_between_grains`.
+pub fn set_voice_grain_sync(ctx: *mut GranularCtx, voice_ix: usize, beats: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= params::VOICE_COUNT || !beats.is_finite() {
+        return;
+    }
+    ctx.voices[voice_ix].sync_beats = if beats > 0. { Some(beats) } else { None };
+}
+
+/// Sizes and spaces a voice's grains in note values of the host's tempo, from 1/32 to 1 bar of
+/// `transport::BEATS_PER_BAR` beats, so rhythmic patterns follow tempo changes.  A value of 0 goes
+/// back to the grain size parameter or to `samples_between_grains`.  The spacing is the same grid
+/// as `set_voice_grain_sync`'s.
+pub fn set_voice_grain_note_values(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    size_bars: f32,
+    spacing_bars: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= params::VOICE_COUNT || !size_bars.is_finite() || !spacing_bars.is_finite() {
+        return;
+    }
+    let beats =
+        |bars: f32| (bars > 0.).then(|| clamp(1. / 32., 1., bars) * transport::BEATS_PER_BAR);
+    let voice = &mut ctx.voices[voice_ix];
+    voice.size_sync_beats = beats(size_bars);
+    voice.sync_beats = beats(spacing_bars);
+}
+
+/// Shapes the attacks and tails of the master output: an `attack` from 0 to 1 emphasizes the
+/// onsets of grains and one from 0 to -1 softens them, and a `sustain` from 0 to 1 draws out their
+/// tails and one from 0 to -1 shortens them, by up to 12 dB.  Both at 0 turn the shaper off.
+pub fn set_master_transient_shaper(ctx: *mut GranularCtx, attack: f32, sustain: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !attack.is_finite() || !sustain.is_finite() {
+        return;
+    }
+    let attack = clamp(-1., 1., attack);
+    let sustain = clamp(-1., 1., sustain);
+    let sample_rate = ctx.sample_rate;
+    match &mut ctx.master_transients {
+        _ if attack == 0. && sustain == 0. => ctx.master_transients = None,
+        Some(shaper) => {
+            shaper.attack = attack;
+            shaper.sustain = sustain;
+        }
+        shaper => *shaper = Some(TransientShaper::new(attack, sustain, sample_rate)),
+    }
+}
+
+/// Saturates the master output with curve `model` (0 for tanh, 1 for a soft-knee polynomial, 2
+/// for a diode clipper and 3 for an asymmetric tube curve), driven by `drive_db` (0 to 24 dB) and
+/// run at `oversampling` (1, 2 or 4) times the sample rate to keep aliasing down.  Full scale
+/// stays full scale at any drive.  Returns false, changing nothing, for an unknown model or
+/// oversampling factor.
+pub fn set_master_saturation(
+    ctx: *mut GranularCtx,
+    model: u32,
+    drive_db: f32,
+    oversampling: u32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(model) = SaturationModel::from_index(model) else {
+        return false;
+    };
+    if !matches!(oversampling, 1 | 2 | 4) || !drive_db.is_finite() {
+        return false;
+    }
+    let drive = 10f32.powf(clamp(0., 24., drive_db) / 20.);
+    let oversampling = oversampling as usize;
+    match &mut ctx.master_saturation {
+        // Changing the oversampling needs new filters
+        Some(saturators) if saturators[0].oversampling() == oversampling => {
+            for saturator in saturators {
+                saturator.model = model;
+                saturator.drive = drive;
+            }
+        }
+        saturators => {
+            let saturator = Saturator::new(model, drive, oversampling, ctx.sample_rate);
+            *saturators = Some([saturator; 3]);
+        }
+    }
+    true
+}
+
+/// Removes the master output's saturation
+pub fn clear_master_saturation(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.master_saturation = None;
+    }
+}
+
+/// Enables or disables the limiter ahead of the output clamp.  `threshold` is a linear peak level
+/// that's capped at the output ceiling of 1.
+pub fn set_limiter(ctx: *mut GranularCtx, enabled: bool, threshold: f32, release_ms: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.limiter_enabled = enabled;
+    if threshold.is_finite() && threshold > 0. {
+        ctx.limiter.threshold = threshold.min(OUTPUT_CEILING);
+    }
+    if release_ms.is_finite() {
+        ctx.limiter.set_release(release_ms, ctx.sample_rate);
+    }
+    if !enabled {
+        ctx.limiter.reset();
+    }
+}
+
+/// Implements a "panic" button; see `GranularCtx::reset`
+pub fn reset(ctx: *mut GranularCtx, fade_ms: f32) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.reset(if fade_ms.is_finite() { fade_ms } else { 0. });
+    }
+}
+
+/// Makes a voice read the selection as if the waveform were reversed.  Grains that are already
+/// playing finish in their original direction.
+pub fn set_voice_reversed_source(ctx: *mut GranularCtx, voice_ix: usize, reversed: bool) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(voice) = ctx.voices.get_mut(voice_ix) else {
+        return;
+    };
+    voice.reversed_source = reversed;
+}
+
+/// Returns the `status` flags of the most recently rendered frame
+pub fn get_status(ctx: *mut GranularCtx) -> u32 {
+    ctx_mut(ctx).map(|ctx| ctx.status).unwrap_or(0)
+}
+
+/// Sets the gain applied to the mixed output of all voices.  Like the voice gains it's ramped
+/// linearly across the next frame.
+pub fn set_master_gain(ctx: *mut GranularCtx, gain: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !gain.is_finite() {
+        return;
+    }
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::MasterGain), gain);
+}
+
+/// Sets the spread in cents by which the voices are detuned around their sample speed ratios, from
+/// the first voice below its ratio to the last one above it.  Clamped to 0..1200.
+pub fn set_detune_spread(ctx: *mut GranularCtx, cents: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !cents.is_finite() {
+        return;
+    }
+    ctx.params.set_target(
+        ParamId::Global(GlobalParam::DetuneSpread),
+        clamp(0., 1200., cents),
+    );
+}
+
+/// Returns the targets of every parameter in flat index order, for saving a configuration to
+/// `morph` between later
+pub fn get_param_values(ctx: *mut GranularCtx) -> Vec<f32> {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.params.target().0.to_vec(),
+        None => Vec::new(),
+    }
+}
+
+/// Crossfades between two configurations saved with `get_param_values`, from `config_a` at a `t`
+/// of 0 to `config_b` at 1.  Until `clear_morph` is called the morphed values replace the ones
+/// passed to `render_granular`, and they're smoothed like any other change, so hosts can sweep
+/// `t` from a single control.  Returns `false` without changing anything if either
+/// configuration has the wrong length or `t` isn't finite.
+pub fn morph(ctx: *mut GranularCtx, config_a: &[f32], config_b: &[f32], t: f32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let (Ok(a), Ok(b)) = (config_a.try_into(), config_b.try_into()) else {
+        return false;
+    };
+    if !t.is_finite() {
+        return false;
+    }
+    ctx.config_morph = Some(ConfigMorph {
+        a: ParamValues(a),
+        b: ParamValues(b),
+        t: clamp(0., 1., t),
+    });
+    true
+}
+
+/// Stops morphing, handing the parameters back to `render_granular` and the setters
+pub fn clear_morph(ctx: *mut GranularCtx) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.config_morph = None;
+}
+
+/// Stores the current parameter targets as scene A (0) or B (1).  Once both scenes are stored
+/// they replace the targets passed to each render, crossfaded by the `SceneMorph` parameter, until
+/// `clear_scenes`.  A morph with `morph` takes precedence.  Returns false for any other scene.
+pub fn store_scene(ctx: *mut GranularCtx, scene: usize) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let target = *ctx.params.target();
+    match ctx.scenes.get_mut(scene) {
+        Some(stored) => {
+            *stored = Some(target);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Stores a configuration saved with `get_param_values` or `get_scene_values` as scene A (0) or
+/// B (1).  Returns false for any other scene or a configuration of the wrong length.
+pub fn set_scene_values(ctx: *mut GranularCtx, scene: usize, values: &[f32]) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Ok(values) = values.try_into() else {
+        return false;
+    };
+    match ctx.scenes.get_mut(scene) {
+        Some(stored) => {
+            *stored = Some(ParamValues(values));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns the parameter values of scene A (0) or B (1) in flat index order, or nothing if it
+/// isn't stored
+pub fn get_scene_values(ctx: *mut GranularCtx, scene: usize) -> Vec<f32> {
+    match ctx_mut(ctx).and_then(|ctx| ctx.scenes.get(scene).copied().flatten()) {
+        Some(values) => values.0.to_vec(),
+        None => Vec::new(),
+    }
+}
+
+/// Sets the position between the stored scenes, from A (0) to B (1).  Continuous parameters are
+/// crossfaded and flags switch halfway.
+pub fn set_scene_morph(ctx: *mut GranularCtx, t: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !t.is_finite() {
+        return;
+    }
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SceneMorph), clamp(0., 1., t));
+}
+
+/// Forgets both scenes, handing the parameters back to `render_granular` and the setters
+pub fn clear_scenes(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.scenes = [None; 2];
+    }
+}
+
+/// Returns the targets of every parameter as a `GranularParams` JSON document, for presets
+#[cfg(feature = "serde")]
+pub fn get_params_json(ctx: *mut GranularCtx) -> String {
+    match ctx_mut(ctx) {
+        Some(ctx) => GranularParams::from_values(ctx.params.target()).to_json(),
+        None => String::new(),
+    }
+}
+
+/// Sets the targets of every parameter from a `GranularParams` JSON document.  Parameters missing
+/// from it go back to their defaults, and `render_granular` keeps overwriting the ones it's
+/// passed.  Returns `false` without changing anything if the document is invalid or has values
+/// out of range.
+#[cfg(feature = "serde")]
+pub fn set_params_json(ctx: *mut GranularCtx, json: &str) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Ok(params) = GranularParams::from_json(json) else {
+        return false;
+    };
+    for (ix, value) in params.values().0.iter().enumerate() {
+        ctx.params
+            .set_target(ParamId::from_index(ix).unwrap(), *value);
+    }
+    true
+}
+
+pub fn get_param_count() -> usize {
+    PARAM_COUNT
+}
+
+pub(crate) fn is_supported_sample_rate(sample_rate: f32) -> bool {
+    SAMPLE_RATES.contains(&sample_rate)
+}
+
+pub fn get_param_metadata() -> String {
+    params::metadata_json()
+}
+
+/// Sets the sample rate used to convert smoothing times into per-sample coefficients.  Rates
+/// outside of `SAMPLE_RATES` are ignored.
+pub fn set_sample_rate(ctx: *mut GranularCtx, sample_rate: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !is_supported_sample_rate(sample_rate) {
+        return;
+    }
+    ctx.sample_rate = sample_rate;
+    ctx.params.set_sample_rate(sample_rate);
+    ctx.sends.set_sample_rate(sample_rate);
+    for voice in &mut ctx.voices {
+        voice.filter.set_sample_rate(sample_rate);
+        voice.filter_right.set_sample_rate(sample_rate);
+        // The delay lines are sized for the sample rate
+        if let Some(resonator) = &mut voice.resonator {
+            *resonator = VoiceResonator::new(resonator.settings, sample_rate);
+        }
+        if let Some(comb) = &mut voice.comb {
+            *comb = VoiceComb::new(comb.settings, sample_rate);
+        }
+        if let Some(harmonizer) = &mut voice.harmonizer {
+            harmonizer.set_sample_rate(sample_rate);
+        }
+        if let Some(grain_glide) = &mut voice.grain_glide {
+            grain_glide.set_sample_rate(sample_rate);
+        }
+        if let Some(grain_tilt) = &mut voice.grain_tilt {
+            grain_tilt.set_sample_rate(sample_rate);
+        }
+    }
+    if let Some(tilt) = &mut ctx.master_tilt {
+        tilt.set(tilt.tilt, tilt.pivot_hz, sample_rate);
+    }
+    if let Some(ducking) = &mut ctx.voice_ducking {
+        ducking.set_sample_rate(sample_rate);
+    }
+    if let Some(eq) = &mut ctx.master_eq {
+        eq.set(eq.settings, sample_rate);
+    }
+    if let Some(shaper) = &mut ctx.master_transients {
+        shaper.set_sample_rate(sample_rate);
+    }
+    if let Some(master) = &mut ctx.master_compressor {
+        master.compressor.set_sample_rate(sample_rate);
+    }
+    ctx.quad_split.set_sample_rate(sample_rate);
+    if let Some(haas) = &mut ctx.master_haas {
+        *haas = HaasWidener::new(haas.settings, sample_rate);
+    }
+    if let Some(saturators) = &mut ctx.master_saturation {
+        let [saturator, ..] = *saturators;
+        let oversampling = saturator.oversampling();
+        let saturator = Saturator::new(saturator.model, saturator.drive, oversampling, sample_rate);
+        *saturators = [saturator; 3];
+    }
+    if let Some(vibrato) = &mut ctx.master_vibrato {
+        *vibrato = MasterVibrato::new(vibrato.rate_hz, vibrato.depth_cents, sample_rate);
+    }
+    if let Some(tape) = &mut ctx.master_tape {
+        *tape = MasterTape::new(tape.settings, sample_rate);
+    }
+    if let Some(stutter) = &mut ctx.stutter {
+        *stutter = Stutter::new(stutter.settings, sample_rate);
+    }
+    ctx.limiter.set_sample_rate(sample_rate);
+    ctx.sidechain_follower.set_sample_rate(sample_rate);
+    #[cfg(feature = "loudness")]
+    ctx.loudness.set_sample_rate(sample_rate);
+}
+
+/// Sets the smoothing time constant of one parameter, addressed by its flat index.  A time of 0
+/// disables smoothing for that parameter, apart from filter cutoffs, which still ramp across each
+/// block rather than stepping at its start.
+pub fn set_param_smoothing_time(ctx: *mut GranularCtx, param_ix: usize, time_ms: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if param_ix >= PARAM_COUNT {
+        return;
+    }
+    ctx.params
+        .set_time_ms(ParamId::from_index(param_ix).unwrap(), time_ms);
+}
+
+/// Sets the target of the parameter at flat index `param_ix`, clamped to its range.  This is how
+/// hosts change parameters that `render_granular` doesn't take, including the switches that turn
+/// modes on and off.  Returns false, changing nothing, for an index past the last parameter or a
+/// value that isn't finite.
+pub fn set_param(ctx: *mut GranularCtx, param_ix: usize, value: f32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(id) = ParamId::from_index(param_ix) else {
+        return false;
+    };
+    if !value.is_finite() {
+        return false;
+    }
+    let info = id.info();
+    ctx.params.set_target(id, clamp(info.min, info.max, value));
+    true
+}
+
+#[test]
+fn crossfade_correctness() {
+    // setting `linear_slope_length` to 0 should cause no crossfade to be applied
+    for pos in [0., 0.2, 0.4, 0.6, 0.8, 0.999] {
+        let volume = Grain::get_volume(pos, 0., 0.);
+        assert_eq!(volume, 1.);
+    }
+}
+
+#[test]
+fn overlap_add_level_is_independent_of_density() {
+    let level = |samples_between_grains: f32| {
+        let mut ctx = GranularCtx {
+            waveform: vec![1.; 44100],
+            ..Default::default()
+        };
+        set_overlap_add_mode(&mut ctx, true);
+        let mut targets = test_targets(44099.);
+        for voice_ix in 0..params::VOICE_COUNT {
+            targets.set(
+                ParamId::Voice(voice_ix, VoiceParam::SamplesBetweenGrains),
+                samples_between_grains,
+            );
+        }
+        for _ in 0..32 {
+            ctx.render(&targets);
+        }
+        let (min, max) = ctx
+            .rendered_output
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), sample| {
+                (min.min(*sample), max.max(*sample))
+            });
+        assert!(max - min < 0.01, "{} {}", min, max);
+        max
+    };
+    let dense = level(100.);
+    let sparse = level(200.);
+    assert!((dense - sparse).abs() < 0.01, "{} {}", dense, sparse);
+}
 
-    // Apply smoothing to the input of the start and end sample to try to avoid clicking
-    if ctx.last_start_sample_ix > 0. {
-        smooth(
-            &mut ctx.last_start_sample_ix,
-            selection_start_sample_ix,
-            0.995,
+#[test]
+fn overlap_compensation_follows_density_and_ramps_in() {
+    let render = |samples_between_grains: f32, compensated: bool| {
+        let mut ctx = GranularCtx {
+            waveform: vec![1.; 44100],
+            ..Default::default()
+        };
+        let mut targets = test_targets(44099.);
+        for voice_ix in 0..params::VOICE_COUNT {
+            targets.set(
+                ParamId::Voice(voice_ix, VoiceParam::SamplesBetweenGrains),
+                samples_between_grains,
+            );
+        }
+        for _ in 0..16 {
+            ctx.render(&targets);
+        }
+        for voice_ix in 0..params::VOICE_COUNT {
+            set_voice_overlap_compensation(&mut ctx, voice_ix, compensated);
+        }
+        targets = *ctx.params.target();
+        let mut output = Vec::new();
+        for _ in 0..64 {
+            ctx.render(&targets);
+            output.extend_from_slice(&ctx.rendered_output);
+        }
+        output
+    };
+    let mean = |output: &[f32]| output.iter().sum::<f32>() / output.len() as f32;
+
+    let dense = render(100., true);
+    let sparse = render(200., true);
+    let (dense_level, sparse_level) = (mean(&dense[4096..]), mean(&sparse[4096..]));
+    assert!(
+        (dense_level / sparse_level - 1.).abs() < 0.05,
+        "{} {}",
+        dense_level,
+        sparse_level
+    );
+    // Switching over from dividing by the sum of the gains, which holds a constant waveform at
+    // full level, doesn't jump
+    assert!(dense
+        .windows(2)
+        .all(|pair| (pair[1] - pair[0]).abs() < 0.05));
+    let uncompensated = render(100., false);
+    assert!((mean(&uncompensated[4096..]) - dense_level).abs() < 0.1);
+}
+
+#[test]
+fn voice_outputs_add_up_to_the_stereo_output() {
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| (i as f32 * 0.01).sin() * 0.5).collect(),
+        ..Default::default()
+    };
+    set_voice_pan(&mut ctx, 0, -0.5);
+    let targets = test_targets(44099.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    assert!(get_voice_output_ptr(&mut ctx, params::VOICE_COUNT).is_null());
+    let [voice_1, voice_2] = &ctx.rendered_voice_outputs;
+    assert!(voice_1.iter().any(|sample| *sample != 0.));
+    for (ix, sample) in ctx.rendered_output_stereo.iter().enumerate() {
+        assert!((voice_1[ix] + voice_2[ix] - sample).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn render_granular_reads_per_voice_params_in_place() {
+    let mut ctx = GranularCtx {
+        waveform: vec![0.; 44100],
+        ..Default::default()
+    };
+    let ptr = get_voice_render_params_ptr(&mut ctx);
+    let rows = unsafe { std::slice::from_raw_parts_mut(ptr, 6 * params::VOICE_COUNT) };
+    rows[2 * params::VOICE_COUNT..3 * params::VOICE_COUNT].copy_from_slice(&[1.5, 0.5]);
+    rows[4 * params::VOICE_COUNT] = 0.25;
+    render_granular(&mut ctx, 0., 44099., 800., 0.5, 0.5);
+    let target = ctx.params.target();
+    assert_eq!(target.voice(0, VoiceParam::SampleSpeedRatio), 1.5);
+    assert_eq!(target.voice(1, VoiceParam::SampleSpeedRatio), 0.5);
+    assert_eq!(target.voice(0, VoiceParam::Gain), 0.25);
+    assert_eq!(
+        target.voice(1, VoiceParam::Gain),
+        VoiceParam::Gain.info().default
+    );
+}
+
+#[test]
+fn render_granular_into_writes_consecutive_frames() {
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| (i as f32 * 0.01).sin()).collect(),
+        ..Default::default()
+    };
+    for param in [VoiceParam::SampleSpeedRatio, VoiceParam::Gain] {
+        ctx.params.set_target(ParamId::Voice(0, param), 1.);
+    }
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    let mut out = vec![f32::NAN; FRAME_SIZE * 3];
+    assert_eq!(
+        render_granular_into(&mut ctx, out.as_mut_ptr(), 3),
+        out.len()
+    );
+    assert!(out.iter().all(|sample| sample.is_finite()));
+    assert_eq!(out[FRAME_SIZE * 2..], ctx.rendered_output);
+    assert_eq!(render_granular_into(&mut ctx, std::ptr::null_mut(), 3), 0);
+    let misaligned = unsafe { out.as_mut_ptr().byte_add(1) };
+    assert_eq!(render_granular_into(&mut ctx, misaligned, 1), 0);
+    assert_eq!(
+        render_granular_into(&mut ctx, out.as_mut_ptr(), usize::MAX / 4),
+        0
+    );
+    let inside = ctx.rendered_output.as_mut_ptr();
+    assert_eq!(render_granular_into(&mut ctx, inside, 1), 0);
+}
+
+#[test]
+fn test_signals_replace_or_stand_in_for_the_waveform() {
+    let mut ctx = GranularCtx::default();
+    assert!(!load_test_signal(&mut ctx, 3, 1000.));
+    assert!(!load_test_signal(&mut ctx, 0, f32::NAN));
+    assert!(load_test_signal(&mut ctx, 1, 1000.));
+    assert_eq!(ctx.waveform.len(), 44100);
+    assert_eq!(ctx.waveform[0], 1.);
+    assert_eq!(ctx.waveform.iter().sum::<f32>(), 1.);
+
+    // A noise slot heard over a silent waveform
+    ctx.load_waveform(vec![0.; 44100], None);
+    let noise = add_test_signal_slot(&mut ctx, 2, 500.);
+    assert_eq!(add_test_signal_slot(&mut ctx, 2, -1.), u32::MAX);
+    assert_eq!(ctx.sample_slots.get(noise).map(<[f32]>::len), Some(22050));
+    assert!(slots::set_voice_slot_selection(
+        &mut ctx,
+        0,
+        0,
+        &[noise],
+        &[]
+    ));
+    let targets = test_targets(44099.);
+    for _ in 0..32 {
+        ctx.render(&targets);
+    }
+    assert!(ctx.rendered_output.iter().any(|sample| sample.abs() > 0.01));
+}
+
+#[test]
+fn morphing_replaces_render_targets() {
+    let mut ctx = GranularCtx {
+        waveform: vec![0.; 44100],
+        ..Default::default()
+    };
+    let mut config_a = get_param_values(&mut ctx);
+    let mut config_b = config_a.clone();
+    let grain_size_ix = ParamId::Global(GlobalParam::GrainSize).index();
+    config_a[grain_size_ix] = 400.;
+    config_b[grain_size_ix] = 800.;
+    assert!(!morph(&mut ctx, &config_a, &config_b[1..], 0.5));
+    assert!(morph(&mut ctx, &config_a, &config_b, 0.5));
+
+    ctx.render(&test_targets(44099.));
+    assert_eq!(ctx.params.target().global(GlobalParam::GrainSize), 600.);
+    clear_morph(&mut ctx);
+    ctx.render(&test_targets(44099.));
+    assert_eq!(ctx.params.target().global(GlobalParam::GrainSize), 800.);
+}
+
+#[test]
+fn the_scene_morph_crossfades_stored_scenes() {
+    let mut ctx = GranularCtx {
+        waveform: vec![0.; 44100],
+        ..Default::default()
+    };
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Global(GlobalParam::GrainSize), 400.);
+    ctx.render(&targets);
+    assert!(store_scene(&mut ctx, 0));
+    assert!(!store_scene(&mut ctx, 2));
+    targets.set(ParamId::Global(GlobalParam::GrainSize), 1600.);
+    targets.set(ParamId::Voice(1, VoiceParam::Mute), 1.);
+    ctx.render(&targets);
+    // Only scene A is stored, so the targets still come from the host
+    assert_eq!(ctx.params.target().global(GlobalParam::GrainSize), 1600.);
+    let scene_b = ctx.params.target().0.to_vec();
+    assert!(!set_scene_values(&mut ctx, 1, &scene_b[1..]));
+    assert!(set_scene_values(&mut ctx, 1, &scene_b));
+    assert_eq!(get_scene_values(&mut ctx, 1), scene_b);
+
+    let morphed = |ctx: &mut GranularCtx, t: f32| {
+        set_scene_morph(ctx, t);
+        let targets = *ctx.params.target();
+        ctx.render(&targets);
+        let target = ctx.params.target();
+        (
+            target.global(GlobalParam::GrainSize),
+            target.voice(1, VoiceParam::Mute),
+        )
+    };
+    assert_eq!(morphed(&mut ctx, 0.), (400., 0.));
+    assert_eq!(morphed(&mut ctx, 0.25), (700., 0.));
+    assert_eq!(morphed(&mut ctx, 0.75), (1300., 1.));
+    assert_eq!(ctx.params.target().global(GlobalParam::SceneMorph), 0.75);
+
+    clear_scenes(&mut ctx);
+    assert!(get_scene_values(&mut ctx, 0).is_empty());
+    targets.set(ParamId::Global(GlobalParam::GrainSize), 900.);
+    ctx.render(&targets);
+    assert_eq!(ctx.params.target().global(GlobalParam::GrainSize), 900.);
+}
+
+#[test]
+fn side_voice_is_silent_for_centered_sources() {
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| (i as f32 * 0.01).sin() * 0.5).collect(),
+        ..Default::default()
+    };
+    ctx.waveform_right = Some(ctx.waveform.clone());
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Global(GlobalParam::MidSide), 1.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    let [mid, side] = &ctx.rendered_voice_outputs;
+    assert!(mid.iter().any(|sample| *sample != 0.));
+    assert!(side.iter().all(|sample| *sample == 0.));
+}
+
+/// An instance playing a second of a sine wave
+#[cfg(test)]
+fn sine_ctx() -> GranularCtx {
+    GranularCtx {
+        waveform: (0..48000).map(|i| (i as f32 * 0.01).sin()).collect(),
+        ..Default::default()
+    }
+}
+
+/// Queues `command` on the command ring, as a host would
+#[cfg(test)]
+fn send_command(ctx: &mut GranularCtx, command: [u32; commands::COMMAND_WORDS]) {
+    let ring = commands::get_command_ring_ptr(ctx);
+    let words = unsafe {
+        std::slice::from_raw_parts_mut(
+            ring,
+            commands::COMMAND_RING_HEADER_LEN
+                + commands::COMMAND_RING_CAPACITY * commands::COMMAND_WORDS,
+        )
+    };
+    let written = words[0] as usize % commands::COMMAND_RING_CAPACITY;
+    let start = commands::COMMAND_RING_HEADER_LEN + written * commands::COMMAND_WORDS;
+    words[start..start + commands::COMMAND_WORDS].copy_from_slice(&command);
+    words[0] = words[0].wrapping_add(1);
+}
+
+/// Grains a voice has spawned since the grain trace was last drained, which drains it
+#[cfg(test)]
+fn spawn_events(ctx: &mut GranularCtx, voice_ix: usize) -> Vec<trace::GrainEvent> {
+    ctx.grain_trace
+        .drain()
+        .into_iter()
+        .filter(|event| event.kind == GrainEventKind::Spawn && event.voice_ix == voice_ix)
+        .collect()
+}
+
+#[cfg(test)]
+fn count_spawns(ctx: &mut GranularCtx, voice_ix: usize) -> usize {
+    spawn_events(ctx, voice_ix).len()
+}
+
+#[cfg(test)]
+fn test_targets(selection_end_sample_ix: f32) -> ParamValues {
+    let mut targets = *ParamSmoother::new(DEFAULT_SAMPLE_RATE).target();
+    targets.set(
+        ParamId::Global(GlobalParam::SelectionEndSampleIx),
+        selection_end_sample_ix,
+    );
+    targets.set(ParamId::Global(GlobalParam::GrainSize), 800.);
+    for voice_ix in 0..params::VOICE_COUNT {
+        targets.set(ParamId::Voice(voice_ix, VoiceParam::SampleSpeedRatio), 1.);
+        targets.set(
+            ParamId::Voice(voice_ix, VoiceParam::SamplesBetweenGrains),
+            100.,
         );
-    } else {
-        ctx.last_start_sample_ix = selection_start_sample_ix;
+        targets.set(ParamId::Voice(voice_ix, VoiceParam::Gain), 1.);
     }
-    if ctx.last_end_sample_ix > 0. {
-        smooth(&mut ctx.last_end_sample_ix, selection_end_sample_ix, 0.995);
-    } else {
-        ctx.last_end_sample_ix = selection_end_sample_ix;
+    targets
+}
+
+#[test]
+fn non_finite_params_produce_finite_output() {
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| (i as f32 * 0.05).sin()).collect(),
+        ..Default::default()
+    };
+
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Voice(0, VoiceParam::FilterCutoff), f32::NAN);
+    targets.set(ParamId::Voice(1, VoiceParam::FilterCutoff), f32::INFINITY);
+    targets.set(ParamId::Global(GlobalParam::GrainSize), f32::NEG_INFINITY);
+
+    for _ in 0..16 {
+        ctx.render(&targets);
+        assert!(ctx.rendered_output.iter().all(|sample| sample.is_finite()));
+    }
+}
+
+#[test]
+fn invalid_selections_are_repaired() {
+    let ctx = GranularCtx {
+        waveform: vec![0.; 1000],
+        ..Default::default()
+    };
+    let start_id = ParamId::Global(GlobalParam::SelectionStartSampleIx);
+    let end_id = ParamId::Global(GlobalParam::SelectionEndSampleIx);
+    let check = |start: f32, end: f32, expected: (f32, f32), expected_flags: u32| {
+        let mut values = ParamValues::default();
+        values.set(start_id, start);
+        values.set(end_id, end);
+        let flags = ctx.validate_selection(&mut values);
+        assert_eq!((values.get(start_id), values.get(end_id)), expected);
+        assert_eq!(flags, expected_flags);
+    };
+
+    check(100., 500., (100., 500.), 0);
+    check(500., 100., (100., 500.), status::SELECTION_SWAPPED);
+    check(-50., 5000., (0., 999.), status::SELECTION_CLAMPED);
+    check(2000., 3000., (0., 999.), status::SELECTION_FALLBACK);
+}
+
+#[test]
+fn empty_waveform_and_selection_render_silence() {
+    let mut ctx = GranularCtx::default();
+    ctx.render(&test_targets(44099.));
+    assert_eq!(ctx.status, status::WAVEFORM_EMPTY);
+    assert!(ctx.rendered_output.iter().all(|sample| *sample == 0.));
+
+    ctx.waveform = vec![1.; 1000];
+    let mut targets = test_targets(500.);
+    targets.set(ParamId::Global(GlobalParam::SelectionStartSampleIx), 500.);
+    for _ in 0..4 {
+        ctx.render(&targets);
+        assert_eq!(ctx.status, status::SELECTION_EMPTY);
+        assert!(ctx.rendered_output.iter().all(|sample| *sample == 0.));
+    }
+}
+
+#[test]
+fn selections_past_the_valid_length_are_rejected() {
+    let mut ctx = GranularCtx {
+        waveform: vec![1.; 1000],
+        ..Default::default()
+    };
+    assert_eq!(get_waveform_capacity(&mut ctx), 1000);
+    assert!(!set_waveform_valid_len(&mut ctx, 1001));
+    assert!(set_waveform_valid_len(&mut ctx, 600));
+    assert_eq!(ctx.samples().len(), 600);
+
+    let mut targets = test_targets(800.);
+    targets.set(ParamId::Global(GlobalParam::SelectionStartSampleIx), 0.);
+    ctx.render(&targets);
+    assert_eq!(ctx.status, status::SELECTION_OUT_OF_BOUNDS);
+    assert!(ctx.rendered_output.iter().all(|sample| *sample == 0.));
+
+    targets.set(ParamId::Global(GlobalParam::SelectionEndSampleIx), 599.);
+    ctx.render(&targets);
+    assert_eq!(ctx.status & status::SELECTION_OUT_OF_BOUNDS, 0);
+
+    ctx.load_waveform(vec![1.; 1000], None);
+    assert_eq!(ctx.samples().len(), 1000);
+}
+
+#[test]
+fn clipped_samples_are_counted() {
+    let mut ctx = GranularCtx {
+        waveform: vec![1.; 44100],
+        ..Default::default()
+    };
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Global(GlobalParam::MasterGain), 4.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    assert!(ctx.status & status::OUTPUT_CLIPPED != 0);
+    assert!(ctx.clip_count > 0);
+    assert!(ctx.rendered_output.iter().all(|sample| sample.abs() <= 1.));
+}
+
+#[test]
+fn limiter_gain_reduction_is_metered() {
+    let mut ctx = GranularCtx {
+        waveform: vec![1.; 44100],
+        limiter_enabled: true,
+        ..Default::default()
+    };
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Global(GlobalParam::MasterGain), 4.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    let gain_reduction_db = ctx.meters.values[meters::METER_VALUE_COUNT - 1];
+    assert!(gain_reduction_db > 1., "{}", gain_reduction_db);
+}
+
+#[test]
+fn output_is_clamped_at_the_ceiling() {
+    let mut ctx = GranularCtx::default();
+    let hot = OutputSample {
+        mono: 3.,
+        left: -2.,
+        right: 0.5,
+        ..Default::default()
+    };
+    let output = ctx.apply_output_safety(hot);
+    assert_eq!(
+        [output.mono, output.left, output.right],
+        [OUTPUT_CEILING, -OUTPUT_CEILING, 0.5]
+    );
+    assert_eq!(ctx.status, status::LIMITING_ACTIVE | status::OUTPUT_CLIPPED);
+    assert_eq!(ctx.clip_count, 1);
+
+    ctx.status = 0;
+    ctx.apply_output_safety(OutputSample {
+        mono: OUTPUT_CEILING,
+        ..Default::default()
+    });
+    assert_eq!(ctx.status, 0);
+}
+
+#[test]
+fn limiting_keeps_hot_output_under_the_threshold() {
+    let mut ctx = GranularCtx::default();
+    set_limiter(&mut ctx, true, 0.5, 100.);
+    for _ in 0..100 {
+        let output = ctx.apply_output_safety(OutputSample {
+            mono: 2.,
+            left: 1.5,
+            right: -2.,
+            ..Default::default()
+        });
+        for sample in [output.mono, output.left, output.right] {
+            assert!(sample.abs() <= 0.5 + 1e-6, "{}", sample);
+        }
+    }
+    // The limiter got there first, so nothing was clamped
+    assert_eq!(ctx.status, status::LIMITING_ACTIVE);
+    assert_eq!(ctx.clip_count, 0);
+
+    // The threshold is capped at the ceiling
+    set_limiter(&mut ctx, true, 4., 100.);
+    assert_eq!(ctx.limiter.threshold, OUTPUT_CEILING);
+}
+
+#[test]
+fn swapped_waveforms_crossfade_active_grains() {
+    let mut ctx = GranularCtx {
+        waveform: vec![1.; 44100],
+        ..Default::default()
+    };
+    let targets = test_targets(44099.);
+    for _ in 0..8 {
+        ctx.render(&targets);
     }
-    // Smoothing for grain size
-    smooth(&mut ctx.last_grain_size, grain_size, 0.9);
+    let staged = get_staging_waveform_ptr(&mut ctx, 44100);
+    unsafe { std::slice::from_raw_parts_mut(staged, 44100) }.fill(-1.);
+    ctx.render(&targets);
+    assert!(ctx.rendered_output.iter().all(|sample| *sample > 0.));
 
-    // Start and end samples must be within the waveform
-    if ctx.last_end_sample_ix >= ctx.waveform.len() as f32 {
-        ctx.last_end_sample_ix = ctx.waveform.len() as f32 - 1.;
+    assert!(swap_staging_waveform(&mut ctx, 10.));
+    ctx.render(&targets);
+    assert!(ctx.waveform_swap.retired.is_some());
+    assert!(ctx.voices[0].grains.iter().any(|grain| grain.retired));
+    for _ in 0..8 {
+        ctx.render(&targets);
     }
-    if ctx.last_start_sample_ix >= ctx.waveform.len() as f32 {
-        ctx.last_start_sample_ix = ctx.waveform.len() as f32 - 1.;
+    assert!(ctx.waveform_swap.retired.is_none());
+    assert!(ctx
+        .voices
+        .iter()
+        .all(|voice| voice.grains.iter().all(|grain| !grain.retired)));
+    assert!(ctx.rendered_output.iter().all(|sample| *sample < 0.));
+}
+
+#[test]
+fn gapless_swaps_let_old_grains_finish() {
+    let mut ctx = GranularCtx {
+        waveform: vec![1.; 44100],
+        ..Default::default()
+    };
+    let targets = test_targets(44099.);
+    for _ in 0..8 {
+        ctx.render(&targets);
     }
+    let staged = get_staging_waveform_ptr(&mut ctx, 44100);
+    unsafe { std::slice::from_raw_parts_mut(staged, 44100) }.fill(-1.);
+    assert!(swap_staging_waveform_gapless(&mut ctx));
+    ctx.render(&targets);
+    let retired = ctx.waveform_swap.retired.as_ref().unwrap();
+    assert!(retired.fade.is_none());
 
-    // End sample can't be less than start sample
-    if ctx.last_end_sample_ix < ctx.last_start_sample_ix {
-        ctx.last_end_sample_ix = ctx.last_start_sample_ix;
+    // Grains are 800 samples long so the old ones have all ended after a few frames
+    for _ in 0..8 {
+        ctx.render(&targets);
     }
+    assert!(ctx.waveform_swap.retired.is_none());
+    assert!(ctx.rendered_output.iter().all(|sample| *sample < 0.));
+}
 
-    let linear_slope_length = clamp(0., 1., linear_slope_length);
-    let slope_linearity = clamp(0., 1., slope_linearity);
+#[test]
+fn loads_are_resampled_from_supported_source_rates() {
+    let mut ctx = GranularCtx::default();
+    set_waveform_source_rate(&mut ctx, 22050., 0);
+    assert_eq!(
+        import_interleaved_waveform(&mut ctx, &[0.5; 100], 1, false),
+        200
+    );
+    // A rate this low would make the waveform grow 4410-fold
+    set_waveform_source_rate(&mut ctx, 10., 0);
+    set_waveform_source_rate(&mut ctx, f32::NAN, 0);
+    assert_eq!(ctx.load_options.source_sample_rate, Some(22050.));
+    set_waveform_source_rate(&mut ctx, 0., 0);
+    assert_eq!(
+        import_interleaved_waveform(&mut ctx, &[0.5; 100], 1, false),
+        100
+    );
+}
 
-    for i in 0..FRAME_SIZE {
-        let sample = ctx.get_sample(
-            ctx.last_start_sample_ix,
-            ctx.last_end_sample_ix,
-            ctx.last_grain_size,
-            linear_slope_length,
-            slope_linearity,
-            voice_1_filter_cutoff,
-            voice_2_filter_cutoff,
-            voice_1_samples_between_grains,
-            voice_2_samples_between_grains,
-            voice_1_gain,
-            voice_2_gain,
-            voice_1_movement_samples_per_sample,
-            voice_2_movement_samples_per_sample,
-            voice_1_sample_speed_ratio,
-            voice_2_sample_speed_ratio,
-            voice_1_grain_start_randomness_samples,
-            voice_2_grain_start_randomness_samples,
+#[test]
+fn staged_stereo_waveforms_swap_in_both_channels() {
+    let mut ctx = GranularCtx {
+        waveform: vec![1.; 44100],
+        ..Default::default()
+    };
+    let targets = test_targets(44099.);
+    ctx.render(&targets);
+    assert!(get_staging_waveform_right_ptr(&mut ctx, 44100).is_null());
+    let staged = get_staging_waveform_ptr(&mut ctx, 44100);
+    unsafe { std::slice::from_raw_parts_mut(staged, 44100) }.fill(0.5);
+    assert!(get_staging_waveform_right_ptr(&mut ctx, 100).is_null());
+    let staged_right = get_staging_waveform_right_ptr(&mut ctx, 44100);
+    unsafe { std::slice::from_raw_parts_mut(staged_right, 44100) }.fill(-0.5);
+    assert!(swap_staging_waveform(&mut ctx, 0.));
+    ctx.render(&targets);
+    assert_eq!(ctx.waveform[0], 0.5);
+    assert_eq!(
+        ctx.waveform_right.as_deref().map(|right| right[0]),
+        Some(-0.5)
+    );
+
+    // A mono waveform staged afterwards replaces both channels
+    get_staging_waveform_ptr(&mut ctx, 44100);
+    assert!(swap_staging_waveform_gapless(&mut ctx));
+    ctx.render(&targets);
+    assert!(ctx.waveform_right.is_none());
+}
+
+#[test]
+fn stereo_waveforms_are_granulated_in_stereo() {
+    let mut ctx = GranularCtx::default();
+    let interleaved: Vec<f32> = (0..44100).flat_map(|_| [1., 0., 0.5]).collect();
+    assert_eq!(
+        import_interleaved_waveform(&mut ctx, &interleaved, 3, true),
+        44100
+    );
+    let targets = test_targets(44099.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    let (left, right) = ctx.rendered_output_stereo.split_at(FRAME_SIZE);
+    assert!(left.iter().all(|sample| *sample > 0.));
+    assert!(right.iter().all(|sample| *sample == 0.));
+    assert!(ctx.rendered_output.iter().all(|sample| *sample > 0.));
+}
+
+#[test]
+fn reversed_sources_read_the_selection_backwards() {
+    let mut ctx = GranularCtx {
+        // A rising ramp, so reading backwards produces falling samples
+        waveform: (0..44100).map(|i| i as f32 / 44100.).collect(),
+        ..Default::default()
+    };
+    ctx.voices[1].reversed_source = true;
+    // The guard's fades shift the weighting between overlapping grains fast enough to make the
+    // mix wobble, which would hide the direction of the ramp
+    envelope::set_click_guard(&mut ctx, false);
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Voice(0, VoiceParam::Gain), 0.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    let grain = ctx.voices[1].grains.get(0).unwrap();
+    assert!(grain.reversed);
+    assert!(grain.start_sample_ix > 40000.);
+    assert!(ctx.rendered_output.windows(2).all(|pair| pair[1] < pair[0]));
+}
+
+#[test]
+fn cropping_keeps_grains_on_the_same_audio() {
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| i as f32).collect(),
+        ..Default::default()
+    };
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Global(GlobalParam::SelectionStartSampleIx), 20000.);
+    ctx.render(&targets);
+    let grain = ctx.voices[0].grains.get(0).unwrap();
+
+    assert_eq!(crop_waveform(&mut ctx, 10000, 30000), 20000);
+    assert_eq!(crop_waveform(&mut ctx, 500, 500), 0);
+    assert_eq!(ctx.waveform.len(), 20000);
+    let cropped_grain = ctx.voices[0].grains.get(0).unwrap();
+    assert_eq!(
+        ctx.waveform[cropped_grain.start_sample_ix as usize],
+        grain.start_sample_ix.floor()
+    );
+}
+
+#[test]
+fn external_waveforms_are_read_until_unbound() {
+    let external = vec![0.5; 44100];
+    let mut ctx = GranularCtx::default();
+    let generation = bind_external_waveform(&mut ctx, external.as_ptr(), external.len());
+    assert_ne!(generation, 0);
+    let targets = test_targets(44099.);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    assert!(ctx.rendered_output.iter().all(|sample| *sample > 0.));
+
+    assert!(!unbind_external_waveform(&mut ctx, generation + 1));
+    assert!(unbind_external_waveform(&mut ctx, generation));
+    assert_eq!(get_external_waveform_generation(&mut ctx), 0);
+    ctx.render(&targets);
+    assert_eq!(ctx.status, status::WAVEFORM_EMPTY);
+
+    // Pointers and lengths that can't form a slice are refused
+    let misaligned = unsafe { external.as_ptr().byte_add(1) };
+    assert_eq!(bind_external_waveform(&mut ctx, misaligned, 4), 0);
+    assert_eq!(bind_external_waveform(&mut ctx, std::ptr::null(), 4), 0);
+    assert_eq!(
+        bind_external_waveform(&mut ctx, external.as_ptr(), usize::MAX / 2),
+        0
+    );
+    assert_eq!(get_external_waveform_generation(&mut ctx), 0);
+}
+
+#[test]
+fn chunked_renders_match_whole_renders() {
+    let render = |chunked: bool| {
+        let mut ctx = GranularCtx::default();
+        ctx.seed(7);
+        ctx.load_waveform((0..44100).map(|i| (i as f32 * 0.01).sin()).collect(), None);
+        let targets = test_targets(44099.);
+        if !chunked {
+            return ctx.render_offline(&targets, 5, 0, |_, _| {});
+        }
+        assert!(ctx.render_chunk(&targets, 2).is_none());
+        ctx.automation.position_seconds = 1.;
+        ctx.start_chunked_render();
+        let mut output = ctx.render_chunk(&targets, 2).unwrap().to_vec();
+        output.extend_from_slice(ctx.render_chunk(&targets, 3).unwrap());
+        assert_eq!(ctx.automation.position_seconds, 1.);
+        assert_eq!(ctx.waveform.len(), 44100);
+        output
+    };
+    let whole = render(false);
+    assert_eq!(whole.len(), 5 * FRAME_SIZE * 2);
+    assert!(whole.iter().any(|sample| *sample != 0.));
+    assert_eq!(render(true), whole);
+}
+
+#[test]
+fn offline_renders_leave_live_playback_alone() {
+    let mut ctx = GranularCtx::default();
+    ctx.seed(7);
+    ctx.load_waveform((0..44100).map(|i| (i as f32 * 0.01).sin()).collect(), None);
+    let targets = test_targets(44099.);
+    for _ in 0..10 {
+        ctx.render(&targets);
+    }
+    ctx.automation.position_seconds = 1.;
+    let mut live = ctx.clone();
+
+    let output = ctx.render_offline(&targets, 20, 0, |_, _| {});
+    assert_eq!(output.len(), 20 * FRAME_SIZE * 2);
+    assert_eq!(ctx.waveform.len(), 44100);
+    assert_eq!(ctx.automation.position_seconds, 1.);
+    for _ in 0..5 {
+        ctx.render(&targets);
+        live.render(&targets);
+        assert_eq!(ctx.rendered_output_stereo, live.rendered_output_stereo);
+    }
+    assert!(ctx.rendered_output.iter().any(|sample| *sample != 0.));
+}
+
+#[test]
+fn uncommitted_waveforms_render_silence() {
+    let mut ctx = GranularCtx::default();
+    let ptr = get_granular_waveform_ptr(&mut ctx, 44100);
+    unsafe { std::slice::from_raw_parts_mut(ptr, 44100) }.fill(0.5);
+    let targets = test_targets(44099.);
+    ctx.render(&targets);
+    assert_eq!(ctx.status, status::WAVEFORM_UNCOMMITTED);
+    assert_eq!(process_loaded_waveform(&mut ctx), 0);
+
+    assert!(!commit_waveform(&mut ctx, 44000));
+    ctx.render(&targets);
+    assert_eq!(ctx.status, status::WAVEFORM_UNCOMMITTED);
+
+    assert!(commit_waveform(&mut ctx, 44100));
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    assert_eq!(ctx.status & status::WAVEFORM_UNCOMMITTED, 0);
+    assert!(ctx.rendered_output.iter().any(|sample| *sample != 0.));
+}
+
+#[test]
+fn wrapping_grains_loop_within_the_selection() {
+    let waveform: Vec<f32> = (0..2000).map(|ix| ix as f32).collect();
+    let mut grain = Grain {
+        len_samples: 200.,
+        start_sample_ix: 900.,
+        samples_read_so_far: 150.5,
+        sample_playback_ratio: 1.,
+        gain: 1.,
+        retired: false,
+        reversed: false,
+        wrap: None,
+        depth: 0.,
+        glide: None,
+        tilt: None,
+        slot: None,
+    };
+    assert_eq!(grain.read(waveform[..].into(), false), 1050.5);
+    grain.wrap = Some(0..1000);
+    assert_eq!(grain.read(waveform[..].into(), false), 50.5);
+    // Between the last sample of the selection and its first
+    grain.samples_read_so_far = 99.5;
+    assert_eq!(grain.read(waveform[..].into(), false), 999. * 0.5);
+}
+
+#[test]
+fn master_saturation_rounds_off_peaks() {
+    let mut ctx = GranularCtx {
+        waveform: (0..4410).map(|i| (i as f32 * 0.05).sin()).collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4409.);
+    let targets = *ctx.params.target();
+    let crest_factor = |ctx: &mut GranularCtx| {
+        let (mut peak, mut energy) = (0f32, 0.);
+        for frame_ix in 0..64 {
+            ctx.render(&targets);
+            if frame_ix >= 32 {
+                for sample in ctx.rendered_output {
+                    peak = peak.max(sample.abs());
+                    energy += sample * sample;
+                }
+            }
+        }
+        peak / (energy / (32 * FRAME_SIZE) as f32).sqrt()
+    };
+    let clean = crest_factor(&mut ctx);
+    assert!(!set_master_saturation(&mut ctx, 4, 12., 2));
+    assert!(!set_master_saturation(&mut ctx, 0, 12., 3));
+    assert!(set_master_saturation(&mut ctx, 1, 24., 2));
+    let saturated = crest_factor(&mut ctx);
+    assert!(saturated < clean * 0.9, "{} {}", saturated, clean);
+
+    // Changing the oversampling replaces the saturators
+    assert!(set_master_saturation(&mut ctx, 3, 6., 4));
+    assert_eq!(ctx.master_saturation.unwrap()[0].oversampling(), 4);
+    clear_master_saturation(&mut ctx);
+    assert!(ctx.master_saturation.is_none());
+}
+
+#[test]
+fn the_output_pointer_never_moves() {
+    let mut ctx = GranularCtx::default();
+    let load = |ctx: &mut GranularCtx, len: usize| {
+        let ptr = get_granular_waveform_ptr(ctx, len);
+        unsafe { std::slice::from_raw_parts_mut(ptr, len) }.fill(0.5);
+        assert!(commit_waveform(ctx, len));
+    };
+    load(&mut ctx, 44100);
+    let output_ptr = get_output_ptr(&mut ctx);
+    let render = |ctx: &mut GranularCtx, end: f32| render_granular(ctx, 0., end, 2000., 0.5, 0.5);
+
+    let mut heard = false;
+    for _ in 0..8 {
+        let rendered = render(&mut ctx, 44099.);
+        assert_eq!(rendered, output_ptr);
+        let output = unsafe { std::slice::from_raw_parts(output_ptr, FRAME_SIZE) };
+        assert_eq!(output, ctx.rendered_output);
+        heard |= output.iter().any(|sample| *sample != 0.);
+    }
+    assert!(heard);
+
+    // A longer waveform buffer
+    load(&mut ctx, 88200);
+    assert_eq!(render(&mut ctx, 88199.), output_ptr);
+    assert_eq!(get_output_ptr(&mut ctx), output_ptr);
+
+    // Renders return the stereo buffer with the stereo layout, but the mono one stays put
+    assert!(configure_output(&mut ctx, 2));
+    assert_ne!(render(&mut ctx, 88199.), output_ptr);
+    assert_eq!(get_output_ptr(&mut ctx), output_ptr);
+    assert!(configure_output(&mut ctx, 1));
+    assert_eq!(render(&mut ctx, 88199.), output_ptr);
+}
+
+#[test]
+fn latency_adds_up_over_the_master_effects() {
+    let mut ctx = GranularCtx::default();
+    assert_eq!(get_latency_frames(&mut ctx), 0);
+    assert!(set_master_saturation(&mut ctx, 0, 12., 1));
+    assert_eq!(get_latency_frames(&mut ctx), 0);
+    assert!(set_master_saturation(&mut ctx, 0, 12., 4));
+    let oversampled = get_latency_frames(&mut ctx);
+    assert!((1..10).contains(&oversampled), "{}", oversampled);
+
+    vibrato::set_master_vibrato(&mut ctx, 5., 50.);
+    let vibrato = vibrato::sweep_width(5., 50., ctx.sample_rate);
+    assert_eq!(
+        get_latency_frames(&mut ctx),
+        (vibrato + ctx.master_saturation.unwrap()[0].latency_samples()).round() as u32
+    );
+    clear_master_saturation(&mut ctx);
+    vibrato::set_master_vibrato(&mut ctx, 5., 0.);
+
+    let spectra = convolution::prepare_impulse_response(&[1.], ctx.sample_rate);
+    let ptr = convolution::get_impulse_response_ptr(&mut ctx, spectra.len());
+    unsafe { std::slice::from_raw_parts_mut(ptr, spectra.len()) }.copy_from_slice(&spectra);
+    assert!(convolution::load_impulse_response(&mut ctx, 0.5));
+    assert_eq!(get_latency_frames(&mut ctx), 0);
+    convolution::set_convolution_mix(&mut ctx, 1.);
+    assert_eq!(get_latency_frames(&mut ctx), convolution::BLOCK_LEN as u32);
+}
+
+#[test]
+fn tails_cover_grains_and_feedback() {
+    use crate::dsp::filters::comb::CombKind;
+    use comb::{CombDelay, CombSettings};
+
+    // 0.5 to the power of 10 is about -60 dB
+    assert!((decay_samples(100., 0.5) - 100. * (1. + 0.001f32.ln() / 0.5f32.ln())).abs() < 1e-3);
+    assert_eq!(decay_samples(100., 0.), 100.);
+    assert_eq!(decay_samples(100., -1.), f32::INFINITY);
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 44100],
+        ..Default::default()
+    };
+    ctx.seed(1);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    let targets = *ctx.params.target();
+    assert_eq!(get_tail_frames(&mut ctx), 0);
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    let grains = get_tail_frames(&mut ctx);
+    assert!(grains > 0);
+    playback::playback_stop(&mut ctx);
+    ctx.render(&targets);
+    assert!(get_tail_frames(&mut ctx) < grains);
+    let frames_left = get_tail_frames(&mut ctx) as usize;
+    for _ in 0..frames_left.div_ceil(FRAME_SIZE) {
+        ctx.render(&targets);
+    }
+    assert_eq!(get_tail_frames(&mut ctx), 0);
+    ctx.render(&targets);
+    assert!(ctx.rendered_output.iter().all(|&sample| sample == 0.));
+
+    ctx.voices[0].comb = Some(VoiceComb::new(
+        CombSettings {
+            kind: CombKind::Feedback,
+            delay: CombDelay::Samples(10.),
+            gain: 0.5,
+        },
+        ctx.sample_rate,
+    ));
+    assert_eq!(
+        get_tail_frames(&mut ctx),
+        decay_samples(10., 0.5).ceil() as u32
+    );
+    freeze::freeze_voice(&mut ctx, 1, 100.);
+    assert_eq!(get_tail_frames(&mut ctx), u32::MAX);
+}
+
+#[test]
+fn shutdowns_drain_once_the_tail_has_played_out() {
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 44100],
+        ..Default::default()
+    };
+    ctx.seed(1);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    sends::set_send_delay(&mut ctx, 50., 0.5);
+    ctx.params
+        .set_target(ParamId::Voice(0, VoiceParam::DelaySend), 1.);
+    let targets = *ctx.params.target();
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    assert!(!ctx.is_drained());
+
+    // Long enough that only the tail running out can drain the instance
+    ctx.begin_shutdown(2000.);
+    let mut echoing_without_grains = false;
+    while !ctx.is_drained() {
+        ctx.render(&targets);
+        let peak = ctx
+            .rendered_output
+            .iter()
+            .fold(0f32, |peak, s| peak.max(s.abs()));
+        if ctx.voices.iter().all(|voice| voice.grains.is_empty()) && peak > 0.01 {
+            echoing_without_grains = true;
+        }
+    }
+    assert!(echoing_without_grains);
+    assert!(!ctx.shutdown.unwrap().is_finished());
+    ctx.render(&targets);
+    assert!(ctx.rendered_output.iter().all(|sample| sample.abs() < 1e-3));
+}
+
+#[test]
+fn faded_resets_wait_for_the_fade() {
+    let mut ctx = sine_ctx();
+    ctx.seed(1);
+    let targets = test_targets(47999.);
+    for _ in 0..40 {
+        ctx.render(&targets);
+    }
+    let playhead = ctx.voices[0].cur_grain_start;
+    assert!(playhead > 0.);
+
+    ctx.reset(10.);
+    ctx.render(&targets);
+    assert!(ctx.pending_reset.is_some());
+    assert!(ctx.voices[0].cur_grain_start > playhead);
+    for _ in 0..3 {
+        ctx.render(&targets);
+    }
+    assert!(ctx.pending_reset.is_none());
+    assert!(ctx.voices[0].cur_grain_start < playhead);
+}
+
+#[test]
+fn skipped_grains_thin_out_the_cloud() {
+    let spawned = |probability: f32| {
+        let mut ctx = GranularCtx {
+            waveform: vec![0.5; 48000],
+            ..Default::default()
+        };
+        ctx.params
+            .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+        set_voice_grain_skip_probability(&mut ctx, 0, probability);
+        ctx.grain_trace.set_enabled(true);
+        let targets = *ctx.params.target();
+        for _ in 0..128 {
+            ctx.render(&targets);
+        }
+        count_spawns(&mut ctx, 0)
+    };
+    let all = spawned(0.);
+    let half = spawned(0.5);
+    assert!(all > 20);
+    assert!(half > all / 4 && half < all * 3 / 4, "{} {}", half, all);
+    assert_eq!(spawned(1.), 0);
+    assert_eq!(spawned(2.), 0);
+}
+
+#[test]
+fn note_values_follow_the_tempo() {
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    set_voice_grain_note_values(&mut ctx, 0, 1. / 16., 1. / 8.);
+    ctx.grain_trace.set_enabled(true);
+    let targets = *ctx.params.target();
+    let spawns = |ctx: &mut GranularCtx, bpm: f32| {
+        transport::set_transport(ctx, false, bpm, 0.);
+        ctx.grain_trace.drain();
+        for _ in 0..400 {
+            ctx.render(&targets);
+        }
+        spawn_events(ctx, 0)
+    };
+    // A sixteenth note is a quarter of a beat and an eighth half of one
+    for bpm in [120., 60.] {
+        let beat = 60. / bpm * ctx.sample_rate;
+        let spawns = spawns(&mut ctx, bpm);
+        assert!(spawns.len() >= 2);
+        assert!(spawns
+            .iter()
+            .all(|event| (event.len_samples - beat / 4.).abs() < 1.));
+        let spacing = (spawns[1].timestamp_samples - spawns[0].timestamp_samples) as f32;
+        assert!((spacing - beat / 2.).abs() <= 1., "{}", spacing);
+    }
+
+    set_voice_grain_note_values(&mut ctx, 0, 0., 0.);
+    assert_eq!(ctx.voices[0].size_sync_beats, None);
+    assert_eq!(ctx.voices[0].sync_beats, None);
+}
+
+#[test]
+fn oversized_waveforms_are_refused_before_allocating() {
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 100],
+        ..Default::default()
+    };
+    assert!(get_granular_waveform_ptr(&mut ctx, MAX_WAVEFORM_LEN + 1).is_null());
+    assert!(!begin_waveform_upload(&mut ctx, usize::MAX));
+    assert!(!append_waveform_chunk(&mut ctx, &[0.; 4]));
+    assert_eq!(ctx.waveform, vec![0.5; 100]);
+
+    assert!(begin_waveform_upload(&mut ctx, 4));
+    assert!(append_waveform_chunk(&mut ctx, &[0.; 4]));
+    assert!(finish_waveform_upload(&mut ctx));
+    assert_eq!(ctx.waveform.len(), 4);
+}
+
+#[test]
+fn relocking_wraps_seeks_laps_ahead_of_the_selection() {
+    let mut ctx = GranularCtx::default();
+    let mut params = *ctx.params.target();
+    params.set(ParamId::Global(GlobalParam::SelectionStartSampleIx), 1000.);
+    params.set(ParamId::Global(GlobalParam::SelectionEndSampleIx), 11000.);
+    params.set(ParamId::Global(GlobalParam::GrainSize), 2000.);
+    params.set(ParamId::Voice(0, VoiceParam::MovementSamplesPerSample), 1.);
+    // The read head has 8000 samples to move through before it wraps
+    let voice = &mut ctx.voices[0];
+    for laps in [0., 1., 3., 250.] {
+        voice.relock_read_head(&params, 0, laps * 8000. + 500.);
+        assert_eq!(voice.cur_grain_start, 1500., "{}", laps);
+    }
+
+    params.set(ParamId::Voice(0, VoiceParam::MovementSamplesPerSample), -1.);
+    voice.relock_read_head(&params, 0, 3. * 8000. + 500.);
+    assert_eq!(voice.cur_grain_start, 8500.);
+}
+
+#[test]
+fn waveforms_in_16_bit_storage_play_like_floats() {
+    let samples = sine_ctx().waveform;
+    let render = |storage| {
+        let mut ctx = GranularCtx::default();
+        set_waveform_storage(&mut ctx, storage);
+        assert_eq!(
+            import_interleaved_waveform(&mut ctx, &samples, 1, false),
+            48000
         );
-        ctx.rendered_output[i] = sample;
+        ctx.params
+            .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+        let targets = *ctx.params.target();
+        let mut output = Vec::new();
+        for _ in 0..20 {
+            ctx.render(&targets);
+            output.extend_from_slice(&ctx.rendered_output);
+        }
+        (ctx, output)
+    };
+    let (_, float_output) = render(0);
+    let (mut ctx, compact_output) = render(1);
+    assert!(ctx.waveform.is_empty());
+    assert_eq!(ctx.compact_waveform.as_ref().unwrap().left.len(), 48000);
+    assert!(float_output.iter().any(|sample| sample.abs() > 0.01));
+    for (float, compact) in float_output.iter().zip(&compact_output) {
+        assert!((float - compact).abs() < 1e-3, "{} {}", float, compact);
     }
 
-    ctx.rendered_output.as_ptr()
+    assert!(!analysis::compute_rms_envelope(&mut ctx, 10.).is_empty());
+    assert_eq!(crop_waveform(&mut ctx, 1000, 2000), 1000);
+    assert_eq!(
+        ctx.samples().at(0),
+        ctx.compact_waveform.as_ref().unwrap().left[0] as f32 / 32768.
+    );
+    // Loading through the host's buffer keeps floats until the load options are applied
+    get_granular_waveform_ptr(&mut ctx, 100);
+    assert!(ctx.compact_waveform.is_none());
+    assert!(commit_waveform(&mut ctx, 100));
+    assert_eq!(process_loaded_waveform(&mut ctx), 100);
+    assert!(ctx.compact_waveform.is_some());
+
+    // Staged swaps are encoded when they're requested, so the render only moves them in
+    get_staging_waveform_ptr(&mut ctx, 200);
+    assert!(swap_staging_waveform(&mut ctx, 0.));
+    assert!(ctx.waveform_swap.staged.is_none());
+    assert_eq!(
+        ctx.waveform_swap
+            .staged_compact
+            .as_ref()
+            .unwrap()
+            .left
+            .len(),
+        200
+    );
+    let targets = *ctx.params.target();
+    ctx.render(&targets);
+    assert!(ctx.waveform_swap.staged_compact.is_none());
+    assert_eq!(ctx.compact_waveform.as_ref().unwrap().left.len(), 200);
 }
 
 #[test]
-fn crossfade_correctness() {
-    // setting `linear_slope_length` to 0 should cause no crossfade to be applied
-    for pos in [0., 0.2, 0.4, 0.6, 0.8, 0.999] {
-        let volume = Grain::get_volume(pos, 0., 1.);
-        assert_eq!(volume, 1.);
+fn window_tables_are_rebuilt_only_when_the_envelope_changes() {
+    let mut ctx = sine_ctx();
+    let targets = test_targets(47999.);
+    let is_built = |ctx: &GranularCtx| {
+        let params =
+            ctx.voices[0].envelope_params(&ctx.params.current, &ctx.modulation.voices[0], 0);
+        ctx.voices[0].window.is_built_for(params)
+    };
+    ctx.render(&targets);
+    assert!(!is_built(&ctx));
+    ctx.render(&targets);
+    assert!(is_built(&ctx));
+
+    assert!(envelope::set_voice_envelope_ripple(&mut ctx, 0, 4, 0.5));
+    assert!(!is_built(&ctx));
+    ctx.render(&targets);
+    assert!(is_built(&ctx));
+    // Grains read the table as they'd work out the envelope themselves
+    let envelope_params =
+        ctx.voices[0].envelope_params(&ctx.params.current, &ctx.modulation.voices[0], 0);
+    for pos_in_grain in [0., 0.1, 0.33, 0.5, 0.9] {
+        let exact = Grain::envelope_gain(pos_in_grain, &ctx.voices[0].envelope, envelope_params);
+        let window = ctx.voices[0].window.built_for(envelope_params).unwrap();
+        let table = window.gain_at(pos_in_grain);
+        assert!((table - exact).abs() < 1e-3, "{} {}", table, exact);
     }
 }
+
+#[test]
+fn control_rate_work_runs_once_per_processing_chunk() {
+    let mut ctx = sine_ctx();
+    let mut targets = test_targets(47999.);
+    assert!(!set_processing_chunk_size(&mut ctx, 8));
+    assert!(!set_processing_chunk_size(&mut ctx, 4096));
+    let grain_size = |ctx: &GranularCtx| ctx.params.current.global(GlobalParam::GrainSize);
+
+    // Chunks of two frames step control-rate parameters on every other frame
+    assert!(set_processing_chunk_size(&mut ctx, FRAME_SIZE * 2));
+    ctx.params
+        .set_time_ms(ParamId::Global(GlobalParam::GrainSize), 50.);
+    ctx.render(&targets);
+    ctx.render(&targets);
+    targets.set(ParamId::Global(GlobalParam::GrainSize), 4000.);
+    ctx.render(&targets);
+    let first = grain_size(&ctx);
+    ctx.render(&targets);
+    assert_eq!(grain_size(&ctx), first);
+    ctx.render(&targets);
+    let third = grain_size(&ctx);
+    assert!(first < third && third < 4000., "{} {}", first, third);
+
+    // Chunks that don't divide a frame carry over into the next one
+    assert!(set_processing_chunk_size(&mut ctx, 48));
+    ctx.render(&targets);
+    assert_eq!(ctx.chunk_pos, FRAME_SIZE % 48);
+}
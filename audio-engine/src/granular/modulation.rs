@@ -0,0 +1,714 @@
+//! Modulation matrix.  A fixed number of source slots (LFOs, etc.) are routed to per-voice
+//! destinations through a list of connections, each with its own depth.  Sources and connections
+//! are configured over the WASM API and evaluated once per sample ahead of the voices.
+//!
+//! Depths are expressed in units that make sense for each destination rather than as a fraction
+//! of the parameter's range; see `ModDestination`.
+
+use rand::{rngs::StdRng, Rng};
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::common;
+use crate::dsp::{clamp, mix};
+
+pub const MOD_SOURCE_COUNT: usize = 8;
+pub const MOD_CONNECTION_COUNT: usize = 32;
+
+/// Things that modulation can be routed to.  Modulation is always applied on top of the smoothed
+/// parameter value and never changes the value the host set.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModDestination {
+    /// Octaves
+    GrainSize,
+    /// Fraction of the selection length that new grains are offset by
+    Position,
+    /// Semitones
+    Pitch,
+    /// Octaves; the filter type selected by the sign of the cutoff is preserved
+    FilterCutoff,
+    /// Added to a gain multiplier of 1
+    Gain,
+    /// Added to the voice's pan position
+    Pan,
+    /// Octaves; positive values spawn grains more often
+    Density,
+    /// Added to the voice's envelope skew
+    EnvelopeSkew,
+    /// Added to the voice's envelope morph
+    EnvelopeMorph,
+    /// Added to the voice's grain skip probability
+    GrainSkip,
+}
+
+impl ModDestination {
+    pub const ALL: [ModDestination; 10] = [
+        ModDestination::GrainSize,
+        ModDestination::Position,
+        ModDestination::Pitch,
+        ModDestination::FilterCutoff,
+        ModDestination::Gain,
+        ModDestination::Pan,
+        ModDestination::Density,
+        ModDestination::EnvelopeSkew,
+        ModDestination::EnvelopeMorph,
+        ModDestination::GrainSkip,
+    ];
+
+    pub fn from_u32(value: u32) -> Option<ModDestination> {
+        ModDestination::ALL.get(value as usize).copied()
+    }
+}
+
+pub const MOD_DESTINATION_COUNT: usize = ModDestination::ALL.len();
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// Holds a new random value for every cycle
+    Random,
+}
+
+impl LfoShape {
+    pub fn from_u32(value: u32) -> Option<LfoShape> {
+        match value {
+            0 => Some(LfoShape::Sine),
+            1 => Some(LfoShape::Triangle),
+            2 => Some(LfoShape::Saw),
+            3 => Some(LfoShape::Square),
+            4 => Some(LfoShape::Random),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ModSourceKind {
+    Off,
+    /// Bipolar LFO running freely at `rate_hz`
+    Lfo {
+        shape: LfoShape,
+        rate_hz: f32,
+    },
+    /// Bounded random walk taking `rate_hz` steps per second of up to `step_size` in either
+    /// direction, reflecting off of `±range`.  The output glides linearly between steps.
+    RandomWalk {
+        step_size: f32,
+        rate_hz: f32,
+        range: f32,
+    },
+    /// Attack/decay envelope that's retriggered whenever the voice it modulates spawns a grain.
+    /// Unipolar, going from 0 up to 1 over `attack_ms` and back down over `decay_ms`.
+    GrainEnvelope {
+        attack_ms: f32,
+        decay_ms: f32,
+    },
+    /// Bipolar random value held per voice and redrawn whenever that voice spawns a grain
+    GrainSampleHold,
+    /// Envelope of the sidechain input supplied by the host.  Unipolar, following the peak level.
+    Sidechain,
+}
+
+impl ModSourceKind {
+    /// Whether the source reacts to events of individual voices and so needs state per voice
+    fn is_per_voice(&self) -> bool {
+        matches!(
+            self,
+            ModSourceKind::GrainEnvelope { .. } | ModSourceKind::GrainSampleHold
+        )
+    }
+}
+
+/// Running state of a source.  Sources that react to a single voice keep one of these per voice;
+/// global sources only use the first one.
+#[derive(Clone, Copy, Default)]
+struct SourceState {
+    phase: f32,
+    held_value: f32,
+    previous_value: f32,
+    /// Samples since the last grain trigger, or `None` if the source hasn't been triggered yet
+    samples_since_trigger: Option<f32>,
+}
+
+#[derive(Clone, Copy)]
+pub struct ModSource {
+    pub kind: ModSourceKind,
+    states: [SourceState; VOICE_COUNT],
+    /// Bipolar output in the range [-1, 1] for each voice
+    pub output: [f32; VOICE_COUNT],
+}
+
+impl Default for ModSource {
+    fn default() -> Self {
+        ModSource {
+            kind: ModSourceKind::Off,
+            states: [SourceState::default(); VOICE_COUNT],
+            output: [0.; VOICE_COUNT],
+        }
+    }
+}
+
+impl ModSource {
+    pub fn new(kind: ModSourceKind) -> Self {
+        ModSource {
+            kind,
+            ..Default::default()
+        }
+    }
+
+    fn tick(&mut self, sample_rate: f32, sidechain_level: f32, rng: &mut StdRng) {
+        match self.kind {
+            ModSourceKind::Off => self.output = [0.; VOICE_COUNT],
+            ModSourceKind::Lfo { shape, rate_hz } => {
+                let state = &mut self.states[0];
+                state.phase += rate_hz / sample_rate;
+                if state.phase >= 1. {
+                    state.phase -= state.phase.floor();
+                    state.held_value = rng.gen_range(-1.0..=1.0);
+                }
+                let value = lfo_value(shape, state.phase, state.held_value);
+                self.output = [value; VOICE_COUNT];
+            }
+            ModSourceKind::RandomWalk {
+                step_size,
+                rate_hz,
+                range,
+            } => {
+                let state = &mut self.states[0];
+                state.phase += rate_hz / sample_rate;
+                if state.phase >= 1. {
+                    state.phase -= state.phase.floor();
+                    state.previous_value = state.held_value;
+                    let step = if step_size > 0. {
+                        rng.gen_range(-step_size..=step_size)
+                    } else {
+                        0.
+                    };
+                    state.held_value = reflect(state.held_value + step, range);
+                }
+                let value = mix(state.phase, state.previous_value, state.held_value);
+                self.output = [value; VOICE_COUNT];
+            }
+            ModSourceKind::GrainEnvelope {
+                attack_ms,
+                decay_ms,
+            } => {
+                let (attack_samples, decay_samples) =
+                    attack_decay_samples(attack_ms, decay_ms, sample_rate);
+                for (state, output) in self.states.iter_mut().zip(self.output.iter_mut()) {
+                    *output = match state.samples_since_trigger {
+                        Some(elapsed) => {
+                            state.samples_since_trigger = Some(elapsed + 1.);
+                            attack_decay_value(elapsed, attack_samples, decay_samples)
+                        }
+                        None => 0.,
+                    };
+                }
+            }
+            ModSourceKind::GrainSampleHold => {
+                for (state, output) in self.states.iter().zip(self.output.iter_mut()) {
+                    *output = state.held_value;
+                }
+            }
+            ModSourceKind::Sidechain => self.output = [sidechain_level; VOICE_COUNT],
+        }
+    }
+
+    /// Called when `voice_ix` is about to spawn a new grain.  Outputs are updated right away so
+    /// that the new grain sees them.
+    fn trigger_grain(&mut self, voice_ix: usize, sample_rate: f32, rng: &mut StdRng) {
+        let state = &mut self.states[voice_ix];
+        match self.kind {
+            ModSourceKind::GrainEnvelope {
+                attack_ms,
+                decay_ms,
+            } => {
+                // The new grain sees the first sample of the envelope, and the next tick the second
+                let (attack_samples, decay_samples) =
+                    attack_decay_samples(attack_ms, decay_ms, sample_rate);
+                state.samples_since_trigger = Some(1.);
+                self.output[voice_ix] = attack_decay_value(0., attack_samples, decay_samples);
+            }
+            ModSourceKind::GrainSampleHold => {
+                state.held_value = rng.gen_range(-1.0..=1.0);
+                self.output[voice_ix] = state.held_value;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn attack_decay_samples(attack_ms: f32, decay_ms: f32, sample_rate: f32) -> (f32, f32) {
+    (
+        attack_ms * sample_rate / 1000.,
+        decay_ms * sample_rate / 1000.,
+    )
+}
+
+fn attack_decay_value(elapsed: f32, attack_samples: f32, decay_samples: f32) -> f32 {
+    if elapsed < attack_samples {
+        elapsed / attack_samples
+    } else if elapsed < attack_samples + decay_samples {
+        1. - (elapsed - attack_samples) / decay_samples
+    } else {
+        0.
+    }
+}
+
+/// Folds `value` back into `[-range, range]` as if it bounced off of the bounds
+fn reflect(value: f32, range: f32) -> f32 {
+    if range <= 0. {
+        return 0.;
+    }
+    let period = range * 4.;
+    let wrapped = (value + range).rem_euclid(period);
+    if wrapped <= range * 2. {
+        wrapped - range
+    } else {
+        range * 3. - wrapped
+    }
+}
+
+fn lfo_value(shape: LfoShape, phase: f32, held_value: f32) -> f32 {
+    match shape {
+        LfoShape::Sine => (phase * 2. * std::f32::consts::PI).sin(),
+        LfoShape::Triangle => 1. - 4. * (phase - 0.5).abs(),
+        LfoShape::Saw => phase * 2. - 1.,
+        LfoShape::Square => {
+            if phase < 0.5 {
+                1.
+            } else {
+                -1.
+            }
+        }
+        LfoShape::Random => held_value,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ModConnection {
+    pub source_ix: usize,
+    pub voice_ix: usize,
+    pub destination: ModDestination,
+    pub depth: f32,
+}
+
+/// Summed modulation of every destination for one voice
+#[derive(Clone, Copy, Default)]
+pub struct VoiceModulation(pub [f32; MOD_DESTINATION_COUNT]);
+
+impl VoiceModulation {
+    #[inline]
+    pub fn get(&self, destination: ModDestination) -> f32 {
+        self.0[destination as usize]
+    }
+
+    /// `value` scaled by the modulation of `destination` interpreted as octaves
+    #[inline]
+    pub fn apply_octaves(&self, destination: ModDestination, value: f32) -> f32 {
+        let amount = self.get(destination);
+        if amount == 0. {
+            value
+        } else {
+            value * amount.exp2()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ModMatrix {
+    pub sources: [ModSource; MOD_SOURCE_COUNT],
+    pub connections: [Option<ModConnection>; MOD_CONNECTION_COUNT],
+    pub voices: [VoiceModulation; VOICE_COUNT],
+    /// Output of the sidechain envelope follower for the current sample
+    pub sidechain_level: f32,
+    rng: StdRng,
+}
+
+impl Default for ModMatrix {
+    fn default() -> Self {
+        ModMatrix {
+            sources: [ModSource::default(); MOD_SOURCE_COUNT],
+            connections: [None; MOD_CONNECTION_COUNT],
+            voices: [VoiceModulation::default(); VOICE_COUNT],
+            sidechain_level: 0.,
+            rng: common::rng(),
+        }
+    }
+}
+
+impl ModMatrix {
+    /// Replaces the generator of the random sources, e.g. with a seeded one for reproducible
+    /// renders
+    pub fn reseed(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    fn has_connections(&self) -> bool {
+        self.connections
+            .iter()
+            .any(|connection| connection.is_some())
+    }
+
+    /// Advances all sources by one sample and recomputes the summed modulation of every voice
+    pub fn tick(&mut self, sample_rate: f32) {
+        if !self.has_connections() {
+            return;
+        }
+
+        for source in &mut self.sources {
+            source.tick(sample_rate, self.sidechain_level, &mut self.rng);
+        }
+
+        for voice_ix in 0..VOICE_COUNT {
+            self.sum_voice(voice_ix);
+        }
+    }
+
+    /// Recomputes the summed modulation of one voice from the current source outputs
+    fn sum_voice(&mut self, voice_ix: usize) {
+        let mut modulation = VoiceModulation::default();
+        for connection in self.connections.iter().flatten() {
+            if connection.voice_ix == voice_ix {
+                let source = &self.sources[connection.source_ix];
+                modulation.0[connection.destination as usize] +=
+                    source.output[voice_ix] * connection.depth;
+            }
+        }
+        self.voices[voice_ix] = modulation;
+    }
+
+    /// Retriggers every source that reacts to grain onsets of `voice_ix`, ahead of the new grain
+    /// being seeded
+    pub fn trigger_grain(&mut self, voice_ix: usize, sample_rate: f32) {
+        if !self.has_connections() {
+            return;
+        }
+        let mut triggered = false;
+        for source in &mut self.sources {
+            if source.kind.is_per_voice() {
+                source.trigger_grain(voice_ix, sample_rate, &mut self.rng);
+                triggered = true;
+            }
+        }
+        if triggered {
+            self.sum_voice(voice_ix);
+        }
+    }
+
+    pub fn set_source(&mut self, source_ix: usize, kind: ModSourceKind) {
+        if let Some(source) = self.sources.get_mut(source_ix) {
+            *source = ModSource::new(kind);
+        }
+    }
+
+    pub fn set_connection(&mut self, connection_ix: usize, connection: Option<ModConnection>) {
+        if let Some(slot) = self.connections.get_mut(connection_ix) {
+            *slot = connection;
+        }
+        if !self.has_connections() {
+            self.voices = [VoiceModulation::default(); VOICE_COUNT];
+        }
+    }
+}
+
+/// Configures a modulation source slot as an LFO.  `shape` is 0 = sine, 1 = triangle, 2 = saw,
+/// 3 = square and 4 = random.
+pub fn set_mod_lfo(ctx: *mut GranularCtx, source_ix: usize, shape: u32, rate_hz: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(shape) = LfoShape::from_u32(shape) else {
+        return;
+    };
+    if !rate_hz.is_finite() {
+        return;
+    }
+    ctx.modulation.set_source(
+        source_ix,
+        ModSourceKind::Lfo {
+            shape,
+            rate_hz: rate_hz.max(0.),
+        },
+    );
+}
+
+/// Configures a modulation source slot as a bounded random walk.  `range` bounds the output in
+/// both directions and is capped at 1.
+pub fn set_mod_random_walk(
+    ctx: *mut GranularCtx,
+    source_ix: usize,
+    step_size: f32,
+    rate_hz: f32,
+    range: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !step_size.is_finite() || !rate_hz.is_finite() || !range.is_finite() {
+        return;
+    }
+    ctx.modulation.set_source(
+        source_ix,
+        ModSourceKind::RandomWalk {
+            step_size: step_size.abs(),
+            rate_hz: rate_hz.max(0.),
+            range: clamp(0., 1., range),
+        },
+    );
+}
+
+/// Configures a modulation source slot as an attack/decay envelope retriggered by every grain
+/// that the modulated voice spawns
+pub fn set_mod_grain_envelope(
+    ctx: *mut GranularCtx,
+    source_ix: usize,
+    attack_ms: f32,
+    decay_ms: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !attack_ms.is_finite() || !decay_ms.is_finite() {
+        return;
+    }
+    ctx.modulation.set_source(
+        source_ix,
+        ModSourceKind::GrainEnvelope {
+            attack_ms: attack_ms.max(0.),
+            decay_ms: decay_ms.max(0.),
+        },
+    );
+}
+
+/// Configures a modulation source slot as a sample-and-hold that picks a new random value for a
+/// voice whenever that voice spawns a grain
+pub fn set_mod_grain_sample_hold(ctx: *mut GranularCtx, source_ix: usize) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.modulation
+            .set_source(source_ix, ModSourceKind::GrainSampleHold);
+    }
+}
+
+/// Configures a modulation source slot to follow the level of the sidechain input
+pub fn set_mod_sidechain(ctx: *mut GranularCtx, source_ix: usize) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.modulation
+            .set_source(source_ix, ModSourceKind::Sidechain);
+    }
+}
+
+/// Sets the attack and release times of the sidechain envelope follower
+pub fn set_sidechain_follower(ctx: *mut GranularCtx, attack_ms: f32, release_ms: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !attack_ms.is_finite() || !release_ms.is_finite() {
+        return;
+    }
+    let sample_rate = ctx.sample_rate;
+    ctx.sidechain_follower
+        .set_times(attack_ms.max(0.), release_ms.max(0.), sample_rate);
+}
+
+pub fn clear_mod_source(ctx: *mut GranularCtx, source_ix: usize) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.modulation.set_source(source_ix, ModSourceKind::Off);
+    }
+}
+
+/// Routes a source slot to a destination of one voice.  See `ModDestination` for the order of
+/// destinations and the units of `depth` for each.
+pub fn set_mod_connection(
+    ctx: *mut GranularCtx,
+    connection_ix: usize,
+    source_ix: usize,
+    voice_ix: usize,
+    destination: u32,
+    depth: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(destination) = ModDestination::from_u32(destination) else {
+        return;
+    };
+    if source_ix >= MOD_SOURCE_COUNT || voice_ix >= VOICE_COUNT || !depth.is_finite() {
+        return;
+    }
+    ctx.modulation.set_connection(
+        connection_ix,
+        Some(ModConnection {
+            source_ix,
+            voice_ix,
+            destination,
+            depth,
+        }),
+    );
+}
+
+pub fn clear_mod_connection(ctx: *mut GranularCtx, connection_ix: usize) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.modulation.set_connection(connection_ix, None);
+    }
+}
+
+#[test]
+fn random_walk_stays_in_range() {
+    let mut matrix = ModMatrix::default();
+    let mut source = ModSource::new(ModSourceKind::RandomWalk {
+        step_size: 0.5,
+        rate_hz: 4410.,
+        range: 0.3,
+    });
+    for _ in 0..44100 {
+        source.tick(44100., 0., &mut matrix.rng);
+        assert!(source.output[0].abs() <= 0.3 + f32::EPSILON);
+    }
+    assert!((reflect(0.4, 0.3) - 0.2).abs() < 1e-6);
+    assert!((reflect(-0.5, 0.3) + 0.1).abs() < 1e-6);
+}
+
+#[test]
+fn sample_hold_changes_only_on_grain_onsets() {
+    use rand::SeedableRng;
+
+    let mut matrix = ModMatrix::default();
+    matrix.reseed(StdRng::seed_from_u64(1));
+    matrix.set_source(3, ModSourceKind::GrainSampleHold);
+    matrix.set_connection(
+        0,
+        Some(ModConnection {
+            source_ix: 3,
+            voice_ix: 0,
+            destination: ModDestination::Pan,
+            depth: 1.,
+        }),
+    );
+
+    let mut previous = matrix.voices[0].get(ModDestination::Pan);
+    for onset in 0..20 {
+        matrix.trigger_grain(0, 44100.);
+        let held = matrix.voices[0].get(ModDestination::Pan);
+        assert_ne!(held, previous, "onset {}", onset);
+        for _ in 0..10 {
+            matrix.tick(44100.);
+            assert_eq!(matrix.voices[0].get(ModDestination::Pan), held);
+        }
+        previous = held;
+    }
+}
+
+#[test]
+fn grain_envelope_starts_on_the_onset() {
+    let mut matrix = ModMatrix::default();
+    matrix.set_source(
+        0,
+        ModSourceKind::GrainEnvelope {
+            attack_ms: 0.,
+            decay_ms: 1.,
+        },
+    );
+    matrix.set_connection(
+        0,
+        Some(ModConnection {
+            source_ix: 0,
+            voice_ix: 0,
+            destination: ModDestination::Pan,
+            depth: 1.,
+        }),
+    );
+    for _ in 0..100 {
+        matrix.tick(44100.);
+    }
+
+    // Without an attack the grain starts at the top of the envelope, then every tick decays
+    matrix.trigger_grain(0, 44100.);
+    let mut previous = matrix.voices[0].get(ModDestination::Pan);
+    assert_eq!(previous, 1.);
+    for _ in 0..44 {
+        matrix.tick(44100.);
+        let value = matrix.voices[0].get(ModDestination::Pan);
+        assert!(value < previous, "{} {}", value, previous);
+        previous = value;
+    }
+}
+
+#[test]
+fn connections_sum_into_destinations() {
+    let mut matrix = ModMatrix::default();
+    matrix.set_source(
+        0,
+        ModSourceKind::Lfo {
+            shape: LfoShape::Square,
+            rate_hz: 1.,
+        },
+    );
+    for connection_ix in 0..2 {
+        matrix.set_connection(
+            connection_ix,
+            Some(ModConnection {
+                source_ix: 0,
+                voice_ix: 1,
+                destination: ModDestination::Pitch,
+                depth: 12.,
+            }),
+        );
+    }
+
+    matrix.tick(44100.);
+    assert_eq!(matrix.voices[1].get(ModDestination::Pitch), 24.);
+    assert_eq!(matrix.voices[0].get(ModDestination::Pitch), 0.);
+}
+
+#[test]
+fn loud_sidechains_modulate_gain_and_density_until_they_release() {
+    use super::{test_targets, DEFAULT_SAMPLE_RATE, FRAME_SIZE};
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 44100],
+        ..Default::default()
+    };
+    let targets = test_targets(44099.);
+    set_mod_sidechain(&mut ctx, 0);
+    set_mod_connection(&mut ctx, 0, 0, 0, ModDestination::Gain as u32, 0.5);
+    set_mod_connection(&mut ctx, 1, 0, 0, ModDestination::Density as u32, 2.);
+    let release_ms = 50.;
+    set_sidechain_follower(&mut ctx, 1., release_ms);
+    let modulation = |ctx: &GranularCtx| {
+        let voice = ctx.modulation.voices[0];
+        (
+            voice.get(ModDestination::Gain),
+            voice.get(ModDestination::Density),
+        )
+    };
+
+    for _ in 0..16 {
+        ctx.sidechain_input = [1.; FRAME_SIZE];
+        ctx.render(&targets);
+    }
+    let (gain, density) = modulation(&ctx);
+    assert!((gain - 0.5).abs() < 1e-3, "{}", gain);
+    assert!((density - 2.).abs() < 1e-2, "{}", density);
+
+    // The sidechain falls silent, and one release time later the level is down by 1/e
+    let release_frames = (release_ms * 0.001 * DEFAULT_SAMPLE_RATE) as usize / FRAME_SIZE;
+    for _ in 0..release_frames {
+        ctx.render(&targets);
+    }
+    let expected = (-((release_frames * FRAME_SIZE) as f32)
+        / (release_ms * 0.001 * DEFAULT_SAMPLE_RATE))
+        .exp();
+    let (gain, density) = modulation(&ctx);
+    assert!((gain - 0.5 * expected).abs() < 1e-2, "{}", gain);
+    assert!((density - 2. * expected).abs() < 2e-2, "{}", density);
+
+    for _ in 0..release_frames * 10 {
+        ctx.render(&targets);
+    }
+    let (gain, density) = modulation(&ctx);
+    assert!(gain < 1e-3 && density < 1e-3);
+}
@@ -0,0 +1,530 @@
+//! Native Rust API.  Rust integrators configure an engine with `GranularConfig::builder()` and
+//! then drive it through `Granular`, setting parameters by `ParamId` instead of passing every
+//! render parameter to each call the way the WASM bindings do.
+//!
+//! Builds that know how many voices they play can fix the count at compile time with
+//! `GranularEngine`, whose per-voice values come and go as arrays of that length.  The WASM
+//! bindings always play the most voices the engine has room for, `VOICE_COUNT`.
+
+use std::fmt;
+
+use super::params::{GlobalParam, GranularParams, ParamId, ParamValues, VoiceParam, VOICE_COUNT};
+use super::{GranularCtx, FRAME_SIZE};
+use crate::dsp::processor::AudioProcessor;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigError {
+    /// The sample rate isn't between 1 kHz and 768 kHz
+    InvalidSampleRate(f32),
+    /// The engine plays between 1 and `VOICE_COUNT` voices
+    UnsupportedVoiceCount(usize),
+    /// Processing chunks are between 16 and 2048 samples
+    UnsupportedChunkSize(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidSampleRate(sample_rate) => {
+                write!(f, "invalid sample rate {}", sample_rate)
+            }
+            ConfigError::UnsupportedVoiceCount(voices) => write!(
+                f,
+                "{} voices requested but the engine plays 1 to {}",
+                voices, VOICE_COUNT
+            ),
+            ConfigError::UnsupportedChunkSize(chunk_size) => write!(
+                f,
+                "processing chunks of {} samples requested but they're 16 to 2048",
+                chunk_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GranularConfig {
+    pub sample_rate: f32,
+    /// Number of voices that play; the rest are muted
+    pub voices: usize,
+    pub master_gain: f32,
+    pub limiter: bool,
+    /// Seed for every random choice the engine makes, which makes its output reproducible
+    pub seed: Option<u64>,
+    /// Samples between runs of the control-rate work, as for `set_processing_chunk_size`
+    pub chunk_size: usize,
+}
+
+impl Default for GranularConfig {
+    fn default() -> Self {
+        GranularConfig {
+            sample_rate: super::DEFAULT_SAMPLE_RATE,
+            voices: VOICE_COUNT,
+            master_gain: 1.,
+            limiter: false,
+            seed: None,
+            chunk_size: FRAME_SIZE,
+        }
+    }
+}
+
+impl GranularConfig {
+    pub fn builder() -> GranularConfigBuilder {
+        GranularConfigBuilder::default()
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct GranularConfigBuilder {
+    config: GranularConfig,
+}
+
+impl GranularConfigBuilder {
+    pub fn sample_rate(mut self, sample_rate: f32) -> Self {
+        self.config.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn voices(mut self, voices: usize) -> Self {
+        self.config.voices = voices;
+        self
+    }
+
+    pub fn master_gain(mut self, master_gain: f32) -> Self {
+        self.config.master_gain = master_gain;
+        self
+    }
+
+    pub fn limiter(mut self, enabled: bool) -> Self {
+        self.config.limiter = enabled;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.config.chunk_size = chunk_size;
+        self
+    }
+
+    /// Checks the configuration and creates an engine from it
+    pub fn build(self) -> Result<Granular, ConfigError> {
+        Granular::new(self.config)
+    }
+
+    /// Like `build`, but for an engine that plays `VOICES` voices whatever the `voices` setting
+    pub fn build_engine<const VOICES: usize>(self) -> Result<GranularEngine<VOICES>, ConfigError> {
+        GranularEngine::new(self.config)
+    }
+}
+
+/// A granular engine driven from Rust.  It can be moved to another thread to render there.
+pub struct Granular {
+    ctx: Box<GranularCtx>,
+    /// Values passed to the engine on every render
+    targets: ParamValues,
+    /// How much of the last rendered frame `fill` and `samples` have handed out, where
+    /// `FRAME_SIZE` means all of it
+    read_pos: usize,
+}
+
+impl Granular {
+    pub fn new(config: GranularConfig) -> Result<Self, ConfigError> {
+        if !super::is_supported_sample_rate(config.sample_rate) {
+            return Err(ConfigError::InvalidSampleRate(config.sample_rate));
+        }
+        if !(1..=VOICE_COUNT).contains(&config.voices) {
+            return Err(ConfigError::UnsupportedVoiceCount(config.voices));
+        }
+
+        let mut ctx = Box::new(GranularCtx::default());
+        if !super::set_processing_chunk_size(&mut *ctx, config.chunk_size) {
+            return Err(ConfigError::UnsupportedChunkSize(config.chunk_size));
+        }
+        super::set_sample_rate(&mut *ctx, config.sample_rate);
+        super::set_limiter(&mut *ctx, config.limiter, f32::NAN, f32::NAN);
+        if let Some(seed) = config.seed {
+            ctx.seed(seed);
+        }
+        let mut targets = *ctx.params.target();
+        targets.set(ParamId::Global(GlobalParam::MasterGain), config.master_gain);
+        for voice_ix in config.voices..VOICE_COUNT {
+            targets.set(ParamId::Voice(voice_ix, VoiceParam::Mute), 1.);
+        }
+        Ok(Granular {
+            ctx,
+            targets,
+            read_pos: FRAME_SIZE,
+        })
+    }
+
+    /// Replaces the waveform with a mono one
+    pub fn load_waveform(&mut self, samples: Vec<f32>) {
+        self.ctx.load_waveform(samples, None);
+    }
+
+    pub fn param(&self, id: ParamId) -> f32 {
+        self.targets.get(id)
+    }
+
+    /// Sets the target of a parameter, which the engine smooths towards over the next renders
+    pub fn set_param(&mut self, id: ParamId, value: f32) {
+        self.targets.set(id, value);
+    }
+
+    pub fn params(&self) -> GranularParams {
+        GranularParams::from_values(&self.targets)
+    }
+
+    /// Sets the targets of every parameter at once, e.g. from a preset
+    pub fn set_params(&mut self, params: &GranularParams) {
+        self.targets = *params.values();
+    }
+
+    /// Renders the next frame and returns its mono output.  Samples of the previous frame that
+    /// `fill` or `samples` haven't handed out yet are dropped.
+    pub fn render(&mut self) -> &[f32; FRAME_SIZE] {
+        self.ctx.render(&self.targets);
+        self.read_pos = FRAME_SIZE;
+        &self.ctx.rendered_output
+    }
+
+    /// Fills `out` with the next mono samples, rendering as many frames as it takes.  Any length
+    /// works: samples left over from the last frame are handed out by the next call.
+    pub fn fill(&mut self, out: &mut [f32]) {
+        let mut written = 0;
+        while written < out.len() {
+            if self.read_pos == FRAME_SIZE {
+                self.ctx.render(&self.targets);
+                self.read_pos = 0;
+            }
+            let len = (out.len() - written).min(FRAME_SIZE - self.read_pos);
+            out[written..written + len]
+                .copy_from_slice(&self.ctx.rendered_output[self.read_pos..self.read_pos + len]);
+            written += len;
+            self.read_pos += len;
+        }
+    }
+
+    /// Renders `frames` whole frames into a new buffer, e.g. for comparing against a golden render
+    pub fn render_frames(&mut self, frames: usize) -> Vec<f32> {
+        let mut output = vec![0.; frames * FRAME_SIZE];
+        self.fill(&mut output);
+        output
+    }
+
+    /// Renders `duration_seconds` of output as interleaved stereo in whole frames, calling
+    /// `progress(rendered_frames, total_frames)` every `progress_interval` frames and at the end.
+    /// Automation lanes start from the beginning.  Renders are capped at 2^17 frames and run on a
+    /// copy of the engine, so its own output carries on from where it was.
+    pub fn render_offline(
+        &mut self,
+        duration_seconds: f32,
+        progress_interval: usize,
+        progress: impl FnMut(usize, usize),
+    ) -> Vec<f32> {
+        let frames =
+            (duration_seconds.max(0.) * self.ctx.sample_rate / FRAME_SIZE as f32).ceil() as usize;
+        self.ctx
+            .render_offline(&self.targets, frames, progress_interval, progress)
+    }
+
+    /// Sets every parameter from `params` and returns an endless iterator over the following mono
+    /// samples, for use with `take` and other iterator adaptors
+    pub fn samples(&mut self, params: &GranularParams) -> Samples<'_> {
+        self.set_params(params);
+        Samples { granular: self }
+    }
+
+    /// Planar stereo output of the last rendered frame
+    pub fn stereo_output(&self) -> &[f32; FRAME_SIZE * 2] {
+        &self.ctx.rendered_output_stereo
+    }
+}
+
+/// A `Granular` playing `VOICES` voices, from 1 to `VOICE_COUNT`, with the count checked at
+/// compile time.  It forwards what `Granular` does, except that the parameters of voices past
+/// `VOICES` can't be set, so those voices stay muted.
+pub struct GranularEngine<const VOICES: usize> {
+    granular: Granular,
+}
+
+impl<const VOICES: usize> GranularEngine<VOICES> {
+    const SUPPORTED: () = assert!(
+        VOICES >= 1 && VOICES <= VOICE_COUNT,
+        "the engine plays 1 to VOICE_COUNT voices"
+    );
+
+    /// Creates an engine from `config`, with its `voices` replaced by `VOICES`
+    pub fn new(config: GranularConfig) -> Result<Self, ConfigError> {
+        let () = Self::SUPPORTED;
+        let granular = Granular::new(GranularConfig {
+            voices: VOICES,
+            ..config
+        })?;
+        Ok(GranularEngine { granular })
+    }
+
+    /// Replaces the waveform with a mono one
+    pub fn load_waveform(&mut self, samples: Vec<f32>) {
+        self.granular.load_waveform(samples);
+    }
+
+    pub fn param(&self, id: ParamId) -> f32 {
+        self.granular.param(id)
+    }
+
+    /// Sets the target of a parameter, which the engine smooths towards over the next renders.
+    /// Returns false, leaving it alone, for a voice past `VOICES`.
+    pub fn set_param(&mut self, id: ParamId, value: f32) -> bool {
+        if matches!(id, ParamId::Voice(voice_ix, _) if voice_ix >= VOICES) {
+            return false;
+        }
+        self.granular.set_param(id, value);
+        true
+    }
+
+    pub fn params(&self) -> GranularParams {
+        self.granular.params()
+    }
+
+    /// Sets the targets of every parameter at once, e.g. from a preset.  Voices past `VOICES`
+    /// stay muted.
+    pub fn set_params(&mut self, params: &GranularParams) {
+        self.granular.set_params(params);
+        for voice_ix in VOICES..VOICE_COUNT {
+            self.granular
+                .targets
+                .set(ParamId::Voice(voice_ix, VoiceParam::Mute), 1.);
+        }
+    }
+
+    /// As for `Granular::render`
+    pub fn render(&mut self) -> &[f32; FRAME_SIZE] {
+        self.granular.render()
+    }
+
+    /// As for `Granular::fill`
+    pub fn fill(&mut self, out: &mut [f32]) {
+        self.granular.fill(out);
+    }
+
+    /// As for `Granular::render_frames`
+    pub fn render_frames(&mut self, frames: usize) -> Vec<f32> {
+        self.granular.render_frames(frames)
+    }
+
+    /// As for `Granular::render_offline`
+    pub fn render_offline(
+        &mut self,
+        duration_seconds: f32,
+        progress_interval: usize,
+        progress: impl FnMut(usize, usize),
+    ) -> Vec<f32> {
+        self.granular
+            .render_offline(duration_seconds, progress_interval, progress)
+    }
+
+    /// Sets every parameter from `params` as `set_params` does and returns an endless iterator
+    /// over the following mono samples
+    pub fn samples(&mut self, params: &GranularParams) -> Samples<'_> {
+        self.set_params(params);
+        Samples {
+            granular: &mut self.granular,
+        }
+    }
+
+    /// Planar stereo output of the last rendered frame
+    pub fn stereo_output(&self) -> &[f32; FRAME_SIZE * 2] {
+        self.granular.stereo_output()
+    }
+
+    /// Targets of a parameter for each voice
+    pub fn voice_param(&self, param: VoiceParam) -> [f32; VOICES] {
+        std::array::from_fn(|voice_ix| self.granular.param(ParamId::Voice(voice_ix, param)))
+    }
+
+    /// Sets the target of a parameter for each voice
+    pub fn set_voice_param(&mut self, param: VoiceParam, values: [f32; VOICES]) {
+        for (voice_ix, value) in values.into_iter().enumerate() {
+            self.granular
+                .set_param(ParamId::Voice(voice_ix, param), value);
+        }
+    }
+
+    /// Planar stereo output of each voice in the last rendered frame, as described for
+    /// `GranularCtx::rendered_voice_outputs`
+    pub fn voice_outputs(&self) -> [&[f32; FRAME_SIZE * 2]; VOICES] {
+        std::array::from_fn(|voice_ix| &self.granular.ctx.rendered_voice_outputs[voice_ix])
+    }
+}
+
+/// The engine is a source: it replaces the block with its next mono samples
+impl AudioProcessor for Granular {
+    fn process_block(&mut self, io: &mut [f32]) {
+        self.fill(io);
+    }
+
+    fn reset(&mut self) {
+        self.ctx.reset(0.);
+        self.read_pos = FRAME_SIZE;
+    }
+}
+
+/// Endless iterator over the mono output of a `Granular`, see `Granular::samples`
+pub struct Samples<'a> {
+    granular: &'a mut Granular,
+}
+
+impl Iterator for Samples<'_> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut sample = [0.];
+        self.granular.fill(&mut sample);
+        Some(sample[0])
+    }
+}
+
+#[test]
+fn builder_validates_the_config() {
+    assert_eq!(
+        GranularConfig::builder().voices(4).build().err(),
+        Some(ConfigError::UnsupportedVoiceCount(4))
+    );
+    assert_eq!(
+        GranularConfig::builder().sample_rate(0.).build().err(),
+        Some(ConfigError::InvalidSampleRate(0.))
+    );
+
+    let mut granular = GranularConfig::builder()
+        .sample_rate(48000.)
+        .voices(1)
+        .build()
+        .unwrap();
+    assert_eq!(granular.param(ParamId::Voice(1, VoiceParam::Mute)), 1.);
+    granular.load_waveform((0..48000).map(|i| (i as f32 * 0.01).sin()).collect());
+    granular.set_param(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    granular.set_param(ParamId::Voice(0, VoiceParam::SampleSpeedRatio), 1.);
+    granular.set_param(ParamId::Voice(0, VoiceParam::SamplesBetweenGrains), 100.);
+    granular.set_param(ParamId::Voice(0, VoiceParam::Gain), 1.);
+    let mut output = Vec::new();
+    for _ in 0..8 {
+        output.extend_from_slice(granular.render());
+    }
+    assert!(output.iter().any(|sample| *sample != 0.));
+}
+
+#[test]
+fn samples_stream_across_frames() {
+    let waveform: Vec<f32> = (0..48000).map(|i| (i as f32 * 0.01).sin()).collect();
+    let mut params = GranularParams::default();
+    params
+        .set_global(GlobalParam::SelectionEndSampleIx, 47999.)
+        .unwrap();
+    params
+        .set_voice(0, VoiceParam::SamplesBetweenGrains, 100.)
+        .unwrap();
+    let new_granular = || {
+        let mut granular = GranularConfig::builder().voices(1).build().unwrap();
+        granular.load_waveform(waveform.clone());
+        granular
+    };
+
+    let mut granular = new_granular();
+    let streamed: Vec<f32> = granular.samples(&params).take(FRAME_SIZE * 2 + 3).collect();
+    let mut filled = vec![0.; FRAME_SIZE * 2 + 3];
+    let mut granular = new_granular();
+    granular.set_params(&params);
+    let (head, tail) = filled.split_at_mut(FRAME_SIZE / 2);
+    granular.fill(head);
+    granular.fill(tail);
+    assert_eq!(streamed, filled);
+    assert!(streamed.iter().any(|sample| *sample != 0.));
+}
+
+#[test]
+fn seeded_renders_are_reproducible() {
+    let render = |seed| {
+        let mut granular = GranularConfig::builder()
+            .sample_rate(44100.)
+            .seed(seed)
+            .build()
+            .unwrap();
+        granular.load_waveform((0..44100).map(|i| (i as f32 * 0.01).sin()).collect());
+        granular.set_param(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+        for voice_ix in 0..VOICE_COUNT {
+            granular.set_param(
+                ParamId::Voice(voice_ix, VoiceParam::GrainStartRandomnessSamples),
+                20000.,
+            );
+        }
+        granular.render_frames(16)
+    };
+    assert_eq!(render(1), render(1));
+    assert_ne!(render(1), render(2));
+}
+
+#[test]
+fn offline_renders_report_progress() {
+    let mut granular = GranularConfig::builder()
+        .sample_rate(12800.)
+        .build()
+        .unwrap();
+    granular.load_waveform(vec![0.5; 12800]);
+    let mut reports = Vec::new();
+    let output = granular.render_offline(1., 40, |rendered, total| reports.push((rendered, total)));
+    assert_eq!(output.len(), 100 * FRAME_SIZE * 2);
+    assert_eq!(reports, vec![(40, 100), (80, 100), (100, 100)]);
+}
+
+#[test]
+fn seeded_engines_render_the_same_on_any_thread() {
+    let new_granular = || {
+        let mut granular = GranularConfig::builder().seed(3).build().unwrap();
+        granular.load_waveform((0..44100).map(|i| (i as f32 * 0.01).sin()).collect());
+        granular.set_param(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+        granular.set_param(
+            ParamId::Voice(0, VoiceParam::GrainStartRandomnessSamples),
+            20000.,
+        );
+        granular
+    };
+    let mut granular = new_granular();
+    let on_worker = std::thread::spawn(move || granular.render_frames(8))
+        .join()
+        .unwrap();
+    // Seeding another engine in between doesn't change what this one renders
+    let mut granular = new_granular();
+    let _ = new_granular().render_frames(1);
+    assert_eq!(granular.render_frames(8), on_worker);
+}
+
+#[test]
+fn engines_play_the_voice_count_they_are_built_with() {
+    let mut engine = GranularConfig::builder()
+        .voices(2)
+        .seed(4)
+        .build_engine::<1>()
+        .unwrap();
+    assert_eq!(engine.param(ParamId::Voice(1, VoiceParam::Mute)), 1.);
+    engine.load_waveform((0..48000).map(|i| (i as f32 * 0.01).sin()).collect());
+    assert!(engine.set_param(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.));
+    // The voice the engine doesn't play can't be unmuted, one by one or from a preset
+    let unmute = ParamId::Voice(1, VoiceParam::Mute);
+    assert!(!engine.set_param(unmute, 0.));
+    let mut params = engine.params();
+    params.set_voice(1, VoiceParam::Mute, 0.).unwrap();
+    engine.set_params(&params);
+    assert_eq!(engine.param(unmute), 1.);
+    engine.set_voice_param(VoiceParam::SamplesBetweenGrains, [100.]);
+    assert_eq!(engine.voice_param(VoiceParam::SamplesBetweenGrains), [100.]);
+    engine.render_frames(8);
+    let [voice_output] = engine.voice_outputs();
+    assert!(voice_output.iter().any(|sample| *sample != 0.));
+}
@@ -0,0 +1,355 @@
+//! Polyphonic note mode.  While it's enabled each voice plays one MIDI note at a time: the note
+//! transposes the voice's sample speed relative to `ROOT_NOTE` and gates its gain.  With MPE
+//! enabled, pitch bend, channel pressure and timbre (CC74) on a note's channel only affect the
+//! voice playing that note.
+//!
+//! Notes are applied to each frame's targets on top of the values the host set, so they only
+//! touch parameters that `render_granular` passes on every call.
+//!
+//! Each voice also has a filter envelope that's gated by the note it plays, sweeping its filter
+//! cutoff for plucked and swept patches.
+
+use super::params::{ParamId, ParamValues, VoiceParam, VOICE_COUNT};
+use super::tuning::Tuning;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::adsr::Adsr;
+use crate::dsp::clamp;
+
+/// Note that plays the sample back at its original speed
+pub const ROOT_NOTE: u8 = 60;
+/// Controller carrying timbre in MPE
+pub const TIMBRE_CC: u8 = 74;
+/// Octaves that full pressure shortens the time between grains by
+const PRESSURE_DENSITY_OCTAVES: f32 = 2.;
+/// Lowest and highest lowpass cutoff reachable with timbre
+const TIMBRE_MIN_CUTOFF: f32 = 20.;
+const TIMBRE_MAX_CUTOFF: f32 = 20000.;
+/// In MPE, messages on this channel apply to every note in the zone
+const MPE_MASTER_CHANNEL: u8 = 0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct ActiveNote {
+    channel: u8,
+    note: u8,
+    velocity: f32,
+    /// Order in which notes started, used to steal the oldest voice
+    age: u64,
+}
+
+/// Expression received for a voice, reset whenever it starts a new note
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+struct Expression {
+    /// -1 to 1, scaled by the pitch bend range
+    pitch_bend: f32,
+    /// 0 to 1
+    pressure: f32,
+    /// 0 to 1, or `None` until the first timbre message so the host's cutoff is kept
+    timbre: Option<f32>,
+}
+
+#[derive(Clone, Copy)]
+pub struct NoteMode {
+    pub enabled: bool,
+    pub mpe: bool,
+    /// Semitones for a full pitch bend in either direction
+    pub pitch_bend_range: f32,
+    notes: [Option<ActiveNote>; VOICE_COUNT],
+    expression: [Expression; VOICE_COUNT],
+    next_age: u64,
+}
+
+impl Default for NoteMode {
+    fn default() -> Self {
+        NoteMode {
+            enabled: false,
+            mpe: false,
+            pitch_bend_range: 2.,
+            notes: [None; VOICE_COUNT],
+            expression: [Expression::default(); VOICE_COUNT],
+            next_age: 0,
+        }
+    }
+}
+
+impl NoteMode {
+    pub fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        // Running status senders use a velocity of 0 for note offs
+        if velocity == 0 {
+            self.note_off(channel, note);
+            return;
+        }
+
+        let voice_ix = match self.notes.iter().position(|note| note.is_none()) {
+            Some(voice_ix) => voice_ix,
+            None => (0..VOICE_COUNT)
+                .min_by_key(|&voice_ix| self.notes[voice_ix].map_or(0, |note| note.age))
+                .unwrap(),
+        };
+        self.notes[voice_ix] = Some(ActiveNote {
+            channel,
+            note,
+            velocity: velocity as f32 / 127.,
+            age: self.next_age,
+        });
+        self.next_age += 1;
+        self.expression[voice_ix] = Expression::default();
+    }
+
+    pub fn note_off(&mut self, channel: u8, note: u8) {
+        for slot in &mut self.notes {
+            if slot.is_some_and(|active| active.channel == channel && active.note == note) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.notes = [None; VOICE_COUNT];
+    }
+
+    /// Velocity from 0 to 1 of the note a voice is playing, or `None` if it isn't playing one or
+    /// note mode is off
+    pub fn velocity(&self, voice_ix: usize) -> Option<f32> {
+        if !self.enabled {
+            return None;
+        }
+        self.notes[voice_ix].map(|note| note.velocity)
+    }
+
+    /// Calls `f` with the expression of every voice that a channel message on `channel` affects
+    fn for_each_expression(&mut self, channel: u8, mut f: impl FnMut(&mut Expression)) {
+        for (note, expression) in self.notes.iter().zip(self.expression.iter_mut()) {
+            let Some(note) = note else {
+                continue;
+            };
+            if !self.mpe || channel == MPE_MASTER_CHANNEL || note.channel == channel {
+                f(expression);
+            }
+        }
+    }
+
+    /// `value` is the raw 14-bit pitch bend value
+    pub fn pitch_bend(&mut self, channel: u8, value: u16) {
+        let bend = (value as f32 - 8192.) / 8192.;
+        self.for_each_expression(channel, |expression| expression.pitch_bend = bend);
+    }
+
+    pub fn pressure(&mut self, channel: u8, value: u8) {
+        let pressure = value as f32 / 127.;
+        self.for_each_expression(channel, |expression| expression.pressure = pressure);
+    }
+
+    pub fn timbre(&mut self, channel: u8, value: u8) {
+        if !self.mpe {
+            return;
+        }
+        let timbre = value as f32 / 127.;
+        self.for_each_expression(channel, |expression| expression.timbre = Some(timbre));
+    }
+
+    /// Applies the held notes and their expression on top of the host's targets.  Notes step
+    /// through the degrees of `tuning` if there is one, and through semitones otherwise.
+    pub fn apply(&self, targets: &mut ParamValues, tuning: Option<&Tuning>) {
+        if !self.enabled {
+            return;
+        }
+
+        for voice_ix in 0..VOICE_COUNT {
+            let gain_id = ParamId::Voice(voice_ix, VoiceParam::Gain);
+            let Some(note) = self.notes[voice_ix] else {
+                targets.set(gain_id, 0.);
+                continue;
+            };
+            let expression = self.expression[voice_ix];
+            targets.set(gain_id, targets.get(gain_id) * note.velocity);
+
+            let steps = note.note as i32 - ROOT_NOTE as i32;
+            let note_semitones = tuning.map_or(steps as f32, |tuning| tuning.semitones(steps));
+            let semitones = note_semitones + expression.pitch_bend * self.pitch_bend_range;
+            let speed_id = ParamId::Voice(voice_ix, VoiceParam::SampleSpeedRatio);
+            targets.set(speed_id, targets.get(speed_id) * (semitones / 12.).exp2());
+
+            let between_grains_id = ParamId::Voice(voice_ix, VoiceParam::SamplesBetweenGrains);
+            targets.set(
+                between_grains_id,
+                targets.get(between_grains_id)
+                    / (expression.pressure * PRESSURE_DENSITY_OCTAVES).exp2(),
+            );
+
+            if let Some(timbre) = expression.timbre {
+                let cutoff =
+                    TIMBRE_MIN_CUTOFF * (TIMBRE_MAX_CUTOFF / TIMBRE_MIN_CUTOFF).powf(timbre);
+                targets.set(ParamId::Voice(voice_ix, VoiceParam::FilterCutoff), cutoff);
+            }
+        }
+    }
+}
+
+/// Envelope that moves a voice's filter cutoff while it plays a note
+#[derive(Clone, Copy, Debug)]
+pub struct FilterEnvelope {
+    pub adsr: Adsr,
+    /// Octaves the cutoff is moved by at the envelope's peak; negative values sweep downwards
+    pub amount: f32,
+    /// How much the amount follows the note's velocity, from 0 (not at all) to 1 (proportionally)
+    pub velocity_sensitivity: f32,
+    velocity: f32,
+    octaves: f32,
+}
+
+impl Default for FilterEnvelope {
+    fn default() -> Self {
+        FilterEnvelope {
+            adsr: Adsr::new(5., 200., 0., 200.),
+            amount: 0.,
+            velocity_sensitivity: 0.,
+            velocity: 1.,
+            octaves: 0.,
+        }
+    }
+}
+
+impl FilterEnvelope {
+    /// Advances the envelope by one sample.  `velocity` is that of the note the voice is playing,
+    /// if any; the envelope is triggered when a note starts and released when it ends.
+    #[inline]
+    pub fn tick(&mut self, velocity: Option<f32>, sample_rate: f32) {
+        match velocity {
+            Some(velocity) if !self.adsr.is_gated() => {
+                self.velocity = velocity;
+                self.adsr.trigger();
+            }
+            None if self.adsr.is_gated() => self.adsr.release(),
+            _ => {}
+        }
+        let level = self.adsr.tick(sample_rate);
+        let velocity_gain = 1. - self.velocity_sensitivity * (1. - self.velocity);
+        self.octaves = level * self.amount * velocity_gain;
+    }
+
+    /// Octaves the cutoff is currently moved by
+    #[inline]
+    pub fn octaves(&self) -> f32 {
+        self.octaves
+    }
+}
+
+/// Turns the polyphonic note mode on or off.  With `mpe` enabled, expression messages only affect
+/// the note on their own channel and timbre (CC74) sets the voice's lowpass cutoff.
+pub fn set_note_mode(ctx: *mut GranularCtx, enabled: bool, mpe: bool, pitch_bend_range: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.notes.enabled = enabled;
+    ctx.notes.mpe = mpe;
+    if pitch_bend_range.is_finite() {
+        ctx.notes.pitch_bend_range = clamp(0., 96., pitch_bend_range);
+    }
+    if !enabled {
+        ctx.notes.all_notes_off();
+    }
+}
+
+/// Sets the stages of a voice's filter envelope, which runs while the voice plays a note in note
+/// mode.  Times are in milliseconds and clamped to 0..10000; `sustain` is clamped to 0..1.
+pub fn set_voice_filter_envelope(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    attack_ms: f32,
+    decay_ms: f32,
+    sustain: f32,
+    release_ms: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT
+        || ![attack_ms, decay_ms, sustain, release_ms]
+            .iter()
+            .all(|value| value.is_finite())
+    {
+        return;
+    }
+    let adsr = &mut ctx.voices[voice_ix].filter_envelope.adsr;
+    adsr.attack_ms = clamp(0., 10000., attack_ms);
+    adsr.decay_ms = clamp(0., 10000., decay_ms);
+    adsr.sustain = clamp(0., 1., sustain);
+    adsr.release_ms = clamp(0., 10000., release_ms);
+}
+
+/// Sets how far a voice's filter envelope moves its cutoff, in octaves at the envelope's peak and
+/// clamped to ±10, and how much that follows the note's velocity from 0 to 1.  The envelope only
+/// affects voices whose filter is on.
+pub fn set_voice_filter_envelope_amount(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    octaves: f32,
+    velocity_sensitivity: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !octaves.is_finite() || !velocity_sensitivity.is_finite() {
+        return;
+    }
+    let envelope = &mut ctx.voices[voice_ix].filter_envelope;
+    envelope.amount = clamp(-10., 10., octaves);
+    envelope.velocity_sensitivity = clamp(0., 1., velocity_sensitivity);
+}
+
+#[test]
+fn filter_envelope_follows_notes_and_velocity() {
+    let mut notes = NoteMode {
+        enabled: true,
+        ..Default::default()
+    };
+    let mut envelope = FilterEnvelope {
+        adsr: Adsr::new(1., 1., 1., 1.),
+        amount: 2.,
+        velocity_sensitivity: 1.,
+        ..Default::default()
+    };
+    envelope.tick(notes.velocity(0), 1000.);
+    assert_eq!(envelope.octaves(), 0.);
+
+    notes.note_on(1, 60, 127);
+    envelope.tick(notes.velocity(0), 1000.);
+    assert_eq!(envelope.octaves(), 2.);
+    notes.note_off(1, 60);
+    envelope.tick(notes.velocity(0), 1000.);
+    assert_eq!(envelope.octaves(), 0.);
+
+    // Half velocity halves the sweep when fully velocity sensitive
+    notes.note_on(1, 60, 127 / 2);
+    envelope.tick(notes.velocity(0), 1000.);
+    assert!((envelope.octaves() - 127. / 2. / 127. * 2.).abs() < 0.01);
+}
+
+#[test]
+fn mpe_expression_only_affects_its_note() {
+    let mut notes = NoteMode {
+        enabled: true,
+        mpe: true,
+        pitch_bend_range: 48.,
+        ..Default::default()
+    };
+    notes.note_on(1, 72, 127);
+    notes.note_on(2, 60, 127);
+    notes.pitch_bend(2, 8192 + 4096);
+    notes.timbre(2, 127);
+
+    let mut targets = ParamValues::default();
+    for voice_ix in 0..VOICE_COUNT {
+        targets.set(ParamId::Voice(voice_ix, VoiceParam::Gain), 1.);
+        targets.set(ParamId::Voice(voice_ix, VoiceParam::SampleSpeedRatio), 1.);
+    }
+    notes.apply(&mut targets, None);
+    assert_eq!(targets.voice(0, VoiceParam::SampleSpeedRatio), 2.);
+    assert_eq!(targets.voice(1, VoiceParam::SampleSpeedRatio), 4.);
+    assert_eq!(targets.voice(0, VoiceParam::FilterCutoff), 0.);
+    assert!((targets.voice(1, VoiceParam::FilterCutoff) - TIMBRE_MAX_CUTOFF).abs() < 1.);
+
+    notes.note_off(1, 72);
+    notes.apply(&mut targets, None);
+    assert_eq!(targets.voice(0, VoiceParam::Gain), 0.);
+}
@@ -0,0 +1,119 @@
+//! Loop-style overdub into the source buffer.  After every frame, the engine's output or the live
+//! input is mixed into a region of the loaded waveform at a write head that loops around the
+//! region, while what was there is scaled by the feedback amount.  Grains reading the region then
+//! granulate their own output, so the loop keeps evolving.
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverdubSource {
+    /// The stereo master output
+    Output,
+    /// The audio input written by the host
+    Input,
+}
+
+impl OverdubSource {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(OverdubSource::Output),
+            1 => Some(OverdubSource::Input),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Overdub {
+    pub source: OverdubSource,
+    pub region_start: usize,
+    pub region_end: usize,
+    /// How much of the existing audio is kept each time the write head passes over it
+    pub feedback: f32,
+    write_ix: usize,
+}
+
+impl Overdub {
+    pub fn new(
+        source: OverdubSource,
+        region_start: usize,
+        region_end: usize,
+        feedback: f32,
+    ) -> Self {
+        Overdub {
+            source,
+            region_start,
+            region_end,
+            feedback,
+            write_ix: region_start,
+        }
+    }
+
+    /// Mixes `input` into the waveform channels at the write head.  The sums are clamped to ±1 so
+    /// that feeding the output back in can't run away.
+    pub fn write(
+        &mut self,
+        left: &mut [f32],
+        mut right: Option<&mut [f32]>,
+        input: (&[f32], &[f32]),
+    ) {
+        let end = self.region_end.min(left.len());
+        if self.region_start >= end {
+            return;
+        }
+        for (input_left, input_right) in input.0.iter().zip(input.1) {
+            if !(self.region_start..end).contains(&self.write_ix) {
+                self.write_ix = self.region_start;
+            }
+            let ix = self.write_ix;
+            left[ix] = clamp(-1., 1., left[ix] * self.feedback + input_left);
+            if let Some(right) = right.as_deref_mut() {
+                right[ix] = clamp(-1., 1., right[ix] * self.feedback + input_right);
+            }
+            self.write_ix += 1;
+        }
+    }
+}
+
+/// Starts mixing the output (`source` 0) or the audio input (1) back into the waveform
+/// between `region_start` and `region_end`, keeping `feedback` of what was there on every pass.
+/// This doesn't apply to external waveforms.
+pub fn set_overdub(
+    ctx: *mut GranularCtx,
+    source: u32,
+    region_start: usize,
+    region_end: usize,
+    feedback: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(source) = OverdubSource::from_index(source) else {
+        return;
+    };
+    if !feedback.is_finite() {
+        return;
+    }
+    ctx.overdub = Some(Overdub::new(
+        source,
+        region_start,
+        region_end,
+        clamp(0., 1., feedback),
+    ));
+}
+
+pub fn clear_overdub(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.overdub = None;
+    }
+}
+
+#[test]
+fn overdub_loops_around_its_region() {
+    let mut waveform = vec![0.; 6];
+    let mut overdub = Overdub::new(OverdubSource::Input, 1, 4, 0.5);
+    let input = [0.25, 0.5, 0.75, 0.125];
+    overdub.write(&mut waveform, None, (&input, &input));
+    assert_eq!(waveform, vec![0., 0.25, 0.5, 0.75, 0., 0.]);
+}
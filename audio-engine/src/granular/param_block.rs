@@ -0,0 +1,263 @@
+//! Packed parameter block.  Instead of passing parameters as call arguments, hosts can write them
+//! into a block in WASM memory and render from it.  The block starts with a header of `u32`
+//! words followed by the values as `f32`s:
+//!
+//! | word | contents                                         |
+//! |------|--------------------------------------------------|
+//! | 0    | `PARAM_BLOCK_MAGIC`, the bytes `GPRM`            |
+//! | 1    | layout version, currently `PARAM_BLOCK_VERSION`  |
+//! | 2    | number of global parameters in the block         |
+//! | 3    | number of parameters per voice in the block      |
+//! | 4    | number of voices in the block                    |
+//! | 5..  | global values, then each voice's values in order |
+//!
+//! Parameters are only ever added at the end of the global or per-voice lists, so the counts in
+//! the header are enough for the engine to read blocks written against an older or newer
+//! parameter list: parameters the block doesn't have keep their previous values, and ones the
+//! engine doesn't know are skipped.  The engine fills in the header with its own layout when the
+//! block is created.
+//!
+//! Hosts that write parameters from another thread than the one rendering use the shared block
+//! instead.  It's the same block behind one more word, a sequence counter, for a seqlock:
+//!
+//! 1. the writer makes the counter odd with an atomic add, e.g. `Atomics.add(words, 0, 1)`
+//! 2. writes the values
+//! 3. makes the counter even again with another atomic add
+//!
+//! The engine takes a copy of the block at the start of each frame and only uses it if the
+//! counter was even and unchanged across the copy, so a frame never sees half of one update and
+//! half of another.  A frame that lands mid-update keeps the previous values, and the update is
+//! picked up on the next frame.
+//!
+//! This needs memory both threads can see, so the shared block only exists in builds with
+//! `shared_memory` set: native builds, and WASM builds with the `atomics` target feature, e.g.
+//! `pnpm run build:wasm:shared`, whose memory the main thread can view through a
+//! `SharedArrayBuffer`.  The default WASM build has no shared memory, and its hosts write the
+//! packed block, or the I/O block, from the worklet that renders.
+
+#[cfg(shared_memory)]
+use std::sync::atomic::{fence, AtomicU32, Ordering};
+
+use super::params::{
+    GlobalParam, ParamId, ParamValues, VoiceParam, GLOBAL_PARAM_COUNT, VOICE_COUNT,
+    VOICE_PARAM_COUNT,
+};
+
+pub const PARAM_BLOCK_MAGIC: u32 = u32::from_le_bytes(*b"GPRM");
+pub const PARAM_BLOCK_VERSION: u32 = 1;
+pub const PARAM_BLOCK_HEADER_LEN: usize = 5;
+/// Words available for values, leaving room for blocks written against bigger parameter lists
+pub const PARAM_BLOCK_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParamBlockError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The counts in the header describe more values than the block has room for
+    TooLarge,
+}
+
+#[derive(Clone)]
+pub struct ParamBlock {
+    words: Box<[u32]>,
+}
+
+pub const PARAM_BLOCK_LEN: usize = PARAM_BLOCK_HEADER_LEN + PARAM_BLOCK_CAPACITY;
+
+/// A zeroed block with the engine's own header
+pub fn default_words() -> Box<[u32]> {
+    let mut words = vec![0; PARAM_BLOCK_LEN].into_boxed_slice();
+    words[..PARAM_BLOCK_HEADER_LEN].copy_from_slice(&[
+        PARAM_BLOCK_MAGIC,
+        PARAM_BLOCK_VERSION,
+        GLOBAL_PARAM_COUNT as u32,
+        VOICE_PARAM_COUNT as u32,
+        VOICE_COUNT as u32,
+    ]);
+    words
+}
+
+impl Default for ParamBlock {
+    fn default() -> Self {
+        ParamBlock {
+            words: default_words(),
+        }
+    }
+}
+
+impl ParamBlock {
+    pub fn as_mut_ptr(&mut self) -> *mut u32 {
+        self.words.as_mut_ptr()
+    }
+
+    /// Writes the values in the block to `targets`
+    pub fn read(&self, targets: &mut ParamValues) -> Result<(), ParamBlockError> {
+        read_words(&self.words, targets)
+    }
+}
+
+/// A parameter block behind a sequence counter, for hosts writing it from another thread
+#[cfg(shared_memory)]
+pub struct SharedParamBlock {
+    /// The counter, then the block
+    words: Box<[AtomicU32]>,
+    /// Copy of the block taken at the start of a frame
+    snapshot: Box<[u32]>,
+    /// Counter of the last update applied
+    applied: u32,
+}
+
+#[cfg(shared_memory)]
+impl Default for SharedParamBlock {
+    fn default() -> Self {
+        let words = std::iter::once(0)
+            .chain(default_words().iter().copied())
+            .map(AtomicU32::new)
+            .collect();
+        SharedParamBlock {
+            words,
+            snapshot: vec![0; PARAM_BLOCK_LEN].into_boxed_slice(),
+            applied: 0,
+        }
+    }
+}
+
+/// A block at its own address holding the same words, which the host doesn't write to
+#[cfg(shared_memory)]
+impl Clone for SharedParamBlock {
+    fn clone(&self) -> Self {
+        SharedParamBlock {
+            words: self
+                .words
+                .iter()
+                .map(|word| AtomicU32::new(word.load(Ordering::Relaxed)))
+                .collect(),
+            snapshot: self.snapshot.clone(),
+            applied: self.applied,
+        }
+    }
+}
+
+#[cfg(shared_memory)]
+impl SharedParamBlock {
+    /// Points at the counter, followed by the block
+    pub fn as_mut_ptr(&mut self) -> *mut u32 {
+        self.words.as_ptr() as *mut u32
+    }
+
+    /// Writes the values in the block to `targets` if the host has finished an update since the
+    /// last one applied.  Returns whether it had.
+    pub fn read(&mut self, targets: &mut ParamValues) -> Result<bool, ParamBlockError> {
+        let (counter, block) = self.words.split_first().unwrap();
+        let sequence = counter.load(Ordering::Acquire);
+        if sequence % 2 == 1 || sequence == self.applied {
+            return Ok(false);
+        }
+        for (copy, word) in self.snapshot.iter_mut().zip(block) {
+            *copy = word.load(Ordering::Relaxed);
+        }
+        // Keeps the second look at the counter from happening before the copy
+        fence(Ordering::Acquire);
+        if counter.load(Ordering::Relaxed) != sequence {
+            return Ok(false);
+        }
+        // An unreadable block is read again every frame, so it stays flagged until it's fixed
+        read_words(&self.snapshot, targets)?;
+        self.applied = sequence;
+        Ok(true)
+    }
+}
+
+/// Writes the values in the block `words` to `targets`
+pub fn read_words(words: &[u32], targets: &mut ParamValues) -> Result<(), ParamBlockError> {
+    let header = &words[..PARAM_BLOCK_HEADER_LEN];
+    if header[0] != PARAM_BLOCK_MAGIC {
+        return Err(ParamBlockError::BadMagic);
+    }
+    if header[1] != PARAM_BLOCK_VERSION {
+        return Err(ParamBlockError::UnsupportedVersion(header[1]));
+    }
+    let [global_count, voice_param_count, voice_count] =
+        [header[2], header[3], header[4]].map(|count| count as usize);
+    let value_count = voice_param_count
+        .checked_mul(voice_count)
+        .and_then(|count| count.checked_add(global_count));
+    if value_count.is_none_or(|count| count > PARAM_BLOCK_CAPACITY) {
+        return Err(ParamBlockError::TooLarge);
+    }
+
+    let values = &words[PARAM_BLOCK_HEADER_LEN..];
+    let value = |ix: usize| f32::from_bits(values[ix]);
+    for (ix, param) in GlobalParam::ALL.into_iter().enumerate().take(global_count) {
+        targets.set(ParamId::Global(param), value(ix));
+    }
+    for voice_ix in 0..voice_count.min(VOICE_COUNT) {
+        let voice_start = global_count + voice_ix * voice_param_count;
+        for (ix, param) in VoiceParam::ALL
+            .into_iter()
+            .enumerate()
+            .take(voice_param_count)
+        {
+            targets.set(ParamId::Voice(voice_ix, param), value(voice_start + ix));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn older_blocks_leave_newer_params_alone() {
+    let mut block = ParamBlock::default();
+    // A block from before the last voice parameter was added
+    let voice_param_count = VOICE_PARAM_COUNT - 1;
+    block.words[3] = voice_param_count as u32;
+    let values = &mut block.words[PARAM_BLOCK_HEADER_LEN..];
+    values[GLOBAL_PARAM_COUNT + voice_param_count + VoiceParam::Gain as usize] = 0.5f32.to_bits();
+
+    let last_param = VoiceParam::ALL[VOICE_PARAM_COUNT - 1];
+    let mut targets = ParamValues::default();
+    targets.set(ParamId::Voice(1, last_param), 0.25);
+    assert_eq!(block.read(&mut targets), Ok(()));
+    assert_eq!(targets.voice(1, VoiceParam::Gain), 0.5);
+    assert_eq!(targets.voice(1, last_param), 0.25);
+
+    block.words[1] = PARAM_BLOCK_VERSION + 1;
+    assert_eq!(
+        block.read(&mut targets),
+        Err(ParamBlockError::UnsupportedVersion(PARAM_BLOCK_VERSION + 1))
+    );
+}
+
+#[cfg(shared_memory)]
+#[test]
+fn shared_blocks_only_apply_finished_updates() {
+    let mut block = SharedParamBlock::default();
+    let gain = PARAM_BLOCK_HEADER_LEN + GLOBAL_PARAM_COUNT + VoiceParam::Gain as usize;
+    let write = |block: &SharedParamBlock, ix: usize, value: f32| {
+        block.words[1 + ix].store(value.to_bits(), Ordering::Relaxed);
+    };
+    let mut targets = ParamValues::default();
+    targets.set(ParamId::Voice(0, VoiceParam::Gain), 0.25);
+    // Nothing's been written yet
+    assert_eq!(block.read(&mut targets), Ok(false));
+
+    // Mid-update
+    block.words[0].fetch_add(1, Ordering::AcqRel);
+    write(&block, gain, 0.5);
+    assert_eq!(block.read(&mut targets), Ok(false));
+    assert_eq!(targets.voice(0, VoiceParam::Gain), 0.25);
+
+    block.words[0].fetch_add(1, Ordering::AcqRel);
+    assert_eq!(block.read(&mut targets), Ok(true));
+    assert_eq!(targets.voice(0, VoiceParam::Gain), 0.5);
+    // The same update isn't applied twice
+    targets.set(ParamId::Voice(0, VoiceParam::Gain), 1.);
+    assert_eq!(block.read(&mut targets), Ok(false));
+    assert_eq!(targets.voice(0, VoiceParam::Gain), 1.);
+
+    // A bad header is reported until it's fixed
+    block.words[0].fetch_add(1, Ordering::AcqRel);
+    write(&block, 0, 0.);
+    block.words[0].fetch_add(1, Ordering::AcqRel);
+    assert_eq!(block.read(&mut targets), Err(ParamBlockError::BadMagic));
+    assert_eq!(block.read(&mut targets), Err(ParamBlockError::BadMagic));
+}
@@ -0,0 +1,1074 @@
+//! Parameter model for the granular engine.  Every render parameter has a stable flat index: the
+//! global parameters come first, followed by each voice's parameters in voice order.  This index is
+//! what the host uses when it needs to address one parameter individually (smoothing times, etc.).
+
+#[cfg(feature = "serde")]
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::dsp::{mix, smooth};
+
+pub const VOICE_COUNT: usize = 2;
+
+/// Parameters shared by all voices
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GlobalParam {
+    SelectionStartSampleIx,
+    SelectionEndSampleIx,
+    GrainSize,
+    LinearSlopeLength,
+    SlopeLinearity,
+    MasterGain,
+    /// Cents by which the outermost voices' sample speed ratios are offset below and above their
+    /// own ratio, with the voices in between spread evenly
+    DetuneSpread,
+    /// Gain of the selection played straight through under the voices
+    DryGain,
+    /// Position of the crossfade between the two stored scenes, from A (0) to B (1)
+    SceneMorph,
+    /// 1 to granulate the mid signal of stereo waveforms with the first voice and the side signal
+    /// with the second, decoding the two back into left and right instead of panning them
+    MidSide,
+    /// 1 to snap the transposition of every grain spawned to the nearest interval of the tuning
+    /// above its root, or to whole semitones without a tuning
+    GrainPitchSnap,
+    /// 1 to make the transport follow the MIDI clock forwarded with `handle_midi_event` instead
+    /// of `set_transport`, once two clock ticks have arrived to measure the tempo from
+    MidiClockSync,
+}
+
+impl GlobalParam {
+    pub const ALL: [GlobalParam; 12] = [
+        GlobalParam::SelectionStartSampleIx,
+        GlobalParam::SelectionEndSampleIx,
+        GlobalParam::GrainSize,
+        GlobalParam::LinearSlopeLength,
+        GlobalParam::SlopeLinearity,
+        GlobalParam::MasterGain,
+        GlobalParam::DetuneSpread,
+        GlobalParam::DryGain,
+        GlobalParam::SceneMorph,
+        GlobalParam::MidSide,
+        GlobalParam::GrainPitchSnap,
+        GlobalParam::MidiClockSync,
+    ];
+}
+
+/// Parameters that each voice has its own copy of
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VoiceParam {
+    /// Positive values are lowpass, negative values are highpass
+    FilterCutoff,
+    MovementSamplesPerSample,
+    SampleSpeedRatio,
+    SamplesBetweenGrains,
+    Gain,
+    GrainStartRandomnessSamples,
+    /// 1 when the voice is muted, 0 otherwise.  Ramped so it can be toggled without clicks.
+    Mute,
+    /// 1 when the voice is soloed, 0 otherwise.  While any voice is soloed the others are silent.
+    Solo,
+    /// Stereo position from -1 (hard left) to 1 (hard right)
+    Pan,
+    /// Per-voice counterparts of the global slope parameters, used instead of them by voices
+    /// that have their own slope shape
+    SlopeLength,
+    SlopeLinearity,
+    /// Moves the peak of the grain envelope from the start of the grain (-1) through its center
+    /// (0) to its end (1)
+    EnvelopeSkew,
+    /// Crossfade between the two window shapes selected for the voice, from the first (0) to the
+    /// second (1)
+    EnvelopeMorph,
+    /// Level of the voice's send to the delay bus
+    DelaySend,
+    /// 1 to scale the voice's grains by the inverse of the amplitude they're expected to add up
+    /// to, 0 to divide them by the sum of their gains.  Ramped so it can be toggled without
+    /// clicks.
+    OverlapCompensation,
+    /// Chance from 0 to 1 that a grain the voice schedules is dropped instead of spawned
+    GrainSkipProbability,
+    /// 1 to treat the selection as circular, so that grains running past its end continue from
+    /// its start.  Applies to grains spawned from then on.
+    WrapsSelection,
+}
+
+impl VoiceParam {
+    pub const ALL: [VoiceParam; 17] = [
+        VoiceParam::FilterCutoff,
+        VoiceParam::MovementSamplesPerSample,
+        VoiceParam::SampleSpeedRatio,
+        VoiceParam::SamplesBetweenGrains,
+        VoiceParam::Gain,
+        VoiceParam::GrainStartRandomnessSamples,
+        VoiceParam::Mute,
+        VoiceParam::Solo,
+        VoiceParam::Pan,
+        VoiceParam::SlopeLength,
+        VoiceParam::SlopeLinearity,
+        VoiceParam::EnvelopeSkew,
+        VoiceParam::EnvelopeMorph,
+        VoiceParam::DelaySend,
+        VoiceParam::OverlapCompensation,
+        VoiceParam::GrainSkipProbability,
+        VoiceParam::WrapsSelection,
+    ];
+}
+
+pub const GLOBAL_PARAM_COUNT: usize = GlobalParam::ALL.len();
+pub const VOICE_PARAM_COUNT: usize = VoiceParam::ALL.len();
+pub const PARAM_COUNT: usize = GLOBAL_PARAM_COUNT + VOICE_COUNT * VOICE_PARAM_COUNT;
+
+/// Static description of a parameter for hosts that generate UIs and validation from it
+#[derive(Clone, Copy, Debug)]
+pub struct ParamInfo {
+    /// Name without any voice prefix, matching the `render_granular` argument names
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+}
+
+const fn info(
+    name: &'static str,
+    unit: &'static str,
+    min: f32,
+    max: f32,
+    default: f32,
+) -> ParamInfo {
+    ParamInfo {
+        name,
+        unit,
+        min,
+        max,
+        default,
+    }
+}
+
+impl GlobalParam {
+    pub fn info(self) -> ParamInfo {
+        match self {
+            GlobalParam::SelectionStartSampleIx => {
+                info("selection_start_sample_ix", "samples", 0., f32::MAX, 0.)
+            }
+            GlobalParam::SelectionEndSampleIx => {
+                info("selection_end_sample_ix", "samples", 0., f32::MAX, 0.)
+            }
+            GlobalParam::GrainSize => info("grain_size", "samples", 1., 96000., 800.),
+            GlobalParam::LinearSlopeLength => info("linear_slope_length", "", 0., 1., 0.5),
+            GlobalParam::SlopeLinearity => info("slope_linearity", "", 0., 1., 0.5),
+            GlobalParam::MasterGain => info("master_gain", "gain", 0., 4., 1.),
+            GlobalParam::DetuneSpread => info("detune_spread", "cents", 0., 1200., 0.),
+            GlobalParam::DryGain => info("dry_gain", "gain", 0., 4., 0.),
+            GlobalParam::SceneMorph => info("scene_morph", "", 0., 1., 0.),
+            GlobalParam::MidSide => info("mid_side", "bool", 0., 1., 0.),
+            GlobalParam::GrainPitchSnap => info("grain_pitch_snap", "bool", 0., 1., 0.),
+            GlobalParam::MidiClockSync => info("midi_clock_sync", "bool", 0., 1., 0.),
+        }
+    }
+}
+
+impl VoiceParam {
+    pub fn info(self) -> ParamInfo {
+        match self {
+            VoiceParam::FilterCutoff => info("filter_cutoff", "Hz", -20000., 20000., 0.),
+            VoiceParam::MovementSamplesPerSample => info(
+                "movement_samples_per_sample",
+                "samples/sample",
+                -16.,
+                16.,
+                0.1,
+            ),
+            VoiceParam::SampleSpeedRatio => info("sample_speed_ratio", "ratio", 0.001, 1000., 1.),
+            VoiceParam::SamplesBetweenGrains => {
+                info("samples_between_grains", "samples", 1., 96000., 400.)
+            }
+            VoiceParam::Gain => info("gain", "gain", 0., 4., 1.),
+            VoiceParam::GrainStartRandomnessSamples => {
+                info("grain_start_randomness_samples", "samples", 0., 96000., 0.)
+            }
+            VoiceParam::Mute => info("mute", "bool", 0., 1., 0.),
+            VoiceParam::Solo => info("solo", "bool", 0., 1., 0.),
+            VoiceParam::Pan => info("pan", "", -1., 1., 0.),
+            VoiceParam::SlopeLength => info("slope_length", "", 0., 1., 0.5),
+            VoiceParam::SlopeLinearity => info("slope_linearity", "", 0., 1., 0.5),
+            VoiceParam::EnvelopeSkew => info("envelope_skew", "", -1., 1., 0.),
+            VoiceParam::EnvelopeMorph => info("envelope_morph", "", 0., 1., 0.),
+            VoiceParam::DelaySend => info("delay_send", "gain", 0., 1., 0.),
+            VoiceParam::OverlapCompensation => info("overlap_compensation", "bool", 0., 1., 0.),
+            VoiceParam::GrainSkipProbability => info("grain_skip_probability", "", 0., 1., 0.),
+            VoiceParam::WrapsSelection => info("wraps_selection", "bool", 0., 1., 0.),
+        }
+    }
+}
+
+/// How a parameter moves from its current value to a new target
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SmoothingMode {
+    /// Exponential approach with a configurable time constant
+    OnePole,
+    /// Straight line from the previous value to the new target across one block.  Used for gains,
+    /// where any step at all at a block boundary is audible.
+    LinearRamp,
+    /// `OnePole` in octaves, so that a sweep moves at the same rate through every octave, and
+    /// ramping like `LinearRamp` instead of stepping at block boundaries when the smoothing time is
+    /// 0.  Used for filter cutoffs, which the octaves run through continuously from lowpass to
+    /// highpass.
+    Cutoff,
+}
+
+/// How often a parameter's value is updated while a frame renders
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParamRate {
+    /// Moved towards its target once at the start of each processing chunk, a frame unless the
+    /// host sets another size, and held for the rest of it.
+    /// Used for the parameters that shape how grains are scheduled and seeded, which only matter
+    /// when a grain starts, so updating them every sample is wasted work.
+    Control,
+    /// Moved every sample, and can be driven sample by sample by a buffer from the host
+    Audio,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ParamId {
+    Global(GlobalParam),
+    Voice(usize, VoiceParam),
+}
+
+impl ParamId {
+    pub fn index(self) -> usize {
+        match self {
+            ParamId::Global(param) => param as usize,
+            ParamId::Voice(voice_ix, param) => {
+                GLOBAL_PARAM_COUNT + voice_ix * VOICE_PARAM_COUNT + param as usize
+            }
+        }
+    }
+
+    pub fn from_index(ix: usize) -> Option<ParamId> {
+        if ix < GLOBAL_PARAM_COUNT {
+            return Some(ParamId::Global(GlobalParam::ALL[ix]));
+        }
+        if ix >= PARAM_COUNT {
+            return None;
+        }
+        let voice_relative_ix = ix - GLOBAL_PARAM_COUNT;
+        Some(ParamId::Voice(
+            voice_relative_ix / VOICE_PARAM_COUNT,
+            VoiceParam::ALL[voice_relative_ix % VOICE_PARAM_COUNT],
+        ))
+    }
+
+    /// Default one-pole smoothing time in milliseconds.  The selection bounds are smoothed heavily
+    /// since jumping the playback region is the most click-prone change a host can make.
+    fn default_smoothing_ms(self) -> f32 {
+        match self {
+            ParamId::Global(GlobalParam::SelectionStartSampleIx)
+            | ParamId::Global(GlobalParam::SelectionEndSampleIx) => 500.,
+            ParamId::Global(GlobalParam::GrainSize) => 25.,
+            _ => 10.,
+        }
+    }
+
+    /// Whether values between the parameter's settings mean anything.  Flags aren't interpolated
+    /// when morphing between configurations.
+    fn is_continuous(self) -> bool {
+        !matches!(
+            self,
+            ParamId::Global(GlobalParam::MidSide)
+                | ParamId::Global(GlobalParam::GrainPitchSnap)
+                | ParamId::Global(GlobalParam::MidiClockSync)
+                | ParamId::Voice(_, VoiceParam::Mute)
+                | ParamId::Voice(_, VoiceParam::Solo)
+                | ParamId::Voice(_, VoiceParam::OverlapCompensation)
+                | ParamId::Voice(_, VoiceParam::WrapsSelection)
+        )
+    }
+
+    fn smoothing_mode(self) -> SmoothingMode {
+        match self {
+            ParamId::Global(GlobalParam::MasterGain)
+            | ParamId::Global(GlobalParam::DryGain)
+            | ParamId::Voice(_, VoiceParam::Gain)
+            | ParamId::Voice(_, VoiceParam::Mute)
+            | ParamId::Voice(_, VoiceParam::Solo)
+            | ParamId::Voice(_, VoiceParam::OverlapCompensation)
+            // Control rate, so they switch at the start of the next processing chunk
+            | ParamId::Global(GlobalParam::MidSide)
+            | ParamId::Global(GlobalParam::GrainPitchSnap)
+            | ParamId::Global(GlobalParam::MidiClockSync)
+            | ParamId::Voice(_, VoiceParam::WrapsSelection) => SmoothingMode::LinearRamp,
+            ParamId::Voice(_, VoiceParam::FilterCutoff) => SmoothingMode::Cutoff,
+            _ => SmoothingMode::OnePole,
+        }
+    }
+
+    pub fn rate(self) -> ParamRate {
+        match self {
+            ParamId::Global(GlobalParam::GrainSize)
+            | ParamId::Global(GlobalParam::DetuneSpread)
+            | ParamId::Global(GlobalParam::MidSide)
+            | ParamId::Global(GlobalParam::GrainPitchSnap)
+            | ParamId::Global(GlobalParam::MidiClockSync)
+            | ParamId::Voice(_, VoiceParam::SamplesBetweenGrains)
+            | ParamId::Voice(_, VoiceParam::GrainStartRandomnessSamples)
+            | ParamId::Voice(_, VoiceParam::GrainSkipProbability)
+            | ParamId::Voice(_, VoiceParam::WrapsSelection) => ParamRate::Control,
+            _ => ParamRate::Audio,
+        }
+    }
+
+    pub fn info(self) -> ParamInfo {
+        match self {
+            ParamId::Global(param) => param.info(),
+            ParamId::Voice(_, param) => param.info(),
+        }
+    }
+
+    /// Full name including the voice prefix, e.g. `voice_1_gain`
+    pub fn name(self) -> String {
+        match self {
+            ParamId::Global(param) => param.info().name.to_string(),
+            ParamId::Voice(voice_ix, param) => {
+                format!("voice_{}_{}", voice_ix + 1, param.info().name)
+            }
+        }
+    }
+
+    /// Value used before the host has set anything.  Parameters passed to every
+    /// `render_granular` call are overwritten immediately; this matters for the ones with
+    /// dedicated setters.
+    fn default_value(self) -> f32 {
+        self.info().default
+    }
+}
+
+/// Formats a float as a JSON number.  JSON has no representation for NaN or infinities, so those
+/// become `null`; unbounded ranges use `f32::MAX` in the first place.
+pub(super) fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        format!("{}", value)
+    } else {
+        "null".to_string()
+    }
+}
+
+/// Returns a JSON array describing every parameter in flat index order
+pub fn metadata_json() -> String {
+    let entries: Vec<String> = (0..PARAM_COUNT)
+        .map(|ix| {
+            let id = ParamId::from_index(ix).unwrap();
+            let info = id.info();
+            let voice = match id {
+                ParamId::Global(_) => "null".to_string(),
+                ParamId::Voice(voice_ix, _) => voice_ix.to_string(),
+            };
+            let smoothing = match id.smoothing_mode() {
+                SmoothingMode::OnePole => "one_pole",
+                SmoothingMode::LinearRamp => "linear_ramp",
+                SmoothingMode::Cutoff => "cutoff",
+            };
+            let rate = match id.rate() {
+                ParamRate::Control => "control",
+                ParamRate::Audio => "audio",
+            };
+            format!(
+                "{{\"id\":{},\"name\":\"{}\",\"voice\":{},\"unit\":\"{}\",\"min\":{},\"max\":{},\"default\":{},\"smoothing\":\"{}\",\"smoothing_ms\":{},\"rate\":\"{}\"}}",
+                ix,
+                id.name(),
+                voice,
+                info.unit,
+                json_number(info.min),
+                json_number(info.max),
+                json_number(info.default),
+                smoothing,
+                json_number(id.default_smoothing_ms()),
+                rate,
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// One value for every parameter, indexed by `ParamId::index`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ParamValues(pub [f32; PARAM_COUNT]);
+
+impl Default for ParamValues {
+    fn default() -> Self {
+        ParamValues([0.; PARAM_COUNT])
+    }
+}
+
+impl ParamValues {
+    #[inline]
+    pub fn get(&self, id: ParamId) -> f32 {
+        self.0[id.index()]
+    }
+
+    #[inline]
+    pub fn set(&mut self, id: ParamId, value: f32) {
+        self.0[id.index()] = value;
+    }
+
+    #[inline]
+    pub fn global(&self, param: GlobalParam) -> f32 {
+        self.get(ParamId::Global(param))
+    }
+
+    #[inline]
+    pub fn voice(&self, voice_ix: usize, param: VoiceParam) -> f32 {
+        self.get(ParamId::Voice(voice_ix, param))
+    }
+
+    /// The voice's sample speed ratio offset by its share of the detune spread.  The first voice
+    /// is detuned downwards and the last one upwards.
+    #[inline]
+    pub fn voice_speed_ratio(&self, voice_ix: usize) -> f32 {
+        let spread_position = if VOICE_COUNT > 1 {
+            2. * voice_ix as f32 / (VOICE_COUNT - 1) as f32 - 1.
+        } else {
+            0.
+        };
+        let cents = self.global(GlobalParam::DetuneSpread) * spread_position;
+        self.voice(voice_ix, VoiceParam::SampleSpeedRatio) * (cents / 1200.).exp2()
+    }
+
+    /// Gain multiplier resulting from the mute and solo state of a voice.  Muting wins over
+    /// soloing.  The flags are ramped, so this moves smoothly between 0 and 1 when they change.
+    pub fn voice_mute_solo_gain(&self, voice_ix: usize) -> f32 {
+        let any_solo = (0..VOICE_COUNT)
+            .map(|voice_ix| self.voice(voice_ix, VoiceParam::Solo))
+            .fold(0., f32::max);
+        let solo_gain = 1. - any_solo * (1. - self.voice(voice_ix, VoiceParam::Solo));
+        (1. - self.voice(voice_ix, VoiceParam::Mute)) * solo_gain
+    }
+
+    /// Replaces NaN and infinite values with the matching value from `fallback`, or 0 if that
+    /// isn't finite either.  Uninitialized state on the JS side makes these easy to pass in and a
+    /// single one reaching a filter would poison its state forever.
+    pub fn sanitize(&mut self, fallback: &ParamValues) {
+        for (value, fallback) in self.0.iter_mut().zip(fallback.0.iter()) {
+            if !value.is_finite() {
+                *value = if fallback.is_finite() { *fallback } else { 0. };
+            }
+        }
+    }
+}
+
+/// A value rejected by `GranularParams` for being outside its parameter's range
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ParamRangeError {
+    pub id: ParamId,
+    pub value: f32,
+}
+
+impl fmt::Display for ParamRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = self.id.info();
+        write!(
+            f,
+            "{} of {} is outside {}..={}",
+            self.id.name(),
+            self.value,
+            info.min,
+            info.max
+        )
+    }
+}
+
+impl std::error::Error for ParamRangeError {}
+
+#[cfg(feature = "serde")]
+#[derive(Clone, PartialEq, Debug)]
+pub enum ParamsJsonError {
+    Syntax,
+    /// The document doesn't have the shape written by `GranularParams::to_json`
+    UnexpectedShape,
+    UnknownParam(String),
+    OutOfRange(ParamRangeError),
+}
+
+#[cfg(feature = "serde")]
+impl fmt::Display for ParamsJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamsJsonError::Syntax => write!(f, "invalid JSON"),
+            ParamsJsonError::UnexpectedShape => write!(f, "unexpected document shape"),
+            ParamsJsonError::UnknownParam(name) => write!(f, "unknown parameter {}", name),
+            ParamsJsonError::OutOfRange(error) => error.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl std::error::Error for ParamsJsonError {}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for ParamsJsonError {
+    fn from(error: serde_json::Error) -> Self {
+        match error.classify() {
+            serde_json::error::Category::Data => ParamsJsonError::UnexpectedShape,
+            _ => ParamsJsonError::Syntax,
+        }
+    }
+}
+
+/// Parameter values keyed by their names without voice prefixes
+#[cfg(feature = "serde")]
+type ParamsSection = BTreeMap<String, f32>;
+
+/// Serialized form of `GranularParams`
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct ParamsDocument {
+    #[serde(default)]
+    global: ParamsSection,
+    #[serde(default)]
+    voices: Vec<ParamsSection>,
+}
+
+/// The values of every parameter, in a global section and one section per voice.  Unlike
+/// `ParamValues` its setters check each value against the parameter's range, which makes it the
+/// type to build parameter sets from outside the engine: the native API, presets and the WASM
+/// layer's JSON import all go through it.
+///
+/// With the `serde` feature it serializes as a `global` object and a `voices` array of objects,
+/// each keyed by parameter names without voice prefixes.  Parameters missing from a document keep
+/// their default value, and deserializing checks every value's range.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(into = "ParamsDocument", try_from = "ParamsDocument")
+)]
+pub struct GranularParams {
+    values: ParamValues,
+}
+
+impl Default for GranularParams {
+    fn default() -> Self {
+        let mut values = ParamValues::default();
+        for ix in 0..PARAM_COUNT {
+            let id = ParamId::from_index(ix).unwrap();
+            values.set(id, id.default_value());
+        }
+        GranularParams { values }
+    }
+}
+
+impl GranularParams {
+    /// Takes values as they are, without checking their ranges
+    pub fn from_values(values: &ParamValues) -> Self {
+        GranularParams { values: *values }
+    }
+
+    pub fn values(&self) -> &ParamValues {
+        &self.values
+    }
+
+    pub fn global(&self, param: GlobalParam) -> f32 {
+        self.values.global(param)
+    }
+
+    pub fn voice(&self, voice_ix: usize, param: VoiceParam) -> f32 {
+        self.values.voice(voice_ix, param)
+    }
+
+    pub fn set_global(&mut self, param: GlobalParam, value: f32) -> Result<(), ParamRangeError> {
+        self.set(ParamId::Global(param), value)
+    }
+
+    pub fn set_voice(
+        &mut self,
+        voice_ix: usize,
+        param: VoiceParam,
+        value: f32,
+    ) -> Result<(), ParamRangeError> {
+        self.set(ParamId::Voice(voice_ix, param), value)
+    }
+
+    /// Sets a parameter if `value` is within its range
+    pub fn set(&mut self, id: ParamId, value: f32) -> Result<(), ParamRangeError> {
+        let info = id.info();
+        if !(info.min..=info.max).contains(&value) {
+            return Err(ParamRangeError { id, value });
+        }
+        self.values.set(id, value);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl GranularParams {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Reads a document written by `to_json`, checking every value's range.  Sections for voices
+    /// the engine doesn't have are ignored.
+    pub fn from_json(json: &str) -> Result<Self, ParamsJsonError> {
+        let document: ParamsDocument = serde_json::from_str(json)?;
+        GranularParams::try_from(document)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<GranularParams> for ParamsDocument {
+    fn from(params: GranularParams) -> Self {
+        let section = |ids: &mut dyn Iterator<Item = ParamId>| {
+            ids.map(|id| (id.info().name.to_string(), params.values.get(id)))
+                .collect()
+        };
+        ParamsDocument {
+            global: section(&mut GlobalParam::ALL.into_iter().map(ParamId::Global)),
+            voices: (0..VOICE_COUNT)
+                .map(|voice_ix| {
+                    section(
+                        &mut VoiceParam::ALL
+                            .into_iter()
+                            .map(|param| ParamId::Voice(voice_ix, param)),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ParamsDocument> for GranularParams {
+    type Error = ParamsJsonError;
+
+    fn try_from(document: ParamsDocument) -> Result<Self, Self::Error> {
+        let mut params = GranularParams::default();
+        let mut read_section = |section: &ParamsSection,
+                                id_for_name: &dyn Fn(&str) -> Option<ParamId>|
+         -> Result<(), ParamsJsonError> {
+            for (name, value) in section {
+                let id =
+                    id_for_name(name).ok_or_else(|| ParamsJsonError::UnknownParam(name.clone()))?;
+                params
+                    .set(id, *value)
+                    .map_err(ParamsJsonError::OutOfRange)?;
+            }
+            Ok(())
+        };
+
+        read_section(&document.global, &|name| {
+            GlobalParam::ALL
+                .into_iter()
+                .find(|param| param.info().name == name)
+                .map(ParamId::Global)
+        })?;
+        for (voice_ix, section) in document.voices.iter().take(VOICE_COUNT).enumerate() {
+            read_section(section, &|name| {
+                VoiceParam::ALL
+                    .into_iter()
+                    .find(|param| param.info().name == name)
+                    .map(|param| ParamId::Voice(voice_ix, param))
+            })?;
+        }
+        Ok(params)
+    }
+}
+
+/// Crossfade between two saved configurations of every parameter, for scene morphing
+#[derive(Clone, Copy)]
+pub struct ConfigMorph {
+    pub a: ParamValues,
+    pub b: ParamValues,
+    /// Position between the configurations, from `a` (0) to `b` (1)
+    pub t: f32,
+}
+
+impl ConfigMorph {
+    /// Replaces `targets` with the interpolated configuration.  Continuous parameters are
+    /// interpolated linearly while flags switch halfway through.  The scene morph position is
+    /// left as it is, since it's what moves between scenes.
+    pub fn apply(&self, targets: &mut ParamValues) {
+        let scene_morph_ix = ParamId::Global(GlobalParam::SceneMorph).index();
+        for (ix, target) in targets.0.iter_mut().enumerate() {
+            if ix == scene_morph_ix {
+                continue;
+            }
+            let (a, b) = (self.a.0[ix], self.b.0[ix]);
+            *target = if ParamId::from_index(ix).unwrap().is_continuous() {
+                mix(self.t, a, b)
+            } else if self.t < 0.5 {
+                a
+            } else {
+                b
+            };
+        }
+    }
+}
+
+/// Converts a smoothing time constant into the per-sample coefficient used by `dsp::smooth`
+fn smoothing_coefficient(time_ms: f32, sample_rate: f32) -> f32 {
+    if time_ms <= 0. {
+        return 0.;
+    }
+    (-1000. / (time_ms * sample_rate)).exp()
+}
+
+/// Cutoff in Hz that `SmoothingMode::Cutoff` counts octaves from, the edge of the band around 0
+/// where voice filters are bypassed
+const CUTOFF_OCTAVE_BASE_HZ: f32 = 15.;
+
+/// Octaves of a signed cutoff above the bypassed band, negative for highpass cutoffs
+fn cutoff_octaves(cutoff: f32) -> f32 {
+    (1. + cutoff.abs() / CUTOFF_OCTAVE_BASE_HZ)
+        .log2()
+        .copysign(cutoff)
+}
+
+fn cutoff_from_octaves(octaves: f32) -> f32 {
+    (CUTOFF_OCTAVE_BASE_HZ * (octaves.abs().exp2() - 1.)).copysign(octaves)
+}
+
+/// Per-parameter smoothing.  Hosts set new targets once per block and the engine moves the
+/// current values of audio-rate parameters towards them every sample, so stepwise changes from the
+/// UI don't zipper, and those of control-rate parameters once per chunk.
+#[derive(Clone)]
+pub struct ParamSmoother {
+    pub current: ParamValues,
+    target: ParamValues,
+    time_ms: ParamValues,
+    coefficients: ParamValues,
+    modes: [SmoothingMode; PARAM_COUNT],
+    rates: [ParamRate; PARAM_COUNT],
+    /// Per-sample increment of each `LinearRamp` parameter for the current block
+    ramp_increments: ParamValues,
+    ramp_samples_remaining: usize,
+    sample_rate: f32,
+    /// Set once the first targets have been received; until then there is nothing to smooth from
+    primed: bool,
+}
+
+impl ParamSmoother {
+    pub fn new(sample_rate: f32) -> Self {
+        let mut time_ms = ParamValues::default();
+        let defaults = *GranularParams::default().values();
+        let mut modes = [SmoothingMode::OnePole; PARAM_COUNT];
+        let mut rates = [ParamRate::Audio; PARAM_COUNT];
+        for ix in 0..PARAM_COUNT {
+            let id = ParamId::from_index(ix).unwrap();
+            time_ms.set(id, id.default_smoothing_ms());
+            modes[ix] = id.smoothing_mode();
+            rates[ix] = id.rate();
+        }
+
+        let mut smoother = ParamSmoother {
+            current: defaults,
+            target: defaults,
+            time_ms,
+            coefficients: ParamValues::default(),
+            modes,
+            rates,
+            ramp_increments: ParamValues::default(),
+            ramp_samples_remaining: 0,
+            sample_rate,
+            primed: false,
+        };
+        smoother.update_coefficients();
+        smoother
+    }
+
+    fn update_coefficients(&mut self) {
+        for (coefficient, time_ms) in self.coefficients.0.iter_mut().zip(self.time_ms.0.iter()) {
+            *coefficient = smoothing_coefficient(*time_ms, self.sample_rate);
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_coefficients();
+    }
+
+    /// Sets the time constant of a `OnePole` or `Cutoff` parameter; `LinearRamp` parameters always
+    /// ramp over exactly one block
+    pub fn set_time_ms(&mut self, id: ParamId, time_ms: f32) {
+        let time_ms = if time_ms.is_finite() {
+            time_ms.max(0.)
+        } else {
+            id.default_smoothing_ms()
+        };
+        self.time_ms.set(id, time_ms);
+        self.coefficients
+            .set(id, smoothing_coefficient(time_ms, self.sample_rate));
+    }
+
+    pub fn set_targets(&mut self, targets: &ParamValues) {
+        self.target = *targets;
+        if !self.primed {
+            self.current = *targets;
+            self.primed = true;
+        }
+    }
+
+    /// Sets the target of a single parameter, e.g. from one of the dedicated setters
+    pub fn set_target(&mut self, id: ParamId, value: f32) {
+        self.target.set(id, value);
+        if !self.primed {
+            self.current.set(id, value);
+        }
+    }
+
+    pub fn target(&self) -> &ParamValues {
+        &self.target
+    }
+
+    /// Steps the control-rate parameters over a chunk of `chunk_len` samples.  Must be called
+    /// after the targets have been set, like `ramp_over`, which sets up the other parameters.
+    pub fn step_control(&mut self, chunk_len: usize) {
+        for ix in 0..PARAM_COUNT {
+            if self.rates[ix] == ParamRate::Control {
+                let current = &mut self.current.0[ix];
+                let target = self.target.0[ix];
+                match self.modes[ix] {
+                    // The same distance a chunk of ticks would have covered
+                    SmoothingMode::OnePole => smooth(
+                        current,
+                        target,
+                        self.coefficients.0[ix].powi(chunk_len as i32),
+                    ),
+                    SmoothingMode::LinearRamp | SmoothingMode::Cutoff => *current = target,
+                }
+            }
+        }
+    }
+
+    /// Sets up the linear ramps to reach their targets in `samples`, e.g. for the rest of a block
+    /// after the targets change part of the way through it.  Control-rate parameters wait for the
+    /// next chunk.
+    pub fn ramp_over(&mut self, samples: usize) {
+        for (ix, mode) in self.modes.iter().enumerate() {
+            if self.rates[ix] == ParamRate::Control {
+                continue;
+            }
+            let (current, target) = (self.current.0[ix], self.target.0[ix]);
+            self.ramp_increments.0[ix] = match mode {
+                SmoothingMode::LinearRamp => (target - current) / samples as f32,
+                SmoothingMode::Cutoff => {
+                    (cutoff_octaves(target) - cutoff_octaves(current)) / samples as f32
+                }
+                SmoothingMode::OnePole => continue,
+            };
+        }
+        self.ramp_samples_remaining = samples;
+    }
+
+    /// Advances every audio-rate parameter by one sample
+    #[inline]
+    pub fn tick(&mut self) {
+        for ix in 0..PARAM_COUNT {
+            if self.rates[ix] == ParamRate::Control {
+                continue;
+            }
+            let current = &mut self.current.0[ix];
+            let target = self.target.0[ix];
+            match self.modes[ix] {
+                SmoothingMode::OnePole => smooth(current, target, self.coefficients.0[ix]),
+                SmoothingMode::LinearRamp => match self.ramp_samples_remaining {
+                    // Land exactly on the target rather than accumulating float error
+                    0 | 1 => *current = target,
+                    _ => *current += self.ramp_increments.0[ix],
+                },
+                SmoothingMode::Cutoff if *current == target => {}
+                SmoothingMode::Cutoff if self.coefficients.0[ix] > 0. => {
+                    let mut octaves = cutoff_octaves(*current);
+                    smooth(
+                        &mut octaves,
+                        cutoff_octaves(target),
+                        self.coefficients.0[ix],
+                    );
+                    *current = cutoff_from_octaves(octaves);
+                }
+                SmoothingMode::Cutoff => match self.ramp_samples_remaining {
+                    0 | 1 => *current = target,
+                    _ => {
+                        *current = cutoff_from_octaves(
+                            cutoff_octaves(*current) + self.ramp_increments.0[ix],
+                        )
+                    }
+                },
+            }
+        }
+        self.ramp_samples_remaining = self.ramp_samples_remaining.saturating_sub(1);
+    }
+}
+
+#[test]
+fn param_index_round_trip() {
+    for ix in 0..PARAM_COUNT {
+        assert_eq!(ParamId::from_index(ix).unwrap().index(), ix);
+    }
+    assert_eq!(ParamId::from_index(PARAM_COUNT), None);
+}
+
+#[test]
+fn gains_ramp_linearly_across_one_block() {
+    let gain_id = ParamId::Global(GlobalParam::MasterGain);
+    let mut smoother = ParamSmoother::new(44100.);
+    smoother.set_target(gain_id, 0.);
+    smoother.set_targets(&ParamValues::default());
+    smoother.set_target(gain_id, 1.);
+
+    smoother.step_control(4);
+    smoother.ramp_over(4);
+    let mut ramp = Vec::new();
+    for _ in 0..4 {
+        smoother.tick();
+        ramp.push(smoother.current.get(gain_id));
+    }
+    assert_eq!(ramp, vec![0.25, 0.5, 0.75, 1.]);
+}
+
+#[test]
+fn control_rate_params_step_once_per_block() {
+    let size_id = ParamId::Global(GlobalParam::GrainSize);
+    let cutoff_id = ParamId::Voice(0, VoiceParam::FilterCutoff);
+    let defaults = ParamValues::default();
+    let mut smoother = ParamSmoother::new(44100.);
+    smoother.set_targets(&defaults);
+    let mut targets = defaults;
+    targets.set(size_id, 100.);
+    targets.set(cutoff_id, 100.);
+    smoother.set_targets(&targets);
+
+    // A block's worth of one-pole steps at once, then held while audio-rate params move
+    smoother.step_control(8);
+    smoother.ramp_over(8);
+    let mut expected = defaults.get(size_id);
+    for _ in 0..8 {
+        smooth(&mut expected, 100., smoothing_coefficient(25., 44100.));
+    }
+    let stepped = smoother.current.get(size_id);
+    assert!((stepped - expected).abs() < 1e-2);
+    assert_eq!(smoother.current.get(cutoff_id), defaults.get(cutoff_id));
+    smoother.tick();
+    assert_eq!(smoother.current.get(size_id), stepped);
+    assert_ne!(smoother.current.get(cutoff_id), defaults.get(cutoff_id));
+}
+
+#[test]
+fn cutoffs_sweep_in_octaves_across_the_block() {
+    let cutoff_id = ParamId::Voice(0, VoiceParam::FilterCutoff);
+    let mut smoother = ParamSmoother::new(44100.);
+    let mut targets = ParamValues::default();
+    targets.set(cutoff_id, 1000.);
+    smoother.set_targets(&targets);
+
+    // Without smoothing, lowpass to highpass through the bypassed band in the middle of the block
+    smoother.set_time_ms(cutoff_id, 0.);
+    smoother.set_target(cutoff_id, -1000.);
+    smoother.step_control(4);
+    smoother.ramp_over(4);
+    let mut ramp = Vec::new();
+    for _ in 0..4 {
+        smoother.tick();
+        ramp.push(smoother.current.get(cutoff_id));
+    }
+    assert!((ramp[0] - 108.4).abs() < 0.1, "{:?}", ramp);
+    assert!(ramp[1].abs() < 1e-3, "{:?}", ramp);
+    assert_eq!(ramp[3], -1000.);
+
+    // Smoothed, a rising sweep takes as long to cover each octave
+    smoother.set_time_ms(cutoff_id, 10.);
+    smoother.set_target(cutoff_id, 16000.);
+    smoother.step_control(128);
+    smoother.ramp_over(128);
+    smoother.tick();
+    let first = smoother.current.get(cutoff_id);
+    let linear_first = mix(smoothing_coefficient(10., 44100.), 16000., -1000.);
+    assert!(first < 0. && first > -1000., "{}", first);
+    assert!(first < linear_first);
+}
+
+#[test]
+fn detune_spread_offsets_voices_symmetrically() {
+    let mut values = ParamValues::default();
+    for voice_ix in 0..VOICE_COUNT {
+        values.set(ParamId::Voice(voice_ix, VoiceParam::SampleSpeedRatio), 2.);
+    }
+    assert_eq!(values.voice_speed_ratio(0), 2.);
+    values.set(ParamId::Global(GlobalParam::DetuneSpread), 1200.);
+    assert!((values.voice_speed_ratio(0) - 1.).abs() < 1e-6);
+    assert!((values.voice_speed_ratio(VOICE_COUNT - 1) - 4.).abs() < 1e-6);
+}
+
+#[test]
+fn solo_silences_other_voices_and_mute_wins() {
+    let mut values = ParamValues::default();
+    assert_eq!(values.voice_mute_solo_gain(0), 1.);
+    values.set(ParamId::Voice(1, VoiceParam::Solo), 1.);
+    assert_eq!(values.voice_mute_solo_gain(0), 0.);
+    assert_eq!(values.voice_mute_solo_gain(1), 1.);
+    values.set(ParamId::Voice(1, VoiceParam::Mute), 1.);
+    assert_eq!(values.voice_mute_solo_gain(1), 0.);
+}
+
+#[test]
+fn morphing_interpolates_continuous_params() {
+    let mut a = ParamValues::default();
+    let mut b = ParamValues::default();
+    let gain_id = ParamId::Voice(1, VoiceParam::Gain);
+    let mute_id = ParamId::Voice(1, VoiceParam::Mute);
+    a.set(gain_id, 1.);
+    b.set(gain_id, 0.5);
+    b.set(mute_id, 1.);
+    let mut morph = ConfigMorph { a, b, t: 0.25 };
+
+    let mut targets = ParamValues::default();
+    morph.apply(&mut targets);
+    assert_eq!(targets.get(gain_id), 0.875);
+    assert_eq!(targets.get(mute_id), 0.);
+    morph.t = 0.5;
+    morph.apply(&mut targets);
+    assert_eq!(targets.get(mute_id), 1.);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn granular_params_round_trip_through_json() {
+    let mut params = GranularParams::default();
+    assert!(params.set_voice(1, VoiceParam::Pan, 2.).is_err());
+    params.set_voice(1, VoiceParam::Pan, -0.5).unwrap();
+    params.set_global(GlobalParam::GrainSize, 1200.).unwrap();
+    let json = params.to_json();
+    assert!(json.starts_with("{\"global\":{\"detune_spread\":0.0,"));
+    assert_eq!(GranularParams::from_json(&json), Ok(params));
+
+    assert_eq!(
+        GranularParams::from_json("{\"voices\":[{\"gane\":1}]}"),
+        Err(ParamsJsonError::UnknownParam("gane".to_string()))
+    );
+    assert!(matches!(
+        GranularParams::from_json("{\"global\":{\"grain_size\":0}}"),
+        Err(ParamsJsonError::OutOfRange(_))
+    ));
+    assert_eq!(
+        GranularParams::from_json("{\"global\":{\"grain_size\":\"big\"}}"),
+        Err(ParamsJsonError::UnexpectedShape)
+    );
+    assert_eq!(
+        GranularParams::from_json("{\"global\":"),
+        Err(ParamsJsonError::Syntax)
+    );
+}
+
+#[test]
+fn metadata_lists_every_param() {
+    let json = metadata_json();
+    assert!(json.starts_with('[') && json.ends_with(']'));
+    assert_eq!(json.matches("\"id\":").count(), PARAM_COUNT);
+    assert!(json.contains("\"name\":\"voice_2_grain_start_randomness_samples\""));
+    assert_eq!(
+        json.matches("\"rate\":\"control\"").count(),
+        5 + 4 * VOICE_COUNT
+    );
+}
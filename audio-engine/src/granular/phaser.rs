@@ -0,0 +1,171 @@
+//! Phaser effect for voices and the master output.  Every channel has its own allpass chain, and
+//! the right channel's LFO runs a quarter of a cycle ahead of the left's so that the notches move
+//! across the stereo field.  The master output's mono mix follows the left channel.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::phaser::{Phaser, PhaserSettings, MAX_STAGES};
+
+#[derive(Clone, Copy)]
+pub struct PhaserEffect {
+    pub settings: PhaserSettings,
+    left: Phaser,
+    right: Phaser,
+    mono: Phaser,
+}
+
+impl PhaserEffect {
+    pub fn new(settings: PhaserSettings) -> Self {
+        PhaserEffect {
+            settings,
+            left: Phaser::default(),
+            right: Phaser::with_phase(0.25),
+            mono: Phaser::default(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+        self.mono.reset();
+    }
+
+    pub fn process_stereo(&mut self, (left, right): (f32, f32), sample_rate: f32) -> (f32, f32) {
+        (
+            self.left.process(left, &self.settings, sample_rate),
+            self.right.process(right, &self.settings, sample_rate),
+        )
+    }
+
+    pub fn process_mono(&mut self, sample: f32, sample_rate: f32) -> f32 {
+        self.mono.process(sample, &self.settings, sample_rate)
+    }
+}
+
+/// Settings of a phaser, with its lengths and amounts brought into range.  Returns `None` for
+/// non-finite values.
+fn phaser_settings(stages: u32, rate_hz: f32, depth: f32, feedback: f32) -> Option<PhaserSettings> {
+    if ![rate_hz, depth, feedback]
+        .iter()
+        .all(|value| value.is_finite())
+    {
+        return None;
+    }
+    Some(PhaserSettings {
+        stages: (stages as usize).clamp(1, MAX_STAGES),
+        rate_hz: clamp(0., 20., rate_hz),
+        depth: clamp(0., 1., depth),
+        feedback: clamp(-0.95, 0.95, feedback),
+    })
+}
+
+/// Feeds a voice's output through a phaser of 1 to 12 allpass `stages`, every two of which add a
+/// notch, swept `rate_hz` times a second over up to 2.5 octaves either side of 800 Hz by `depth`
+/// from 0 to 1.  `feedback` from -0.95 to 0.95 deepens the notches.  The phaser comes after the
+/// voice's comb filter and before its filter, and changing the settings of one that's on keeps its
+/// sweep going.
+pub fn set_voice_phaser(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    stages: u32,
+    rate_hz: f32,
+    depth: f32,
+    feedback: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT {
+        return;
+    }
+    let Some(settings) = phaser_settings(stages, rate_hz, depth, feedback) else {
+        return;
+    };
+    match &mut ctx.voices[voice_ix].phaser {
+        Some(phaser) => phaser.settings = settings,
+        phaser => *phaser = Some(PhaserEffect::new(settings)),
+    }
+}
+
+/// Removes a voice's phaser
+pub fn clear_voice_phaser(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.phaser = None;
+    }
+}
+
+/// Feeds the master output through a phaser like `set_voice_phaser`'s, after the stutter and
+/// before the limiter
+pub fn set_master_phaser(
+    ctx: *mut GranularCtx,
+    stages: u32,
+    rate_hz: f32,
+    depth: f32,
+    feedback: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let Some(settings) = phaser_settings(stages, rate_hz, depth, feedback) else {
+        return;
+    };
+    match &mut ctx.master_phaser {
+        Some(phaser) => phaser.settings = settings,
+        phaser => *phaser = Some(PhaserEffect::new(settings)),
+    }
+}
+
+/// Removes the master output's phaser
+pub fn clear_master_phaser(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.master_phaser = None;
+    }
+}
+
+#[test]
+fn phasers_notch_voices_and_the_master_output() {
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+    use super::DEFAULT_SAMPLE_RATE;
+
+    let period = DEFAULT_SAMPLE_RATE / 800.;
+    let mut ctx = GranularCtx {
+        waveform: (0..44100)
+            .map(|i| (std::f32::consts::TAU * i as f32 / period).sin())
+            .collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::GrainSize), 8192.);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    let targets = *ctx.params.target();
+    let level = |ctx: &mut GranularCtx| {
+        let mut sum = 0.;
+        for frame_ix in 0..128 {
+            ctx.render(&targets);
+            if frame_ix >= 64 {
+                sum += ctx.rendered_output.iter().map(|s| s * s).sum::<f32>();
+            }
+        }
+        sum
+    };
+    let dry = level(&mut ctx);
+    // A standing notch at 800 Hz
+    set_master_phaser(&mut ctx, 2, 0., 0., 0.);
+    assert!(level(&mut ctx) < dry * 0.1);
+    clear_master_phaser(&mut ctx);
+    assert!(ctx.master_phaser.is_none());
+
+    set_voice_phaser(&mut ctx, 0, 2, 0., 0., 0.);
+    assert!(level(&mut ctx) < dry * 0.1);
+    set_voice_phaser(&mut ctx, 0, 4, 0., 0., f32::NAN);
+    assert_eq!(ctx.voices[0].phaser.unwrap().settings.stages, 2);
+    clear_voice_phaser(&mut ctx, 0);
+    assert!(ctx.voices[0].phaser.is_none());
+}
@@ -0,0 +1,195 @@
+//! Clip-style playback control.  Out of the box the engine granulates all the time with the read
+//! heads moving freely through the selection.  A host that wants it to behave like a clip on a
+//! timeline can stop, start and seek the read heads instead, and every change waits for the start
+//! of the next frame, so "start granulating from this sample" can be queued ahead of the render it
+//! should land on.  Stopping only stops new grains, so grains already playing ring out.
+
+use super::{ctx_mut, GranularCtx};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PlaybackState {
+    /// Granulating without any playback control
+    #[default]
+    FreeRunning,
+    Playing,
+    Stopped,
+}
+
+impl PlaybackState {
+    pub fn index(self) -> u32 {
+        match self {
+            PlaybackState::FreeRunning => 0,
+            PlaybackState::Playing => 1,
+            PlaybackState::Stopped => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlaybackChange {
+    /// Starts granulating, from a sample index or else from where the read heads are
+    Play {
+        from: Option<f32>,
+    },
+    Stop,
+    /// Moves the read heads to a sample index without starting or stopping
+    Seek(f32),
+    /// Goes back to granulating without playback control
+    Release,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Playback {
+    pub state: PlaybackState,
+    pending: Option<PlaybackChange>,
+}
+
+impl Playback {
+    /// Queues a change for the next frame.  A play and a seek together start from where the seek
+    /// goes, and otherwise the latest change wins.
+    pub fn queue(&mut self, change: PlaybackChange) {
+        self.pending = Some(match (self.pending, change) {
+            (Some(PlaybackChange::Play { .. }), PlaybackChange::Seek(from))
+            | (Some(PlaybackChange::Seek(from)), PlaybackChange::Play { from: None }) => {
+                PlaybackChange::Play { from: Some(from) }
+            }
+            (_, change) => change,
+        });
+    }
+
+    pub fn has_pending_change(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Takes the change queued for the frame about to be rendered and moves to its state.
+    /// Returns the sample index the read heads should jump to, if any.
+    pub fn begin_frame(&mut self) -> Option<f32> {
+        let (state, jump) = match self.pending.take()? {
+            PlaybackChange::Play { from } => (PlaybackState::Playing, from),
+            PlaybackChange::Stop => (PlaybackState::Stopped, None),
+            PlaybackChange::Seek(to) => (self.state, Some(to)),
+            PlaybackChange::Release => (PlaybackState::FreeRunning, None),
+        };
+        self.state = state;
+        jump
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state == PlaybackState::Stopped
+    }
+}
+
+/// Starts granulating at the start of the next frame, from `from_sample_ix` in the waveform or,
+/// when it's negative, from where the read heads are, after which the read heads move as usual
+pub fn playback_start(ctx: *mut GranularCtx, from_sample_ix: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let from = (from_sample_ix.is_finite() && from_sample_ix >= 0.).then_some(from_sample_ix);
+    ctx.playback.queue(PlaybackChange::Play { from });
+}
+
+/// Stops spawning grains at the start of the next frame, letting the ones playing ring out
+pub fn playback_stop(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.playback.queue(PlaybackChange::Stop);
+    }
+}
+
+/// Moves the read heads to `sample_ix` at the start of the next frame, playing or not
+pub fn playback_seek(ctx: *mut GranularCtx, sample_ix: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if sample_ix.is_finite() {
+        ctx.playback.queue(PlaybackChange::Seek(sample_ix.max(0.)));
+    }
+}
+
+/// Goes back to granulating all the time, without playback control, from the next frame
+pub fn release_playback(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.playback.queue(PlaybackChange::Release);
+    }
+}
+
+/// Returns 0 when granulating without playback control, 1 when playing and 2 when stopped
+pub fn get_playback_state(ctx: *mut GranularCtx) -> u32 {
+    ctx_mut(ctx).map_or(0, |ctx| ctx.playback.state.index())
+}
+
+/// Returns the sample index in the waveform of a voice's read head, or 0 for an unknown voice
+pub fn get_playhead(ctx: *mut GranularCtx, voice_ix: usize) -> f32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return 0.;
+    };
+    ctx.voices
+        .get(voice_ix)
+        .map_or(0., |voice| voice.cur_grain_start)
+}
+
+#[test]
+fn changes_wait_for_the_next_frame() {
+    let mut playback = Playback::default();
+    playback.queue(PlaybackChange::Stop);
+    assert_eq!(playback.state, PlaybackState::FreeRunning);
+    assert_eq!(playback.begin_frame(), None);
+    assert!(playback.is_stopped());
+
+    playback.queue(PlaybackChange::Play { from: None });
+    playback.queue(PlaybackChange::Seek(100.));
+    assert_eq!(playback.begin_frame(), Some(100.));
+    assert_eq!(playback.state, PlaybackState::Playing);
+    playback.queue(PlaybackChange::Seek(50.));
+    playback.queue(PlaybackChange::Play { from: None });
+    assert_eq!(playback.begin_frame(), Some(50.));
+    assert_eq!(playback.begin_frame(), None);
+
+    // Seeking doesn't start or stop
+    playback.queue(PlaybackChange::Stop);
+    playback.begin_frame();
+    playback.queue(PlaybackChange::Seek(5.));
+    assert_eq!(playback.begin_frame(), Some(5.));
+    assert!(playback.is_stopped());
+}
+
+#[test]
+fn playback_stops_and_starts_on_frame_boundaries() {
+    use super::params::{GlobalParam, ParamId, VOICE_COUNT};
+    use super::FRAME_SIZE;
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 44100],
+        ..Default::default()
+    };
+    ctx.seed(1);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    let targets = *ctx.params.target();
+    for _ in 0..8 {
+        ctx.render(&targets);
+    }
+    playback_stop(&mut ctx);
+    assert_eq!(get_playback_state(&mut ctx), 0);
+    // Long enough for every grain to end
+    for _ in 0..200 {
+        ctx.render(&targets);
+    }
+    assert_eq!(get_playback_state(&mut ctx), 2);
+    assert!(ctx.voices.iter().all(|voice| voice.grains.is_empty()));
+    assert!(ctx.rendered_output.iter().all(|&sample| sample == 0.));
+
+    playback_seek(&mut ctx, 20000.);
+    playback_start(&mut ctx, -1.);
+    ctx.render(&targets);
+    assert_eq!(get_playback_state(&mut ctx), 1);
+    assert!(ctx.rendered_output.iter().any(|&sample| sample != 0.));
+    for voice_ix in 0..VOICE_COUNT {
+        let playhead = get_playhead(&mut ctx, voice_ix);
+        assert!(
+            (20000. ..20000. + FRAME_SIZE as f32).contains(&playhead),
+            "{}",
+            playhead
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Opt-in render profiling.  While enabled, every render is timed as a whole and split into three
+//! stages: control (parameter targets, macros, notes and other per-frame setup), synthesis
+//! (per-sample parameter and modulation updates, grain scheduling and playback, filtering and
+//! mixing of the voices) and output (limiting, metering).  Synthesis and output are interleaved
+//! per sample, so measuring them costs two clock reads per sample; that's why profiling is off by
+//! default.
+
+use super::{ctx_mut, GranularCtx};
+use crate::common;
+
+/// Weight of the newest render in the running average
+const AVERAGE_WEIGHT: f32 = 0.05;
+
+/// Indices into `Profiler::values`
+pub const PROFILE_LAST_MS: usize = 0;
+pub const PROFILE_AVERAGE_MS: usize = 1;
+pub const PROFILE_MAX_MS: usize = 2;
+pub const PROFILE_CONTROL_MS: usize = 3;
+pub const PROFILE_SYNTHESIS_MS: usize = 4;
+pub const PROFILE_OUTPUT_MS: usize = 5;
+pub const PROFILE_VALUE_COUNT: usize = 6;
+
+#[derive(Clone, Copy, Default)]
+pub struct Profiler {
+    pub enabled: bool,
+    /// Timings of the last render and running statistics, all in milliseconds
+    pub values: [f32; PROFILE_VALUE_COUNT],
+    render_count: u64,
+    render_start: f64,
+    control_end: f64,
+    synthesis_ms: f64,
+    output_ms: f64,
+}
+
+impl Profiler {
+    #[inline]
+    fn now(&self) -> f64 {
+        if self.enabled {
+            common::now_ms()
+        } else {
+            0.
+        }
+    }
+
+    pub fn begin_render(&mut self) {
+        self.render_start = self.now();
+        self.synthesis_ms = 0.;
+        self.output_ms = 0.;
+    }
+
+    pub fn end_control(&mut self) {
+        self.control_end = self.now();
+    }
+
+    /// Returns a timestamp to pass to `end_synthesis`
+    #[inline]
+    pub fn begin_synthesis(&self) -> f64 {
+        self.now()
+    }
+
+    /// Returns a timestamp to pass to `end_output`
+    #[inline]
+    pub fn end_synthesis(&mut self, start: f64) -> f64 {
+        let now = self.now();
+        self.synthesis_ms += now - start;
+        now
+    }
+
+    #[inline]
+    pub fn end_output(&mut self, start: f64) {
+        self.output_ms += self.now() - start;
+    }
+
+    pub fn end_render(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        let total_ms = (self.now() - self.render_start) as f32;
+        self.values[PROFILE_LAST_MS] = total_ms;
+        self.values[PROFILE_AVERAGE_MS] = if self.render_count == 0 {
+            total_ms
+        } else {
+            self.values[PROFILE_AVERAGE_MS]
+                + (total_ms - self.values[PROFILE_AVERAGE_MS]) * AVERAGE_WEIGHT
+        };
+        self.values[PROFILE_MAX_MS] = self.values[PROFILE_MAX_MS].max(total_ms);
+        self.values[PROFILE_CONTROL_MS] = (self.control_end - self.render_start).max(0.) as f32;
+        self.values[PROFILE_SYNTHESIS_MS] = self.synthesis_ms as f32;
+        self.values[PROFILE_OUTPUT_MS] = self.output_ms as f32;
+        self.render_count += 1;
+    }
+
+    pub fn reset(&mut self) {
+        *self = Profiler {
+            enabled: self.enabled,
+            ..Default::default()
+        };
+    }
+}
+
+/// Turns render profiling on or off; see `Profiler`
+pub fn set_profiling(ctx: *mut GranularCtx, enabled: bool) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.profiler.enabled = enabled;
+        ctx.profiler.reset();
+    }
+}
+
+/// Returns a pointer to the `PROFILE_VALUE_COUNT` timings of the profiler in milliseconds
+pub fn get_profile(ctx: *mut GranularCtx) -> *const f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.profiler.values.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+#[test]
+fn only_enabled_profiling_fills_in_the_timings() {
+    use super::test_targets;
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 44100],
+        ..Default::default()
+    };
+    let targets = test_targets(44099.);
+    let profile = |ctx: &mut GranularCtx| {
+        let ptr = get_profile(ctx);
+        unsafe { std::slice::from_raw_parts(ptr, PROFILE_VALUE_COUNT) }.to_vec()
+    };
+    for _ in 0..4 {
+        ctx.render(&targets);
+    }
+    assert_eq!(profile(&mut ctx), [0.; PROFILE_VALUE_COUNT]);
+
+    set_profiling(&mut ctx, true);
+    for _ in 0..4 {
+        ctx.render(&targets);
+    }
+    let values = profile(&mut ctx);
+    assert!(values.iter().all(|ms| *ms > 0.), "{:?}", values);
+    let stages_ms =
+        values[PROFILE_CONTROL_MS] + values[PROFILE_SYNTHESIS_MS] + values[PROFILE_OUTPUT_MS];
+    assert!(stages_ms <= values[PROFILE_LAST_MS] * 1.01, "{:?}", values);
+    assert!(values[PROFILE_MAX_MS] >= values[PROFILE_LAST_MS]);
+
+    set_profiling(&mut ctx, false);
+    ctx.render(&targets);
+    assert_eq!(profile(&mut ctx), [0.; PROFILE_VALUE_COUNT]);
+}
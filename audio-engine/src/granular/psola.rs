@@ -0,0 +1,198 @@
+//! Pitch-synchronous granulation (PSOLA).  A voice in PSOLA mode cuts grains of two source
+//! periods centred on the pitch marks of the waveform, one per period, and plays them back at
+//! their original speed but spaced one period of the target pitch apart.  The overlap-added
+//! grains repeat at the target pitch while keeping the formants of the source, which shifts the
+//! pitch of monophonic material far more cleanly than reading grains faster or slower.
+//!
+//! The pitch of the waveform comes from a pitch track analyzed ahead of time.  Where the source
+//! is unpitched or the track is missing, the voice granulates as usual.
+
+use super::params::VOICE_COUNT;
+use super::tuning::Tuning;
+use super::waveform::ChannelSamples;
+use super::wavetable::ROOT_FREQUENCY_HZ;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::pitch::estimate_pitch;
+
+/// Pitch of every `window_len` samples of the waveform
+#[derive(Clone, Default)]
+pub struct PitchTrack {
+    pub window_len: usize,
+    /// Period in samples of each window, `None` where it's unpitched
+    pub periods: Vec<Option<f32>>,
+}
+
+impl PitchTrack {
+    pub fn analyze(samples: &[f32], window_len: usize, sample_rate: f32) -> Self {
+        let window_len = window_len.max(1);
+        PitchTrack {
+            window_len,
+            periods: samples
+                .chunks(window_len)
+                .map(|window| estimate_pitch(window, sample_rate).map(|hz| sample_rate / hz))
+                .collect(),
+        }
+    }
+
+    /// Period of the source at sample `position`
+    pub fn period_at(&self, position: f32) -> Option<f32> {
+        if position.is_nan() || position < 0. {
+            return None;
+        }
+        let window_ix = position as usize / self.window_len.max(1);
+        self.periods.get(window_ix).copied().flatten()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Psola {
+    /// Fixed output pitch.  Without one the source pitch is transposed by the voice's sample speed,
+    /// so note mode and detune work as usual.
+    pub target_hz: Option<f32>,
+    /// Snaps the output pitch to the nearest note, for pitch correction
+    pub snap_to_notes: bool,
+}
+
+impl Psola {
+    /// Output pitch for a source pitch of `source_hz` on a voice playing at `speed_ratio`.  Notes
+    /// to snap to are the degrees of `tuning` if there is one, and equal-tempered otherwise.
+    pub fn output_hz(&self, source_hz: f32, speed_ratio: f32, tuning: Option<&Tuning>) -> f32 {
+        let hz = self.target_hz.unwrap_or(source_hz * speed_ratio);
+        if self.snap_to_notes {
+            let semitones = 12. * (hz / ROOT_FREQUENCY_HZ).log2();
+            let semitones = tuning.map_or(semitones.round(), |tuning| tuning.nearest(semitones));
+            ROOT_FREQUENCY_HZ * (semitones / 12.).exp2()
+        } else {
+            hz
+        }
+    }
+}
+
+/// Grain planned for a voice in PSOLA mode, replacing the size and start it would otherwise have
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PsolaGrain {
+    pub start: f32,
+    pub len: f32,
+}
+
+/// Start of the grain for the period of the source starting at `position`: the peak within that
+/// period marks its centre
+pub fn grain_start(samples: ChannelSamples, position: f32, period: f32) -> f32 {
+    let start = position.max(0.) as usize;
+    let end = (position.max(0.) + period).min(samples.len() as f32) as usize;
+    let peak_ix = (start..end)
+        .max_by(|&a, &b| samples.at(a).abs().total_cmp(&samples.at(b).abs()))
+        .unwrap_or(start);
+    peak_ix as f32 - period
+}
+
+/// Switches a voice to pitch-synchronous granulation, which cuts grains at the pitch marks of the
+/// waveform and respaces them to shift its pitch.  A `target_hz` above 0 plays every pitched part
+/// at that pitch; otherwise the source pitch is transposed by the voice's sample speed.
+/// `snap_to_notes` rounds the output pitch to the nearest equal-tempered note.  Has no effect
+/// until `analyze_pitch` has been called, and none on unpitched parts of the waveform.
+pub fn set_voice_psola(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    target_hz: f32,
+    snap_to_notes: bool,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !target_hz.is_finite() {
+        return;
+    }
+    ctx.voices[voice_ix].psola = Some(Psola {
+        target_hz: (target_hz > 0.).then(|| clamp(20., 5000., target_hz)),
+        snap_to_notes,
+    });
+}
+
+/// Switches a voice back from pitch-synchronous to free granulation
+pub fn clear_voice_psola(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.psola = None;
+        voice.psola_grain = None;
+    }
+}
+
+#[test]
+fn grains_are_cut_at_pitch_marks_and_retuned() {
+    // A 200 Hz pulse train at 48 kHz, peaking every 240 samples
+    let samples: Vec<f32> = (0..4096)
+        .map(|i| if i % 240 == 30 { 1. } else { 0.02 })
+        .collect();
+    let track = PitchTrack::analyze(&samples, 2048, 48000.);
+    let period = track.period_at(1000.).unwrap();
+    assert!((period - 240.).abs() < 1., "{}", period);
+    assert_eq!(track.period_at(5000.), None);
+    assert_eq!(
+        grain_start(samples[..].into(), 1000., period).round(),
+        1230. - 240.
+    );
+
+    let transpose = Psola {
+        target_hz: None,
+        snap_to_notes: false,
+    };
+    assert_eq!(transpose.output_hz(200., 2., None), 400.);
+    let correct = Psola {
+        target_hz: Some(445.),
+        snap_to_notes: true,
+    };
+    // Snapped to A4
+    assert!((correct.output_hz(200., 1., None) - 440.).abs() < 0.01);
+}
+
+#[test]
+fn psola_voices_shift_the_pitch_by_respacing_grains() {
+    use super::analysis::analyze_pitch;
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+    use super::DEFAULT_SAMPLE_RATE;
+
+    // A 200 Hz tone with a peak every period
+    let period = DEFAULT_SAMPLE_RATE / 200.;
+    let mut ctx = GranularCtx {
+        waveform: (0..22050)
+            .map(|i| {
+                let phase = std::f32::consts::TAU * i as f32 / period;
+                phase.sin() + 0.5 * (2. * phase).sin()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 22049.);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    ctx.params
+        .set_target(ParamId::Voice(0, VoiceParam::SampleSpeedRatio), 1.5);
+    let pitches = analyze_pitch(&mut ctx, 50.);
+    assert!(pitches.iter().all(|&hz| (hz - 200.).abs() < 2.));
+    set_voice_psola(&mut ctx, 0, 0., false);
+    let targets = *ctx.params.target();
+    let mut output = Vec::new();
+    for _ in 0..64 {
+        ctx.render(&targets);
+        output.extend_from_slice(&ctx.rendered_output);
+    }
+    // Grains play at the source's speed but repeat a fifth up, once the speed has been smoothed
+    let output = &output[4096..];
+    assert!(ctx.voices[0]
+        .grains
+        .iter()
+        .all(|grain| grain.sample_playback_ratio == 1.));
+    let correlation =
+        |lag: usize| -> f32 { output[lag..].iter().zip(output).map(|(a, b)| a * b).sum() };
+    let best_lag = (100..200).max_by(|&a, &b| correlation(a).total_cmp(&correlation(b)));
+    assert!((best_lag.unwrap() as f32 - period / 1.5).abs() <= 3.);
+    assert!(correlation(period.round() as usize) < 0.);
+
+    clear_voice_psola(&mut ctx, 0);
+    assert!(ctx.voices[0].psola.is_none());
+}
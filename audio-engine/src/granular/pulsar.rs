@@ -0,0 +1,128 @@
+//! Pulsar synthesis.  A voice in pulsar mode plays a train of pulsars at a fundamental rate
+//! instead of granulating freely: each pulsar is a pulsaret, a grain read from the waveform and
+//! shaped by the voice's grain envelope, followed by silence for the rest of its period.  The duty
+//! cycle sets how much of the period the pulsaret fills and its pitch sets how fast it reads the
+//! waveform, independently of each other, so the fundamental and the formant of the train move
+//! separately.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pulsar {
+    /// Pulsars per second
+    pub fundamental_hz: f32,
+    /// Fraction of each period filled by the pulsaret, from 0 to 1
+    pub duty_cycle: f32,
+    /// Transposition of the pulsaret in semitones, on top of the voice's playback speed
+    pub pulsaret_semitones: f32,
+}
+
+impl Pulsar {
+    pub fn period_samples(&self, sample_rate: f32) -> f32 {
+        sample_rate / self.fundamental_hz
+    }
+
+    /// Length of the pulsaret in waveform samples when it's read at `playback_ratio` and its
+    /// pulsar lasts `period_samples` output samples
+    pub fn pulsaret_len(&self, period_samples: f32, playback_ratio: f32) -> f32 {
+        self.duty_cycle * period_samples * playback_ratio
+    }
+
+    pub fn pitch_ratio(&self) -> f32 {
+        (self.pulsaret_semitones / 12.).exp2()
+    }
+}
+
+/// Switches a voice to pulsar synthesis, playing `fundamental_hz` pulsars a second whose
+/// pulsarets fill `duty_cycle` of each period and are transposed by `pulsaret_semitones`.  The
+/// pulsar train takes precedence over grain sync.
+pub fn set_voice_pulsar(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    fundamental_hz: f32,
+    duty_cycle: f32,
+    pulsaret_semitones: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT
+        || !fundamental_hz.is_finite()
+        || fundamental_hz <= 0.
+        || !duty_cycle.is_finite()
+        || !pulsaret_semitones.is_finite()
+    {
+        return;
+    }
+    ctx.voices[voice_ix].pulsar = Some(Pulsar {
+        // At least one sample per period
+        fundamental_hz: fundamental_hz.min(ctx.sample_rate),
+        duty_cycle: clamp(0., 1., duty_cycle),
+        pulsaret_semitones: clamp(-48., 48., pulsaret_semitones),
+    });
+}
+
+/// Switches a voice back from pulsar synthesis to granulating
+pub fn clear_voice_pulsar(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.pulsar = None;
+    }
+}
+
+#[test]
+fn pulsaret_length_follows_the_duty_cycle_at_any_pitch() {
+    let pulsar = Pulsar {
+        fundamental_hz: 100.,
+        duty_cycle: 0.25,
+        pulsaret_semitones: 12.,
+    };
+    let period = pulsar.period_samples(48000.);
+    assert_eq!(period, 480.);
+    // Read twice as fast, the pulsaret covers twice as many waveform samples in the same time
+    let playback_ratio = pulsar.pitch_ratio();
+    assert_eq!(playback_ratio, 2.);
+    assert_eq!(
+        pulsar.pulsaret_len(period, playback_ratio) / playback_ratio,
+        120.
+    );
+}
+
+#[test]
+fn pulsar_voices_play_pulsarets_separated_by_silence() {
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| (i as f32 * 0.01).sin()).collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Voice(0, VoiceParam::Gain), 1.);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    // One pulsar every 100 samples, with a pulsaret of 25 samples read at twice the speed
+    set_voice_pulsar(&mut ctx, 0, 441., 0.25, 12.);
+    set_voice_pulsar(&mut ctx, 0, f32::NAN, 0.5, 0.);
+    let targets = *ctx.params.target();
+    let mut output = Vec::new();
+    for _ in 0..8 {
+        ctx.render(&targets);
+        output.extend_from_slice(&ctx.rendered_output);
+        for grain in &ctx.voices[0].grains {
+            assert!((grain.sample_playback_ratio - 2.).abs() < 1e-3);
+            assert!((grain.len_samples / grain.sample_playback_ratio - 25.).abs() < 0.1);
+        }
+    }
+    let steady = &output[200..];
+    let silent = steady.iter().filter(|sample| **sample == 0.).count();
+    assert!((0.7..0.8).contains(&(silent as f32 / steady.len() as f32)));
+
+    clear_voice_pulsar(&mut ctx, 0);
+    assert!(ctx.voices[0].pulsar.is_none());
+}
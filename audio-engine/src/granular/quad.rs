@@ -0,0 +1,207 @@
+//! Output layouts.  The engine renders mono and stereo output every frame, and for multichannel
+//! destinations like installations it can also render quad: front left, front right, rear left
+//! and rear right.  Every grain has a depth between the front and rear pairs, picked when it
+//! spawns, on top of the left to right position of its voice.  The master effects run on the
+//! stereo mix, so the quad output is that mix split between the two pairs by how much of each
+//! side is coming from grains at the front and at the back.
+
+use rand::Rng;
+
+use super::pan_gains;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::dynamics::EnvelopeFollower;
+
+/// Attack and release of the levels that the split follows
+const SPLIT_ATTACK_MS: f32 = 1.;
+const SPLIT_RELEASE_MS: f32 = 20.;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum OutputLayout {
+    #[default]
+    Mono,
+    Stereo,
+    Quad,
+}
+
+impl OutputLayout {
+    pub fn from_channels(channels: u32) -> Option<Self> {
+        match channels {
+            1 => Some(OutputLayout::Mono),
+            2 => Some(OutputLayout::Stereo),
+            4 => Some(OutputLayout::Quad),
+            _ => None,
+        }
+    }
+
+    pub fn channels(self) -> u32 {
+        match self {
+            OutputLayout::Mono => 1,
+            OutputLayout::Stereo => 2,
+            OutputLayout::Quad => 4,
+        }
+    }
+}
+
+/// Where a voice's grains go between the front and rear pairs
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct QuadPosition {
+    /// From -1, only the front pair, through 0, both, to 1, only the rear pair
+    pub depth: f32,
+    /// How far each grain's depth is randomized around `depth`, from 0 to 2
+    pub spread: f32,
+}
+
+impl QuadPosition {
+    /// Depth of a new grain.  Draws nothing from `rng` without a spread, so that voices that
+    /// don't use quad output get the same grains as before it existed.
+    pub fn pick(&self, rng: &mut impl Rng) -> f32 {
+        if self.spread == 0. {
+            return self.depth;
+        }
+        (self.depth + rng.gen_range(-self.spread..=self.spread)).clamp(-1., 1.)
+    }
+}
+
+/// Splits the stereo master output between the front and rear pairs
+#[derive(Clone)]
+pub struct QuadSplit {
+    /// Levels of the front left, front right, rear left and rear right parts of the mix
+    followers: [EnvelopeFollower; 4],
+}
+
+impl QuadSplit {
+    pub fn new(sample_rate: f32) -> Self {
+        let follower = EnvelopeFollower::new(SPLIT_ATTACK_MS, SPLIT_RELEASE_MS, sample_rate);
+        QuadSplit {
+            followers: [
+                follower.clone(),
+                follower.clone(),
+                follower.clone(),
+                follower,
+            ],
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        for follower in &mut self.followers {
+            follower.set_sample_rate(sample_rate);
+        }
+    }
+
+    /// Takes the front left, front right, rear left and rear right parts of the mix before the
+    /// master effects, and returns the four channels of the master output
+    pub fn split(&mut self, parts: [f32; 4], left: f32, right: f32) -> [f32; 4] {
+        let levels: [f32; 4] = std::array::from_fn(|ix| self.followers[ix].process(parts[ix]));
+        let gains = |front: f32, rear: f32| {
+            let balance = if front + rear > 1e-9 {
+                (rear - front) / (rear + front)
+            } else {
+                0.
+            };
+            pan_gains(balance)
+        };
+        let (front_left_gain, rear_left_gain) = gains(levels[0], levels[2]);
+        let (front_right_gain, rear_right_gain) = gains(levels[1], levels[3]);
+        [
+            left * front_left_gain,
+            right * front_right_gain,
+            left * rear_left_gain,
+            right * rear_right_gain,
+        ]
+    }
+}
+
+/// Returns a pointer to the planar quad output of the last rendered frame: 128 samples each of
+/// front left, front right, rear left and rear right.  It's only rendered while the layout is quad.
+pub fn get_quad_output_ptr(ctx: *mut GranularCtx) -> *const f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.rendered_output_quad.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// Places a voice's grains between the front (`depth` -1) and rear (1) pairs of quad output,
+/// with each new grain's depth randomized by up to `spread` (0 to 2) either way
+pub fn set_voice_quad_position(ctx: *mut GranularCtx, voice_ix: usize, depth: f32, spread: f32) {
+    let Some(voice) = ctx_mut(ctx).and_then(|ctx| ctx.voices.get_mut(voice_ix)) else {
+        return;
+    };
+    if !depth.is_finite() || !spread.is_finite() {
+        return;
+    }
+    voice.quad = QuadPosition {
+        depth: clamp(-1., 1., depth),
+        spread: clamp(0., 2., spread),
+    };
+}
+
+#[test]
+fn split_follows_where_the_mix_comes_from() {
+    assert_eq!(OutputLayout::from_channels(4), Some(OutputLayout::Quad));
+    assert_eq!(OutputLayout::from_channels(3), None);
+
+    let mut split = QuadSplit::new(48000.);
+    // Only the left side from the front, and the right side from both pairs
+    for _ in 0..4800 {
+        split.split([1., 0.5, 0., 0.5], 1., 1.);
+    }
+    let channels = split.split([1., 0.5, 0., 0.5], 1., 1.);
+    assert_eq!(channels, [1., 1., 0., 1.]);
+
+    let position = QuadPosition {
+        depth: 0.5,
+        spread: 0.,
+    };
+    let mut rng = crate::common::rng();
+    assert_eq!(position.pick(&mut rng), 0.5);
+}
+
+#[test]
+fn quad_output_follows_grain_depth() {
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+    use super::{configure_output, get_output_channels, FRAME_SIZE};
+
+    let mut ctx = GranularCtx {
+        waveform: (0..4410).map(|i| (i as f32 * 0.05).sin()).collect(),
+        ..Default::default()
+    };
+    ctx.seed(1);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4409.);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::GrainSize), 1024.);
+    let targets = *ctx.params.target();
+    assert!(!configure_output(&mut ctx, 3));
+    assert!(configure_output(&mut ctx, 4));
+    assert_eq!(get_output_channels(&mut ctx), 4);
+    let pair_levels = |ctx: &mut GranularCtx| {
+        let (mut front, mut rear) = (0f32, 0f32);
+        for frame_ix in 0..96 {
+            ctx.render(&targets);
+            if frame_ix >= 64 {
+                let (front_pair, rear_pair) = ctx.rendered_output_quad.split_at(FRAME_SIZE * 2);
+                front = front_pair.iter().fold(front, |max, s| max.max(s.abs()));
+                rear = rear_pair.iter().fold(rear, |max, s| max.max(s.abs()));
+            }
+        }
+        (front, rear)
+    };
+    set_voice_quad_position(&mut ctx, 0, -1., 0.);
+    let (front, rear) = pair_levels(&mut ctx);
+    assert!(front > 0.1 && rear < 1e-3, "{} {}", front, rear);
+    assert_eq!(
+        ctx.rendered_output_quad[..FRAME_SIZE * 2],
+        ctx.rendered_output_stereo
+    );
+    set_voice_quad_position(&mut ctx, 0, 1., 0.);
+    let (front, rear) = pair_levels(&mut ctx);
+    assert!(rear > 0.1 && front < 1e-3, "{} {}", front, rear);
+    // Spread grains reach both pairs
+    set_voice_quad_position(&mut ctx, 0, 0., 2.);
+    let (front, rear) = pair_levels(&mut ctx);
+    assert!(rear > 0.1 && front > 0.1, "{} {}", front, rear);
+    assert_eq!(ctx.output_ptr(), ctx.rendered_output_quad.as_ptr());
+}
@@ -0,0 +1,98 @@
+//! Random value pools.  Each voice draws its spawn decisions, start offsets, transpositions and
+//! other random choices from a pool of values generated ahead of time, topped up once per
+//! processing chunk, so the sample loop only takes the next word instead of running the
+//! generator.  A pool hands out exactly the words its generator would have in the same order, so
+//! seeded renders come out the same as drawing from the generator directly.  A chunk that draws
+//! more than the pool holds runs the generator for the rest.
+
+use std::collections::VecDeque;
+
+use rand::rngs::StdRng;
+use rand::{Error, RngCore};
+
+/// Words a pool holds, enough for the draws of hundreds of grain spawns
+pub const RANDOM_POOL_LEN: usize = 1024;
+
+#[derive(Clone)]
+pub struct RandomPool {
+    rng: StdRng,
+    values: VecDeque<u32>,
+}
+
+impl RandomPool {
+    pub fn new(rng: StdRng) -> Self {
+        let mut pool = RandomPool {
+            rng,
+            values: VecDeque::with_capacity(RANDOM_POOL_LEN),
+        };
+        pool.refill();
+        pool
+    }
+
+    /// Replaces the generator, dropping the values drawn from the old one
+    pub fn reseed(&mut self, rng: StdRng) {
+        self.rng = rng;
+        self.values.clear();
+        self.refill();
+    }
+
+    /// Generates values until the pool is full
+    pub fn refill(&mut self) {
+        while self.values.len() < RANDOM_POOL_LEN {
+            self.values.push_back(self.rng.next_u32());
+        }
+    }
+}
+
+impl RngCore for RandomPool {
+    #[inline]
+    fn next_u32(&mut self) -> u32 {
+        match self.values.pop_front() {
+            Some(value) => value,
+            None => self.rng.next_u32(),
+        }
+    }
+
+    /// The low word first, like the block generators `StdRng` is built on
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        let low = self.next_u32();
+        u64::from(self.next_u32()) << 32 | u64::from(low)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[test]
+fn pools_draw_what_their_generator_would() {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut pool = RandomPool::new(StdRng::seed_from_u64(5));
+    // Past the end of the pool, and across a refill
+    for ix in 0..RANDOM_POOL_LEN * 3 {
+        if ix == RANDOM_POOL_LEN * 2 {
+            pool.refill();
+        }
+        assert_eq!(pool.gen_range(0.0..1.0f32), rng.gen_range(0.0..1.0f32));
+        assert_eq!(pool.gen::<u64>(), rng.gen::<u64>());
+    }
+    let (mut from_pool, mut from_rng) = ([0; 7], [0; 7]);
+    pool.fill_bytes(&mut from_pool);
+    rng.fill_bytes(&mut from_rng);
+    assert_eq!(from_pool, from_rng);
+
+    pool.reseed(StdRng::seed_from_u64(6));
+    let mut rng = StdRng::seed_from_u64(6);
+    assert_eq!(pool.next_u32(), rng.next_u32());
+}
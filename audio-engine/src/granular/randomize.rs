@@ -0,0 +1,266 @@
+//! Constrained parameter randomizer, for throwing a texture somewhere new without landing on
+//! settings that are silent or unusable.  Every parameter it can move has a musically sane range
+//! and belongs to a group, and the host picks the groups to randomize and how far to move from
+//! the current settings towards the random ones.  Locked parameters are left alone, which the
+//! levels are until the host unlocks them.  The selection and the on/off parameters are never
+//! randomized.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use super::params::{GlobalParam, ParamId, ParamValues, VoiceParam, PARAM_COUNT};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::mix;
+
+/// Grain size, spacing, start randomness and skip probability
+pub const GROUP_GRAINS: u32 = 1;
+/// Playback speed and detune
+pub const GROUP_PITCH: u32 = 1 << 1;
+/// Envelope slopes, skew and morph
+pub const GROUP_ENVELOPE: u32 = 1 << 2;
+pub const GROUP_FILTER: u32 = 1 << 3;
+/// Pan and delay send
+pub const GROUP_SPACE: u32 = 1 << 4;
+/// Read head movement
+pub const GROUP_MOVEMENT: u32 = 1 << 5;
+/// Voice, master and dry gains, locked by default
+pub const GROUP_LEVELS: u32 = 1 << 6;
+
+/// Where a parameter is randomized within
+#[derive(Clone, Copy)]
+struct RandomRange {
+    group: u32,
+    min: f32,
+    max: f32,
+    /// Picked and moved towards in octaves rather than linearly
+    log: bool,
+}
+
+const fn linear(group: u32, min: f32, max: f32) -> Option<RandomRange> {
+    Some(RandomRange {
+        group,
+        min,
+        max,
+        log: false,
+    })
+}
+
+const fn log(group: u32, min: f32, max: f32) -> Option<RandomRange> {
+    Some(RandomRange {
+        group,
+        min,
+        max,
+        log: true,
+    })
+}
+
+/// Range of a parameter, or None for the ones that are never randomized.  `current` picks the
+/// filter type, which is kept, and a filter that's off stays off.
+fn random_range(id: ParamId, current: f32) -> Option<RandomRange> {
+    match id {
+        ParamId::Global(param) => match param {
+            GlobalParam::GrainSize => log(GROUP_GRAINS, 400., 12000.),
+            GlobalParam::LinearSlopeLength => linear(GROUP_ENVELOPE, 0.1, 1.),
+            GlobalParam::SlopeLinearity => linear(GROUP_ENVELOPE, 0., 1.),
+            GlobalParam::DetuneSpread => linear(GROUP_PITCH, 0., 50.),
+            GlobalParam::MasterGain => linear(GROUP_LEVELS, 0.5, 1.),
+            GlobalParam::DryGain => linear(GROUP_LEVELS, 0., 0.5),
+            GlobalParam::SelectionStartSampleIx
+            | GlobalParam::SelectionEndSampleIx
+            | GlobalParam::SceneMorph
+            | GlobalParam::MidSide
+            | GlobalParam::GrainPitchSnap
+            | GlobalParam::MidiClockSync => None,
+        },
+        ParamId::Voice(_, param) => match param {
+            VoiceParam::FilterCutoff if current < 0. => log(GROUP_FILTER, -1000., -40.),
+            VoiceParam::FilterCutoff if current > 0. => log(GROUP_FILTER, 1000., 18000.),
+            VoiceParam::FilterCutoff => None,
+            VoiceParam::MovementSamplesPerSample => linear(GROUP_MOVEMENT, -0.5, 1.),
+            VoiceParam::SampleSpeedRatio => log(GROUP_PITCH, 0.5, 2.),
+            VoiceParam::SamplesBetweenGrains => log(GROUP_GRAINS, 40., 4000.),
+            VoiceParam::Gain => linear(GROUP_LEVELS, 0.5, 1.),
+            VoiceParam::GrainStartRandomnessSamples => linear(GROUP_GRAINS, 0., 20000.),
+            VoiceParam::Pan => linear(GROUP_SPACE, -0.75, 0.75),
+            VoiceParam::SlopeLength => linear(GROUP_ENVELOPE, 0.1, 1.),
+            VoiceParam::SlopeLinearity => linear(GROUP_ENVELOPE, 0., 1.),
+            VoiceParam::EnvelopeSkew => linear(GROUP_ENVELOPE, -0.5, 0.5),
+            VoiceParam::EnvelopeMorph => linear(GROUP_ENVELOPE, 0., 1.),
+            VoiceParam::DelaySend => linear(GROUP_SPACE, 0., 0.5),
+            VoiceParam::GrainSkipProbability => linear(GROUP_GRAINS, 0., 0.5),
+            VoiceParam::Mute
+            | VoiceParam::Solo
+            | VoiceParam::OverlapCompensation
+            | VoiceParam::WrapsSelection => None,
+        },
+    }
+}
+
+#[derive(Clone)]
+pub struct Randomizer {
+    locked: [bool; PARAM_COUNT],
+    rng: StdRng,
+}
+
+impl Randomizer {
+    pub fn new(rng: StdRng) -> Self {
+        let mut locked = [false; PARAM_COUNT];
+        for (ix, locked) in locked.iter_mut().enumerate() {
+            let id = ParamId::from_index(ix).unwrap();
+            *locked = random_range(id, 1.).is_some_and(|range| range.group == GROUP_LEVELS);
+        }
+        Randomizer { locked, rng }
+    }
+
+    pub fn reseed(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    pub fn set_locked(&mut self, id: ParamId, locked: bool) {
+        self.locked[id.index()] = locked;
+    }
+
+    pub fn is_locked(&self, id: ParamId) -> bool {
+        self.locked[id.index()]
+    }
+
+    /// Moves every unlocked parameter in the groups of `mask` `amount` of the way, from 0 to 1,
+    /// towards a random value in its range
+    pub fn randomize(&mut self, targets: &mut ParamValues, amount: f32, mask: u32) {
+        if amount <= 0. {
+            return;
+        }
+        for (ix, target) in targets.0.iter_mut().enumerate() {
+            let id = ParamId::from_index(ix).unwrap();
+            let Some(range) = random_range(id, *target) else {
+                continue;
+            };
+            if self.locked[ix] || range.group & mask == 0 {
+                continue;
+            }
+            *target = if range.log {
+                // Highpass cutoffs are below 0, and move in octaves of their magnitude
+                let sign = range.min.signum();
+                let (min, max) = ((range.min * sign).log2(), (range.max * sign).log2());
+                let (min, max) = (min.min(max), min.max(max));
+                let random = self.rng.gen_range(min..=max);
+                let current = (*target * sign)
+                    .max(f32::MIN_POSITIVE)
+                    .log2()
+                    .clamp(min, max);
+                sign * mix(amount, current, random).exp2()
+            } else {
+                let random = self.rng.gen_range(range.min..=range.max);
+                mix(amount, *target, random)
+            };
+        }
+    }
+}
+
+/// Moves the parameter targets in the groups of `mask` (the `GROUP_*` bits) `amount`
+/// of the way, from 0 to 1, towards random values within sane ranges.  Locked parameters, the
+/// levels by default, are left alone, as are the selection, mute, solo and the scene morph, and
+/// filters that are off stay off.
+pub fn randomize(ctx: *mut GranularCtx, amount: f32, mask: u32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let mut targets = *ctx.params.target();
+    ctx.randomizer
+        .randomize(&mut targets, amount.clamp(0., 1.), mask);
+    ctx.params.set_targets(&targets);
+}
+
+/// Locks the parameter at `param_ix` against `randomize`, or unlocks it.  Returns false for
+/// indices past the last parameter.
+pub fn set_param_locked(ctx: *mut GranularCtx, param_ix: usize, locked: bool) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(id) = ParamId::from_index(param_ix) else {
+        return false;
+    };
+    ctx.randomizer.set_locked(id, locked);
+    true
+}
+
+pub fn is_param_locked(ctx: *mut GranularCtx, param_ix: usize) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    ParamId::from_index(param_ix).is_some_and(|id| ctx.randomizer.is_locked(id))
+}
+
+#[test]
+fn randomizing_stays_in_range_and_respects_locks() {
+    use rand::SeedableRng;
+
+    let mut randomizer = Randomizer::new(StdRng::seed_from_u64(3));
+    let mut defaults = ParamValues([0.; PARAM_COUNT]);
+    for ix in 0..PARAM_COUNT {
+        let id = ParamId::from_index(ix).unwrap();
+        defaults.set(id, id.info().default);
+    }
+    let grain_size = ParamId::Global(GlobalParam::GrainSize);
+    let cutoff = ParamId::Voice(0, VoiceParam::FilterCutoff);
+    let gain = ParamId::Voice(1, VoiceParam::Gain);
+    assert!(randomizer.is_locked(gain));
+
+    let mut targets = defaults;
+    randomizer.randomize(&mut targets, 0., u32::MAX);
+    assert_eq!(targets, defaults);
+
+    for _ in 0..32 {
+        let mut targets = defaults;
+        targets.set(cutoff, 4000.);
+        targets.set(ParamId::Voice(1, VoiceParam::FilterCutoff), -200.);
+        randomizer.randomize(&mut targets, 1., GROUP_GRAINS | GROUP_FILTER | GROUP_LEVELS);
+        assert!((400. ..=12000.).contains(&targets.get(grain_size)));
+        assert!((1000. ..=18000.).contains(&targets.get(cutoff)));
+        let highpass = targets.voice(1, VoiceParam::FilterCutoff);
+        assert!((-1000. ..=-40.).contains(&highpass), "{}", highpass);
+        assert_eq!(targets.get(gain), 1.);
+        assert_eq!(targets.voice(0, VoiceParam::Pan), 0.);
+    }
+
+    // Halfway from the default to a random size, in octaves
+    let mut targets = defaults;
+    randomizer.set_locked(gain, false);
+    randomizer.randomize(
+        &mut targets,
+        0.5,
+        GROUP_GRAINS | GROUP_FILTER | GROUP_LEVELS,
+    );
+    let octaves = (targets.get(grain_size) / 800.).log2();
+    assert!(((400f32 / 800.).log2() / 2. ..=(12000f32 / 800.).log2() / 2.).contains(&octaves));
+    assert!((0.75..=1.).contains(&targets.get(gain)));
+    assert_eq!(targets.get(cutoff), 0.);
+}
+
+#[test]
+fn randomizing_is_seeded_and_leaves_locked_parameters_alone() {
+    let randomized = |lock_pan: bool| {
+        let mut ctx = GranularCtx::default();
+        ctx.seed(11);
+        let pan = ParamId::Voice(1, VoiceParam::Pan);
+        assert!(set_param_locked(&mut ctx, pan.index(), lock_pan));
+        assert_eq!(is_param_locked(&mut ctx, pan.index()), lock_pan);
+        let mask = GROUP_SPACE | GROUP_GRAINS;
+        randomize(&mut ctx, 1., mask);
+        *ctx.params.target()
+    };
+    let defaults = *GranularCtx::default().params.target();
+    let targets = randomized(false);
+    assert_eq!(targets, randomized(false));
+    let grain_size = GlobalParam::GrainSize;
+    assert_ne!(targets.global(grain_size), defaults.global(grain_size));
+    assert_ne!(targets.voice(1, VoiceParam::Pan), 0.);
+    assert_eq!(randomized(true).voice(1, VoiceParam::Pan), 0.);
+    for param in [GlobalParam::SelectionEndSampleIx, GlobalParam::MasterGain] {
+        assert_eq!(targets.global(param), defaults.global(param));
+    }
+    let gain = |targets: &ParamValues| targets.voice(0, VoiceParam::Gain);
+    assert_eq!(gain(&targets), gain(&defaults));
+    let mut ctx = GranularCtx::default();
+    assert!(!set_param_locked(&mut ctx, PARAM_COUNT, true));
+}
@@ -0,0 +1,374 @@
+//! Recorder for the master output.  While it's on, every rendered frame is appended to a buffer
+//! allocated when the recording starts, which can be exported as a stereo WAV file so hosts can
+//! offer "record this texture" without tapping their audio graph.  Recording stops by itself once
+//! the buffer is full.  Exports can be resampled to any rate and written as 16 or 24-bit PCM with
+//! optional TPDF dither, or as 32-bit float, as long as they fit in a WAV file's 4 GiB.
+
+use rand::Rng;
+
+use super::FRAME_SIZE;
+use super::{ctx_mut, is_supported_sample_rate, GranularCtx};
+use crate::common;
+use crate::dsp::resample::{resample_interleaved, ResampleQuality};
+
+/// Longest recording in frames of one sample per channel, about six minutes at 48 kHz
+pub const MAX_RECORDING_FRAMES: usize = 1 << 24;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BitDepth {
+    #[default]
+    Int16,
+    Int24,
+    /// IEEE float, which keeps samples beyond full scale instead of clipping them
+    Float32,
+}
+
+impl BitDepth {
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(BitDepth::Int16),
+            1 => Some(BitDepth::Int24),
+            2 => Some(BitDepth::Float32),
+            _ => None,
+        }
+    }
+
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            BitDepth::Int16 => 2,
+            BitDepth::Int24 => 3,
+            BitDepth::Float32 => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct WavFormat {
+    pub bit_depth: BitDepth,
+    /// Adds triangular dither of one LSB before quantizing to integer samples, which turns the
+    /// truncation distortion of quiet passages into benign noise.  Float files aren't dithered.
+    pub dither: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct Recorder {
+    recording: bool,
+    /// Interleaved stereo samples
+    samples: Vec<f32>,
+    /// Samples the recording stops at, which `samples` has the capacity for
+    max_len: usize,
+    /// Sample rate when the recording started, which the WAV header declares
+    sample_rate: u32,
+}
+
+impl Recorder {
+    /// Starts a new recording of at most `max_frames`, up to `MAX_RECORDING_FRAMES`, dropping the
+    /// previous one
+    pub fn start(&mut self, sample_rate: f32, max_frames: usize) {
+        self.recording = true;
+        self.samples.clear();
+        self.max_len = max_frames.min(MAX_RECORDING_FRAMES) * 2;
+        self.samples.reserve_exact(self.max_len);
+        self.sample_rate = sample_rate.round() as u32;
+    }
+
+    pub fn stop(&mut self) {
+        self.recording = false;
+    }
+
+    /// Length of the recording in frames of one sample per channel
+    pub fn len(&self) -> usize {
+        self.samples.len() / 2
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Appends a planar stereo frame while recording, stopping once the recording is full
+    pub fn record(&mut self, stereo: &[f32; FRAME_SIZE * 2]) {
+        if !self.recording {
+            return;
+        }
+        let (left, right) = stereo.split_at(FRAME_SIZE);
+        let room = (self.max_len - self.samples.len()) / 2;
+        for (left, right) in left.iter().zip(right).take(room) {
+            self.samples.extend_from_slice(&[*left, *right]);
+        }
+        if self.samples.len() == self.max_len {
+            self.recording = false;
+        }
+    }
+
+    /// Encodes the recording as a stereo WAV file
+    pub fn to_wav(&self, format: WavFormat) -> Option<Vec<u8>> {
+        encode_wav(&self.samples, self.sample_rate, format)
+    }
+
+    /// Encodes the recording like `to_wav`, resampled to `sample_rate`.  Returns None without
+    /// resampling if the result wouldn't fit in a WAV file.
+    pub fn to_wav_at(
+        &self,
+        sample_rate: u32,
+        quality: ResampleQuality,
+        format: WavFormat,
+    ) -> Option<Vec<u8>> {
+        let resampled_len = (self.samples.len() as f64 * sample_rate as f64
+            / self.sample_rate.max(1) as f64)
+            .ceil();
+        if resampled_len >= usize::MAX as f64 {
+            return None;
+        }
+        data_len(resampled_len as usize, format.bit_depth)?;
+        let samples = resample_interleaved(
+            &self.samples,
+            2,
+            self.sample_rate as f32,
+            sample_rate as f32,
+            quality,
+        );
+        encode_wav(&samples, sample_rate, format)
+    }
+}
+
+/// Length of the data chunk of `sample_count` samples, or None if the file would be over the 4 GiB
+/// that the RIFF header can describe
+fn data_len(sample_count: usize, bit_depth: BitDepth) -> Option<u32> {
+    let len = sample_count.checked_mul(bit_depth.bytes_per_sample() as usize)?;
+    u32::try_from(len)
+        .ok()
+        .filter(|len| len.checked_add(36).is_some())
+}
+
+/// Encodes interleaved stereo `samples` as a WAV file, or returns None if they don't fit in one
+pub fn encode_wav(samples: &[f32], sample_rate: u32, format: WavFormat) -> Option<Vec<u8>> {
+    const CHANNELS: u16 = 2;
+    const PCM: u16 = 1;
+    const IEEE_FLOAT: u16 = 3;
+    let bytes_per_sample = format.bit_depth.bytes_per_sample();
+    let data_len = data_len(samples.len(), format.bit_depth)?;
+    let block_align = CHANNELS * bytes_per_sample;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    let format_tag = match format.bit_depth {
+        BitDepth::Float32 => IEEE_FLOAT,
+        BitDepth::Int16 | BitDepth::Int24 => PCM,
+    };
+    wav.extend_from_slice(&format_tag.to_le_bytes());
+    wav.extend_from_slice(&CHANNELS.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&(bytes_per_sample * 8).to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    if format.bit_depth == BitDepth::Float32 {
+        for sample in samples {
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+        return Some(wav);
+    }
+
+    let full_scale = ((1 << (bytes_per_sample * 8 - 1)) - 1) as f32;
+    let mut rng = common::rng();
+    for sample in samples {
+        let dither = if format.dither {
+            rng.gen::<f32>() - rng.gen::<f32>()
+        } else {
+            0.
+        };
+        let sample = (sample.clamp(-1., 1.) * full_scale + dither)
+            .round()
+            .clamp(-full_scale - 1., full_scale) as i32;
+        wav.extend_from_slice(&sample.to_le_bytes()[..bytes_per_sample as usize]);
+    }
+    Some(wav)
+}
+
+/// Starts recording at most `max_seconds` of the stereo master output, up to
+/// `MAX_RECORDING_FRAMES`, replacing any previous recording.  The whole recording is allocated
+/// here so that renders only copy into it, and it stops by itself once it's full.
+pub fn start_recording(ctx: *mut GranularCtx, max_seconds: f32) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        let frames = (max_seconds * ctx.sample_rate)
+            .ceil()
+            .max(0.)
+            .min(MAX_RECORDING_FRAMES as f32);
+        ctx.recorder.start(ctx.sample_rate, frames as usize);
+    }
+}
+
+/// Stops recording, keeping the recording for `export_recording_wav`
+pub fn stop_recording(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.recorder.stop();
+    }
+}
+
+/// Whether the master output is being recorded, which stops by itself once the recording is full
+pub fn is_recording(ctx: *mut GranularCtx) -> bool {
+    ctx_mut(ctx).is_some_and(|ctx| ctx.recorder.is_recording())
+}
+
+/// Returns the length of the recording in frames of one sample per channel
+pub fn get_recording_len(ctx: *mut GranularCtx) -> usize {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.recorder.len(),
+        None => 0,
+    }
+}
+
+/// `WavFormat` with `BitDepth` from its index (0 = 16-bit, 1 = 24-bit, 2 = 32-bit float)
+fn wav_format(bit_depth: u32, dither: bool) -> Option<WavFormat> {
+    Some(WavFormat {
+        bit_depth: BitDepth::from_index(bit_depth)?,
+        dither,
+    })
+}
+
+/// Returns the recording as a stereo WAV file with the given bit depth, which is empty apart from
+/// its header if nothing was recorded.  Returns an empty buffer for an invalid bit depth or a
+/// recording too long for a WAV file.
+pub fn export_recording_wav(ctx: *mut GranularCtx, bit_depth: u32, dither: bool) -> Vec<u8> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    match wav_format(bit_depth, dither) {
+        Some(format) => ctx.recorder.to_wav(format).unwrap_or_default(),
+        None => Vec::new(),
+    }
+}
+
+/// Returns the recording like `export_recording_wav`, resampled to `sample_rate` with the given
+/// `ResampleQuality` (0 = fast, 1 = standard, 2 = best).  Returns an empty buffer for an invalid
+/// quality or bit depth, a rate outside the supported sample rates or a resampled recording too
+/// long for a WAV file.
+pub fn export_recording_wav_at(
+    ctx: *mut GranularCtx,
+    sample_rate: u32,
+    quality: u32,
+    bit_depth: u32,
+    dither: bool,
+) -> Vec<u8> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    let Some(quality) = ResampleQuality::from_index(quality) else {
+        return Vec::new();
+    };
+    let Some(format) = wav_format(bit_depth, dither) else {
+        return Vec::new();
+    };
+    if !is_supported_sample_rate(sample_rate as f32) {
+        return Vec::new();
+    }
+    ctx.recorder
+        .to_wav_at(sample_rate, quality, format)
+        .unwrap_or_default()
+}
+
+#[test]
+fn recordings_encode_as_interleaved_wav() {
+    let mut recorder = Recorder::default();
+    let mut frame = [0.; FRAME_SIZE * 2];
+    frame[0] = 1.;
+    frame[FRAME_SIZE] = -1.;
+    recorder.record(&frame);
+    assert_eq!(recorder.len(), 0);
+
+    recorder.start(44100., MAX_RECORDING_FRAMES);
+    recorder.record(&frame);
+    recorder.stop();
+    recorder.record(&frame);
+    assert_eq!(recorder.len(), FRAME_SIZE);
+
+    let wav = recorder.to_wav(WavFormat::default()).unwrap();
+    assert_eq!(wav.len(), 44 + FRAME_SIZE * 4);
+    assert_eq!(&wav[..4], b"RIFF");
+    assert_eq!(wav[24..28], 44100u32.to_le_bytes());
+    assert_eq!(wav[44..48], [0xff, 0x7f, 0x01, 0x80]);
+
+    let wav = recorder
+        .to_wav_at(22050, ResampleQuality::Fast, WavFormat::default())
+        .unwrap();
+    assert_eq!(wav.len(), 44 + FRAME_SIZE * 2);
+    assert_eq!(wav[24..28], 22050u32.to_le_bytes());
+}
+
+#[test]
+fn recordings_stop_when_full_and_exports_fit_in_a_wav_file() {
+    let mut recorder = Recorder::default();
+    recorder.start(44100., FRAME_SIZE + 10);
+    let capacity = recorder.samples.capacity();
+    for _ in 0..3 {
+        recorder.record(&[0.5; FRAME_SIZE * 2]);
+    }
+    assert_eq!(recorder.len(), FRAME_SIZE + 10);
+    assert_eq!(recorder.samples.capacity(), capacity);
+    assert!(!recorder.is_recording());
+
+    let float = BitDepth::Float32;
+    assert_eq!(data_len(1000, float), Some(4000));
+    assert_eq!(
+        data_len((u32::MAX as usize - 36) / 4, float),
+        Some(u32::MAX - 39)
+    );
+    assert_eq!(data_len(u32::MAX as usize / 4, float), None);
+    assert_eq!(data_len(usize::MAX, BitDepth::Int16), None);
+    // Upsampling from 1 kHz to 768 kHz would need more than 4 GiB
+    recorder.start(1000., MAX_RECORDING_FRAMES);
+    recorder.samples.resize(2_000_000, 0.);
+    let format = WavFormat {
+        bit_depth: float,
+        dither: false,
+    };
+    assert!(recorder
+        .to_wav_at(768_000, ResampleQuality::Fast, format)
+        .is_none());
+}
+
+#[test]
+fn wav_bit_depths_and_dither() {
+    let samples = [1., -1., 1.5, 0.];
+    let wav = encode_wav(
+        &samples,
+        48000,
+        WavFormat {
+            bit_depth: BitDepth::Int24,
+            dither: false,
+        },
+    )
+    .unwrap();
+    assert_eq!(wav.len(), 44 + 12);
+    assert_eq!(wav[34..36], 24u16.to_le_bytes());
+    assert_eq!(
+        wav[44..53],
+        [0xff, 0xff, 0x7f, 0x01, 0x00, 0x80, 0xff, 0xff, 0x7f]
+    );
+
+    let format = WavFormat {
+        bit_depth: BitDepth::Float32,
+        dither: true,
+    };
+    let wav = encode_wav(&samples, 48000, format).unwrap();
+    assert_eq!(wav[20..22], 3u16.to_le_bytes());
+    assert_eq!(wav[52..56], 1.5f32.to_le_bytes());
+
+    // Dithered silence stays within one LSB of zero
+    let format = WavFormat {
+        bit_depth: BitDepth::Int16,
+        dither: true,
+    };
+    let wav = encode_wav(&[0.; 1024], 48000, format).unwrap();
+    let dithered: Vec<i16> = wav[44..]
+        .chunks(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+    assert!(dithered.iter().all(|sample| sample.abs() <= 1));
+    assert!(dithered.iter().any(|sample| *sample != 0));
+}
@@ -0,0 +1,197 @@
+//! Voice resonators.  A voice with a resonator feeds its grain stream through a Karplus-Strong
+//! comb before its filter, so that every grain plucks it like a string.  Noisy clouds come out
+//! pitched at the resonator's frequency.
+//!
+//! A note-tracked resonator is tuned to its frequency times the voice's sample speed, which note
+//! mode, detune and pitch modulation transpose, so with the frequency at that of the root note it
+//! plays the notes the voice is given.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::resonator::CombResonator;
+use crate::dsp::{clamp, decay_samples, mix};
+
+/// Lowest frequency the delay lines are long enough for
+pub const MIN_FREQUENCY_HZ: f32 = 20.;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ResonatorSettings {
+    /// Frequency at a sample speed of 1 when note-tracked, otherwise the fixed frequency
+    pub frequency_hz: f32,
+    pub track_notes: bool,
+    /// From 0 to just below 1; the higher, the longer it rings
+    pub feedback: f32,
+    /// From 0 to just below 1; the higher, the faster high partials die away
+    pub damping: f32,
+    /// Balance between the grains (0) and the resonator (1)
+    pub mix: f32,
+}
+
+#[derive(Clone)]
+pub struct VoiceResonator {
+    pub settings: ResonatorSettings,
+    left: CombResonator,
+    right: CombResonator,
+}
+
+impl VoiceResonator {
+    /// How long the resonator rings on after its input stops, at its untracked frequency
+    pub fn tail_samples(&self, sample_rate: f32) -> f32 {
+        let period = sample_rate / self.settings.frequency_hz.max(MIN_FREQUENCY_HZ);
+        decay_samples(period, self.settings.feedback)
+    }
+
+    pub fn new(settings: ResonatorSettings, sample_rate: f32) -> Self {
+        let max_delay = (sample_rate / MIN_FREQUENCY_HZ).ceil() as usize;
+        VoiceResonator {
+            settings,
+            left: CombResonator::new(max_delay),
+            right: CombResonator::new(max_delay),
+        }
+    }
+
+    /// Silences the resonator
+    pub fn clear(&mut self) {
+        self.left.clear();
+        self.right.clear();
+    }
+
+    /// Resonates one stereo sample.  `speed_ratio` is the voice's sample speed including pitch
+    /// modulation, and only matters when note-tracked.
+    pub fn process(
+        &mut self,
+        (left, right): (f32, f32),
+        speed_ratio: f32,
+        sample_rate: f32,
+    ) -> (f32, f32) {
+        let settings = self.settings;
+        let frequency_hz = if settings.track_notes {
+            settings.frequency_hz * speed_ratio
+        } else {
+            settings.frequency_hz
+        };
+        let period = sample_rate / frequency_hz.max(MIN_FREQUENCY_HZ);
+        let resonate = |comb: &mut CombResonator, sample: f32| {
+            let resonance = comb.process(sample, period, settings.feedback, settings.damping);
+            mix(settings.mix, sample, resonance)
+        };
+        (
+            resonate(&mut self.left, left),
+            resonate(&mut self.right, right),
+        )
+    }
+}
+
+/// Feeds a voice's output through a Karplus-Strong resonator tuned to `frequency_hz` (20 Hz to
+/// 10 kHz), times the voice's sample speed if `track_notes` is set.  `feedback` sets how long it
+/// rings and `damping` how quickly its high partials fade, both from 0 to 0.999, and `mix` the
+/// balance between the grains (0) and the resonator (1).  A voice that already has a resonator
+/// keeps ringing with the new settings.
+#[allow(clippy::too_many_arguments)]
+pub fn set_voice_resonator(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    frequency_hz: f32,
+    track_notes: bool,
+    feedback: f32,
+    damping: f32,
+    mix: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT
+        || !frequency_hz.is_finite()
+        || !feedback.is_finite()
+        || !damping.is_finite()
+        || !mix.is_finite()
+    {
+        return;
+    }
+    let settings = ResonatorSettings {
+        frequency_hz: clamp(MIN_FREQUENCY_HZ, 10000., frequency_hz),
+        track_notes,
+        feedback: clamp(0., 0.999, feedback),
+        damping: clamp(0., 0.999, damping),
+        mix: clamp(0., 1., mix),
+    };
+    let sample_rate = ctx.sample_rate;
+    ctx.voices[voice_ix]
+        .resonator
+        .get_or_insert_with(|| VoiceResonator::new(settings, sample_rate))
+        .settings = settings;
+}
+
+/// Removes a voice's resonator
+pub fn clear_voice_resonator(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.resonator = None;
+    }
+}
+
+#[test]
+fn tracked_resonators_follow_the_sample_speed() {
+    let sample_rate = 1000.;
+    let mut resonator = VoiceResonator::new(
+        ResonatorSettings {
+            frequency_hz: 25.,
+            track_notes: true,
+            feedback: 0.9,
+            damping: 0.,
+            mix: 1.,
+        },
+        sample_rate,
+    );
+    // An octave up, the repeats of an impulse are 20 samples apart
+    let output: Vec<f32> = (0..60)
+        .map(|ix| {
+            let input = if ix == 0 { 1. } else { 0. };
+            resonator.process((input, input), 2., sample_rate).0
+        })
+        .collect();
+    assert_eq!(output[20], 1.);
+    assert!((output[40] - 0.9).abs() < 1e-6);
+    assert_eq!(output[10], 0.);
+}
+
+#[test]
+fn resonators_pitch_noisy_grains_at_the_played_note() {
+    use super::midi::handle_midi_event;
+    use super::notes::{set_note_mode, ROOT_NOTE};
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+    use super::wavetable::ROOT_FREQUENCY_HZ;
+    use super::DEFAULT_SAMPLE_RATE;
+    use crate::common;
+    use rand::Rng;
+
+    let mut rng = common::rng();
+    let mut ctx = GranularCtx {
+        waveform: (0..8192).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 8191.);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    set_voice_resonator(&mut ctx, 0, ROOT_FREQUENCY_HZ, true, 0.98, 0.2, 1.);
+    set_note_mode(&mut ctx, true, false, 2.);
+    handle_midi_event(&mut ctx, 0x90, ROOT_NOTE + 12, 127);
+    let targets = *ctx.params.target();
+    let mut output = Vec::new();
+    for _ in 0..64 {
+        ctx.render(&targets);
+        output.extend_from_slice(&ctx.rendered_output);
+    }
+    // The output repeats every period of the note an octave above the root
+    let period = DEFAULT_SAMPLE_RATE / (2. * ROOT_FREQUENCY_HZ);
+    let correlation =
+        |lag: usize| -> f32 { output[lag..].iter().zip(&output).map(|(a, b)| a * b).sum() };
+    let best_lag = (40..120).max_by(|&a, &b| correlation(a).total_cmp(&correlation(b)));
+    assert!((best_lag.unwrap() as f32 - period).abs() <= 1.5);
+
+    clear_voice_resonator(&mut ctx, 0);
+    assert!(ctx.voices[0].resonator.is_none());
+}
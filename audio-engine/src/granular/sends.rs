@@ -0,0 +1,123 @@
+//! Send buses.  Each voice sends some of its panned output to buses that are shared by all
+//! voices, each feeding one internal effect, and the effects' output is summed into the master
+//! ahead of the master gain.  Send levels are voice parameters so that they're smoothed.
+//!
+//! The only effect so far is a feedback delay.  Its line is sized for the longest delay when the
+//! buses are created and whenever the sample rate changes, so that the render never allocates it.
+
+use super::params::{ParamId, VOICE_COUNT};
+use super::params::{ParamValues, VoiceParam};
+use super::DEFAULT_SAMPLE_RATE;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::delay::StereoDelay;
+
+pub const MAX_DELAY_MS: f32 = 2000.;
+
+#[derive(Clone)]
+pub struct SendBuses {
+    pub delay: StereoDelay,
+    pub delay_ms: f32,
+    /// Fraction of the delayed signal fed back into the delay, below 1 so that it dies out
+    pub delay_feedback: f32,
+    /// Sum of the voices' sends to the delay for the current sample
+    delay_input: (f32, f32),
+    /// Sample rate the delay line is sized for
+    sample_rate: f32,
+}
+
+impl Default for SendBuses {
+    fn default() -> Self {
+        SendBuses {
+            delay: delay_line(DEFAULT_SAMPLE_RATE),
+            delay_ms: 250.,
+            delay_feedback: 0.4,
+            delay_input: (0., 0.),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        }
+    }
+}
+
+fn delay_line(sample_rate: f32) -> StereoDelay {
+    StereoDelay::new((MAX_DELAY_MS * sample_rate / 1000.).ceil() as usize)
+}
+
+impl SendBuses {
+    /// Resizes the delay line for the longest delay at `sample_rate`, which clears it
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        if self.sample_rate != sample_rate {
+            self.delay = delay_line(sample_rate);
+            self.sample_rate = sample_rate;
+        }
+    }
+
+    /// Adds a voice's output to the buses according to its send levels
+    #[inline]
+    pub fn send(&mut self, params: &ParamValues, voice_ix: usize, (left, right): (f32, f32)) {
+        let level = params.voice(voice_ix, VoiceParam::DelaySend);
+        if level > 0. {
+            self.delay_input.0 += left * level;
+            self.delay_input.1 += right * level;
+        }
+    }
+
+    /// Runs the effects on everything sent this sample and returns their summed output
+    #[inline]
+    pub fn process(&mut self, sample_rate: f32) -> (f32, f32) {
+        let input = std::mem::take(&mut self.delay_input);
+        let delay_samples = (self.delay_ms * sample_rate / 1000.) as usize;
+        self.delay
+            .process(input, delay_samples, self.delay_feedback)
+    }
+
+    pub fn clear(&mut self) {
+        self.delay.clear();
+        self.delay_input = (0., 0.);
+    }
+}
+
+/// Sets how much of a voice's output goes to the delay bus, from 0 to 1.  The send is taken after
+/// the voice's gain and pan.
+pub fn set_voice_delay_send(ctx: *mut GranularCtx, voice_ix: usize, level: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if voice_ix >= VOICE_COUNT || !level.is_finite() {
+        return;
+    }
+    ctx.params.set_target(
+        ParamId::Voice(voice_ix, VoiceParam::DelaySend),
+        clamp(0., 1., level),
+    );
+}
+
+/// Sets the delay bus's time, clamped to 1..2000 ms, and feedback, clamped to 0..0.95
+pub fn set_send_delay(ctx: *mut GranularCtx, time_ms: f32, feedback: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !time_ms.is_finite() || !feedback.is_finite() {
+        return;
+    }
+    ctx.sends.delay_ms = clamp(1., MAX_DELAY_MS, time_ms);
+    ctx.sends.delay_feedback = clamp(0., 0.95, feedback);
+}
+
+#[test]
+fn sends_are_delayed_into_the_return() {
+    let mut params = ParamValues::default();
+    params.set(super::params::ParamId::Voice(1, VoiceParam::DelaySend), 0.5);
+    let mut buses = SendBuses {
+        delay_ms: 2.,
+        delay_feedback: 0.,
+        ..Default::default()
+    };
+    assert_eq!(buses.delay.max_delay_samples(), 88200);
+    buses.set_sample_rate(1000.);
+    assert_eq!(buses.delay.max_delay_samples(), 2000);
+    buses.send(&params, 0, (1., 1.));
+    buses.send(&params, 1, (1., -1.));
+    assert_eq!(buses.process(1000.), (0., 0.));
+    assert_eq!(buses.process(1000.), (0., 0.));
+    assert_eq!(buses.process(1000.), (0.5, -0.5));
+}
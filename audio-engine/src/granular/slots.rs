@@ -0,0 +1,387 @@
+//! Sample slots holding audio rendered by the engine itself, such as bounces of the current
+//! texture, or added by the host, which can then be loaded as the waveform and granulated again.
+//! Slot ids stay valid until the slot is freed and aren't reused.
+//!
+//! A voice can also play its grains straight from slots, picking a slot for every grain by a
+//! `SlotSelection`, so that one voice interleaves several recordings.
+
+use rand::Rng;
+
+use super::params::VOICE_COUNT;
+use super::waveform::{ChannelSamples, WaveformChannels};
+use super::waveform::{CompactWaveform, SwapTail, WaveformStorage};
+use super::{ctx_mut, GranularCtx};
+
+/// Most slots a voice's selection picks from
+pub const MAX_SELECTION_SLOTS: usize = 64;
+
+#[derive(Clone)]
+struct Slot {
+    /// Left channel, or the only one for mono slots
+    left: Vec<f32>,
+    /// Right channel of a stereo slot, the same length as `left`
+    right: Option<Vec<f32>>,
+}
+
+#[derive(Clone, Default)]
+pub struct SampleSlots {
+    slots: Vec<Option<Slot>>,
+}
+
+impl SampleSlots {
+    /// Stores mono `samples` in a new slot and returns its id
+    pub fn add(&mut self, samples: Vec<f32>) -> u32 {
+        self.add_stereo(samples, None)
+    }
+
+    /// Stores `left` and, for stereo audio, `right` of the same length in a new slot and returns
+    /// its id
+    pub fn add_stereo(&mut self, left: Vec<f32>, right: Option<Vec<f32>>) -> u32 {
+        self.slots.push(Some(Slot { left, right }));
+        (self.slots.len() - 1) as u32
+    }
+
+    /// The slot's left channel, or its only one if it's mono
+    pub fn get(&self, slot_id: u32) -> Option<&[f32]> {
+        Some(&self.slots.get(slot_id as usize)?.as_ref()?.left)
+    }
+
+    /// The slot's right channel if it's stereo
+    pub fn get_right(&self, slot_id: u32) -> Option<&[f32]> {
+        self.slots.get(slot_id as usize)?.as_ref()?.right.as_deref()
+    }
+
+    /// The slot's audio for grains to read, which is silent once the slot is freed
+    pub fn channels(&self, slot_id: u32) -> WaveformChannels<'_> {
+        WaveformChannels {
+            left: ChannelSamples::Float(self.get(slot_id).unwrap_or_default()),
+            right: self.get_right(slot_id).map(ChannelSamples::Float),
+        }
+    }
+
+    /// Frees the slot's audio, returning false if it didn't exist
+    pub fn remove(&mut self, slot_id: u32) -> bool {
+        self.slots
+            .get_mut(slot_id as usize)
+            .and_then(Option::take)
+            .is_some()
+    }
+}
+
+/// How a voice picks the slot each of its grains plays from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SlotPolicy {
+    /// Always the first slot
+    Fixed,
+    /// The slots in turn
+    RoundRobin,
+    /// At random, each slot as likely as its weight
+    WeightedRandom,
+}
+
+impl SlotPolicy {
+    pub fn from_u32(value: u32) -> Option<SlotPolicy> {
+        match value {
+            0 => Some(SlotPolicy::Fixed),
+            1 => Some(SlotPolicy::RoundRobin),
+            2 => Some(SlotPolicy::WeightedRandom),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SlotSelection {
+    pub policy: SlotPolicy,
+    slot_ids: Vec<u32>,
+    /// Running sums of the weights, for weighted picks
+    cumulative_weights: Vec<f32>,
+    /// Slot the next round-robin pick lands on
+    next: usize,
+}
+
+impl SlotSelection {
+    /// Returns None if there are no slots or too many, or for weighted picks if there isn't one
+    /// weight per slot, a weight is negative or they're all 0
+    pub fn new(policy: SlotPolicy, slot_ids: &[u32], weights: &[f32]) -> Option<Self> {
+        if slot_ids.is_empty() || slot_ids.len() > MAX_SELECTION_SLOTS {
+            return None;
+        }
+        let cumulative_weights = if policy == SlotPolicy::WeightedRandom {
+            if weights.len() != slot_ids.len()
+                || !weights
+                    .iter()
+                    .all(|weight| weight.is_finite() && *weight >= 0.)
+            {
+                return None;
+            }
+            let cumulative: Vec<f32> = weights
+                .iter()
+                .scan(0., |sum, weight| {
+                    *sum += weight;
+                    Some(*sum)
+                })
+                .collect();
+            if cumulative.last().is_some_and(|total| *total <= 0.) {
+                return None;
+            }
+            cumulative
+        } else {
+            vec![]
+        };
+        Some(SlotSelection {
+            policy,
+            slot_ids: slot_ids.to_vec(),
+            cumulative_weights,
+            next: 0,
+        })
+    }
+
+    /// Slot of a new grain
+    pub fn pick(&mut self, rng: &mut impl Rng) -> u32 {
+        match self.policy {
+            SlotPolicy::Fixed => self.slot_ids[0],
+            SlotPolicy::RoundRobin => {
+                let slot_id = self.slot_ids[self.next];
+                self.next = (self.next + 1) % self.slot_ids.len();
+                slot_id
+            }
+            SlotPolicy::WeightedRandom => {
+                let total = self.cumulative_weights[self.cumulative_weights.len() - 1];
+                let target = rng.gen_range(0.0..total);
+                let ix = self
+                    .cumulative_weights
+                    .partition_point(|&sum| sum <= target)
+                    .min(self.slot_ids.len() - 1);
+                self.slot_ids[ix]
+            }
+        }
+    }
+}
+
+/// Swaps the audio in a sample slot in as the waveform like `swap_staging_waveform`.  Load
+/// options don't apply since the slot is already at the engine's rate.  Returns false for an
+/// unknown slot.
+pub fn load_sample_slot(ctx: *mut GranularCtx, slot_id: u32, crossfade_ms: f32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if !crossfade_ms.is_finite() {
+        return false;
+    }
+    if !stage_slot(ctx, slot_id) {
+        return false;
+    }
+    ctx.waveform_swap.pending = Some(SwapTail::Crossfade(crossfade_ms.max(0.)));
+    true
+}
+
+/// Copies a sample slot into the staged waveform without swapping it in, so that a
+/// `Command::LoadSlot` for it later only has to swap.  Returns false if the slot doesn't exist.
+pub fn stage_sample_slot(ctx: *mut GranularCtx, slot_id: u32) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    ctx.waveform_swap.pending = None;
+    stage_slot(ctx, slot_id)
+}
+
+fn stage_slot(ctx: &mut GranularCtx, slot_id: u32) -> bool {
+    let Some(samples) = ctx.sample_slots.get(slot_id) else {
+        return false;
+    };
+    let right = ctx.sample_slots.get_right(slot_id);
+    let swap = &mut ctx.waveform_swap;
+    if ctx.load_options.storage == WaveformStorage::Int16 {
+        swap.staged_compact = Some(CompactWaveform::encode(samples, right));
+        swap.staged = None;
+        swap.staged_right = None;
+    } else {
+        swap.staged_compact = None;
+        swap.staged = Some(samples.to_vec());
+        swap.staged_right = right.map(<[f32]>::to_vec);
+    }
+    swap.staged_slot = Some(slot_id);
+    true
+}
+
+/// Stores audio from the host in a new sample slot and returns its id, or `u32::MAX` if there's
+/// no audio.  The load options apply as they do to a loaded waveform.
+pub fn add_sample_slot(ctx: *mut GranularCtx, samples: &[f32]) -> u32 {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return u32::MAX;
+    };
+    if samples.is_empty() {
+        return u32::MAX;
+    }
+    let samples = samples
+        .iter()
+        .map(|sample| if sample.is_finite() { *sample } else { 0. })
+        .collect();
+    let (samples, _) = ctx.load_options.prepare(samples, None, ctx.sample_rate);
+    ctx.sample_slots.add(samples)
+}
+
+pub fn free_sample_slot(ctx: *mut GranularCtx, slot_id: u32) -> bool {
+    ctx_mut(ctx).is_some_and(|ctx| ctx.sample_slots.remove(slot_id))
+}
+
+/// Plays a voice's grains from sample slots instead of the waveform, picking one of `slot_ids`
+/// for every grain by `policy`: 0 = always the first, 1 = each in turn, 2 = at random, weighted
+/// by `weights`.  Each grain starts as far into its slot as it would have into the selection.
+/// Grains of a slot that's freed go silent.  Returns false and changes nothing for an unknown
+/// policy, no slots or more than `MAX_SELECTION_SLOTS`, or for weighted picks unless
+/// there's a weight of at least 0 for each slot and they don't add up to 0.
+pub fn set_voice_slot_selection(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    policy: u32,
+    slot_ids: &[u32],
+    weights: &[f32],
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(policy) = SlotPolicy::from_u32(policy) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT {
+        return false;
+    }
+    let Some(selection) = SlotSelection::new(policy, slot_ids, weights) else {
+        return false;
+    };
+    ctx.voices[voice_ix].slot_selection = Some(selection);
+    true
+}
+
+/// Goes back to playing a voice's grains from the waveform
+pub fn clear_voice_slot_selection(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.slot_selection = None;
+    }
+}
+
+#[test]
+fn slots_are_picked_by_the_policy() {
+    assert_eq!(SlotSelection::new(SlotPolicy::Fixed, &[], &[]), None);
+    assert_eq!(
+        SlotSelection::new(SlotPolicy::WeightedRandom, &[1, 2], &[1.]),
+        None
+    );
+    assert_eq!(
+        SlotSelection::new(SlotPolicy::WeightedRandom, &[1, 2], &[0., 0.]),
+        None
+    );
+
+    let mut rng = crate::common::rng();
+    let mut fixed = SlotSelection::new(SlotPolicy::Fixed, &[4, 5], &[]).unwrap();
+    assert!((0..4).all(|_| fixed.pick(&mut rng) == 4));
+    let mut round_robin = SlotSelection::new(SlotPolicy::RoundRobin, &[4, 5, 6], &[]).unwrap();
+    let picks: Vec<u32> = (0..5).map(|_| round_robin.pick(&mut rng)).collect();
+    assert_eq!(picks, [4, 5, 6, 4, 5]);
+
+    let mut weighted =
+        SlotSelection::new(SlotPolicy::WeightedRandom, &[4, 5, 6], &[3., 0., 1.]).unwrap();
+    let mut counts = [0; 3];
+    for _ in 0..4000 {
+        counts[weighted.pick(&mut rng) as usize - 4] += 1;
+    }
+    assert_eq!(counts[1], 0);
+    assert!((2700..3300).contains(&counts[0]), "{:?}", counts);
+}
+
+#[test]
+fn bounced_slots_can_be_loaded() {
+    use super::bounce_selection;
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+
+    let mut ctx = GranularCtx {
+        waveform: (0..44100).map(|i| (i as f32 * 0.01).sin()).collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    ctx.params
+        .set_target(ParamId::Voice(0, VoiceParam::Gain), 1.);
+    ctx.params
+        .set_target(ParamId::Voice(0, VoiceParam::Pan), -0.5);
+    ctx.seed(3);
+    let targets = *ctx.params.target();
+    ctx.render(&targets);
+    let mut live = ctx.clone();
+
+    // Bouncing leaves live playback where it was
+    let slot_id = bounce_selection(&mut ctx, 1000);
+    assert_eq!(ctx.sample_slots.get(slot_id).map(<[f32]>::len), Some(1000));
+    let left = ctx.sample_slots.get(slot_id).unwrap();
+    let right = ctx.sample_slots.get_right(slot_id).unwrap();
+    assert_eq!(right.len(), 1000);
+    assert_ne!(left, right);
+    ctx.render(&targets);
+    live.render(&targets);
+    assert_eq!(ctx.rendered_output_stereo, live.rendered_output_stereo);
+
+    assert!(load_sample_slot(&mut ctx, slot_id, 10.));
+    ctx.render(&targets);
+    assert_eq!(ctx.waveform.len(), 1000);
+    assert_eq!(ctx.waveform_right.as_ref().map(Vec::len), Some(1000));
+
+    assert!(free_sample_slot(&mut ctx, slot_id));
+    assert!(!load_sample_slot(&mut ctx, slot_id, 10.));
+}
+
+#[test]
+fn voices_interleave_grains_from_slots() {
+    use super::params::{ParamId, VoiceParam};
+    use super::{test_targets, Grain};
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.; 44100],
+        ..Default::default()
+    };
+    let quiet = add_sample_slot(&mut ctx, &[0.25; 20000]);
+    let loud = add_sample_slot(&mut ctx, &[0.75; 30000]);
+    assert_eq!(add_sample_slot(&mut ctx, &[]), u32::MAX);
+    assert!(!set_voice_slot_selection(&mut ctx, 0, 3, &[quiet], &[]));
+    assert!(!set_voice_slot_selection(
+        &mut ctx,
+        0,
+        2,
+        &[quiet, loud],
+        &[1.]
+    ));
+    assert!(set_voice_slot_selection(
+        &mut ctx,
+        0,
+        1,
+        &[quiet, loud],
+        &[]
+    ));
+    let mut targets = test_targets(44099.);
+    targets.set(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    for _ in 0..32 {
+        ctx.render(&targets);
+    }
+    let grains: Vec<Grain> = ctx.voices[0].grains.iter().collect();
+    assert!(grains.iter().any(|grain| grain.slot == Some(quiet)));
+    assert!(grains.iter().any(|grain| grain.slot == Some(loud)));
+    assert!(grains.iter().all(|grain| match grain.slot {
+        Some(slot_id) => grain.start_sample_ix < if slot_id == quiet { 20000. } else { 30000. },
+        None => false,
+    }));
+    // The waveform is silent, so everything heard comes from the slots
+    assert!(ctx.rendered_output.iter().any(|sample| *sample > 0.1));
+
+    assert!(set_voice_slot_selection(&mut ctx, 0, 0, &[quiet], &[]));
+    assert!(free_sample_slot(&mut ctx, quiet));
+    for _ in 0..32 {
+        ctx.render(&targets);
+    }
+    assert!(ctx.rendered_output.iter().all(|sample| sample.abs() < 1e-6));
+    clear_voice_slot_selection(&mut ctx, 0);
+    assert!(ctx.voices[0].slot_selection.is_none());
+}
@@ -0,0 +1,314 @@
+//! Spectral granulation.  A voice in spectral mode granulates in the frequency domain instead of
+//! the time domain: every quarter of a frame it takes an FFT frame of the waveform around its read
+//! head, scattered by the grain start randomness, rearranges the magnitudes of its bins and
+//! resynthesizes it by overlap-add.
+//!
+//! Shuffling swaps the magnitudes of bins with random neighbours, which scatters the partials of
+//! the source into inharmonic clusters.  Smearing blends each frame's magnitudes with those of the
+//! frames before it, blurring the spectrum over time into a wash.  Phases are kept, so with
+//! neither the voice resynthesizes the waveform around the read head.
+
+use rand::Rng;
+use std::f32::consts::PI;
+
+use super::params::VOICE_COUNT;
+use super::waveform::WaveformChannels;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::fft::Fft;
+use crate::dsp::{clamp, mix};
+
+pub const MIN_FRAME_LEN: usize = 256;
+/// Longest frame, which bounds the transforms a hop runs in a single sample
+pub const MAX_FRAME_LEN: usize = 4096;
+/// Frames overlap by three quarters
+const HOPS_PER_FRAME: usize = 4;
+/// Furthest a shuffled bin's magnitude moves, in bins
+const SHUFFLE_SPAN: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SpectralSettings {
+    /// Samples per FFT frame, a power of two from `MIN_FRAME_LEN` to `MAX_FRAME_LEN`
+    pub frame_len: usize,
+    /// Fraction of bins whose magnitudes are swapped, from 0 to 1
+    pub shuffle: f32,
+    /// How much of each bin's magnitude carries over from the frames before, from 0 to just
+    /// below 1
+    pub smear: f32,
+}
+
+impl SpectralSettings {
+    pub fn is_valid_frame_len(frame_len: usize) -> bool {
+        frame_len.is_power_of_two() && (MIN_FRAME_LEN..=MAX_FRAME_LEN).contains(&frame_len)
+    }
+}
+
+/// Analysis and resynthesis state of one channel
+#[derive(Clone, Debug)]
+struct Channel {
+    re: Vec<f32>,
+    im: Vec<f32>,
+    /// Smeared magnitudes of the previous frame
+    magnitudes: Vec<f32>,
+    /// Overlap-add accumulator, a ring buffer starting at `SpectralGranulator::pos`
+    output: Vec<f32>,
+}
+
+impl Channel {
+    fn new(frame_len: usize) -> Self {
+        Channel {
+            re: vec![0.; frame_len],
+            im: vec![0.; frame_len],
+            magnitudes: vec![0.; frame_len / 2 + 1],
+            output: vec![0.; frame_len],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SpectralGranulator {
+    pub settings: SpectralSettings,
+    /// Hann window
+    window: Vec<f32>,
+    fft: Fft,
+    left: Channel,
+    /// Only used for stereo waveforms
+    right: Channel,
+    /// Bin each bin takes its magnitude from, the same for both channels
+    sources: Vec<usize>,
+    /// Position in the output accumulators
+    pos: usize,
+    /// Samples until the next frame
+    until_frame: usize,
+}
+
+impl SpectralGranulator {
+    /// `settings.frame_len` must be valid
+    pub fn new(settings: SpectralSettings) -> Self {
+        let frame_len = settings.frame_len;
+        SpectralGranulator {
+            settings,
+            window: (0..frame_len)
+                .map(|i| 0.5 - 0.5 * (2. * PI * i as f32 / frame_len as f32).cos())
+                .collect(),
+            fft: Fft::new(frame_len),
+            left: Channel::new(frame_len),
+            right: Channel::new(frame_len),
+            sources: (0..=frame_len / 2).collect(),
+            pos: 0,
+            until_frame: 0,
+        }
+    }
+
+    /// Whether the next sample starts a new frame, which `add_frame` has to be called for first
+    pub fn needs_frame(&self) -> bool {
+        self.until_frame == 0
+    }
+
+    /// Analyzes the frame of the waveform starting at `start`, read at `playback_ratio`, and adds
+    /// its resynthesis to the output
+    pub fn add_frame(
+        &mut self,
+        channels: WaveformChannels,
+        start: f32,
+        playback_ratio: f32,
+        rng: &mut impl Rng,
+    ) {
+        let bin_count = self.sources.len();
+        for (bin, source) in self.sources.iter_mut().enumerate() {
+            *source = bin;
+        }
+        for bin in 1..bin_count {
+            if rng.gen::<f32>() < self.settings.shuffle {
+                let span = bin.min(bin_count - 1 - bin).min(SHUFFLE_SPAN);
+                let other = rng.gen_range(bin - span..=bin + span);
+                self.sources.swap(bin, other);
+            }
+        }
+
+        let channel_signals = std::iter::once((&mut self.left, channels.left))
+            .chain(channels.right.map(|right| (&mut self.right, right)));
+        for (channel, samples) in channel_signals {
+            for (i, (re, im)) in channel.re.iter_mut().zip(&mut channel.im).enumerate() {
+                let sample = samples.read(start + i as f32 * playback_ratio);
+                *re = if sample.is_finite() {
+                    sample * self.window[i]
+                } else {
+                    0.
+                };
+                *im = 0.;
+            }
+            self.fft.forward(&mut channel.re, &mut channel.im);
+            resynthesize(channel, &self.fft, &self.sources, self.settings.smear);
+
+            // Hann windows at each end sum to 1.5 at this overlap
+            let scale = 1. / (0.375 * HOPS_PER_FRAME as f32);
+            let frame_len = self.window.len();
+            for (i, (sample, window)) in channel.re.iter().zip(&self.window).enumerate() {
+                channel.output[(self.pos + i) % frame_len] += sample * window * scale;
+            }
+        }
+        self.until_frame = self.window.len() / HOPS_PER_FRAME;
+    }
+
+    /// Takes the next output sample.  Right is the same as left for mono waveforms.
+    pub fn next_sample(&mut self, stereo: bool) -> (f32, f32) {
+        let left = std::mem::take(&mut self.left.output[self.pos]);
+        let right = if stereo {
+            std::mem::take(&mut self.right.output[self.pos])
+        } else {
+            left
+        };
+        self.pos = (self.pos + 1) % self.window.len();
+        self.until_frame = self.until_frame.saturating_sub(1);
+        (left, right)
+    }
+}
+
+/// Rearranges and smears the magnitudes of the spectrum in `channel.re` and `channel.im` and
+/// transforms it back into `channel.re`, unwindowed
+fn resynthesize(channel: &mut Channel, fft: &Fft, sources: &[usize], smear: f32) {
+    let frame_len = channel.re.len();
+    for (bin, &source) in sources.iter().enumerate() {
+        let magnitude = channel.re[source].hypot(channel.im[source]);
+        channel.magnitudes[bin] = mix(smear, magnitude, channel.magnitudes[bin]);
+    }
+    for (bin, &smeared) in channel.magnitudes.iter().enumerate() {
+        let (re, im) = (channel.re[bin], channel.im[bin]);
+        let magnitude = re.hypot(im);
+        let (new_re, new_im) = if magnitude > 1e-12 {
+            (re / magnitude * smeared, im / magnitude * smeared)
+        } else {
+            (smeared, 0.)
+        };
+        channel.re[bin] = new_re;
+        channel.im[bin] = new_im;
+        // Keep the spectrum of a real signal
+        if bin > 0 && bin < frame_len / 2 {
+            channel.re[frame_len - bin] = new_re;
+            channel.im[frame_len - bin] = -new_im;
+        }
+    }
+    fft.inverse(&mut channel.re, &mut channel.im);
+}
+
+/// Switches a voice to spectral granulation with FFT frames of `frame_len` samples, a power of two
+/// from 256 to 4096.  `shuffle` is the fraction of bins whose magnitudes are swapped with a random
+/// neighbour's and `smear` how much of the magnitudes carries over from frame to frame, both from
+/// 0 to 1.  Returns false if the frame length isn't supported.
+pub fn set_voice_spectral(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    frame_len: usize,
+    shuffle: f32,
+    smear: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT
+        || !SpectralSettings::is_valid_frame_len(frame_len)
+        || !shuffle.is_finite()
+        || !smear.is_finite()
+    {
+        return false;
+    }
+    let settings = SpectralSettings {
+        frame_len,
+        shuffle: clamp(0., 1., shuffle),
+        // Magnitudes that carry over completely would never change again
+        smear: clamp(0., 0.999, smear),
+    };
+    let voice = &mut ctx.voices[voice_ix];
+    match &mut voice.spectral {
+        Some(spectral) if spectral.settings.frame_len == frame_len => spectral.settings = settings,
+        spectral => *spectral = Some(SpectralGranulator::new(settings)),
+    }
+    true
+}
+
+/// Switches a voice back from spectral to time-domain granulation
+pub fn clear_voice_spectral(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.spectral = None;
+    }
+}
+
+#[test]
+fn spectral_frames_resynthesize_the_waveform_until_rearranged() {
+    let samples: Vec<f32> = (0..8192).map(|i| (i as f32 * 0.05).sin()).collect();
+    let channels = WaveformChannels {
+        left: samples[..].into(),
+        right: None,
+    };
+    let mut rng = crate::common::rng();
+    let settings = SpectralSettings {
+        frame_len: 512,
+        shuffle: 0.,
+        smear: 0.,
+    };
+    assert!(!SpectralSettings::is_valid_frame_len(500));
+    let play = |settings: SpectralSettings, rng: &mut rand::rngs::StdRng| {
+        let mut spectral = SpectralGranulator::new(settings);
+        let mut output = Vec::new();
+        for i in 0..4096 {
+            if spectral.needs_frame() {
+                spectral.add_frame(channels, i as f32, 1., rng);
+            }
+            output.push(spectral.next_sample(false).0);
+        }
+        output
+    };
+
+    // Once the frames overlap fully, reading along the waveform reconstructs it
+    let output = play(settings, &mut rng);
+    for i in 512..4096 {
+        assert!((output[i] - samples[i]).abs() < 1e-3, "{}", i);
+    }
+
+    // Shuffling moves the energy of the sine to other frequencies
+    let shuffled = play(
+        SpectralSettings {
+            shuffle: 1.,
+            ..settings
+        },
+        &mut rng,
+    );
+    let error: f32 = (512..4096)
+        .map(|i| (shuffled[i] - samples[i]).abs())
+        .sum::<f32>()
+        / 3584.;
+    assert!(error > 0.1, "{}", error);
+}
+
+#[test]
+fn spectral_voices_resynthesize_instead_of_spawning_grains() {
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+
+    let mut ctx = GranularCtx {
+        waveform: (0..4096).map(|i| (i as f32 * 0.05).sin()).collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 4095.);
+    ctx.params
+        .set_target(ParamId::Voice(1, VoiceParam::Gain), 0.);
+    assert!(!set_voice_spectral(&mut ctx, 0, 1000, 0., 0.));
+    assert!(set_voice_spectral(&mut ctx, 0, 512, 0.5, 0.5));
+    let targets = *ctx.params.target();
+    let mut peak: f32 = 0.;
+    for _ in 0..16 {
+        ctx.render(&targets);
+        peak = ctx
+            .rendered_output
+            .iter()
+            .fold(peak, |peak, s| peak.max(s.abs()));
+    }
+    assert!(ctx.voices[0].grains.is_empty());
+    assert!(peak > 0.01);
+
+    clear_voice_spectral(&mut ctx, 0);
+    assert!(ctx.voices[0].spectral.is_none());
+}
@@ -0,0 +1,177 @@
+//! Pitch spray.  A voice with one transposes every grain it spawns by a random offset of up to
+//! its spread either way, snapped to the nearest of a set of intervals within the octave if it
+//! has any and then folded by whole octaves into its range.  A two-octave spread folded into
+//! 0 to 2 octaves above the voice's pitch and snapped to 0 and 7 semitones keeps a sprayed cloud
+//! on upward octaves and fifths, however wide the spread.
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use rand::Rng;
+
+/// Most intervals grains can be snapped to
+pub const MAX_SPRAY_INTERVALS: usize = 12;
+/// Widest spread and range, in octaves either way
+pub const MAX_SPRAY_OCTAVES: f32 = 4.;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PitchSpray {
+    /// Largest random offset in semitones either way
+    spread: f32,
+    /// Lowest and highest offsets in semitones that offsets are folded into
+    low: f32,
+    high: f32,
+    /// Semitones above each octave that offsets are snapped to, from 0 to 12
+    intervals: Vec<f32>,
+}
+
+impl PitchSpray {
+    /// Returns None if `low` is above `high` or there are more than `MAX_SPRAY_INTERVALS`
+    /// intervals
+    pub fn new(spread: f32, low: f32, high: f32, intervals: &[f32]) -> Option<Self> {
+        if low > high || intervals.len() > MAX_SPRAY_INTERVALS {
+            return None;
+        }
+        Some(PitchSpray {
+            spread,
+            low,
+            high,
+            intervals: intervals
+                .iter()
+                .map(|interval| interval.rem_euclid(12.))
+                .collect(),
+        })
+    }
+
+    /// Snaps and folds an offset in semitones.  Ranges narrower than an octave may not hold a
+    /// fold of every offset, which are then clamped into them.
+    pub fn fold(&self, semitones: f32) -> f32 {
+        let octave = (semitones / 12.).floor() * 12.;
+        // Intervals in the octaves either side can be nearer
+        let mut semitones = [octave - 12., octave, octave + 12.]
+            .iter()
+            .flat_map(|octave| self.intervals.iter().map(move |interval| octave + interval))
+            .min_by(|a, b| (a - semitones).abs().total_cmp(&(b - semitones).abs()))
+            .unwrap_or(semitones);
+        if semitones > self.high {
+            semitones -= ((semitones - self.high) / 12.).ceil() * 12.;
+        }
+        if semitones < self.low {
+            semitones += ((self.low - semitones) / 12.).ceil() * 12.;
+        }
+        semitones.clamp(self.low, self.high)
+    }
+
+    /// Transposition in semitones of a new grain
+    pub fn pick(&self, rng: &mut impl Rng) -> f32 {
+        let semitones = if self.spread > 0. {
+            rng.gen_range(-self.spread..=self.spread)
+        } else {
+            0.
+        };
+        self.fold(semitones)
+    }
+}
+
+/// Transposes every grain a voice spawns by a random offset of up to `spread_semitones` (0 to 48)
+/// either way, snapped to the nearest of `intervals` in semitones above each octave if any are
+/// given, and folded by whole octaves into `low_octaves` to `high_octaves` (-4 to 4) from the
+/// voice's pitch.  Returns false and changes nothing if the range is upside down or there are
+/// more than `MAX_SPRAY_INTERVALS` intervals.
+pub fn set_voice_pitch_spray(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    spread_semitones: f32,
+    low_octaves: f32,
+    high_octaves: f32,
+    intervals: &[f32],
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT
+        || ![spread_semitones, low_octaves, high_octaves]
+            .iter()
+            .chain(intervals)
+            .all(|value| value.is_finite())
+    {
+        return false;
+    }
+    let max_octaves = MAX_SPRAY_OCTAVES;
+    let Some(spray) = PitchSpray::new(
+        clamp(0., max_octaves * 12., spread_semitones),
+        clamp(-max_octaves, max_octaves, low_octaves) * 12.,
+        clamp(-max_octaves, max_octaves, high_octaves) * 12.,
+        intervals,
+    ) else {
+        return false;
+    };
+    ctx.voices[voice_ix].pitch_spray = Some(spray);
+    true
+}
+
+/// Stops transposing a voice's grains by random offsets
+pub fn clear_voice_pitch_spray(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.pitch_spray = None;
+    }
+}
+
+#[test]
+fn sprayed_pitches_fold_into_the_range() {
+    assert_eq!(PitchSpray::new(12., 1., 0., &[]), None);
+
+    let within_an_octave = PitchSpray::new(48., -12., 12., &[]).unwrap();
+    assert_eq!(within_an_octave.fold(5.), 5.);
+    assert_eq!(within_an_octave.fold(19.), 7.);
+    assert_eq!(within_an_octave.fold(-31.), -7.);
+
+    let octaves_and_fifths = PitchSpray::new(24., 0., 24., &[0., 7.]).unwrap();
+    assert_eq!(octaves_and_fifths.fold(-5.), 7.);
+    assert_eq!(octaves_and_fifths.fold(-2.), 0.);
+    assert_eq!(octaves_and_fifths.fold(11.5), 12.);
+    assert_eq!(octaves_and_fifths.fold(4.), 7.);
+
+    let mut rng = crate::common::rng();
+    for _ in 0..1000 {
+        let semitones = octaves_and_fifths.pick(&mut rng);
+        assert!(
+            [0., 7., 12., 19., 24.].contains(&semitones),
+            "{}",
+            semitones
+        );
+    }
+}
+
+#[test]
+fn sprayed_grains_stay_on_octaves_and_fifths() {
+    use super::params::{GlobalParam, ParamId};
+    use super::{sine_ctx, spawn_events};
+
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    assert!(!set_voice_pitch_spray(&mut ctx, 0, 24., 1., 0., &[]));
+    assert!(set_voice_pitch_spray(&mut ctx, 0, 24., 0., 2., &[0., 7.]));
+    ctx.grain_trace.set_enabled(true);
+    let targets = *ctx.params.target();
+    for _ in 0..256 {
+        ctx.render(&targets);
+    }
+    let ratios: Vec<f32> = spawn_events(&mut ctx, 0)
+        .iter()
+        .map(|event| event.sample_playback_ratio)
+        .collect();
+    assert!(ratios.len() >= 4);
+    let allowed = [0., 7., 12., 19., 24.].map(|semitones: f32| (semitones / 12.).exp2());
+    assert!(ratios
+        .iter()
+        .all(|ratio| allowed.iter().any(|allowed| (ratio - allowed).abs() < 1e-4)));
+    assert!(ratios.iter().any(|&ratio| ratio != ratios[0]));
+
+    clear_voice_pitch_spray(&mut ctx, 0);
+    assert!(ctx.voices[0].pitch_spray.is_none());
+}
@@ -0,0 +1,98 @@
+//! Grain scheduler statistics, published once per frame for UIs and tests.  Each voice reports
+//! four values in this order: grains spawned during the frame, grains active at the end of it,
+//! their average length in samples and the effective density in grains per second.  Spawns are
+//! too sparse within a single frame to give a stable rate, so density is averaged over roughly
+//! `DENSITY_AVERAGING_SECONDS`.
+
+use super::params::VOICE_COUNT;
+use super::GranularVoice;
+use super::{ctx_mut, GranularCtx};
+
+const DENSITY_AVERAGING_SECONDS: f32 = 0.5;
+
+pub const STATS_PER_VOICE: usize = 4;
+pub const STATS_VALUE_COUNT: usize = VOICE_COUNT * STATS_PER_VOICE;
+
+#[derive(Clone, Copy)]
+pub struct GrainStats {
+    spawned: [u32; VOICE_COUNT],
+    pub values: [f32; STATS_VALUE_COUNT],
+}
+
+impl Default for GrainStats {
+    fn default() -> Self {
+        GrainStats {
+            spawned: [0; VOICE_COUNT],
+            values: [0.; STATS_VALUE_COUNT],
+        }
+    }
+}
+
+impl GrainStats {
+    #[inline]
+    pub fn grain_spawned(&mut self, voice_ix: usize) {
+        self.spawned[voice_ix] += 1;
+    }
+
+    pub fn finish_frame(&mut self, voices: &[GranularVoice], frame_len: usize, sample_rate: f32) {
+        let frame_seconds = frame_len as f32 / sample_rate;
+        let density_weight = 1. - (-frame_seconds / DENSITY_AVERAGING_SECONDS).exp();
+        for (voice_ix, voice) in voices.iter().enumerate() {
+            let active = voice.grains.len();
+            let average_len = if active == 0 {
+                0.
+            } else {
+                voice
+                    .grains
+                    .iter()
+                    .map(|grain| grain.len_samples)
+                    .sum::<f32>()
+                    / active as f32
+            };
+            let values = &mut self.values[voice_ix * STATS_PER_VOICE..][..STATS_PER_VOICE];
+            values[0] = self.spawned[voice_ix] as f32;
+            values[1] = active as f32;
+            values[2] = average_len;
+            let frame_density = self.spawned[voice_ix] as f32 / frame_seconds;
+            values[3] += (frame_density - values[3]) * density_weight;
+        }
+        self.spawned = [0; VOICE_COUNT];
+    }
+
+    pub fn clear(&mut self) {
+        *self = GrainStats::default();
+    }
+}
+
+/// Returns a pointer to the `STATS_VALUE_COUNT` grain statistics of the last frame
+pub fn get_grain_stats(ctx: *mut GranularCtx) -> *const f32 {
+    match ctx_mut(ctx) {
+        Some(ctx) => ctx.grain_stats.values.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+#[test]
+fn density_matches_spawn_rate() {
+    use super::params::{GlobalParam, ParamId, VoiceParam};
+    use super::GranularCtx;
+
+    let mut ctx = GranularCtx {
+        waveform: vec![0.5; 44100],
+        ..Default::default()
+    };
+    let mut targets = *ctx.params.target();
+    targets.set(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    for voice_ix in 0..VOICE_COUNT {
+        targets.set(
+            ParamId::Voice(voice_ix, VoiceParam::SamplesBetweenGrains),
+            441.,
+        );
+    }
+    for _ in 0..1000 {
+        ctx.render(&targets);
+    }
+    let density = ctx.grain_stats.values[3];
+    assert!((density - 100.).abs() < 5., "{}", density);
+    assert_eq!(ctx.grain_stats.values[2], 800.);
+}
@@ -0,0 +1,31 @@
+//! Status flags describing how the engine handled the most recent render.  They're recomputed
+//! every frame and exposed to the host as a bitfield so it can warn the user about bad input
+//! instead of the engine silently working around it.
+
+/// The selection extended outside of the waveform and was clamped to fit
+pub const SELECTION_CLAMPED: u32 = 1 << 0;
+/// The selection end was before its start and the two were swapped
+pub const SELECTION_SWAPPED: u32 = 1 << 1;
+/// The selection was entirely outside of the waveform so the full buffer is used instead
+pub const SELECTION_FALLBACK: u32 = 1 << 2;
+/// No waveform has been loaded, so the output is silent
+pub const WAVEFORM_EMPTY: u32 = 1 << 3;
+/// The selection has zero length, so the output is silent
+pub const SELECTION_EMPTY: u32 = 1 << 4;
+/// The safety limiter reduced gain or the output had to be hard clamped
+pub const LIMITING_ACTIVE: u32 = 1 << 5;
+/// At least one output sample exceeded ±1 and was hard clamped
+pub const OUTPUT_CLIPPED: u32 = 1 << 6;
+/// The selection reached past the valid length declared by the host, so the frame was rejected
+/// and rendered silent rather than reading samples the host never wrote
+pub const SELECTION_OUT_OF_BOUNDS: u32 = 1 << 7;
+/// The packed parameter block had a header the engine can't read, so the previous parameters
+/// were kept
+pub const PARAM_BLOCK_INVALID: u32 = 1 << 8;
+/// A call on the instance panicked.  It outputs silence and ignores every call until it's freed.
+/// Only builds that unwind on panic can catch one, so abort builds never set it.
+#[cfg(panic = "unwind")]
+pub const INSTANCE_POISONED: u32 = 1 << 9;
+/// The waveform buffer was handed to the host but hasn't been committed with the length it was
+/// allocated with, so the output is silent rather than granulating samples the host never wrote
+pub const WAVEFORM_UNCOMMITTED: u32 = 1 << 10;
@@ -0,0 +1,305 @@
+//! Beat-synced stutter.  The engine keeps the last few seconds of its output, or of the live
+//! input, in a history buffer.  Engaging the stutter waits for the next line of its repeat grid
+//! while the transport plays, then cuts the last `capture_beats` beats out of the history and
+//! replaces the output with them, restarting from the top of the capture every repeat.  The repeat
+//! interval can ramp from one length to another over a number of beats, for the accelerating
+//! rolls of glitch and IDM, and every repeat is faded in and out so the cuts don't click.
+
+use super::overdub::OverdubSource;
+use super::transport::Transport;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Longest stretch of history kept, which caps the capture length
+const MAX_HISTORY_SECONDS: f32 = 8.;
+const FADE_MS: f32 = 2.;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StutterSettings {
+    pub source: OverdubSource,
+    pub capture_beats: f32,
+    /// Repeat interval when the stutter engages
+    pub repeat_beats: f32,
+    /// Repeat interval at the end of the ramp
+    pub end_repeat_beats: f32,
+    /// Beats from engaging to reaching `end_repeat_beats`, 0 for no ramp
+    pub ramp_beats: f32,
+}
+
+impl StutterSettings {
+    /// Repeat interval `beats` beats after engaging.  The interval ramps exponentially, so that
+    /// halving it takes as long at every length.
+    fn repeat_beats_at(&self, beats: f32) -> f32 {
+        if self.ramp_beats <= 0. {
+            return self.repeat_beats;
+        }
+        let progress = (beats / self.ramp_beats).min(1.);
+        self.repeat_beats * (self.end_repeat_beats / self.repeat_beats).powf(progress)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Recording,
+    /// Engaged and waiting for the grid
+    Armed,
+    Repeating {
+        /// History index of the first captured sample
+        capture_start: usize,
+        capture_len: usize,
+        /// Frames since the stutter started repeating
+        elapsed: f64,
+        /// Frames from engaging to the next retrigger
+        next_trigger: f64,
+        /// Frames into the current repeat, and its length
+        repeat_pos: usize,
+        repeat_len: usize,
+    },
+}
+
+#[derive(Clone)]
+pub struct Stutter {
+    pub settings: StutterSettings,
+    /// Mono, left and right samples, written in a loop
+    history: Vec<(f32, f32, f32)>,
+    write_ix: usize,
+    /// Number of samples written to the history, up to its length
+    recorded: usize,
+    fade_len: usize,
+    state: State,
+}
+
+impl Stutter {
+    pub fn new(settings: StutterSettings, sample_rate: f32) -> Self {
+        Stutter {
+            settings,
+            history: vec![(0., 0., 0.); (MAX_HISTORY_SECONDS * sample_rate) as usize],
+            write_ix: 0,
+            recorded: 0,
+            fade_len: (FADE_MS * 0.001 * sample_rate) as usize,
+            state: State::Recording,
+        }
+    }
+
+    pub fn set_engaged(&mut self, engaged: bool) {
+        self.state = match (engaged, self.state) {
+            (false, _) => State::Recording,
+            (true, State::Recording) => State::Armed,
+            (true, state) => state,
+        };
+    }
+
+    pub fn is_repeating(&self) -> bool {
+        matches!(self.state, State::Repeating { .. })
+    }
+
+    /// Takes the next sample of the source and returns what replaces the output while repeating
+    pub fn process(
+        &mut self,
+        source: (f32, f32, f32),
+        transport: &Transport,
+        sample_rate: f32,
+    ) -> Option<(f32, f32, f32)> {
+        if self.history.is_empty() {
+            return None;
+        }
+        if self.state == State::Armed {
+            let grid = transport.beats_to_frames(self.settings.repeat_beats, sample_rate);
+            if !transport.playing || transport.crosses_grid(grid) {
+                self.start_repeating(transport, sample_rate);
+            }
+        }
+        let State::Repeating {
+            capture_start,
+            capture_len,
+            elapsed,
+            next_trigger,
+            repeat_pos,
+            repeat_len,
+        } = &mut self.state
+        else {
+            self.history[self.write_ix] = source;
+            self.write_ix = (self.write_ix + 1) % self.history.len();
+            self.recorded = (self.recorded + 1).min(self.history.len());
+            return None;
+        };
+
+        if *elapsed >= *next_trigger {
+            let beat_frames = transport.beats_to_frames(1., sample_rate);
+            let beats = (*next_trigger / beat_frames) as f32;
+            let interval = beat_frames * self.settings.repeat_beats_at(beats) as f64;
+            *next_trigger += interval.max(1.);
+            *repeat_pos = 0;
+            *repeat_len = (*next_trigger - *elapsed).ceil().max(1.) as usize;
+        }
+        // Fade at both ends of the repeat and wherever it loops around the capture
+        let capture_pos = *repeat_pos % *capture_len;
+        let fade_len = self
+            .fade_len
+            .min((*repeat_len).min(*capture_len) / 4)
+            .max(1);
+        let edge_distance = capture_pos
+            .min(*capture_len - 1 - capture_pos)
+            .min(*repeat_pos)
+            .min(repeat_len.saturating_sub(*repeat_pos + 1));
+        let gain = (edge_distance as f32 / fade_len as f32).min(1.);
+        let (mono, left, right) = self.history[(*capture_start + capture_pos) % self.history.len()];
+        *repeat_pos += 1;
+        *elapsed += 1.;
+        Some((mono * gain, left * gain, right * gain))
+    }
+
+    fn start_repeating(&mut self, transport: &Transport, sample_rate: f32) {
+        let capture_frames = transport.beats_to_frames(self.settings.capture_beats, sample_rate);
+        let capture_len = (capture_frames.round() as usize).min(self.recorded);
+        if capture_len == 0 {
+            return;
+        }
+        let history_len = self.history.len();
+        self.state = State::Repeating {
+            capture_start: (self.write_ix + history_len - capture_len) % history_len,
+            capture_len,
+            elapsed: 0.,
+            next_trigger: 0.,
+            repeat_pos: 0,
+            repeat_len: 1,
+        };
+    }
+}
+
+/// Turns on the stutter effect, which keeps a history of the output, or of the audio input for a
+/// `source` of 1, to repeat once engaged.  When it engages, the last `capture_beats` beats are
+/// repeated from the top every `repeat_beats` beats, ramping to every `end_repeat_beats` beats over
+/// `ramp_beats` beats.  Changing the settings of a stutter that's on keeps its history.  Returns
+/// false for an unknown source or non-finite lengths.
+pub fn set_stutter_mode(
+    ctx: *mut GranularCtx,
+    source: u32,
+    capture_beats: f32,
+    repeat_beats: f32,
+    end_repeat_beats: f32,
+    ramp_beats: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    let Some(source) = OverdubSource::from_index(source) else {
+        return false;
+    };
+    if ![capture_beats, repeat_beats, end_repeat_beats, ramp_beats]
+        .iter()
+        .all(|beats| beats.is_finite())
+    {
+        return false;
+    }
+    let settings = StutterSettings {
+        source,
+        capture_beats: clamp(1. / 64., 16., capture_beats),
+        repeat_beats: clamp(1. / 64., 16., repeat_beats),
+        end_repeat_beats: clamp(1. / 64., 16., end_repeat_beats),
+        ramp_beats: clamp(0., 64., ramp_beats),
+    };
+    match &mut ctx.stutter {
+        Some(stutter) => stutter.settings = settings,
+        None => ctx.stutter = Some(Stutter::new(settings, ctx.sample_rate)),
+    }
+    true
+}
+
+/// Turns the stutter effect off and drops its history
+pub fn clear_stutter_mode(ctx: *mut GranularCtx) {
+    if let Some(ctx) = ctx_mut(ctx) {
+        ctx.stutter = None;
+    }
+}
+
+/// Whether the stutter is repeating, rather than off, recording or waiting for the grid
+pub fn is_stutter_repeating(ctx: *mut GranularCtx) -> bool {
+    ctx_mut(ctx)
+        .and_then(|ctx| ctx.stutter.as_ref())
+        .is_some_and(Stutter::is_repeating)
+}
+
+#[test]
+fn repeats_the_capture_on_a_ramping_grid() {
+    let settings = StutterSettings {
+        source: OverdubSource::Output,
+        capture_beats: 1.,
+        repeat_beats: 0.5,
+        end_repeat_beats: 0.125,
+        ramp_beats: 2.,
+    };
+    assert_eq!(settings.repeat_beats_at(0.), 0.5);
+    assert_eq!(settings.repeat_beats_at(1.), 0.25);
+    assert_eq!(settings.repeat_beats_at(10.), 0.125);
+
+    // One beat at 120 BPM and 1 kHz is 500 samples
+    let mut transport = Transport::default();
+    let mut stutter = Stutter::new(settings, 1000.);
+    for ix in 0..1000 {
+        assert_eq!(
+            stutter.process((ix as f32, 0., 0.), &transport, 1000.),
+            None
+        );
+    }
+    stutter.set_engaged(true);
+    let output: Vec<f32> = (0..1000)
+        .map(|_| stutter.process((0., 0., 0.), &transport, 1000.).unwrap().0)
+        .collect();
+    assert!(stutter.is_repeating());
+    // The capture is the last beat, sample 500 onwards, restarted after 250 samples and then
+    // after a shrinking interval of 177
+    assert_eq!(output[200], 700.);
+    assert_eq!(output[300], 550.);
+    assert_eq!(output[477], 550.);
+
+    stutter.set_engaged(false);
+    assert!(!stutter.is_repeating());
+    assert_eq!(stutter.process((1., 1., 1.), &transport, 1000.), None);
+
+    // While the transport plays it waits for the repeat grid
+    transport.set(true, 120., 1.);
+    stutter.set_engaged(true);
+    for _ in 0..249 {
+        assert_eq!(stutter.process((0., 0., 0.), &transport, 1000.), None);
+        transport.tick();
+    }
+    assert!(stutter.process((0., 0., 0.), &transport, 1000.).is_some());
+}
+
+#[test]
+fn stutter_repeats_the_output_until_released() {
+    use super::params::{GlobalParam, ParamId};
+    use super::{send_command, sine_ctx, DEFAULT_SAMPLE_RATE};
+
+    let mut ctx = sine_ctx();
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 47999.);
+    assert!(!set_stutter_mode(&mut ctx, 2, 1., 0.25, 0.25, 0.));
+    // A sixteenth at 120 BPM, repeated every sixteenth
+    assert!(set_stutter_mode(&mut ctx, 0, 0.25, 0.25, 0.25, 0.));
+    let targets = *ctx.params.target();
+    for _ in 0..64 {
+        ctx.render(&targets);
+    }
+    send_command(&mut ctx, [7, 0, 1, 0]);
+    let mut output = Vec::new();
+    for _ in 0..64 {
+        ctx.render(&targets);
+        output.extend_from_slice(&ctx.rendered_output);
+    }
+    assert!(is_stutter_repeating(&mut ctx));
+    // The grid falls between samples, so the first repeat is rounded up
+    let repeat_len = (0.125 * DEFAULT_SAMPLE_RATE).ceil() as usize;
+    assert!(output[100..repeat_len - 100]
+        .iter()
+        .zip(&output[repeat_len + 100..])
+        .all(|(a, b)| a == b));
+    assert!(output.iter().any(|sample| sample.abs() > 0.01));
+
+    send_command(&mut ctx, [7, 0, 0, 0]);
+    ctx.render(&targets);
+    assert!(!is_stutter_repeating(&mut ctx));
+    clear_stutter_mode(&mut ctx);
+    assert!(ctx.stutter.is_none());
+}
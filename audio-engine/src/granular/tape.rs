@@ -0,0 +1,208 @@
+//! Tape character on the master output: wow, flutter, saturation and hiss, for a vintage-tape
+//! sound over the whole texture.  Like the vibrato, wow and flutter bend the pitch by sweeping the
+//! length of a short delay the output goes through, as an uneven capstan moves the tape past the
+//! head.  Wow is a slow random drift and flutter a faster regular wobble.  The saturation rounds
+//! off peaks like overdriven tape, oversampled twice, and the hiss is quiet white noise under
+//! everything.
+
+use rand::{rngs::StdRng, Rng};
+
+use super::vibrato::sweep_width;
+use super::{ctx_mut, GranularCtx};
+use crate::common;
+use crate::dsp::delay::ModulatedDelay;
+use crate::dsp::saturation::{SaturationModel, Saturator};
+use crate::dsp::{clamp, smooth};
+
+/// Largest swing of the delay either way at full wow
+const MAX_WOW_MS: f32 = 4.;
+/// Average time between the points the wow drifts towards
+const WOW_TARGET_SECONDS: f32 = 0.6;
+/// Time constant of each of the two smoothing stages that drifts the wow towards its target
+const WOW_SMOOTHING_SECONDS: f32 = 0.2;
+const FLUTTER_HZ: f32 = 6.;
+const MAX_FLUTTER_CENTS: f32 = 12.;
+const MAX_DRIVE: f32 = 5.;
+const SATURATION_OVERSAMPLING: usize = 2;
+/// Level of the hiss at full amount, -40 dBFS
+const MAX_HISS: f32 = 0.01;
+/// Smoothing of the sweep widths per sample, so that changing the amounts doesn't jump
+const WIDTH_SMOOTHING: f32 = 0.999;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct TapeSettings {
+    /// Amounts from 0 to 1
+    pub wow: f32,
+    pub flutter: f32,
+    pub saturation: f32,
+    pub hiss: f32,
+}
+
+#[derive(Clone)]
+pub struct MasterTape {
+    pub settings: TapeSettings,
+    rng: StdRng,
+    /// Random point from -1 to 1 the wow drifts towards, and samples until it picks another
+    wow_target: f32,
+    wow_countdown: usize,
+    /// The two smoothing stages of the drift, the second of which is its position
+    wow_drift: [f32; 2],
+    wow_width: f32,
+    /// Position of the flutter in its cycle, from 0 to 1
+    flutter_phase: f32,
+    flutter_width: f32,
+    /// Left, right and mono mix
+    delays: [ModulatedDelay; 3],
+    saturators: [Saturator; 3],
+}
+
+impl MasterTape {
+    pub fn new(settings: TapeSettings, sample_rate: f32) -> Self {
+        let max_wow = MAX_WOW_MS * 0.001 * sample_rate;
+        let max_flutter = sweep_width(FLUTTER_HZ, MAX_FLUTTER_CENTS, sample_rate);
+        let delay = ModulatedDelay::new((2. * (max_wow + max_flutter)).ceil() as usize + 1);
+        MasterTape {
+            settings,
+            rng: common::rng(),
+            wow_target: 0.,
+            wow_countdown: 0,
+            wow_drift: [0.; 2],
+            wow_width: 0.,
+            flutter_phase: 0.,
+            flutter_width: 0.,
+            delays: [delay.clone(), delay.clone(), delay],
+            saturators: [Saturator::new(
+                SaturationModel::Tanh,
+                1.,
+                SATURATION_OVERSAMPLING,
+                sample_rate,
+            ); 3],
+        }
+    }
+
+    pub fn reseed(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    /// The delay wow and flutter centre on once they settle, plus the delay of the oversampled
+    /// saturation
+    pub fn latency_samples(&self, sample_rate: f32) -> f32 {
+        let settings = self.settings;
+        let wow = settings.wow * MAX_WOW_MS * 0.001 * sample_rate;
+        let flutter = sweep_width(
+            FLUTTER_HZ,
+            settings.flutter * MAX_FLUTTER_CENTS,
+            sample_rate,
+        );
+        let saturation = if settings.saturation > 0. {
+            self.saturators[0].latency_samples()
+        } else {
+            0.
+        };
+        wow + flutter + saturation
+    }
+
+    /// Runs the left and right channels and the mono mix through the tape
+    pub fn process(&mut self, samples: [f32; 3], sample_rate: f32) -> [f32; 3] {
+        let settings = self.settings;
+        let wow_width = settings.wow * MAX_WOW_MS * 0.001 * sample_rate;
+        smooth(&mut self.wow_width, wow_width, WIDTH_SMOOTHING);
+        let flutter_width = sweep_width(
+            FLUTTER_HZ,
+            settings.flutter * MAX_FLUTTER_CENTS,
+            sample_rate,
+        );
+        smooth(&mut self.flutter_width, flutter_width, WIDTH_SMOOTHING);
+
+        if self.wow_countdown == 0 {
+            self.wow_target = self.rng.gen_range(-1.0..1.0);
+            let seconds = WOW_TARGET_SECONDS * self.rng.gen_range(0.5..1.5);
+            self.wow_countdown = (seconds * sample_rate) as usize;
+        }
+        self.wow_countdown = self.wow_countdown.saturating_sub(1);
+        // Two stages keep the drift's speed, and so the pitch, from jumping at each new target
+        let coefficient = (-1. / (WOW_SMOOTHING_SECONDS * sample_rate)).exp();
+        let [first, second] = &mut self.wow_drift;
+        smooth(first, self.wow_target, coefficient);
+        smooth(second, *first, coefficient);
+        self.flutter_phase = (self.flutter_phase + FLUTTER_HZ / sample_rate).fract();
+
+        let delay = self.wow_width * (1. + self.wow_drift[1])
+            + self.flutter_width * (1. - (self.flutter_phase * std::f32::consts::TAU).cos());
+        let drive = 1. + settings.saturation * (MAX_DRIVE - 1.);
+        let hiss = settings.hiss * MAX_HISS;
+        let hiss_left = hiss * self.rng.gen_range(-1.0..1.0);
+        let hiss_right = hiss * self.rng.gen_range(-1.0..1.0);
+        let hiss = [hiss_left, hiss_right, (hiss_left + hiss_right) * 0.5];
+
+        let mut output = [0.; 3];
+        for (ix, delay_line) in self.delays.iter_mut().enumerate() {
+            let mut sample = delay_line.process(samples[ix], delay);
+            if settings.saturation > 0. {
+                let saturator = &mut self.saturators[ix];
+                saturator.drive = drive;
+                sample = saturator.process(sample);
+            }
+            output[ix] = sample + hiss[ix];
+        }
+        output
+    }
+}
+
+/// Runs the master output through a simulated tape machine, with amounts from 0 to 1 of `wow`, a
+/// slow random drift of its pitch, `flutter`, a faster wobble of it, `saturation` and `hiss`.
+/// Changing the amounts of a tape that's on doesn't restart it, and all of them at 0 turn it off.
+pub fn set_master_tape(ctx: *mut GranularCtx, wow: f32, flutter: f32, saturation: f32, hiss: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    let amounts = [wow, flutter, saturation, hiss];
+    if !amounts.iter().all(|amount| amount.is_finite()) {
+        return;
+    }
+    let settings = TapeSettings {
+        wow: clamp(0., 1., wow),
+        flutter: clamp(0., 1., flutter),
+        saturation: clamp(0., 1., saturation),
+        hiss: clamp(0., 1., hiss),
+    };
+    let sample_rate = ctx.sample_rate;
+    match &mut ctx.master_tape {
+        _ if settings == TapeSettings::default() => ctx.master_tape = None,
+        Some(tape) => tape.settings = settings,
+        tape => *tape = Some(MasterTape::new(settings, sample_rate)),
+    }
+}
+
+#[test]
+fn tape_wobbles_the_pitch_and_adds_hiss() {
+    let sample_rate = 1000.;
+    let settings = TapeSettings {
+        wow: 1.,
+        flutter: 1.,
+        ..Default::default()
+    };
+    let mut tape = MasterTape::new(settings, sample_rate);
+    tape.reseed(rand::SeedableRng::seed_from_u64(1));
+    // The slope of a delayed ramp is the pitch
+    let output: Vec<f32> = (0..4000)
+        .map(|ix| tape.process([ix as f32; 3], sample_rate)[0])
+        .collect();
+    let slopes: Vec<f32> = output[1000..].windows(2).map(|w| w[1] - w[0]).collect();
+    assert!(slopes.iter().any(|&slope| slope < 0.995));
+    assert!(slopes.iter().any(|&slope| slope > 1.005));
+    // No more than a semitone and a bit either way
+    assert!(slopes.iter().all(|&slope| (0.92..1.08).contains(&slope)));
+
+    tape.settings = TapeSettings {
+        hiss: 1.,
+        ..Default::default()
+    };
+    let silence: Vec<f32> = (0..8000)
+        .map(|_| tape.process([0.; 3], sample_rate)[1])
+        .collect();
+    let peak = silence[4000..]
+        .iter()
+        .fold(0f32, |peak, s| peak.max(s.abs()));
+    assert!(peak > MAX_HISS * 0.5 && peak <= MAX_HISS, "{}", peak);
+}
@@ -0,0 +1,270 @@
+//! Texture presets.  The generator measures the loaded waveform and proposes a parameter set for
+//! one of a handful of named styles, as a starting point for new users to tweak from.  Grain
+//! sizes follow the pitch of the waveform where it has one, selections centre on its loudest part
+//! and filters sit relative to its brightness, so the same style suits different material.
+
+use std::ops::Range;
+
+use super::analysis::{region_centroid, region_energy};
+use super::params::{GlobalParam, GranularParams, ParamInfo, VoiceParam, VOICE_COUNT};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::pitch::estimate_pitch;
+
+/// Length of the windows searched for the loudest part of the waveform
+const WINDOW_MS: f32 = 100.;
+
+/// Parameters a style sets on one voice
+type VoiceValues = [(VoiceParam, f32); 6];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TextureStyle {
+    /// Dense, scattered grains from the whole waveform
+    Cloud,
+    /// Back-to-back repeats of a short slice of the loudest part
+    Stutter,
+    /// Long, slowly drifting grains at pitch and an octave down, detuned against each other
+    Drone,
+    /// Bright grains an octave and an octave and a fifth up, sent to the delay
+    Shimmer,
+}
+
+impl TextureStyle {
+    pub const ALL: [TextureStyle; 4] = [
+        TextureStyle::Cloud,
+        TextureStyle::Stutter,
+        TextureStyle::Drone,
+        TextureStyle::Shimmer,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TextureStyle::Cloud => "cloud",
+            TextureStyle::Stutter => "stutter",
+            TextureStyle::Drone => "drone",
+            TextureStyle::Shimmer => "shimmer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|style| style.name() == name)
+    }
+}
+
+/// What the generator needs to know about the waveform
+#[derive(Clone, PartialEq, Debug)]
+pub struct SampleProfile {
+    pub len: usize,
+    pub sample_rate: f32,
+    /// The loudest window of the waveform
+    pub loudest: Range<usize>,
+    /// Spectral centroid of the loudest window
+    pub centroid_hz: f32,
+    /// Pitch of the loudest window, if it has one
+    pub pitch_hz: Option<f32>,
+}
+
+impl SampleProfile {
+    pub fn analyze(samples: &[f32], sample_rate: f32) -> Self {
+        let window_len = ((WINDOW_MS * 0.001 * sample_rate) as usize).max(1);
+        let (loudest_ix, _) = samples.chunks(window_len).enumerate().fold(
+            (0, -1.),
+            |(loudest_ix, loudest_energy), (window_ix, window)| {
+                let energy = region_energy(window);
+                if energy > loudest_energy {
+                    (window_ix, energy)
+                } else {
+                    (loudest_ix, loudest_energy)
+                }
+            },
+        );
+        let start = (loudest_ix * window_len).min(samples.len());
+        let loudest = start..(start + window_len).min(samples.len());
+        let window = &samples[loudest.clone()];
+        SampleProfile {
+            len: samples.len(),
+            sample_rate,
+            centroid_hz: if window.is_empty() {
+                0.
+            } else {
+                region_centroid(window, sample_rate)
+            },
+            pitch_hz: estimate_pitch(window, sample_rate),
+            loudest,
+        }
+    }
+
+    fn ms(&self, ms: f32) -> f32 {
+        ms * 0.001 * self.sample_rate
+    }
+
+    /// `ms` rounded to a whole number of pitch periods, so that repeating grains stay in tune
+    fn periods(&self, ms: f32) -> f32 {
+        let samples = self.ms(ms);
+        match self.pitch_hz {
+            Some(pitch_hz) => {
+                let period = self.sample_rate / pitch_hz;
+                (samples / period).round().max(1.) * period
+            }
+            None => samples,
+        }
+    }
+
+    /// `len` samples centred on the loudest window, within the waveform
+    fn around_loudest(&self, len: f32) -> (f32, f32) {
+        let last = self.len.saturating_sub(1) as f32;
+        let centre = (self.loudest.start + self.loudest.end) as f32 * 0.5;
+        let start = (centre - len * 0.5).clamp(0., (last - len).max(0.));
+        (start, (start + len).min(last))
+    }
+}
+
+/// Proposed parameter set for `style`.  Parameters the style doesn't care about keep their
+/// defaults.
+pub fn texture_preset(style: TextureStyle, profile: &SampleProfile) -> GranularParams {
+    let mut params = GranularParams::default();
+    let mut set_global = |param: GlobalParam, value: f32| {
+        let ParamInfo { min, max, .. } = param.info();
+        params.set_global(param, value.clamp(min, max)).unwrap();
+    };
+    let whole = (0., profile.len.saturating_sub(1) as f32);
+    // Lowpass cutoffs at multiples of the brightness; negative cutoffs are highpass
+    let lowpass = |centroid_ratio: f32| (profile.centroid_hz * centroid_ratio).max(200.);
+    let (selection, voices): ((f32, f32), [VoiceValues; VOICE_COUNT]) = match style {
+        TextureStyle::Cloud => {
+            set_global(GlobalParam::GrainSize, profile.ms(80.));
+            let scatter = whole.1 * 0.5;
+            let voice = |pan: f32| {
+                [
+                    (VoiceParam::SamplesBetweenGrains, profile.ms(8.)),
+                    (VoiceParam::GrainStartRandomnessSamples, scatter),
+                    (VoiceParam::MovementSamplesPerSample, 0.05),
+                    (VoiceParam::Pan, pan),
+                    (VoiceParam::Gain, 0.7),
+                    (VoiceParam::FilterCutoff, lowpass(2.)),
+                ]
+            };
+            (whole, [voice(-0.6), voice(0.6)])
+        }
+        TextureStyle::Stutter => {
+            let grain_size = profile.periods(60.);
+            set_global(GlobalParam::GrainSize, grain_size);
+            set_global(GlobalParam::LinearSlopeLength, 0.1);
+            let voice = |gain: f32| {
+                [
+                    (VoiceParam::SamplesBetweenGrains, grain_size),
+                    (VoiceParam::GrainStartRandomnessSamples, 0.),
+                    (VoiceParam::MovementSamplesPerSample, 0.),
+                    (VoiceParam::Pan, 0.),
+                    (VoiceParam::Gain, gain),
+                    (VoiceParam::FilterCutoff, 0.),
+                ]
+            };
+            (
+                profile.around_loudest(grain_size * 4.),
+                [voice(1.), voice(0.)],
+            )
+        }
+        TextureStyle::Drone => {
+            let grain_size = profile.periods(250.);
+            set_global(GlobalParam::GrainSize, grain_size);
+            set_global(GlobalParam::DetuneSpread, 12.);
+            let voice = |speed: f32, pan: f32| {
+                [
+                    (VoiceParam::SamplesBetweenGrains, grain_size * 0.25),
+                    (VoiceParam::GrainStartRandomnessSamples, profile.ms(20.)),
+                    (VoiceParam::MovementSamplesPerSample, 0.01),
+                    (VoiceParam::SampleSpeedRatio, speed),
+                    (VoiceParam::Pan, pan),
+                    (VoiceParam::FilterCutoff, lowpass(1.)),
+                ]
+            };
+            (
+                profile.around_loudest(profile.ms(1000.)),
+                [voice(0.5, -0.4), voice(1., 0.4)],
+            )
+        }
+        TextureStyle::Shimmer => {
+            set_global(GlobalParam::GrainSize, profile.ms(120.));
+            set_global(GlobalParam::DetuneSpread, 8.);
+            let voice = |speed: f32, pan: f32| {
+                [
+                    (VoiceParam::SamplesBetweenGrains, profile.ms(25.)),
+                    (VoiceParam::GrainStartRandomnessSamples, profile.ms(200.)),
+                    (VoiceParam::SampleSpeedRatio, speed),
+                    (VoiceParam::Pan, pan),
+                    (VoiceParam::DelaySend, 0.35),
+                    (VoiceParam::FilterCutoff, -200.),
+                ]
+            };
+            (
+                profile.around_loudest(profile.ms(500.)),
+                [voice(2., -0.7), voice(3., 0.7)],
+            )
+        }
+    };
+    set_global(GlobalParam::SelectionStartSampleIx, selection.0);
+    set_global(GlobalParam::SelectionEndSampleIx, selection.1);
+    for (voice_ix, voice) in voices.into_iter().enumerate() {
+        for (param, value) in voice {
+            let ParamInfo { min, max, .. } = param.info();
+            params
+                .set_voice(voice_ix, param, value.clamp(min, max))
+                .unwrap();
+        }
+    }
+    params
+}
+
+/// Analyzes the loaded waveform and proposes parameter targets for the texture style named
+/// `style` ("cloud", "stutter", "drone" or "shimmer") as a `GranularParams` JSON document, which
+/// `set_params_json` applies.  Nothing changes until it's applied.  Returns an empty string for
+/// unknown styles.
+#[cfg(feature = "serde")]
+pub fn generate_texture_preset(ctx: *mut GranularCtx, style: &str) -> String {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return String::new();
+    };
+    let Some(style) = TextureStyle::from_name(style) else {
+        return String::new();
+    };
+    let profile = SampleProfile::analyze(&ctx.samples().decode(), ctx.sample_rate);
+    texture_preset(style, &profile).to_json()
+}
+
+#[test]
+fn presets_follow_the_material() {
+    use std::f32::consts::PI;
+
+    // A second of silence, then a second of a 200 Hz tone
+    let sample_rate = 48000.;
+    let samples: Vec<f32> = (0..96000)
+        .map(|i| {
+            if i < 48000 {
+                0.
+            } else {
+                (2. * PI * 200. * i as f32 / sample_rate).sin()
+            }
+        })
+        .collect();
+    let profile = SampleProfile::analyze(&samples, sample_rate);
+    assert!(profile.loudest.start >= 48000);
+    assert!((profile.pitch_hz.unwrap() - 200.).abs() < 2.);
+    assert_eq!(TextureStyle::from_name("drone"), Some(TextureStyle::Drone));
+    assert_eq!(TextureStyle::from_name("polka"), None);
+
+    // Stutters repeat a whole number of periods from the tone
+    let stutter = texture_preset(TextureStyle::Stutter, &profile);
+    let periods = stutter.global(GlobalParam::GrainSize) / 240.;
+    assert!((periods - periods.round()).abs() < 0.05, "{}", periods);
+    assert!(stutter.global(GlobalParam::SelectionStartSampleIx) >= 48000.);
+
+    for style in TextureStyle::ALL {
+        let params = texture_preset(style, &profile);
+        let selection_end = params.global(GlobalParam::SelectionEndSampleIx);
+        assert!(selection_end > params.global(GlobalParam::SelectionStartSampleIx));
+        assert!(selection_end < 96000.);
+    }
+    // Even an empty waveform gets a valid preset
+    let empty = SampleProfile::analyze(&[], sample_rate);
+    texture_preset(TextureStyle::Cloud, &empty);
+}
@@ -0,0 +1,270 @@
+//! Tilt EQ on the master output, a single control for darkening or brightening the whole texture,
+//! and on single grains, which each get a random tilt so that a cloud varies in brightness from
+//! grain to grain
+
+use rand::Rng;
+
+use super::params::VOICE_COUNT;
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+use crate::dsp::filters::tilt::{Tilt, TiltFilter};
+
+#[derive(Clone, Copy)]
+pub struct MasterTilt {
+    /// From -1, darkest, to 1, brightest
+    pub tilt: f32,
+    pub pivot_hz: f32,
+    coefficients: Tilt,
+    /// Left, right and mono mix
+    filters: [TiltFilter; 3],
+}
+
+impl MasterTilt {
+    pub fn new(tilt: f32, pivot_hz: f32, sample_rate: f32) -> Self {
+        MasterTilt {
+            tilt,
+            pivot_hz,
+            coefficients: Tilt::new(tilt, pivot_hz, sample_rate),
+            filters: [TiltFilter::default(); 3],
+        }
+    }
+
+    /// Changes the tilt and pivot without clearing the filters
+    pub fn set(&mut self, tilt: f32, pivot_hz: f32, sample_rate: f32) {
+        self.tilt = tilt;
+        self.pivot_hz = pivot_hz;
+        self.coefficients = Tilt::new(tilt, pivot_hz, sample_rate);
+    }
+
+    /// Tilts the mono mix and the left and right channels
+    pub fn process(&mut self, samples: [f32; 3]) -> [f32; 3] {
+        let [left, right, mono] = &mut self.filters;
+        let coefficients = &self.coefficients;
+        [
+            left.process(samples[0], coefficients),
+            right.process(samples[1], coefficients),
+            mono.process(samples[2], coefficients),
+        ]
+    }
+}
+
+/// Range of tilts a voice picks from for each grain it spawns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GrainTiltRange {
+    /// From -1, darkest, to 1, brightest
+    pub low: f32,
+    pub high: f32,
+    pub pivot_hz: f32,
+    sample_rate: f32,
+}
+
+impl GrainTiltRange {
+    /// None if the range is upside down
+    pub fn new(low: f32, high: f32, pivot_hz: f32, sample_rate: f32) -> Option<Self> {
+        (low <= high).then_some(GrainTiltRange {
+            low,
+            high,
+            pivot_hz,
+            sample_rate,
+        })
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// A filter for a new grain, with a tilt picked from the range
+    pub fn pick(&self, rng: &mut impl Rng) -> GrainTilt {
+        let tilt = if self.low < self.high {
+            rng.gen_range(self.low..=self.high)
+        } else {
+            self.low
+        };
+        GrainTilt {
+            coefficients: Tilt::new(tilt, self.pivot_hz, self.sample_rate),
+            filters: [TiltFilter::default(); 2],
+        }
+    }
+}
+
+/// A grain's own tilt filter, which starts from silence along with the grain
+#[derive(Clone, Copy)]
+pub struct GrainTilt {
+    coefficients: Tilt,
+    /// Left and right
+    filters: [TiltFilter; 2],
+}
+
+impl GrainTilt {
+    pub fn process(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let [left_filter, right_filter] = &mut self.filters;
+        (
+            left_filter.process(left, &self.coefficients),
+            right_filter.process(right, &self.coefficients),
+        )
+    }
+
+    /// Tilts a mono grain, which only needs one of the filters
+    pub fn process_mono(&mut self, sample: f32) -> f32 {
+        self.filters[0].process(sample, &self.coefficients)
+    }
+}
+
+/// Tilts the master output around `pivot_hz` (100 Hz to 10 kHz): a `tilt` from 0 to 1 brightens
+/// it by turning the highs up and the lows down by up to 6 dB, and one from 0 to -1 darkens it.  A
+/// tilt of 0 turns the EQ off.
+pub fn set_master_tilt(ctx: *mut GranularCtx, tilt: f32, pivot_hz: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !tilt.is_finite() || !pivot_hz.is_finite() {
+        return;
+    }
+    let tilt = clamp(-1., 1., tilt);
+    let pivot_hz = clamp(100., 10000., pivot_hz);
+    let sample_rate = ctx.sample_rate;
+    match &mut ctx.master_tilt {
+        _ if tilt == 0. => ctx.master_tilt = None,
+        Some(master_tilt) => master_tilt.set(tilt, pivot_hz, sample_rate),
+        master_tilt => *master_tilt = Some(MasterTilt::new(tilt, pivot_hz, sample_rate)),
+    }
+}
+
+/// Tilts every grain a voice spawns darker or brighter around `pivot_hz` (100 to 10000) by a
+/// random amount from `low_tilt` to `high_tilt`, each from -1, darkest, to 1, brightest, with the
+/// shelves of `set_master_tilt`.  Grains that are already playing keep their tilt.  Returns false
+/// and changes nothing if the range is upside down.
+pub fn set_voice_grain_tilt(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    low_tilt: f32,
+    high_tilt: f32,
+    pivot_hz: f32,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT
+        || ![low_tilt, high_tilt, pivot_hz]
+            .iter()
+            .all(|value| value.is_finite())
+    {
+        return false;
+    }
+    let Some(range) = GrainTiltRange::new(
+        clamp(-1., 1., low_tilt),
+        clamp(-1., 1., high_tilt),
+        clamp(100., 10000., pivot_hz),
+        ctx.sample_rate,
+    ) else {
+        return false;
+    };
+    ctx.voices[voice_ix].grain_tilt = Some(range);
+    true
+}
+
+/// Stops tilting the grains a voice spawns
+pub fn clear_voice_grain_tilt(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.grain_tilt = None;
+    }
+}
+
+#[test]
+fn grain_tilts_are_picked_from_the_range() {
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    assert!(GrainTiltRange::new(0.5, -0.5, 1000., 44100.).is_none());
+    let range = GrainTiltRange::new(-1., 1., 1000., 44100.).unwrap();
+    // Held lows tell the tilts apart: darker grains turn them up and brighter ones down
+    let lows: Vec<f32> = (0..64)
+        .map(|_| {
+            let mut tilt = range.pick(&mut rng);
+            (0..2000).map(|_| tilt.process_mono(1.)).last().unwrap()
+        })
+        .collect();
+    let (quietest, loudest) = lows.iter().fold((f32::MAX, f32::MIN), |(min, max), &low| {
+        (min.min(low), max.max(low))
+    });
+    assert!(quietest < 0.6 && loudest > 1.7, "{} {}", quietest, loudest);
+    assert!(lows.iter().all(|&low| (0.49..2.01).contains(&low)));
+
+    let fixed = GrainTiltRange::new(0., 0., 1000., 44100.).unwrap();
+    let mut tilt = fixed.pick(&mut rng);
+    let (left, right) = tilt.process(0.25, -0.5);
+    assert!((left - 0.25).abs() < 1e-6 && (right + 0.5).abs() < 1e-6);
+}
+
+#[test]
+fn master_tilt_trades_lows_for_highs() {
+    use super::params::{GlobalParam, ParamId};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut ctx = GranularCtx {
+        waveform: (0..8192).map(|_| rng.gen_range(-1.0..1.0)).collect(),
+        ..Default::default()
+    };
+    ctx.seed(1);
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 8191.);
+    let targets = *ctx.params.target();
+    // How much of the output's energy is in its sample-to-sample changes, which grows with
+    // brightness
+    let brightness = |ctx: &mut GranularCtx| {
+        let mut output = Vec::new();
+        for _ in 0..64 {
+            ctx.render(&targets);
+            output.extend_from_slice(&ctx.rendered_output);
+        }
+        let output = &output[4096..];
+        let energy: f32 = output.iter().map(|s| s * s).sum();
+        let change: f32 = output.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum();
+        change / energy
+    };
+    set_master_tilt(&mut ctx, -1., 1000.);
+    let dark = brightness(&mut ctx);
+    set_master_tilt(&mut ctx, 1., 1000.);
+    assert_eq!(ctx.master_tilt.unwrap().tilt, 1.);
+    let bright = brightness(&mut ctx);
+    assert!(bright > dark * 1.5, "{} {}", bright, dark);
+
+    set_master_tilt(&mut ctx, 0., 1000.);
+    assert!(ctx.master_tilt.is_none());
+}
+
+#[test]
+fn grain_tilt_changes_the_sound_of_new_grains() {
+    use super::test_targets;
+
+    let render = |tilted: bool| {
+        let mut ctx = GranularCtx {
+            waveform: (0..48000).map(|i| (i as f32 * 0.05).sin()).collect(),
+            ..Default::default()
+        };
+        let targets = test_targets(47999.);
+        if tilted {
+            assert!(!set_voice_grain_tilt(&mut ctx, 0, 1., -1., 1000.));
+            assert!(set_voice_grain_tilt(&mut ctx, 0, -1., -0.5, 200.));
+        }
+        for _ in 0..32 {
+            ctx.render(&targets);
+        }
+        let tilts = ctx.voices[0]
+            .grains
+            .iter()
+            .filter(|grain| grain.tilt.is_some());
+        assert_eq!(tilts.count() > 0, tilted);
+        (ctx.rendered_output, ctx)
+    };
+    let (plain, _) = render(false);
+    let (tilted, mut ctx) = render(true);
+    assert!(plain.iter().zip(&tilted).any(|(a, b)| (a - b).abs() > 0.01));
+
+    clear_voice_grain_tilt(&mut ctx, 0);
+    assert!(ctx.voices[0].grain_tilt.is_none());
+}
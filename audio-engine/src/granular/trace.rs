@@ -0,0 +1,347 @@
+//! Opt-in trace of grain events for debugging the scheduler and drawing grain histories.  While
+//! enabled, every grain spawn and end is recorded into a fixed-size ring buffer which the host
+//! drains periodically; if it falls behind, the oldest events are overwritten.
+//!
+//! For reconstructing a render, the trace can also keep a `DataTrack`, which records every grain
+//! onset plus the voices' playhead positions at a fixed interval.  It keeps the newest
+//! `TRACK_CAPACITY` rows, allocated when the track is started, so set the interval to fit the
+//! render into that.
+
+use std::fmt::Write;
+
+use super::params::VOICE_COUNT;
+use super::Grain;
+use super::{ctx_mut, GranularCtx};
+
+pub const TRACE_CAPACITY: usize = 4096;
+/// Rows a data track keeps before overwriting the oldest
+pub const TRACK_CAPACITY: usize = 1 << 16;
+/// Values per event when exported: timestamp in samples since tracing was enabled, kind, voice
+/// index, start position in samples, length in samples and playback ratio
+pub const TRACE_EVENT_VALUE_COUNT: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GrainEventKind {
+    Spawn = 0,
+    End = 1,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GrainEvent {
+    pub timestamp_samples: u64,
+    pub kind: GrainEventKind,
+    pub voice_ix: usize,
+    pub start_sample_ix: f32,
+    pub len_samples: f32,
+    pub sample_playback_ratio: f32,
+}
+
+impl GrainEvent {
+    pub fn values(&self) -> [f64; TRACE_EVENT_VALUE_COUNT] {
+        [
+            self.timestamp_samples as f64,
+            self.kind as u32 as f64,
+            self.voice_ix as f64,
+            self.start_sample_ix as f64,
+            self.len_samples as f64,
+            self.sample_playback_ratio as f64,
+        ]
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrackRowKind {
+    Playhead,
+    Onset,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TrackRow {
+    pub timestamp_samples: u64,
+    pub kind: TrackRowKind,
+    pub voice_ix: usize,
+    /// Read head position for playhead rows and start position for onsets, in samples
+    pub position: f32,
+    /// Length and playback ratio of the grain for onsets
+    pub grain: Option<(f32, f32)>,
+}
+
+/// Timestamped record of what the voices did over a render
+#[derive(Clone, Default)]
+pub struct DataTrack {
+    /// Samples between playhead rows
+    interval_samples: u64,
+    elapsed_samples: u64,
+    rows: Vec<TrackRow>,
+    /// Index of the oldest row once the buffer has wrapped
+    next_ix: usize,
+}
+
+impl DataTrack {
+    pub fn new(interval_samples: u32) -> Self {
+        DataTrack {
+            interval_samples: interval_samples.max(1) as u64,
+            rows: Vec::with_capacity(TRACK_CAPACITY),
+            ..Default::default()
+        }
+    }
+
+    /// The rows kept, oldest first
+    pub fn rows(&self) -> impl Iterator<Item = &TrackRow> {
+        self.rows[self.next_ix..]
+            .iter()
+            .chain(&self.rows[..self.next_ix])
+    }
+
+    #[inline]
+    fn push(&mut self, row: TrackRow) {
+        if self.rows.len() < TRACK_CAPACITY {
+            self.rows.push(row);
+        } else {
+            self.rows[self.next_ix] = row;
+            self.next_ix = (self.next_ix + 1) % TRACK_CAPACITY;
+        }
+    }
+
+    /// Advances by one sample, recording the voices' playheads if one is due
+    #[inline]
+    pub fn tick(&mut self, playheads: [f32; VOICE_COUNT]) {
+        if self.elapsed_samples.is_multiple_of(self.interval_samples) {
+            for (voice_ix, position) in playheads.into_iter().enumerate() {
+                self.push(TrackRow {
+                    timestamp_samples: self.elapsed_samples,
+                    kind: TrackRowKind::Playhead,
+                    voice_ix,
+                    position,
+                    grain: None,
+                });
+            }
+        }
+        self.elapsed_samples += 1;
+    }
+
+    fn onset(&mut self, voice_ix: usize, grain: &Grain) {
+        self.push(TrackRow {
+            timestamp_samples: self.elapsed_samples,
+            kind: TrackRowKind::Onset,
+            voice_ix,
+            position: grain.start_sample_ix,
+            grain: Some((grain.len_samples, grain.sample_playback_ratio)),
+        });
+    }
+
+    /// The rows as CSV with a header, leaving the grain columns of playhead rows empty
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("timestamp_samples,kind,voice,position,length,playback_ratio\n");
+        for row in self.rows() {
+            let kind = match row.kind {
+                TrackRowKind::Playhead => "playhead",
+                TrackRowKind::Onset => "onset",
+            };
+            let _ = write!(
+                csv,
+                "{},{},{},{}",
+                row.timestamp_samples, kind, row.voice_ix, row.position
+            );
+            let _ = match row.grain {
+                Some((len_samples, ratio)) => writeln!(csv, ",{},{}", len_samples, ratio),
+                None => writeln!(csv, ",,"),
+            };
+        }
+        csv
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct GrainTrace {
+    /// Recording of the whole render, kept independently of the event trace
+    pub track: Option<DataTrack>,
+    enabled: bool,
+    events: Vec<GrainEvent>,
+    /// Index of the oldest event once the buffer has wrapped
+    next_ix: usize,
+    elapsed_samples: u64,
+}
+
+impl GrainTrace {
+    /// Enabling starts a fresh trace with timestamps counted from now
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.events = if enabled {
+            Vec::with_capacity(TRACE_CAPACITY)
+        } else {
+            Vec::new()
+        };
+        self.next_ix = 0;
+        self.elapsed_samples = 0;
+    }
+
+    #[inline]
+    pub fn tick(&mut self) {
+        if self.enabled {
+            self.elapsed_samples += 1;
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, kind: GrainEventKind, voice_ix: usize, grain: &Grain) {
+        if let Some(track) = &mut self.track {
+            if kind == GrainEventKind::Spawn {
+                track.onset(voice_ix, grain);
+            }
+        }
+        if !self.enabled {
+            return;
+        }
+        let event = GrainEvent {
+            timestamp_samples: self.elapsed_samples,
+            kind,
+            voice_ix,
+            start_sample_ix: grain.start_sample_ix,
+            len_samples: grain.len_samples,
+            sample_playback_ratio: grain.sample_playback_ratio,
+        };
+        if self.events.len() < TRACE_CAPACITY {
+            self.events.push(event);
+        } else {
+            self.events[self.next_ix] = event;
+            self.next_ix = (self.next_ix + 1) % TRACE_CAPACITY;
+        }
+    }
+
+    /// Removes all recorded events and returns them oldest first
+    pub fn drain(&mut self) -> Vec<GrainEvent> {
+        let mut events = Vec::with_capacity(self.events.len());
+        events.extend_from_slice(&self.events[self.next_ix..]);
+        events.extend_from_slice(&self.events[..self.next_ix]);
+        self.events.clear();
+        self.next_ix = 0;
+        events
+    }
+}
+
+/// Turns the grain event trace on or off; see `GrainTrace`
+pub fn set_grain_trace(ctx: *mut GranularCtx, enabled: bool) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.grain_trace.set_enabled(enabled);
+}
+
+/// Drains the grain event trace, returning `TRACE_EVENT_VALUE_COUNT` values per event,
+/// oldest first
+pub fn get_grain_trace(ctx: *mut GranularCtx) -> Vec<f64> {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return Vec::new();
+    };
+    ctx.grain_trace
+        .drain()
+        .into_iter()
+        .flat_map(|event| event.values())
+        .collect()
+}
+
+/// Starts recording playhead positions every `interval_samples` and every grain onset into a
+/// fresh data track, allocated here, or stops and drops the track; see `DataTrack`
+pub fn set_data_track(ctx: *mut GranularCtx, enabled: bool, interval_samples: u32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.grain_trace.track = enabled.then(|| DataTrack::new(interval_samples));
+}
+
+/// Data track recorded so far as CSV, or an empty string if it isn't enabled
+pub fn export_data_track_csv(ctx: *mut GranularCtx) -> String {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return String::new();
+    };
+    ctx.grain_trace
+        .track
+        .as_ref()
+        .map_or_else(String::new, DataTrack::to_csv)
+}
+
+#[test]
+fn trace_keeps_the_newest_events() {
+    let grain = Grain {
+        len_samples: 100.,
+        start_sample_ix: 0.,
+        samples_read_so_far: 0.,
+        sample_playback_ratio: 1.,
+        gain: 1.,
+        retired: false,
+        reversed: false,
+        wrap: None,
+        depth: 0.,
+        glide: None,
+        tilt: None,
+        slot: None,
+    };
+    let mut trace = GrainTrace::default();
+    trace.record(GrainEventKind::Spawn, 0, &grain);
+    assert!(trace.drain().is_empty());
+
+    trace.set_enabled(true);
+    for _ in 0..TRACE_CAPACITY + 10 {
+        trace.record(GrainEventKind::Spawn, 1, &grain);
+        trace.tick();
+    }
+    let events = trace.drain();
+    assert_eq!(events.len(), TRACE_CAPACITY);
+    assert_eq!(events[0].timestamp_samples, 10);
+    assert_eq!(
+        events[TRACE_CAPACITY - 1].timestamp_samples,
+        TRACE_CAPACITY as u64 + 9
+    );
+    assert!(trace.drain().is_empty());
+}
+
+#[test]
+fn data_track_records_playheads_and_onsets() {
+    let grain = Grain {
+        len_samples: 100.,
+        start_sample_ix: 50.,
+        samples_read_so_far: 0.,
+        sample_playback_ratio: 2.,
+        gain: 1.,
+        retired: false,
+        reversed: false,
+        wrap: None,
+        depth: 0.,
+        glide: None,
+        tilt: None,
+        slot: None,
+    };
+    let mut trace = GrainTrace {
+        track: Some(DataTrack::new(2)),
+        ..Default::default()
+    };
+    for ix in 0..3 {
+        if ix == 1 {
+            trace.record(GrainEventKind::Spawn, 1, &grain);
+        }
+        trace.track.as_mut().unwrap().tick([ix as f32, 0.]);
+    }
+    assert_eq!(
+        trace.track.unwrap().to_csv(),
+        "timestamp_samples,kind,voice,position,length,playback_ratio\n\
+         0,playhead,0,0,,\n\
+         0,playhead,1,0,,\n\
+         1,onset,1,50,100,2\n\
+         2,playhead,0,2,,\n\
+         2,playhead,1,0,,\n"
+    );
+
+    // Past the capacity the oldest rows are overwritten
+    let mut track = DataTrack::new(1);
+    for ix in 0..TRACK_CAPACITY / VOICE_COUNT + 5 {
+        track.tick([ix as f32; VOICE_COUNT]);
+    }
+    assert_eq!(track.rows.capacity(), TRACK_CAPACITY);
+    assert_eq!(track.rows().count(), TRACK_CAPACITY);
+    assert_eq!(track.rows().next().unwrap().timestamp_samples, 5);
+    assert_eq!(
+        track.rows().last().unwrap().timestamp_samples,
+        (TRACK_CAPACITY / VOICE_COUNT + 4) as u64
+    );
+}
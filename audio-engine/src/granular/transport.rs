@@ -0,0 +1,104 @@
+//! Host transport.  The host reports its play state, tempo and song position ahead of each frame
+//! and the engine advances the position itself while rendering, so that tempo-synced grain
+//! scheduling stays locked to the host's timeline.  A reported position that doesn't line up with
+//! where the engine expected the transport to be is treated as a seek.
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::clamp;
+
+/// Positions can drift by a fraction of a frame when hosts round them, which isn't a seek
+const SEEK_TOLERANCE_FRAMES: f64 = 1.;
+/// Beats in a bar of note values.  Hosts don't report a time signature, so bars are of 4/4.
+pub const BEATS_PER_BAR: f32 = 4.;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Transport {
+    pub playing: bool,
+    pub bpm: f32,
+    /// Song position of the sample being rendered, in frames
+    pub position_frames: f64,
+    /// Set for the frame after the host moved the song position somewhere unexpected
+    pub seeked: bool,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport {
+            playing: false,
+            bpm: 120.,
+            position_frames: 0.,
+            seeked: false,
+        }
+    }
+}
+
+impl Transport {
+    /// Applies the state reported by the host for the next frame
+    pub fn set(&mut self, playing: bool, bpm: f32, position_frames: f64) {
+        self.seeked |= playing
+            && (!self.playing
+                || (position_frames - self.position_frames).abs() > SEEK_TOLERANCE_FRAMES);
+        self.playing = playing;
+        self.bpm = bpm;
+        self.position_frames = position_frames;
+    }
+
+    /// Moves on to the next sample
+    pub fn tick(&mut self) {
+        if self.playing {
+            self.position_frames += 1.;
+        }
+    }
+
+    /// Length of `beats` beats at the current tempo, in frames
+    pub fn beats_to_frames(&self, beats: f32, sample_rate: f32) -> f64 {
+        beats as f64 * 60. / self.bpm as f64 * sample_rate as f64
+    }
+
+    /// Whether a multiple of `interval_frames` falls on the sample being rendered
+    pub fn crosses_grid(&self, interval_frames: f64) -> bool {
+        if !self.playing || interval_frames <= 0. {
+            return false;
+        }
+        let position = self.position_frames;
+        (position / interval_frames).floor() > ((position - 1.) / interval_frames).floor()
+    }
+}
+
+/// Reports the host's transport state for the next frame.  Seeks are detected from
+/// discontinuities in `song_position_frames`.
+pub fn set_transport(ctx: *mut GranularCtx, playing: bool, bpm: f32, song_position_frames: f64) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !bpm.is_finite() || bpm <= 0. || !song_position_frames.is_finite() {
+        return;
+    }
+    ctx.transport
+        .set(playing, clamp(1., 999., bpm), song_position_frames.max(0.));
+}
+
+#[test]
+fn grid_follows_seeks() {
+    let mut transport = Transport::default();
+    transport.set(true, 120., 0.);
+    assert!(transport.seeked);
+    transport.seeked = false;
+    // One beat at 120 BPM and 100 Hz is 50 frames
+    let interval = transport.beats_to_frames(1., 100.);
+    assert_eq!(interval, 50.);
+
+    let mut crossings = 0;
+    for _ in 0..128 {
+        crossings += transport.crosses_grid(interval) as usize;
+        transport.tick();
+    }
+    assert_eq!(crossings, 3);
+
+    transport.set(true, 120., 128.);
+    assert!(!transport.seeked);
+    transport.set(true, 120., 149.);
+    assert!(transport.seeked);
+    transport.tick();
+    assert!(transport.crosses_grid(interval));
+}
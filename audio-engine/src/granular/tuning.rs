@@ -0,0 +1,325 @@
+//! Scala tunings.  A `.scl` file lists the degrees of a scale above its root as cents or ratios,
+//! ending with the interval the scale repeats at, usually the octave.  With one loaded, note mode
+//! maps the steps above `ROOT_NOTE` onto its degrees instead of equal-tempered semitones, and
+//! PSOLA voices that snap to notes snap to its degrees.  The root of a Scala tuning stays at
+//! `ROOT_NOTE`, which plays the sample at its original speed, while the scale of a key is rooted
+//! at its tonic.
+//!
+//! Grain pitch snapping rounds every grain's transposition to the nearest interval of the tuning
+//! above its root, as if the untransposed source were on the root.
+
+use std::fmt;
+
+use super::key::Key;
+use super::{ctx_mut, GranularCtx};
+use crate::common::log::{self, LogLevel};
+
+/// Most degrees accepted in a scale
+pub const MAX_DEGREES: usize = 1024;
+/// Furthest a degree can be from the root, in cents either way
+const MAX_DEGREE_CENTS: f32 = 9600.;
+/// Smallest period accepted, in cents
+const MIN_PERIOD_CENTS: f32 = 1.;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ScalaError {
+    /// The file ends before the line giving the number of degrees
+    MissingCount,
+    InvalidCount,
+    /// The file ends before the number of degrees it gives
+    MissingDegree,
+    /// A degree that's neither cents nor a positive ratio, or is more than
+    /// `MAX_DEGREE_CENTS` from the root, by its line number from 1
+    InvalidDegree(usize),
+    /// The last degree, which the scale repeats at, isn't at least `MIN_PERIOD_CENTS` above the
+    /// root
+    InvalidPeriod,
+}
+
+impl fmt::Display for ScalaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScalaError::MissingCount => write!(f, "missing number of degrees"),
+            ScalaError::InvalidCount => write!(f, "invalid number of degrees"),
+            ScalaError::MissingDegree => write!(f, "fewer degrees than listed"),
+            ScalaError::InvalidDegree(line) => write!(f, "invalid degree on line {}", line),
+            ScalaError::InvalidPeriod => write!(f, "scale doesn't repeat above its root"),
+        }
+    }
+}
+
+impl std::error::Error for ScalaError {}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tuning {
+    pub description: String,
+    /// Cents of each degree above the root, the last being the period the scale repeats at
+    degrees: Vec<f32>,
+    /// Semitones from `ROOT_NOTE` to the root
+    root_semitones: f32,
+}
+
+/// Cents of a degree in the Scala notation: cents if it has a period, else a ratio like `3/2` or
+/// `2`
+fn parse_degree(text: &str) -> Option<f32> {
+    let cents = if text.contains('.') {
+        text.parse::<f32>().ok()?
+    } else {
+        let (numerator, denominator) = text.split_once('/').unwrap_or((text, "1"));
+        let numerator: f64 = numerator.parse::<u64>().ok()? as f64;
+        let denominator: f64 = denominator.parse::<u64>().ok()? as f64;
+        if numerator == 0. || denominator == 0. {
+            return None;
+        }
+        (1200. * (numerator / denominator).log2()) as f32
+    };
+    (cents.abs() <= MAX_DEGREE_CENTS).then_some(cents)
+}
+
+impl Tuning {
+    /// Parses the text of a `.scl` file
+    pub fn parse_scala(text: &str) -> Result<Self, ScalaError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.starts_with('!'));
+        let description = lines.next().map_or("", |(_, line)| line.trim()).to_string();
+        let (_, count_line) = lines.next().ok_or(ScalaError::MissingCount)?;
+        let count: usize = count_line
+            .split_whitespace()
+            .next()
+            .and_then(|count| count.parse().ok())
+            .filter(|count| (1..=MAX_DEGREES).contains(count))
+            .ok_or(ScalaError::InvalidCount)?;
+        let degrees = lines
+            .take(count)
+            .map(|(line_ix, line)| {
+                line.split_whitespace()
+                    .next()
+                    .and_then(parse_degree)
+                    .ok_or(ScalaError::InvalidDegree(line_ix + 1))
+            })
+            .collect::<Result<Vec<f32>, ScalaError>>()?;
+        if degrees.len() < count {
+            return Err(ScalaError::MissingDegree);
+        }
+        let tuning = Tuning {
+            description,
+            degrees,
+            root_semitones: 0.,
+        };
+        if tuning.period() < MIN_PERIOD_CENTS {
+            return Err(ScalaError::InvalidPeriod);
+        }
+        Ok(tuning)
+    }
+
+    /// The equal-tempered scale of `key`, rooted at its tonic within a tritone of `ROOT_NOTE`
+    pub fn from_key(key: Key) -> Self {
+        let degrees = key.scale()[1..]
+            .iter()
+            .map(|&semitones| semitones as f32 * 100.)
+            .chain(std::iter::once(1200.))
+            .collect();
+        let tonic = key.tonic as f32;
+        Tuning {
+            description: key.to_string(),
+            degrees,
+            root_semitones: if tonic > 6. { tonic - 12. } else { tonic },
+        }
+    }
+
+    fn period(&self) -> f32 {
+        *self.degrees.last().unwrap()
+    }
+
+    fn cents(&self, degree: i32) -> f32 {
+        let len = self.degrees.len() as i32;
+        let (periods, degree) = (degree.div_euclid(len), degree.rem_euclid(len));
+        let within = if degree == 0 {
+            0.
+        } else {
+            self.degrees[degree as usize - 1]
+        };
+        periods as f32 * self.period() + within
+    }
+
+    /// Equal-tempered semitones above `ROOT_NOTE` of the note `steps` scale degrees above the
+    /// root, or below it for negative steps
+    pub fn semitones(&self, steps: i32) -> f32 {
+        self.root_semitones + self.cents(steps) / 100.
+    }
+
+    /// The degree nearest to `semitones` above `ROOT_NOTE`, in semitones above it
+    pub fn nearest(&self, semitones: f32) -> f32 {
+        self.root_semitones + self.nearest_interval(semitones - self.root_semitones)
+    }
+
+    /// The interval of the scale nearest to `semitones` above its root, in semitones
+    pub fn nearest_interval(&self, semitones: f32) -> f32 {
+        let cents = semitones * 100.;
+        let period = self.period();
+        let periods = (cents / period).floor();
+        let within = cents - periods * period;
+        let nearest = std::iter::once(0.)
+            .chain(self.degrees.iter().copied())
+            .min_by(|a, b| (a - within).abs().total_cmp(&(b - within).abs()))
+            .unwrap();
+        (periods * period + nearest) / 100.
+    }
+}
+
+/// Tunes note mode and PSOLA note snapping to the scale in the text of a Scala `.scl` file, with
+/// its root at `notes::ROOT_NOTE`.  Returns false and keeps the current tuning if the file can't
+/// be parsed.
+pub fn load_scala_tuning(ctx: *mut GranularCtx, text: &str) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    match Tuning::parse_scala(text) {
+        Ok(tuning) => {
+            ctx.tuning = Some(tuning);
+            true
+        }
+        Err(error) => {
+            log::log(
+                LogLevel::Warn,
+                format_args!("Scala tuning not loaded: {}", error),
+            );
+            false
+        }
+    }
+}
+
+/// Goes back to equal temperament
+pub fn clear_tuning(ctx: *mut GranularCtx) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    ctx.tuning = None;
+}
+
+#[test]
+fn scala_files_map_steps_to_degrees() {
+    let text = [
+        "! pythagorean.scl",
+        "!",
+        "Pythagorean pentatonic",
+        " 5",
+        "!",
+        "9/8",
+        " 203.910 whole tone as cents",
+        "3/2",
+        "27/16",
+        "2",
+    ]
+    .join("\n");
+    let tuning = Tuning::parse_scala(&text).unwrap();
+    assert_eq!(tuning.description, "Pythagorean pentatonic");
+    assert!((tuning.semitones(1) - 2.039).abs() < 0.001);
+    assert!((tuning.semitones(3) - 7.02).abs() < 0.001);
+    // Steps wrap around the period in both directions
+    assert!((tuning.semitones(5) - 12.).abs() < 1e-4);
+    assert!((tuning.semitones(-2) - (7.02 - 12.)).abs() < 0.001);
+    assert!((tuning.nearest(6.) - 7.02).abs() < 0.001);
+    assert!((tuning.nearest(-1.) - 0.).abs() < 1e-4);
+    assert!((tuning.nearest(23.) - 24.).abs() < 1e-4);
+
+    assert_eq!(
+        Tuning::parse_scala("empty\n"),
+        Err(ScalaError::MissingCount)
+    );
+    assert_eq!(
+        Tuning::parse_scala("short\n2\n100.0\n"),
+        Err(ScalaError::MissingDegree)
+    );
+    assert_eq!(
+        Tuning::parse_scala("bad\n1\n-3/2\n"),
+        Err(ScalaError::InvalidDegree(3))
+    );
+    assert_eq!(
+        Tuning::parse_scala("down\n1\n-100.0\n"),
+        Err(ScalaError::InvalidPeriod)
+    );
+}
+
+/// How grain transpositions are snapped
+#[derive(Clone, Copy, Debug)]
+pub enum PitchSnap<'a> {
+    Off,
+    /// To whole semitones
+    EqualTempered,
+    Tuning(&'a Tuning),
+}
+
+impl PitchSnap<'_> {
+    /// Snaps a grain's speed ratio relative to the source
+    pub fn ratio(self, ratio: f32) -> f32 {
+        let semitones = 12. * ratio.log2();
+        let snapped = match self {
+            PitchSnap::Off => return ratio,
+            PitchSnap::EqualTempered => semitones.round(),
+            PitchSnap::Tuning(tuning) => tuning.nearest_interval(semitones),
+        };
+        (snapped / 12.).exp2()
+    }
+}
+
+#[test]
+fn keys_are_rooted_at_their_tonic() {
+    use super::key::Mode;
+
+    // D major: notes step up the scale from D, and snapping leaves out C and F
+    let tuning = Tuning::from_key(Key {
+        tonic: 2,
+        mode: Mode::Major,
+    });
+    assert_eq!(tuning.description, "D major");
+    assert!((tuning.semitones(0) - 2.).abs() < 1e-4);
+    assert!((tuning.semitones(2) - 6.).abs() < 1e-4);
+    assert!((tuning.nearest(0.4) - 1.).abs() < 1e-4);
+    assert!((tuning.nearest(5.4) - 6.).abs() < 1e-4);
+
+    // Transpositions snap to the intervals of the scale
+    let snap = PitchSnap::Tuning(&tuning);
+    assert!((snap.ratio(1.02) - 1.).abs() < 1e-4);
+    assert!((snap.ratio(1.27) - 2f32.powf(4. / 12.)).abs() < 1e-4);
+    assert!((PitchSnap::EqualTempered.ratio(1.02) - 1.).abs() < 1e-4);
+    assert_eq!(PitchSnap::Off.ratio(1.02), 1.02);
+}
+
+#[test]
+fn notes_follow_a_loaded_scala_tuning() {
+    use super::midi::handle_midi_event;
+    use super::notes::{set_note_mode, ROOT_NOTE};
+    use super::params::{ParamId, VoiceParam};
+
+    let mut ctx = GranularCtx::default();
+    ctx.params
+        .set_target(ParamId::Voice(0, VoiceParam::SampleSpeedRatio), 1.);
+    set_note_mode(&mut ctx, true, false, 2.);
+    // Fifths and octaves only
+    assert!(load_scala_tuning(
+        &mut ctx,
+        "! fifths.scl\nFifths\n2\n3/2\n2/1\n"
+    ));
+    assert!(!load_scala_tuning(&mut ctx, "Broken\n2\n3/2\n"));
+    assert_eq!(ctx.tuning.as_ref().unwrap().description, "Fifths");
+
+    let speed_for_note = |ctx: &mut GranularCtx, note: u8| {
+        handle_midi_event(ctx, 0x90, note, 127);
+        let targets = *ctx.params.target();
+        let speed = ctx
+            .resolve_targets(&targets)
+            .voice(0, VoiceParam::SampleSpeedRatio);
+        handle_midi_event(ctx, 0x80, note, 0);
+        speed
+    };
+    assert!((speed_for_note(&mut ctx, ROOT_NOTE + 1) - 1.5).abs() < 1e-4);
+    assert!((speed_for_note(&mut ctx, ROOT_NOTE + 4) - 4.).abs() < 1e-4);
+    assert!((speed_for_note(&mut ctx, ROOT_NOTE - 1) - 0.75).abs() < 1e-4);
+
+    clear_tuning(&mut ctx);
+    let semitone = 2f32.powf(1. / 12.);
+    assert!((speed_for_note(&mut ctx, ROOT_NOTE + 1) - semitone).abs() < 1e-4);
+}
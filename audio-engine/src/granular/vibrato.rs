@@ -0,0 +1,142 @@
+//! Vibrato on the master output, for a tape-like warble across the whole texture independently
+//! of the grains' own pitch.  The output goes through a short delay whose length a sine LFO
+//! sweeps, and the changing delay bends the pitch of everything coming out of it.
+
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::delay::ModulatedDelay;
+use crate::dsp::{clamp, smooth};
+
+pub const MIN_RATE_HZ: f32 = 0.1;
+pub const MAX_RATE_HZ: f32 = 20.;
+pub const MAX_DEPTH_CENTS: f32 = 100.;
+/// Smoothing of the sweep's width per sample, so that changing the rate or depth doesn't jump
+const WIDTH_SMOOTHING: f32 = 0.999;
+
+/// Delay sweep, in samples either side of its middle, that bends the pitch by up to
+/// `depth_cents` at `rate_hz`.  The pitch follows the rate of change of the delay, whose largest
+/// value is the width of the sweep times its angular frequency.
+pub fn sweep_width(rate_hz: f32, depth_cents: f32, sample_rate: f32) -> f32 {
+    let ratio = 2f32.powf(depth_cents / 1200.) - 1.;
+    ratio * sample_rate / (std::f32::consts::TAU * rate_hz)
+}
+
+#[derive(Clone)]
+pub struct MasterVibrato {
+    pub rate_hz: f32,
+    pub depth_cents: f32,
+    /// Position of the LFO in its cycle, from 0 to 1
+    phase: f32,
+    width: f32,
+    /// Left, right and mono mix
+    delays: [ModulatedDelay; 3],
+}
+
+impl MasterVibrato {
+    pub fn new(rate_hz: f32, depth_cents: f32, sample_rate: f32) -> Self {
+        let max_width = sweep_width(MIN_RATE_HZ, MAX_DEPTH_CENTS, sample_rate);
+        let delay = ModulatedDelay::new((2. * max_width).ceil() as usize + 1);
+        MasterVibrato {
+            rate_hz,
+            depth_cents,
+            phase: 0.,
+            width: sweep_width(rate_hz, depth_cents, sample_rate),
+            delays: [delay.clone(), delay.clone(), delay],
+        }
+    }
+
+    /// The delay the sweep centres on once it settles, which the whole output lags by
+    pub fn latency_samples(&self, sample_rate: f32) -> f32 {
+        sweep_width(self.rate_hz, self.depth_cents, sample_rate)
+    }
+
+    /// Bends the pitch of the left and right channels and the mono mix
+    pub fn process(&mut self, samples: [f32; 3], sample_rate: f32) -> [f32; 3] {
+        let target = sweep_width(self.rate_hz, self.depth_cents, sample_rate);
+        smooth(&mut self.width, target, WIDTH_SMOOTHING);
+        self.phase = (self.phase + self.rate_hz / sample_rate).fract();
+        // Starting the cycle at no delay means turning the vibrato on doesn't jump
+        let delay = self.width * (1. - (self.phase * std::f32::consts::TAU).cos());
+        let [left, right, mono] = &mut self.delays;
+        [
+            left.process(samples[0], delay),
+            right.process(samples[1], delay),
+            mono.process(samples[2], delay),
+        ]
+    }
+}
+
+/// Makes the pitch of the master output waver by up to `depth_cents` (0 to 100) either way,
+/// `rate_hz` (0.1 to 20 Hz) times a second, independently of the grains' own pitch.  A depth of 0
+/// turns the vibrato off.
+pub fn set_master_vibrato(ctx: *mut GranularCtx, rate_hz: f32, depth_cents: f32) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !rate_hz.is_finite() || !depth_cents.is_finite() {
+        return;
+    }
+    let rate_hz = clamp(MIN_RATE_HZ, MAX_RATE_HZ, rate_hz);
+    let depth_cents = clamp(0., MAX_DEPTH_CENTS, depth_cents);
+    let sample_rate = ctx.sample_rate;
+    match &mut ctx.master_vibrato {
+        _ if depth_cents == 0. => ctx.master_vibrato = None,
+        Some(vibrato) => {
+            vibrato.rate_hz = rate_hz;
+            vibrato.depth_cents = depth_cents;
+        }
+        vibrato => *vibrato = Some(MasterVibrato::new(rate_hz, depth_cents, sample_rate)),
+    }
+}
+
+#[test]
+fn vibrato_bends_the_pitch_by_its_depth() {
+    let sample_rate = 1000.;
+    let mut vibrato = MasterVibrato::new(1., MAX_DEPTH_CENTS, sample_rate);
+    // The delay of a ramp is how far its output falls behind it, and its slope is the pitch
+    let output: Vec<f32> = (0..1000)
+        .map(|ix| vibrato.process([ix as f32; 3], sample_rate)[0])
+        .collect();
+    let slopes: Vec<f32> = output.windows(2).map(|w| w[1] - w[0]).collect();
+    let lowest = slopes.iter().copied().fold(f32::INFINITY, f32::min);
+    let highest = slopes.iter().copied().fold(0., f32::max);
+    // A semitone either way
+    assert!((lowest - 2f32.powf(-1. / 12.)).abs() < 0.01, "{}", lowest);
+    assert!((highest - 2f32.powf(1. / 12.)).abs() < 0.01, "{}", highest);
+}
+
+#[test]
+fn master_vibrato_bends_the_output_pitch() {
+    use super::params::{GlobalParam, ParamId};
+    use super::DEFAULT_SAMPLE_RATE;
+
+    let period = DEFAULT_SAMPLE_RATE / 441.;
+    let mut ctx = GranularCtx {
+        waveform: (0..44100)
+            .map(|i| (std::f32::consts::TAU * i as f32 / period).sin())
+            .collect(),
+        ..Default::default()
+    };
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 44099.);
+    let targets = *ctx.params.target();
+    let render = |ctx: &mut GranularCtx| {
+        let mut output = Vec::new();
+        for _ in 0..64 {
+            ctx.render(&targets);
+            output.extend_from_slice(&ctx.rendered_output);
+        }
+        output
+    };
+    ctx.seed(1);
+    let dry = render(&mut ctx);
+    ctx.seed(1);
+    set_master_vibrato(&mut ctx, 5., 50.);
+    let wet = render(&mut ctx);
+    let difference: f32 = dry.iter().zip(&wet).map(|(a, b)| (a - b).abs()).sum();
+    assert!(difference > dry.len() as f32 * 0.01);
+
+    set_master_vibrato(&mut ctx, f32::NAN, 10.);
+    assert_eq!(ctx.master_vibrato.as_ref().unwrap().depth_cents, 50.);
+    set_master_vibrato(&mut ctx, 5., 0.);
+    assert!(ctx.master_vibrato.is_none());
+}
@@ -0,0 +1,663 @@
+//! Loading waveforms into the engine.  Besides handing out a buffer for the host to fill in one
+//! go, waveforms can be streamed in as a series of chunks so that large files never need a second
+//! full-length copy on the JS side.  The current waveform keeps playing until an upload finishes.
+//!
+//! Waveforms can also be replaced without a dropout: the host fills a staging buffer while the
+//! current waveform keeps playing and then asks for a swap.  The swap happens at the start of the
+//! next frame, and grains that were playing from the old waveform keep reading it while they fade
+//! out, or until they end on their own for a gapless replacement.
+//!
+//! Uploaded and staged waveforms are processed according to the `LoadOptions` before they're
+//! used: resampled from the rate of the source file to the engine's rate, stripped of DC offset
+//! and normalized, in that order.  The processing happens within the call that loads or stages
+//! the waveform, and resampling takes time in proportion to the waveform's length and the
+//! quality, so hosts that render from an audio callback should load waveforms outside of it.
+//!
+//! Interleaved multichannel data can be imported too, either summed to mono or keeping the first
+//! two channels as a stereo waveform that grains read in stereo.
+//!
+//! Voices can also granulate the mid or side signal of a stereo waveform instead of its left and
+//! right channels, which is worked out from the two channels as grains read them.
+//!
+//! Finally, hosts that have already decoded a long sample into linear memory can bind it as an
+//! external waveform instead of copying it.  Grains then read that memory directly until the host
+//! unbinds it, quoting the generation it got when binding so that a stale unbind can't detach a
+//! newer buffer.
+//!
+//! Loaded waveforms are kept as 32-bit floats unless the load options ask for 16-bit storage,
+//! which halves the memory a long sample takes at the cost of 16-bit quality.  Grains convert
+//! the samples back to floats as they read them.
+
+use std::borrow::Cow;
+use std::ops::Range;
+
+use super::slots::SampleSlots;
+use super::OutputFade;
+#[cfg(feature = "loudness")]
+use crate::dsp::loudness::LoudnessMeter;
+use crate::dsp::resample::{resample, ResampleQuality};
+use crate::dsp::{read_interpolated_with, read_interpolated_wrapped, EndPolicy, Sample};
+
+/// Longest waveform the host can load, about 50 minutes at 44.1 kHz and 512 MB as floats.  Longer
+/// buffers and uploads are refused before anything is allocated for them.
+pub const MAX_WAVEFORM_LEN: usize = 1 << 27;
+
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub enum Normalization {
+    #[default]
+    Off,
+    /// Scales the loudest sample to this level in dBFS
+    Peak(f32),
+    /// Scales the integrated loudness to this level in LUFS
+    #[cfg(feature = "loudness")]
+    Loudness(f32),
+}
+
+/// Format loaded waveforms are kept in
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum WaveformStorage {
+    #[default]
+    Float32,
+    /// 16-bit PCM
+    Int16,
+}
+
+impl WaveformStorage {
+    pub const ALL: [WaveformStorage; 2] = [WaveformStorage::Float32, WaveformStorage::Int16];
+
+    pub fn from_index(ix: u32) -> Option<Self> {
+        match ix {
+            0 => Some(WaveformStorage::Float32),
+            1 => Some(WaveformStorage::Int16),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            WaveformStorage::Float32 => "float32",
+            WaveformStorage::Int16 => "int16",
+        }
+    }
+
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            WaveformStorage::Float32 => 4,
+            WaveformStorage::Int16 => 2,
+        }
+    }
+
+    /// What the format costs in quality, for hosts to show next to the choice
+    fn quality_note(self) -> &'static str {
+        match self {
+            WaveformStorage::Float32 => "lossless",
+            WaveformStorage::Int16 => {
+                "half the memory; adds a noise floor near -96 dBFS, which slowed-down and \
+                 heavily compressed grains can bring up, and clips samples beyond full scale"
+            }
+        }
+    }
+}
+
+/// Returns a JSON array describing each `WaveformStorage` in index order
+pub fn storage_metadata_json() -> String {
+    let entries: Vec<String> = WaveformStorage::ALL
+        .iter()
+        .enumerate()
+        .map(|(ix, storage)| {
+            format!(
+                "{{\"id\":{},\"name\":\"{}\",\"bytes_per_sample\":{},\"quality\":\"{}\"}}",
+                ix,
+                storage.name(),
+                storage.bytes_per_sample(),
+                storage.quality_note(),
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// Processing applied to waveforms as they're loaded
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LoadOptions {
+    /// Rate the host's samples are at, if it differs from the engine's
+    pub source_sample_rate: Option<f32>,
+    pub resample_quality: ResampleQuality,
+    pub remove_dc: bool,
+    pub normalization: Normalization,
+    pub storage: WaveformStorage,
+}
+
+fn remove_dc(samples: &mut [f32]) {
+    if samples.is_empty() {
+        return;
+    }
+    let mean = samples
+        .iter()
+        .filter(|sample| sample.is_finite())
+        .map(|sample| *sample as f64)
+        .sum::<f64>()
+        / samples.len() as f64;
+    for sample in samples {
+        *sample -= mean as f32;
+    }
+}
+
+impl LoadOptions {
+    /// Processes both channels of a waveform.  Stereo channels are normalized by the same gain so
+    /// that their balance is kept.
+    pub fn prepare(
+        &self,
+        samples: Vec<f32>,
+        right: Option<Vec<f32>>,
+        engine_sample_rate: f32,
+    ) -> (Vec<f32>, Option<Vec<f32>>) {
+        let resample_channel = |mut channel: Vec<f32>| match self.source_sample_rate {
+            Some(source_sample_rate) if source_sample_rate != engine_sample_rate => {
+                // Upsampling is cut short where the waveform would grow past `MAX_WAVEFORM_LEN`
+                let ratio = source_sample_rate as f64 / engine_sample_rate as f64;
+                channel.truncate((MAX_WAVEFORM_LEN as f64 * ratio) as usize);
+                resample(
+                    &channel,
+                    source_sample_rate,
+                    engine_sample_rate,
+                    self.resample_quality,
+                )
+            }
+            _ => channel,
+        };
+        let mut samples = resample_channel(samples);
+        let mut right = right.map(resample_channel);
+
+        if self.remove_dc {
+            remove_dc(&mut samples);
+            if let Some(right) = &mut right {
+                remove_dc(right);
+            }
+        }
+
+        if let Some(gain) = self.normalization_gain(&samples, right.as_deref(), engine_sample_rate)
+        {
+            for sample in samples.iter_mut().chain(right.iter_mut().flatten()) {
+                *sample *= gain;
+            }
+        }
+        (samples, right)
+    }
+
+    /// Gain that brings the waveform to the normalization target, if there's one and the waveform
+    /// isn't silent
+    #[cfg_attr(not(feature = "loudness"), allow(unused_variables))]
+    fn normalization_gain(
+        &self,
+        samples: &[f32],
+        right: Option<&[f32]>,
+        sample_rate: f32,
+    ) -> Option<f32> {
+        let db_to_gain = |db: f32| 10f32.powf(db / 20.);
+        let gain = match self.normalization {
+            Normalization::Off => return None,
+            Normalization::Peak(target_db) => {
+                let peak = samples
+                    .iter()
+                    .chain(right.into_iter().flatten())
+                    .filter(|sample| sample.is_finite())
+                    .fold(0., |peak: f32, sample| peak.max(sample.abs()));
+                db_to_gain(target_db) / peak
+            }
+            #[cfg(feature = "loudness")]
+            Normalization::Loudness(target_lufs) => {
+                let mut meter = LoudnessMeter::new(sample_rate, 128);
+                for (ix, sample) in samples.iter().enumerate() {
+                    meter.process(*sample, right.map_or(0., |right| right[ix]));
+                }
+                db_to_gain(target_lufs - meter.integrated())
+            }
+        };
+        (gain.is_finite() && gain > 0.).then_some(gain)
+    }
+}
+
+/// A waveform being streamed in by the host
+#[derive(Clone, Debug)]
+pub struct WaveformUpload {
+    samples: Vec<f32>,
+    total_len: usize,
+}
+
+impl WaveformUpload {
+    /// `None` if `total_len` is over `MAX_WAVEFORM_LEN`
+    pub fn new(total_len: usize) -> Option<Self> {
+        (total_len <= MAX_WAVEFORM_LEN).then(|| WaveformUpload {
+            samples: Vec::with_capacity(total_len),
+            total_len,
+        })
+    }
+
+    /// Appends `chunk`, dropping anything past the announced total length.  Returns whether the
+    /// whole chunk fit.
+    pub fn append(&mut self, chunk: &[f32]) -> bool {
+        let remaining = self.total_len - self.samples.len();
+        let fitting_len = chunk.len().min(remaining);
+        self.samples.extend_from_slice(&chunk[..fitting_len]);
+        fitting_len == chunk.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.samples.len() == self.total_len
+    }
+
+    /// The uploaded samples, or `None` if fewer than the announced total length were appended
+    pub fn finish(self) -> Option<Vec<f32>> {
+        if self.is_complete() {
+            Some(self.samples)
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits interleaved frames of `channel_count` samples into a mono waveform, or into left and
+/// right channels if `keep_stereo` is set and there are at least two.  Channels past the second
+/// are dropped when keeping stereo; a trailing partial frame is always dropped.
+pub fn deinterleave(
+    interleaved: &[f32],
+    channel_count: usize,
+    keep_stereo: bool,
+) -> (Vec<f32>, Option<Vec<f32>>) {
+    let frames = interleaved.chunks_exact(channel_count.max(1));
+    if keep_stereo && channel_count >= 2 {
+        let (left, right) = frames.map(|frame| (frame[0], frame[1])).unzip();
+        (left, Some(right))
+    } else {
+        let mono = frames
+            .map(|frame| frame.iter().sum::<f32>() / channel_count as f32)
+            .collect();
+        (mono, None)
+    }
+}
+
+/// Signal of a stereo waveform that a voice granulates in mid/side mode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MidSideChannel {
+    Mid,
+    Side,
+}
+
+impl MidSideChannel {
+    /// This channel's part of a stereo sample
+    #[inline]
+    pub fn encode(self, left: f32, right: f32) -> f32 {
+        match self {
+            MidSideChannel::Mid => (left + right) * 0.5,
+            MidSideChannel::Side => (left - right) * 0.5,
+        }
+    }
+
+    /// Left and right gains that turn this channel back into stereo, so that the mid and side
+    /// voices add up to left and right again
+    #[inline]
+    pub fn decode_gains(self) -> (f32, f32) {
+        match self {
+            MidSideChannel::Mid => (1., 1.),
+            MidSideChannel::Side => (1., -1.),
+        }
+    }
+}
+
+/// Mono waveform living in memory owned by the host
+#[derive(Clone)]
+pub struct ExternalWaveform {
+    ptr: *const f32,
+    len: usize,
+    pub generation: u32,
+}
+
+impl ExternalWaveform {
+    /// Binds `len` samples at `ptr`, or returns `None` if the pointer is null or misaligned or the
+    /// samples would span more than `isize::MAX` bytes, which slices can't
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` initialized samples that stay valid and unmodified until the
+    /// waveform is unbound.
+    pub unsafe fn new(ptr: *const f32, len: usize, generation: u32) -> Option<Self> {
+        let fits = len
+            .checked_mul(std::mem::size_of::<f32>())
+            .is_some_and(|bytes| bytes <= isize::MAX as usize);
+        (!ptr.is_null() && ptr.is_aligned() && fits).then_some(ExternalWaveform {
+            ptr,
+            len,
+            generation,
+        })
+    }
+
+    #[inline]
+    pub fn samples(&self) -> &[f32] {
+        // SAFETY: upheld by the caller of `new` for as long as this binding exists
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+// SAFETY: the binding only reads the host's memory, which `new` requires to stay valid and
+// unmodified, so reading it from whichever thread renders the instance is fine
+unsafe impl Send for ExternalWaveform {}
+
+/// A waveform kept in 16-bit storage
+#[derive(Clone, Default, Debug)]
+pub struct CompactWaveform {
+    pub left: Vec<i16>,
+    pub right: Option<Vec<i16>>,
+}
+
+impl CompactWaveform {
+    /// Rounds each sample to 16 bits, clipping it to full scale.  Samples that aren't finite
+    /// become silence.
+    pub fn encode(samples: &[f32], right: Option<&[f32]>) -> Self {
+        let encode_channel = |channel: &[f32]| {
+            channel
+                .iter()
+                .map(|sample| {
+                    if sample.is_finite() {
+                        (sample * 32768.).round().clamp(-32768., 32767.) as i16
+                    } else {
+                        0
+                    }
+                })
+                .collect()
+        };
+        CompactWaveform {
+            left: encode_channel(samples),
+            right: right.map(encode_channel),
+        }
+    }
+
+    pub fn channels(&self) -> WaveformChannels<'_> {
+        WaveformChannels {
+            left: ChannelSamples::Int16(&self.left),
+            right: self.right.as_deref().map(ChannelSamples::Int16),
+        }
+    }
+}
+
+/// The samples of one channel, in the format they're stored in
+#[derive(Clone, Copy, Debug)]
+pub enum ChannelSamples<'a> {
+    Float(&'a [f32]),
+    Int16(&'a [i16]),
+}
+
+impl<'a> From<&'a [f32]> for ChannelSamples<'a> {
+    fn from(samples: &'a [f32]) -> Self {
+        ChannelSamples::Float(samples)
+    }
+}
+
+impl<'a> ChannelSamples<'a> {
+    #[inline]
+    pub fn len(self) -> usize {
+        match self {
+            ChannelSamples::Float(samples) => samples.len(),
+            ChannelSamples::Int16(samples) => samples.len(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn slice(self, range: Range<usize>) -> Self {
+        match self {
+            ChannelSamples::Float(samples) => ChannelSamples::Float(&samples[range]),
+            ChannelSamples::Int16(samples) => ChannelSamples::Int16(&samples[range]),
+        }
+    }
+
+    /// The part the host has declared valid, or all of it if it hasn't
+    #[inline]
+    pub fn valid_part(self, valid_len: Option<usize>) -> Self {
+        let len = self.len();
+        self.slice(0..valid_len.map_or(len, |valid_len| valid_len.min(len)))
+    }
+
+    #[inline]
+    pub fn at(self, ix: usize) -> f32 {
+        match self {
+            ChannelSamples::Float(samples) => samples[ix],
+            ChannelSamples::Int16(samples) => samples[ix].to_float(),
+        }
+    }
+
+    /// Interpolated read, like `read_interpolated_with`
+    #[inline]
+    pub fn read_with(self, index: f32, end: EndPolicy) -> f32 {
+        match self {
+            ChannelSamples::Float(samples) => read_interpolated_with(samples, index, end),
+            ChannelSamples::Int16(samples) => read_interpolated_with(samples, index, end),
+        }
+    }
+
+    #[inline]
+    pub fn read(self, index: f32) -> f32 {
+        self.read_with(index, EndPolicy::Clamp)
+    }
+
+    /// Interpolated read looping over `range`, like `read_interpolated_wrapped`
+    #[inline]
+    pub fn read_wrapped(self, range: Range<usize>, index: f32) -> f32 {
+        match self {
+            ChannelSamples::Float(samples) => read_interpolated_wrapped(samples, range, index),
+            ChannelSamples::Int16(samples) => read_interpolated_wrapped(samples, range, index),
+        }
+    }
+
+    /// The samples as floats, only copied if they're stored in another format
+    pub fn decode(self) -> Cow<'a, [f32]> {
+        match self {
+            ChannelSamples::Float(samples) => Cow::Borrowed(samples),
+            ChannelSamples::Int16(samples) => {
+                Cow::Owned(samples.iter().map(|sample| sample.to_float()).collect())
+            }
+        }
+    }
+}
+
+/// What happens to grains still reading the old waveform after a swap
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SwapTail {
+    /// They fade out over this many milliseconds
+    Crossfade(f32),
+    /// They play out at full level and end on their own
+    Finish,
+}
+
+/// A replaced waveform that grains spawned before the swap are still reading from
+#[derive(Clone)]
+pub struct RetiredWaveform {
+    pub samples: Vec<f32>,
+    pub right: Option<Vec<f32>>,
+    /// The waveform in 16-bit storage, in which case `samples` and `right` are empty
+    pub compact: Option<CompactWaveform>,
+    /// `None` when grains are left to finish
+    pub fade: Option<OutputFade>,
+}
+
+impl RetiredWaveform {
+    pub fn channels(&self) -> WaveformChannels<'_> {
+        match &self.compact {
+            Some(compact) => compact.channels(),
+            None => WaveformChannels {
+                left: ChannelSamples::Float(&self.samples),
+                right: self.right.as_deref().map(ChannelSamples::Float),
+            },
+        }
+    }
+
+    /// Advances the fade by one sample and returns the gain of grains reading this waveform
+    #[inline]
+    pub fn tick(&mut self) -> f32 {
+        self.fade.as_mut().map_or(1., OutputFade::tick)
+    }
+
+    pub fn is_faded_out(&self) -> bool {
+        self.fade.is_some_and(|fade| fade.is_finished())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WaveformSwap {
+    /// Buffer handed out to the host to fill with the next waveform
+    pub staged: Option<Vec<f32>>,
+    /// Right channel of `staged` if the next waveform is in stereo
+    pub staged_right: Option<Vec<f32>>,
+    /// The next waveform when it's kept in 16-bit storage, encoded when it was staged so that
+    /// the swap only has to move it in.  `staged` and `staged_right` are None then.
+    pub staged_compact: Option<CompactWaveform>,
+    /// Sample slot `staged` holds a copy of, which `Command::LoadSlot` can swap in without copying
+    pub staged_slot: Option<u32>,
+    /// Tail of a swap requested for the start of the next frame
+    pub pending: Option<SwapTail>,
+    pub retired: Option<RetiredWaveform>,
+}
+
+/// The channels of one waveform
+#[derive(Clone, Copy)]
+pub struct WaveformChannels<'a> {
+    /// Left channel, or the only one for mono waveforms
+    pub left: ChannelSamples<'a>,
+    pub right: Option<ChannelSamples<'a>>,
+}
+
+impl<'a> WaveformChannels<'a> {
+    /// The channels of the waveform the engine has loaded, in whichever of its stores holds it
+    pub fn stored(
+        external: Option<&'a ExternalWaveform>,
+        compact: Option<&'a CompactWaveform>,
+        samples: &'a [f32],
+        right: Option<&'a [f32]>,
+    ) -> Self {
+        match (external, compact) {
+            (Some(external), _) => WaveformChannels {
+                left: ChannelSamples::Float(external.samples()),
+                right: None,
+            },
+            (None, Some(compact)) => compact.channels(),
+            (None, None) => WaveformChannels {
+                left: ChannelSamples::Float(samples),
+                right: right.map(ChannelSamples::Float),
+            },
+        }
+    }
+
+    /// Both channels cut to the length the host has declared valid
+    #[inline]
+    pub fn valid_part(self, valid_len: Option<usize>) -> Self {
+        WaveformChannels {
+            left: self.left.valid_part(valid_len),
+            right: self.right.map(|right| right.valid_part(valid_len)),
+        }
+    }
+}
+
+/// The buffers grains read from while rendering a sample
+pub struct WaveformSources<'a> {
+    pub current: WaveformChannels<'a>,
+    /// The waveform being faded out after a swap, along with its gain for this sample
+    pub retired: Option<(WaveformChannels<'a>, f32)>,
+    /// Slots that grains of voices with a slot selection read instead of the waveform
+    pub slots: &'a SampleSlots,
+}
+
+impl<'a> WaveformSources<'a> {
+    /// Buffers and gain for a grain depending on whether it was spawned before the last swap
+    #[inline]
+    pub fn for_grain(&self, retired: bool) -> (WaveformChannels<'a>, f32) {
+        match (retired, self.retired) {
+            (true, Some(retired)) => retired,
+            (true, None) => (
+                WaveformChannels {
+                    left: ChannelSamples::Float(&[]),
+                    right: None,
+                },
+                0.,
+            ),
+            (false, _) => (self.current, 1.),
+        }
+    }
+}
+
+#[test]
+fn chunked_uploads_must_match_announced_length() {
+    assert!(WaveformUpload::new(MAX_WAVEFORM_LEN + 1).is_none());
+    let mut upload = WaveformUpload::new(5).unwrap();
+    assert!(upload.append(&[1., 2.]));
+    assert!(!upload.is_complete());
+    assert!(upload.clone().finish().is_none());
+    assert!(!upload.append(&[3., 4., 5., 6.]));
+    assert_eq!(upload.finish(), Some(vec![1., 2., 3., 4., 5.]));
+}
+
+#[test]
+fn interleaved_data_is_split_or_summed() {
+    let interleaved = [1., 3., 0.5, 0.5, 2.];
+    assert_eq!(
+        deinterleave(&interleaved, 2, true),
+        (vec![1., 0.5], Some(vec![3., 0.5]))
+    );
+    assert_eq!(deinterleave(&interleaved, 2, false), (vec![2., 0.5], None));
+    assert_eq!(
+        deinterleave(&interleaved, 1, true),
+        (interleaved.to_vec(), None)
+    );
+}
+
+#[test]
+fn mid_and_side_decode_to_left_and_right() {
+    let (left, right) = (0.75, -0.25);
+    let (mut decoded_left, mut decoded_right) = (0., 0.);
+    for channel in [MidSideChannel::Mid, MidSideChannel::Side] {
+        let sample = channel.encode(left, right);
+        let (left_gain, right_gain) = channel.decode_gains();
+        decoded_left += sample * left_gain;
+        decoded_right += sample * right_gain;
+    }
+    assert_eq!((decoded_left, decoded_right), (left, right));
+}
+
+#[test]
+fn load_options_remove_dc_and_normalize() {
+    let options = LoadOptions {
+        remove_dc: true,
+        normalization: Normalization::Peak(-6.),
+        ..Default::default()
+    };
+    let (samples, right) = options.prepare(vec![0.5, 0.3], Some(vec![0.1, 0.2]), 44100.);
+    let target = 10f32.powf(-6. / 20.);
+    assert!((samples[0] - target).abs() < 1e-5);
+    assert!((samples[1] + target).abs() < 1e-5);
+    assert!((right.unwrap()[1] - target / 2.).abs() < 1e-5);
+
+    // Silence can't be normalized and is left alone
+    let (samples, _) = options.prepare(vec![0.; 4], None, 44100.);
+    assert_eq!(samples, vec![0.; 4]);
+}
+
+#[test]
+fn compact_waveforms_round_to_16_bits() {
+    let compact = CompactWaveform::encode(
+        &[0., 0.5, -1., 2., f32::NAN, f32::INFINITY],
+        Some(&[0.25; 6]),
+    );
+    assert_eq!(compact.left, [0, 16384, -32768, 32767, 0, 0]);
+    let channels = compact.channels();
+    assert_eq!(channels.left.read(0.5), 0.25);
+    assert_eq!(channels.right.unwrap().at(4), 0.25);
+    assert_eq!(channels.left.slice(1..3).decode().as_ref(), [0.5, -1.]);
+
+    assert_eq!(WaveformStorage::from_index(1), Some(WaveformStorage::Int16));
+    assert_eq!(WaveformStorage::from_index(2), None);
+    let json = storage_metadata_json();
+    assert!(json.contains("\"name\":\"int16\",\"bytes_per_sample\":2"));
+}
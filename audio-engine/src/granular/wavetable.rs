@@ -0,0 +1,243 @@
+//! Wavetable scanning over the loaded waveform.  A voice in wavetable mode treats a region of the
+//! waveform as a table of single-cycle frames of a fixed length and plays it as an oscillator
+//! instead of granulating it.  The position picks the frame, morphing between neighbouring ones,
+//! and can scan through the table back and forth on its own.
+//!
+//! The oscillator plays `ROOT_FREQUENCY_HZ` times the voice's sample speed, so note mode, detune
+//! and pitch modulation transpose it like they transpose grains.  Its output goes through the
+//! voice's filter as usual.
+
+use super::params::VOICE_COUNT;
+use super::waveform::{ChannelSamples, WaveformChannels};
+use super::{ctx_mut, GranularCtx};
+use crate::dsp::{clamp, mix, EndPolicy};
+
+/// Frequency at a sample speed of 1, that of `notes::ROOT_NOTE`
+pub const ROOT_FREQUENCY_HZ: f32 = 261.625_58;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Wavetable {
+    /// Index of the table's first sample in the waveform
+    pub start: usize,
+    /// Samples per frame, i.e. in one cycle of the oscillator
+    pub frame_len: usize,
+    /// Frames in the table.  Frames past the end of the waveform are left out.
+    pub frame_count: usize,
+    /// Frame played from 0 (the first) to 1 (the last)
+    pub position: f32,
+    /// Round trips through the table per second, starting from `position`; 0 holds the position
+    pub scan_hz: f32,
+    /// Position in the current cycle from 0 to 1
+    phase: f32,
+    /// Distance travelled by the scan, in table lengths
+    scan_offset: f32,
+}
+
+impl Wavetable {
+    pub fn new(start: usize, frame_len: usize, frame_count: usize) -> Self {
+        Wavetable {
+            start,
+            frame_len,
+            frame_count,
+            position: 0.,
+            scan_hz: 0.,
+            phase: 0.,
+            scan_offset: 0.,
+        }
+    }
+
+    /// This table's oscillator and scan state with the settings of `other`
+    pub fn with_settings_of(self, other: &Wavetable) -> Self {
+        Wavetable {
+            phase: self.phase,
+            scan_offset: self.scan_offset,
+            ..*other
+        }
+    }
+
+    /// Frames of the table that are part of `samples`
+    fn available_frames(&self, samples: ChannelSamples) -> usize {
+        let available = samples.len().saturating_sub(self.start) / self.frame_len.max(1);
+        self.frame_count.min(available)
+    }
+
+    fn read_frame(&self, samples: ChannelSamples, frame_ix: usize) -> f32 {
+        let start = self.start + frame_ix * self.frame_len;
+        let frame = samples.slice(start..start + self.frame_len);
+        frame.read_with(self.phase * self.frame_len as f32, EndPolicy::Wrap)
+    }
+
+    /// Sample of one channel with the table position at `position`
+    fn read(&self, samples: ChannelSamples, position: f32) -> f32 {
+        let frame_count = self.available_frames(samples);
+        if frame_count == 0 {
+            return 0.;
+        }
+        let frame_position = position * (frame_count - 1) as f32;
+        let frame_ix = (frame_position as usize).min(frame_count - 1);
+        let next_frame_ix = (frame_ix + 1).min(frame_count - 1);
+        mix(
+            frame_position - frame_ix as f32,
+            self.read_frame(samples, frame_ix),
+            self.read_frame(samples, next_frame_ix),
+        )
+    }
+
+    /// Plays one sample at `frequency_hz` and advances the oscillator and the scan.
+    /// `position_offset` is added to the position, e.g. from modulation.
+    pub fn next_sample(
+        &mut self,
+        channels: WaveformChannels,
+        frequency_hz: f32,
+        position_offset: f32,
+        sample_rate: f32,
+    ) -> (f32, f32) {
+        // The position bounces off the ends of the table so that scanning never jumps
+        let position = (self.position + self.scan_offset + position_offset).rem_euclid(2.);
+        let position = if position > 1. {
+            2. - position
+        } else {
+            position
+        };
+        let left = self.read(channels.left, position);
+        let right = channels
+            .right
+            .map_or(left, |right| self.read(right, position));
+
+        let phase_step = frequency_hz / sample_rate;
+        if phase_step.is_finite() {
+            self.phase = (self.phase + phase_step).rem_euclid(1.);
+        }
+        self.scan_offset = (self.scan_offset + 2. * self.scan_hz / sample_rate).rem_euclid(2.);
+        (left, right)
+    }
+}
+
+/// Switches a voice to playing `frame_count` frames of `frame_len` samples each from `start` on in
+/// the waveform as a wavetable.  The position and scan rate of a voice that's already playing one
+/// are kept.  Returns false if the frames are empty.
+pub fn set_voice_wavetable(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    start: usize,
+    frame_len: usize,
+    frame_count: usize,
+) -> bool {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return false;
+    };
+    if voice_ix >= VOICE_COUNT || frame_len == 0 || frame_count == 0 {
+        return false;
+    }
+    let wavetable = ctx.voices[voice_ix]
+        .wavetable
+        .get_or_insert_with(|| Wavetable::new(start, frame_len, frame_count));
+    wavetable.start = start;
+    wavetable.frame_len = frame_len;
+    wavetable.frame_count = frame_count;
+    true
+}
+
+/// Sets the frame a voice's wavetable plays from 0 to 1 and how many round trips a second it
+/// scans through the table from there
+pub fn set_voice_wavetable_position(
+    ctx: *mut GranularCtx,
+    voice_ix: usize,
+    position: f32,
+    scan_hz: f32,
+) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if !position.is_finite() || !scan_hz.is_finite() {
+        return;
+    }
+    if let Some(wavetable) = ctx
+        .voices
+        .get_mut(voice_ix)
+        .and_then(|voice| voice.wavetable.as_mut())
+    {
+        wavetable.position = clamp(0., 1., position);
+        wavetable.scan_hz = clamp(0., 100., scan_hz);
+    }
+}
+
+/// Switches a voice back from a wavetable to granulating
+pub fn clear_voice_wavetable(ctx: *mut GranularCtx, voice_ix: usize) {
+    let Some(ctx) = ctx_mut(ctx) else {
+        return;
+    };
+    if let Some(voice) = ctx.voices.get_mut(voice_ix) {
+        voice.wavetable = None;
+    }
+}
+
+#[test]
+fn wavetables_morph_between_frames_and_scan_back() {
+    // Two frames of 4 samples: a constant 0 and a constant 1
+    let samples = [9., 0., 0., 0., 0., 1., 1., 1., 1.];
+    let channels = WaveformChannels {
+        left: samples[..].into(),
+        right: None,
+    };
+    let mut wavetable = Wavetable::new(1, 4, 3);
+    wavetable.position = 0.25;
+    // The third frame runs past the end of the waveform, so the table has two
+    assert_eq!(
+        wavetable.next_sample(channels, 100., 0., 1000.),
+        (0.25, 0.25)
+    );
+    assert_eq!(
+        wavetable.next_sample(channels, 100., 1., 1000.),
+        (0.75, 0.75)
+    );
+
+    // A quarter of a round trip every sample goes to the end and back
+    let mut wavetable = Wavetable {
+        scan_hz: 250.,
+        ..Wavetable::new(1, 4, 3)
+    };
+    let scanned: Vec<f32> = (0..5)
+        .map(|_| wavetable.next_sample(channels, 100., 0., 1000.).0)
+        .collect();
+    assert_eq!(scanned, vec![0., 0.5, 1., 0.5, 0.]);
+}
+
+#[test]
+fn wavetable_voices_play_notes_from_the_table() {
+    use super::midi::handle_midi_event;
+    use super::notes::{set_note_mode, ROOT_NOTE};
+    use super::params::{GlobalParam, ParamId};
+    use super::DEFAULT_SAMPLE_RATE;
+
+    // A single frame holding one cycle of a sine
+    let mut ctx = GranularCtx {
+        waveform: (0..100)
+            .map(|i| (i as f32 / 100. * std::f32::consts::TAU).sin())
+            .collect(),
+        ..Default::default()
+    };
+    assert!(!set_voice_wavetable(&mut ctx, 0, 0, 0, 1));
+    assert!(set_voice_wavetable(&mut ctx, 0, 0, 100, 1));
+    ctx.params
+        .set_target(ParamId::Global(GlobalParam::SelectionEndSampleIx), 99.);
+    set_note_mode(&mut ctx, true, false, 2.);
+    // An octave above the root note
+    handle_midi_event(&mut ctx, 0x90, ROOT_NOTE + 12, 127);
+    let targets = *ctx.params.target();
+    let mut output = Vec::new();
+    for _ in 0..16 {
+        ctx.render(&targets);
+        output.extend_from_slice(&ctx.rendered_output);
+    }
+    assert!(ctx.voices[0].grains.is_empty());
+    let rising_zero_crossings = output
+        .windows(2)
+        .filter(|pair| pair[0] <= 0. && pair[1] > 0.)
+        .count();
+    let expected = 2. * ROOT_FREQUENCY_HZ * output.len() as f32 / DEFAULT_SAMPLE_RATE;
+    assert!((rising_zero_crossings as f32 - expected).abs() <= 1.);
+
+    clear_voice_wavetable(&mut ctx, 0);
+    assert!(ctx.voices[0].wavetable.is_none());
+}
@@ -0,0 +1,95 @@
+//! Grain window tables.  Working out a voice's envelope takes a few transcendental functions
+//! for every playing grain on every sample, so each voice keeps a table of its envelope sampled
+//! over the length of a grain and grains read it with linear interpolation instead.  The table is
+//! indexed by the position in the grain rather than by sample, so grains of every length share
+//! it.
+//!
+//! Tables are only rebuilt when the voice's envelope settings change or its slope, skew and
+//! morph parameters move to new values and then hold them for a whole frame.  While the
+//! parameters are still moving, e.g. under modulation or smoothing, grains work the envelope out
+//! directly, which keeps it exact and saves rebuilding a table that would be out of date by the
+//! next sample.
+
+use super::envelope::EnvelopeParams;
+use crate::dsp::read_interpolated;
+
+/// Segments of the table between grain start and end.  Enough that interpolating the most
+/// ripples a voice can have stays within a thousandth of the exact envelope.
+pub const WINDOW_TABLE_LEN: usize = 4096;
+
+#[derive(Clone, Default)]
+pub struct GrainWindow {
+    /// `WINDOW_TABLE_LEN + 1` gains, from the start of the grain to its end
+    table: Vec<f32>,
+    /// Parameters the table was built for, or None if it's out of date
+    built_for: Option<EnvelopeParams>,
+    /// Parameters at the start of the last frame
+    last_frame: Option<EnvelopeParams>,
+}
+
+impl GrainWindow {
+    /// Marks the table out of date after the voice's envelope settings change
+    pub fn invalidate(&mut self) {
+        self.built_for = None;
+    }
+
+    /// Whether the table is up to date for `params`
+    #[inline]
+    pub fn is_built_for(&self, params: EnvelopeParams) -> bool {
+        self.built_for == Some(params)
+    }
+
+    /// The table if it's up to date for `params`
+    #[inline]
+    pub fn built_for(&self, params: EnvelopeParams) -> Option<&Self> {
+        self.is_built_for(params).then_some(self)
+    }
+
+    /// Rebuilds the table from `gain_at` if it's out of date and the parameters have held still
+    /// since the start of the last frame.  Runs once per frame.
+    pub fn update(&mut self, params: EnvelopeParams, gain_at: impl Fn(f32) -> f32) {
+        let held = self.last_frame.replace(params) == Some(params);
+        if !held || self.is_built_for(params) {
+            return;
+        }
+        self.table.clear();
+        self.table
+            .extend((0..=WINDOW_TABLE_LEN).map(|ix| gain_at(ix as f32 / WINDOW_TABLE_LEN as f32)));
+        self.built_for = Some(params);
+    }
+
+    /// Gain at `pos_in_grain`, which is only the voice's envelope if the table is `built_for` its
+    /// parameters
+    #[inline]
+    pub fn gain_at(&self, pos_in_grain: f32) -> f32 {
+        let index = pos_in_grain.clamp(0., 1.) * WINDOW_TABLE_LEN as f32;
+        read_interpolated(&self.table, index)
+    }
+}
+
+#[test]
+fn window_tables_wait_for_parameters_to_settle() {
+    let params = EnvelopeParams {
+        linear_slope_length: 0.5,
+        slope_linearity: 0.,
+        skew: 0.,
+        morph: 0.,
+    };
+    let sine = |pos: f32| (pos * std::f32::consts::PI).sin();
+    let mut window = GrainWindow::default();
+    window.update(params, sine);
+    assert!(window.built_for(params).is_none());
+    window.update(params, sine);
+    assert_eq!(window.built_for(params).unwrap().gain_at(0.5), 1.);
+    assert!((window.gain_at(0.1) - sine(0.1)).abs() < 1e-6);
+
+    let moved = EnvelopeParams {
+        skew: 0.5,
+        ..params
+    };
+    assert!(window.built_for(moved).is_none());
+    window.invalidate();
+    assert!(window.built_for(params).is_none());
+    window.update(params, sine);
+    assert!(window.is_built_for(params));
+}
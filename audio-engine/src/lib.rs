@@ -5,73 +5,2559 @@ mod common;
 mod dsp;
 mod granular;
 
-use granular::GranularCtx;
+use granular::handles::{guard, with_instance, InstanceHandle};
 use wasm_bindgen::prelude::*;
 
-/// Create a new granular synthesis instance
+// Native Rust API
+pub use dsp::processor::AudioProcessor;
+pub use dsp::resample;
+
+/// Filters and effects that are `AudioProcessor`s, for building chains around the engine
+pub mod processors {
+    pub use crate::dsp::convolution::{Convolver, ImpulseResponse};
+    pub use crate::dsp::delay::ModulatedDelay;
+    pub use crate::dsp::dynamics::{Compressor, Limiter, TransientShaper};
+    pub use crate::dsp::filters::allpass::{FirstOrderAllpass, SecondOrderAllpass};
+    pub use crate::dsp::filters::biquad::{Biquad, BiquadCoefficients};
+    pub use crate::dsp::filters::butterworth::ButterworthFilter;
+    pub use crate::dsp::filters::comb::{CombFilter, CombKind};
+    pub use crate::dsp::filters::tilt::{Tilt, TiltFilter};
+    pub use crate::dsp::float::Float;
+    pub use crate::dsp::phaser::{Phaser, PhaserSettings};
+    pub use crate::dsp::processor::Configured;
+    pub use crate::dsp::resonator::CombResonator;
+    pub use crate::dsp::saturation::{SaturationModel, Saturator};
+}
+pub use dsp::{read_interpolated_with, EndPolicy};
+pub use granular::native::{
+    ConfigError, Granular, GranularConfig, GranularConfigBuilder, GranularEngine, Samples,
+};
+#[cfg(feature = "serde")]
+pub use granular::params::ParamsJsonError;
+pub use granular::params::{GlobalParam, GranularParams, ParamId, ParamRangeError, VoiceParam};
+
+/// Create a new granular synthesis instance and return its handle, which every other call takes,
+/// or 0 if no more instances can be created
+/// Handles of freed instances stay invalid, so calls with them do nothing instead of reaching
+/// another instance
 #[wasm_bindgen]
-pub fn create_granular_instance() -> *mut GranularCtx {
+pub fn create_granular_instance() -> InstanceHandle {
     granular::create_granular_instance()
 }
 
 /// Get a pointer to the waveform buffer for setting audio data
-/// This allocates a buffer of the specified length
+/// This allocates a buffer of the specified length. Renders are silent until the host has filled
+/// it and called `commit_waveform`. Returns null if the length is over the longest waveform the
+/// engine loads
+#[wasm_bindgen]
+pub fn get_granular_waveform_ptr(ctx: InstanceHandle, new_waveform_len: usize) -> *mut f32 {
+    guard(ctx, |ctx| {
+        granular::get_granular_waveform_ptr(ctx, new_waveform_len)
+    })
+}
+
+/// Get a pointer to a staging buffer of `len` samples for the next waveform
+/// The current waveform keeps playing while the host fills it; call `swap_staging_waveform` once
+/// it's complete
+#[wasm_bindgen]
+pub fn get_staging_waveform_ptr(ctx: InstanceHandle, len: usize) -> *mut f32 {
+    guard(ctx, |ctx| granular::get_staging_waveform_ptr(ctx, len))
+}
+
+/// Get a pointer to the right channel of the staged waveform, making it a stereo waveform
+/// Returns null if nothing has been staged or `len` isn't the length of the staged left channel
+#[wasm_bindgen]
+pub fn get_staging_waveform_right_ptr(ctx: InstanceHandle, len: usize) -> *mut f32 {
+    guard(ctx, |ctx| {
+        granular::get_staging_waveform_right_ptr(ctx, len)
+    })
+}
+
+/// Swap the staged waveform in at the start of the next frame
+/// Grains playing from the old waveform fade out over `crossfade_ms` while new grains read the
+/// new one. Returns false if nothing has been staged
+#[wasm_bindgen]
+pub fn swap_staging_waveform(ctx: InstanceHandle, crossfade_ms: f32) -> bool {
+    guard(ctx, |ctx| {
+        granular::swap_staging_waveform(ctx, crossfade_ms)
+    })
+}
+
+/// Swap the staged waveform in at the start of the next frame without fading the old one
+/// Grains playing from the old waveform play out in full while new grains read the new one.
+/// Returns false if nothing has been staged
+#[wasm_bindgen]
+pub fn swap_staging_waveform_gapless(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::swap_staging_waveform_gapless)
+}
+
+/// Set the sample rate of waveforms loaded from now on so they're resampled to the engine's rate
+/// quality: 0 = fast, 1 = standard, 2 = best. A rate of 0 means waveforms are at the engine's rate
+/// and unsupported rates are ignored. Loads resample within the call
+#[wasm_bindgen]
+pub fn set_waveform_source_rate(ctx: InstanceHandle, source_sample_rate: f32, quality: u32) {
+    guard(ctx, |ctx| {
+        granular::set_waveform_source_rate(ctx, source_sample_rate, quality)
+    })
+}
+
+/// Set the processing of waveforms loaded from now on
+/// remove_dc: subtract each channel's DC offset
+/// mode: 0 = no normalization, 1 = peak to `target` dBFS, 2 = integrated loudness to `target` LUFS
+#[wasm_bindgen]
+pub fn set_waveform_normalization(ctx: InstanceHandle, remove_dc: bool, mode: u32, target: f32) {
+    guard(ctx, |ctx| {
+        granular::set_waveform_normalization(ctx, remove_dc, mode, target)
+    })
+}
+
+/// Set the format waveforms loaded from now on are kept in: 0 = 32-bit float, 1 = 16-bit
+/// 16-bit storage halves the memory at some cost in quality, and can't be overdubbed
+#[wasm_bindgen]
+pub fn set_waveform_storage(ctx: InstanceHandle, storage: u32) {
+    guard(ctx, |ctx| granular::set_waveform_storage(ctx, storage))
+}
+
+/// Get a JSON array describing each waveform storage format (ID, name, bytes per sample and a
+/// note on its quality) for `set_waveform_storage`
+#[wasm_bindgen]
+pub fn get_waveform_storage_metadata() -> String {
+    granular::get_waveform_storage_metadata()
+}
+
+/// Set how many samples apart control-rate parameters, automation lanes, window tables and
+/// density compensation update, from 16 to 2048; the default is one 128-sample frame
+/// Returns false, keeping the current size, if it's out of range
+#[wasm_bindgen]
+pub fn set_processing_chunk_size(ctx: InstanceHandle, chunk_size: usize) -> bool {
+    guard(ctx, |ctx| {
+        granular::set_processing_chunk_size(ctx, chunk_size)
+    })
+}
+
+/// Commit the waveform buffer once all `written_len` samples have been copied into it
+/// Returns false if that isn't the length it was allocated with, in which case renders stay silent
+#[wasm_bindgen]
+pub fn commit_waveform(ctx: InstanceHandle, written_len: usize) -> bool {
+    guard(ctx, |ctx| granular::commit_waveform(ctx, written_len))
+}
+
+/// Apply the load options to a committed waveform written through `get_granular_waveform_ptr`
+/// Returns the new length of the waveform, or 0 before it's committed. Uploads and staged swaps are
+/// processed automatically
+#[wasm_bindgen]
+pub fn process_loaded_waveform(ctx: InstanceHandle) -> usize {
+    guard(ctx, granular::process_loaded_waveform)
+}
+
+/// Load interleaved multichannel audio
+/// Channels are summed to mono unless `keep_stereo` is set, in which case the first two channels
+/// are granulated in stereo. Returns the length of the loaded waveform in samples
+#[wasm_bindgen]
+pub fn import_interleaved_waveform(
+    ctx: InstanceHandle,
+    interleaved: &[f32],
+    channel_count: usize,
+    keep_stereo: bool,
+) -> usize {
+    guard(ctx, |ctx| {
+        granular::import_interleaved_waveform(ctx, interleaved, channel_count, keep_stereo)
+    })
+}
+
+/// Get the number of samples allocated for the waveform
+#[wasm_bindgen]
+pub fn get_waveform_capacity(ctx: InstanceHandle) -> usize {
+    guard(ctx, granular::get_waveform_capacity)
+}
+
+/// Declare how many samples at the start of the waveform hold audio
+/// Renders whose selection reaches past this length are then rejected and rendered silent with
+/// the `SELECTION_OUT_OF_BOUNDS` status flag. Cleared by loading a new waveform. Returns false if
+/// it exceeds the capacity
+#[wasm_bindgen]
+pub fn set_waveform_valid_len(ctx: InstanceHandle, valid_len: usize) -> bool {
+    guard(ctx, |ctx| granular::set_waveform_valid_len(ctx, valid_len))
+}
+
+/// Make grains read `len` samples at `ptr` in WASM memory directly instead of a copy
+/// The memory must stay valid and unmodified until unbound or until another waveform is loaded.
+/// Returns the generation of the binding for `unbind_external_waveform`, or 0 on failure
+#[wasm_bindgen]
+pub fn bind_external_waveform(ctx: InstanceHandle, ptr: *const f32, len: usize) -> u32 {
+    guard(ctx, |ctx| granular::bind_external_waveform(ctx, ptr, len))
+}
+
+/// Stop reading the external waveform bound with `generation` so its memory can be reused
+/// Returns false if that binding is no longer current
+#[wasm_bindgen]
+pub fn unbind_external_waveform(ctx: InstanceHandle, generation: u32) -> bool {
+    guard(ctx, |ctx| {
+        granular::unbind_external_waveform(ctx, generation)
+    })
+}
+
+/// Get the generation of the bound external waveform, or 0 if none is bound
+#[wasm_bindgen]
+pub fn get_external_waveform_generation(ctx: InstanceHandle) -> u32 {
+    guard(ctx, granular::get_external_waveform_generation)
+}
+
+/// Trim the waveform to the samples from `start` up to but not including `end`, freeing the rest
+/// Selections sent afterwards are relative to the new start. Returns the new length, or 0 if the
+/// region is empty, in which case the waveform is left alone
+#[wasm_bindgen]
+pub fn crop_waveform(ctx: InstanceHandle, start: usize, end: usize) -> usize {
+    guard(ctx, |ctx| granular::crop_waveform(ctx, start, end))
+}
+
+/// Start streaming in a new waveform of `total_len` samples
+/// The current waveform keeps playing until the upload is finished. Returns false if the length
+/// is over the longest waveform the engine loads
+#[wasm_bindgen]
+pub fn begin_waveform_upload(ctx: InstanceHandle, total_len: usize) -> bool {
+    guard(ctx, |ctx| granular::begin_waveform_upload(ctx, total_len))
+}
+
+/// Append the next chunk of the waveform being uploaded
+/// Returns false if no upload is in progress or the chunk runs past the announced length
+#[wasm_bindgen]
+pub fn append_waveform_chunk(ctx: InstanceHandle, chunk: &[f32]) -> bool {
+    guard(ctx, |ctx| granular::append_waveform_chunk(ctx, chunk))
+}
+
+/// Replace the waveform with the uploaded one
+/// Returns false, keeping the current waveform, if fewer samples than announced were appended
+#[wasm_bindgen]
+pub fn finish_waveform_upload(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::finish_waveform_upload)
+}
+
+/// Compute the RMS level of every `window_ms` of the loaded waveform
+/// Returns one level per window, in order. The engine keeps the envelope for energy-weighted grain
+/// positions, so call this again after loading a new waveform
+#[wasm_bindgen]
+pub fn compute_rms_envelope(ctx: InstanceHandle, window_ms: f32) -> Vec<f32> {
+    guard(ctx, |ctx| {
+        granular::analysis::compute_rms_envelope(ctx, window_ms)
+    })
+}
+
+/// Set how strongly randomized grain start positions favour loud regions of the waveform
+/// 0 picks positions uniformly, 1 picks them in proportion to the RMS envelope
+#[wasm_bindgen]
+pub fn set_position_weighting(ctx: InstanceHandle, amount: f32) {
+    guard(ctx, |ctx| {
+        granular::analysis::set_position_weighting(ctx, amount)
+    })
+}
+
+/// Measure the energy and spectral centroid of every `region_ms` of the loaded waveform
+/// Returns interleaved `[rms, centroid_hz]` pairs, one per region. The engine keeps the features
+/// for `set_feature_weighting`, so call this again after loading a new waveform
+#[wasm_bindgen]
+pub fn analyze_features(ctx: InstanceHandle, region_ms: f32) -> Vec<f32> {
+    guard(ctx, |ctx| {
+        granular::analysis::analyze_features(ctx, region_ms)
+    })
+}
+
+/// Make grain spawning favour regions of the waveform with certain features
+/// preference: 0 = loud, 1 = quiet, 2 = bright, 3 = dark
+/// amount: 0 spawns every grain, 1 spawns grains in proportion to how well their region matches
+#[wasm_bindgen]
+pub fn set_feature_weighting(ctx: InstanceHandle, preference: u32, amount: f32) {
+    guard(ctx, |ctx| {
+        granular::analysis::set_feature_weighting(ctx, preference, amount)
+    })
+}
+
+/// Split the loaded waveform into segments of `segment_ms` and measure each for concatenative mode
+/// Returns interleaved `[rms, centroid_hz, pitch_hz]` triplets, one per segment, with a pitch of
+/// 0 for unpitched segments. Call this again after loading a new waveform
+#[wasm_bindgen]
+pub fn analyze_corpus(ctx: InstanceHandle, segment_ms: f32) -> Vec<f32> {
+    guard(ctx, |ctx| granular::corpus::analyze_corpus(ctx, segment_ms))
+}
+
+/// Track the pitch of every `window_ms` of the loaded waveform for PSOLA voices
+/// Returns one pitch in Hz per window, 0 for unpitched windows. Call this again after loading a
+/// new waveform
+#[wasm_bindgen]
+pub fn analyze_pitch(ctx: InstanceHandle, window_ms: f32) -> Vec<f32> {
+    guard(ctx, |ctx| granular::analysis::analyze_pitch(ctx, window_ms))
+}
+
+/// Enable or disable concatenative mode, where each grain plays the corpus segment best matching
+/// the target
+/// candidates: number of best matches each grain is picked from at random (1 to 16)
+#[wasm_bindgen]
+pub fn set_concatenative_mode(ctx: InstanceHandle, enabled: bool, candidates: u32) {
+    guard(ctx, |ctx| {
+        granular::corpus::set_concatenative_mode(ctx, enabled, candidates)
+    })
+}
+
+/// Set how much energy, brightness and pitch differences count when matching segments (0 to 10)
+#[wasm_bindgen]
+pub fn set_concatenative_weights(ctx: InstanceHandle, energy: f32, brightness: f32, pitch: f32) {
+    guard(ctx, |ctx| {
+        granular::corpus::set_concatenative_weights(ctx, energy, brightness, pitch)
+    })
+}
+
+/// Match grains to fixed features, e.g. from a control input
+/// pitch_hz: 0 or less matches segments regardless of pitch
+#[wasm_bindgen]
+pub fn set_concatenative_target(ctx: InstanceHandle, energy: f32, centroid_hz: f32, pitch_hz: f32) {
+    guard(ctx, |ctx| {
+        granular::corpus::set_concatenative_target(ctx, energy, centroid_hz, pitch_hz)
+    })
+}
+
+/// Match grains to the feature trajectory of another buffer, followed in real time and looped
+/// Returns the number of target segments, or 0 if there's no corpus or the buffer is too short
 #[wasm_bindgen]
-pub fn get_granular_waveform_ptr(ctx: *mut GranularCtx, new_waveform_len: usize) -> *mut f32 {
-    granular::get_granular_waveform_ptr(ctx, new_waveform_len)
+pub fn set_concatenative_target_buffer(ctx: InstanceHandle, samples: &[f32]) -> usize {
+    guard(ctx, |ctx| {
+        granular::corpus::set_concatenative_target_buffer(ctx, samples)
+    })
 }
 
 /// Render a frame of 128 samples with granular synthesis
-/// Returns a pointer to the output buffer (128 samples)
+/// Returns a pointer to the output buffer of the layout set with `configure_output`: 128 mono
+/// samples by default
+/// Parameters are smoothed internally, so hosts can pass new values once per frame
+/// Per-voice parameters are read from the buffer at `get_voice_render_params_ptr`
 #[wasm_bindgen]
 pub fn render_granular(
-    ctx: *mut GranularCtx,
+    ctx: InstanceHandle,
     selection_start_sample_ix: f32,
     selection_end_sample_ix: f32,
     grain_size: f32,
-    voice_1_filter_cutoff: f32,
-    voice_2_filter_cutoff: f32,
     linear_slope_length: f32,
     slope_linearity: f32,
-    voice_1_movement_samples_per_sample: f32,
-    voice_2_movement_samples_per_sample: f32,
-    voice_1_sample_speed_ratio: f32,
-    voice_2_sample_speed_ratio: f32,
-    voice_1_samples_between_grains: f32,
-    voice_2_samples_between_grains: f32,
-    voice_1_gain: f32,
-    voice_2_gain: f32,
-    voice_1_grain_start_randomness_samples: f32,
-    voice_2_grain_start_randomness_samples: f32,
 ) -> *const f32 {
-    granular::render_granular(
-        ctx,
-        selection_start_sample_ix,
-        selection_end_sample_ix,
-        grain_size,
-        voice_1_filter_cutoff,
-        voice_2_filter_cutoff,
-        linear_slope_length,
-        slope_linearity,
-        voice_1_movement_samples_per_sample,
-        voice_2_movement_samples_per_sample,
-        voice_1_sample_speed_ratio,
-        voice_2_sample_speed_ratio,
-        voice_1_samples_between_grains,
-        voice_2_samples_between_grains,
-        voice_1_gain,
-        voice_2_gain,
-        voice_1_grain_start_randomness_samples,
-        voice_2_grain_start_randomness_samples,
-    )
+    guard(ctx, |ctx| {
+        granular::render_granular(
+            ctx,
+            selection_start_sample_ix,
+            selection_end_sample_ix,
+            grain_size,
+            linear_slope_length,
+            slope_linearity,
+        )
+    })
+}
+
+/// Get a pointer to the per-voice parameters `render_granular` reads, for writing them in place
+/// One row of a value per voice for each of filter cutoff, movement, speed ratio, spacing, gain
+/// and start randomness, in that order. Values that aren't finite keep the voice's current target.
+/// The buffer doesn't move, though views have to be recreated if the WASM memory grows
+#[wasm_bindgen]
+pub fn get_voice_render_params_ptr(ctx: InstanceHandle) -> *mut f32 {
+    guard(ctx, granular::get_voice_render_params_ptr)
+}
+
+/// Get a pointer to the level meters of the last rendered frame (10 values)
+/// Linear RMS and peak pairs for voice 1, voice 2, master left and master right, followed by the
+/// phase correlation of the master channels (-1 to 1) for checking mono compatibility and the
+/// largest gain reduction applied by the output limiter during the frame in dB
+#[wasm_bindgen]
+pub fn get_meters(ctx: InstanceHandle) -> *const f32 {
+    guard(ctx, granular::meters::get_meters)
+}
+
+/// Get the K-weighted loudness of the master output in LUFS
+/// `measurement`: 0 = momentary (400 ms), 1 = short-term (3 s), 2 = integrated since the last
+/// `reset_loudness`. Returns -Infinity until enough audio has been rendered
+#[cfg(feature = "loudness")]
+#[wasm_bindgen]
+pub fn get_loudness(ctx: InstanceHandle, measurement: u32) -> f32 {
+    guard(ctx, |ctx| granular::meters::get_loudness(ctx, measurement))
+}
+
+/// Restart the loudness measurement, e.g. at the start of an offline render
+#[cfg(feature = "loudness")]
+#[wasm_bindgen]
+pub fn reset_loudness(ctx: InstanceHandle) {
+    guard(ctx, granular::meters::reset_loudness)
+}
+
+/// Get the number of output samples that exceeded ±1 and were clamped since the last reset
+/// The `OUTPUT_CLIPPED` status flag is set for every frame in which this happens
+#[wasm_bindgen]
+pub fn get_clip_count(ctx: InstanceHandle) -> u32 {
+    guard(ctx, granular::meters::get_clip_count)
+}
+
+/// Reset the clip counter, e.g. when the user clicks the clip indicator
+#[wasm_bindgen]
+pub fn reset_clip_count(ctx: InstanceHandle) {
+    guard(ctx, granular::meters::reset_clip_count)
+}
+
+/// Get a pointer to the grain statistics of the last frame (4 values per voice)
+/// Grains spawned during the frame, active grains, their average length in samples and the
+/// effective density in grains per second
+#[wasm_bindgen]
+pub fn get_grain_stats(ctx: InstanceHandle) -> *const f32 {
+    guard(ctx, granular::stats::get_grain_stats)
+}
+
+/// Make a voice read the selection as if the waveform were reversed
+/// Layering a reversed voice over a forward one gives classic forward/backward textures
+#[wasm_bindgen]
+pub fn set_voice_reversed_source(ctx: InstanceHandle, voice_ix: usize, reversed: bool) {
+    guard(ctx, |ctx| {
+        granular::set_voice_reversed_source(ctx, voice_ix, reversed)
+    })
+}
+
+/// Make a voice's grains follow a drawn envelope instead of the built-in slope shape
+/// The table is read from the start of each grain to its end with interpolation and its values
+/// are clamped to 0..1. An empty table restores the built-in shape. Returns false if the table
+/// has more than 4096 values or contains non-finite ones
+#[wasm_bindgen]
+pub fn set_grain_envelope_table(ctx: InstanceHandle, voice_ix: usize, table: &[f32]) -> bool {
+    guard(ctx, |ctx| {
+        granular::envelope::set_grain_envelope_table(ctx, voice_ix, table)
+    })
+}
+
+/// Give a voice's grains separate attack and release slopes
+/// Lengths are fractions of the grain (slopes adding up to more than the grain are shortened to
+/// meet) and linearities mix between a quarter sine (0) and a straight line (1). Overrides
+/// `linear_slope_length` and `slope_linearity` for this voice. Returns false for non-finite values
+#[wasm_bindgen]
+pub fn set_voice_grain_slopes(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    attack_length: f32,
+    attack_linearity: f32,
+    release_length: f32,
+    release_linearity: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::envelope::set_voice_grain_slopes(
+            ctx,
+            voice_ix,
+            attack_length,
+            attack_linearity,
+            release_length,
+            release_linearity,
+        )
+    })
+}
+
+/// Set the curves of a voice's attack and release slopes
+/// 0 = sine/linear blend set by the slope linearity (default), 1 = linear, 2 = raised cosine,
+/// 3 = exponential, 4 = logarithmic. Returns false for unknown curves
+#[wasm_bindgen]
+pub fn set_voice_slope_curves(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    attack_curve: u32,
+    release_curve: u32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::envelope::set_voice_slope_curves(ctx, voice_ix, attack_curve, release_curve)
+    })
+}
+
+/// Give a voice its own slope length and linearity instead of the global ones
+/// Lets one voice play smooth clouds while another plays clicks. Both are clamped to 0..1 and
+/// smoothed. Returns false for non-finite values
+#[wasm_bindgen]
+pub fn set_voice_slope_shape(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    slope_length: f32,
+    slope_linearity: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::envelope::set_voice_slope_shape(ctx, voice_ix, slope_length, slope_linearity)
+    })
+}
+
+/// Make a voice follow the global `linear_slope_length` and `slope_linearity` again
+#[wasm_bindgen]
+pub fn clear_voice_slope_shape(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::envelope::clear_voice_slope_shape(ctx, voice_ix)
+    })
+}
+
+/// Make a voice's grains use the symmetric global slopes again
+#[wasm_bindgen]
+pub fn clear_voice_grain_slopes(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::envelope::clear_voice_grain_slopes(ctx, voice_ix)
+    })
+}
+
+/// Render each of the next `count` grains spawned, up to 16, into its own buffer as it plays,
+/// replacing earlier captures
+/// The grains are rendered with their envelope, before the voice's filter, gain and pan, and cut
+/// short after 2^18 samples
+#[wasm_bindgen]
+pub fn capture_grains(ctx: InstanceHandle, count: usize) {
+    guard(ctx, |ctx| granular::capture::capture_grains(ctx, count))
+}
+
+/// Get the number of grains captured so far, which are numbered in the order they finished
+#[wasm_bindgen]
+pub fn get_captured_grain_count(ctx: InstanceHandle) -> usize {
+    guard(ctx, granular::capture::get_captured_grain_count)
+}
+
+/// Get a captured grain's voice index, start position in samples, playback ratio and length in
+/// samples
+#[wasm_bindgen]
+pub fn get_captured_grain_info(ctx: InstanceHandle, grain_ix: usize) -> Vec<f64> {
+    guard(ctx, |ctx| {
+        granular::capture::get_captured_grain_info(ctx, grain_ix)
+    })
+}
+
+/// Get the mono samples of a captured grain
+#[wasm_bindgen]
+pub fn get_captured_grain_samples(ctx: InstanceHandle, grain_ix: usize) -> Vec<f32> {
+    guard(ctx, |ctx| {
+        granular::capture::get_captured_grain_samples(ctx, grain_ix)
+    })
+}
+
+/// Enable or disable tracing of grain spawn and end events; enabling it starts a fresh trace
+#[wasm_bindgen]
+pub fn set_grain_trace(ctx: InstanceHandle, enabled: bool) {
+    guard(ctx, |ctx| granular::trace::set_grain_trace(ctx, enabled))
+}
+
+/// Take the grain events recorded since the last call, oldest first (6 values per event)
+/// Timestamp in samples since tracing was enabled, kind (0 = spawn, 1 = end), voice index, start
+/// position in samples, length in samples and playback ratio. The trace holds the newest 4096
+/// events, so drain it at least that often
+#[wasm_bindgen]
+pub fn get_grain_trace(ctx: InstanceHandle) -> Vec<f64> {
+    guard(ctx, granular::trace::get_grain_trace)
+}
+
+/// Start recording a data track of every grain onset plus each voice's playhead position every
+/// `interval_samples`, replacing any earlier track, or stop and drop it
+/// The track is allocated here and keeps the newest 65536 rows, overwriting the oldest after that
+#[wasm_bindgen]
+pub fn set_data_track(ctx: InstanceHandle, enabled: bool, interval_samples: u32) {
+    guard(ctx, |ctx| {
+        granular::trace::set_data_track(ctx, enabled, interval_samples)
+    })
+}
+
+/// Get the data track recorded so far as CSV, or an empty string if it isn't enabled
+/// Columns are timestamp in samples since the track started, kind (playhead or onset), voice
+/// index, position in samples, then the grain's length in samples and playback ratio for onsets
+#[wasm_bindgen]
+pub fn export_data_track_csv(ctx: InstanceHandle) -> String {
+    guard(ctx, granular::trace::export_data_track_csv)
+}
+
+/// Enable or disable render profiling; enabling it resets the collected timings
+/// Profiling reads the clock twice per sample, so leave it off unless diagnosing overruns
+#[wasm_bindgen]
+pub fn set_profiling(ctx: InstanceHandle, enabled: bool) {
+    guard(ctx, |ctx| granular::profile::set_profiling(ctx, enabled))
+}
+
+/// Get a pointer to the render timings in milliseconds (6 values)
+/// Last render, running average, maximum, then the control, synthesis and output stages of the
+/// last render
+#[wasm_bindgen]
+pub fn get_profile(ctx: InstanceHandle) -> *const f32 {
+    guard(ctx, granular::profile::get_profile)
+}
+
+/// Get a pointer to the packed parameter block, a versioned header followed by every parameter
+/// value, which `render_granular_block` reads
+#[wasm_bindgen]
+pub fn get_param_block_ptr(ctx: InstanceHandle) -> *mut u32 {
+    guard(ctx, granular::get_param_block_ptr)
+}
+
+/// Render a frame with the parameters in the packed parameter block
+#[wasm_bindgen]
+pub fn render_granular_block(ctx: InstanceHandle) -> *const f32 {
+    guard(ctx, granular::render_granular_block)
+}
+
+/// Get a pointer to the shared parameter block, a sequence counter followed by a packed parameter
+/// block, which another thread can write. Make the counter odd with an atomic add before writing
+/// values and even again after, and `render_granular_shared` only picks up finished updates
+/// Only in native builds and WASM builds with shared memory (`pnpm run build:wasm:shared`), where
+/// the main thread can write it through a `SharedArrayBuffer` view of the module's memory
+#[cfg(shared_memory)]
+#[wasm_bindgen]
+pub fn get_shared_param_block_ptr(ctx: InstanceHandle) -> *mut u32 {
+    guard(ctx, granular::get_shared_param_block_ptr)
+}
+
+/// Render a frame with the parameters last written in full to the shared parameter block
+#[cfg(shared_memory)]
+#[wasm_bindgen]
+pub fn render_granular_shared(ctx: InstanceHandle) -> *const f32 {
+    guard(ctx, granular::render_granular_shared)
+}
+
+/// Get a pointer to the I/O block: a header of magic, version, status, frames rendered, events
+/// queued, event capacity and the offsets in words of its sections, followed by a packed
+/// parameter block, events, audio and sidechain input, output and meters
+#[wasm_bindgen]
+pub fn get_io_block_ptr(ctx: InstanceHandle) -> *mut u32 {
+    guard(ctx, granular::io_block::get_io_block_ptr)
+}
+
+/// Render a frame in a single call from the I/O block, which takes its parameters, events and
+/// inputs and gets back the output, meters and status
+/// Events are commands like the command ring's but timed by their sample in the frame
+#[wasm_bindgen]
+pub fn process_io_block(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::io_block::process_io_block)
+}
+
+/// Get a pointer to the command ring, a header of write count, read count, clock and capacity
+/// followed by commands of kind, time and two arguments
+/// Each command runs on the sample of its time: 0 = load slot (slot id staged with
+/// `stage_sample_slot`, crossfade ms), 1 = set selection (start, end), 2 = release selection,
+/// 3 = note on (note, velocity), 4 = note off (note), 5 = reset (fade ms), 6 = drone hold (1 to
+/// hold, 0 to release), 7 = stutter (1 to engage, 0 to release)
+/// Other threads can write it in shared-memory builds; otherwise only the thread that renders can
+#[wasm_bindgen]
+pub fn get_command_ring_ptr(ctx: InstanceHandle) -> *mut u32 {
+    guard(ctx, granular::commands::get_command_ring_ptr)
+}
+
+/// Get the time in samples of the next sample to be rendered, which commands are timed against
+#[wasm_bindgen]
+pub fn get_command_clock(ctx: InstanceHandle) -> u32 {
+    guard(ctx, granular::commands::get_command_clock)
+}
+
+/// Render `duration_seconds` of output with the current parameters and automation as interleaved
+/// stereo, e.g. in a worker. `progress(renderedFrames, totalFrames)` is called every
+/// `progress_interval` frames of 128 samples and once at the end
+/// Renders stop after 2^17 frames and run on a copy of the instance, so live playback carries on
+/// where it was
+#[wasm_bindgen]
+pub fn render_offline(
+    ctx: InstanceHandle,
+    duration_seconds: f32,
+    progress_interval: usize,
+    progress: &js_sys::Function,
+) -> Vec<f32> {
+    guard(ctx, |ctx| {
+        granular::render_offline(
+            ctx,
+            duration_seconds,
+            progress_interval,
+            |rendered, total| {
+                let _ = progress.call2(
+                    &JsValue::NULL,
+                    &JsValue::from(rendered as u32),
+                    &JsValue::from(total as u32),
+                );
+            },
+        )
+    })
+}
+
+/// Start a chunked offline render from the beginning of the automation lanes, on a copy of the
+/// instance so live playback carries on where it was
+#[wasm_bindgen]
+pub fn start_chunked_render(ctx: InstanceHandle) {
+    guard(ctx, granular::start_chunked_render)
+}
+
+/// Render the next `frames` frames, up to 2^17, of a chunked offline render with the current
+/// parameters
+/// Returns a pointer to `frames * 256` interleaved stereo samples, which stays valid until the next
+/// call, so stream each chunk to disk before rendering the next one, or null if no chunked render
+/// was started
+#[wasm_bindgen]
+pub fn render_next_chunk(ctx: InstanceHandle, frames: usize) -> *const f32 {
+    guard(ctx, |ctx| granular::render_next_chunk(ctx, frames))
+}
+
+/// Render `duration_frames` samples, up to 2^24, of the current texture in stereo into a new sample
+/// slot and return its id, or 2^32 - 1 if there's nothing to render. The render runs on a copy of
+/// the instance, so live playback carries on where it was
+#[wasm_bindgen]
+pub fn bounce_selection(ctx: InstanceHandle, duration_frames: usize) -> u32 {
+    guard(ctx, |ctx| granular::bounce_selection(ctx, duration_frames))
+}
+
+/// Copy a sample slot into the staged waveform without swapping it in, for a load slot command
+/// Commands only load slots staged this way, so that the render never copies a slot. Returns false
+/// if the slot doesn't exist
+#[wasm_bindgen]
+pub fn stage_sample_slot(ctx: InstanceHandle, slot_id: u32) -> bool {
+    guard(ctx, |ctx| granular::slots::stage_sample_slot(ctx, slot_id))
+}
+
+/// Swap a sample slot in as the waveform, crossfading from the current one over `crossfade_ms`
+#[wasm_bindgen]
+pub fn load_sample_slot(ctx: InstanceHandle, slot_id: u32, crossfade_ms: f32) -> bool {
+    guard(ctx, |ctx| {
+        granular::slots::load_sample_slot(ctx, slot_id, crossfade_ms)
+    })
+}
+
+/// Store audio in a new sample slot and return its id, or 2^32 - 1 if there's no audio
+/// The audio is resampled by the load options like a loaded waveform
+#[wasm_bindgen]
+pub fn add_sample_slot(ctx: InstanceHandle, samples: &[f32]) -> u32 {
+    guard(ctx, |ctx| granular::slots::add_sample_slot(ctx, samples))
+}
+
+/// Replace the waveform with a built-in test signal, e.g. to check filters, envelopes and latency
+/// signal: 0 = exponential sine sweep from 20 Hz to 20 kHz at -6 dBFS, 1 = a full-scale impulse
+/// on the first sample, 2 = white noise at -6 dBFS
+/// duration_ms: length of the signal, up to 60 seconds
+/// The load options other than the storage don't apply. Returns false for an unknown signal or a
+/// duration that isn't positive
+#[wasm_bindgen]
+pub fn load_test_signal(ctx: InstanceHandle, signal: u32, duration_ms: f32) -> bool {
+    guard(ctx, |ctx| {
+        granular::load_test_signal(ctx, signal, duration_ms)
+    })
+}
+
+/// Store a test signal, as `load_test_signal` generates it, in a new sample slot and return its
+/// id, or 2^32 - 1 for an unknown signal or a duration that isn't positive
+/// Select the slot for a voice with `set_voice_slot_selection` to play it instead of the waveform
+#[wasm_bindgen]
+pub fn add_test_signal_slot(ctx: InstanceHandle, signal: u32, duration_ms: f32) -> u32 {
+    guard(ctx, |ctx| {
+        granular::add_test_signal_slot(ctx, signal, duration_ms)
+    })
+}
+
+/// Free the audio of a sample slot
+#[wasm_bindgen]
+pub fn free_sample_slot(ctx: InstanceHandle, slot_id: u32) -> bool {
+    guard(ctx, |ctx| granular::slots::free_sample_slot(ctx, slot_id))
+}
+
+/// Play a voice's grains from sample slots instead of the waveform, picking a slot for every grain
+/// policy: 0 = always the first slot, 1 = the slots in turn, 2 = at random, weighted by `weights`
+/// slot_ids: up to 64 slots to pick from
+/// weights: one weight of at least 0 per slot for weighted picks, ignored otherwise
+/// Returns false without changing anything for an unknown policy, no slots or unusable weights
+#[wasm_bindgen]
+pub fn set_voice_slot_selection(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    policy: u32,
+    slot_ids: &[u32],
+    weights: &[f32],
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::slots::set_voice_slot_selection(ctx, voice_ix, policy, slot_ids, weights)
+    })
+}
+
+/// Go back to playing a voice's grains from the waveform
+#[wasm_bindgen]
+pub fn clear_voice_slot_selection(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::slots::clear_voice_slot_selection(ctx, voice_ix)
+    })
+}
+
+/// Freeze a voice: capture its next `loop_ms` of output and loop that instead of granulating live
+#[wasm_bindgen]
+pub fn freeze_voice(ctx: InstanceHandle, voice_ix: usize, loop_ms: f32) {
+    guard(ctx, |ctx| {
+        granular::freeze::freeze_voice(ctx, voice_ix, loop_ms)
+    })
+}
+
+/// Go back to granulating a frozen voice live
+#[wasm_bindgen]
+pub fn unfreeze_voice(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| granular::freeze::unfreeze_voice(ctx, voice_ix))
+}
+
+/// Check whether a frozen voice has finished capturing and is looping
+#[wasm_bindgen]
+pub fn is_voice_frozen(ctx: InstanceHandle, voice_ix: usize) -> bool {
+    guard(ctx, |ctx| granular::freeze::is_voice_frozen(ctx, voice_ix))
+}
+
+/// Overdub the output (`source` 0) or the audio input (1) into the waveform between
+/// `region_start` and `region_end`, keeping `feedback` of the existing audio on every pass
+#[wasm_bindgen]
+pub fn set_overdub(
+    ctx: InstanceHandle,
+    source: u32,
+    region_start: usize,
+    region_end: usize,
+    feedback: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::overdub::set_overdub(ctx, source, region_start, region_end, feedback)
+    })
+}
+
+/// Stop overdubbing
+#[wasm_bindgen]
+pub fn clear_overdub(ctx: InstanceHandle) {
+    guard(ctx, granular::overdub::clear_overdub)
+}
+
+/// Granulate the audio input live from a history of the last `history_seconds` seconds, from
+/// 1 to 60, which replaces the waveform
+/// Returns false for a non-finite length
+#[wasm_bindgen]
+pub fn set_live_input(ctx: InstanceHandle, history_seconds: f32) -> bool {
+    guard(ctx, |ctx| {
+        granular::live::set_live_input(ctx, history_seconds)
+    })
+}
+
+/// Set how far back in the live history grains are taken from, in seconds
+#[wasm_bindgen]
+pub fn set_live_scrub(ctx: InstanceHandle, seconds_ago: f32) {
+    guard(ctx, |ctx| granular::live::set_live_scrub(ctx, seconds_ago))
+}
+
+/// Stop writing the input into the live history, keeping it as the waveform
+#[wasm_bindgen]
+pub fn clear_live_input(ctx: InstanceHandle) {
+    guard(ctx, granular::live::clear_live_input)
+}
+
+/// Turn on the beat-synced stutter, which keeps a history of the output (`source` 0) or the
+/// audio input (1) to repeat once engaged
+/// capture_beats: length of the repeated stretch, the last beats before engaging
+/// repeat_beats, end_repeat_beats: interval between restarts, ramping from one to the other
+/// ramp_beats: length of the ramp, or 0 to keep repeating every `repeat_beats`
+/// Returns false for an unknown source or non-finite lengths
+#[wasm_bindgen]
+pub fn set_stutter_mode(
+    ctx: InstanceHandle,
+    source: u32,
+    capture_beats: f32,
+    repeat_beats: f32,
+    end_repeat_beats: f32,
+    ramp_beats: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::stutter::set_stutter_mode(
+            ctx,
+            source,
+            capture_beats,
+            repeat_beats,
+            end_repeat_beats,
+            ramp_beats,
+        )
+    })
+}
+
+/// Turn the stutter off
+#[wasm_bindgen]
+pub fn clear_stutter_mode(ctx: InstanceHandle) {
+    guard(ctx, granular::stutter::clear_stutter_mode)
+}
+
+/// Whether the stutter is repeating
+#[wasm_bindgen]
+pub fn is_stutter_repeating(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::stutter::is_stutter_repeating)
+}
+
+/// Start recording the stereo master output into an internal buffer, replacing the last recording
+/// The buffer holds `max_seconds`, up to 2^24 samples per channel, and recording stops once it's
+/// full
+#[wasm_bindgen]
+pub fn start_recording(ctx: InstanceHandle, max_seconds: f32) {
+    guard(ctx, |ctx| {
+        granular::recorder::start_recording(ctx, max_seconds)
+    })
+}
+
+/// Stop recording
+#[wasm_bindgen]
+pub fn stop_recording(ctx: InstanceHandle) {
+    guard(ctx, granular::recorder::stop_recording)
+}
+
+/// Whether the master output is being recorded, which stops by itself once the buffer is full
+#[wasm_bindgen]
+pub fn is_recording(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::recorder::is_recording)
+}
+
+/// Get the length of the recording so far in samples per channel
+#[wasm_bindgen]
+pub fn get_recording_len(ctx: InstanceHandle) -> usize {
+    guard(ctx, granular::recorder::get_recording_len)
+}
+
+/// Get the recording encoded as a stereo WAV file, with `bit_depth` 0 = 16-bit, 1 = 24-bit or
+/// 2 = 32-bit float
+/// `dither` adds TPDF dither to 16 and 24-bit files. Returns an empty buffer if the file would be
+/// over 4 GiB
+#[wasm_bindgen]
+pub fn export_recording_wav(ctx: InstanceHandle, bit_depth: u32, dither: bool) -> Vec<u8> {
+    guard(ctx, |ctx| {
+        granular::recorder::export_recording_wav(ctx, bit_depth, dither)
+    })
+}
+
+/// Get the recording as a stereo WAV file resampled to `sample_rate`, with `quality`
+/// 0 = fast, 1 = standard or 2 = best, and `bit_depth` and `dither` as for `export_recording_wav`
+#[wasm_bindgen]
+pub fn export_recording_wav_at(
+    ctx: InstanceHandle,
+    sample_rate: u32,
+    quality: u32,
+    bit_depth: u32,
+    dither: bool,
+) -> Vec<u8> {
+    guard(ctx, |ctx| {
+        granular::recorder::export_recording_wav_at(ctx, sample_rate, quality, bit_depth, dither)
+    })
+}
+
+/// Resample interleaved audio with `channels` channels, e.g. an offline render, to another rate
+/// Both rates must be between 1 kHz and 768 kHz; returns an empty buffer otherwise
+#[wasm_bindgen]
+pub fn resample_interleaved(
+    samples: &[f32],
+    channels: usize,
+    from_rate: f32,
+    to_rate: f32,
+    quality: u32,
+) -> Vec<f32> {
+    granular::resample_interleaved(samples, channels, from_rate, to_rate, quality)
+}
+
+/// Seed the engine's random number generators and reset the voices so that the following renders
+/// are reproducible
+#[wasm_bindgen]
+pub fn set_random_seed(ctx: InstanceHandle, seed: u32) {
+    guard(ctx, |ctx| granular::set_random_seed(ctx, seed))
+}
+
+/// Render `frames` frames of 128 samples with the current parameters straight into host memory at
+/// `out_ptr`, e.g. a ring buffer shared with the worklet, instead of copying each frame out
+///
+/// Returns the number of samples written, which is 0 if `out_ptr` isn't aligned to 4 bytes or the
+/// region overlaps the instance
+#[wasm_bindgen]
+pub fn render_granular_into(ctx: InstanceHandle, out_ptr: *mut f32, frames: usize) -> usize {
+    guard(ctx, |ctx| {
+        granular::render_granular_into(ctx, out_ptr, frames)
+    })
+}
+
+/// Get the number of instances that have been created and not freed yet, for detecting leaks
+/// At log level 4 every create and free is also logged with the handle and the new count
+#[wasm_bindgen]
+pub fn get_instance_count() -> usize {
+    granular::handles::instance_count()
+}
+
+/// Set the most verbose level of messages passed to the host's `log_msg` import: 0 turns logging
+/// off, then 1 = errors, 2 = warnings (the default), 3 = info and 4 = debug
+#[wasm_bindgen]
+pub fn set_log_level(level: u32) {
+    common::log::set_max_level(level)
+}
+
+/// Get the status flags describing the last rendered frame (e.g. an invalid selection that had to
+/// be repaired) so the host can warn the user
+/// In builds that unwind on panic, this and the output pointers keep working for an instance that
+/// panicked, whose status has bit 9 set; every other call on it does nothing until it's freed
+#[wasm_bindgen]
+pub fn get_status(ctx: InstanceHandle) -> u32 {
+    with_instance(ctx, granular::get_status)
+}
+
+/// Mute or unmute a voice; the change is ramped across one frame
+#[wasm_bindgen]
+pub fn set_voice_mute(ctx: InstanceHandle, voice_ix: usize, muted: bool) {
+    guard(ctx, |ctx| granular::set_voice_mute(ctx, voice_ix, muted))
+}
+
+/// Turn a voice's overlap compensation on or off; the change is ramped across one frame
+/// While it's on, the voice's grains are scaled by the inverse of the amplitude they're expected to
+/// add up to, from its envelope shape, grain size and density, instead of by the sum of their gains
+#[wasm_bindgen]
+pub fn set_voice_overlap_compensation(ctx: InstanceHandle, voice_ix: usize, enabled: bool) {
+    guard(ctx, |ctx| {
+        granular::set_voice_overlap_compensation(ctx, voice_ix, enabled)
+    })
+}
+
+/// Solo or unsolo a voice; while any voice is soloed all non-soloed voices are silent
+#[wasm_bindgen]
+pub fn set_voice_solo(ctx: InstanceHandle, voice_ix: usize, soloed: bool) {
+    guard(ctx, |ctx| granular::set_voice_solo(ctx, voice_ix, soloed))
+}
+
+/// Set the gain applied to the mix of all voices
+/// Gain changes are ramped across one frame to avoid clicks
+#[wasm_bindgen]
+pub fn set_master_gain(ctx: InstanceHandle, gain: f32) {
+    guard(ctx, |ctx| granular::set_master_gain(ctx, gain))
+}
+
+/// Set the detune spread across the voices in cents, from 0 to 1200
+/// The first voice plays this far below its sample speed ratio and the last one this far above
+#[wasm_bindgen]
+pub fn set_detune_spread(ctx: InstanceHandle, cents: f32) {
+    guard(ctx, |ctx| granular::set_detune_spread(ctx, cents))
+}
+
+/// Get the target of every parameter in flat index order, for saving a configuration
+#[wasm_bindgen]
+pub fn get_param_values(ctx: InstanceHandle) -> Vec<f32> {
+    guard(ctx, granular::get_param_values)
+}
+
+/// Crossfade between two configurations saved with `get_param_values`
+/// `t` goes from 0 (`config_a`) to 1 (`config_b`). The morphed values replace the ones passed to
+/// `render_granular` until `clear_morph` is called
+/// Returns false if either configuration has the wrong length
+#[wasm_bindgen]
+pub fn morph(ctx: InstanceHandle, config_a: &[f32], config_b: &[f32], t: f32) -> bool {
+    guard(ctx, |ctx| granular::morph(ctx, config_a, config_b, t))
+}
+
+/// Stop morphing between configurations
+#[wasm_bindgen]
+pub fn clear_morph(ctx: InstanceHandle) {
+    guard(ctx, granular::clear_morph)
+}
+
+/// Store the current parameter targets as scene A (0) or B (1)
+/// Once both are stored they replace the values passed to `render_granular`, crossfaded by the
+/// scene morph, until `clear_scenes` is called. A `morph` between configurations takes precedence
+/// Returns false for any other scene
+#[wasm_bindgen]
+pub fn store_scene(ctx: InstanceHandle, scene: usize) -> bool {
+    guard(ctx, |ctx| granular::store_scene(ctx, scene))
+}
+
+/// Store a configuration saved with `get_param_values` or `get_scene_values` as scene A (0) or
+/// B (1)
+/// Returns false for any other scene or if the configuration has the wrong length
+#[wasm_bindgen]
+pub fn set_scene_values(ctx: InstanceHandle, scene: usize, values: &[f32]) -> bool {
+    guard(ctx, |ctx| granular::set_scene_values(ctx, scene, values))
+}
+
+/// Get the parameter values of scene A (0) or B (1) in flat index order, for saving it
+/// Returns an empty array if the scene isn't stored
+#[wasm_bindgen]
+pub fn get_scene_values(ctx: InstanceHandle, scene: usize) -> Vec<f32> {
+    guard(ctx, |ctx| granular::get_scene_values(ctx, scene))
+}
+
+/// Set the position between the stored scenes, from 0 (A) to 1 (B)
+/// Continuous parameters are crossfaded and on/off parameters switch halfway
+#[wasm_bindgen]
+pub fn set_scene_morph(ctx: InstanceHandle, t: f32) {
+    guard(ctx, |ctx| granular::set_scene_morph(ctx, t))
+}
+
+/// Forget both scenes
+#[wasm_bindgen]
+pub fn clear_scenes(ctx: InstanceHandle) {
+    guard(ctx, granular::clear_scenes)
+}
+
+/// Set the gain of the dry playback, which plays the selection straight through under the voices
+/// 0 turns it off. Changes are ramped across one frame
+#[wasm_bindgen]
+pub fn set_dry_gain(ctx: InstanceHandle, gain: f32) {
+    guard(ctx, |ctx| granular::dry::set_dry_gain(ctx, gain))
+}
+
+/// Get the targets of every parameter as JSON for storing in presets
+/// The document has a `global` object and a `voices` array of objects keyed by parameter name
+#[cfg(feature = "serde")]
+#[wasm_bindgen]
+pub fn get_params_json(ctx: InstanceHandle) -> String {
+    guard(ctx, granular::get_params_json)
+}
+
+/// Set the targets of every parameter from JSON written by `get_params_json`
+/// Missing parameters go back to their defaults. Returns false without changing anything if the
+/// document is invalid or has out-of-range values
+#[cfg(feature = "serde")]
+#[wasm_bindgen]
+pub fn set_params_json(ctx: InstanceHandle, json: &str) -> bool {
+    guard(ctx, |ctx| granular::set_params_json(ctx, json))
+}
+
+/// Record the current parameter targets in the undo history, e.g. after each sound design move
+/// Commit the starting state too, so that the first move can be undone. States that were undone
+/// are forgotten, and the history keeps the last 64 states
+#[wasm_bindgen]
+pub fn commit_param_state(ctx: InstanceHandle) {
+    guard(ctx, granular::history::commit_param_state)
+}
+
+/// Set every parameter target back to the state committed before the current one
+/// Hosts that pass parameters to `render_granular` read them back with `get_param_values`
+/// Returns false if there's nothing to undo
+#[wasm_bindgen]
+pub fn undo(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::history::undo)
+}
+
+/// Set every parameter target to the state that was last undone
+/// Returns false if there's nothing to redo
+#[wasm_bindgen]
+pub fn redo(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::history::redo)
+}
+
+/// Whether there's a committed state to go back to
+#[wasm_bindgen]
+pub fn can_undo(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::history::can_undo)
+}
+
+/// Whether there's an undone state to go forward to
+#[wasm_bindgen]
+pub fn can_redo(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::history::can_redo)
+}
+
+/// Forget every committed state
+#[wasm_bindgen]
+pub fn clear_param_history(ctx: InstanceHandle) {
+    guard(ctx, granular::history::clear_param_history)
+}
+
+/// Move the parameter targets towards random values within musically sane ranges
+/// amount: how far to move, from 0 (not at all) to 1 (all the way to the random values)
+/// mask: groups to randomize, the sum of 1 = grain size, spacing, start randomness and skips,
+/// 2 = pitch, 4 = envelope, 8 = filter, 16 = pan and delay send, 32 = movement, 64 = levels
+/// Locked parameters, the levels until they're unlocked, are left alone, as are the selection,
+/// mute, solo and the scene morph, and filters that are off stay off
+#[wasm_bindgen]
+pub fn randomize(ctx: InstanceHandle, amount: f32, mask: u32) {
+    guard(ctx, |ctx| granular::randomize::randomize(ctx, amount, mask))
+}
+
+/// Lock a parameter against `randomize`, or unlock it
+/// param_ix: index of the parameter, as in `get_param_metadata`
+/// Returns false for indices past the last parameter
+#[wasm_bindgen]
+pub fn set_param_locked(ctx: InstanceHandle, param_ix: usize, locked: bool) -> bool {
+    guard(ctx, |ctx| {
+        granular::randomize::set_param_locked(ctx, param_ix, locked)
+    })
+}
+
+/// Whether a parameter is locked against `randomize`
+#[wasm_bindgen]
+pub fn is_param_locked(ctx: InstanceHandle, param_ix: usize) -> bool {
+    guard(ctx, |ctx| {
+        granular::randomize::is_param_locked(ctx, param_ix)
+    })
+}
+
+/// Propose parameter targets for a texture style from an analysis of the loaded waveform
+/// style: "cloud", "stutter", "drone" or "shimmer"
+/// Returns a JSON document for `set_params_json`, or an empty string for unknown styles
+#[cfg(feature = "serde")]
+#[wasm_bindgen]
+pub fn generate_texture_preset(ctx: InstanceHandle, style: &str) -> String {
+    guard(ctx, |ctx| {
+        granular::texture::generate_texture_preset(ctx, style)
+    })
+}
+
+/// Get the number of parameters addressable by flat index
+#[wasm_bindgen]
+pub fn get_param_count() -> usize {
+    granular::get_param_count()
+}
+
+/// Get a JSON array describing every parameter (ID, name, unit, range, default, smoothing and
+/// rate) so hosts can generate UIs and validation without duplicating ranges. A "control" rate
+/// parameter changes once per 128-sample frame, like a k-rate AudioParam, and an "audio" rate one
+/// every sample
+#[wasm_bindgen]
+pub fn get_param_metadata() -> String {
+    granular::get_param_metadata()
+}
+
+/// Set the sample rate that the instance is rendering at
+/// Rates below 1 kHz or above 768 kHz are ignored
+#[wasm_bindgen]
+pub fn set_sample_rate(ctx: InstanceHandle, sample_rate: f32) {
+    guard(ctx, |ctx| granular::set_sample_rate(ctx, sample_rate))
+}
+
+/// Set the smoothing time constant in milliseconds for a single parameter
+/// Parameters are indexed with the globals first followed by each voice's parameters
+#[wasm_bindgen]
+pub fn set_param_smoothing_time(ctx: InstanceHandle, param_ix: usize, time_ms: f32) {
+    guard(ctx, |ctx| {
+        granular::set_param_smoothing_time(ctx, param_ix, time_ms)
+    })
+}
+
+/// Set the target of a single parameter, clamped to its range
+/// param_ix: index of the parameter, as in `get_param_metadata`
+/// Modes such as mid/side granulation, grain pitch snapping, MIDI clock sync and selection
+/// wrapping are switched on and off by their parameters, with 1 for on and 0 for off
+/// Returns false for indices past the last parameter or values that aren't finite
+#[wasm_bindgen]
+pub fn set_param(ctx: InstanceHandle, param_ix: usize, value: f32) -> bool {
+    guard(ctx, |ctx| granular::set_param(ctx, param_ix, value))
+}
+
+/// Set how much of a voice's output is sent to the shared delay bus, from 0 to 1
+/// The bus output is summed into the master ahead of the master gain
+#[wasm_bindgen]
+pub fn set_voice_delay_send(ctx: InstanceHandle, voice_ix: usize, level: f32) {
+    guard(ctx, |ctx| {
+        granular::sends::set_voice_delay_send(ctx, voice_ix, level)
+    })
+}
+
+/// Set the delay time (1 to 2000 ms) and feedback (0 to 0.95) of the delay bus
+#[wasm_bindgen]
+pub fn set_send_delay(ctx: InstanceHandle, time_ms: f32, feedback: f32) {
+    guard(ctx, |ctx| {
+        granular::sends::set_send_delay(ctx, time_ms, feedback)
+    })
+}
+
+/// Set the stereo position of a voice from -1 (hard left) to 1 (hard right)
+#[wasm_bindgen]
+pub fn set_voice_pan(ctx: InstanceHandle, voice_ix: usize, pan: f32) {
+    guard(ctx, |ctx| granular::set_voice_pan(ctx, voice_ix, pan))
+}
+
+/// Get a pointer to the 128-sample mono output buffer
+/// It's the pointer renders return with the default mono layout and doesn't change across renders,
+/// so a view of it can be created once. Views still have to be recreated if the WASM memory grows
+#[wasm_bindgen]
+pub fn get_output_ptr(ctx: InstanceHandle) -> *const f32 {
+    with_instance(ctx, granular::get_output_ptr)
+}
+
+/// Get a pointer to the stereo output of the last rendered frame
+/// The buffer is planar: 128 left samples followed by 128 right samples
+#[wasm_bindgen]
+pub fn get_stereo_output_ptr(ctx: InstanceHandle) -> *const f32 {
+    with_instance(ctx, granular::get_stereo_output_ptr)
+}
+
+/// Choose the output layout renders return: 1 channel for mono, 2 for stereo or 4 for quad, laid
+/// out planar like `get_stereo_output_ptr` and `get_quad_output_ptr`
+/// Returns false for any other channel count, keeping the current layout
+#[wasm_bindgen]
+pub fn configure_output(ctx: InstanceHandle, channels: u32) -> bool {
+    guard(ctx, |ctx| granular::configure_output(ctx, channels))
+}
+
+/// Get the number of channels of the output layout
+#[wasm_bindgen]
+pub fn get_output_channels(ctx: InstanceHandle) -> u32 {
+    guard(ctx, granular::get_output_channels)
+}
+
+/// Get a pointer to the quad output of the last rendered frame, rendered while the layout is quad
+/// The buffer is planar: 128 samples each of front left, front right, rear left and rear right
+#[wasm_bindgen]
+pub fn get_quad_output_ptr(ctx: InstanceHandle) -> *const f32 {
+    with_instance(ctx, granular::quad::get_quad_output_ptr)
+}
+
+/// Place a voice's grains between the front and rear speakers of quad output
+/// depth: from -1, front only, through 0, both pairs, to 1, rear only
+/// spread: how far each new grain's depth is randomized either way, from 0 to 2
+#[wasm_bindgen]
+pub fn set_voice_quad_position(ctx: InstanceHandle, voice_ix: usize, depth: f32, spread: f32) {
+    guard(ctx, |ctx| {
+        granular::quad::set_voice_quad_position(ctx, voice_ix, depth, spread)
+    })
+}
+
+/// Get a pointer to the stereo output of one voice for the last rendered frame
+/// Laid out like `get_stereo_output_ptr`, for routing voices to separate effect chains or
+/// recording stems. The voice outputs add up to the stereo output unless it clipped. Returns null
+/// for an invalid voice
+#[wasm_bindgen]
+pub fn get_voice_output_ptr(ctx: InstanceHandle, voice_ix: usize) -> *const f32 {
+    with_instance(ctx, |ctx| granular::get_voice_output_ptr(ctx, voice_ix))
+}
+
+/// Configure a modulation source slot as an LFO
+/// `shape`: 0 = sine, 1 = triangle, 2 = saw, 3 = square, 4 = random
+#[wasm_bindgen]
+pub fn set_mod_lfo(ctx: InstanceHandle, source_ix: usize, shape: u32, rate_hz: f32) {
+    guard(ctx, |ctx| {
+        granular::modulation::set_mod_lfo(ctx, source_ix, shape, rate_hz)
+    })
+}
+
+/// Configure a modulation source slot as a random walk ("drunk") generator
+/// It takes `rate_hz` steps per second of up to `step_size` and stays within `±range`
+#[wasm_bindgen]
+pub fn set_mod_random_walk(
+    ctx: InstanceHandle,
+    source_ix: usize,
+    step_size: f32,
+    rate_hz: f32,
+    range: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::modulation::set_mod_random_walk(ctx, source_ix, step_size, rate_hz, range)
+    })
+}
+
+/// Configure a modulation source slot as an envelope retriggered on every grain spawn
+/// Connect it to e.g. a voice's filter cutoff for a percussive blip on each grain
+#[wasm_bindgen]
+pub fn set_mod_grain_envelope(
+    ctx: InstanceHandle,
+    source_ix: usize,
+    attack_ms: f32,
+    decay_ms: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::modulation::set_mod_grain_envelope(ctx, source_ix, attack_ms, decay_ms)
+    })
+}
+
+/// Configure a modulation source slot as a sample-and-hold clocked by grain onsets
+/// Each voice gets a new random value exactly when it spawns a grain
+#[wasm_bindgen]
+pub fn set_mod_grain_sample_hold(ctx: InstanceHandle, source_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::modulation::set_mod_grain_sample_hold(ctx, source_ix)
+    })
+}
+
+/// Configure a modulation source slot to follow the level of the sidechain input
+/// Route it to `Density` or `Gain` to make grains respond to e.g. a drum track
+#[wasm_bindgen]
+pub fn set_mod_sidechain(ctx: InstanceHandle, source_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::modulation::set_mod_sidechain(ctx, source_ix)
+    })
+}
+
+/// Get a pointer to the audio input buffer (128 samples)
+/// Write the audio to granulate live, overdub or stutter here before each `render_granular` call;
+/// it's cleared after each frame
+#[wasm_bindgen]
+pub fn get_audio_input_ptr(ctx: InstanceHandle) -> *mut f32 {
+    guard(ctx, granular::get_audio_input_ptr)
+}
+
+/// Get a pointer to the sidechain input buffer (128 samples)
+/// Write the sidechain audio here before each `render_granular` call; it's never heard, only
+/// followed by sidechain modulation sources and a keyed master compressor, and it's cleared after
+/// each frame
+#[wasm_bindgen]
+pub fn get_sidechain_input_ptr(ctx: InstanceHandle) -> *mut f32 {
+    guard(ctx, granular::get_sidechain_input_ptr)
+}
+
+/// Set the attack and release times of the sidechain envelope follower
+#[wasm_bindgen]
+pub fn set_sidechain_follower(ctx: InstanceHandle, attack_ms: f32, release_ms: f32) {
+    guard(ctx, |ctx| {
+        granular::modulation::set_sidechain_follower(ctx, attack_ms, release_ms)
+    })
+}
+
+/// Turn off a modulation source slot
+#[wasm_bindgen]
+pub fn clear_mod_source(ctx: InstanceHandle, source_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::modulation::clear_mod_source(ctx, source_ix)
+    })
+}
+
+/// Move the peak of a voice's grain envelopes towards the start (-1) or end (1) of the grain
+/// Negative values make grains percussive and positive ones make them sound reversed. Can also
+/// be modulated through the `EnvelopeSkew` modulation destination
+#[wasm_bindgen]
+pub fn set_voice_envelope_skew(ctx: InstanceHandle, voice_ix: usize, skew: f32) {
+    guard(ctx, |ctx| {
+        granular::envelope::set_voice_envelope_skew(ctx, voice_ix, skew)
+    })
+}
+
+/// Enable or disable the short fade applied to the edges of every grain (on by default)
+/// It stops grains with hard-edged envelopes, like a slope length of 0, from clicking
+#[wasm_bindgen]
+pub fn set_click_guard(ctx: InstanceHandle, enabled: bool) {
+    guard(ctx, |ctx| granular::envelope::set_click_guard(ctx, enabled))
+}
+
+/// Switch between the default grain mixing and constant overlap-add mixing
+/// In overlap-add mode grains use a Hann window and the sum is scaled by grain spacing and
+/// duration, so the level stays stable as grain size and density change instead of pumping.
+/// Voice envelope settings, skew and the click guard are ignored in this mode
+#[wasm_bindgen]
+pub fn set_overlap_add_mode(ctx: InstanceHandle, enabled: bool) {
+    guard(ctx, |ctx| granular::set_overlap_add_mode(ctx, enabled))
+}
+
+/// Add amplitude bumps to every grain of a voice for tremolo textures within grains
+/// `count` bumps per grain (up to 64) at `depth` from 0 (none) to 1 (separate bumps). A count or
+/// depth of 0 removes them. Returns false for invalid values
+#[wasm_bindgen]
+pub fn set_voice_envelope_ripple(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    count: u32,
+    depth: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::envelope::set_voice_envelope_ripple(ctx, voice_ix, count, depth)
+    })
+}
+
+/// Make a voice's grain envelopes a crossfade between two window shapes
+/// 0 = sine, 1 = Hann, 2 = Gaussian, 3 = triangle, 4 = trapezoid, 5 = rectangle. Replaces the
+/// voice's other shape settings; ripples and skew still apply. Returns false for unknown shapes
+#[wasm_bindgen]
+pub fn set_voice_envelope_morph_shapes(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    from_shape: u32,
+    to_shape: u32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::envelope::set_voice_envelope_morph_shapes(ctx, voice_ix, from_shape, to_shape)
+    })
+}
+
+/// Stop morphing a voice's grain envelopes between window shapes
+#[wasm_bindgen]
+pub fn clear_voice_envelope_morph_shapes(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::envelope::clear_voice_envelope_morph_shapes(ctx, voice_ix)
+    })
+}
+
+/// Set the position of the crossfade between a voice's morph shapes, from 0 to 1
+/// Can also be modulated through the `EnvelopeMorph` modulation destination
+#[wasm_bindgen]
+pub fn set_voice_envelope_morph(ctx: InstanceHandle, voice_ix: usize, morph: f32) {
+    guard(ctx, |ctx| {
+        granular::envelope::set_voice_envelope_morph(ctx, voice_ix, morph)
+    })
+}
+
+/// Set the chance that each grain a voice schedules is dropped, from 0 to 1, thinning out the cloud
+/// while the grains that are left keep to the voice's clock or grid
+/// Can also be modulated through the `GrainSkip` modulation destination
+#[wasm_bindgen]
+pub fn set_voice_grain_skip_probability(ctx: InstanceHandle, voice_ix: usize, probability: f32) {
+    guard(ctx, |ctx| {
+        granular::set_voice_grain_skip_probability(ctx, voice_ix, probability)
+    })
+}
+
+/// Enable or disable automatic gain compensation for grain density
+/// Each voice's level is scaled from its grain spacing, duration and envelope energy so that it
+/// stays roughly constant as density and grain size change. Ignored in overlap-add mode
+#[wasm_bindgen]
+pub fn set_density_compensation(ctx: InstanceHandle, enabled: bool) {
+    guard(ctx, |ctx| granular::set_density_compensation(ctx, enabled))
+}
+
+/// Link the two voices into a stereo pair panned hard left and right
+/// The second voice follows the first one's parameters, with its grain clock and read head offset
+/// by `phase_offset` of a grain interval and `position_offset` of the selection
+#[wasm_bindgen]
+pub fn set_voice_link(ctx: InstanceHandle, enabled: bool, phase_offset: f32, position_offset: f32) {
+    guard(ctx, |ctx| {
+        granular::link::set_voice_link(ctx, enabled, phase_offset, position_offset)
+    })
+}
+
+/// Duck the second voice by the level of the first, so a rhythmic first voice carves room out of
+/// the second
+/// amount: 0 to 1, where 1 lets a full-scale first voice silence the second and 0 turns ducking off
+/// attack_ms: 0.1 to 1000, release_ms: 1 to 5000, how fast the ducking follows the first voice
+#[wasm_bindgen]
+pub fn set_voice_ducking(ctx: InstanceHandle, amount: f32, attack_ms: f32, release_ms: f32) {
+    guard(ctx, |ctx| {
+        granular::duck::set_voice_ducking(ctx, amount, attack_ms, release_ms)
+    })
+}
+
+/// Route a modulation source to a destination of one voice
+/// `destination`: 0 = grain size (octaves), 1 = position (fraction of selection),
+/// 2 = pitch (semitones), 3 = filter cutoff (octaves), 4 = gain, 5 = pan,
+/// 6 = density (octaves), 7 = envelope skew, 8 = envelope morph, 9 = grain skip probability
+#[wasm_bindgen]
+pub fn set_mod_connection(
+    ctx: InstanceHandle,
+    connection_ix: usize,
+    source_ix: usize,
+    voice_ix: usize,
+    destination: u32,
+    depth: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::modulation::set_mod_connection(
+            ctx,
+            connection_ix,
+            source_ix,
+            voice_ix,
+            destination,
+            depth,
+        )
+    })
+}
+
+/// Remove a modulation connection
+#[wasm_bindgen]
+pub fn clear_mod_connection(ctx: InstanceHandle, connection_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::modulation::clear_mod_connection(ctx, connection_ix)
+    })
+}
+
+/// Set the position of one of the 4 macro knobs (0-1)
+#[wasm_bindgen]
+pub fn set_macro_value(ctx: InstanceHandle, macro_ix: usize, value: f32) {
+    guard(ctx, |ctx| {
+        granular::macros::set_macro_value(ctx, macro_ix, value)
+    })
+}
+
+/// Map a macro onto a parameter addressed by its index in `get_param_metadata`
+/// The parameter moves from `min` to `max` as the macro goes from 0 to 1; `curve` is an exponent
+/// applied to the macro value, with 1 being linear
+#[wasm_bindgen]
+pub fn set_macro_mapping(
+    ctx: InstanceHandle,
+    mapping_ix: usize,
+    macro_ix: usize,
+    param_ix: usize,
+    min: f32,
+    max: f32,
+    curve: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::macros::set_macro_mapping(ctx, mapping_ix, macro_ix, param_ix, min, max, curve)
+    })
+}
+
+/// Remove a macro mapping slot
+#[wasm_bindgen]
+pub fn clear_macro_mapping(ctx: InstanceHandle, mapping_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::macros::clear_macro_mapping(ctx, mapping_ix)
+    })
+}
+
+/// Forward a raw MIDI channel or system message (status byte and two data bytes) to the engine
+/// Notes, pitch bend and channel pressure drive the note mode; control changes are also routed
+/// through the CC mapping table. Clock, start, continue, stop and song position messages drive
+/// the MIDI clock; forward them as they arrive, since they're timed by when they're received
+#[wasm_bindgen]
+pub fn handle_midi_event(ctx: InstanceHandle, status: u8, data_1: u8, data_2: u8) {
+    guard(ctx, |ctx| {
+        granular::midi::handle_midi_event(ctx, status, data_1, data_2)
+    })
+}
+
+/// Enable or disable the polyphonic note mode, where each voice plays one MIDI note
+/// Notes transpose the voice relative to middle C (60) and gate its gain
+/// With `mpe` enabled, per-note pitch bend controls pitch (`pitch_bend_range` semitones), pressure
+/// controls density and timbre (CC74) controls the lowpass cutoff
+#[wasm_bindgen]
+pub fn set_note_mode(ctx: InstanceHandle, enabled: bool, mpe: bool, pitch_bend_range: f32) {
+    guard(ctx, |ctx| {
+        granular::notes::set_note_mode(ctx, enabled, mpe, pitch_bend_range)
+    })
+}
+
+/// Tune notes to the scale in the text of a Scala .scl file, rooted at middle C (60)
+/// PSOLA voices that snap to notes snap to its degrees. Returns false and keeps the current
+/// tuning if the file can't be parsed
+#[wasm_bindgen]
+pub fn load_scala_tuning(ctx: InstanceHandle, text: &str) -> bool {
+    guard(ctx, |ctx| granular::tuning::load_scala_tuning(ctx, text))
+}
+
+/// Go back to equal temperament
+#[wasm_bindgen]
+pub fn clear_tuning(ctx: InstanceHandle) {
+    guard(ctx, granular::tuning::clear_tuning)
+}
+
+/// Estimate the key of the loaded waveform
+/// Returns [tonic, mode, confidence]: the tonic from 0 for C to 11 for B, 0 for major or 1 for
+/// minor, and a confidence from -1 to 1; empty for a waveform that's too short or silent
+/// apply: make the key's scale the tuning and turn on grain pitch snapping, so grains stay in the
+/// source's key
+#[wasm_bindgen]
+pub fn estimate_key(ctx: InstanceHandle, apply: bool) -> Vec<f32> {
+    guard(ctx, |ctx| granular::key::estimate_key(ctx, apply))
+}
+
+/// Map a MIDI CC onto a parameter addressed by its index in `get_param_metadata`
+/// A `channel` above 15 listens on every channel; `min`, `max` and `curve` work like macro mappings
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub fn set_cc_mapping(
+    ctx: InstanceHandle,
+    mapping_ix: usize,
+    channel: u8,
+    cc: u8,
+    param_ix: usize,
+    min: f32,
+    max: f32,
+    curve: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::midi::set_cc_mapping(ctx, mapping_ix, channel, cc, param_ix, min, max, curve)
+    })
+}
+
+/// Remove a CC mapping slot
+#[wasm_bindgen]
+pub fn clear_cc_mapping(ctx: InstanceHandle, mapping_ix: usize) {
+    guard(ctx, |ctx| granular::midi::clear_cc_mapping(ctx, mapping_ix))
+}
+
+/// Map the next incoming CC onto a parameter ("MIDI learn")
+#[wasm_bindgen]
+pub fn begin_cc_learn(ctx: InstanceHandle, param_ix: usize, min: f32, max: f32, curve: f32) {
+    guard(ctx, |ctx| {
+        granular::midi::begin_cc_learn(ctx, param_ix, min, max, curve)
+    })
+}
+
+/// Stop waiting for a CC to learn
+#[wasm_bindgen]
+pub fn cancel_cc_learn(ctx: InstanceHandle) {
+    guard(ctx, granular::midi::cancel_cc_learn)
+}
+
+/// Returns true while the engine is waiting for a CC to learn
+#[wasm_bindgen]
+pub fn is_cc_learning(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::midi::is_cc_learning)
+}
+
+/// Get the CC mapping table as JSON for storing in presets
+/// Each entry has `slot`, `channel`, `cc`, `param`, `min`, `max` and `curve`, matching the
+/// arguments of `set_cc_mapping`
+#[wasm_bindgen]
+pub fn get_cc_mappings(ctx: InstanceHandle) -> String {
+    guard(ctx, granular::midi::get_cc_mappings)
+}
+
+/// Get the tempo measured from the MIDI clock, or 0 until two clock ticks have arrived
+#[wasm_bindgen]
+pub fn get_midi_clock_bpm(ctx: InstanceHandle) -> f32 {
+    guard(ctx, granular::midi_clock::get_midi_clock_bpm)
+}
+
+/// Report the host's transport state; call before each `render_granular`
+/// `song_position_frames` is the song position of the first sample of the frame. Jumps in it are
+/// treated as seeks, which re-align tempo-synced voices to the new position
+#[wasm_bindgen]
+pub fn set_transport(ctx: InstanceHandle, playing: bool, bpm: f32, song_position_frames: f64) {
+    guard(ctx, |ctx| {
+        granular::transport::set_transport(ctx, playing, bpm, song_position_frames)
+    })
+}
+
+/// Start granulating at the start of the next frame, like a clip player
+/// `from_sample_ix`: where in the waveform to start, or negative to start from where the read
+/// heads are
+#[wasm_bindgen]
+pub fn playback_start(ctx: InstanceHandle, from_sample_ix: f32) {
+    guard(ctx, |ctx| {
+        granular::playback::playback_start(ctx, from_sample_ix)
+    })
+}
+
+/// Stop spawning grains at the start of the next frame, letting the ones playing ring out
+#[wasm_bindgen]
+pub fn playback_stop(ctx: InstanceHandle) {
+    guard(ctx, granular::playback::playback_stop)
+}
+
+/// Move the read heads to `sample_ix` at the start of the next frame without starting or stopping
+#[wasm_bindgen]
+pub fn playback_seek(ctx: InstanceHandle, sample_ix: f32) {
+    guard(ctx, |ctx| granular::playback::playback_seek(ctx, sample_ix))
+}
+
+/// Go back to granulating all the time, without playback control
+#[wasm_bindgen]
+pub fn release_playback(ctx: InstanceHandle) {
+    guard(ctx, granular::playback::release_playback)
+}
+
+/// Get the playback state: 0 = no playback control, 1 = playing, 2 = stopped
+#[wasm_bindgen]
+pub fn get_playback_state(ctx: InstanceHandle) -> u32 {
+    guard(ctx, granular::playback::get_playback_state)
+}
+
+/// Get the position of a voice's read head as a sample index in the waveform
+#[wasm_bindgen]
+pub fn get_playhead(ctx: InstanceHandle, voice_ix: usize) -> f32 {
+    guard(ctx, |ctx| granular::playback::get_playhead(ctx, voice_ix))
+}
+
+/// Set the breakpoint automation of a parameter addressed by its index in `get_param_metadata`
+/// `points` is a flat list of `time_seconds, value, curve` triples evaluated every sample; pass an
+/// empty list to remove the lane. Lanes follow the transport while it's playing
+#[wasm_bindgen]
+pub fn set_automation_lane(ctx: InstanceHandle, param_ix: usize, points: &[f32]) {
+    guard(ctx, |ctx| {
+        granular::automation::set_automation_lane(ctx, param_ix, points)
+    })
+}
+
+/// Remove every automation lane
+#[wasm_bindgen]
+pub fn clear_automation(ctx: InstanceHandle) {
+    guard(ctx, granular::automation::clear_automation)
+}
+
+/// Start recording every change to the parameter targets into automation lanes, replacing the
+/// last recording, or stop recording and keep it
+/// Times are in seconds since recording started, counted in rendered frames. Recording stops by
+/// itself after 65536 breakpoints
+#[wasm_bindgen]
+pub fn set_automation_recording(ctx: InstanceHandle, recording: bool) {
+    guard(ctx, |ctx| {
+        granular::automation::set_automation_recording(ctx, recording)
+    })
+}
+
+/// Whether automation is being recorded
+#[wasm_bindgen]
+pub fn is_recording_automation(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::automation::is_recording_automation)
+}
+
+/// Get the recorded automation, 4 values per breakpoint: parameter index, time in seconds, value
+/// and curve, lane by lane and in time order within each lane
+/// Each lane's triples can be passed to `set_automation_lane`
+#[wasm_bindgen]
+pub fn get_recorded_automation(ctx: InstanceHandle) -> Vec<f64> {
+    guard(ctx, granular::automation::get_recorded_automation)
+}
+
+/// Stop recording and replace the automation lanes with the recorded ones, from the start of the
+/// automation clock, e.g. to render the performance again offline
+/// Returns false, leaving the lanes alone, if nothing was recorded
+#[wasm_bindgen]
+pub fn replay_recorded_automation(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::automation::replay_recorded_automation)
+}
+
+/// Move the automation clock used while the transport isn't playing, e.g. before an offline render
+#[wasm_bindgen]
+pub fn set_automation_position(ctx: InstanceHandle, position_seconds: f64) {
+    guard(ctx, |ctx| {
+        granular::automation::set_automation_position(ctx, position_seconds)
+    })
+}
+
+/// Drive a parameter, addressed by its index in `get_param_metadata`, at audio rate
+/// Returns a pointer to a buffer of 128 values, one per sample of the next frame, e.g. the values
+/// of an AudioParam. Write it before each render; it's reused until it's rewritten. Audio-rate
+/// values override the target and any automation lane. Returns null for an unknown parameter, one
+/// whose metadata has a "control" rate, or when 8 parameters are already audio-rate
+#[wasm_bindgen]
+pub fn get_audio_rate_param_ptr(ctx: InstanceHandle, param_ix: usize) -> *mut f32 {
+    guard(ctx, |ctx| {
+        granular::audio_rate::get_audio_rate_param_ptr(ctx, param_ix)
+    })
+}
+
+/// Return a parameter to following its target
+#[wasm_bindgen]
+pub fn disable_audio_rate_param(ctx: InstanceHandle, param_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::audio_rate::disable_audio_rate_param(ctx, param_ix)
+    })
+}
+
+/// Return every audio-rate parameter to following its target
+#[wasm_bindgen]
+pub fn clear_audio_rate_params(ctx: InstanceHandle) {
+    guard(ctx, granular::audio_rate::clear_audio_rate_params)
+}
+
+/// Set the stages of a voice's filter envelope, which runs while the voice plays a note in note
+/// mode. Times are in milliseconds and `sustain` is a level from 0 to 1
+#[wasm_bindgen]
+pub fn set_voice_filter_envelope(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    attack_ms: f32,
+    decay_ms: f32,
+    sustain: f32,
+    release_ms: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::notes::set_voice_filter_envelope(
+            ctx, voice_ix, attack_ms, decay_ms, sustain, release_ms,
+        )
+    })
+}
+
+/// Set how far a voice's filter envelope moves its cutoff in octaves, and how much that follows
+/// note velocity from 0 to 1. Only voices with their filter on are affected
+#[wasm_bindgen]
+pub fn set_voice_filter_envelope_amount(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    octaves: f32,
+    velocity_sensitivity: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::notes::set_voice_filter_envelope_amount(
+            ctx,
+            voice_ix,
+            octaves,
+            velocity_sensitivity,
+        )
+    })
+}
+
+/// Set up a voice's auto-pan LFO on top of its static pan
+/// `depth` is the largest offset from the pan position and `phase` the starting point of the cycle
+/// from 0 to 1. A depth of 0 turns auto-pan off
+#[wasm_bindgen]
+pub fn set_voice_auto_pan(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    rate_hz: f32,
+    depth: f32,
+    phase: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::autopan::set_voice_auto_pan(ctx, voice_ix, rate_hz, depth, phase)
+    })
+}
+
+/// Sync a voice's auto-pan LFO to the transport tempo with one cycle every `beats` beats
+/// A value of 0 returns to the free rate
+#[wasm_bindgen]
+pub fn set_voice_auto_pan_sync(ctx: InstanceHandle, voice_ix: usize, beats: f32) {
+    guard(ctx, |ctx| {
+        granular::autopan::set_voice_auto_pan_sync(ctx, voice_ix, beats)
+    })
+}
+
+/// Sync a voice's grain spawning to the transport tempo with one grain every `beats` beats
+/// Pass 0 to go back to `samples_between_grains`
+#[wasm_bindgen]
+pub fn set_voice_grain_sync(ctx: InstanceHandle, voice_ix: usize, beats: f32) {
+    guard(ctx, |ctx| {
+        granular::set_voice_grain_sync(ctx, voice_ix, beats)
+    })
+}
+
+/// Size and space a voice's grains in note values of the transport tempo
+/// size_bars: length of each grain, from 1/32 to 1 bar of 4/4, or 0 for `grain_size`
+/// spacing_bars: time between grains, from 1/32 to 1 bar, or 0 for `samples_between_grains`
+#[wasm_bindgen]
+pub fn set_voice_grain_note_values(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    size_bars: f32,
+    spacing_bars: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::set_voice_grain_note_values(ctx, voice_ix, size_bars, spacing_bars)
+    })
+}
+
+/// Apply a groove template to the onsets of tempo-synced grains while the transport plays, with
+/// the bar split into a step per value
+/// timings: fraction of a step each step's onsets move later by, or earlier if negative, -0.5 to
+/// 0.5
+/// gains: gain of the grains spawned on each step, 0 to 2
+/// Returns false without changing anything unless both lists hold the same number of values,
+/// from 1 to 64
+#[wasm_bindgen]
+pub fn set_groove(ctx: InstanceHandle, timings: &[f32], gains: &[f32]) -> bool {
+    guard(ctx, |ctx| granular::groove::set_groove(ctx, timings, gains))
+}
+
+/// Go back to tempo-synced onsets on the straight grid
+#[wasm_bindgen]
+pub fn clear_groove(ctx: InstanceHandle) {
+    guard(ctx, granular::groove::clear_groove)
+}
+
+/// Switch a voice to pulsar synthesis: `fundamental_hz` times a second it plays a pulsaret, a
+/// grain shaped by the voice's envelope, that fills `duty_cycle` (0 to 1) of the period and is
+/// silent for the rest. `pulsaret_semitones` transposes the pulsarets without changing their
+/// length, so the formant moves independently of the fundamental
+#[wasm_bindgen]
+pub fn set_voice_pulsar(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    fundamental_hz: f32,
+    duty_cycle: f32,
+    pulsaret_semitones: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::pulsar::set_voice_pulsar(
+            ctx,
+            voice_ix,
+            fundamental_hz,
+            duty_cycle,
+            pulsaret_semitones,
+        )
+    })
+}
+
+/// Switch a voice back from pulsar synthesis to granulating
+#[wasm_bindgen]
+pub fn clear_voice_pulsar(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::pulsar::clear_voice_pulsar(ctx, voice_ix)
+    })
+}
+
+/// Make a voice play a region of the waveform as a wavetable instead of granulating it
+/// The table holds `frame_count` single-cycle frames of `frame_len` samples from `start` on. The
+/// voice plays middle C at a sample speed of 1, so note mode plays it chromatically, and its
+/// filter applies. Like grains, it's silent while the selection is empty. Returns false if the
+/// frames are empty
+#[wasm_bindgen]
+pub fn set_voice_wavetable(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    start: usize,
+    frame_len: usize,
+    frame_count: usize,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::wavetable::set_voice_wavetable(ctx, voice_ix, start, frame_len, frame_count)
+    })
+}
+
+/// Set the frame a voice's wavetable plays, from 0 (the first) to 1 (the last), morphing between
+/// neighbouring frames. With a `scan_hz` above 0 the position sweeps to the end of the table and
+/// back that many times a second; position modulation moves it as a fraction of the table
+#[wasm_bindgen]
+pub fn set_voice_wavetable_position(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    position: f32,
+    scan_hz: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::wavetable::set_voice_wavetable_position(ctx, voice_ix, position, scan_hz)
+    })
+}
+
+/// Switch a voice back from a wavetable to granulating
+#[wasm_bindgen]
+pub fn clear_voice_wavetable(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::wavetable::clear_voice_wavetable(ctx, voice_ix)
+    })
+}
+
+/// Switch a voice to spectral granulation, which resynthesizes FFT frames instead of playing grains
+/// frame_len: samples per frame, a power of two from 256 to 4096
+/// shuffle: fraction of bins whose magnitudes are swapped with a neighbour's (0 to 1)
+/// smear: how much of the magnitudes carries over from frame to frame (0 to 1)
+/// Returns false if the frame length isn't supported
+#[wasm_bindgen]
+pub fn set_voice_spectral(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    frame_len: usize,
+    shuffle: f32,
+    smear: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::spectral::set_voice_spectral(ctx, voice_ix, frame_len, shuffle, smear)
+    })
+}
+
+/// Switch a voice back from spectral to time-domain granulation
+#[wasm_bindgen]
+pub fn clear_voice_spectral(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::spectral::clear_voice_spectral(ctx, voice_ix)
+    })
+}
+
+/// Feed a voice's grains through a Karplus-Strong resonator, turning them into plucked tones
+/// frequency_hz: pitch of the resonator (20 to 10000), at a sample speed of 1 if track_notes is set
+/// track_notes: follow the voice's sample speed, and with it the notes of note mode
+/// feedback: how long it rings (0 to 0.999)
+/// damping: how quickly its high partials fade (0 to 0.999)
+/// mix: balance between the grains (0) and the resonator (1)
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub fn set_voice_resonator(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    frequency_hz: f32,
+    track_notes: bool,
+    feedback: f32,
+    damping: f32,
+    mix: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::resonator::set_voice_resonator(
+            ctx,
+            voice_ix,
+            frequency_hz,
+            track_notes,
+            feedback,
+            damping,
+            mix,
+        )
+    })
+}
+
+/// Remove a voice's resonator
+#[wasm_bindgen]
+pub fn clear_voice_resonator(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::resonator::clear_voice_resonator(ctx, voice_ix)
+    })
+}
+
+/// Feed a voice's output through a feedforward (`kind` 0) or feedback (1) comb filter
+/// delay: in samples, or the frequency whose period it is if `delay_in_hz` is set, up to 50 ms
+/// gain: of the delayed signal, from -1 to 1
+#[wasm_bindgen]
+pub fn set_voice_comb(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    kind: u32,
+    delay: f32,
+    delay_in_hz: bool,
+    gain: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::comb::set_voice_comb(ctx, voice_ix, kind, delay, delay_in_hz, gain)
+    })
+}
+
+/// Remove a voice's comb filter
+#[wasm_bindgen]
+pub fn clear_voice_comb(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| granular::comb::clear_voice_comb(ctx, voice_ix))
+}
+
+/// Feed a voice's output through a phaser, after its comb filter and before its filter
+/// stages: 1 to 12 allpass stages, every two adding a notch
+/// rate_hz, depth: speed of the sweep, and how far from 0 to 1 it moves the notches
+/// feedback: from -0.95 to 0.95, deepening the notches
+#[wasm_bindgen]
+pub fn set_voice_phaser(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    stages: u32,
+    rate_hz: f32,
+    depth: f32,
+    feedback: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::phaser::set_voice_phaser(ctx, voice_ix, stages, rate_hz, depth, feedback)
+    })
+}
+
+/// Remove a voice's phaser
+#[wasm_bindgen]
+pub fn clear_voice_phaser(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::phaser::clear_voice_phaser(ctx, voice_ix)
+    })
+}
+
+/// Feed the master output through a phaser with the same settings as `set_voice_phaser`
+#[wasm_bindgen]
+pub fn set_master_phaser(
+    ctx: InstanceHandle,
+    stages: u32,
+    rate_hz: f32,
+    depth: f32,
+    feedback: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::phaser::set_master_phaser(ctx, stages, rate_hz, depth, feedback)
+    })
+}
+
+/// Remove the master output's phaser
+#[wasm_bindgen]
+pub fn clear_master_phaser(ctx: InstanceHandle) {
+    guard(ctx, granular::phaser::clear_master_phaser)
+}
+
+/// Set up the three-band master EQ: a low shelf, a mid peak and a high shelf
+/// low_hz: 20 Hz to 1 kHz, mid_hz: 100 Hz to 10 kHz, high_hz: 1 to 20 kHz
+/// Gains are from -18 to 18 dB
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub fn set_master_eq(
+    ctx: InstanceHandle,
+    low_hz: f32,
+    low_db: f32,
+    mid_hz: f32,
+    mid_db: f32,
+    high_hz: f32,
+    high_db: f32,
+) {
+    guard(ctx, |ctx| {
+        granular::eq::set_master_eq(ctx, low_hz, low_db, mid_hz, mid_db, high_hz, high_db)
+    })
+}
+
+/// Remove the master EQ
+#[wasm_bindgen]
+pub fn clear_master_eq(ctx: InstanceHandle) {
+    guard(ctx, granular::eq::clear_master_eq)
+}
+
+/// Tilt the master output darker or brighter around a pivot frequency
+/// tilt: from -1, darkest, to 1, brightest, turning the lows and highs 6 dB in opposite directions
+/// pivot_hz: from 100 Hz to 10 kHz
+/// A tilt of 0 turns it off
+#[wasm_bindgen]
+pub fn set_master_tilt(ctx: InstanceHandle, tilt: f32, pivot_hz: f32) {
+    guard(ctx, |ctx| {
+        granular::tilt::set_master_tilt(ctx, tilt, pivot_hz)
+    })
+}
+
+/// Shape the attacks and tails of the master output
+/// attack: from -1, softening grain onsets, to 1, emphasizing them, by up to 12 dB
+/// sustain: from -1, shortening tails, to 1, drawing them out
+/// Both at 0 turn it off
+#[wasm_bindgen]
+pub fn set_master_transient_shaper(ctx: InstanceHandle, attack: f32, sustain: f32) {
+    guard(ctx, |ctx| {
+        granular::set_master_transient_shaper(ctx, attack, sustain)
+    })
+}
+
+/// Compress the master output
+/// threshold_db: from -60 to 0 dB, with a 6 dB soft knee
+/// ratio: from 1 to 20; 1 turns it off
+/// attack_ms, release_ms: from 0.1 to 200 and 5 to 2000 ms
+/// makeup_db: from 0 to 24 dB
+/// keyed: follow the level of the sidechain input instead of the output, to duck under it
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub fn set_master_compressor(
+    ctx: InstanceHandle,
+    threshold_db: f32,
+    ratio: f32,
+    attack_ms: f32,
+    release_ms: f32,
+    makeup_db: f32,
+    keyed: bool,
+) {
+    guard(ctx, |ctx| {
+        granular::compressor::set_master_compressor(
+            ctx,
+            threshold_db,
+            ratio,
+            attack_ms,
+            release_ms,
+            makeup_db,
+            keyed,
+        )
+    })
+}
+
+/// Get how far the master compressor is turning the output down in dB, or 0 while it's off
+#[wasm_bindgen]
+pub fn get_master_compressor_reduction_db(ctx: InstanceHandle) -> f32 {
+    guard(
+        ctx,
+        granular::compressor::get_master_compressor_reduction_db,
+    )
+}
+
+/// Saturate the master output
+/// model: 0 for tanh, 1 for a soft-knee polynomial, 2 for a diode clipper, 3 for an asymmetric tube
+/// curve
+/// drive_db: from 0 to 24 dB
+/// oversampling: 1, 2 or 4 times the sample rate around the curve, to keep aliasing down
+/// Returns false for an unknown model or oversampling factor
+#[wasm_bindgen]
+pub fn set_master_saturation(
+    ctx: InstanceHandle,
+    model: u32,
+    drive_db: f32,
+    oversampling: u32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::set_master_saturation(ctx, model, drive_db, oversampling)
+    })
+}
+
+/// Remove the master saturation
+#[wasm_bindgen]
+pub fn clear_master_saturation(ctx: InstanceHandle) {
+    guard(ctx, granular::clear_master_saturation)
+}
+
+/// Normalize an impulse response and transform it into the spectra `load_impulse_response` takes
+/// This needs no instance, so run it in a worker and hand the spectra to the instance that renders.
+/// Responses are cut to 2 seconds at `sample_rate`. Returns an empty buffer for a response that's
+/// silent or not finite, or an unsupported sample rate
+#[wasm_bindgen]
+pub fn prepare_impulse_response(samples: &[f32], sample_rate: f32) -> Vec<f32> {
+    granular::convolution::prepare_impulse_response(samples, sample_rate)
+}
+
+/// Get a pointer to a buffer of `len` values to write the spectra from `prepare_impulse_response`
+/// into
+/// Call `load_impulse_response` once they're written. Returns null for responses longer than 2
+/// seconds
+#[wasm_bindgen]
+pub fn get_impulse_response_ptr(ctx: InstanceHandle, len: usize) -> *mut f32 {
+    guard(ctx, |ctx| {
+        granular::convolution::get_impulse_response_ptr(ctx, len)
+    })
+}
+
+/// Start convolving the master output with the spectra written to `get_impulse_response_ptr`,
+/// without transforming anything
+/// mix: from 0, dry, to 1, only the convolved signal, which lags by 128 samples
+/// Returns false if no spectra were written or they aren't ones `prepare_impulse_response` made
+#[wasm_bindgen]
+pub fn load_impulse_response(ctx: InstanceHandle, mix: f32) -> bool {
+    guard(ctx, |ctx| {
+        granular::convolution::load_impulse_response(ctx, mix)
+    })
+}
+
+/// Set how much of the convolved signal the master output is made of, from 0 to 1
+#[wasm_bindgen]
+pub fn set_convolution_mix(ctx: InstanceHandle, mix: f32) {
+    guard(ctx, |ctx| {
+        granular::convolution::set_convolution_mix(ctx, mix)
+    })
+}
+
+/// Stop convolving the master output
+#[wasm_bindgen]
+pub fn clear_master_convolution(ctx: InstanceHandle) {
+    guard(ctx, granular::convolution::clear_master_convolution)
+}
+
+/// Widen the master output by delaying one of its channels, a cheap alternative to grain spread
+/// delayed_channel: 0 for left, 1 for right
+/// delay_ms: from 0 to 30 ms
+/// highpass_hz: from 20 Hz to 1 kHz, below which the channel isn't delayed so it stays mono-safe
+/// Returns false for an unknown channel
+#[wasm_bindgen]
+pub fn set_master_haas(
+    ctx: InstanceHandle,
+    delayed_channel: u32,
+    delay_ms: f32,
+    highpass_hz: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::haas::set_master_haas(ctx, delayed_channel, delay_ms, highpass_hz)
+    })
+}
+
+/// Remove the master Haas widener
+#[wasm_bindgen]
+pub fn clear_master_haas(ctx: InstanceHandle) {
+    guard(ctx, granular::haas::clear_master_haas)
+}
+
+/// Make the pitch of the master output waver, for a tape-like warble independent of grain pitch
+/// rate_hz: from 0.1 to 20 Hz
+/// depth_cents: from 0 to 100 cents either way, where 0 turns it off
+#[wasm_bindgen]
+pub fn set_master_vibrato(ctx: InstanceHandle, rate_hz: f32, depth_cents: f32) {
+    guard(ctx, |ctx| {
+        granular::vibrato::set_master_vibrato(ctx, rate_hz, depth_cents)
+    })
+}
+
+/// Run the master output through a simulated tape machine for a vintage character
+/// wow: slow random drift of the pitch, flutter: faster wobble of it, saturation: rounding off of
+/// peaks, hiss: white noise up to -40 dBFS
+/// Amounts are from 0 to 1, and all of them at 0 turn it off
+#[wasm_bindgen]
+pub fn set_master_tape(ctx: InstanceHandle, wow: f32, flutter: f32, saturation: f32, hiss: f32) {
+    guard(ctx, |ctx| {
+        granular::tape::set_master_tape(ctx, wow, flutter, saturation, hiss)
+    })
+}
+
+/// Switch a voice to pitch-synchronous (PSOLA) granulation for clean monophonic pitch shifting
+/// target_hz: fixed output pitch, or 0 to transpose the source by the voice's sample speed
+/// snap_to_notes: round the output pitch to the nearest note, for pitch correction
+/// Needs `analyze_pitch`; unpitched parts of the waveform are granulated as usual
+#[wasm_bindgen]
+pub fn set_voice_psola(ctx: InstanceHandle, voice_ix: usize, target_hz: f32, snap_to_notes: bool) {
+    guard(ctx, |ctx| {
+        granular::psola::set_voice_psola(ctx, voice_ix, target_hz, snap_to_notes)
+    })
+}
+
+/// Switch a voice back from PSOLA to free granulation
+#[wasm_bindgen]
+pub fn clear_voice_psola(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| granular::psola::clear_voice_psola(ctx, voice_ix))
+}
+
+/// Transpose every grain of a voice by a ratio drawn at random from a table, instead of a random
+/// detune, e.g. [1, 1.25, 1.5] for just intervals; repeat a ratio to make it more likely
+/// Returns false without changing anything for an empty table, more than 64 ratios or any ratio
+/// outside 1/16 to 16
+#[wasm_bindgen]
+pub fn set_voice_pitch_intervals(ctx: InstanceHandle, voice_ix: usize, ratios: &[f32]) -> bool {
+    guard(ctx, |ctx| {
+        granular::intervals::set_voice_pitch_intervals(ctx, voice_ix, ratios)
+    })
+}
+
+/// Make every grain trigger of a voice spawn a cluster of grains, one per interval
+/// semitones: intervals from the voice's pitch, -48 to 48, e.g. [0, 7, 12] for root, fifth and
+/// octave; the root is only heard if 0 is listed
+/// gains: gain of each interval, 0 to 2
+/// Returns false without changing anything unless both lists hold the same number of values,
+/// from 1 to 6
+#[wasm_bindgen]
+pub fn set_voice_chord(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    semitones: &[f32],
+    gains: &[f32],
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::chord::set_voice_chord(ctx, voice_ix, semitones, gains)
+    })
+}
+
+/// Walk a voice's grains through a sequence of transpositions, for granular arpeggios
+/// semitones: the steps, -48 to 48, up to 32 of them
+/// step_beats: beats of the host's tempo per step, or 0 to move on a step with every grain
+/// Returns false without changing anything for an empty or too long sequence
+#[wasm_bindgen]
+pub fn set_voice_arpeggio(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    semitones: &[f32],
+    step_beats: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::arpeggio::set_voice_arpeggio(ctx, voice_ix, semitones, step_beats)
+    })
+}
+
+/// Stop walking a voice's grains through its pitch sequence
+#[wasm_bindgen]
+pub fn clear_voice_arpeggio(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::arpeggio::clear_voice_arpeggio(ctx, voice_ix)
+    })
+}
+
+/// Transpose each grain a voice spawns by a random offset folded into an octave range
+/// spread_semitones: largest offset either way, 0 to 48
+/// low_octaves, high_octaves: range the offsets are folded into, -4 to 4
+/// intervals: semitones above each octave that offsets snap to, up to 12 of them, or none for
+/// any pitch
+/// Returns false without changing anything if the range is upside down
+#[wasm_bindgen]
+pub fn set_voice_pitch_spray(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    spread_semitones: f32,
+    low_octaves: f32,
+    high_octaves: f32,
+    intervals: &[f32],
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::spray::set_voice_pitch_spray(
+            ctx,
+            voice_ix,
+            spread_semitones,
+            low_octaves,
+            high_octaves,
+            intervals,
+        )
+    })
+}
+
+/// Stop transposing a voice's grains by random offsets
+#[wasm_bindgen]
+pub fn clear_voice_pitch_spray(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::spray::clear_voice_pitch_spray(ctx, voice_ix)
+    })
+}
+
+/// Tilt each grain a voice spawns darker or brighter by a random amount, so that grains vary in
+/// brightness
+/// low_tilt, high_tilt: range the tilts are picked from, each from -1, darkest, to 1, brightest
+/// pivot_hz: frequency the tilts turn around, 100 to 10000
+/// Returns false without changing anything if the range is upside down
+#[wasm_bindgen]
+pub fn set_voice_grain_tilt(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    low_tilt: f32,
+    high_tilt: f32,
+    pivot_hz: f32,
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::tilt::set_voice_grain_tilt(ctx, voice_ix, low_tilt, high_tilt, pivot_hz)
+    })
+}
+
+/// Stop tilting the grains a voice spawns
+#[wasm_bindgen]
+pub fn clear_voice_grain_tilt(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::tilt::clear_voice_grain_tilt(ctx, voice_ix)
+    })
+}
+
+/// Slide each grain a voice spawns from the pitch of the grain before it to its own
+/// glide_ms: time each grain takes to reach its pitch, up to 10000, or 0 for no glide
+#[wasm_bindgen]
+pub fn set_voice_grain_glide(ctx: InstanceHandle, voice_ix: usize, glide_ms: f32) {
+    guard(ctx, |ctx| {
+        granular::glide::set_voice_grain_glide(ctx, voice_ix, glide_ms)
+    })
+}
+
+/// Add pitch-shifted copies of a voice's output on top of it, like a harmonizer
+/// semitones: interval of each copy, -24 to 24
+/// gains: gain of each copy, 0 to 2
+/// Returns false without changing anything unless both lists hold the same number of values,
+/// from 1 to 4
+#[wasm_bindgen]
+pub fn set_voice_harmonies(
+    ctx: InstanceHandle,
+    voice_ix: usize,
+    semitones: &[f32],
+    gains: &[f32],
+) -> bool {
+    guard(ctx, |ctx| {
+        granular::harmonizer::set_voice_harmonies(ctx, voice_ix, semitones, gains)
+    })
+}
+
+/// Remove a voice's harmonies
+#[wasm_bindgen]
+pub fn clear_voice_harmonies(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::harmonizer::clear_voice_harmonies(ctx, voice_ix)
+    })
+}
+
+/// Go back to spawning one grain per trigger on a voice
+#[wasm_bindgen]
+pub fn clear_voice_chord(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| granular::chord::clear_voice_chord(ctx, voice_ix))
+}
+
+/// Stop drawing a voice's grain transpositions from its interval table
+#[wasm_bindgen]
+pub fn clear_voice_pitch_intervals(ctx: InstanceHandle, voice_ix: usize) {
+    guard(ctx, |ctx| {
+        granular::intervals::clear_voice_pitch_intervals(ctx, voice_ix)
+    })
+}
+
+/// Turn drone mode on or off, which sets the grain parameters from a single blur amount
+/// blur: from 0 to 1, longer, denser, more scattered and more detuned grains the higher it is
+/// Turning it off releases a held drone
+#[wasm_bindgen]
+pub fn set_drone_mode(ctx: InstanceHandle, enabled: bool, blur: f32) {
+    guard(ctx, |ctx| {
+        granular::drone::set_drone_mode(ctx, enabled, blur)
+    })
+}
+
+/// Set the blur amount of drone mode, from 0 to 1
+#[wasm_bindgen]
+pub fn set_drone_blur(ctx: InstanceHandle, blur: f32) {
+    guard(ctx, |ctx| granular::drone::set_drone_blur(ctx, blur))
+}
+
+/// Configure the safety limiter that runs before the final output clamp
+/// The `LIMITING_ACTIVE` status flag is set whenever either of them reduces the output
+#[wasm_bindgen]
+pub fn set_limiter(ctx: InstanceHandle, enabled: bool, threshold: f32, release_ms: f32) {
+    guard(ctx, |ctx| {
+        granular::set_limiter(ctx, enabled, threshold, release_ms)
+    })
+}
+
+/// Kill all active grains, clear filter state and reset playheads without recreating the instance
+/// If `fade_ms` is greater than 0 the output is faded out before the reset happens
+#[wasm_bindgen]
+pub fn reset(ctx: InstanceHandle, fade_ms: f32) {
+    guard(ctx, |ctx| granular::reset(ctx, fade_ms))
+}
+
+/// Start fading out the instance over `fade_ms` and stop spawning new grains
+/// Poll `is_drained` and free the instance once it returns true to avoid a click
+#[wasm_bindgen]
+pub fn begin_shutdown(ctx: InstanceHandle, fade_ms: f32) {
+    guard(ctx, |ctx| granular::begin_shutdown(ctx, fade_ms))
+}
+
+/// Check whether an instance that's shutting down has become silent
+#[wasm_bindgen]
+pub fn is_drained(ctx: InstanceHandle) -> bool {
+    guard(ctx, granular::is_drained)
+}
+
+/// Get how many samples the master effects currently delay the output by, so hosts and plugin
+/// wrappers can compensate. It changes when oversampling, vibrato, tape or a fully wet
+/// convolution are set up
+#[wasm_bindgen]
+pub fn get_latency_frames(ctx: InstanceHandle) -> u32 {
+    guard(ctx, granular::get_latency_frames)
+}
+
+/// Get how many samples the output will stay non-silent for once no new grains start, e.g. after
+/// the last note, counting grain tails, resonators, combs, the send delay and convolution, so
+/// hosts know when it's safe to suspend or free the node. 4294967295 (`u32::MAX`) means it never
+/// goes silent: a voice is frozen, the stutter is repeating or the tape is hissing
+#[wasm_bindgen]
+pub fn get_tail_frames(ctx: InstanceHandle) -> u32 {
+    guard(ctx, granular::get_tail_frames)
 }
 
-/// Free a granular synthesis instance
+/// Free a granular synthesis instance, invalidating its handle
 #[wasm_bindgen]
-pub fn free_granular_instance(ctx: *mut GranularCtx) {
-    unsafe {
-        if !ctx.is_null() {
-            let _ = Box::from_raw(ctx);
-        }
-    }
+pub fn free_granular_instance(ctx: InstanceHandle) {
+    granular::free_granular_instance(ctx)
 }
@@ -4,6 +4,9 @@
 import init, { 
   create_granular_instance,
   get_granular_waveform_ptr,
+  commit_waveform,
+  get_output_ptr,
+  get_voice_render_params_ptr,
   render_granular,
   free_granular_instance,
   type InitOutput,
@@ -42,13 +45,22 @@ export function isWasmInitialized(): boolean {
  * Granular synthesis instance wrapper
  */
 export class GranularInstance {
-  private instancePtr: number | null = null;
+  private instanceHandle: number | null = null;
+  // View of the engine's output buffer, which stays at the same address across renders
+  private outputView: Float32Array | null = null;
+  // View of the per-voice parameters that renders read, one row of two voices per parameter
+  private voiceParamsView: Float32Array | null = null;
 
   constructor() {
     if (!wasmInitialized) {
       throw new Error('WASM module not initialized. Call initWasm() first.');
     }
-    this.instancePtr = create_granular_instance();
+    const handle = create_granular_instance();
+    // 0 is never a valid handle; the engine returns it when it can't create another instance
+    if (handle === 0) {
+      throw new Error('Could not create a granular instance');
+    }
+    this.instanceHandle = handle;
   }
 
   /**
@@ -56,12 +68,15 @@ export class GranularInstance {
    * @param waveform - Audio data as Float32Array
    */
   setWaveform(waveform: Float32Array): void {
-    if (this.instancePtr === null) {
+    if (this.instanceHandle === null) {
       throw new Error('Granular instance has been freed');
     }
 
     const len = waveform.length;
-    const ptr = get_granular_waveform_ptr(this.instancePtr, len);
+    const ptr = get_granular_waveform_ptr(this.instanceHandle, len);
+    if (ptr === 0) {
+      throw new Error('Waveform is too long to load');
+    }
     
     // Copy the waveform data into the WASM memory
     // Get WASM memory from the module
@@ -78,6 +93,7 @@ export class GranularInstance {
     for (let i = 0; i < len; i++) {
       memoryView[offset + i] = waveform[i];
     }
+    commit_waveform(this.instanceHandle, len);
   }
 
   /**
@@ -86,55 +102,64 @@ export class GranularInstance {
    * @returns Float32Array of 128 samples
    */
   renderFrame(params: GranularParams): Float32Array {
-    if (this.instancePtr === null) {
+    if (this.instanceHandle === null) {
       throw new Error('Granular instance has been freed');
     }
 
-    const outputPtr = render_granular(
-      this.instancePtr,
+    if (!wasmModule || !wasmModule.memory) {
+      throw new Error('WASM module not initialized or memory not available');
+    }
+
+    // Growing the WASM memory detaches its old buffer, so the views are only recreated then
+    let memoryBuffer = wasmModule.memory.buffer;
+    if (this.voiceParamsView === null || this.voiceParamsView.buffer !== memoryBuffer) {
+      this.voiceParamsView = new Float32Array(
+        memoryBuffer,
+        get_voice_render_params_ptr(this.instanceHandle),
+        12,
+      );
+    }
+    const voiceParams = this.voiceParamsView;
+    voiceParams[0] = params.voice1FilterCutoff;
+    voiceParams[1] = params.voice2FilterCutoff;
+    voiceParams[2] = params.voice1MovementSamplesPerSample;
+    voiceParams[3] = params.voice2MovementSamplesPerSample;
+    voiceParams[4] = params.voice1SampleSpeedRatio;
+    voiceParams[5] = params.voice2SampleSpeedRatio;
+    voiceParams[6] = params.voice1SamplesBetweenGrains;
+    voiceParams[7] = params.voice2SamplesBetweenGrains;
+    voiceParams[8] = params.voice1Gain;
+    voiceParams[9] = params.voice2Gain;
+    voiceParams[10] = params.voice1GrainStartRandomnessSamples;
+    voiceParams[11] = params.voice2GrainStartRandomnessSamples;
+
+    render_granular(
+      this.instanceHandle,
       params.selectionStartSampleIx,
       params.selectionEndSampleIx,
       params.grainSize,
-      params.voice1FilterCutoff,
-      params.voice2FilterCutoff,
       params.linearSlopeLength,
       params.slopeLinearity,
-      params.voice1MovementSamplesPerSample,
-      params.voice2MovementSamplesPerSample,
-      params.voice1SampleSpeedRatio,
-      params.voice2SampleSpeedRatio,
-      params.voice1SamplesBetweenGrains,
-      params.voice2SamplesBetweenGrains,
-      params.voice1Gain,
-      params.voice2Gain,
-      params.voice1GrainStartRandomnessSamples,
-      params.voice2GrainStartRandomnessSamples,
     );
 
     // Read the output from WASM memory
-    if (!wasmModule || !wasmModule.memory) {
-      throw new Error('WASM module not initialized or memory not available');
-    }
-    
-    const memoryBuffer = wasmModule.memory.buffer;
-    const memoryView = new Float32Array(memoryBuffer);
-    const offset = outputPtr / 4; // Divide by 4 because f32 is 4 bytes
-    const output = new Float32Array(128);
-    
-    for (let i = 0; i < 128; i++) {
-      output[i] = memoryView[offset + i];
+    memoryBuffer = wasmModule.memory.buffer;
+    if (this.outputView === null || this.outputView.buffer !== memoryBuffer) {
+      this.outputView = new Float32Array(memoryBuffer, get_output_ptr(this.instanceHandle), 128);
     }
-    
-    return output;
+
+    return this.outputView.slice();
   }
 
   /**
    * Free the granular instance
    */
   free(): void {
-    if (this.instancePtr !== null) {
-      free_granular_instance(this.instancePtr);
-      this.instancePtr = null;
+    if (this.instanceHandle !== null) {
+      free_granular_instance(this.instanceHandle);
+      this.instanceHandle = null;
+      this.outputView = null;
+      this.voiceParamsView = null;
     }
   }
 }